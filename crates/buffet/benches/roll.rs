@@ -0,0 +1,71 @@
+use buffet::{Roll, RollMut};
+use codspeed_criterion_compat::{black_box, criterion_group, criterion_main, Criterion};
+
+const HEADER_VALUE: &[u8] =
+    b"  text/html, application/xhtml+xml, application/xml;q=0.9, */*;q=0.8  ";
+
+fn header_value() -> Roll {
+    buffet::bufpool::initialize_allocator().unwrap();
+    let mut rm = RollMut::alloc().unwrap();
+    rm.put(HEADER_VALUE).unwrap();
+    rm.filled()
+}
+
+pub fn as_str(c: &mut Criterion) {
+    let roll = header_value();
+    let mut c = c.benchmark_group("as_str");
+
+    c.bench_function("as_str/to_vec", |b| {
+        b.iter(|| black_box(String::from_utf8(roll.to_vec()).unwrap().len()))
+    });
+
+    c.bench_function("as_str/borrowed", |b| {
+        b.iter(|| black_box(roll.as_str().unwrap().len()))
+    });
+}
+
+pub fn split_and_trim(c: &mut Criterion) {
+    let roll = header_value();
+    let mut c = c.benchmark_group("split_and_trim");
+
+    c.bench_function("split_and_trim/strings", |b| {
+        b.iter(|| {
+            let s = String::from_utf8(roll.to_vec()).unwrap();
+            let parts = s
+                .split(',')
+                .map(|part| part.trim().to_string())
+                .collect::<Vec<_>>();
+            black_box(parts)
+        })
+    });
+
+    c.bench_function("split_and_trim/rolls", |b| {
+        b.iter(|| {
+            let parts = roll
+                .clone()
+                .split_on(b',')
+                .map(|part| part.trim_ascii())
+                .collect::<Vec<_>>();
+            black_box(parts)
+        })
+    });
+}
+
+pub fn eq_ignore_ascii_case(c: &mut Criterion) {
+    let roll = header_value().trim_ascii().split_once(b',').unwrap().0;
+    let mut c = c.benchmark_group("eq_ignore_ascii_case");
+
+    c.bench_function("eq_ignore_ascii_case/lowercase", |b| {
+        b.iter(|| {
+            let s = String::from_utf8(roll.to_vec()).unwrap().to_lowercase();
+            black_box(s == "text/html")
+        })
+    });
+
+    c.bench_function("eq_ignore_ascii_case/in_place", |b| {
+        b.iter(|| black_box(roll.eq_ignore_ascii_case("TEXT/HTML")))
+    });
+}
+
+criterion_group!(benches, as_str, split_and_trim, eq_ignore_ascii_case);
+criterion_main!(benches);
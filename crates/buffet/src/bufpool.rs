@@ -8,7 +8,8 @@ mod privatepool;
 pub type BufResult<T, B> = (std::io::Result<T>, B);
 
 pub use privatepool::{
-    initialize_allocator_with_num_bufs, is_allocator_initialized, num_free, Error, Result, BUF_SIZE,
+    initialize_allocator_with_conf, initialize_allocator_with_num_bufs, is_allocator_initialized,
+    num_bufs, num_free, stats, Error, Growth, PoolConf, PoolStats, Result, BUF_SIZE,
 };
 
 /// Initialize the allocator. Must be called before any other
@@ -18,27 +19,20 @@ pub fn initialize_allocator() -> Result<()> {
         return Ok(());
     }
 
-    // 64 * 1024 * 4096 bytes = 256 MiB
-    #[cfg(not(feature = "miri"))]
-    let default_num_bufs = 64 * 1024;
-
-    #[cfg(feature = "miri")]
-    let default_num_bufs = 1024;
-
-    let mut num_bufs = default_num_bufs;
+    let mut conf = PoolConf::default();
 
     if let Ok(env_num_bufs) = std::env::var("BUFFET_NUM_BUFS") {
-        if let Ok(parsed_num_bufs) = env_num_bufs.parse::<usize>() {
-            num_bufs = parsed_num_bufs;
+        if let Ok(parsed_num_bufs) = env_num_bufs.parse::<u32>() {
+            conf.num_bufs = parsed_num_bufs;
         }
     }
 
-    let mem_usage_in_mb: f64 = num_bufs as f64 * (BUF_SIZE as usize) as f64 / 1024.0 / 1024.0;
+    let mem_usage_in_mb: f64 = conf.num_bufs as f64 * (BUF_SIZE as usize) as f64 / 1024.0 / 1024.0;
     eprintln!(
         "==== buffet will use {} buffers, for a constant {:.2} MiB usage (override with $BUFFET_NUM_BUFS)",
-        num_bufs, mem_usage_in_mb
+        conf.num_bufs, mem_usage_in_mb
     );
-    initialize_allocator_with_num_bufs(default_num_bufs as _)
+    initialize_allocator_with_conf(&conf)
 }
 
 impl BufMut {
@@ -73,7 +67,8 @@ impl BufMut {
         b
     }
 
-    /// Dangerous: freeze a slice of this.
+    /// Dangerous: freeze a slice of this. The slice holds its own reference
+    /// to the buffer, so it stays valid after this [BufMut] is dropped.
     ///
     /// # Safety
     /// Must only be used if you can guarantee this portion won't be written to
@@ -87,6 +82,7 @@ impl BufMut {
 
             _non_send: PhantomData,
         };
+        privatepool::inc(b.index);
 
         b.slice(range)
     }
@@ -166,13 +162,14 @@ impl ops::DerefMut for BufMut {
 }
 
 mod iobufmut {
-    use crate::{ReadInto, RollMut};
+    use crate::{ReadInto, ReadvInto, RollMut};
 
     use super::BufMut;
     pub trait Sealed {}
     impl Sealed for BufMut {}
     impl Sealed for RollMut {}
     impl Sealed for ReadInto {}
+    impl Sealed for ReadvInto {}
     impl Sealed for Vec<u8> {}
 }
 
@@ -393,6 +390,30 @@ mod tests {
         assert_eq!(total_bufs, num_free());
     }
 
+    #[test]
+    fn freeze_slice_test() {
+        crate::bufpool::initialize_allocator().unwrap();
+
+        let total_bufs = num_free();
+        let mut bm = BufMut::alloc().unwrap();
+        bm[..11].copy_from_slice(b"hello world");
+
+        // dropping a frozen slice must not give the buffer back to the pool
+        // while the `BufMut` is still around, and vice versa
+        let b = unsafe { bm.freeze_slice(..5) };
+        assert_eq!(&b[..], b"hello");
+        drop(b);
+        assert_eq!(total_bufs - 1, num_free());
+
+        let b = unsafe { bm.freeze_slice(6..11) };
+        drop(bm);
+        assert_eq!(total_bufs - 1, num_free());
+        assert_eq!(&b[..], b"world");
+
+        drop(b);
+        assert_eq!(total_bufs, num_free());
+    }
+
     #[test]
     fn split_test() {
         crate::bufpool::initialize_allocator().unwrap();
@@ -409,4 +430,47 @@ mod tests {
 
         drop((a, b));
     }
+
+    #[test]
+    fn growth_test() {
+        // a thread of our own, so the pool is ours to configure
+        std::thread::spawn(|| {
+            use crate::bufpool::{initialize_allocator_with_conf, stats, Growth, PoolConf};
+
+            initialize_allocator_with_conf(&PoolConf {
+                num_bufs: 3,
+                slab_bufs: 3,
+                growth: Growth::Slabs { max_bufs: 6 },
+            })
+            .unwrap();
+
+            // rounded up to one slab of 4
+            let pool = stats();
+            assert_eq!((pool.allocated, pool.capacity, pool.slabs), (4, 6, 1));
+
+            let mut bufs = (0..6)
+                .map(|i| {
+                    let mut bm = BufMut::alloc().unwrap();
+                    bm[0] = i;
+                    bm
+                })
+                .collect::<Vec<_>>();
+            assert!(BufMut::alloc().is_err());
+            for (i, bm) in bufs.iter().enumerate() {
+                assert_eq!(bm[0], i as u8);
+            }
+
+            let pool = stats();
+            assert_eq!((pool.allocated, pool.slabs), (6, 2));
+            assert_eq!((pool.in_use, pool.high_water_mark), (6, 6));
+            assert_eq!(pool.alloc_failures, 1);
+
+            bufs.truncate(2);
+            let pool = stats();
+            assert_eq!((pool.in_use, pool.high_water_mark), (2, 6));
+            assert_eq!(num_free(), 4);
+        })
+        .join()
+        .unwrap();
+    }
 }
@@ -25,18 +25,84 @@ thread_local! {
     static POOL: Pool = const { Pool::new() };
 }
 
+/// How a thread's buffer pool is laid out, cf.
+/// [initialize_allocator_with_conf]
+#[derive(Debug, Clone)]
+pub struct PoolConf {
+    /// How many buffers to map up front
+    pub num_bufs: u32,
+
+    /// How many buffers are mapped at once, rounded up to a power of two
+    pub slab_bufs: u32,
+
+    /// What happens once every buffer is in use
+    pub growth: Growth,
+}
+
+impl Default for PoolConf {
+    fn default() -> Self {
+        Self {
+            // 64 * 1024 * 4096 bytes = 256 MiB
+            #[cfg(not(feature = "miri"))]
+            num_bufs: 64 * 1024,
+            #[cfg(feature = "miri")]
+            num_bufs: 1024,
+
+            // 16 MiB
+            slab_bufs: 4096,
+
+            growth: Growth::Fixed,
+        }
+    }
+}
+
+/// Whether a buffer pool can map more buffers than it started with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Growth {
+    /// Allocations fail once all [PoolConf::num_bufs] are in use
+    Fixed,
+
+    /// Another slab is mapped when all buffers are in use, until the pool
+    /// has `max_bufs` buffers. [PoolConf::num_bufs] is rounded up to whole
+    /// slabs.
+    Slabs { max_bufs: u32 },
+}
+
+/// A snapshot of the current thread's buffer pool, cf. [stats]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Buffers mapped so far, in use or not
+    pub allocated: usize,
+
+    /// Buffers the pool can grow to
+    pub capacity: usize,
+
+    /// Buffers currently handed out
+    pub in_use: usize,
+
+    /// The most buffers ever in use at once
+    pub high_water_mark: usize,
+
+    /// Memory mappings the buffers live in
+    pub slabs: usize,
+
+    /// Allocations that failed because the pool was exhausted
+    pub alloc_failures: u64,
+}
+
 /// A buffer pool
 struct Pool {
     inner: UnsafeCell<Option<Inner>>,
 }
 
 struct Inner {
-    // mmapped memory
-    ptr: *mut u8,
+    // mmapped memory, `1 << slab_shift` buffers per slab (the last one may
+    // be shorter)
+    slabs: Vec<Slab>,
+    slab_shift: u32,
 
-    // The mmap object, if we're using an anonymous mapping.
-    // This is only used for its `Drop` implementation.
-    _mmap: Option<MmapMut>,
+    // how many buffers we may map in total
+    max_bufs: u32,
 
     // index of free blocks
     // there's several optimizations we could do here:
@@ -48,6 +114,18 @@ struct Inner {
 
     // ref counts start as all zeroes, get incremented when a block is borrowed
     ref_counts: Vec<i16>,
+
+    in_use: usize,
+    high_water_mark: usize,
+    alloc_failures: u64,
+}
+
+struct Slab {
+    ptr: *mut u8,
+
+    // The mmap object, if we're using an anonymous mapping.
+    // This is only used for its `Drop` implementation.
+    _mmap: Option<MmapMut>,
 }
 
 impl Pool {
@@ -65,6 +143,42 @@ impl Pool {
     }
 }
 
+impl Inner {
+    /// Maps a slab of up to `1 << slab_shift` buffers, stopping at
+    /// `max_bufs`, and adds them to the free list
+    fn map_slab(&mut self) -> Result<()> {
+        let first = self.ref_counts.len() as u32;
+        let len = (1u32 << self.slab_shift).min(self.max_bufs - first);
+        if len == 0 {
+            return Err(Error::OutOfMemory);
+        }
+        let alloc_len = len as usize * BUF_SIZE as usize;
+
+        #[cfg(feature = "miri")]
+        let slab = {
+            let mut map = vec![0; alloc_len];
+            let ptr = map.as_mut_ptr();
+            std::mem::forget(map);
+            Slab { ptr, _mmap: None }
+        };
+
+        #[cfg(not(feature = "miri"))]
+        let slab = {
+            let mut map = memmap2::MmapOptions::new().len(alloc_len).map_anon()?;
+            Slab {
+                ptr: map.as_mut_ptr(),
+                _mmap: Some(map),
+            }
+        };
+
+        self.slabs.push(slab);
+        self.free.extend(first..first + len);
+        self.ref_counts
+            .resize(self.ref_counts.len() + len as usize, 0);
+        Ok(())
+    }
+}
+
 #[inline(always)]
 fn with<T>(f: impl FnOnce(&mut Inner) -> T) -> T {
     POOL.with(|pool| pool.with(f))
@@ -79,32 +193,52 @@ pub fn is_allocator_initialized() -> bool {
 
 /// Initializes the allocator with the given number of buffers
 pub fn initialize_allocator_with_num_bufs(num_bufs: u32) -> Result<()> {
+    initialize_allocator_with_conf(&PoolConf {
+        num_bufs,
+        slab_bufs: num_bufs,
+        growth: Growth::Fixed,
+    })
+}
+
+/// Initializes the current thread's allocator. Does nothing if it already
+/// is: call this before [crate::start] to use something else than the
+/// defaults.
+pub fn initialize_allocator_with_conf(conf: &PoolConf) -> Result<()> {
     POOL.with(|pool| {
         if unsafe { (*pool.inner.get()).is_some() } {
             return Ok(());
         }
 
-        let mut inner = Inner {
-            ptr: std::ptr::null_mut(),
-            _mmap: None,
-            free: VecDeque::from_iter(0..num_bufs),
-            ref_counts: vec![0; num_bufs as usize],
+        let slab_bufs = conf
+            .slab_bufs
+            .max(1)
+            .checked_next_power_of_two()
+            .unwrap_or(1 << 31);
+        let slab_shift = slab_bufs.trailing_zeros();
+        let (num_bufs, max_bufs) = match conf.growth {
+            Growth::Fixed => (conf.num_bufs, conf.num_bufs),
+            Growth::Slabs { max_bufs } => {
+                let max_bufs = max_bufs.max(conf.num_bufs);
+                let whole_slabs = conf
+                    .num_bufs
+                    .checked_next_multiple_of(slab_bufs)
+                    .unwrap_or(max_bufs);
+                (whole_slabs.min(max_bufs), max_bufs)
+            }
         };
 
-        let alloc_len = num_bufs as usize * BUF_SIZE as usize;
-
-        #[cfg(feature = "miri")]
-        {
-            let mut map = vec![0; alloc_len];
-            inner.ptr = map.as_mut_ptr();
-            std::mem::forget(map);
-        }
-
-        #[cfg(not(feature = "miri"))]
-        {
-            let mut map = memmap2::MmapOptions::new().len(alloc_len).map_anon()?;
-            inner.ptr = map.as_mut_ptr();
-            inner._mmap = Some(map);
+        let mut inner = Inner {
+            slabs: Vec::new(),
+            slab_shift,
+            max_bufs,
+            free: VecDeque::with_capacity(num_bufs as usize),
+            ref_counts: Vec::with_capacity(num_bufs as usize),
+            in_use: 0,
+            high_water_mark: 0,
+            alloc_failures: 0,
+        };
+        while inner.ref_counts.len() < num_bufs as usize {
+            inner.map_slab()?;
         }
 
         unsafe {
@@ -120,11 +254,35 @@ pub fn num_free() -> usize {
     with(|inner| inner.free.len())
 }
 
+/// Returns the number of buffers in the pool, free or not
+pub fn num_bufs() -> usize {
+    with(|inner| inner.ref_counts.len())
+}
+
+/// Returns a snapshot of the current thread's pool
+pub fn stats() -> PoolStats {
+    with(|inner| PoolStats {
+        allocated: inner.ref_counts.len(),
+        capacity: inner.max_bufs as usize,
+        in_use: inner.in_use,
+        high_water_mark: inner.high_water_mark,
+        slabs: inner.slabs.len(),
+        alloc_failures: inner.alloc_failures,
+    })
+}
+
 /// Allocate a buffer
 pub fn alloc() -> Result<BufMut> {
     with(|inner| {
+        if inner.free.is_empty() && inner.ref_counts.len() < inner.max_bufs as usize {
+            // if that fails, so does this allocation
+            let _ = inner.map_slab();
+        }
+
         if let Some(index) = inner.free.pop_front() {
             inner.ref_counts[index as usize] += 1;
+            inner.in_use += 1;
+            inner.high_water_mark = inner.high_water_mark.max(inner.in_use);
             Ok(BufMut {
                 index,
                 off: 0,
@@ -132,6 +290,7 @@ pub fn alloc() -> Result<BufMut> {
                 _non_send: PhantomData,
             })
         } else {
+            inner.alloc_failures += 1;
             Err(Error::OutOfMemory)
         }
     })
@@ -152,6 +311,7 @@ pub fn dec(index: u32) {
         *slot -= 1;
         if *slot == 0 {
             inner.free.push_back(index);
+            inner.in_use -= 1;
         }
     })
 }
@@ -160,8 +320,9 @@ pub fn dec(index: u32) {
 #[inline(always)]
 pub unsafe fn base_ptr_with_offset(index: u32, offset: isize) -> *mut u8 {
     with(|inner| {
-        inner
-            .ptr
-            .byte_offset(offset + index as isize * BUF_SIZE as isize)
+        let slab = &inner.slabs[(index >> inner.slab_shift) as usize];
+        let index_in_slab = index & ((1 << inner.slab_shift) - 1);
+        slab.ptr
+            .byte_offset(offset + index_in_slab as isize * BUF_SIZE as isize)
     })
 }
@@ -5,9 +5,28 @@ pub use pipe::*;
 
 mod non_uring;
 
+mod compat;
+pub use compat::*;
+
 #[allow(async_fn_in_trait)] // we never require Send
 pub trait ReadOwned {
     async fn read_owned<B: IoBufMut>(&mut self, buf: B) -> BufResult<usize, B>;
+
+    /// Read into several buffers at once, filling them in order. Like
+    /// [ReadOwned::read_owned], might read less than they can hold.
+    ///
+    /// The default implementation only reads into the first buffer.
+    /// Implementations that can read into all of them with a single syscall
+    /// (`readv`) or submission should override it.
+    async fn readv_owned<B: IoBufMut>(&mut self, mut bufs: Vec<B>) -> BufResult<usize, Vec<B>> {
+        if bufs.is_empty() {
+            return (Ok(0), bufs);
+        }
+        let first = bufs.remove(0);
+        let (res, first) = self.read_owned(first).await;
+        bufs.insert(0, first);
+        (res, bufs)
+    }
 }
 
 #[allow(async_fn_in_trait)] // we never require Send
@@ -105,11 +124,58 @@ pub trait WriteOwned {
         Ok(())
     }
 
+    /// Write `len` bytes of `file`, starting at `offset`.
+    ///
+    /// The default implementation reads the file into memory, one buffer at a
+    /// time, and writes that with [WriteOwned::write_all_owned].
+    /// Implementations that wrap a plain socket may override it to let the
+    /// kernel move the data directly (`splice`, `sendfile`).
+    async fn write_file_all(
+        &mut self,
+        file: &std::fs::File,
+        mut offset: u64,
+        len: u64,
+    ) -> std::io::Result<()> {
+        let mut remaining = len;
+        while remaining > 0 {
+            let piece = read_file_piece(file, offset, remaining)?;
+            offset += piece.len() as u64;
+            remaining -= piece.len() as u64;
+            self.write_all_owned(piece).await?;
+        }
+        Ok(())
+    }
+
     /// Shuts down the write end of this socket. This flushes
     /// any data that may not have been send.
     async fn shutdown(&mut self) -> std::io::Result<()>;
 }
 
+/// How much of a file [read_file_piece] reads at once
+pub const FILE_READ_SIZE: usize = 64 * 1024;
+
+/// Read at most `max_len` bytes (and at most [FILE_READ_SIZE]) of `file`,
+/// starting at `offset`, into a fresh [Piece].
+///
+/// This is a blocking read: it's meant for regular files, which are typically
+/// served from the page cache. Errors out with
+/// [std::io::ErrorKind::UnexpectedEof] if the file is shorter than expected.
+pub fn read_file_piece(file: &std::fs::File, offset: u64, max_len: u64) -> std::io::Result<Piece> {
+    use std::os::unix::fs::FileExt;
+
+    let read_len = std::cmp::min(max_len, FILE_READ_SIZE as u64) as usize;
+    let mut buf = vec![0u8; read_len];
+    let n = file.read_at(&mut buf[..], offset)?;
+    if n == 0 && read_len > 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "file is shorter than expected",
+        ));
+    }
+    buf.truncate(n);
+    Ok(buf.into())
+}
+
 #[cfg(all(test, not(feature = "miri")))]
 mod tests {
     use std::{cell::RefCell, rc::Rc};
@@ -192,6 +258,38 @@ mod tests {
             assert_eq!(&writer.bytes.borrow()[..], &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
         });
     }
+
+    #[test]
+    fn test_write_file_all() {
+        use std::io::Write;
+
+        let path =
+            std::env::temp_dir().join(format!("buffet-test-write-file-all-{}", std::process::id()));
+        let contents = (0..(crate::FILE_READ_SIZE * 2 + 100))
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(&contents)
+            .unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut out: Vec<u8> = Vec::new();
+        crate::start(async move {
+            let offset = 10;
+            let len = contents.len() as u64 - 20;
+            out.write_file_all(&file, offset, len).await.unwrap();
+            assert_eq!(&out[..], &contents[10..contents.len() - 10]);
+
+            // asking for more than the file has is an error
+            let mut out: Vec<u8> = Vec::new();
+            let res = out
+                .write_file_all(&file, 0, contents.len() as u64 + 1)
+                .await;
+            assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        });
+    }
 }
 
 pub trait IntoHalves: 'static {
@@ -201,3 +299,17 @@ pub trait IntoHalves: 'static {
     /// Split this into an owned read half and an owned write half.
     fn into_halves(self) -> (Self::Read, Self::Write);
 }
+
+/// Halves that were already split, e.g. to read from one before serving
+impl<R, W> IntoHalves for (R, W)
+where
+    R: ReadOwned + 'static,
+    W: WriteOwned + 'static,
+{
+    type Read = R;
+    type Write = W;
+
+    fn into_halves(self) -> (R, W) {
+        self
+    }
+}
@@ -0,0 +1,278 @@
+//! Bridges between tokio's poll-based IO traits and buffet's owned-buffer
+//! ones, so anything that speaks one can be used where the other is
+//! expected: in-memory duplex pipes, TLS streams from other stacks, etc.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
+
+use crate::{Buf, BufMut, IntoHalves, Piece, ReadOwned, WriteOwned};
+
+/// Lets any [AsyncRead] + [AsyncWrite] stream be split with
+/// [IntoHalves::into_halves], e.g. a [tokio::io::duplex] pipe or a TLS
+/// stream. The halves are [ReadOwned] and [WriteOwned] like any other
+/// [AsyncRead] and [AsyncWrite] type.
+///
+/// Both halves share the stream behind a lock, cf. [tokio::io::split].
+pub struct TokioIo<T>(pub T);
+
+impl<T> IntoHalves for TokioIo<T>
+where
+    T: AsyncRead + AsyncWrite + 'static,
+{
+    type Read = ReadHalf<T>;
+    type Write = WriteHalf<T>;
+
+    fn into_halves(self) -> (Self::Read, Self::Write) {
+        tokio::io::split(self.0)
+    }
+}
+
+type ReadFuture<R> = Pin<Box<dyn Future<Output = (R, io::Result<Buf>)>>>;
+
+enum ReadState<R> {
+    Idle(R),
+    Reading(ReadFuture<R>),
+    /// A read panicked, and took the reader with it
+    Poisoned,
+}
+
+/// An [AsyncRead] that reads from a [ReadOwned], one pool buffer at a time
+pub struct AsyncReadAdapter<R> {
+    state: ReadState<R>,
+    /// What the last read returned that didn't fit in the caller's buffer
+    leftover: Option<Buf>,
+}
+
+impl<R: ReadOwned + 'static> AsyncReadAdapter<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            state: ReadState::Idle(inner),
+            leftover: None,
+        }
+    }
+}
+
+// nothing is pinned in place: the read future is boxed
+impl<R> Unpin for AsyncReadAdapter<R> {}
+
+impl<R: ReadOwned + 'static> AsyncRead for AsyncReadAdapter<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            if let Some(leftover) = self.leftover.take() {
+                let n = leftover.len().min(buf.remaining());
+                buf.put_slice(&leftover[..n]);
+                if n < leftover.len() {
+                    self.leftover = Some(leftover.slice(n..));
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            match std::mem::replace(&mut self.state, ReadState::Poisoned) {
+                ReadState::Idle(mut inner) => {
+                    self.state = ReadState::Reading(Box::pin(async move {
+                        let res = match BufMut::alloc() {
+                            Ok(buf) => {
+                                let (res, buf) = inner.read_owned(buf).await;
+                                res.map(|n| buf.freeze().slice(..n))
+                            }
+                            Err(e) => Err(io::Error::other(e)),
+                        };
+                        (inner, res)
+                    }));
+                }
+                ReadState::Reading(mut fut) => {
+                    let (inner, res) = match fut.as_mut().poll(cx) {
+                        Poll::Ready(res) => res,
+                        Poll::Pending => {
+                            self.state = ReadState::Reading(fut);
+                            return Poll::Pending;
+                        }
+                    };
+                    self.state = ReadState::Idle(inner);
+                    let read = res?;
+                    if read.is_empty() {
+                        // EOF
+                        return Poll::Ready(Ok(()));
+                    }
+                    self.leftover = Some(read);
+                }
+                ReadState::Poisoned => return Poll::Ready(Err(poisoned())),
+            }
+        }
+    }
+}
+
+type WriteFuture<W> = Pin<Box<dyn Future<Output = (W, io::Result<()>)>>>;
+
+enum WriteState<W> {
+    Idle(W),
+    Writing(WriteFuture<W>),
+    /// A write panicked, and took the writer with it
+    Poisoned,
+}
+
+/// An [AsyncWrite] that writes to a [WriteOwned].
+///
+/// Writes are accepted as soon as they're copied, and go out in the
+/// background: the next write, flush or shutdown waits for them, and is
+/// where their errors show up.
+pub struct AsyncWriteAdapter<W> {
+    state: WriteState<W>,
+    shutting_down: bool,
+}
+
+impl<W: WriteOwned + 'static> AsyncWriteAdapter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            state: WriteState::Idle(inner),
+            shutting_down: false,
+        }
+    }
+
+    /// Waits for the write in flight, if any
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match std::mem::replace(&mut self.state, WriteState::Poisoned) {
+            WriteState::Idle(inner) => {
+                self.state = WriteState::Idle(inner);
+                Poll::Ready(Ok(()))
+            }
+            WriteState::Writing(mut fut) => match fut.as_mut().poll(cx) {
+                Poll::Ready((inner, res)) => {
+                    self.state = WriteState::Idle(inner);
+                    Poll::Ready(res)
+                }
+                Poll::Pending => {
+                    self.state = WriteState::Writing(fut);
+                    Poll::Pending
+                }
+            },
+            WriteState::Poisoned => Poll::Ready(Err(poisoned())),
+        }
+    }
+
+    /// Starts `op` on the writer, which must be idle
+    fn start<F>(&mut self, op: impl FnOnce(W) -> F)
+    where
+        F: Future<Output = (W, io::Result<()>)> + 'static,
+    {
+        let WriteState::Idle(inner) = std::mem::replace(&mut self.state, WriteState::Poisoned)
+        else {
+            unreachable!("writer is busy");
+        };
+        self.state = WriteState::Writing(Box::pin(op(inner)));
+    }
+}
+
+// nothing is pinned in place: the write future is boxed
+impl<W> Unpin for AsyncWriteAdapter<W> {}
+
+impl<W: WriteOwned + 'static> AsyncWrite for AsyncWriteAdapter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_idle(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let piece = Piece::from(buf.to_vec());
+        self.start(|mut inner| async move {
+            let res = inner.write_all_owned(piece).await;
+            (inner, res)
+        });
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_idle(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_idle(cx))?;
+        if !self.shutting_down {
+            self.shutting_down = true;
+            self.start(|mut inner| async move {
+                let res = inner.shutdown().await;
+                (inner, res)
+            });
+        }
+        self.poll_idle(cx)
+    }
+}
+
+fn poisoned() -> io::Error {
+    io::Error::other("a previous operation panicked")
+}
+
+#[cfg(all(test, not(feature = "miri")))]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{AsyncReadAdapter, AsyncWriteAdapter, TokioIo};
+    use crate::{IntoHalves, ReadOwned, WriteOwned};
+
+    #[test]
+    fn test_tokio_io_halves() {
+        crate::start(async move {
+            let (client, server) = tokio::io::duplex(64);
+            let (mut server_read, mut server_write) = TokioIo(server).into_halves();
+            let (mut client_read, mut client_write) = TokioIo(client).into_halves();
+
+            client_write.write_all_owned("ping").await.unwrap();
+            let (res, buf) = server_read.read_owned(vec![0u8; 16]).await;
+            assert_eq!(&buf[..res.unwrap()], b"ping");
+
+            server_write.write_all_owned("pong").await.unwrap();
+            WriteOwned::shutdown(&mut server_write).await.unwrap();
+            let mut read = Vec::new();
+            client_read.read_to_end(&mut read).await.unwrap();
+            assert_eq!(&read[..], b"pong");
+        });
+    }
+
+    #[test]
+    fn test_adapters() {
+        crate::start(async move {
+            let (write, read) = crate::pipe();
+            let mut write = AsyncWriteAdapter::new(write);
+            let mut read = AsyncReadAdapter::new(read);
+
+            let contents = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let reader = crate::spawn(async move {
+                // smaller than what each read returns
+                let mut buf = [0u8; 1000];
+                let mut read_all = Vec::new();
+                loop {
+                    let n = read.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    read_all.extend_from_slice(&buf[..n]);
+                }
+                read_all
+            });
+
+            for chunk in contents.chunks(7000) {
+                write.write_all(chunk).await.unwrap();
+            }
+            AsyncWriteExt::shutdown(&mut write).await.unwrap();
+            assert_eq!(reader.await.unwrap(), contents);
+        });
+    }
+}
@@ -6,7 +6,7 @@ use crate::{Piece, ReadOwned, WriteOwned};
 pub fn pipe() -> (PipeWrite, PipeRead) {
     let (tx, rx) = mpsc::channel(1);
     (
-        PipeWrite { tx },
+        PipeWrite { tx: Some(tx) },
         PipeRead {
             rx,
             state: Default::default(),
@@ -87,16 +87,26 @@ impl ReadOwned for PipeRead {
 }
 
 pub struct PipeWrite {
-    tx: mpsc::Sender<PipeEvent>,
+    // `None` after shutdown
+    tx: Option<mpsc::Sender<PipeEvent>>,
 }
 
 impl PipeWrite {
     /// Simulate a connection reset
     pub async fn reset(self) {
-        self.tx.send(PipeEvent::Reset).await.unwrap()
+        self.tx
+            .as_ref()
+            .expect("pipe was shut down")
+            .send(PipeEvent::Reset)
+            .await
+            .unwrap()
     }
 }
 
+fn broken_pipe() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "simulated broken pipe")
+}
+
 impl WriteOwned for PipeWrite {
     async fn write_owned(&mut self, buf: impl Into<Piece>) -> crate::BufResult<usize, Piece> {
         let buf = buf.into();
@@ -104,15 +114,19 @@ impl WriteOwned for PipeWrite {
             // ignore 0-length writes
         }
 
-        if self.tx.send(PipeEvent::Piece(buf.clone())).await.is_err() {
-            let err = std::io::Error::new(std::io::ErrorKind::BrokenPipe, "simulated broken pipe");
-            return (Err(err), buf);
+        let Some(tx) = self.tx.as_ref() else {
+            return (Err(broken_pipe()), buf);
+        };
+        if tx.send(PipeEvent::Piece(buf.clone())).await.is_err() {
+            return (Err(broken_pipe()), buf);
         }
 
         (Ok(buf.len()), buf)
     }
 
+    /// The reader gets EOF once it has read everything written so far
     async fn shutdown(&mut self) -> std::io::Result<()> {
+        self.tx = None;
         Ok(())
     }
 }
@@ -230,4 +244,24 @@ mod tests {
             }
         })
     }
+
+    #[test]
+    fn test_pipe_shutdown() {
+        crate::start(async move {
+            let (mut w, mut r) = pipe();
+
+            crate::spawn(async move {
+                w.write_all_owned("last words").await.unwrap();
+                w.shutdown().await.unwrap();
+                assert!(w.write_all_owned("too late").await.is_err());
+            });
+
+            let buf = vec![0u8; 256];
+            let (res, buf) = r.read_owned(buf).await;
+            assert_eq!(&buf[..res.unwrap()], b"last words");
+
+            let (res, _) = r.read_owned(buf).await;
+            assert_eq!(res.unwrap(), 0, "reached EOF");
+        })
+    }
 }
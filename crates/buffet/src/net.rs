@@ -12,6 +12,14 @@ mod net_noring;
 #[cfg(not(all(target_os = "linux", feature = "uring")))]
 pub use net_noring::*;
 
+#[cfg(unix)]
+pub mod handoff;
+
+#[cfg(unix)]
+mod shard;
+#[cfg(unix)]
+pub use shard::*;
+
 impl IntoHalves for tokio::net::TcpStream {
     type Read = tokio::net::tcp::OwnedReadHalf;
     type Write = tokio::net::tcp::OwnedWriteHalf;
@@ -0,0 +1,276 @@
+//! Passing sockets to another process over a Unix socket, for binary
+//! upgrades that don't drop connections: the new process receives the old
+//! one's listening sockets (and possibly established connections, along with
+//! whatever was already read from them) and starts accepting on them.
+//!
+//! Every item is sent as a 5-byte header (kind, then payload length as a
+//! big-endian u32) carrying the file descriptor as `SCM_RIGHTS` ancillary
+//! data, followed by the payload. An item of kind `END` terminates the list.
+//!
+//! Both [send] and [recv] block: they're meant to be called once, during an
+//! upgrade, with a peer that's actively taking part in it.
+
+use std::{
+    io::{self, Read, Write},
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+};
+
+use super::{TcpListener, TcpStream};
+
+const KIND_END: u8 = 0;
+const KIND_LISTENER: u8 = 1;
+const KIND_CONNECTION: u8 = 2;
+
+const HEADER_LEN: usize = 5;
+
+/// Something handed off to (or received from) another process
+pub enum Handoff {
+    /// A listening socket: the receiver should accept connections on it
+    Listener(TcpListener),
+
+    /// An established connection, and the bytes that were read from it but
+    /// not processed yet
+    Connection {
+        stream: TcpStream,
+        buffered: Vec<u8>,
+    },
+}
+
+/// Sends `items` over `sock`. The sender keeps its own copies of the sockets:
+/// it should stop using them, and close them once it's done.
+pub fn send(sock: &UnixStream, items: &[Handoff]) -> io::Result<()> {
+    for item in items {
+        let (kind, fd, payload) = match item {
+            Handoff::Listener(ln) => (KIND_LISTENER, ln.as_raw_fd(), &[][..]),
+            Handoff::Connection { stream, buffered } => {
+                (KIND_CONNECTION, stream.as_raw_fd(), &buffered[..])
+            }
+        };
+        let len = u32::try_from(payload.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "payload too large"))?;
+
+        let mut header = [0u8; HEADER_LEN];
+        header[0] = kind;
+        header[1..].copy_from_slice(&len.to_be_bytes());
+        send_with_fd(sock, &header, Some(fd))?;
+        (&*sock).write_all(payload)?;
+    }
+    send_with_fd(sock, &[KIND_END, 0, 0, 0, 0], None)
+}
+
+/// Receives everything a peer sent with [send]. Must be called from within
+/// a runtime created by [crate::start].
+pub fn recv(sock: &UnixStream) -> io::Result<Vec<Handoff>> {
+    let mut items = vec![];
+    loop {
+        let mut header = [0u8; HEADER_LEN];
+        let fd = recv_with_fd(sock, &mut header)?;
+        let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+
+        let kind = header[0];
+        if kind == KIND_END {
+            return Ok(items);
+        }
+        let Some(fd) = fd else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "handoff item without a file descriptor",
+            ));
+        };
+
+        let mut payload = vec![0u8; len];
+        (&*sock).read_exact(&mut payload)?;
+
+        match kind {
+            KIND_LISTENER => {
+                items.push(Handoff::Listener(TcpListener::from_std(
+                    std::net::TcpListener::from(fd),
+                )?));
+            }
+            KIND_CONNECTION => {
+                items.push(Handoff::Connection {
+                    stream: stream_from_std(std::net::TcpStream::from(fd))?,
+                    buffered: payload,
+                });
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown handoff item kind {kind}"),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "uring"))]
+fn stream_from_std(stream: std::net::TcpStream) -> io::Result<TcpStream> {
+    TcpStream::from_std(stream)
+}
+
+#[cfg(not(all(target_os = "linux", feature = "uring")))]
+fn stream_from_std(stream: std::net::TcpStream) -> io::Result<TcpStream> {
+    stream.set_nonblocking(true)?;
+    TcpStream::from_std(stream)
+}
+
+/// Room for a single `SCM_RIGHTS` control message carrying one fd, suitably
+/// aligned for `cmsghdr`
+type CmsgBuf = [u64; 4];
+
+fn send_with_fd(sock: &UnixStream, bytes: &[u8], fd: Option<RawFd>) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: bytes.as_ptr() as *mut libc::c_void,
+        iov_len: bytes.len(),
+    };
+    let mut cmsg_buf: CmsgBuf = [0; 4];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if let Some(fd) = fd {
+        let space = unsafe { libc::CMSG_SPACE(size_of::<RawFd>() as _) } as usize;
+        assert!(space <= size_of::<CmsgBuf>());
+        msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+        msg.msg_controllen = space as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<RawFd>() as _) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+        }
+    }
+
+    let sent = loop {
+        let ret = unsafe { libc::sendmsg(sock.as_raw_fd(), &msg, 0) };
+        if ret >= 0 {
+            break ret as usize;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    };
+    // the fd went out with the first byte, the rest can follow as plain data
+    (&*sock).write_all(&bytes[sent..])
+}
+
+fn recv_with_fd(sock: &UnixStream, buf: &mut [u8]) -> io::Result<Option<OwnedFd>> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut cmsg_buf: CmsgBuf = [0; 4];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+    msg.msg_controllen = size_of::<CmsgBuf>() as _;
+
+    #[cfg(target_os = "linux")]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(target_os = "linux"))]
+    let flags = 0;
+
+    let received = loop {
+        let ret = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, flags) };
+        if ret >= 0 {
+            break ret as usize;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    };
+    if received == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let mut fds = vec![];
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let data_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..data_len / size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "too many file descriptors in handoff item",
+        ));
+    }
+
+    (&*sock).read_exact(&mut buf[received..])?;
+    Ok(fds.into_iter().next())
+}
+
+#[cfg(all(test, not(feature = "miri")))]
+mod tests {
+    use std::{io::Write, os::unix::net::UnixStream};
+
+    use super::{recv, send, Handoff};
+    use crate::{
+        io::{IntoHalves, ReadOwned},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn test_handoff() {
+        async fn test_handoff_inner() {
+            let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            client.write_all(b"world").unwrap();
+
+            let (a, b) = UnixStream::pair().unwrap();
+            send(
+                &a,
+                &[
+                    Handoff::Listener(listener),
+                    Handoff::Connection {
+                        stream,
+                        buffered: b"hello ".to_vec(),
+                    },
+                ],
+            )
+            .unwrap();
+
+            let mut items = recv(&b).unwrap().into_iter();
+            let Some(Handoff::Listener(listener)) = items.next() else {
+                panic!("expected a listener");
+            };
+            assert_eq!(listener.local_addr().unwrap(), addr);
+            let Some(Handoff::Connection { stream, buffered }) = items.next() else {
+                panic!("expected a connection");
+            };
+            assert_eq!(buffered, b"hello ");
+            assert!(items.next().is_none());
+
+            let (mut r, _w) = stream.into_halves();
+            let (res, buf) = r.read_owned(vec![0u8; 16]).await;
+            assert_eq!(&buf[..res.unwrap()], b"world");
+
+            // the received listener still accepts connections
+            let _client2 = std::net::TcpStream::connect(addr).unwrap();
+            listener.accept().await.unwrap();
+        }
+        crate::start(async move { test_handoff_inner().await });
+    }
+}
@@ -1,4 +1,7 @@
-use std::net::SocketAddr;
+use std::{
+    net::SocketAddr,
+    os::fd::{AsRawFd, RawFd},
+};
 use tokio::net::{TcpListener as TokListener, TcpStream as TokStream};
 
 pub type TcpStream = TokStream;
@@ -16,6 +19,12 @@ impl TcpListener {
         Ok(Self { tok })
     }
 
+    pub fn from_std(listener: std::net::TcpListener) -> std::io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        let tok = TokListener::from_std(listener)?;
+        Ok(Self { tok })
+    }
+
     pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
         self.tok.local_addr()
     }
@@ -27,3 +36,9 @@ impl TcpListener {
         })
     }
 }
+
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.tok.as_raw_fd()
+    }
+}
@@ -1,16 +1,23 @@
 use std::{
+    cell::{Cell, RefCell},
+    future::poll_fn,
     mem::ManuallyDrop,
     net::SocketAddr,
-    os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
+    os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
     rc::Rc,
 };
 
-use io_uring::opcode::{Accept, Read, Write};
+use io_uring::{
+    cqueue,
+    opcode::{Accept, AcceptMulti, Read, RecvMulti, Write},
+};
+use luring::{BufRing, MultishotOp};
 use nix::errno::Errno;
 
 use crate::{
     get_ring,
     io::{IntoHalves, ReadOwned, WriteOwned},
+    uring::{get_buf_ring, multishot_enabled},
     BufResult, IoBufMut, Piece,
 };
 
@@ -41,6 +48,26 @@ impl TcpStream {
         cqe.error_for_errno()?;
         Ok(Self { fd })
     }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        let socket = ManuallyDrop::new(unsafe { socket2::Socket::from_raw_fd(self.fd) });
+        let addr = socket.local_addr()?;
+        Ok(addr.as_socket().unwrap())
+    }
+
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        let socket = ManuallyDrop::new(unsafe { socket2::Socket::from_raw_fd(self.fd) });
+        let addr = socket.peer_addr()?;
+        Ok(addr.as_socket().unwrap())
+    }
+
+    pub fn from_std(stream: std::net::TcpStream) -> std::io::Result<Self> {
+        // io_uring does the waiting for us, the socket itself should block
+        stream.set_nonblocking(false)?;
+        Ok(Self {
+            fd: stream.into_raw_fd(),
+        })
+    }
 }
 
 impl Drop for TcpStream {
@@ -53,6 +80,12 @@ impl Drop for TcpStream {
     }
 }
 
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
 impl IntoRawFd for TcpStream {
     fn into_raw_fd(self) -> RawFd {
         let fd = self.fd;
@@ -63,6 +96,12 @@ impl IntoRawFd for TcpStream {
 
 pub struct TcpListener {
     fd: i32,
+
+    /// The multishot accept op, if one is armed
+    multishot: RefCell<Option<MultishotOp<cqueue::Entry>>>,
+
+    /// Cleared if the kernel turns down multishot accept
+    multishot_supported: Cell<bool>,
 }
 
 impl TcpListener {
@@ -86,7 +125,20 @@ impl TcpListener {
         let fd = socket.as_raw_fd();
         std::mem::forget(socket);
 
-        Ok(Self { fd })
+        Ok(Self::from_fd(fd))
+    }
+
+    pub fn from_std(listener: std::net::TcpListener) -> std::io::Result<Self> {
+        listener.set_nonblocking(false)?;
+        Ok(Self::from_fd(listener.into_raw_fd()))
+    }
+
+    fn from_fd(fd: i32) -> Self {
+        Self {
+            fd,
+            multishot: Default::default(),
+            multishot_supported: Cell::new(multishot_enabled()),
+        }
     }
 
     pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
@@ -95,7 +147,60 @@ impl TcpListener {
         Ok(addr.as_socket().unwrap())
     }
 
+    /// Accepts a connection. Uses a multishot accept op when possible, which
+    /// keeps going between calls: only one task should be accepting at a time.
     pub async fn accept(&self) -> std::io::Result<(TcpStream, SocketAddr)> {
+        if self.multishot_supported.get() {
+            match self.accept_multishot().await {
+                Some(res) => return res,
+                None => {
+                    tracing::debug!("multishot accept unsupported, falling back to one-shot");
+                    self.multishot_supported.set(false);
+                }
+            }
+        }
+        self.accept_oneshot().await
+    }
+
+    /// Returns `None` if the kernel doesn't do multishot accept (before 5.19)
+    async fn accept_multishot(&self) -> Option<std::io::Result<(TcpStream, SocketAddr)>> {
+        loop {
+            let cqe = poll_fn(|cx| {
+                let mut op = self.multishot.borrow_mut();
+                let op = op.get_or_insert_with(|| {
+                    let sqe = AcceptMulti::new(io_uring::types::Fd(self.fd)).build();
+                    get_ring().push_multishot(sqe, |cqe: cqueue::Entry| {
+                        // accepted after we stopped listening
+                        if cqe.result() >= 0 {
+                            unsafe { libc::close(cqe.result()) };
+                        }
+                    })
+                });
+                op.poll_next(cx)
+            })
+            .await;
+
+            let Some(cqe) = cqe else {
+                // the kernel stopped it (after an error, for example): re-arm
+                self.multishot.borrow_mut().take();
+                continue;
+            };
+            let fd = match cqe.error_for_errno() {
+                Ok(fd) => fd,
+                Err(Errno::EINVAL) => {
+                    self.multishot.borrow_mut().take();
+                    return None;
+                }
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            // multishot accept has nowhere to put peer addresses
+            let stream = TcpStream { fd };
+            return Some(stream.peer_addr().map(|addr| (stream, addr)));
+        }
+    }
+
+    async fn accept_oneshot(&self) -> std::io::Result<(TcpStream, SocketAddr)> {
         let u = get_ring();
         struct AcceptUserData {
             sockaddr_storage: libc::sockaddr_storage,
@@ -126,21 +231,62 @@ impl TcpListener {
     }
 }
 
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
 // TODO: fix about the lifetime of TcpStream, closing
 // the underlying fd, in-flight operations etc.
-pub struct TcpReadHalf(Rc<TcpStream>);
+pub struct TcpReadHalf {
+    stream: Rc<TcpStream>,
 
-impl ReadOwned for TcpReadHalf {
-    async fn read_owned<B: IoBufMut>(&mut self, mut buf: B) -> BufResult<usize, B> {
+    /// Provided buffers for multishot recv, if we're using them
+    buf_ring: Option<Rc<BufRing>>,
+
+    /// The multishot recv op, if one is armed
+    recv: Option<MultishotOp<cqueue::Entry>>,
+
+    /// Received, but not read yet
+    pending: Option<PendingRecv>,
+}
+
+/// Part of a provided buffer that's yet to be read
+struct PendingRecv {
+    bid: u16,
+    offset: usize,
+    len: usize,
+}
+
+impl TcpReadHalf {
+    fn new(stream: Rc<TcpStream>) -> Self {
+        Self {
+            stream,
+            buf_ring: get_buf_ring(),
+            recv: None,
+            pending: None,
+        }
+    }
+
+    async fn read_oneshot<B: IoBufMut>(&mut self, mut buf: B) -> BufResult<usize, B> {
         let sqe = Read::new(
-            io_uring::types::Fd(self.0.fd),
+            io_uring::types::Fd(self.stream.fd),
             buf.io_buf_mut_stable_mut_ptr(),
             buf.io_buf_mut_capacity() as u32,
         )
         .build();
         tracing::trace!(
             "submitting read_owned, reading from fd {} to {:p} with capacity {}",
-            self.0.fd,
+            self.stream.fd,
             buf.io_buf_mut_stable_mut_ptr(),
             buf.io_buf_mut_capacity()
         );
@@ -151,6 +297,156 @@ impl ReadOwned for TcpReadHalf {
         };
         (Ok(ret as usize), buf)
     }
+
+    async fn readv_oneshot<B: IoBufMut>(&mut self, mut bufs: Vec<B>) -> BufResult<usize, Vec<B>> {
+        use io_uring::opcode::Readv;
+        use libc::iovec;
+
+        let iovecs = bufs
+            .iter_mut()
+            .map(|buf| iovec {
+                iov_base: buf.io_buf_mut_stable_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.io_buf_mut_capacity(),
+            })
+            .collect::<Vec<_>>();
+        let sqe = Readv::new(
+            io_uring::types::Fd(self.stream.fd),
+            iovecs.as_ptr(),
+            iovecs.len() as u32,
+        )
+        .build();
+        let cqe = get_ring().push(sqe).await;
+        drop(iovecs);
+        let ret = match cqe.error_for_errno() {
+            Ok(ret) => ret,
+            Err(e) => return (Err(std::io::Error::from(e)), bufs),
+        };
+        (Ok(ret as usize), bufs)
+    }
+
+    /// Copies what the multishot recv op got into `bufs`, waiting for it to
+    /// get something if needed. Returns `None` if we should do a one-shot
+    /// read instead.
+    async fn recv_into<B: IoBufMut>(
+        &mut self,
+        ring: &Rc<BufRing>,
+        bufs: &mut [B],
+    ) -> std::io::Result<Option<usize>> {
+        loop {
+            if self.pending.is_some() {
+                let mut total = 0;
+                for buf in bufs.iter_mut() {
+                    let Some(pending) = &mut self.pending else {
+                        break;
+                    };
+                    let n = std::cmp::min(pending.len, buf.io_buf_mut_capacity());
+                    unsafe {
+                        let src =
+                            &ring.buf(pending.bid, pending.offset + pending.len)[pending.offset..];
+                        std::ptr::copy_nonoverlapping(
+                            src.as_ptr(),
+                            buf.io_buf_mut_stable_mut_ptr(),
+                            n,
+                        );
+                    }
+                    pending.offset += n;
+                    pending.len -= n;
+                    total += n;
+                    if pending.len == 0 {
+                        ring.recycle(pending.bid);
+                        self.pending = None;
+                    }
+                }
+                return Ok(Some(total));
+            }
+
+            let recv = self.recv.get_or_insert_with(|| {
+                let sqe = RecvMulti::new(io_uring::types::Fd(self.stream.fd), ring.bgid()).build();
+                let ring = ring.clone();
+                get_ring().push_multishot(sqe, move |cqe: cqueue::Entry| {
+                    if let Some(bid) = cqueue::buffer_select(cqe.flags()) {
+                        ring.recycle(bid);
+                    }
+                })
+            });
+            let Some(cqe) = recv.next().await else {
+                // the kernel stopped it, after running out of buffers for
+                // example: re-arm
+                self.recv = None;
+                continue;
+            };
+
+            let bid = cqueue::buffer_select(cqe.flags());
+            let n = match cqe.error_for_errno() {
+                Ok(n) => n as usize,
+                Err(Errno::ENOBUFS) => {
+                    // other connections are holding on to every buffer:
+                    // don't spin waiting for one
+                    self.recv = None;
+                    return Ok(None);
+                }
+                Err(Errno::EINVAL) => {
+                    tracing::debug!("multishot recv unsupported, falling back to one-shot reads");
+                    self.buf_ring = None;
+                    self.recv = None;
+                    return Ok(None);
+                }
+                Err(e) => return Err(std::io::Error::from(e)),
+            };
+            match bid {
+                Some(bid) if n > 0 => {
+                    self.pending = Some(PendingRecv {
+                        bid,
+                        offset: 0,
+                        len: n,
+                    })
+                }
+                Some(bid) => {
+                    ring.recycle(bid);
+                    return Ok(Some(0));
+                }
+                // EOF
+                None => return Ok(Some(0)),
+            }
+        }
+    }
+}
+
+impl ReadOwned for TcpReadHalf {
+    /// With provided buffers, a single multishot recv op keeps receiving
+    /// into buffers of the thread-local [BufRing] for as long as the
+    /// connection lives, and reads copy out of them.
+    async fn read_owned<B: IoBufMut>(&mut self, mut buf: B) -> BufResult<usize, B> {
+        let Some(ring) = self.buf_ring.clone() else {
+            return self.read_oneshot(buf).await;
+        };
+        match self.recv_into(&ring, std::slice::from_mut(&mut buf)).await {
+            Ok(Some(n)) => (Ok(n), buf),
+            Ok(None) => self.read_oneshot(buf).await,
+            Err(e) => (Err(e), buf),
+        }
+    }
+
+    /// One provided buffer can fill several of `bufs`, or a single `readv`
+    /// does it without them.
+    async fn readv_owned<B: IoBufMut>(&mut self, mut bufs: Vec<B>) -> BufResult<usize, Vec<B>> {
+        let Some(ring) = self.buf_ring.clone() else {
+            return self.readv_oneshot(bufs).await;
+        };
+        match self.recv_into(&ring, &mut bufs).await {
+            Ok(Some(n)) => (Ok(n), bufs),
+            Ok(None) => self.readv_oneshot(bufs).await,
+            Err(e) => (Err(e), bufs),
+        }
+    }
+}
+
+impl Drop for TcpReadHalf {
+    fn drop(&mut self) {
+        if let (Some(pending), Some(ring)) = (self.pending.take(), &self.buf_ring) {
+            ring.recycle(pending.bid);
+        }
+    }
 }
 
 pub struct TcpWriteHalf(Rc<TcpStream>);
@@ -176,8 +472,8 @@ impl WriteOwned for TcpWriteHalf {
     // TODO: implement writev
 
     async fn writev_owned(&mut self, list: &crate::PieceList) -> std::io::Result<usize> {
-        use libc::iovec;
         use io_uring::opcode::Writev;
+        use libc::iovec;
 
         let mut iovecs = Vec::with_capacity(list.pieces.len());
         for piece in &list.pieces {
@@ -190,12 +486,7 @@ impl WriteOwned for TcpWriteHalf {
         let iov_cnt = iovecs.len();
         std::mem::forget(iovecs); // FIXME: don't leak memory
 
-        let sqe = Writev::new(
-            io_uring::types::Fd(self.0.fd),
-            iov_ptr,
-            iov_cnt as u32,
-        )
-        .build();
+        let sqe = Writev::new(io_uring::types::Fd(self.0.fd), iov_ptr, iov_cnt as u32).build();
 
         let cqe = get_ring().push(sqe).await;
         let ret = match cqe.error_for_errno() {
@@ -205,6 +496,70 @@ impl WriteOwned for TcpWriteHalf {
         Ok(ret as usize)
     }
 
+    /// Moves the file's contents to the socket with two `splice` operations
+    /// (file to pipe, pipe to socket), so they never go through userspace.
+    async fn write_file_all(
+        &mut self,
+        file: &std::fs::File,
+        mut offset: u64,
+        len: u64,
+    ) -> std::io::Result<()> {
+        use io_uring::opcode::Splice;
+
+        // splice needs one end of each transfer to be a pipe
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let (pipe_r, pipe_w) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        let mut remaining = len;
+        while remaining > 0 {
+            // don't ask for more than the default pipe capacity, or the splice
+            // would block until the other end drains it.
+            let chunk_len = std::cmp::min(remaining, crate::FILE_READ_SIZE as u64) as u32;
+            let sqe = Splice::new(
+                io_uring::types::Fd(file.as_raw_fd()),
+                offset as i64,
+                io_uring::types::Fd(pipe_w.as_raw_fd()),
+                -1,
+                chunk_len,
+            )
+            .build();
+            let n = get_ring().push(sqe).await.error_for_errno()? as u32;
+            if n == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "file is shorter than expected",
+                ));
+            }
+            offset += n as u64;
+            remaining -= n as u64;
+
+            let mut in_pipe = n;
+            while in_pipe > 0 {
+                let sqe = Splice::new(
+                    io_uring::types::Fd(pipe_r.as_raw_fd()),
+                    -1,
+                    io_uring::types::Fd(self.0.fd),
+                    -1,
+                    in_pipe,
+                )
+                .build();
+                let n = get_ring().push(sqe).await.error_for_errno()? as u32;
+                if n == 0 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::WriteZero,
+                        "write zero",
+                    ));
+                }
+                in_pipe -= n;
+            }
+        }
+        Ok(())
+    }
+
     async fn shutdown(&mut self) -> std::io::Result<()> {
         tracing::debug!("requesting shutdown");
         let sqe =
@@ -221,7 +576,7 @@ impl IntoHalves for TcpStream {
 
     fn into_halves(self) -> (Self::Read, Self::Write) {
         let self_rc = Rc::new(self);
-        (TcpReadHalf(self_rc.clone()), TcpWriteHalf(self_rc))
+        (TcpReadHalf::new(self_rc.clone()), TcpWriteHalf(self_rc))
     }
 }
 
@@ -296,4 +651,89 @@ mod tests {
         }
         crate::start(async move { test_accept_inner().await });
     }
+
+    #[test]
+    fn test_accept_and_read_many() {
+        async fn test_accept_and_read_many_inner() {
+            let listener = super::TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            // more than a provided buffer's worth, so reads get split
+            let contents = (0..40_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let client = std::thread::spawn({
+                let contents = contents.clone();
+                move || {
+                    use std::io::Write;
+
+                    for _ in 0..3 {
+                        let mut sock = std::net::TcpStream::connect(addr).unwrap();
+                        sock.write_all(&contents).unwrap();
+                    }
+                }
+            });
+
+            for _ in 0..3 {
+                let (stream, _addr) = listener.accept().await.unwrap();
+                let (mut r, _w) = stream.into_halves();
+                let mut read = Vec::new();
+                loop {
+                    let (res, buf) = r.read_owned(vec![0u8; 4096]).await;
+                    let n = res.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    read.extend_from_slice(&buf[..n]);
+                }
+                assert_eq!(read, contents);
+            }
+            client.join().unwrap();
+        }
+        crate::start(async move { test_accept_and_read_many_inner().await });
+    }
+
+    #[test]
+    fn test_write_file_all_splice() {
+        async fn test_write_file_all_splice_inner() {
+            use std::io::Write;
+
+            let path =
+                std::env::temp_dir().join(format!("buffet-test-splice-{}", std::process::id()));
+            let contents = (0..(crate::FILE_READ_SIZE * 3 + 17))
+                .map(|i| (i % 253) as u8)
+                .collect::<Vec<_>>();
+            std::fs::File::create(&path)
+                .unwrap()
+                .write_all(&contents)
+                .unwrap();
+            let file = std::fs::File::open(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            let listener = super::TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let expected = contents[5..].to_vec();
+            let client = std::thread::spawn(move || {
+                use std::io::Read;
+
+                let mut sock = std::net::TcpStream::connect(addr).unwrap();
+                let mut buf = vec![];
+                sock.read_to_end(&mut buf).unwrap();
+                assert_eq!(buf, expected);
+            });
+
+            let (stream, _addr) = listener.accept().await.unwrap();
+            let (_r, mut w) = stream.into_halves();
+            w.write_file_all(&file, 5, contents.len() as u64 - 5)
+                .await
+                .unwrap();
+            w.shutdown().await.unwrap();
+
+            client.join().unwrap();
+        }
+        crate::start(async move { test_write_file_all_splice_inner().await });
+    }
 }
@@ -0,0 +1,157 @@
+//! Runs one single-threaded runtime per core, each with its own listening
+//! socket bound with `SO_REUSEPORT`, so the kernel spreads incoming
+//! connections across them.
+
+use std::{future::Future, io, net::SocketAddr, num::NonZeroUsize, sync::Arc, thread};
+
+use super::TcpListener;
+
+/// How many shards to run, and how their listening sockets are set up
+#[derive(Debug, Clone)]
+pub struct ShardConf {
+    /// How many shards (threads, runtimes and listening sockets) to run.
+    /// Defaults to [thread::available_parallelism].
+    pub shards: NonZeroUsize,
+
+    /// The listen backlog of each socket
+    pub backlog: i32,
+}
+
+impl Default for ShardConf {
+    fn default() -> Self {
+        Self {
+            shards: thread::available_parallelism().unwrap_or(NonZeroUsize::MIN),
+            backlog: 1024,
+        }
+    }
+}
+
+/// Running shards, cf. [serve_sharded]
+pub struct Shards<T> {
+    local_addr: SocketAddr,
+    threads: Vec<thread::JoinHandle<T>>,
+}
+
+impl<T> Shards<T> {
+    /// The address every shard listens on. Useful when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Waits for every shard to return, and returns what they returned, in
+    /// shard order. Panics if any shard panicked.
+    pub fn join(self) -> Vec<T> {
+        self.threads
+            .into_iter()
+            .map(|t| t.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+            .collect()
+    }
+}
+
+/// Binds `conf.shards` listening sockets to `addr` with `SO_REUSEPORT`, then
+/// starts a thread for each, with its own runtime (cf. [crate::start]), that
+/// runs `f(shard_index, listener)`.
+///
+/// All sockets are bound before any thread starts, so binding errors are
+/// returned here, and binding to port 0 gets every shard the same port.
+pub fn serve_sharded<F, Fut>(
+    addr: SocketAddr,
+    conf: ShardConf,
+    f: F,
+) -> io::Result<Shards<Fut::Output>>
+where
+    F: Fn(usize, TcpListener) -> Fut + Send + Sync + 'static,
+    Fut: Future,
+    Fut::Output: Send + 'static,
+{
+    let mut addr = addr;
+    let mut listeners = Vec::with_capacity(conf.shards.get());
+    for _ in 0..conf.shards.get() {
+        let listener = bind_reuse_port(addr, conf.backlog)?;
+        // with port 0, the first socket picks the port the others share
+        addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+
+    let f = Arc::new(f);
+    let threads = listeners
+        .into_iter()
+        .enumerate()
+        .map(|(index, listener)| {
+            let f = f.clone();
+            thread::Builder::new()
+                .name(format!("buffet-shard-{index}"))
+                .spawn(move || {
+                    crate::start(async move {
+                        let listener = TcpListener::from_std(listener)
+                            .expect("could not register listener with the runtime");
+                        f(index, listener).await
+                    })
+                })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    Ok(Shards {
+        local_addr: addr,
+        threads,
+    })
+}
+
+fn bind_reuse_port(addr: SocketAddr, backlog: i32) -> io::Result<std::net::TcpListener> {
+    let addr: socket2::SockAddr = addr.into();
+    let socket = socket2::Socket::new(addr.domain(), socket2::Type::STREAM, None)?;
+    socket.set_nodelay(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr)?;
+    socket.listen(backlog)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, io::Read, num::NonZeroUsize};
+
+    use crate::io::{IntoHalves, WriteOwned};
+
+    use super::{serve_sharded, ShardConf};
+
+    #[test]
+    fn test_serve_sharded() {
+        let shards = serve_sharded(
+            "127.0.0.1:0".parse().unwrap(),
+            ShardConf {
+                shards: NonZeroUsize::new(4).unwrap(),
+                ..Default::default()
+            },
+            |index, listener| async move {
+                // every shard answers one connection with its index
+                let (stream, _) = listener.accept().await.unwrap();
+                let (_r, mut w) = stream.into_halves();
+                w.write_all_owned(format!("{index}").into_bytes())
+                    .await
+                    .unwrap();
+                index
+            },
+        )
+        .unwrap();
+        let addr = shards.local_addr();
+        assert_ne!(addr.port(), 0);
+
+        // the kernel picks a shard for each connection, so keep connecting
+        // until every shard has had one
+        let mut seen = HashSet::new();
+        while seen.len() < 4 {
+            let mut sock = std::net::TcpStream::connect(addr).unwrap();
+            // connections queued on a shard that's done get reset
+            let mut index = String::new();
+            if sock.read_to_string(&mut index).is_ok() {
+                if let Ok(index) = index.parse::<usize>() {
+                    seen.insert(index);
+                }
+            }
+        }
+
+        assert_eq!(shards.join(), vec![0, 1, 2, 3]);
+    }
+}
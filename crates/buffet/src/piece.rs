@@ -14,6 +14,9 @@ use crate::{Roll, RollStr};
 
 /// A piece of data (arbitrary bytes) with a stable address, suitable for
 /// passing to the kernel (io_uring writes).
+///
+/// [PieceCore::Inline] pieces hold their bytes themselves: those stay put as
+/// long as the piece does, e.g. while a write owns it.
 #[derive(Clone)]
 pub enum Piece {
     Full {
@@ -48,6 +51,32 @@ impl Piece {
             core: PieceCore::Static(&[]),
         }
     }
+
+    /// A piece of constant data, usable in `const` items
+    pub const fn from_static(bytes: &'static [u8]) -> Self {
+        Self::Full {
+            core: PieceCore::Static(bytes),
+        }
+    }
+
+    /// Copies `bytes` into a new piece, inline if they're at most
+    /// [PIECE_INLINE_CAP] long, into a `Vec` otherwise
+    pub fn copy_from_slice(bytes: &[u8]) -> Self {
+        match InlineBytes::new(bytes) {
+            Some(inline) => PieceCore::Inline(inline).into(),
+            None => bytes.to_vec().into(),
+        }
+    }
+
+    /// Formats `args` into a new piece, inline if the result is at most
+    /// [PIECE_INLINE_CAP] long, e.g. `Piece::from_fmt(format_args!("{len:x}\r\n"))`
+    pub fn from_fmt(args: fmt::Arguments<'_>) -> Self {
+        let mut inline = InlineBytes::default();
+        match fmt::Write::write_fmt(&mut inline, args) {
+            Ok(()) => PieceCore::Inline(inline).into(),
+            Err(_) => fmt::format(args).into_bytes().into(),
+        }
+    }
 }
 
 #[derive(Clone, Hash)]
@@ -56,6 +85,70 @@ pub enum PieceCore {
     Vec(Rc<Vec<u8>>),
     Roll(Roll),
     HeaderName(HeaderName),
+    Inline(InlineBytes),
+}
+
+/// How many bytes [PieceCore::Inline] can hold: as many as fit without
+/// making [PieceCore] (and [Piece]) any bigger. Enough for a date, a
+/// content-length, a chunk size line.
+pub const PIECE_INLINE_CAP: usize = 38;
+
+/// A few bytes, stored in place instead of on the heap or in the buffer
+/// pool, cf. [Piece::copy_from_slice] and [Piece::from_fmt]
+#[derive(Clone, Copy)]
+pub struct InlineBytes {
+    len: u8,
+    bytes: [u8; PIECE_INLINE_CAP],
+}
+
+impl Default for InlineBytes {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            bytes: [0; PIECE_INLINE_CAP],
+        }
+    }
+}
+
+impl InlineBytes {
+    /// Returns `None` if `bytes` is longer than [PIECE_INLINE_CAP]
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        let mut inline = Self::default();
+        inline.push(bytes).then_some(inline)
+    }
+
+    /// Appends `bytes`, unless they don't fit
+    fn push(&mut self, bytes: &[u8]) -> bool {
+        let len = self.len as usize;
+        let Some(dst) = self.bytes.get_mut(len..len + bytes.len()) else {
+            return false;
+        };
+        dst.copy_from_slice(bytes);
+        self.len += bytes.len() as u8;
+        true
+    }
+}
+
+impl AsRef<[u8]> for InlineBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+impl Hash for InlineBytes {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_ref().hash(state)
+    }
+}
+
+impl fmt::Write for InlineBytes {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.push(s.as_bytes()) {
+            Ok(())
+        } else {
+            Err(fmt::Error)
+        }
+    }
 }
 
 impl<T> From<T> for Piece
@@ -146,6 +239,7 @@ impl AsRef<[u8]> for PieceCore {
             PieceCore::Vec(vec) => vec.as_ref(),
             PieceCore::Roll(roll) => roll.as_ref(),
             PieceCore::HeaderName(name) => name.as_str().as_bytes(),
+            PieceCore::Inline(inline) => inline.as_ref(),
         }
     }
 }
@@ -392,7 +486,7 @@ impl From<RollStr> for PieceStr {
 
 #[cfg(test)]
 mod tests {
-    use crate::{Piece, PieceCore};
+    use crate::{Piece, PieceCore, PIECE_INLINE_CAP};
 
     #[test]
     fn test_slice() {
@@ -422,4 +516,59 @@ mod tests {
         assert_eq!(&first_name[..], "".as_bytes());
         assert_eq!(&last_name[..], "".as_bytes());
     }
+
+    #[test]
+    fn test_inline() {
+        let piece = Piece::copy_from_slice(b"content-length");
+        assert!(matches!(
+            piece,
+            Piece::Full {
+                core: PieceCore::Inline(_)
+            }
+        ));
+        let (left, right) = piece.split_at(7);
+        assert_eq!(&left[..], b"content");
+        assert_eq!(&right[..], b"-length");
+
+        let long = [b'a'; PIECE_INLINE_CAP + 1];
+        let piece = Piece::copy_from_slice(&long[..PIECE_INLINE_CAP]);
+        assert!(matches!(
+            piece,
+            Piece::Full {
+                core: PieceCore::Inline(_)
+            }
+        ));
+        let piece = Piece::copy_from_slice(&long);
+        assert!(matches!(
+            piece,
+            Piece::Full {
+                core: PieceCore::Vec(_)
+            }
+        ));
+        assert_eq!(&piece[..], &long[..]);
+
+        let piece = Piece::from_fmt(format_args!("{:x}\r\n", 4096));
+        assert!(matches!(
+            piece,
+            Piece::Full {
+                core: PieceCore::Inline(_)
+            }
+        ));
+        assert_eq!(&piece[..], b"1000\r\n");
+        let piece = Piece::from_fmt(format_args!("{}", "b".repeat(PIECE_INLINE_CAP + 1)));
+        assert!(matches!(
+            piece,
+            Piece::Full {
+                core: PieceCore::Vec(_)
+            }
+        ));
+        assert_eq!(piece.len(), PIECE_INLINE_CAP + 1);
+
+        const CRLF: Piece = Piece::from_static(b"\r\n");
+        assert_eq!(&CRLF[..], b"\r\n");
+
+        // inline pieces don't make the others any bigger
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(std::mem::size_of::<PieceCore>(), 40);
+    }
 }
@@ -1,6 +1,6 @@
 use std::{
     borrow::Cow,
-    cell::UnsafeCell,
+    cell::{Cell, UnsafeCell},
     fmt::{Debug, Formatter},
     hash::{Hash, Hasher},
     iter::Enumerate,
@@ -27,6 +27,21 @@ use crate::{Buf, BufMut, BUF_SIZE};
 
 type Result<T, E = crate::Error> = std::result::Result<T, E>;
 
+thread_local! {
+    static NUM_BYTES_COPIED: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Returns how many bytes [RollMut]s on this thread have copied so far when
+/// growing or compacting their storage. Useful to check that a read path
+/// hands out slices of its read buffer rather than copies.
+pub fn num_bytes_copied() -> u64 {
+    NUM_BYTES_COPIED.with(|n| n.get())
+}
+
+fn record_copy(len: usize) {
+    NUM_BYTES_COPIED.with(|n| n.set(n.get() + len as u64));
+}
+
 /// A "rolling buffer". Uses either one [BufMut] or a `Box<[u8]>` for storage.
 /// This buffer never grows, but it can be split, and it can be reallocated so
 /// it regains its initical capacity, minus the length of the filled part.
@@ -160,6 +175,7 @@ impl RollMut {
         };
         let dst_slice = bs.slice_mut(self.len() as u32);
         dst_slice.copy_from_slice(&self[..]);
+        record_copy(self.len());
         let next_storage = StorageMut::Box(bs);
 
         self.storage = next_storage;
@@ -174,6 +190,7 @@ impl RollMut {
     pub fn compact(&mut self) -> Result<()> {
         assert!(self.len() != self.storage_size());
         tracing::trace!("compacting");
+        record_copy(self.len());
 
         let next_storage = match &self.storage {
             StorageMut::Buf(bm) => {
@@ -241,21 +258,36 @@ impl RollMut {
             // we can compact the filled portion!
             self.compact()?;
         } else {
-            // we need to allocate box storage of the right size
-            let new_storage_size = std::cmp::max(self.storage_size() * 2, requested_len + len);
-            let mut new_b = vec![0u8; new_storage_size].into_boxed_slice();
-            // copy the filled portion
-            new_b[..self.len()].copy_from_slice(&self[..]);
-            self.storage = StorageMut::Box(BoxStorage {
-                buf: Rc::new(UnsafeCell::new(new_b)),
-                off: 0,
-            });
+            self.realloc_box(requested_len);
         }
 
         debug_assert!(self.cap() >= requested_len);
         Ok(())
     }
 
+    /// Moves the filled portion to box storage with room for at least
+    /// `requested_len` more bytes
+    fn realloc_box(&mut self, requested_len: usize) {
+        // if the filled portion starts at the beginning of the storage, we're
+        // growing it, so double to amortize. otherwise, the start has been
+        // consumed, and doubling every time would grow the storage without
+        // bound.
+        let len = self.len();
+        let new_storage_size = if self.storage.off() == 0 {
+            std::cmp::max(self.storage_size() * 2, requested_len + len)
+        } else {
+            std::cmp::max(BUF_SIZE as usize, requested_len + len)
+        };
+        let mut new_b = vec![0u8; new_storage_size].into_boxed_slice();
+        // copy the filled portion
+        new_b[..len].copy_from_slice(&self[..]);
+        record_copy(len);
+        self.storage = StorageMut::Box(BoxStorage {
+            buf: Rc::new(UnsafeCell::new(new_b)),
+            off: 0,
+        });
+    }
+
     /// The length (filled portion) of this buffer, that can be read
     #[inline(always)]
     pub fn len(&self) -> usize {
@@ -313,6 +345,81 @@ impl RollMut {
         (res, read_into.buf)
     }
 
+    /// Like [Self::read_into], but when `limit` is more than this buffer's
+    /// capacity, up to [READV_MAX_BUFS] buffers from the pool are read into
+    /// as well, with a single [ReadOwned::readv_owned] call. Whatever lands
+    /// in those is then copied over, growing this buffer as needed.
+    ///
+    /// Saves reads (and syscalls) when lots of data is waiting, e.g. large
+    /// header sections, big bodies.
+    ///
+    /// Panics if `cap` is zero
+    pub async fn read_into_vectored(
+        self,
+        limit: usize,
+        r: &mut impl ReadOwned,
+    ) -> (std::io::Result<usize>, Self) {
+        let read_cap = std::cmp::min(limit, self.cap());
+        assert!(read_cap > 0, "refusing to do empty read");
+
+        let mut extra_cap = limit - read_cap;
+        let mut bufs = vec![];
+        while extra_cap > 0 && bufs.len() < READV_MAX_BUFS {
+            // running low on buffers isn't worth failing the read over
+            let Ok(mut buf) = BufMut::alloc() else {
+                break;
+            };
+            buf.len = std::cmp::min(extra_cap, buf.len()) as u16;
+            extra_cap -= buf.len();
+            bufs.push(ReadvInto::Buf(buf));
+        }
+        if bufs.is_empty() {
+            return self.read_into(limit, r).await;
+        }
+
+        let read_off = self.len;
+        trace!(%read_off, %read_cap, extra_bufs = %bufs.len(), "read_into_vectored in progress...");
+        bufs.insert(
+            0,
+            ReadvInto::Roll(ReadInto {
+                buf: self,
+                off: read_off,
+                cap: read_cap.try_into().unwrap(),
+            }),
+        );
+        let (res, bufs) = r.readv_owned(bufs).await;
+
+        let mut bufs = bufs.into_iter();
+        let Some(ReadvInto::Roll(ReadInto { buf: mut this, .. })) = bufs.next() else {
+            unreachable!("readv_owned hands back the buffers it was given")
+        };
+        let Ok(n) = res else {
+            trace!("read_into_vectored failed: {:?}", res);
+            return (res, this);
+        };
+        trace!("read_into_vectored got {} bytes", n);
+
+        let in_place = std::cmp::min(n, read_cap);
+        this.len += in_place as u32;
+        let mut rest = n - in_place;
+        if rest > this.cap() {
+            this.realloc_box(rest);
+        }
+        for buf in bufs {
+            if rest == 0 {
+                break;
+            }
+            let ReadvInto::Buf(buf) = buf else {
+                unreachable!()
+            };
+            let len = std::cmp::min(rest, buf.len());
+            this.put(&buf[..len]).unwrap();
+            record_copy(len);
+            rest -= len;
+        }
+        (Ok(n), this)
+    }
+
     /// Put a slice into this buffer, fails if the slice doesn't fit in the
     /// buffer's capacity
     #[inline]
@@ -372,6 +479,12 @@ impl RollMut {
         }
     }
 
+    /// Borrows the filled part as a `&str` if it's valid utf-8, without
+    /// copying
+    pub fn as_str(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(&self[..])
+    }
+
     /// Get a [Roll] corresponding to the filled portion of this buffer
     #[inline(always)]
     pub fn filled(&self) -> Roll {
@@ -521,6 +634,33 @@ unsafe impl IoBufMut for ReadInto {
     }
 }
 
+/// How many pool buffers [RollMut::read_into_vectored] reads into, on top
+/// of the [RollMut] itself
+pub const READV_MAX_BUFS: usize = 4;
+
+/// What [RollMut::read_into_vectored] reads into: the [RollMut], then
+/// buffers from the pool
+pub(crate) enum ReadvInto {
+    Roll(ReadInto),
+    Buf(BufMut),
+}
+
+unsafe impl IoBufMut for ReadvInto {
+    fn io_buf_mut_stable_mut_ptr(&mut self) -> *mut u8 {
+        match self {
+            ReadvInto::Roll(r) => r.io_buf_mut_stable_mut_ptr(),
+            ReadvInto::Buf(b) => b.io_buf_mut_stable_mut_ptr(),
+        }
+    }
+
+    fn io_buf_mut_capacity(&self) -> usize {
+        match self {
+            ReadvInto::Roll(r) => r.io_buf_mut_capacity(),
+            ReadvInto::Buf(b) => b.io_buf_mut_capacity(),
+        }
+    }
+}
+
 /// An immutable view into a [RollMut]
 #[derive(Clone)]
 pub struct Roll {
@@ -736,6 +876,74 @@ impl Roll {
     pub unsafe fn to_string_unchecked(self) -> RollStr {
         RollStr { roll: self }
     }
+
+    /// Borrows as a `&str` if this is valid utf-8, without copying
+    pub fn as_str(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(self)
+    }
+
+    /// Compares with `other`, ignoring ASCII case, the way header names and
+    /// tokens like `chunked` are compared
+    pub fn eq_ignore_ascii_case(&self, other: impl AsRef<[u8]>) -> bool {
+        self[..].eq_ignore_ascii_case(other.as_ref())
+    }
+
+    /// Returns the part of this roll without leading and trailing ASCII
+    /// whitespace. Doesn't copy.
+    pub fn trim_ascii(self) -> Roll {
+        let start = self
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .unwrap_or(self.len());
+        let end = self[start..]
+            .iter()
+            .rposition(|b| !b.is_ascii_whitespace())
+            .map_or(start, |pos| start + pos + 1);
+
+        let (_, rest) = self.split_at(start);
+        let (trimmed, _) = rest.split_at(end - start);
+        trimmed
+    }
+
+    /// Splits around the first `delim`, which ends up in neither half.
+    /// Doesn't copy.
+    pub fn split_once(self, delim: u8) -> Option<(Roll, Roll)> {
+        let pos = memchr::memchr(delim, &self)?;
+        let (left, rest) = self.split_at(pos);
+        let (_, right) = rest.split_at(1);
+        Some((left, right))
+    }
+
+    /// Iterates over the parts of this roll separated by `delim`, like
+    /// `<[u8]>::split` does, but yields rolls. Doesn't copy.
+    pub fn split_on(self, delim: u8) -> RollSplit {
+        RollSplit {
+            rest: Some(self),
+            delim,
+        }
+    }
+}
+
+/// Returned by [Roll::split_on]
+pub struct RollSplit {
+    rest: Option<Roll>,
+    delim: u8,
+}
+
+impl Iterator for RollSplit {
+    type Item = Roll;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest.take()?;
+        match memchr::memchr(self.delim, &rest) {
+            Some(pos) => {
+                let (part, rest) = rest.split_at(pos);
+                self.rest = Some(rest.split_at(1).1);
+                Some(part)
+            }
+            None => Some(rest),
+        }
+    }
 }
 
 impl InputIter for Roll {
@@ -1164,6 +1372,64 @@ mod tests {
         });
     }
 
+    #[test]
+    #[cfg(not(feature = "miri"))]
+    fn test_roll_read_into_vectored() {
+        crate::bufpool::initialize_allocator().unwrap();
+
+        use crate::{
+            io::IntoHalves,
+            net::{TcpListener, TcpStream},
+            WriteOwned,
+        };
+
+        crate::start(async move {
+            let data = (0..20_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+            let ln = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let local_addr = ln.local_addr().unwrap();
+            let sent = data.clone();
+            crate::spawn(async move {
+                let stream = TcpStream::connect(local_addr).await.unwrap();
+                let (_stream_r, mut stream_w) = IntoHalves::into_halves(stream);
+                stream_w.write_all_owned(sent).await.unwrap();
+            });
+            let (stream, _) = ln.accept().await.unwrap();
+            let (mut stream_r, _stream_w) = IntoHalves::into_halves(stream);
+
+            // spills over into pool buffers, ends up in box storage
+            let mut rm = RollMut::alloc().unwrap();
+            rm.put(b"xyz").unwrap();
+            rm.skip(3);
+            let free = crate::bufpool::num_free();
+            while rm.len() < data.len() {
+                if rm.cap() == 0 {
+                    rm.reserve().unwrap();
+                }
+                let limit = data.len() - rm.len();
+                let res;
+                (res, rm) = rm.read_into_vectored(limit, &mut stream_r).await;
+                assert_ne!(res.unwrap(), 0);
+            }
+            assert_eq!(&rm[..], &data[..]);
+            // the extra buffers went back to the pool
+            assert!(crate::bufpool::num_free() >= free);
+
+            // with a limit that fits, it's a plain read
+            let (mut send, mut read) = crate::pipe();
+            crate::spawn(async move {
+                send.write_all_owned("123456").await.unwrap();
+            });
+            let mut rm = RollMut::alloc().unwrap();
+            let res;
+            (res, rm) = rm.read_into_vectored(3, &mut read).await;
+            assert_eq!(res.unwrap(), 3);
+            assert_eq!(rm.filled().as_ref(), b"123");
+        });
+    }
+
     #[test]
     fn test_roll_keep() {
         crate::bufpool::initialize_allocator().unwrap();
@@ -1458,4 +1724,66 @@ mod tests {
         rm.reserve_at_least(5263945).unwrap();
         assert!(rm.cap() >= 5263945);
     }
+
+    #[test]
+    fn test_reserve_at_least_after_consuming() {
+        crate::bufpool::initialize_allocator().unwrap();
+
+        // like a frame reader would: consume a bit, then make room for more
+        // than what's left, over and over. the storage must not keep growing.
+        let mut rm = RollMut::alloc().unwrap();
+        for _ in 0..16 {
+            std::io::Write::write_all(&mut rm, b"head").unwrap();
+            rm.skip(3);
+
+            let copied_before = crate::num_bytes_copied();
+            rm.reserve_at_least(16384).unwrap();
+            assert_eq!(crate::num_bytes_copied() - copied_before, 1);
+            assert!(rm.storage_size() <= 2 * 16384);
+
+            rm.put(b" ".repeat(16384)).unwrap();
+            rm.skip(16385);
+        }
+    }
+
+    #[test]
+    fn test_roll_str_helpers() {
+        crate::bufpool::initialize_allocator().unwrap();
+
+        let mut rm = RollMut::alloc().unwrap();
+        rm.put(b"  Transfer-Encoding:\tgzip, chunked \r\n").unwrap();
+        assert_eq!(
+            rm.as_str().unwrap(),
+            "  Transfer-Encoding:\tgzip, chunked \r\n"
+        );
+        let roll = rm.filled();
+        let base = roll.as_ptr() as usize;
+        let within = |r: &Roll| (base..base + roll.len()).contains(&(r.as_ptr() as usize));
+
+        let line = roll.clone().trim_ascii();
+        assert_eq!(line.as_str().unwrap(), "Transfer-Encoding:\tgzip, chunked");
+        assert!(within(&line));
+
+        let (name, value) = line.split_once(b':').unwrap();
+        assert!(name.eq_ignore_ascii_case("transfer-encoding"));
+        assert!(!name.eq_ignore_ascii_case("transfer-encodin"));
+
+        let codings = value
+            .split_on(b',')
+            .map(|coding| coding.trim_ascii())
+            .collect::<Vec<_>>();
+        assert_eq!(codings, ["gzip", "chunked"]);
+        assert!(codings.iter().all(within));
+
+        let roll = |input: &[u8]| {
+            let mut rm = RollMut::alloc().unwrap();
+            rm.put(input).unwrap();
+            rm.filled()
+        };
+        assert_eq!(roll(b" \t ").trim_ascii().len(), 0);
+        assert_eq!(Roll::empty().trim_ascii().len(), 0);
+        assert!(Roll::empty().split_once(b',').is_none());
+        assert_eq!(roll(b"a,,b,").split_on(b',').count(), 4);
+        assert!(roll(b"\xff").as_str().is_err());
+    }
 }
@@ -1,8 +1,52 @@
-use std::rc::Rc;
+use std::{rc::Rc, sync::OnceLock};
 
-use luring::IoUringAsync;
+use luring::{BufRing, IoUringAsync};
 
 /// Returns the thread-local IoUringAsync instance
 pub fn get_ring() -> Rc<IoUringAsync> {
     luring::get_ring()
 }
+
+/// Number of provided buffers per thread, and their size
+const BUF_RING_ENTRIES: u16 = 256;
+const BUF_RING_BUF_LEN: usize = 16 * 1024;
+
+/// Buffer group id of the thread-local [BufRing]
+const BUF_RING_BGID: u16 = 0;
+
+/// Whether to use multishot accept, and multishot recv with provided buffers,
+/// which cuts down on SQEs per request. They need Linux 5.19 or later: on
+/// older kernels, we fall back to one-shot accept and reads.
+pub(crate) fn multishot_enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| {
+        let enabled = !matches!(
+            std::env::var("BUFFET_URING_MULTISHOT").as_deref(),
+            Ok("0") | Ok("false")
+        );
+        eprintln!("==== MULTISHOT: {enabled} (override with $BUFFET_URING_MULTISHOT=0)");
+        enabled
+    })
+}
+
+thread_local! {
+    static BUF_RING: Option<Rc<BufRing>> = {
+        if multishot_enabled() {
+            match get_ring().register_buf_ring(BUF_RING_ENTRIES, BUF_RING_BUF_LEN, BUF_RING_BGID) {
+                Ok(ring) => Some(Rc::new(ring)),
+                Err(e) => {
+                    tracing::debug!("provided buffer rings unavailable, reads won't use them: {e}");
+                    None
+                }
+            }
+        } else {
+            None
+        }
+    };
+}
+
+/// Returns the thread-local ring of provided buffers, if multishot is
+/// enabled and the kernel supports them
+pub(crate) fn get_buf_ring() -> Option<Rc<BufRing>> {
+    BUF_RING.with(|ring| ring.clone())
+}
@@ -0,0 +1,333 @@
+//! Load generation: opens a number of HTTP/2 connections, keeps a number of
+//! streams in flight on each of them, all sending the same request, and
+//! reports throughput and latency percentiles.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use buffet::net::TcpStream;
+use httpwg::{Config, Conn, Ev, Headers};
+use loona_h2::{
+    ContinuationFlags, DataFlags, FrameType, HeadersFlags, PingFlags, Setting, SettingPairs,
+    SettingsFlags, StreamId,
+};
+
+/// The largest flow-control window HTTP/2 allows
+const MAX_WINDOW: u32 = (1 << 31) - 1;
+
+/// Stream identifiers are 31-bit
+pub(crate) const MAX_STREAM_ID: u32 = (1 << 31) - 1;
+
+/// The initial connection-level window, see RFC 9113 section 6.9.2
+const INITIAL_CONN_WINDOW: u32 = 65535;
+
+/// What to send and how hard
+pub struct LoadConf {
+    /// how many connections to open
+    pub connections: usize,
+
+    /// how many streams to keep in flight on each connection
+    pub streams: usize,
+
+    /// how long to keep sending new requests for
+    pub duration: Duration,
+
+    /// how many requests to send on each connection, at most
+    pub requests: Option<u64>,
+
+    /// the request sent over and over, pseudo-headers included
+    pub request: Headers,
+}
+
+/// What a single connection saw
+#[derive(Default)]
+pub(crate) struct Stats {
+    pub(crate) latencies: Vec<Duration>,
+
+    /// 4xx responses
+    pub(crate) client_errors: u64,
+
+    /// 5xx responses
+    pub(crate) server_errors: u64,
+
+    /// streams reset by the server
+    pub(crate) resets: u64,
+
+    /// connections that ended with an error
+    pub(crate) conn_errors: u64,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.latencies.extend(other.latencies);
+        self.client_errors += other.client_errors;
+        self.server_errors += other.server_errors;
+        self.resets += other.resets;
+        self.conn_errors += other.conn_errors;
+    }
+}
+
+/// Sends load to `addr` according to `load` and prints a report to stderr
+pub async fn run(addr: SocketAddr, config: Rc<Config>, load: LoadConf) {
+    let load = Rc::new(load);
+
+    eprintln!(
+        "Sending load to {addr} for {:?}: {} connections, {} streams each",
+        load.duration, load.connections, load.streams
+    );
+
+    let start = Instant::now();
+    let mut handles = Vec::with_capacity(load.connections);
+    for _ in 0..load.connections {
+        let config = config.clone();
+        let load = load.clone();
+        handles.push(tokio::task::spawn_local(async move {
+            let mut stats = Stats::default();
+            if let Err(e) = drive_conn(addr, config, &load, &mut stats).await {
+                eprintln!("❌ Connection failed: {e:?}");
+                stats.conn_errors += 1;
+            }
+            stats
+        }));
+    }
+
+    let mut stats = Stats::default();
+    for handle in handles {
+        stats.merge(handle.await.unwrap());
+    }
+    let elapsed = start.elapsed();
+
+    report(&mut stats, elapsed);
+}
+
+/// Sends requests over a single connection until `load.duration` is up or
+/// `load.requests` were sent, then waits for the responses
+pub(crate) async fn drive_conn(
+    addr: SocketAddr,
+    config: Rc<Config>,
+    load: &LoadConf,
+    stats: &mut Stats,
+) -> eyre::Result<()> {
+    let stream = tokio::time::timeout(config.timeout, TcpStream::connect(addr))
+        .await
+        .map_err(|_| eyre::eyre!("timed out connecting to {addr}"))??;
+    let mut conn = Conn::new(config.clone(), stream);
+    conn.handshake().await?;
+
+    // open our receive windows all the way, so the server never waits on us:
+    // stream windows through SETTINGS, the connection window through a
+    // WINDOW_UPDATE, which we top up as DATA comes in.
+    conn.write_settings(SettingPairs::from(
+        [(Setting::InitialWindowSize, MAX_WINDOW)].as_ref(),
+    ))
+    .await?;
+    conn.write_window_update(StreamId::CONNECTION, MAX_WINDOW - INITIAL_CONN_WINDOW)
+        .await?;
+    let mut conn_window_consumed: u32 = 0;
+
+    let max_streams = conn
+        .settings
+        .max_concurrent_streams
+        .map_or(load.streams, |max| load.streams.min(max as usize));
+    let deadline = Instant::now() + load.duration;
+
+    let mut remaining = load.requests.unwrap_or(u64::MAX);
+    let mut next_stream_id = 1_u32;
+    let mut in_flight: HashMap<StreamId, Instant> = HashMap::new();
+    // header block fragments we're waiting on CONTINUATION frames for
+    let mut partial_block: Option<(StreamId, bool, Vec<u8>)> = None;
+
+    loop {
+        while in_flight.len() < max_streams
+            && Instant::now() < deadline
+            && remaining > 0
+            && next_stream_id <= MAX_STREAM_ID
+        {
+            let stream_id = StreamId(next_stream_id);
+            next_stream_id += 2;
+            remaining -= 1;
+            conn.encode_and_write_headers(
+                stream_id,
+                HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+                &load.request,
+            )
+            .await?;
+            in_flight.insert(stream_id, Instant::now());
+        }
+
+        if in_flight.is_empty() {
+            return Ok(());
+        }
+
+        let ev = tokio::time::timeout(config.timeout, conn.ev_rx.recv())
+            .await
+            .map_err(|_| {
+                eyre::eyre!(
+                    "no frame within {:?} ({} streams in flight)",
+                    config.timeout,
+                    in_flight.len()
+                )
+            })?;
+        let (frame, payload) = match ev {
+            Some(Ev::Frame { frame, payload }) => (frame, payload),
+            Some(Ev::IoError { error }) => return Err(error.into()),
+            None => eyre::bail!("server hung up with {} streams in flight", in_flight.len()),
+        };
+
+        // the header block, once complete, and whether it ends the stream
+        let mut block = None;
+
+        match frame.frame_type {
+            FrameType::Headers(flags) => {
+                let end_stream = flags.contains(HeadersFlags::EndStream);
+                if flags.contains(HeadersFlags::EndHeaders) {
+                    block = Some((payload[..].to_vec(), end_stream));
+                } else {
+                    partial_block = Some((frame.stream_id, end_stream, payload[..].to_vec()));
+                }
+            }
+            FrameType::Continuation(flags) => {
+                let Some((stream_id, end_stream, mut fragment)) = partial_block.take() else {
+                    eyre::bail!("got CONTINUATION without a HEADERS frame first");
+                };
+                eyre::ensure!(
+                    stream_id == frame.stream_id,
+                    "got CONTINUATION for {:?}, expected {stream_id:?}",
+                    frame.stream_id
+                );
+                fragment.extend_from_slice(&payload[..]);
+                if flags.contains(ContinuationFlags::EndHeaders) {
+                    block = Some((fragment, end_stream));
+                } else {
+                    partial_block = Some((stream_id, end_stream, fragment));
+                }
+            }
+            FrameType::Data(flags) => {
+                conn_window_consumed += frame.len;
+                if conn_window_consumed >= MAX_WINDOW / 2 {
+                    conn.write_window_update(StreamId::CONNECTION, conn_window_consumed)
+                        .await?;
+                    conn_window_consumed = 0;
+                }
+                if flags.contains(DataFlags::EndStream) {
+                    finish_stream(&mut in_flight, frame.stream_id, stats);
+                }
+            }
+            FrameType::RstStream if in_flight.remove(&frame.stream_id).is_some() => {
+                stats.resets += 1;
+            }
+            FrameType::Ping(flags) if !flags.contains(PingFlags::Ack) => {
+                conn.write_ping(true, payload).await?;
+            }
+            FrameType::Settings(flags) if !flags.contains(SettingsFlags::Ack) => {
+                conn.write_frame(
+                    FrameType::Settings(SettingsFlags::Ack.into()).into_frame(StreamId::CONNECTION),
+                    (),
+                )
+                .await?;
+            }
+            FrameType::GoAway => {
+                eyre::bail!(
+                    "server sent GOAWAY with {} streams in flight",
+                    in_flight.len()
+                )
+            }
+            _ => {
+                // priority, window updates (we send nothing flow-controlled),
+                // acks, unknown frames: nothing to do
+            }
+        }
+
+        if let Some((fragment, end_stream)) = block {
+            // always decode, even if we don't care about the status: that's
+            // what keeps our HPACK table in sync with the server's
+            let headers = conn.decode_headers(fragment.into())?;
+            let status = headers
+                .get_first(&":status".into())
+                .and_then(|s| std::str::from_utf8(&s[..]).ok()?.parse::<u16>().ok());
+            match status {
+                // informational responses, the final one comes later
+                Some(100..=199) => continue,
+                Some(200..=399) => {}
+                Some(400..=499) => stats.client_errors += 1,
+                _ => stats.server_errors += 1,
+            }
+            if end_stream {
+                finish_stream(&mut in_flight, frame.stream_id, stats);
+            }
+        }
+    }
+}
+
+fn finish_stream(
+    in_flight: &mut HashMap<StreamId, Instant>,
+    stream_id: StreamId,
+    stats: &mut Stats,
+) {
+    if let Some(sent_at) = in_flight.remove(&stream_id) {
+        stats.latencies.push(sent_at.elapsed());
+    }
+}
+
+/// Returns the latency under which `p` (between 0 and 1) of the samples
+/// fall. `sorted` must be sorted and not empty.
+pub(crate) fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn report(stats: &mut Stats, elapsed: Duration) {
+    let num_requests = stats.latencies.len();
+    eprintln!(
+        "🚄 Completed \x1b[1;32m{num_requests}\x1b[0m requests in \x1b[1;33m{:.2}\x1b[0m seconds (\x1b[1;36m{:.0}\x1b[0m req/s)",
+        elapsed.as_secs_f64(),
+        num_requests as f64 / elapsed.as_secs_f64()
+    );
+    if stats.client_errors > 0
+        || stats.server_errors > 0
+        || stats.resets > 0
+        || stats.conn_errors > 0
+    {
+        eprintln!(
+            "❌ {} 4xx responses, {} 5xx responses, {} streams reset, {} connections failed",
+            stats.client_errors, stats.server_errors, stats.resets, stats.conn_errors
+        );
+    }
+    if num_requests == 0 {
+        return;
+    }
+
+    stats.latencies.sort_unstable();
+    let sorted = &stats.latencies[..];
+    eprintln!(
+        "   latency: p50 {:?}, p99 {:?}, p999 {:?}, max {:?}",
+        percentile(sorted, 0.5),
+        percentile(sorted, 0.99),
+        percentile(sorted, 0.999),
+        sorted[sorted.len() - 1],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::percentile;
+
+    #[test]
+    fn test_percentile() {
+        let samples: Vec<_> = (1..=1000).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&samples, 0.5), Duration::from_millis(500));
+        assert_eq!(percentile(&samples, 0.99), Duration::from_millis(990));
+        assert_eq!(percentile(&samples, 0.999), Duration::from_millis(999));
+        assert_eq!(percentile(&samples, 1.0), Duration::from_millis(1000));
+
+        let one = [Duration::from_millis(3)];
+        assert_eq!(percentile(&one, 0.0), one[0]);
+        assert_eq!(percentile(&one, 0.999), one[0]);
+    }
+}
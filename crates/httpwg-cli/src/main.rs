@@ -1,5 +1,6 @@
 use std::{
-    cell::RefCell, collections::HashMap, ffi::OsString, net::SocketAddr, rc::Rc, time::Duration,
+    cell::RefCell, collections::HashMap, ffi::OsString, net::SocketAddr, path::PathBuf, rc::Rc,
+    time::Duration,
 };
 
 use buffet::{net::TcpStream, IntoHalves};
@@ -7,6 +8,10 @@ use httpwg::{Config, Conn};
 use tracing::Level;
 use tracing_subscriber::{filter::Targets, layer::SubscriberExt, util::SubscriberInitExt};
 
+mod load;
+mod personas;
+mod record;
+
 #[derive(Default, Debug)]
 struct Args {
     /// the binary to run tests against (and any args to pass to it)
@@ -26,6 +31,49 @@ struct Args {
 
     /// whether to print verbose output
     verbose: bool,
+
+    /// whether to generate load instead of running tests
+    load: bool,
+
+    /// (load mode) how many connections to open
+    connections: Option<usize>,
+
+    /// (load mode) how many streams to keep in flight per connection
+    streams: Option<usize>,
+
+    /// (load mode) how long to send requests for (in seconds)
+    duration: Option<u64>,
+
+    /// (load mode) how many requests to send per connection
+    requests: Option<u64>,
+
+    /// (load mode) the request method
+    method: Option<String>,
+
+    /// (load mode) the request path
+    path: Option<String>,
+
+    /// (load mode) extra request headers
+    headers: Vec<(String, String)>,
+
+    /// which misbehaving client to attack the server as
+    persona: Option<personas::Persona>,
+
+    /// (persona mode) how often to check the server is still available (in
+    /// milliseconds)
+    probe_interval: Option<u64>,
+
+    /// (persona mode) the highest acceptable p99 latency (in milliseconds)
+    max_latency: Option<u64>,
+
+    /// (persona mode) the lowest acceptable percentage of successful probes
+    min_success: Option<f64>,
+
+    /// the h2spec test to record a replay of, e.g. `http2/6.5/2`
+    record_h2spec: Option<String>,
+
+    /// (record mode) the directory to write the replay to
+    out: Option<PathBuf>,
 }
 
 pub trait IntoStringResult {
@@ -87,6 +135,95 @@ fn parse_args() -> eyre::Result<Args> {
             lexopt::Arg::Long("verbose") | lexopt::Arg::Short('v') => {
                 args.verbose = true;
             }
+            lexopt::Arg::Long("load") => {
+                args.load = true;
+            }
+            lexopt::Arg::Long("connections") | lexopt::Arg::Short('c') => {
+                args.connections = Some(
+                    parser
+                        .value()?
+                        .into_string_result()?
+                        .parse()
+                        .map_err(|e| eyre::eyre!("Failed to parse connections: {}", e))?,
+                );
+            }
+            lexopt::Arg::Long("streams") | lexopt::Arg::Short('s') => {
+                args.streams = Some(
+                    parser
+                        .value()?
+                        .into_string_result()?
+                        .parse()
+                        .map_err(|e| eyre::eyre!("Failed to parse streams: {}", e))?,
+                );
+            }
+            lexopt::Arg::Long("duration") | lexopt::Arg::Short('d') => {
+                args.duration = Some(
+                    parser
+                        .value()?
+                        .into_string_result()?
+                        .parse()
+                        .map_err(|e| eyre::eyre!("Failed to parse duration: {}", e))?,
+                );
+            }
+            lexopt::Arg::Long("requests") | lexopt::Arg::Short('n') => {
+                args.requests = Some(
+                    parser
+                        .value()?
+                        .into_string_result()?
+                        .parse()
+                        .map_err(|e| eyre::eyre!("Failed to parse requests: {}", e))?,
+                );
+            }
+            lexopt::Arg::Long("method") | lexopt::Arg::Short('m') => {
+                args.method = Some(parser.value()?.into_string_result()?);
+            }
+            lexopt::Arg::Long("path") => {
+                args.path = Some(parser.value()?.into_string_result()?);
+            }
+            lexopt::Arg::Long("persona") => {
+                args.persona = Some(parser.value()?.into_string_result()?.parse()?);
+            }
+            lexopt::Arg::Long("probe-interval") => {
+                args.probe_interval = Some(
+                    parser
+                        .value()?
+                        .into_string_result()?
+                        .parse()
+                        .map_err(|e| eyre::eyre!("Failed to parse probe interval: {}", e))?,
+                );
+            }
+            lexopt::Arg::Long("max-latency") => {
+                args.max_latency = Some(
+                    parser
+                        .value()?
+                        .into_string_result()?
+                        .parse()
+                        .map_err(|e| eyre::eyre!("Failed to parse max latency: {}", e))?,
+                );
+            }
+            lexopt::Arg::Long("min-success") => {
+                args.min_success = Some(
+                    parser
+                        .value()?
+                        .into_string_result()?
+                        .parse()
+                        .map_err(|e| eyre::eyre!("Failed to parse min success: {}", e))?,
+                );
+            }
+            lexopt::Arg::Long("record-h2spec") => {
+                args.record_h2spec = Some(parser.value()?.into_string_result()?);
+            }
+            lexopt::Arg::Long("out") | lexopt::Arg::Short('o') => {
+                args.out = Some(parser.value()?.into());
+            }
+            lexopt::Arg::Long("header") | lexopt::Arg::Short('H') => {
+                let value = parser.value()?.into_string_result()?;
+                let (name, value) = value
+                    .split_once(':')
+                    .ok_or_else(|| eyre::eyre!("Expected 'name: value', got: {}", value))?;
+                args.headers
+                    .push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+            }
             lexopt::Arg::Value(value) => {
                 args.server_binary.push(value.into_string_result()?);
             }
@@ -107,6 +244,28 @@ Options:
     -f, --filter <FILTER>      Which tests to run
     -v, --verbose              Print verbose output
 
+Load mode (HTTP/2 only, sends the same request over and over):
+    --load                     Generate load instead of running tests
+    -c, --connections <N>      How many connections to open [default: 1]
+    -s, --streams <N>          How many streams to keep in flight per connection [default: 1]
+    -d, --duration <SECONDS>   How long to send requests for [default: 10]
+    -n, --requests <N>         How many requests to send per connection, at most
+    -m, --method <METHOD>      The request method [default: GET]
+    --path <PATH>              The request path [default: /]
+    -H, --header <NAME: VALUE> An extra request header, can be repeated
+
+Persona mode (HTTP/2 only, attacks the server while probing it with the request above):
+    --persona <NAME>           One of: slowloris, rapid-reset, window-starver, header-bomber
+    -c, --connections <N>      How many connections attack at once [default: 8]
+    -d, --duration <SECONDS>   How long the attack lasts [default: 10]
+    --probe-interval <MS>      How often to send a probe request [default: 100]
+    --max-latency <MS>         The highest acceptable p99 probe latency [default: 1000]
+    --min-success <PERCENT>    The lowest acceptable share of successful probes [default: 99]
+
+Record mode (HTTP/2 only, needs h2spec on the PATH):
+    --record-h2spec <ID>       Record a failing h2spec test as a replay, e.g. http2/6.5/2
+    -o, --out <DIR>            Where to write the replay [default: print it]
+
 Arguments:
     SERVER                     The server to run tests against
     [ARGS]                     Any additional arguments to pass to the server
@@ -114,6 +273,9 @@ Arguments:
 Examples:
     httpwg-test-suite -a 127.0.0.1:8080 -- ./my_server
     httpwg-test-suite -f 'RFC 9113' -- ./my_server --go-fast
+    httpwg-test-suite -a 127.0.0.1:8080 --load -c 8 -s 32 -d 30 --path /hello
+    httpwg-test-suite -a 127.0.0.1:8080 --persona rapid-reset --max-latency 200
+    httpwg-test-suite -a 127.0.0.1:8080 --record-h2spec http2/6.5/2 -o tests/h2spec-replays
 "
     );
     Ok(())
@@ -152,6 +314,8 @@ async fn async_main(mut args: Args) -> eyre::Result<()> {
     };
     let frame_timeout = match args.frame_timeout {
         Some(timeout) => Duration::from_millis(timeout),
+        // under load, responses can legitimately take a while
+        None if args.load || args.persona.is_some() => Duration::from_millis(5000),
         None => Duration::from_millis(250),
     };
     let conf = Rc::new(Config {
@@ -219,6 +383,56 @@ async fn async_main(mut args: Args) -> eyre::Result<()> {
         }
     }
 
+    if let Some(id) = args.record_h2spec {
+        return record::run(addr, &id, args.out.as_deref());
+    }
+
+    if args.load || args.persona.is_some() {
+        let mut request = httpwg::Headers::default();
+        request.append(
+            ":method",
+            args.method.unwrap_or_else(|| "GET".into()).into_bytes(),
+        );
+        request.append(":scheme", "http");
+        request.append(
+            ":path",
+            args.path.unwrap_or_else(|| "/".into()).into_bytes(),
+        );
+        request.append(":authority", addr.to_string().into_bytes());
+        for (name, value) in args.headers {
+            request.append(name.into_bytes(), value.into_bytes());
+        }
+
+        let duration = Duration::from_secs(args.duration.unwrap_or(10));
+
+        if let Some(persona) = args.persona {
+            let conf = personas::PersonaConf {
+                persona,
+                attackers: args.connections.unwrap_or(8),
+                duration,
+                probe_interval: Duration::from_millis(args.probe_interval.unwrap_or(100)),
+                probe_timeout: frame_timeout,
+                max_probe_latency: Duration::from_millis(args.max_latency.unwrap_or(1000)),
+                min_probe_success: args.min_success.unwrap_or(99.0) / 100.0,
+                request,
+            };
+            if !personas::run(addr, conf).await {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+
+        let load = load::LoadConf {
+            connections: args.connections.unwrap_or(1),
+            streams: args.streams.unwrap_or(1),
+            duration,
+            requests: args.requests,
+            request,
+        };
+        load::run(addr, conf, load).await;
+        return Ok(());
+    }
+
     let mut local_set = tokio::task::LocalSet::new();
 
     let sequential = std::env::var("SEQUENTIAL")
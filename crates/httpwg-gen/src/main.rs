@@ -243,10 +243,29 @@ fn main() {
         w!("/// The `$body` argument is pasted inside those unit test, and");
         w!("/// in that scope, `test` is the `httpwg` function you can use");
         w!("/// to run the test (that takes a `mut conn: Conn<IO>`)");
+        w!("///");
+        w!("/// Tests can be skipped, or expected to fail, by function name, so");
+        w!("/// servers can adopt the suite one test at a time:");
+        w!("///");
+        w!("/// ```ignore");
+        w!("/// httpwg_macros::tests! {{");
+        w!("///     ignore: [sends_settings_max_frame_size_with_invalid_value_above_max],");
+        w!("///     should_panic: [exceeds_concurrent_stream_limit],");
+        w!("///     {{ /* body */ }}");
+        w!("/// }}");
+        w!("/// ```");
+        w!("///");
+        w!("/// `ignore` tests get `#[ignore]`, `should_panic` ones get");
+        w!("/// `#[should_panic]`: `$body` must panic when a test fails.");
         w!("#[macro_export]");
         w!("macro_rules! tests {{");
         {
-            w!("  ($body: tt) => {{");
+            w!("  (@expand ($d:tt) [$($ignored:ident)*] [$($failing:ident)*] $body: tt) => {{");
+            w!("macro_rules! __httpwg_test {{");
+            w!("$( ($ignored $d($d item:tt)*) => {{ #[ignore] $d($d item)* }}; )*");
+            w!("$( ($failing $d($d item:tt)*) => {{ #[should_panic] $d($d item)* }}; )*");
+            w!("($d name:ident $d($d item:tt)*) => {{ $d($d item)* }};");
+            w!("}}");
             for suite in &suites {
                 let suite_name = &suite.name;
                 w!("");
@@ -269,6 +288,7 @@ fn main() {
                             for test in &group.tests {
                                 let test_name = &test.name;
                                 w!("");
+                                w!("__httpwg_test! {{ {test_name}");
                                 for line in test.docs.as_deref().unwrap_or_default().lines() {
                                     w!("/// {line}");
                                 }
@@ -279,6 +299,7 @@ fn main() {
                                     w!("$body");
                                 }
                                 w!("}}");
+                                w!("}}");
                             }
                         }
                         w!("}}");
@@ -286,7 +307,19 @@ fn main() {
                 }
                 w!("}}");
             }
-            w!("}}");
+            w!("  }};");
+            w!("  (ignore: [$($ignored:ident),* $(,)?], should_panic: [$($failing:ident),* $(,)?], $body: tt) => {{");
+            w!("    $crate::tests! {{ @expand ($) [$($ignored)*] [$($failing)*] $body }}");
+            w!("  }};");
+            w!("  (ignore: [$($ignored:ident),* $(,)?], $body: tt) => {{");
+            w!("    $crate::tests! {{ @expand ($) [$($ignored)*] [] $body }}");
+            w!("  }};");
+            w!("  (should_panic: [$($failing:ident),* $(,)?], $body: tt) => {{");
+            w!("    $crate::tests! {{ @expand ($) [] [$($failing)*] $body }}");
+            w!("  }};");
+            w!("  ($body: tt) => {{");
+            w!("    $crate::tests! {{ @expand ($) [] [] $body }}");
+            w!("  }};");
         }
         w!("}}");
 
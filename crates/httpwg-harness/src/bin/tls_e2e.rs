@@ -0,0 +1,387 @@
+//! Runs h2spec and h2load against a test server over TLS, and writes what
+//! they found to an artifacts directory:
+//!
+//!   - `h2spec.log`, `h2spec.xml` (JUnit), `h2load.log`: the raw outputs
+//!   - `summary.json`: the numbers that matter, cf. [Summary]
+//!
+//! Exits with a non-zero status if anything failed, so it can gate CI. TLS
+//! framing and ALPN bugs don't show up in the cleartext httpwg runs, which is
+//! why this exists.
+//!
+//! ```text
+//! httpwg-tls-e2e --out target/tls-e2e -- ./target/release/httpwg-loona
+//! ```
+
+use std::{
+    io::{BufRead, BufReader},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    time::Duration,
+};
+
+use httpwg_harness::{Proto, Settings};
+use serde::Serialize;
+
+struct Args {
+    out: PathBuf,
+    path: String,
+    requests: u64,
+    clients: u64,
+    streams: u64,
+    /// Report missing tools as skipped rather than failed
+    allow_missing: bool,
+    server: Vec<String>,
+}
+
+fn parse_args() -> eyre::Result<Args> {
+    let mut args = Args {
+        out: PathBuf::from("target/tls-e2e"),
+        path: "/repeat-4k-blocks/4".to_string(),
+        requests: 10_000,
+        clients: 4,
+        streams: 16,
+        allow_missing: false,
+        server: Vec::new(),
+    };
+
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        let mut value = |name: &str| {
+            iter.next()
+                .ok_or_else(|| eyre::eyre!("{name} needs a value"))
+        };
+        match arg.as_str() {
+            "--out" => args.out = value("--out")?.into(),
+            "--path" => args.path = value("--path")?,
+            "-n" | "--requests" => args.requests = value("--requests")?.parse()?,
+            "-c" | "--clients" => args.clients = value("--clients")?.parse()?,
+            "-m" | "--streams" => args.streams = value("--streams")?.parse()?,
+            "--allow-missing" => args.allow_missing = true,
+            "--" => {
+                args.server.extend(iter);
+                break;
+            }
+            _ => eyre::bail!("unexpected argument: {arg}"),
+        }
+    }
+    if args.server.is_empty() {
+        eyre::bail!(
+            "usage: httpwg-tls-e2e [--out DIR] [--path PATH] [-n REQUESTS] [-c CLIENTS] \
+             [-m STREAMS] [--allow-missing] -- SERVER [ARGS]"
+        );
+    }
+    Ok(args)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Passed,
+    Failed,
+    /// The tool isn't installed
+    Skipped,
+}
+
+/// What ends up in `summary.json`
+#[derive(Debug, Serialize)]
+struct Summary {
+    server: String,
+    addr: SocketAddr,
+    status: Status,
+    h2spec: H2specReport,
+    h2load: H2loadReport,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct H2specReport {
+    status: Option<Status>,
+    exit_code: Option<i32>,
+    tests: u64,
+    passed: u64,
+    skipped: u64,
+    failed: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct H2loadReport {
+    status: Option<Status>,
+    exit_code: Option<i32>,
+    /// What ALPN picked: anything but `h2` is a failure
+    application_protocol: Option<String>,
+    tls_protocol: Option<String>,
+    cipher: Option<String>,
+    requests_total: u64,
+    succeeded: u64,
+    failed: u64,
+    errored: u64,
+    timeout: u64,
+    status_2xx: u64,
+    status_other: u64,
+    req_per_sec: Option<f64>,
+}
+
+fn main() -> eyre::Result<()> {
+    let args = parse_args()?;
+    std::fs::create_dir_all(&args.out)?;
+
+    let (mut server, addr) = start_server(&args.server)?;
+    eprintln!("{} is listening on {addr}", args.server[0]);
+
+    let h2spec = run_h2spec(&args, addr);
+    let h2load = run_h2load(&args, addr);
+    _ = server.kill();
+    _ = server.wait();
+    let (h2spec, h2load) = (h2spec?, h2load?);
+
+    let statuses = [h2spec.status, h2load.status];
+    let status = if statuses.contains(&Some(Status::Failed)) {
+        Status::Failed
+    } else if statuses.contains(&Some(Status::Skipped)) {
+        Status::Skipped
+    } else {
+        Status::Passed
+    };
+
+    let summary = Summary {
+        server: args.server.join(" "),
+        addr,
+        status,
+        h2spec,
+        h2load,
+    };
+    let summary_path = args.out.join("summary.json");
+    std::fs::write(&summary_path, serde_json::to_string_pretty(&summary)?)?;
+    eprintln!("{summary:#?}");
+    eprintln!("wrote {}", summary_path.display());
+
+    match status {
+        Status::Passed => Ok(()),
+        Status::Skipped if args.allow_missing => Ok(()),
+        _ => std::process::exit(1),
+    }
+}
+
+/// Starts the server with `PROTO=tls` on a random port, and waits for it to
+/// say where it's listening
+fn start_server(cmd: &[String]) -> eyre::Result<(Child, SocketAddr)> {
+    let mut child = Command::new(&cmd[0])
+        .args(&cmd[1..])
+        .env("PROTO", "tls")
+        .env("PORT", std::env::var("PORT").unwrap_or("0".into()))
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let settings = Settings {
+        listen_addr: "127.0.0.1:0".parse()?,
+        proto: Proto::TLS,
+    };
+    let stdout = child.stdout.take().unwrap();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut sent = false;
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else { break };
+            if !sent {
+                if let Ok(Some(addr)) = settings.decode_listen_line(&line) {
+                    _ = tx.send(addr);
+                    sent = true;
+                    continue;
+                }
+            }
+            println!("{line}");
+        }
+    });
+
+    match rx.recv_timeout(Duration::from_secs(10)) {
+        Ok(addr) => Ok((child, addr)),
+        Err(_) => {
+            _ = child.kill();
+            eyre::bail!("server didn't print its listen line within 10 seconds")
+        }
+    }
+}
+
+/// Runs `cmd`, saving its output to `log`. `None` if it isn't installed.
+fn run_tool(cmd: &mut Command, log: &Path) -> eyre::Result<Option<(Option<i32>, String)>> {
+    eprintln!("running {cmd:?}");
+    let output = match cmd.output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            eprintln!("{:?} isn't installed, skipping", cmd.get_program());
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    std::fs::write(log, &text)?;
+    Ok(Some((output.status.code(), text)))
+}
+
+fn run_h2spec(args: &Args, addr: SocketAddr) -> eyre::Result<H2specReport> {
+    let junit = args.out.join("h2spec.xml");
+    let mut cmd = Command::new("h2spec");
+    cmd.arg("--tls")
+        .arg("--insecure")
+        .args(["--host", &addr.ip().to_string()])
+        .args(["--port", &addr.port().to_string()])
+        .args(["--timeout", "2"])
+        .arg("--junit-report")
+        .arg(&junit);
+
+    let Some((exit_code, output)) = run_tool(&mut cmd, &args.out.join("h2spec.log"))? else {
+        return Ok(H2specReport {
+            status: Some(Status::Skipped),
+            ..Default::default()
+        });
+    };
+    let mut report = parse_h2spec(&output);
+    report.exit_code = exit_code;
+    report.status = Some(
+        if exit_code == Some(0) && report.tests > 0 && report.failed == 0 {
+            Status::Passed
+        } else {
+            Status::Failed
+        },
+    );
+    Ok(report)
+}
+
+fn run_h2load(args: &Args, addr: SocketAddr) -> eyre::Result<H2loadReport> {
+    let mut cmd = Command::new("h2load");
+    cmd.args(["--npn-list", "h2"])
+        .args(["-n", &args.requests.to_string()])
+        .args(["-c", &args.clients.to_string()])
+        .args(["-m", &args.streams.to_string()])
+        .arg(format!("https://{addr}{}", args.path));
+
+    let Some((exit_code, output)) = run_tool(&mut cmd, &args.out.join("h2load.log"))? else {
+        return Ok(H2loadReport {
+            status: Some(Status::Skipped),
+            ..Default::default()
+        });
+    };
+    let mut report = parse_h2load(&output);
+    report.exit_code = exit_code;
+    let ok = exit_code == Some(0)
+        && report.application_protocol.as_deref() == Some("h2")
+        && report.requests_total > 0
+        && report.succeeded == report.requests_total
+        && report.status_other == 0;
+    report.status = Some(if ok { Status::Passed } else { Status::Failed });
+    Ok(report)
+}
+
+/// Numbers from a comma-separated list of `<number> <label>`, e.g.
+/// `1000 total, 1000 started, 0 failed`
+fn counts(list: &str) -> impl Iterator<Item = (&str, u64)> {
+    list.split(',').filter_map(|item| {
+        let (n, label) = item.trim().split_once(' ')?;
+        Some((label.trim(), n.parse().ok()?))
+    })
+}
+
+/// Looks for h2spec's last line, e.g. `146 tests, 145 passed, 1 skipped, 0 failed`
+fn parse_h2spec(output: &str) -> H2specReport {
+    let mut report = H2specReport::default();
+    for line in output.lines().filter(|l| l.contains(" tests, ")) {
+        for (label, n) in counts(line) {
+            match label {
+                "tests" => report.tests = n,
+                "passed" => report.passed = n,
+                "skipped" => report.skipped = n,
+                "failed" => report.failed = n,
+                _ => {}
+            }
+        }
+    }
+    report
+}
+
+fn parse_h2load(output: &str) -> H2loadReport {
+    let mut report = H2loadReport::default();
+    for line in output.lines() {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("Application protocol:") {
+            report.application_protocol = Some(rest.trim().to_string());
+        } else if let Some(rest) = line.strip_prefix("TLS Protocol:") {
+            report.tls_protocol = Some(rest.trim().to_string());
+        } else if let Some(rest) = line.strip_prefix("Cipher:") {
+            report.cipher = Some(rest.trim().to_string());
+        } else if let Some(rest) = line.strip_prefix("requests:") {
+            for (label, n) in counts(rest) {
+                match label {
+                    "total" => report.requests_total = n,
+                    "succeeded" => report.succeeded = n,
+                    "failed" => report.failed = n,
+                    "errored" => report.errored = n,
+                    "timeout" => report.timeout = n,
+                    _ => {}
+                }
+            }
+        } else if let Some(rest) = line.strip_prefix("status codes:") {
+            for (label, n) in counts(rest) {
+                match label {
+                    "2xx" => report.status_2xx = n,
+                    _ => report.status_other += n,
+                }
+            }
+        } else if let Some(rest) = line.strip_prefix("finished in") {
+            // finished in 1.05s, 950.54 req/s, 34.55KB/s
+            report.req_per_sec = rest
+                .split(',')
+                .find_map(|item| item.trim().strip_suffix(" req/s")?.parse().ok());
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_h2load, parse_h2spec};
+
+    #[test]
+    fn test_parse_h2spec() {
+        let report = parse_h2spec(
+            "Generic tests for HTTP/2 server\n  1. Starting HTTP/2\n    \u{2714} 1: Sends a client connection preface\n\nFinished in 3.1234 seconds\n146 tests, 145 passed, 1 skipped, 0 failed\n",
+        );
+        assert_eq!(
+            (report.tests, report.passed, report.skipped, report.failed),
+            (146, 145, 1, 0)
+        );
+    }
+
+    #[test]
+    fn test_parse_h2load() {
+        let report = parse_h2load(
+            "starting benchmark...
+spawning thread #0: 4 total client(s). 1000 total requests
+TLS Protocol: TLSv1.3
+Cipher: TLS_AES_256_GCM_SHA384
+Server Temp Key: X25519 253 bits
+Application protocol: h2
+progress: 100% done
+
+finished in 1.05s, 950.54 req/s, 3.74MB/s
+requests: 1000 total, 1000 started, 1000 done, 998 succeeded, 2 failed, 2 errored, 0 timeout
+status codes: 997 2xx, 0 3xx, 1 4xx, 0 5xx
+",
+        );
+        assert_eq!(report.application_protocol.as_deref(), Some("h2"));
+        assert_eq!(report.tls_protocol.as_deref(), Some("TLSv1.3"));
+        assert_eq!(report.cipher.as_deref(), Some("TLS_AES_256_GCM_SHA384"));
+        assert_eq!(
+            (
+                report.requests_total,
+                report.succeeded,
+                report.failed,
+                report.errored
+            ),
+            (1000, 998, 2, 2)
+        );
+        assert_eq!((report.status_2xx, report.status_other), (997, 1));
+        assert_eq!(report.req_per_sec, Some(950.54));
+    }
+}
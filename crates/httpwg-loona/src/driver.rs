@@ -3,7 +3,7 @@ use httpwg_harness::{Settings, SAMPLE_4K_BLOCK};
 
 use buffet::Piece;
 use loona::{
-    error::NeverError, http::StatusCode, Body, BodyChunk, Encoder, ExpectResponseHeaders,
+    error::NeverError, http::StatusCode, Body, BodyChunk, Encoder, ExpectResponseHeaders, FileBody,
     HeadersExt, Responder, Response, ResponseDone, ServerDriver, SinglePieceBody,
 };
 
@@ -81,14 +81,14 @@ where
             }
             ["stream-file", name] => {
                 drain_body(req_body).await?;
-                let _ = name;
 
+                let mut body = FileBody::open(format!("/tmp/stream-file/{name}")).bx()?;
                 res.write_final_response_with_body(
                     Response {
-                        status: StatusCode::INTERNAL_SERVER_ERROR,
+                        status: StatusCode::OK,
                         ..Default::default()
                     },
-                    &mut SinglePieceBody::from("/stream-file: not implemented yet"),
+                    &mut body,
                 )
                 .await
                 .bx()?
@@ -142,6 +142,9 @@ async fn drain_body(body: &mut impl Body) -> Result<(), BX> {
             BodyChunk::Chunk(chunk) => {
                 req_body_len += chunk.len();
             }
+            BodyChunk::File { len, .. } => {
+                req_body_len += len as usize;
+            }
         }
     }
     tracing::debug!(%req_body_len, "read request body");
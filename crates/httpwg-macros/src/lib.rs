@@ -7,9 +7,28 @@
 /// The `$body` argument is pasted inside those unit test, and
 /// in that scope, `test` is the `httpwg` function you can use
 /// to run the test (that takes a `mut conn: Conn<IO>`)
+///
+/// Tests can be skipped, or expected to fail, by function name, so
+/// servers can adopt the suite one test at a time:
+///
+/// ```ignore
+/// httpwg_macros::tests! {
+///     ignore: [sends_settings_max_frame_size_with_invalid_value_above_max],
+///     should_panic: [exceeds_concurrent_stream_limit],
+///     { /* body */ }
+/// }
+/// ```
+///
+/// `ignore` tests get `#[ignore]`, `should_panic` ones get
+/// `#[should_panic]`: `$body` must panic when a test fails.
 #[macro_export]
 macro_rules! tests {
-  ($body: tt) => {
+  (@expand ($d:tt) [$($ignored:ident)*] [$($failing:ident)*] $body: tt) => {
+macro_rules! __httpwg_test {
+$( ($ignored $d($d item:tt)*) => { #[ignore] $d($d item)* }; )*
+$( ($failing $d($d item:tt)*) => { #[should_panic] $d($d item)* }; )*
+($d name:ident $d($d item:tt)*) => { $d($d item)* };
+}
 
 /// RFC 9113 describes an optimized expression of the
 /// semantics of the Hypertext Transfer Protocol (HTTP), referred to as
@@ -30,6 +49,7 @@ use ::httpwg::rfc9113 as __suite;
 mod _3_starting_http2 {
 use super::__suite::_3_starting_http2 as __group;
 
+__httpwg_test! { sends_client_connection_preface
 /// The server connection preface consists of a potentially empty
 /// SETTINGS frame (Section 6.5) that MUST be the first frame
 /// the server sends in the HTTP/2 connection.
@@ -38,7 +58,9 @@ fn sends_client_connection_preface() {
 use __group::sends_client_connection_preface as test;
 $body
 }
+}
 
+__httpwg_test! { sends_invalid_connection_preface
 /// Clients and servers MUST treat an invalid connection preface as
 /// a connection error (Section 5.4.1) of type PROTOCOL_ERROR.
 #[test]
@@ -47,18 +69,22 @@ use __group::sends_invalid_connection_preface as test;
 $body
 }
 }
+}
 
 /// Section 4: HTTP Frames
 mod _4_http_frames {
 use super::__suite::_4_http_frames as __group;
 
+__httpwg_test! { sends_frame_with_unknown_type
 /// Implementations MUST ignore and discard frames of unknown types.
 #[test]
 fn sends_frame_with_unknown_type() {
 use __group::sends_frame_with_unknown_type as test;
 $body
 }
+}
 
+__httpwg_test! { sends_frame_with_unused_flags
 /// Unused flags MUST be ignored on receipt and MUST be left
 /// unset (0x00) when sending.
 #[test]
@@ -66,7 +92,9 @@ fn sends_frame_with_unused_flags() {
 use __group::sends_frame_with_unused_flags as test;
 $body
 }
+}
 
+__httpwg_test! { sends_frame_with_reserved_bit_set
 /// Reserved: A reserved 1-bit field. The semantics of this bit are
 /// undefined, and the bit MUST remain unset (0x00) when sending and
 /// MUST be ignored when receiving.
@@ -75,13 +103,17 @@ fn sends_frame_with_reserved_bit_set() {
 use __group::sends_frame_with_reserved_bit_set as test;
 $body
 }
+}
 
+__httpwg_test! { data_frame_with_max_length
 #[test]
 fn data_frame_with_max_length() {
 use __group::data_frame_with_max_length as test;
 $body
 }
+}
 
+__httpwg_test! { frame_exceeding_max_size
 /// An endpoint MUST send an error code of FRAME_SIZE_ERROR if a frame
 /// exceeds the size defined in SETTINGS_MAX_FRAME_SIZE, exceeds any
 /// limit defined for the frame type, or is too small to contain mandatory frame
@@ -91,7 +123,9 @@ fn frame_exceeding_max_size() {
 use __group::frame_exceeding_max_size as test;
 $body
 }
+}
 
+__httpwg_test! { large_headers_frame_exceeding_max_size
 /// A frame size error in a frame that could alter the state of
 /// the entire connection MUST be treated as a connection error
 /// (Section 5.4.1); this includes any frame carrying a field block
@@ -102,7 +136,30 @@ fn large_headers_frame_exceeding_max_size() {
 use __group::large_headers_frame_exceeding_max_size as test;
 $body
 }
+}
 
+__httpwg_test! { response_frames_within_max_frame_size
+/// SETTINGS_MAX_FRAME_SIZE is the largest frame payload the sender of the
+/// setting is willing to receive: frames carrying the response must fit in
+/// it, however large the response is.
+#[test]
+fn response_frames_within_max_frame_size() {
+use __group::response_frames_within_max_frame_size as test;
+$body
+}
+}
+
+__httpwg_test! { response_frames_within_raised_max_frame_size
+/// After the client raises SETTINGS_MAX_FRAME_SIZE, frames carrying the
+/// response must still fit in the new value.
+#[test]
+fn response_frames_within_raised_max_frame_size() {
+use __group::response_frames_within_raised_max_frame_size as test;
+$body
+}
+}
+
+__httpwg_test! { invalid_header_block_fragment
 /// A decoding error in a header block MUST be treated as a connection error
 /// (Section 5.4.1) of type COMPRESSION_ERROR.
 #[test]
@@ -110,7 +167,9 @@ fn invalid_header_block_fragment() {
 use __group::invalid_header_block_fragment as test;
 $body
 }
+}
 
+__httpwg_test! { priority_frame_while_sending_headers
 /// Each header block is processed as a discrete unit. Header blocks
 /// MUST be transmitted as a contiguous sequence of frames, with no
 /// interleaved frames of any other type or from any other stream.
@@ -119,7 +178,9 @@ fn priority_frame_while_sending_headers() {
 use __group::priority_frame_while_sending_headers as test;
 $body
 }
+}
 
+__httpwg_test! { headers_frame_to_another_stream
 /// Each header block is processed as a discrete unit. Header blocks
 /// MUST be transmitted as a contiguous sequence of frames, with no
 /// interleaved frames of any other type or from any other stream.
@@ -129,11 +190,13 @@ use __group::headers_frame_to_another_stream as test;
 $body
 }
 }
+}
 
 /// Section 5: Streams and Multiplexing
 mod _5_streams_and_multiplexing {
 use super::__suite::_5_streams_and_multiplexing as __group;
 
+__httpwg_test! { idle_sends_data_frame
 /// idle:
 /// Receiving any frame other than HEADERS or PRIORITY on a stream
 /// in this state MUST be treated as a connection error
@@ -143,7 +206,9 @@ fn idle_sends_data_frame() {
 use __group::idle_sends_data_frame as test;
 $body
 }
+}
 
+__httpwg_test! { idle_sends_rst_stream_frame
 /// idle:
 /// Receiving any frame other than HEADERS or PRIORITY on a stream
 /// in this state MUST be treated as a connection error
@@ -153,7 +218,9 @@ fn idle_sends_rst_stream_frame() {
 use __group::idle_sends_rst_stream_frame as test;
 $body
 }
+}
 
+__httpwg_test! { idle_sends_window_update_frame
 /// idle:
 /// Receiving any frame other than HEADERS or PRIORITY on a stream
 /// in this state MUST be treated as a connection error
@@ -163,7 +230,9 @@ fn idle_sends_window_update_frame() {
 use __group::idle_sends_window_update_frame as test;
 $body
 }
+}
 
+__httpwg_test! { idle_sends_continuation_frame
 /// idle:
 /// Receiving any frame other than HEADERS or PRIORITY on a stream
 /// in this state MUST be treated as a connection error
@@ -173,7 +242,9 @@ fn idle_sends_continuation_frame() {
 use __group::idle_sends_continuation_frame as test;
 $body
 }
+}
 
+__httpwg_test! { half_closed_remote_sends_data_frame
 /// half-closed (remote):
 /// If an endpoint receives additional frames, other than
 /// WINDOW_UPDATE, PRIORITY, or RST_STREAM, for a stream that is in
@@ -184,7 +255,9 @@ fn half_closed_remote_sends_data_frame() {
 use __group::half_closed_remote_sends_data_frame as test;
 $body
 }
+}
 
+__httpwg_test! { half_closed_remote_sends_headers_frame
 /// half-closed (remote):
 /// If an endpoint receives additional frames, other than
 /// WINDOW_UPDATE, PRIORITY, or RST_STREAM, for a stream that is in
@@ -195,7 +268,9 @@ fn half_closed_remote_sends_headers_frame() {
 use __group::half_closed_remote_sends_headers_frame as test;
 $body
 }
+}
 
+__httpwg_test! { half_closed_remote_sends_continuation_frame
 /// half-closed (remote):
 /// If an endpoint receives additional frames, other than
 /// WINDOW_UPDATE, PRIORITY, or RST_STREAM, for a stream that is in
@@ -206,7 +281,9 @@ fn half_closed_remote_sends_continuation_frame() {
 use __group::half_closed_remote_sends_continuation_frame as test;
 $body
 }
+}
 
+__httpwg_test! { closed_sends_data_frame_after_rst_stream
 /// closed:
 /// An endpoint that receives any frame other than PRIORITY after
 /// receiving a RST_STREAM MUST treat that as a stream error
@@ -216,7 +293,9 @@ fn closed_sends_data_frame_after_rst_stream() {
 use __group::closed_sends_data_frame_after_rst_stream as test;
 $body
 }
+}
 
+__httpwg_test! { closed_sends_headers_frame_after_rst_stream
 /// closed:
 /// An endpoint that receives any frame other than PRIORITY after
 /// receiving a RST_STREAM MUST treat that as a stream error
@@ -226,7 +305,9 @@ fn closed_sends_headers_frame_after_rst_stream() {
 use __group::closed_sends_headers_frame_after_rst_stream as test;
 $body
 }
+}
 
+__httpwg_test! { closed_sends_continuation_frame_after_rst_stream
 /// closed:
 /// An endpoint that receives any frame other than PRIORITY after
 /// receiving a RST_STREAM MUST treat that as a stream error
@@ -236,7 +317,9 @@ fn closed_sends_continuation_frame_after_rst_stream() {
 use __group::closed_sends_continuation_frame_after_rst_stream as test;
 $body
 }
+}
 
+__httpwg_test! { closed_sends_data_frame
 /// closed:
 /// An endpoint that receives any frames after receiving a frame
 /// with the END_STREAM flag set MUST treat that as a connection
@@ -246,7 +329,9 @@ fn closed_sends_data_frame() {
 use __group::closed_sends_data_frame as test;
 $body
 }
+}
 
+__httpwg_test! { closed_sends_headers_frame
 /// closed:
 /// An endpoint that receives any frames after receiving a frame
 /// with the END_STREAM flag set MUST treat that as a connection
@@ -256,7 +341,9 @@ fn closed_sends_headers_frame() {
 use __group::closed_sends_headers_frame as test;
 $body
 }
+}
 
+__httpwg_test! { closed_sends_continuation_frame
 /// closed:
 /// An endpoint that receives any frames after receiving a frame
 /// with the END_STREAM flag set MUST treat that as a connection
@@ -266,7 +353,9 @@ fn closed_sends_continuation_frame() {
 use __group::closed_sends_continuation_frame as test;
 $body
 }
+}
 
+__httpwg_test! { sends_even_numbered_stream_identifier
 /// An endpoint that receives an unexpected stream identifier
 /// MUST respond with a connection error (Section 5.4.1) of
 /// type PROTOCOL_ERROR.
@@ -275,7 +364,9 @@ fn sends_even_numbered_stream_identifier() {
 use __group::sends_even_numbered_stream_identifier as test;
 $body
 }
+}
 
+__httpwg_test! { sends_smaller_stream_identifier
 /// An endpoint that receives an unexpected stream identifier
 /// MUST respond with a connection error (Section 5.4.1) of
 /// type PROTOCOL_ERROR.
@@ -284,13 +375,17 @@ fn sends_smaller_stream_identifier() {
 use __group::sends_smaller_stream_identifier as test;
 $body
 }
+}
 
+__httpwg_test! { exceeds_concurrent_stream_limit
 #[test]
 fn exceeds_concurrent_stream_limit() {
 use __group::exceeds_concurrent_stream_limit as test;
 $body
 }
+}
 
+__httpwg_test! { invalid_ping_frame_for_connection_close
 /// After sending the GOAWAY frame for an error condition,
 /// the endpoint MUST close the TCP connection.
 #[test]
@@ -298,13 +393,17 @@ fn invalid_ping_frame_for_connection_close() {
 use __group::invalid_ping_frame_for_connection_close as test;
 $body
 }
+}
 
+__httpwg_test! { test_invalid_ping_frame_for_goaway
 #[test]
 fn test_invalid_ping_frame_for_goaway() {
 use __group::test_invalid_ping_frame_for_goaway as test;
 $body
 }
+}
 
+__httpwg_test! { unknown_extension_frame_in_header_block
 /// Extension frames that appear in the middle of a header block
 /// (Section 4.3) are not permitted; these MUST be treated as
 /// a connection error (Section 5.4.1) of type PROTOCOL_ERROR.
@@ -314,11 +413,13 @@ use __group::unknown_extension_frame_in_header_block as test;
 $body
 }
 }
+}
 
 /// Section 6: Frame Definitions
 mod _6_frame_definitions {
 use super::__suite::_6_frame_definitions as __group;
 
+__httpwg_test! { sends_data_frame_with_zero_stream_id
 /// DATA frames MUST be associated with a stream. If a DATA frame is
 /// received whose stream identifier field is 0x0, the recipient
 /// MUST respond with a connection error (Section 5.4.1) of type
@@ -328,7 +429,9 @@ fn sends_data_frame_with_zero_stream_id() {
 use __group::sends_data_frame_with_zero_stream_id as test;
 $body
 }
+}
 
+__httpwg_test! { sends_data_frame_on_invalid_stream_state
 /// If a DATA frame is received whose stream is not in "open" or
 /// "half-closed (local)" state, the recipient MUST respond with
 /// a stream error (Section 5.4.2) of type STREAM_CLOSED.
@@ -339,7 +442,9 @@ fn sends_data_frame_on_invalid_stream_state() {
 use __group::sends_data_frame_on_invalid_stream_state as test;
 $body
 }
+}
 
+__httpwg_test! { sends_data_frame_with_invalid_pad_length
 /// If the length of the padding is the length of the frame payload
 /// or greater, the recipient MUST treat this as a connection error
 /// (Section 5.4.1) of type PROTOCOL_ERROR.
@@ -348,7 +453,9 @@ fn sends_data_frame_with_invalid_pad_length() {
 use __group::sends_data_frame_with_invalid_pad_length as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_zero_stream_id
 /// HEADERS frames MUST be associated with a stream. If a HEADERS
 /// frame is received whose stream identifier field is 0x0, the
 /// recipient MUST respond with a connection error (Section 5.4.1)
@@ -358,7 +465,9 @@ fn sends_headers_frame_with_zero_stream_id() {
 use __group::sends_headers_frame_with_zero_stream_id as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_invalid_pad_length
 /// The HEADERS frame can include padding. Padding fields and flags
 /// are identical to those defined for DATA frames (Section 6.1).
 /// Padding that exceeds the size remaining for the header block
@@ -368,7 +477,9 @@ fn sends_headers_frame_with_invalid_pad_length() {
 use __group::sends_headers_frame_with_invalid_pad_length as test;
 $body
 }
+}
 
+__httpwg_test! { sends_priority_frame_with_zero_stream_id
 /// The PRIORITY frame always identifies a stream. If a PRIORITY
 /// frame is received with a stream identifier of 0x0, the recipient
 /// MUST respond with a connection error (Section 5.4.1) of type
@@ -378,7 +489,9 @@ fn sends_priority_frame_with_zero_stream_id() {
 use __group::sends_priority_frame_with_zero_stream_id as test;
 $body
 }
+}
 
+__httpwg_test! { sends_priority_frame_with_invalid_length
 /// A PRIORITY frame with a length other than 5 octets MUST be
 /// treated as a stream error (Section 5.4.2) of type
 /// FRAME_SIZE_ERROR.
@@ -387,7 +500,9 @@ fn sends_priority_frame_with_invalid_length() {
 use __group::sends_priority_frame_with_invalid_length as test;
 $body
 }
+}
 
+__httpwg_test! { sends_rst_stream_frame_with_zero_stream_id
 /// RST_STREAM frames MUST be associated with a stream. If a
 /// RST_STREAM frame is received with a stream identifier of 0x0,
 /// the recipient MUST treat this as a connection error
@@ -397,7 +512,9 @@ fn sends_rst_stream_frame_with_zero_stream_id() {
 use __group::sends_rst_stream_frame_with_zero_stream_id as test;
 $body
 }
+}
 
+__httpwg_test! { sends_rst_stream_frame_on_idle_stream
 /// RST_STREAM frames MUST NOT be sent for a stream in the "idle"
 /// state. If a RST_STREAM frame identifying an idle stream is
 /// received, the recipient MUST treat this as a connection error
@@ -407,7 +524,9 @@ fn sends_rst_stream_frame_on_idle_stream() {
 use __group::sends_rst_stream_frame_on_idle_stream as test;
 $body
 }
+}
 
+__httpwg_test! { sends_rst_stream_frame_with_invalid_length
 /// A RST_STREAM frame with a length other than 4 octets MUST be
 /// treated as a connection error (Section 5.4.1) of type
 /// FRAME_SIZE_ERROR.
@@ -416,7 +535,9 @@ fn sends_rst_stream_frame_with_invalid_length() {
 use __group::sends_rst_stream_frame_with_invalid_length as test;
 $body
 }
+}
 
+__httpwg_test! { sends_settings_frame_with_ack_and_payload
 /// ACK (0x1):
 /// When set, bit 0 indicates that this frame acknowledges receipt
 /// and application of the peer's SETTINGS frame. When this bit is
@@ -429,7 +550,9 @@ fn sends_settings_frame_with_ack_and_payload() {
 use __group::sends_settings_frame_with_ack_and_payload as test;
 $body
 }
+}
 
+__httpwg_test! { sends_settings_frame_with_non_zero_stream_id
 /// SETTINGS frames always apply to a connection, never a single
 /// stream. The stream identifier for a SETTINGS frame MUST be
 /// zero (0x0). If an endpoint receives a SETTINGS frame whose
@@ -441,7 +564,9 @@ fn sends_settings_frame_with_non_zero_stream_id() {
 use __group::sends_settings_frame_with_non_zero_stream_id as test;
 $body
 }
+}
 
+__httpwg_test! { sends_settings_frame_with_invalid_length
 /// The SETTINGS frame affects connection state. A badly formed or
 /// incomplete SETTINGS frame MUST be treated as a connection error
 /// (Section 5.4.1) of type PROTOCOL_ERROR.
@@ -454,7 +579,9 @@ fn sends_settings_frame_with_invalid_length() {
 use __group::sends_settings_frame_with_invalid_length as test;
 $body
 }
+}
 
+__httpwg_test! { sends_settings_enable_push_with_invalid_value
 /// SETTINGS_ENABLE_PUSH (0x2):
 /// The initial value is 1, which indicates that server push is
 /// permitted. Any value other than 0 or 1 MUST be treated as a
@@ -464,7 +591,9 @@ fn sends_settings_enable_push_with_invalid_value() {
 use __group::sends_settings_enable_push_with_invalid_value as test;
 $body
 }
+}
 
+__httpwg_test! { sends_settings_initial_window_size_with_invalid_value
 /// SETTINGS_INITIAL_WINDOW_SIZE (0x4):
 /// Values above the maximum flow-control window size of 2^31-1
 /// MUST be treated as a connection error (Section 5.4.1) of
@@ -474,7 +603,9 @@ fn sends_settings_initial_window_size_with_invalid_value() {
 use __group::sends_settings_initial_window_size_with_invalid_value as test;
 $body
 }
+}
 
+__httpwg_test! { sends_settings_max_frame_size_with_invalid_value_below_initial
 /// SETTINGS_MAX_FRAME_SIZE (0x5):
 /// The initial value is 2^14 (16,384) octets. The value advertised
 /// by an endpoint MUST be between this initial value and the
@@ -486,7 +617,9 @@ fn sends_settings_max_frame_size_with_invalid_value_below_initial() {
 use __group::sends_settings_max_frame_size_with_invalid_value_below_initial as test;
 $body
 }
+}
 
+__httpwg_test! { sends_settings_max_frame_size_with_invalid_value_above_max
 /// SETTINGS_MAX_FRAME_SIZE (0x5):
 /// The initial value is 2^14 (16,384) octets. The value advertised
 /// by an endpoint MUST be between this initial value and the
@@ -498,7 +631,9 @@ fn sends_settings_max_frame_size_with_invalid_value_above_max() {
 use __group::sends_settings_max_frame_size_with_invalid_value_above_max as test;
 $body
 }
+}
 
+__httpwg_test! { sends_settings_frame_with_unknown_identifier
 /// An endpoint that receives a SETTINGS frame with any unknown
 /// or unsupported identifier MUST ignore that setting.
 #[test]
@@ -506,7 +641,9 @@ fn sends_settings_frame_with_unknown_identifier() {
 use __group::sends_settings_frame_with_unknown_identifier as test;
 $body
 }
+}
 
+__httpwg_test! { sends_multiple_values_of_settings_initial_window_size
 /// The values in the SETTINGS frame MUST be processed in the order
 /// they appear, with no other frame processing between values.
 #[test]
@@ -514,7 +651,9 @@ fn sends_multiple_values_of_settings_initial_window_size() {
 use __group::sends_multiple_values_of_settings_initial_window_size as test;
 $body
 }
+}
 
+__httpwg_test! { sends_settings_frame_without_ack_flag
 /// Once all values have been processed, the recipient MUST
 /// immediately emit a SETTINGS frame with the ACK flag set.
 #[test]
@@ -522,7 +661,9 @@ fn sends_settings_frame_without_ack_flag() {
 use __group::sends_settings_frame_without_ack_flag as test;
 $body
 }
+}
 
+__httpwg_test! { sends_ping_frame
 /// Receivers of a PING frame that does not include an ACK flag MUST
 /// send a PING frame with the ACK flag set in response, with an
 /// identical payload.
@@ -531,7 +672,9 @@ fn sends_ping_frame() {
 use __group::sends_ping_frame as test;
 $body
 }
+}
 
+__httpwg_test! { sends_ping_frame_with_ack
 /// ACK (0x1):
 /// When set, bit 0 indicates that this PING frame is a PING
 /// response. An endpoint MUST set this flag in PING responses.
@@ -542,7 +685,9 @@ fn sends_ping_frame_with_ack() {
 use __group::sends_ping_frame_with_ack as test;
 $body
 }
+}
 
+__httpwg_test! { sends_ping_frame_with_non_zero_stream_id
 /// If a PING frame is received with a stream identifier field value
 /// other than 0x0, the recipient MUST respond with a connection
 /// error (Section 5.4.1) of type PROTOCOL_ERROR.
@@ -551,7 +696,9 @@ fn sends_ping_frame_with_non_zero_stream_id() {
 use __group::sends_ping_frame_with_non_zero_stream_id as test;
 $body
 }
+}
 
+__httpwg_test! { sends_ping_frame_with_invalid_length
 /// Receipt of a PING frame with a length field value other than 8
 /// MUST be treated as a connection error (Section 5.4.1) of type
 /// FRAME_SIZE_ERROR.
@@ -560,7 +707,9 @@ fn sends_ping_frame_with_invalid_length() {
 use __group::sends_ping_frame_with_invalid_length as test;
 $body
 }
+}
 
+__httpwg_test! { sends_goaway_frame_with_non_zero_stream_id
 /// An endpoint MUST treat a GOAWAY frame with a stream identifier
 /// other than 0x0 as a connection error (Section 5.4.1) of type
 /// PROTOCOL_ERROR.
@@ -569,7 +718,9 @@ fn sends_goaway_frame_with_non_zero_stream_id() {
 use __group::sends_goaway_frame_with_non_zero_stream_id as test;
 $body
 }
+}
 
+__httpwg_test! { sends_window_update_frame_with_zero_increment
 /// A receiver MUST treat the receipt of a WINDOW_UPDATE frame with
 /// a flow-control window increment of 0 as a stream error
 /// (Section 5.4.2) of type PROTOCOL_ERROR; errors on the connection
@@ -580,7 +731,9 @@ fn sends_window_update_frame_with_zero_increment() {
 use __group::sends_window_update_frame_with_zero_increment as test;
 $body
 }
+}
 
+__httpwg_test! { sends_window_update_frame_with_zero_increment_on_stream
 /// A receiver MUST treat the receipt of a WINDOW_UPDATE frame with
 /// a flow-control window increment of 0 as a stream error
 /// (Section 5.4.2) of type PROTOCOL_ERROR; errors on the connection
@@ -591,7 +744,9 @@ fn sends_window_update_frame_with_zero_increment_on_stream() {
 use __group::sends_window_update_frame_with_zero_increment_on_stream as test;
 $body
 }
+}
 
+__httpwg_test! { sends_window_update_frame_with_invalid_length
 /// A WINDOW_UPDATE frame with a length other than 4 octets MUST
 /// be treated as a connection error (Section 5.4.1) of type
 /// FRAME_SIZE_ERROR.
@@ -600,7 +755,9 @@ fn sends_window_update_frame_with_invalid_length() {
 use __group::sends_window_update_frame_with_invalid_length as test;
 $body
 }
+}
 
+__httpwg_test! { sends_settings_frame_to_set_initial_window_size_to_1_and_sends_headers_frame
 /// The sender MUST NOT send a flow-controlled frame with a length
 /// that exceeds the space available in either of the flow-control
 /// windows advertised by the receiver.
@@ -609,7 +766,9 @@ fn sends_settings_frame_to_set_initial_window_size_to_1_and_sends_headers_frame(
 use __group::sends_settings_frame_to_set_initial_window_size_to_1_and_sends_headers_frame as test;
 $body
 }
+}
 
+__httpwg_test! { sends_multiple_window_update_frames_increasing_flow_control_window_above_max
 /// A sender MUST NOT allow a flow-control window to exceed 2^31-1
 /// octets. If a sender receives a WINDOW_UPDATE that causes a
 /// flow-control window to exceed this maximum, it MUST terminate
@@ -622,7 +781,9 @@ fn sends_multiple_window_update_frames_increasing_flow_control_window_above_max(
 use __group::sends_multiple_window_update_frames_increasing_flow_control_window_above_max as test;
 $body
 }
+}
 
+__httpwg_test! { sends_multiple_window_update_frames_increasing_flow_control_window_above_max_on_stream
 /// A sender MUST NOT allow a flow-control window to exceed 2^31-1
 /// octets. If a sender receives a WINDOW_UPDATE that causes a
 /// flow-control window to exceed this maximum, it MUST terminate
@@ -635,7 +796,21 @@ fn sends_multiple_window_update_frames_increasing_flow_control_window_above_max_
 use __group::sends_multiple_window_update_frames_increasing_flow_control_window_above_max_on_stream as test;
 $body
 }
+}
+
+__httpwg_test! { exhausts_connection_window_while_another_stream_waits
+/// The connection flow-control window is shared by all streams: once it's
+/// exhausted, streams that have a response to send wait for a WINDOW_UPDATE
+/// on stream 0, and all of them resume when it comes, including those that
+/// haven't sent a single byte yet.
+#[test]
+fn exhausts_connection_window_while_another_stream_waits() {
+use __group::exhausts_connection_window_while_another_stream_waits as test;
+$body
+}
+}
 
+__httpwg_test! { changes_settings_initial_window_size_after_sending_headers_frame
 /// When the value of SETTINGS_INITIAL_WINDOW_SIZE changes,
 /// a receiver MUST adjust the size of all stream flow-control
 /// windows that it maintains by the difference between the new
@@ -645,7 +820,9 @@ fn changes_settings_initial_window_size_after_sending_headers_frame() {
 use __group::changes_settings_initial_window_size_after_sending_headers_frame as test;
 $body
 }
+}
 
+__httpwg_test! { sends_settings_frame_for_window_size_to_be_negative
 /// A sender MUST track the negative flow-control window and
 /// MUST NOT send new flow-controlled frames until it receives
 /// WINDOW_UPDATE frames that cause the flow-control window to
@@ -655,7 +832,9 @@ fn sends_settings_frame_for_window_size_to_be_negative() {
 use __group::sends_settings_frame_for_window_size_to_be_negative as test;
 $body
 }
+}
 
+__httpwg_test! { sends_settings_initial_window_size_with_exceeded_max_window_size_value
 /// An endpoint MUST treat a change to SETTINGS_INITIAL_WINDOW_SIZE
 /// that causes any flow-control window to exceed the maximum size
 /// as a connection error (Section 5.4.1) of type FLOW_CONTROL_ERROR.
@@ -664,7 +843,9 @@ fn sends_settings_initial_window_size_with_exceeded_max_window_size_value() {
 use __group::sends_settings_initial_window_size_with_exceeded_max_window_size_value as test;
 $body
 }
+}
 
+__httpwg_test! { sends_multiple_continuation_frames_preceded_by_headers_frame
 /// The CONTINUATION frame (type=0x9) is used to continue a sequence
 /// of header block fragments (Section 4.3). Any number of
 /// CONTINUATION frames can be sent, as long as the preceding frame
@@ -675,7 +856,9 @@ fn sends_multiple_continuation_frames_preceded_by_headers_frame() {
 use __group::sends_multiple_continuation_frames_preceded_by_headers_frame as test;
 $body
 }
+}
 
+__httpwg_test! { sends_continuation_frame_followed_by_non_continuation_frame
 /// END_HEADERS (0x4):
 /// If the END_HEADERS bit is not set, this frame MUST be followed
 /// by another CONTINUATION frame. A receiver MUST treat the receipt
@@ -686,7 +869,9 @@ fn sends_continuation_frame_followed_by_non_continuation_frame() {
 use __group::sends_continuation_frame_followed_by_non_continuation_frame as test;
 $body
 }
+}
 
+__httpwg_test! { sends_continuation_frame_with_zero_stream_id
 /// CONTINUATION frames MUST be associated with a stream. If a
 /// CONTINUATION frame is received whose stream identifier field is
 /// 0x0, the recipient MUST respond with a connection error
@@ -696,7 +881,9 @@ fn sends_continuation_frame_with_zero_stream_id() {
 use __group::sends_continuation_frame_with_zero_stream_id as test;
 $body
 }
+}
 
+__httpwg_test! { sends_continuation_frame_preceded_by_headers_frame_with_end_headers_flag
 /// A CONTINUATION frame MUST be preceded by a HEADERS, PUSH_PROMISE
 /// or CONTINUATION frame without the END_HEADERS flag set.
 /// A recipient that observes violation of this rule MUST respond
@@ -706,7 +893,9 @@ fn sends_continuation_frame_preceded_by_headers_frame_with_end_headers_flag() {
 use __group::sends_continuation_frame_preceded_by_headers_frame_with_end_headers_flag as test;
 $body
 }
+}
 
+__httpwg_test! { sends_continuation_frame_preceded_by_continuation_frame_with_end_headers_flag
 /// A CONTINUATION frame MUST be preceded by a HEADERS, PUSH_PROMISE
 /// or CONTINUATION frame without the END_HEADERS flag set.
 /// A recipient that observes violation of this rule MUST respond
@@ -716,7 +905,9 @@ fn sends_continuation_frame_preceded_by_continuation_frame_with_end_headers_flag
 use __group::sends_continuation_frame_preceded_by_continuation_frame_with_end_headers_flag as test;
 $body
 }
+}
 
+__httpwg_test! { sends_continuation_frame_preceded_by_data_frame
 /// A CONTINUATION frame MUST be preceded by a HEADERS, PUSH_PROMISE
 /// or CONTINUATION frame without the END_HEADERS flag set.
 /// A recipient that observes violation of this rule MUST respond
@@ -727,11 +918,13 @@ use __group::sends_continuation_frame_preceded_by_data_frame as test;
 $body
 }
 }
+}
 
 /// Section 7: Error Codes
 mod _7_error_codes {
 use super::__suite::_7_error_codes as __group;
 
+__httpwg_test! { sends_goaway_frame_with_unknown_error_code
 /// Unknown or unsupported error codes MUST NOT trigger any special
 /// behavior. These MAY be treated by an implementation as being
 /// equivalent to INTERNAL_ERROR.
@@ -740,7 +933,9 @@ fn sends_goaway_frame_with_unknown_error_code() {
 use __group::sends_goaway_frame_with_unknown_error_code as test;
 $body
 }
+}
 
+__httpwg_test! { sends_rst_stream_frame_with_unknown_error_code
 /// Unknown or unsupported error codes MUST NOT trigger any special
 /// behavior. These MAY be treated by an implementation as being
 /// equivalent to INTERNAL_ERROR.
@@ -750,29 +945,37 @@ use __group::sends_rst_stream_frame_with_unknown_error_code as test;
 $body
 }
 }
+}
 
 /// Section 8: Expressing HTTP Semantics in HTTP/2
 mod _8_expressing_http_semantics_in_http2 {
 use super::__suite::_8_expressing_http_semantics_in_http2 as __group;
 
+__httpwg_test! { sends_second_headers_frame_without_end_stream
 #[test]
 fn sends_second_headers_frame_without_end_stream() {
 use __group::sends_second_headers_frame_without_end_stream as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_incorrect_content_length_single_data_frame
 #[test]
 fn sends_headers_frame_with_incorrect_content_length_single_data_frame() {
 use __group::sends_headers_frame_with_incorrect_content_length_single_data_frame as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_incorrect_content_length_multiple_data_frames
 #[test]
 fn sends_headers_frame_with_incorrect_content_length_multiple_data_frames() {
 use __group::sends_headers_frame_with_incorrect_content_length_multiple_data_frames as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_uppercase_field_name
 /// A field name MUST NOT contain characters in the ranges 0x00-0x20, 0x41-0x5a,
 /// or 0x7f-0xff (all ranges inclusive). This specifically excludes all
 /// non-visible ASCII characters, ASCII SP (0x20), and uppercase characters ('A'
@@ -787,7 +990,9 @@ fn sends_headers_frame_with_uppercase_field_name() {
 use __group::sends_headers_frame_with_uppercase_field_name as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_space_in_field_name
 /// A field name MUST NOT contain characters in the ranges 0x00-0x20, 0x41-0x5a,
 /// or 0x7f-0xff (all ranges inclusive). This specifically excludes all
 /// non-visible ASCII characters, ASCII SP (0x20), and uppercase characters ('A'
@@ -802,7 +1007,9 @@ fn sends_headers_frame_with_space_in_field_name() {
 use __group::sends_headers_frame_with_space_in_field_name as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_non_visible_ascii
 /// A field name MUST NOT contain characters in the ranges 0x00-0x20, 0x41-0x5a,
 /// or 0x7f-0xff (all ranges inclusive). This specifically excludes all
 /// non-visible ASCII characters, ASCII SP (0x20), and uppercase characters ('A'
@@ -817,7 +1024,9 @@ fn sends_headers_frame_with_non_visible_ascii() {
 use __group::sends_headers_frame_with_non_visible_ascii as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_del_character
 /// A field name MUST NOT contain characters in the ranges 0x00-0x20, 0x41-0x5a,
 /// or 0x7f-0xff (all ranges inclusive). This specifically excludes all
 /// non-visible ASCII characters, ASCII SP (0x20), and uppercase characters ('A'
@@ -832,7 +1041,9 @@ fn sends_headers_frame_with_del_character() {
 use __group::sends_headers_frame_with_del_character as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_non_ascii_character
 /// A field name MUST NOT contain characters in the ranges 0x00-0x20, 0x41-0x5a,
 /// or 0x7f-0xff (all ranges inclusive). This specifically excludes all
 /// non-visible ASCII characters, ASCII SP (0x20), and uppercase characters ('A'
@@ -847,7 +1058,9 @@ fn sends_headers_frame_with_non_ascii_character() {
 use __group::sends_headers_frame_with_non_ascii_character as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_colon_in_field_name
 /// With the exception of pseudo-header fields (Section 8.3), which have a name
 /// that starts with a single colon, field names MUST NOT include a colon (ASCII
 /// COLON, 0x3a).
@@ -861,7 +1074,9 @@ fn sends_headers_frame_with_colon_in_field_name() {
 use __group::sends_headers_frame_with_colon_in_field_name as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_lf_in_field_value
 /// A field value MUST NOT contain the zero value (ASCII NUL, 0x00), line feed
 /// (ASCII LF, 0x0a), or carriage return (ASCII CR, 0x0d) at any position.
 ///
@@ -874,7 +1089,9 @@ fn sends_headers_frame_with_lf_in_field_value() {
 use __group::sends_headers_frame_with_lf_in_field_value as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_cr_in_field_value
 /// A field value MUST NOT contain the zero value (ASCII NUL, 0x00), line feed
 /// (ASCII LF, 0x0a), or carriage return (ASCII CR, 0x0d) at any position.
 ///
@@ -887,7 +1104,9 @@ fn sends_headers_frame_with_cr_in_field_value() {
 use __group::sends_headers_frame_with_cr_in_field_value as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_nul_in_field_value
 /// A field value MUST NOT contain the zero value (ASCII NUL, 0x00), line feed
 /// (ASCII LF, 0x0a), or carriage return (ASCII CR, 0x0d) at any position.
 ///
@@ -900,7 +1119,9 @@ fn sends_headers_frame_with_nul_in_field_value() {
 use __group::sends_headers_frame_with_nul_in_field_value as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_leading_space_in_field_value
 /// A field value MUST NOT start or end with an ASCII whitespace character
 /// (ASCII SP or HTAB, 0x20 or 0x09).
 /// When a request message violates one of these requirements, an implementation
@@ -912,7 +1133,9 @@ fn sends_headers_frame_with_leading_space_in_field_value() {
 use __group::sends_headers_frame_with_leading_space_in_field_value as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_trailing_tab_in_field_value
 /// A field value MUST NOT start or end with an ASCII whitespace character
 /// (ASCII SP or HTAB, 0x20 or 0x09).
 /// When a request message violates one of these requirements, an implementation
@@ -924,7 +1147,9 @@ fn sends_headers_frame_with_trailing_tab_in_field_value() {
 use __group::sends_headers_frame_with_trailing_tab_in_field_value as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_connection_header
 /// HTTP/2 does not use the Connection header field (Section 7.6.1 of HTTP) to
 /// indicate connection-specific header fields; in this protocol,
 /// connection-specific metadata is conveyed by other means. An endpoint MUST
@@ -939,7 +1164,9 @@ fn sends_headers_frame_with_connection_header() {
 use __group::sends_headers_frame_with_connection_header as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_proxy_connection_header
 /// HTTP/2 does not use the Connection header field (Section 7.6.1 of HTTP) to
 /// indicate connection-specific header fields; in this protocol,
 /// connection-specific metadata is conveyed by other means. An endpoint MUST
@@ -955,7 +1182,9 @@ fn sends_headers_frame_with_proxy_connection_header() {
 use __group::sends_headers_frame_with_proxy_connection_header as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_keep_alive_header
 /// HTTP/2 does not use the Connection header field (Section 7.6.1 of HTTP) to
 /// indicate connection-specific header fields; in this protocol,
 /// connection-specific metadata is conveyed by other means. An endpoint MUST
@@ -971,7 +1200,9 @@ fn sends_headers_frame_with_keep_alive_header() {
 use __group::sends_headers_frame_with_keep_alive_header as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_transfer_encoding_header
 /// HTTP/2 does not use the Connection header field (Section 7.6.1 of HTTP) to
 /// indicate connection-specific header fields; in this protocol,
 /// connection-specific metadata is conveyed by other means. An endpoint MUST
@@ -987,7 +1218,9 @@ fn sends_headers_frame_with_transfer_encoding_header() {
 use __group::sends_headers_frame_with_transfer_encoding_header as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_upgrade_header
 /// HTTP/2 does not use the Connection header field (Section 7.6.1 of HTTP) to
 /// indicate connection-specific header fields; in this protocol,
 /// connection-specific metadata is conveyed by other means. An endpoint MUST
@@ -1003,7 +1236,9 @@ fn sends_headers_frame_with_upgrade_header() {
 use __group::sends_headers_frame_with_upgrade_header as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_te_trailers
 /// The only exception to this is the TE header field, which MAY be present in
 /// an HTTP/2 request; when it is, it MUST NOT contain any value other than
 /// "trailers".
@@ -1012,7 +1247,9 @@ fn sends_headers_frame_with_te_trailers() {
 use __group::sends_headers_frame_with_te_trailers as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_te_not_trailers
 /// The only exception to this is the TE header field, which MAY be present in
 /// an HTTP/2 request; when it is, it MUST NOT contain any value other than
 /// "trailers".
@@ -1021,7 +1258,9 @@ fn sends_headers_frame_with_te_not_trailers() {
 use __group::sends_headers_frame_with_te_not_trailers as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_response_pseudo_header
 /// [...] pseudo-header fields defined for responses MUST NOT appear in requests
 /// [...] Endpoints MUST treat a request or response that contains undefined or
 /// invalid pseudo-header fields as malformed (Section 8.1.1).
@@ -1030,7 +1269,21 @@ fn sends_headers_frame_with_response_pseudo_header() {
 use __group::sends_headers_frame_with_response_pseudo_header as test;
 $body
 }
+}
+
+__httpwg_test! { sends_headers_frame_with_unknown_pseudo_header
+/// [...] Endpoints MUST NOT generate pseudo-header fields other than those
+/// defined in this document. [...] Endpoints MUST treat a request or response
+/// that contains undefined or invalid pseudo-header fields as malformed
+/// (Section 8.1.1).
+#[test]
+fn sends_headers_frame_with_unknown_pseudo_header() {
+use __group::sends_headers_frame_with_unknown_pseudo_header as test;
+$body
+}
+}
 
+__httpwg_test! { sends_headers_frame_with_pseudo_header_in_trailer
 /// [...] Pseudo-header fields MUST NOT appear in a trailer section. Endpoints
 /// MUST treat a request or response that contains undefined or invalid
 /// pseudo-header fields as malformed (Section 8.1.1).
@@ -1039,7 +1292,9 @@ fn sends_headers_frame_with_pseudo_header_in_trailer() {
 use __group::sends_headers_frame_with_pseudo_header_in_trailer as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_duplicate_pseudo_headers
 /// The same pseudo-header field name MUST NOT appear more than once in a field
 /// block. A field block for an HTTP request or response that contains a
 /// repeated pseudo-header field name MUST be treated as malformed (Section
@@ -1049,7 +1304,9 @@ fn sends_headers_frame_with_duplicate_pseudo_headers() {
 use __group::sends_headers_frame_with_duplicate_pseudo_headers as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_mismatched_host_authority
 /// A server SHOULD treat a request as malformed if it contains a Host header
 /// field that identifies an entity that differs from the entity in the
 /// ":authority" pseudo-header field. The values of fields need to be normalized
@@ -1063,7 +1320,9 @@ fn sends_headers_frame_with_mismatched_host_authority() {
 use __group::sends_headers_frame_with_mismatched_host_authority as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_empty_path_component
 /// This pseudo-header field MUST NOT be empty for "http" or "https" URIs;
 /// "http" or "https" URIs that do not contain a path component MUST include a
 /// value of '/'. The exceptions to this rule are:
@@ -1077,7 +1336,9 @@ fn sends_headers_frame_with_empty_path_component() {
 use __group::sends_headers_frame_with_empty_path_component as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_without_method
 /// All HTTP/2 requests MUST include exactly one valid value for the ":method",
 /// ":scheme", and ":path" pseudo-header fields, unless they are CONNECT
 /// requests (Section 8.5). An HTTP request that omits mandatory pseudo-header
@@ -1087,25 +1348,33 @@ fn sends_headers_frame_without_method() {
 use __group::sends_headers_frame_without_method as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_without_scheme
 #[test]
 fn sends_headers_frame_without_scheme() {
 use __group::sends_headers_frame_without_scheme as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_without_path
 #[test]
 fn sends_headers_frame_without_path() {
 use __group::sends_headers_frame_without_path as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_without_status
 #[test]
 fn sends_headers_frame_without_status() {
 use __group::sends_headers_frame_without_status as test;
 $body
 }
+}
 
+__httpwg_test! { client_sends_push_promise_frame
 /// A client cannot push. Thus, servers MUST treat the receipt of a PUSH_PROMISE
 /// frame as a connection error (Section 5.4.1) of type PROTOCOL_ERROR. A server
 /// cannot set the SETTINGS_ENABLE_PUSH setting to a value other than 0 (see
@@ -1115,7 +1384,9 @@ fn client_sends_push_promise_frame() {
 use __group::client_sends_push_promise_frame as test;
 $body
 }
+}
 
+__httpwg_test! { sends_connect_with_scheme
 /// The CONNECT method (Section 9.3.6 of HTTP) is used to convert an HTTP
 /// connection into a tunnel to a remote host. CONNECT is primarily used with
 /// HTTP proxies to establish a TLS session with an origin server for the
@@ -1136,19 +1407,25 @@ fn sends_connect_with_scheme() {
 use __group::sends_connect_with_scheme as test;
 $body
 }
+}
 
+__httpwg_test! { sends_connect_with_path
 #[test]
 fn sends_connect_with_path() {
 use __group::sends_connect_with_path as test;
 $body
 }
+}
 
+__httpwg_test! { sends_connect_without_authority
 #[test]
 fn sends_connect_without_authority() {
 use __group::sends_connect_without_authority as test;
 $body
 }
+}
 
+__httpwg_test! { sends_headers_frame_with_pseudo_headers_after_regular_headers
 /// All pseudo-header fields MUST appear in a field block before all regular
 /// field lines (RFC 9113, section 8.3)
 #[test]
@@ -1159,6 +1436,157 @@ $body
 }
 }
 }
+
+/// RFC 9218 describes a scheme that allows an HTTP client to communicate
+/// its preferences for how the upstream server prioritizes responses to
+/// its requests, and also allows a server to hint to a downstream
+/// intermediary how its responses should be prioritized when they are
+/// forwarded.
+///
+/// This document defines the Priority header field for communicating the
+/// initial priority in an HTTP version-independent manner, as well as
+/// HTTP/2 and HTTP/3 frames for reprioritizing responses.
+///
+/// cf. <https://httpwg.org/specs/rfc9218.html>
+#[cfg(test)]
+mod rfc9218 {
+use ::httpwg::rfc9218 as __suite;
+
+/// Section 5: The Priority HTTP Header Field
+mod _5_the_priority_http_header_field {
+use super::__suite::_5_the_priority_http_header_field as __group;
+
+__httpwg_test! { sends_request_with_priority_header
+/// The Priority HTTP header field is an SF Dictionary that carries
+/// priority parameters (see Section 4). It can appear in requests and
+/// responses.
+#[test]
+fn sends_request_with_priority_header() {
+use __group::sends_request_with_priority_header as test;
+$body
+}
+}
+
+__httpwg_test! { sends_request_with_invalid_priority_header
+/// Unknown parameters, parameters with out-of-range values, or parameters
+/// with values of unexpected types MUST be ignored.
+#[test]
+fn sends_request_with_invalid_priority_header() {
+use __group::sends_request_with_invalid_priority_header as test;
+$body
+}
+}
+}
+
+/// Section 7: Reprioritization
+mod _7_reprioritization {
+use super::__suite::_7_reprioritization as __group;
+
+__httpwg_test! { sends_priority_update_for_open_stream
+/// The PRIORITY_UPDATE frame (type=0x10) is used by clients to signal the
+/// initial priority of a response, or to reprioritize a response or push
+/// stream. It carries the stream ID of the response and the priority in
+/// ASCII text, using the same representation as the Priority header field
+/// value.
+#[test]
+fn sends_priority_update_for_open_stream() {
+use __group::sends_priority_update_for_open_stream as test;
+$body
+}
+}
+
+__httpwg_test! { sends_priority_update_for_idle_stream
+/// When the PRIORITY_UPDATE frame applies to a request stream, clients
+/// SHOULD provide a prioritized stream ID that refers to a stream in the
+/// "open", "half-closed (local)", or "idle" state [...] Servers can
+/// receive a PRIORITY_UPDATE frame for an idle stream, the priority it
+/// carries applies once the request arrives.
+#[test]
+fn sends_priority_update_for_idle_stream() {
+use __group::sends_priority_update_for_idle_stream as test;
+$body
+}
+}
+
+__httpwg_test! { sends_priority_update_for_closed_stream
+/// A client MAY send a PRIORITY_UPDATE frame for a stream that the
+/// server has already closed: the server ignores it.
+#[test]
+fn sends_priority_update_for_closed_stream() {
+use __group::sends_priority_update_for_closed_stream as test;
+$body
+}
+}
+
+__httpwg_test! { sends_priority_update_with_invalid_field_value
+/// Unknown parameters, parameters with out-of-range values, or parameters
+/// with values of unexpected types MUST be ignored.
+#[test]
+fn sends_priority_update_with_invalid_field_value() {
+use __group::sends_priority_update_with_invalid_field_value as test;
+$body
+}
+}
+
+__httpwg_test! { sends_priority_update_with_non_zero_stream_id
+/// The PRIORITY_UPDATE frame MUST be sent on stream 0. If a
+/// PRIORITY_UPDATE frame is received with a Stream Identifier other than
+/// 0x0, the recipient MUST respond with a connection error (Section 5.4.1
+/// of [HTTP/2]) of type PROTOCOL_ERROR.
+#[test]
+fn sends_priority_update_with_non_zero_stream_id() {
+use __group::sends_priority_update_with_non_zero_stream_id as test;
+$body
+}
+}
+
+__httpwg_test! { sends_priority_update_with_zero_prioritized_stream_id
+/// If a PRIORITY_UPDATE frame is received with a Prioritized Stream ID of
+/// 0x0, the recipient MUST respond with a connection error of type
+/// PROTOCOL_ERROR.
+#[test]
+fn sends_priority_update_with_zero_prioritized_stream_id() {
+use __group::sends_priority_update_with_zero_prioritized_stream_id as test;
+$body
+}
+}
+
+__httpwg_test! { sends_priority_update_for_push_stream
+/// If a server receives a PRIORITY_UPDATE frame with a Prioritized Stream
+/// ID that refers to a push stream that is not in the "reserved (local)"
+/// or "half-closed (remote)" state, the server MUST respond with a
+/// connection error of type PROTOCOL_ERROR.
+#[test]
+fn sends_priority_update_for_push_stream() {
+use __group::sends_priority_update_for_push_stream as test;
+$body
+}
+}
+
+__httpwg_test! { sends_priority_update_with_invalid_length
+/// A PRIORITY_UPDATE frame with a length less than 4 bytes MUST be
+/// treated as a connection error of type FRAME_SIZE_ERROR.
+#[test]
+fn sends_priority_update_with_invalid_length() {
+use __group::sends_priority_update_with_invalid_length as test;
+$body
+}
+}
+}
+}
+  };
+  (ignore: [$($ignored:ident),* $(,)?], should_panic: [$($failing:ident),* $(,)?], $body: tt) => {
+    $crate::tests! { @expand ($) [$($ignored)*] [$($failing)*] $body }
+  };
+  (ignore: [$($ignored:ident),* $(,)?], $body: tt) => {
+    $crate::tests! { @expand ($) [$($ignored)*] [] $body }
+  };
+  (should_panic: [$($failing:ident),* $(,)?], $body: tt) => {
+    $crate::tests! { @expand ($) [] [$($failing)*] $body }
+  };
+  ($body: tt) => {
+    $crate::tests! { @expand ($) [] [] $body }
+  };
 }
 
 /// This generates a function that returns a Catalog of type
@@ -1215,6 +1643,14 @@ macro_rules! gen_catalog {
                     "large headers frame exceeding max size",
                     Box::new(|conn: Conn<IO>| Box::pin(s::large_headers_frame_exceeding_max_size(conn))),
                 );
+                _4_http_frames.insert(
+                    "response frames within max frame size",
+                    Box::new(|conn: Conn<IO>| Box::pin(s::response_frames_within_max_frame_size(conn))),
+                );
+                _4_http_frames.insert(
+                    "response frames within raised max frame size",
+                    Box::new(|conn: Conn<IO>| Box::pin(s::response_frames_within_raised_max_frame_size(conn))),
+                );
                 _4_http_frames.insert(
                     "invalid header block fragment",
                     Box::new(|conn: Conn<IO>| Box::pin(s::invalid_header_block_fragment(conn))),
@@ -1441,6 +1877,10 @@ macro_rules! gen_catalog {
                     "sends multiple window update frames increasing flow control window above max on stream",
                     Box::new(|conn: Conn<IO>| Box::pin(s::sends_multiple_window_update_frames_increasing_flow_control_window_above_max_on_stream(conn))),
                 );
+                _6_frame_definitions.insert(
+                    "exhausts connection window while another stream waits",
+                    Box::new(|conn: Conn<IO>| Box::pin(s::exhausts_connection_window_while_another_stream_waits(conn))),
+                );
                 _6_frame_definitions.insert(
                     "changes settings initial window size after sending headers frame",
                     Box::new(|conn: Conn<IO>| Box::pin(s::changes_settings_initial_window_size_after_sending_headers_frame(conn))),
@@ -1587,6 +2027,10 @@ macro_rules! gen_catalog {
                     "sends headers frame with response pseudo header",
                     Box::new(|conn: Conn<IO>| Box::pin(s::sends_headers_frame_with_response_pseudo_header(conn))),
                 );
+                _8_expressing_http_semantics_in_http2.insert(
+                    "sends headers frame with unknown pseudo header",
+                    Box::new(|conn: Conn<IO>| Box::pin(s::sends_headers_frame_with_unknown_pseudo_header(conn))),
+                );
                 _8_expressing_http_semantics_in_http2.insert(
                     "sends headers frame with pseudo header in trailer",
                     Box::new(|conn: Conn<IO>| Box::pin(s::sends_headers_frame_with_pseudo_header_in_trailer(conn))),
@@ -1646,6 +2090,67 @@ macro_rules! gen_catalog {
             rfcs.insert("RFC 9113", sections);
         }
 
+        {
+            let mut sections: HashMap<&'static str, _> = Default::default();
+
+            {
+                use ::httpwg::rfc9218::_5_the_priority_http_header_field as s;
+                let mut _5_the_priority_http_header_field: HashMap<&'static str, BoxedTest<IO>> = Default::default();
+
+                _5_the_priority_http_header_field.insert(
+                    "sends request with priority header",
+                    Box::new(|conn: Conn<IO>| Box::pin(s::sends_request_with_priority_header(conn))),
+                );
+                _5_the_priority_http_header_field.insert(
+                    "sends request with invalid priority header",
+                    Box::new(|conn: Conn<IO>| Box::pin(s::sends_request_with_invalid_priority_header(conn))),
+                );
+
+                sections.insert("5. the priority http header field", _5_the_priority_http_header_field);
+            }
+            {
+                use ::httpwg::rfc9218::_7_reprioritization as s;
+                let mut _7_reprioritization: HashMap<&'static str, BoxedTest<IO>> = Default::default();
+
+                _7_reprioritization.insert(
+                    "sends priority update for open stream",
+                    Box::new(|conn: Conn<IO>| Box::pin(s::sends_priority_update_for_open_stream(conn))),
+                );
+                _7_reprioritization.insert(
+                    "sends priority update for idle stream",
+                    Box::new(|conn: Conn<IO>| Box::pin(s::sends_priority_update_for_idle_stream(conn))),
+                );
+                _7_reprioritization.insert(
+                    "sends priority update for closed stream",
+                    Box::new(|conn: Conn<IO>| Box::pin(s::sends_priority_update_for_closed_stream(conn))),
+                );
+                _7_reprioritization.insert(
+                    "sends priority update with invalid field value",
+                    Box::new(|conn: Conn<IO>| Box::pin(s::sends_priority_update_with_invalid_field_value(conn))),
+                );
+                _7_reprioritization.insert(
+                    "sends priority update with non zero stream id",
+                    Box::new(|conn: Conn<IO>| Box::pin(s::sends_priority_update_with_non_zero_stream_id(conn))),
+                );
+                _7_reprioritization.insert(
+                    "sends priority update with zero prioritized stream id",
+                    Box::new(|conn: Conn<IO>| Box::pin(s::sends_priority_update_with_zero_prioritized_stream_id(conn))),
+                );
+                _7_reprioritization.insert(
+                    "sends priority update for push stream",
+                    Box::new(|conn: Conn<IO>| Box::pin(s::sends_priority_update_for_push_stream(conn))),
+                );
+                _7_reprioritization.insert(
+                    "sends priority update with invalid length",
+                    Box::new(|conn: Conn<IO>| Box::pin(s::sends_priority_update_with_invalid_length(conn))),
+                );
+
+                sections.insert("7. reprioritization", _7_reprioritization);
+            }
+
+            rfcs.insert("RFC 9218", sections);
+        }
+
         rfcs
     }
   }
@@ -0,0 +1,248 @@
+//! An IO wrapper that misbehaves on command, to check how a server copes
+//! with a flaky peer: frames split across many reads, a slow reader, or a
+//! connection that drops in the middle of a frame.
+//!
+//! ```ignore
+//! let (io, faults) = FaultyIo::new(io);
+//! let mut conn = Conn::new(config, io);
+//! conn.handshake().await?;
+//!
+//! // the server only gets 4 bytes of the next frame
+//! faults.truncate_after(4);
+//! assert!(conn.send_empty_post_to_root(StreamId(1)).await.is_err());
+//! ```
+
+use std::{cell::RefCell, rc::Rc, time::Duration};
+
+use buffet::{
+    bufpool::{BufResult, IoBufMut},
+    IntoHalves, Piece, ReadOwned, WriteOwned,
+};
+use tokio::sync::Notify;
+
+/// Wraps both halves of `IO`, cf. the [module docs](self)
+pub struct FaultyIo<IO> {
+    inner: IO,
+    faults: Faults,
+}
+
+impl<IO: IntoHalves> FaultyIo<IO> {
+    /// Returns the wrapped IO, and the handle that controls its faults. It
+    /// behaves like `inner` until told otherwise.
+    pub fn new(inner: IO) -> (Self, Faults) {
+        let faults = Faults::default();
+        (
+            Self {
+                inner,
+                faults: faults.clone(),
+            },
+            faults,
+        )
+    }
+}
+
+/// Controls the faults of a [FaultyIo]. Changes apply to the next read or
+/// write.
+#[derive(Clone, Default)]
+pub struct Faults {
+    shared: Rc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: RefCell<State>,
+    /// Wakes up reads in progress when we hang up
+    hung_up: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    max_write: Option<usize>,
+    read_delay: Option<Duration>,
+    /// How many more bytes can be written before we hang up
+    write_budget: Option<u64>,
+    hung_up: bool,
+    /// Shuts down the write half, if it isn't busy writing
+    shutdown_writer: Option<Box<dyn FnOnce()>>,
+}
+
+impl Faults {
+    /// Writes go through at most `max` bytes at a time, so the peer sees
+    /// frames split at arbitrary byte boundaries. `1` sends one byte at a
+    /// time.
+    pub fn split_writes(&self, max: usize) {
+        assert!(max > 0, "writes can't be split into empty chunks");
+        self.shared.state.borrow_mut().max_write = Some(max);
+    }
+
+    /// Each read waits for `delay` before reading anything
+    pub fn delay_reads(&self, delay: Duration) {
+        self.shared.state.borrow_mut().read_delay = Some(delay);
+    }
+
+    /// Lets `len` more bytes through, then hangs up, even if that's in the
+    /// middle of a frame. The write that goes over is cut short, and the rest
+    /// of it fails like any write after a hang-up.
+    pub fn truncate_after(&self, len: u64) {
+        self.shared.state.borrow_mut().write_budget = Some(len);
+        if len == 0 {
+            self.hang_up();
+        }
+    }
+
+    /// Hangs up now: our reads see EOF, including the ones in progress, the
+    /// peer sees EOF, and writes fail with [std::io::ErrorKind::BrokenPipe]
+    pub fn hang_up(&self) {
+        let shutdown_writer = {
+            let mut state = self.shared.state.borrow_mut();
+            state.hung_up = true;
+            state.shutdown_writer.take()
+        };
+        if let Some(shutdown_writer) = shutdown_writer {
+            shutdown_writer();
+        }
+        self.shared.hung_up.notify_waiters();
+    }
+
+    /// Whether we've hung up, on command or because of
+    /// [Faults::truncate_after]
+    pub fn is_hung_up(&self) -> bool {
+        self.shared.state.borrow().hung_up
+    }
+}
+
+impl<IO> IntoHalves for FaultyIo<IO>
+where
+    IO: IntoHalves,
+    IO::Read: 'static,
+    IO::Write: 'static,
+{
+    type Read = FaultyRead<IO::Read>;
+    type Write = FaultyWrite<IO::Write>;
+
+    fn into_halves(self) -> (Self::Read, Self::Write) {
+        let (r, w) = self.inner.into_halves();
+
+        let writer = Rc::new(RefCell::new(Some(w)));
+        self.faults.shared.state.borrow_mut().shutdown_writer = Some(Box::new({
+            let writer = writer.clone();
+            move || {
+                // if it's busy, it'll shut itself down once it's done
+                if let Some(mut w) = writer.borrow_mut().take() {
+                    buffet::spawn(async move {
+                        _ = w.shutdown().await;
+                    });
+                }
+            }
+        }));
+
+        (
+            FaultyRead {
+                inner: r,
+                faults: self.faults.clone(),
+            },
+            FaultyWrite {
+                inner: writer,
+                faults: self.faults,
+            },
+        )
+    }
+}
+
+pub struct FaultyRead<R> {
+    inner: R,
+    faults: Faults,
+}
+
+impl<R: ReadOwned> ReadOwned for FaultyRead<R> {
+    async fn read_owned<B: IoBufMut>(&mut self, mut buf: B) -> BufResult<usize, B> {
+        let delay = self.faults.shared.state.borrow().read_delay;
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+
+        let hung_up = self.faults.shared.hung_up.notified();
+        if self.faults.is_hung_up() {
+            return (Ok(0), buf);
+        }
+
+        // read into a buffer of our own, so we can give up on the read when
+        // we hang up, and still hand `buf` back.
+        let ours = vec![0u8; buf.io_buf_mut_capacity()];
+        tokio::select! {
+            _ = hung_up => (Ok(0), buf),
+            (res, ours) = self.inner.read_owned(ours) => match res {
+                Ok(n) => {
+                    unsafe { buf.slice_mut()[..n].copy_from_slice(&ours[..n]) };
+                    (Ok(n), buf)
+                }
+                Err(e) => (Err(e), buf),
+            },
+        }
+    }
+}
+
+pub struct FaultyWrite<W> {
+    /// Shared with [Faults], so it can shut it down
+    inner: Rc<RefCell<Option<W>>>,
+    faults: Faults,
+}
+
+impl<W: WriteOwned> WriteOwned for FaultyWrite<W> {
+    async fn write_owned(&mut self, buf: impl Into<Piece>) -> BufResult<usize, Piece> {
+        let buf = buf.into();
+
+        let Some(mut w) = self.inner.borrow_mut().take() else {
+            return (Err(hung_up()), buf);
+        };
+
+        let (len, truncated) = {
+            let mut state = self.faults.shared.state.borrow_mut();
+            let mut len = buf.len();
+            if let Some(max) = state.max_write {
+                len = len.min(max);
+            }
+            let mut truncated = false;
+            if let Some(budget) = state.write_budget.as_mut() {
+                if *budget <= len as u64 {
+                    len = *budget as usize;
+                    truncated = true;
+                }
+                *budget -= len as u64;
+            }
+            (len, truncated)
+        };
+
+        let res = if len > 0 {
+            let (res, _) = w.write_owned(buf.clone().split_at(len).0).await;
+            res
+        } else {
+            Ok(0)
+        };
+
+        if truncated {
+            self.faults.hang_up();
+        }
+        if self.faults.is_hung_up() {
+            _ = w.shutdown().await;
+            return match res {
+                Ok(0) => (Err(hung_up()), buf),
+                res => (res, buf),
+            };
+        }
+
+        *self.inner.borrow_mut() = Some(w);
+        (res, buf)
+    }
+
+    async fn shutdown(&mut self) -> std::io::Result<()> {
+        let Some(mut w) = self.inner.borrow_mut().take() else {
+            return Ok(());
+        };
+        w.shutdown().await
+    }
+}
+
+fn hung_up() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "faulty IO hung up")
+}
@@ -8,19 +8,23 @@ use loona_h2::{
     enumflags2,
     nom::{self, Finish},
     ContinuationFlags, DataFlags, ErrorCode, Frame, FrameType, GoAway, HeadersFlags, IntoPiece,
-    KnownErrorCode, PingFlags, PrioritySpec, RstStream, SettingPairs, Settings, SettingsFlags,
-    StreamId, WindowUpdate, PREFACE,
+    KnownErrorCode, Origin, PingFlags, PrioritySpec, PriorityUpdate, RstStream, SettingPairs,
+    Settings, SettingsFlags, StreamId, WindowUpdate, PREFACE,
 };
 use tokio::time::Instant;
 use tracing::{debug, trace};
 
 use crate::rfc9113::default_settings;
 
+pub mod faults;
+pub mod replay;
 pub mod rfc9113;
+pub mod rfc9218;
+pub mod shaping;
 
 pub type BoxedTest<IO> = Box<dyn Fn(Conn<IO>) -> Pin<Box<dyn Future<Output = eyre::Result<()>>>>>;
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct Headers {
     values: VecDeque<(Piece, Piece)>,
 }
@@ -128,27 +132,31 @@ pub enum FrameWaitOutcome {
 
 impl FrameWaitOutcome {
     pub fn unwrap(self) -> (Frame, Roll) {
+        self.into_result().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Like [FrameWaitOutcome::unwrap], but returns an error instead of
+    /// panicking
+    pub fn into_result(self) -> eyre::Result<(Frame, Roll)> {
         match self {
-            FrameWaitOutcome::Success(frame, payload) => (frame, payload),
+            FrameWaitOutcome::Success(frame, payload) => Ok((frame, payload)),
             FrameWaitOutcome::Timeout {
                 wanted,
                 last_frame,
                 waited,
-            } => {
-                panic!(
-                    "Wanted ({wanted:?}), timed out after {waited:?}. Last frame: {last_frame:?}"
-                );
-            }
-            FrameWaitOutcome::Eof { wanted, last_frame } => {
-                panic!("Wanted ({wanted:?}), peer hung up. Last frame: {last_frame:?}");
-            }
+            } => Err(eyre!(
+                "Wanted ({wanted:?}), timed out after {waited:?}. Last frame: {last_frame:?}"
+            )),
+            FrameWaitOutcome::Eof { wanted, last_frame } => Err(eyre!(
+                "Wanted ({wanted:?}), peer hung up. Last frame: {last_frame:?}"
+            )),
             FrameWaitOutcome::IoError {
                 wanted,
                 last_frame,
                 error,
-            } => {
-                panic!("Wanted ({wanted:?}), got I/O error {error}. Last frame: {last_frame:?}")
-            }
+            } => Err(eyre!(
+                "Wanted ({wanted:?}), got I/O error {error}. Last frame: {last_frame:?}"
+            )),
         }
     }
 }
@@ -169,6 +177,8 @@ pub enum FrameT {
     GoAway,
     WindowUpdate,
     Continuation,
+    PriorityUpdate,
+    Origin,
     Unknown,
 }
 
@@ -185,6 +195,8 @@ impl From<FrameType> for FrameT {
             FrameType::GoAway => Self::GoAway,
             FrameType::WindowUpdate => Self::WindowUpdate,
             FrameType::Continuation(_) => Self::Continuation,
+            FrameType::PriorityUpdate => Self::PriorityUpdate,
+            FrameType::Origin => Self::Origin,
             FrameType::Unknown(_) => Self::Unknown,
         }
     }
@@ -422,6 +434,33 @@ impl<IO: IntoHalves> Conn<IO> {
             .await
     }
 
+    /// Send a PRIORITY_UPDATE frame (RFC 9218) for `prioritized_stream_id`
+    pub async fn write_priority_update(
+        &mut self,
+        prioritized_stream_id: StreamId,
+        priority_field_value: &'static [u8],
+    ) -> eyre::Result<()> {
+        self.write_frame(
+            FrameType::PriorityUpdate.into_frame(StreamId::CONNECTION),
+            PriorityUpdate {
+                prioritized_stream_id,
+                priority_field_value: priority_field_value.into(),
+            },
+        )
+        .await
+    }
+
+    /// Send an ORIGIN frame (RFC 8336) listing `origins`
+    pub async fn write_origin(&mut self, origins: &[&'static [u8]]) -> eyre::Result<()> {
+        self.write_frame(
+            FrameType::Origin.into_frame(StreamId::CONNECTION),
+            Origin {
+                origins: origins.iter().map(|&o| o.into()).collect(),
+            },
+        )
+        .await
+    }
+
     /// Send a PING frame and wait for the peer to acknowledge it.
     pub async fn verify_connection_still_alive(&mut self) -> eyre::Result<()> {
         let payload = b"pingpong";
@@ -533,8 +572,8 @@ impl<IO: IntoHalves> Conn<IO> {
 
         self.write_settings(default_settings()).await?;
 
-        let (frame, payload) = self.wait_for_frame(FrameT::Settings).await.unwrap();
-        assert!(
+        let (frame, payload) = self.wait_for_frame(FrameT::Settings).await.into_result()?;
+        eyre::ensure!(
             !frame.is_ack(),
             "server should send their settings first thing (no ack)"
         );
@@ -551,8 +590,8 @@ impl<IO: IntoHalves> Conn<IO> {
         .await?;
 
         // and wait until the server acknowledges our settings
-        let (frame, _payload) = self.wait_for_frame(FrameT::Settings).await.unwrap();
-        assert!(frame.is_ack(), "server should acknowledge our settings");
+        let (frame, _payload) = self.wait_for_frame(FrameT::Settings).await.into_result()?;
+        eyre::ensure!(frame.is_ack(), "server should acknowledge our settings");
 
         Ok(())
     }
@@ -762,7 +801,8 @@ impl<IO: IntoHalves> Conn<IO> {
         }
     }
 
-    fn common_headers(&self, method: &'static str) -> Headers {
+    /// The pseudo-headers every request needs, for `method`
+    pub fn common_headers(&self, method: &'static str) -> Headers {
         let (scheme, default_port) = if self.config.tls {
             ("https", self.config.port == 443)
         } else {
@@ -828,6 +868,26 @@ impl<IO: IntoHalves> Conn<IO> {
         .await
     }
 
+    /// Reads DATA frames on `stream_id` until it ends or `limit` bytes
+    /// (flow-controlled length, padding included) came in. Returns how many
+    /// bytes came in, and whether the stream ended.
+    pub async fn read_data(
+        &mut self,
+        stream_id: StreamId,
+        limit: usize,
+    ) -> eyre::Result<(usize, bool)> {
+        let mut received = 0;
+        while received < limit {
+            let (frame, _payload) = self.wait_for_frame(FrameT::Data).await.into_result()?;
+            assert_eq!(frame.stream_id, stream_id, "unexpected stream ID");
+            received += frame.len as usize;
+            if frame.is_end_stream() {
+                return Ok((received, true));
+            }
+        }
+        Ok((received, false))
+    }
+
     pub async fn encode_and_write_headers(
         &mut self,
         stream_id: StreamId,
@@ -947,7 +1007,7 @@ impl<IO: IntoHalves> Conn<IO> {
             .await
     }
 
-    async fn write_window_update(
+    pub async fn write_window_update(
         &mut self,
         stream_id: StreamId,
         increment: u32,
@@ -972,7 +1032,8 @@ impl<IO: IntoHalves> Conn<IO> {
         Ok(())
     }
 
-    async fn send_req_and_expect_status(
+    /// Sends a request with no body, and checks the status it gets back
+    pub async fn send_req_and_expect_status(
         &mut self,
         stream_id: StreamId,
         headers: &Headers,
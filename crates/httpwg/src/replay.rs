@@ -0,0 +1,268 @@
+//! Byte-level reproductions of h2spec test cases: the exact bytes h2spec
+//! sent, and what it expected to get back. `httpwg --record-h2spec <ID>`
+//! records them from a failing run, [Replay::run] plays them back, so an
+//! h2spec regression can become a permanent test without writing any frames
+//! by hand.
+//!
+//! They're stored as text, one per file:
+//!
+//! ```text
+//! # Sends a SETTINGS frame with a length other than a multiple of 6 octets
+//! id: http2/6.5/2
+//! expect: connection-error FRAME_SIZE_ERROR
+//! > 505249202a20485454502f322e300d0a0d0a534d0d0a0d0a
+//! > 000003040000000000000000
+//! ```
+//!
+//! Each `>` line is one write from the client, in hex.
+
+use std::{fmt, str::FromStr};
+
+use buffet::IntoHalves;
+use enumflags2::BitFlags;
+use loona_h2::StreamId;
+
+use crate::{Conn, ErrorC, FrameT};
+
+/// One recorded test case, cf. the [module docs](self)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replay {
+    /// The h2spec test ID, e.g. `http2/6.5/2`
+    pub id: String,
+    pub description: Option<String>,
+    pub expect: Expectation,
+    /// What the client wrote, one write at a time
+    pub writes: Vec<Vec<u8>>,
+}
+
+/// What the server should do after the client is done writing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expectation {
+    /// A GOAWAY with one of these codes, or the connection closing
+    ConnectionError(BitFlags<ErrorC>),
+    /// An RST_STREAM or GOAWAY with one of these codes, or the connection
+    /// closing
+    StreamError(BitFlags<ErrorC>),
+    /// A GOAWAY, or the connection closing
+    ConnectionClose,
+    /// A HEADERS frame on that stream
+    Headers(u32),
+    /// A PING with the ACK flag
+    PingAck,
+    /// A SETTINGS with the ACK flag
+    SettingsAck,
+    /// The connection still answers PINGs
+    Alive,
+}
+
+const ERROR_NAMES: [(ErrorC, &str); 14] = [
+    (ErrorC::NoError, "NO_ERROR"),
+    (ErrorC::ProtocolError, "PROTOCOL_ERROR"),
+    (ErrorC::InternalError, "INTERNAL_ERROR"),
+    (ErrorC::FlowControlError, "FLOW_CONTROL_ERROR"),
+    (ErrorC::SettingsTimeout, "SETTINGS_TIMEOUT"),
+    (ErrorC::StreamClosed, "STREAM_CLOSED"),
+    (ErrorC::FrameSizeError, "FRAME_SIZE_ERROR"),
+    (ErrorC::RefusedStream, "REFUSED_STREAM"),
+    (ErrorC::Cancel, "CANCEL"),
+    (ErrorC::CompressionError, "COMPRESSION_ERROR"),
+    (ErrorC::ConnectError, "CONNECT_ERROR"),
+    (ErrorC::EnhanceYourCalm, "ENHANCE_YOUR_CALM"),
+    (ErrorC::InadequateSecurity, "INADEQUATE_SECURITY"),
+    (ErrorC::Http1_1Required, "HTTP_1_1_REQUIRED"),
+];
+
+fn parse_codes(s: &str) -> eyre::Result<BitFlags<ErrorC>> {
+    let mut codes = BitFlags::empty();
+    for name in s.split('|').map(str::trim) {
+        let Some((code, _)) = ERROR_NAMES.iter().find(|(_, n)| *n == name) else {
+            eyre::bail!("unknown error code: {name}");
+        };
+        codes |= *code;
+    }
+    Ok(codes)
+}
+
+fn fmt_codes(f: &mut fmt::Formatter<'_>, codes: BitFlags<ErrorC>) -> fmt::Result {
+    let names = ERROR_NAMES
+        .iter()
+        .filter(|(code, _)| codes.contains(*code))
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>();
+    f.write_str(&names.join("|"))
+}
+
+impl fmt::Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expectation::ConnectionError(codes) => {
+                f.write_str("connection-error ")?;
+                fmt_codes(f, *codes)
+            }
+            Expectation::StreamError(codes) => {
+                f.write_str("stream-error ")?;
+                fmt_codes(f, *codes)
+            }
+            Expectation::ConnectionClose => f.write_str("connection-close"),
+            Expectation::Headers(stream_id) => write!(f, "headers {stream_id}"),
+            Expectation::PingAck => f.write_str("ping-ack"),
+            Expectation::SettingsAck => f.write_str("settings-ack"),
+            Expectation::Alive => f.write_str("alive"),
+        }
+    }
+}
+
+impl FromStr for Expectation {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, arg) = s.trim().split_once(' ').unwrap_or((s.trim(), ""));
+        Ok(match kind {
+            "connection-error" => Expectation::ConnectionError(parse_codes(arg)?),
+            "stream-error" => Expectation::StreamError(parse_codes(arg)?),
+            "connection-close" => Expectation::ConnectionClose,
+            "headers" => Expectation::Headers(arg.trim().parse()?),
+            "ping-ack" => Expectation::PingAck,
+            "settings-ack" => Expectation::SettingsAck,
+            "alive" => Expectation::Alive,
+            _ => eyre::bail!("unknown expectation: {s}"),
+        })
+    }
+}
+
+impl Expectation {
+    /// Translates what h2spec prints after `Expected:` for a failed test,
+    /// e.g. `GOAWAY Frame (Error Code: PROTOCOL_ERROR)`, one line per
+    /// acceptable outcome.
+    pub fn from_h2spec(expected: &str) -> Option<Self> {
+        let mut codes = BitFlags::empty();
+        for rest in expected.split("Error Code: ").skip(1) {
+            let name = rest
+                .split(|c: char| !(c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'))
+                .next()
+                .unwrap_or_default();
+            codes |= parse_codes(name).ok()?;
+        }
+
+        Some(if expected.contains("RST_STREAM Frame") {
+            Expectation::StreamError(codes)
+        } else if expected.contains("GOAWAY Frame") && !codes.is_empty() {
+            Expectation::ConnectionError(codes)
+        } else if expected.contains("GOAWAY Frame") || expected.contains("Connection closed") {
+            Expectation::ConnectionClose
+        } else if expected.contains("HEADERS Frame") || expected.contains("DATA Frame") {
+            let stream_id = expected
+                .split("stream_id:")
+                .nth(1)
+                .and_then(|s| s.split(|c: char| !c.is_ascii_digit()).next())
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(1);
+            Expectation::Headers(stream_id)
+        } else if expected.contains("PING Frame") {
+            Expectation::PingAck
+        } else if expected.contains("SETTINGS Frame") {
+            Expectation::SettingsAck
+        } else {
+            return None;
+        })
+    }
+}
+
+impl Replay {
+    /// Parses the text format described in the [module docs](self)
+    pub fn parse(input: &str) -> eyre::Result<Self> {
+        let mut id = None;
+        let mut description = None;
+        let mut expect = None;
+        let mut writes = Vec::new();
+
+        for (i, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(comment) = line.strip_prefix('#') {
+                description.get_or_insert_with(|| comment.trim().to_string());
+            } else if let Some(rest) = line.strip_prefix("id:") {
+                id = Some(rest.trim().to_string());
+            } else if let Some(rest) = line.strip_prefix("expect:") {
+                expect = Some(rest.parse()?);
+            } else if let Some(rest) = line.strip_prefix('>') {
+                writes.push(
+                    decode_hex(rest.trim())
+                        .ok_or_else(|| eyre::eyre!("line {}: invalid hex", i + 1))?,
+                );
+            } else {
+                eyre::bail!("line {}: unexpected {line:?}", i + 1);
+            }
+        }
+
+        Ok(Self {
+            id: id.ok_or_else(|| eyre::eyre!("missing 'id:' line"))?,
+            description,
+            expect: expect.ok_or_else(|| eyre::eyre!("missing 'expect:' line"))?,
+            writes,
+        })
+    }
+
+    /// Sends the recorded writes over `conn`, then checks the server does
+    /// what h2spec expected it to.
+    pub async fn run<IO: IntoHalves>(&self, mut conn: Conn<IO>) -> eyre::Result<()> {
+        for write in &self.writes {
+            conn.send(write.clone()).await?;
+        }
+
+        match &self.expect {
+            Expectation::ConnectionError(codes) => conn.verify_connection_error(*codes).await,
+            Expectation::StreamError(codes) => conn.verify_stream_error(*codes).await,
+            Expectation::ConnectionClose => conn.verify_connection_close().await,
+            Expectation::Headers(stream_id) => {
+                conn.verify_headers_frame(StreamId(*stream_id)).await
+            }
+            Expectation::PingAck => loop {
+                let (frame, _payload) = conn.wait_for_frame(FrameT::Ping).await.into_result()?;
+                if frame.is_ack() {
+                    return Ok(());
+                }
+            },
+            Expectation::SettingsAck => loop {
+                // skip the server's own settings
+                let (frame, _payload) =
+                    conn.wait_for_frame(FrameT::Settings).await.into_result()?;
+                if frame.is_ack() {
+                    return Ok(());
+                }
+            },
+            Expectation::Alive => conn.verify_connection_still_alive().await,
+        }
+    }
+}
+
+impl fmt::Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(description) = &self.description {
+            writeln!(f, "# {description}")?;
+        }
+        writeln!(f, "id: {}", self.id)?;
+        writeln!(f, "expect: {}", self.expect)?;
+        for write in &self.writes {
+            f.write_str("> ")?;
+            for b in write {
+                write!(f, "{b:02x}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -1,10 +1,11 @@
 //! Section 4: HTTP Frames
 
-use crate::{dummy_bytes, Conn, ErrorC};
+use crate::{dummy_bytes, rfc9113::DEFAULT_FRAME_SIZE, Conn, ErrorC, FrameT};
 use buffet::IntoHalves;
 use enumflags2::BitFlags;
 use loona_h2::{
-    ContinuationFlags, EncodedFrameType, Frame, FrameType, HeadersFlags, PrioritySpec, StreamId,
+    ContinuationFlags, EncodedFrameType, Frame, FrameType, HeadersFlags, PrioritySpec, Setting,
+    StreamId,
 };
 
 //---- Section 4.1: Frame Format
@@ -148,6 +149,62 @@ pub async fn large_headers_frame_exceeding_max_size<IO: IntoHalves>(
     Ok(())
 }
 
+/// SETTINGS_MAX_FRAME_SIZE is the largest frame payload the sender of the
+/// setting is willing to receive: frames carrying the response must fit in
+/// it, however large the response is.
+pub async fn response_frames_within_max_frame_size<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    verify_response_frame_sizes(&mut conn, DEFAULT_FRAME_SIZE).await
+}
+
+/// After the client raises SETTINGS_MAX_FRAME_SIZE, frames carrying the
+/// response must still fit in the new value.
+pub async fn response_frames_within_raised_max_frame_size<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    const MAX_FRAME_SIZE: u32 = DEFAULT_FRAME_SIZE * 2;
+    conn.handshake().await?;
+    conn.write_and_ack_settings(&[(Setting::MaxFrameSize, MAX_FRAME_SIZE)])
+        .await?;
+
+    verify_response_frame_sizes(&mut conn, MAX_FRAME_SIZE).await
+}
+
+/// Sends a request and reads the whole response, giving back flow control
+/// credit as DATA comes in, checking every frame is at most `max_frame_size`
+async fn verify_response_frame_sizes<IO: IntoHalves>(
+    conn: &mut Conn<IO>,
+    max_frame_size: u32,
+) -> eyre::Result<()> {
+    let stream_id = StreamId(1);
+    conn.send_empty_post_to_root(stream_id).await?;
+
+    loop {
+        let (frame, _payload) = conn
+            .wait_for_frame(FrameT::Headers | FrameT::Continuation | FrameT::Data)
+            .await
+            .into_result()?;
+        assert!(
+            frame.len <= max_frame_size,
+            "{frame:?} is larger than SETTINGS_MAX_FRAME_SIZE ({max_frame_size})"
+        );
+
+        if frame.is_end_stream() {
+            break;
+        }
+        if matches!(frame.frame_type, FrameType::Data(_)) && frame.len > 0 {
+            conn.write_window_update(StreamId::CONNECTION, frame.len)
+                .await?;
+            conn.write_window_update(stream_id, frame.len).await?;
+        }
+    }
+
+    Ok(())
+}
+
 //---- Section 4.3: Header Compression and Decompression
 
 /// A decoding error in a header block MUST be treated as a connection error
@@ -739,6 +739,71 @@ pub async fn sends_multiple_window_update_frames_increasing_flow_control_window_
     Ok(())
 }
 
+/// The connection flow-control window is shared by all streams: once it's
+/// exhausted, streams that have a response to send wait for a WINDOW_UPDATE
+/// on stream 0, and all of them resume when it comes, including those that
+/// haven't sent a single byte yet.
+pub async fn exhausts_connection_window_while_another_stream_waits<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    const DEFAULT_WINDOW_SIZE: usize = 65535;
+
+    conn.handshake().await?;
+
+    // we never send a WINDOW_UPDATE for the connection, so every response
+    // eats into the initial 65535 bytes. responses can be any size, measure
+    // the first one.
+    let mut stream_id = StreamId(1);
+    conn.send_empty_post_to_root(stream_id).await?;
+    let (body_len, _) = conn.read_data(stream_id, usize::MAX).await?;
+    assert!(body_len > 0, "the response should have a body");
+    let mut consumed = body_len;
+
+    // use up the connection window until not even one more body fits
+    while DEFAULT_WINDOW_SIZE - consumed >= body_len {
+        stream_id.0 += 2;
+        conn.send_empty_post_to_root(stream_id).await?;
+        assert_eq!(
+            conn.read_data(stream_id, usize::MAX).await?,
+            (body_len, true),
+            "all responses should have the same body"
+        );
+        consumed += body_len;
+    }
+    let remaining = DEFAULT_WINDOW_SIZE - consumed;
+
+    // the first stream gets whatever's left of the window, and stalls
+    let first = StreamId(stream_id.0 + 2);
+    conn.send_empty_post_to_root(first).await?;
+    let (mut first_received, first_done) = conn.read_data(first, remaining).await?;
+    assert!(!first_done, "the first stream should stall");
+
+    // the second one can't send anything
+    let second = StreamId(first.0 + 2);
+    conn.send_empty_post_to_root(second).await?;
+
+    // enough room for both of them
+    conn.write_window_update(StreamId::CONNECTION, (body_len * 2) as u32)
+        .await?;
+
+    let mut second_received = 0;
+    let (mut first_done, mut second_done) = (false, false);
+    while !(first_done && second_done) {
+        let (frame, _payload) = conn.wait_for_frame(FrameT::Data).await.unwrap();
+        if frame.stream_id == first {
+            first_received += frame.len as usize;
+            first_done |= frame.is_end_stream();
+        } else if frame.stream_id == second {
+            second_received += frame.len as usize;
+            second_done |= frame.is_end_stream();
+        }
+    }
+    assert_eq!(first_received, body_len);
+    assert_eq!(second_received, body_len);
+
+    Ok(())
+}
+
 //---- Section 6.9.2: Initial Flow-Control Window Size
 
 /// When the value of SETTINGS_INITIAL_WINDOW_SIZE changes,
@@ -496,6 +496,23 @@ pub async fn sends_headers_frame_with_response_pseudo_header<IO: IntoHalves>(
     Ok(())
 }
 
+/// [...] Endpoints MUST NOT generate pseudo-header fields other than those
+/// defined in this document. [...] Endpoints MUST treat a request or response
+/// that contains undefined or invalid pseudo-header fields as malformed
+/// (Section 8.1.1).
+pub async fn sends_headers_frame_with_unknown_pseudo_header<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    let mut headers = conn.common_headers("POST");
+    headers.append(":foo", "bar");
+    conn.send_req_and_expect_stream_rst(StreamId(1), &headers)
+        .await?;
+
+    Ok(())
+}
+
 /// [...] Pseudo-header fields MUST NOT appear in a trailer section. Endpoints
 /// MUST treat a request or response that contains undefined or invalid
 /// pseudo-header fields as malformed (Section 8.1.1).
@@ -783,11 +800,16 @@ pub async fn sends_headers_frame_with_pseudo_headers_after_regular_headers<IO: I
 ) -> eyre::Result<()> {
     conn.handshake().await?;
 
-    let mut headers = Headers::default();
-    headers.append(":method", "POST");
-    headers.append(":path", "/");
+    // every other pseudo-header is there, so the ordering is the only thing
+    // wrong with this request
+    let mut headers = conn.common_headers("POST");
+    let authority = headers
+        .get_first(&":authority".into())
+        .expect("common headers include :authority")
+        .clone();
+    headers.remove(&":authority".into());
     headers.append("content-type", "application/json");
-    headers.append(":authority", "example.com");
+    headers.append(":authority", authority);
 
     conn.send_req_and_expect_stream_rst(StreamId(1), &headers)
         .await?;
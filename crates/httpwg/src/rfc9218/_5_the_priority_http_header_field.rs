@@ -0,0 +1,37 @@
+//! Section 5: The Priority HTTP Header Field
+
+use buffet::IntoHalves;
+use loona_h2::StreamId;
+
+use crate::Conn;
+
+/// The Priority HTTP header field is an SF Dictionary that carries
+/// priority parameters (see Section 4). It can appear in requests and
+/// responses.
+pub async fn sends_request_with_priority_header<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    let mut headers = conn.common_headers("GET");
+    headers.append("priority", "u=0, i");
+    conn.send_req_and_expect_status(StreamId(1), &headers, 200)
+        .await?;
+
+    Ok(())
+}
+
+/// Unknown parameters, parameters with out-of-range values, or parameters
+/// with values of unexpected types MUST be ignored.
+pub async fn sends_request_with_invalid_priority_header<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    let mut headers = conn.common_headers("GET");
+    headers.append("priority", "u=9, i=maybe, foo");
+    conn.send_req_and_expect_status(StreamId(1), &headers, 200)
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//! Section 7: Reprioritization
+
+use buffet::IntoHalves;
+use loona_h2::{Frame, FrameType, HeadersFlags, StreamId};
+
+use crate::{Conn, ErrorC};
+
+//---- Section 7.1: HTTP/2 PRIORITY_UPDATE Frame
+
+/// The PRIORITY_UPDATE frame (type=0x10) is used by clients to signal the
+/// initial priority of a response, or to reprioritize a response or push
+/// stream. It carries the stream ID of the response and the priority in
+/// ASCII text, using the same representation as the Priority header field
+/// value.
+pub async fn sends_priority_update_for_open_stream<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    let stream_id = StreamId(1);
+
+    conn.handshake().await?;
+
+    let headers = conn.common_headers("POST");
+    conn.encode_and_write_headers(stream_id, HeadersFlags::EndHeaders, &headers)
+        .await?;
+    conn.write_priority_update(stream_id, b"u=1, i").await?;
+    conn.write_data(stream_id, true, b"test").await?;
+
+    conn.verify_headers_frame(stream_id).await?;
+
+    Ok(())
+}
+
+/// When the PRIORITY_UPDATE frame applies to a request stream, clients
+/// SHOULD provide a prioritized stream ID that refers to a stream in the
+/// "open", "half-closed (local)", or "idle" state [...] Servers can
+/// receive a PRIORITY_UPDATE frame for an idle stream, the priority it
+/// carries applies once the request arrives.
+pub async fn sends_priority_update_for_idle_stream<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    conn.write_priority_update(StreamId(1), b"u=0").await?;
+
+    let headers = conn.common_headers("GET");
+    conn.send_req_and_expect_status(StreamId(1), &headers, 200)
+        .await?;
+
+    Ok(())
+}
+
+/// A client MAY send a PRIORITY_UPDATE frame for a stream that the
+/// server has already closed: the server ignores it.
+pub async fn sends_priority_update_for_closed_stream<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    let headers = conn.common_headers("GET");
+    conn.send_req_and_expect_status(StreamId(1), &headers, 200)
+        .await?;
+
+    conn.write_priority_update(StreamId(1), b"u=5").await?;
+
+    conn.verify_connection_still_alive().await?;
+
+    Ok(())
+}
+
+/// Unknown parameters, parameters with out-of-range values, or parameters
+/// with values of unexpected types MUST be ignored.
+pub async fn sends_priority_update_with_invalid_field_value<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    conn.write_priority_update(StreamId(1), b"u=9, i=maybe, foo")
+        .await?;
+
+    let headers = conn.common_headers("GET");
+    conn.send_req_and_expect_status(StreamId(1), &headers, 200)
+        .await?;
+
+    Ok(())
+}
+
+/// The PRIORITY_UPDATE frame MUST be sent on stream 0. If a
+/// PRIORITY_UPDATE frame is received with a Stream Identifier other than
+/// 0x0, the recipient MUST respond with a connection error (Section 5.4.1
+/// of [HTTP/2]) of type PROTOCOL_ERROR.
+pub async fn sends_priority_update_with_non_zero_stream_id<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    conn.write_frame(
+        FrameType::PriorityUpdate.into_frame(StreamId(1)),
+        &b"\x00\x00\x00\x01u=1"[..],
+    )
+    .await?;
+
+    conn.verify_connection_error(ErrorC::ProtocolError).await?;
+
+    Ok(())
+}
+
+/// If a PRIORITY_UPDATE frame is received with a Prioritized Stream ID of
+/// 0x0, the recipient MUST respond with a connection error of type
+/// PROTOCOL_ERROR.
+pub async fn sends_priority_update_with_zero_prioritized_stream_id<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    conn.write_priority_update(StreamId::CONNECTION, b"u=1")
+        .await?;
+
+    conn.verify_connection_error(ErrorC::ProtocolError).await?;
+
+    Ok(())
+}
+
+/// If a server receives a PRIORITY_UPDATE frame with a Prioritized Stream
+/// ID that refers to a push stream that is not in the "reserved (local)"
+/// or "half-closed (remote)" state, the server MUST respond with a
+/// connection error of type PROTOCOL_ERROR.
+pub async fn sends_priority_update_for_push_stream<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    conn.write_priority_update(StreamId(2), b"u=1").await?;
+
+    conn.verify_connection_error(ErrorC::ProtocolError).await?;
+
+    Ok(())
+}
+
+/// A PRIORITY_UPDATE frame with a length less than 4 bytes MUST be
+/// treated as a connection error of type FRAME_SIZE_ERROR.
+pub async fn sends_priority_update_with_invalid_length<IO: IntoHalves>(
+    mut conn: Conn<IO>,
+) -> eyre::Result<()> {
+    conn.handshake().await?;
+
+    conn.write_frame(
+        Frame::new(FrameType::PriorityUpdate, StreamId::CONNECTION),
+        &b"\x00\x00\x01"[..],
+    )
+    .await?;
+
+    conn.verify_connection_error(ErrorC::FrameSizeError).await?;
+
+    Ok(())
+}
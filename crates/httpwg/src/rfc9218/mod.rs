@@ -0,0 +1,14 @@
+//! RFC 9218 describes a scheme that allows an HTTP client to communicate
+//! its preferences for how the upstream server prioritizes responses to
+//! its requests, and also allows a server to hint to a downstream
+//! intermediary how its responses should be prioritized when they are
+//! forwarded.
+//!
+//! This document defines the Priority header field for communicating the
+//! initial priority in an HTTP version-independent manner, as well as
+//! HTTP/2 and HTTP/3 frames for reprioritizing responses.
+//!
+//! cf. <https://httpwg.org/specs/rfc9218.html>
+
+pub mod _5_the_priority_http_header_field;
+pub mod _7_reprioritization;
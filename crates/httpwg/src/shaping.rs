@@ -0,0 +1,313 @@
+//! An IO wrapper that behaves like a network link rather than a pipe: bytes
+//! arrive late, at a limited rate, and in segments that don't line up with
+//! what was written. Conformance tests run over it exercise the code paths
+//! where frames straddle reads.
+//!
+//! ```ignore
+//! let io = ShapedIo::new(io, Shape {
+//!     latency: Duration::from_millis(2),
+//!     mtu: Some(1200),
+//!     split_seed: Some(0x1005e),
+//!     ..Default::default()
+//! });
+//! let mut conn = Conn::new(config, io);
+//! ```
+//!
+//! Both directions are shaped alike: writes return as soon as their bytes
+//! are queued on the link, and a task per direction delivers them.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    rc::Rc,
+    time::Duration,
+};
+
+use buffet::{
+    bufpool::{BufResult, IoBufMut},
+    IntoHalves, Piece, ReadOwned, WriteOwned,
+};
+use tokio::{sync::Notify, time::Instant};
+
+/// How a [ShapedIo] link behaves, in each direction
+#[derive(Debug, Clone, Default)]
+pub struct Shape {
+    /// How long a segment takes to reach the other end, once sent
+    pub latency: Duration,
+
+    /// In bytes per second: segments queue up behind each other past that.
+    /// `None` for unlimited.
+    pub bandwidth: Option<u64>,
+
+    /// The largest segment the link carries: larger writes (and reads) are
+    /// cut into segments of at most this many bytes.
+    pub mtu: Option<usize>,
+
+    /// Cuts every segment again, at random points drawn from a generator
+    /// seeded with this, so failures can be reproduced.
+    pub split_seed: Option<u64>,
+}
+
+/// Wraps both halves of `IO`, cf. the [module docs](self)
+pub struct ShapedIo<IO> {
+    inner: IO,
+    shape: Shape,
+}
+
+impl<IO: IntoHalves> ShapedIo<IO> {
+    pub fn new(inner: IO, shape: Shape) -> Self {
+        if let Some(mtu) = shape.mtu {
+            assert!(mtu > 0, "links carry segments of at least one byte");
+        }
+        Self { inner, shape }
+    }
+}
+
+impl<IO> IntoHalves for ShapedIo<IO>
+where
+    IO: IntoHalves,
+    IO::Read: 'static,
+    IO::Write: 'static,
+{
+    type Read = ShapedRead;
+    type Write = ShapedWrite;
+
+    fn into_halves(self) -> (Self::Read, Self::Write) {
+        let (r, w) = self.inner.into_halves();
+
+        // distinct, so both directions don't split alike
+        let mut down_shape = self.shape.clone();
+        down_shape.split_seed = down_shape.split_seed.map(|seed| !seed);
+
+        let up = Rc::new(Link::default());
+        buffet::spawn(deliver(up.clone(), w));
+        let down = Rc::new(Link::default());
+        buffet::spawn(receive(down.clone(), r, Shaper::new(down_shape)));
+
+        (
+            ShapedRead { link: down },
+            ShapedWrite {
+                link: up,
+                shaper: Shaper::new(self.shape),
+            },
+        )
+    }
+}
+
+/// One direction of the link: segments in flight, in order, with the time
+/// they reach the other end.
+#[derive(Default)]
+struct Link {
+    segments: RefCell<VecDeque<(Instant, Piece)>>,
+    /// Nothing more will be queued
+    closed: Cell<bool>,
+    /// Set when the far end of the link failed, which also closes it
+    error: RefCell<Option<std::io::Error>>,
+    /// Set once everything queued was delivered after closing
+    drained: Cell<bool>,
+    /// Wakes up both ends whenever any of the above changes
+    changed: Notify,
+}
+
+impl Link {
+    fn push(&self, segments: impl IntoIterator<Item = (Instant, Piece)>) {
+        self.segments.borrow_mut().extend(segments);
+        self.changed.notify_waiters();
+    }
+
+    fn close(&self) {
+        self.closed.set(true);
+        self.changed.notify_waiters();
+    }
+
+    fn fail(&self, e: std::io::Error) {
+        *self.error.borrow_mut() = Some(e);
+        self.closed.set(true);
+        self.changed.notify_waiters();
+    }
+
+    fn take_error(&self) -> Option<std::io::Error> {
+        self.error.borrow_mut().take()
+    }
+
+    /// The next segment, once it's arrived, or `None` if the link was closed
+    /// and there's nothing left on it.
+    async fn next(&self) -> Option<Piece> {
+        loop {
+            let changed = self.changed.notified();
+            let head = self.segments.borrow().front().map(|(at, _)| *at);
+            match head {
+                Some(at) => {
+                    tokio::time::sleep_until(at).await;
+                    return self.segments.borrow_mut().pop_front().map(|(_, s)| s);
+                }
+                None if self.closed.get() => return None,
+                None => changed.await,
+            }
+        }
+    }
+}
+
+/// Cuts bytes into segments, and works out when each arrives
+struct Shaper {
+    shape: Shape,
+    rng: Option<XorShift>,
+    /// When the link is done sending what's already queued
+    busy_until: Instant,
+}
+
+impl Shaper {
+    fn new(shape: Shape) -> Self {
+        Self {
+            rng: shape.split_seed.map(XorShift::new),
+            shape,
+            busy_until: Instant::now(),
+        }
+    }
+
+    fn segments(&mut self, mut buf: Piece) -> Vec<(Instant, Piece)> {
+        let mut segments = vec![];
+        while !buf.is_empty() {
+            let mut len = buf.len().min(self.shape.mtu.unwrap_or(usize::MAX));
+            if let Some(rng) = self.rng.as_mut() {
+                len = 1 + (rng.next() % len as u64) as usize;
+            }
+            let (segment, rest) = buf.split_at(len);
+            buf = rest;
+
+            let mut sent = self.busy_until.max(Instant::now());
+            if let Some(bandwidth) = self.shape.bandwidth {
+                sent += Duration::from_nanos(len as u64 * 1_000_000_000 / bandwidth.max(1));
+            }
+            self.busy_until = sent;
+            segments.push((sent + self.shape.latency, segment));
+        }
+        segments
+    }
+}
+
+/// Writes segments to `w` as they arrive, until the link is closed
+async fn deliver<W: WriteOwned>(link: Rc<Link>, mut w: W) {
+    let res = async {
+        while let Some(segment) = link.next().await {
+            w.write_all_owned(segment).await?;
+        }
+        w.shutdown().await
+    };
+    if let Err(e) = res.await {
+        link.fail(e);
+    }
+    link.drained.set(true);
+    link.changed.notify_waiters();
+}
+
+/// Queues what's read from `r` on the link, until EOF
+async fn receive<R: ReadOwned>(link: Rc<Link>, mut r: R, mut shaper: Shaper) {
+    loop {
+        let (res, mut buf) = r.read_owned(vec![0u8; 64 * 1024]).await;
+        match res {
+            Ok(0) => break,
+            Ok(n) => {
+                buf.truncate(n);
+                link.push(shaper.segments(buf.into()));
+            }
+            Err(e) => {
+                // bytes already read are still on their way
+                link.push([(shaper.busy_until + shaper.shape.latency, Piece::empty())]);
+                link.fail(e);
+                break;
+            }
+        }
+    }
+    link.close();
+}
+
+pub struct ShapedRead {
+    link: Rc<Link>,
+}
+
+impl ReadOwned for ShapedRead {
+    async fn read_owned<B: IoBufMut>(&mut self, mut buf: B) -> BufResult<usize, B> {
+        loop {
+            let Some(segment) = self.link.next().await else {
+                return match self.link.take_error() {
+                    Some(e) => (Err(e), buf),
+                    None => (Ok(0), buf),
+                };
+            };
+            if segment.is_empty() {
+                // marks where an error happened
+                if let Some(e) = self.link.take_error() {
+                    return (Err(e), buf);
+                }
+                continue;
+            }
+
+            let n = segment.len().min(buf.io_buf_mut_capacity());
+            let (ours, rest) = segment.split_at(n);
+            if !rest.is_empty() {
+                // it's already arrived, so it goes first next time
+                let mut segments = self.link.segments.borrow_mut();
+                segments.push_front((Instant::now(), rest));
+            }
+            unsafe { buf.slice_mut()[..n].copy_from_slice(&ours[..]) };
+            return (Ok(n), buf);
+        }
+    }
+}
+
+pub struct ShapedWrite {
+    link: Rc<Link>,
+    shaper: Shaper,
+}
+
+impl WriteOwned for ShapedWrite {
+    async fn write_owned(&mut self, buf: impl Into<Piece>) -> BufResult<usize, Piece> {
+        let buf = buf.into();
+        if let Some(e) = self.link.take_error() {
+            return (Err(e), buf);
+        }
+        if self.link.closed.get() {
+            return (Err(std::io::ErrorKind::BrokenPipe.into()), buf);
+        }
+
+        let len = buf.len();
+        self.link.push(self.shaper.segments(buf.clone()));
+        (Ok(len), buf)
+    }
+
+    /// Waits for everything written so far to be delivered
+    async fn shutdown(&mut self) -> std::io::Result<()> {
+        self.link.close();
+        loop {
+            let changed = self.link.changed.notified();
+            if let Some(e) = self.link.take_error() {
+                return Err(e);
+            }
+            if self.link.drained.get() {
+                return Ok(());
+            }
+            changed.await;
+        }
+    }
+}
+
+/// Plenty random for picking where to split segments, cf.
+/// <https://www.jstatsoft.org/article/view/v008i14>
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // zero is the one state it never leaves
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+}
@@ -46,6 +46,10 @@ pub enum RawFrameType {
     GoAway = 0x07,
     WindowUpdate = 0x08,
     Continuation = 0x09,
+    /// cf. <https://httpwg.org/specs/rfc8336.html#OriginFrame>
+    Origin = 0x0c,
+    /// cf. <https://httpwg.org/specs/rfc9218.html#frame>
+    PriorityUpdate = 0x10,
 }
 
 impl RawFrameType {
@@ -65,6 +69,8 @@ impl RawFrameType {
             0x07 => Some(RawFrameType::GoAway),
             0x08 => Some(RawFrameType::WindowUpdate),
             0x09 => Some(RawFrameType::Continuation),
+            0x0c => Some(RawFrameType::Origin),
+            0x10 => Some(RawFrameType::PriorityUpdate),
             _ => None,
         }
     }
@@ -83,6 +89,8 @@ fn test_raw_frame_type_roundtrip() {
         RawFrameType::GoAway,
         RawFrameType::WindowUpdate,
         RawFrameType::Continuation,
+        RawFrameType::Origin,
+        RawFrameType::PriorityUpdate,
     ];
 
     for &variant in &variants {
@@ -108,6 +116,8 @@ pub enum FrameType {
     GoAway,
     WindowUpdate,
     Continuation(BitFlags<ContinuationFlags>),
+    Origin,
+    PriorityUpdate,
     Unknown(EncodedFrameType),
 }
 
@@ -202,6 +212,8 @@ impl FrameType {
             FrameType::GoAway => (RawFrameType::GoAway, 0).into(),
             FrameType::WindowUpdate => (RawFrameType::WindowUpdate, 0).into(),
             FrameType::Continuation(f) => (RawFrameType::Continuation, f.bits()).into(),
+            FrameType::Origin => (RawFrameType::Origin, 0).into(),
+            FrameType::PriorityUpdate => (RawFrameType::PriorityUpdate, 0).into(),
             FrameType::Unknown(ft) => ft,
         }
     }
@@ -229,6 +241,8 @@ impl FrameType {
                 RawFrameType::Continuation => FrameType::Continuation(
                     BitFlags::<ContinuationFlags>::from_bits_truncate(ft.flags),
                 ),
+                RawFrameType::Origin => FrameType::Origin,
+                RawFrameType::PriorityUpdate => FrameType::PriorityUpdate,
             },
             None => FrameType::Unknown(ft),
         }
@@ -318,6 +332,8 @@ impl fmt::Debug for Frame {
             FrameType::GoAway => "GoAway",
             FrameType::WindowUpdate => "WindowUpdate",
             FrameType::Continuation(_) => "Continuation",
+            FrameType::Origin => "Origin",
+            FrameType::PriorityUpdate => "PriorityUpdate",
             FrameType::Unknown(EncodedFrameType { ty, flags }) => {
                 return write!(f, "UnknownFrame({:#x}, {:#x}, len={})", ty, flags, self.len)
             }
@@ -792,6 +808,12 @@ pub struct Settings {
     /// For any given request, a lower limit than what is advertised MAY be
     /// enforced. The initial value of this setting is unlimited.
     pub max_header_list_size: u32,
+
+    /// Whether the sender accepts extended CONNECT requests, which carry a
+    /// `:protocol` pseudo-header (RFC 8441, section 3). Only servers send
+    /// it. The initial value is 0, any value other than 0 or 1 is a
+    /// connection error of type PROTOCOL_ERROR.
+    pub enable_connect_protocol: bool,
 }
 
 impl Default for Settings {
@@ -804,6 +826,7 @@ impl Default for Settings {
             initial_window_size: (1 << 16) - 1,
             max_frame_size: (1 << 14),
             max_header_list_size: 0,
+            enable_connect_protocol: false,
         }
     }
 }
@@ -839,6 +862,13 @@ impl Settings {
             Setting::MaxHeaderListSize => {
                 self.max_header_list_size = value;
             }
+            Setting::EnableConnectProtocol => match value {
+                0 => self.enable_connect_protocol = false,
+                1 => self.enable_connect_protocol = true,
+                _ => {
+                    return Err(SettingsError::InvalidEnableConnectProtocolValue { actual: value })
+                }
+            },
         }
 
         Ok(())
@@ -858,6 +888,9 @@ pub enum SettingsError {
         "bad SETTINGS_MAX_FRAME_SIZE value {actual}, should be between 2^14 and 2^24-1 inclusive"
     )]
     SettingsMaxFrameSizeInvalid { actual: u32 },
+
+    #[error("ENABLE_CONNECT_PROTOCOL setting is supposed to be either 0 or 1, got {actual}")]
+    InvalidEnableConnectProtocolValue { actual: u32 },
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -868,6 +901,7 @@ pub enum Setting {
     InitialWindowSize = 0x04,
     MaxFrameSize = 0x05,
     MaxHeaderListSize = 0x06,
+    EnableConnectProtocol = 0x08,
 }
 
 impl Setting {
@@ -883,6 +917,7 @@ impl Setting {
             0x04 => Some(Setting::InitialWindowSize),
             0x05 => Some(Setting::MaxFrameSize),
             0x06 => Some(Setting::MaxHeaderListSize),
+            0x08 => Some(Setting::EnableConnectProtocol),
             _ => None,
         }
     }
@@ -897,6 +932,7 @@ fn test_setting_roundtrip() {
         Setting::InitialWindowSize,
         Setting::MaxFrameSize,
         Setting::MaxHeaderListSize,
+        Setting::EnableConnectProtocol,
     ];
 
     for &setting in &settings {
@@ -1072,6 +1108,216 @@ impl WindowUpdate {
     }
 }
 
+/// Payload for an ORIGIN frame: the origins a server is authoritative for,
+/// serialized like `https://example.org`. cf.
+/// <https://httpwg.org/specs/rfc8336.html#OriginFrame>
+#[derive(Clone, Default)]
+pub struct Origin {
+    pub origins: Vec<Piece>,
+}
+
+impl IntoPiece for Origin {
+    fn into_piece(self, scratch: &mut RollMut) -> std::io::Result<Piece> {
+        let len = self.origins.iter().map(|o| 2 + o.len()).sum();
+        let roll = scratch
+            .put_to_roll(len, |mut slice| {
+                for origin in &self.origins {
+                    let origin_len: u16 = origin.len().try_into().map_err(|_| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidInput, "origin too long")
+                    })?;
+                    slice.write_u16::<BigEndian>(origin_len)?;
+                    slice.write_all(&origin[..])?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        Ok(roll.into())
+    }
+}
+
+impl Origin {
+    pub fn parse(i: Roll) -> IResult<Roll, Self> {
+        let (i, origins) = nom::multi::many0(nom::combinator::map(
+            nom::multi::length_data(nom::number::complete::be_u16),
+            Piece::from,
+        ))(i)?;
+        if !i.is_empty() {
+            // a truncated entry
+            return Err(nom::Err::Error(nom::error::Error::new(
+                i,
+                nom::error::ErrorKind::Eof,
+            )));
+        }
+        Ok((i, Self { origins }))
+    }
+}
+
+#[test]
+fn test_origin_roundtrip() {
+    buffet::bufpool::initialize_allocator().unwrap();
+
+    let origin = Origin {
+        origins: vec![
+            Piece::from("https://example.org"),
+            Piece::from("https://static.example.org:8443"),
+        ],
+    };
+    let mut scratch = RollMut::alloc().unwrap();
+    let piece = origin.into_piece(&mut scratch).unwrap();
+    assert_eq!(&piece[..2], &[0, 19]);
+
+    let mut roll = RollMut::alloc().unwrap();
+    roll.put(&piece[..]).unwrap();
+    let (_, parsed) = Origin::parse(roll.take_all()).unwrap();
+    let parsed: Vec<&[u8]> = parsed.origins.iter().map(|o| &o[..]).collect();
+    assert_eq!(
+        parsed,
+        [
+            &b"https://example.org"[..],
+            b"https://static.example.org:8443"
+        ]
+    );
+
+    let (_, empty) = Origin::parse(Roll::empty()).unwrap();
+    assert!(empty.origins.is_empty());
+
+    // an entry longer than what's left
+    roll.put(&[0, 10, b'h'][..]).unwrap();
+    assert!(Origin::parse(roll.take_all()).is_err());
+}
+
+/// Payload for a PRIORITY_UPDATE frame, cf.
+/// <https://httpwg.org/specs/rfc9218.html#frame>
+pub struct PriorityUpdate {
+    pub prioritized_stream_id: StreamId,
+    /// Same syntax as the `priority` header, cf. [Priority::parse]
+    pub priority_field_value: Piece,
+}
+
+impl IntoPiece for PriorityUpdate {
+    fn into_piece(self, scratch: &mut RollMut) -> std::io::Result<Piece> {
+        let roll = scratch
+            .put_to_roll(4 + self.priority_field_value.len(), |mut slice| {
+                slice.write_all(&pack_reserved_and_stream_id(0, self.prioritized_stream_id))?;
+                slice.write_all(&self.priority_field_value[..])?;
+                Ok(())
+            })
+            .unwrap();
+        Ok(roll.into())
+    }
+}
+
+impl PriorityUpdate {
+    pub fn parse(i: Roll) -> IResult<Roll, Self> {
+        let (rest, (_reserved, prioritized_stream_id)) = parse_reserved_and_stream_id(i)?;
+
+        let i = Roll::empty();
+        Ok((
+            i,
+            Self {
+                prioritized_stream_id,
+                priority_field_value: rest.into(),
+            },
+        ))
+    }
+}
+
+/// Extensible priorities of a stream, cf.
+/// <https://httpwg.org/specs/rfc9218.html#parameters>
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Priority {
+    /// 0 (most urgent) to 7 (least urgent)
+    pub urgency: u8,
+    /// Whether the response can be processed a piece at a time, so it may
+    /// share bandwidth with others of the same urgency.
+    pub incremental: bool,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self {
+            urgency: Self::DEFAULT_URGENCY,
+            incremental: false,
+        }
+    }
+}
+
+impl Priority {
+    pub const DEFAULT_URGENCY: u8 = 3;
+    pub const MAX_URGENCY: u8 = 7;
+
+    /// Parses the value of a `priority` header or of a PRIORITY_UPDATE frame,
+    /// a structured field dictionary like `u=1, i`. Members that are missing,
+    /// unknown or invalid keep their default value, as the RFC asks.
+    pub fn parse(value: &[u8]) -> Self {
+        let mut priority = Self::default();
+
+        for member in value.split(|&b| b == b',') {
+            // parameters of members are of no interest to us
+            let member = member.split(|&b| b == b';').next().unwrap_or_default();
+            let member = trim_ows(member);
+            let (key, value) = match member.iter().position(|&b| b == b'=') {
+                Some(eq) => (&member[..eq], Some(&member[eq + 1..])),
+                None => (member, None),
+            };
+
+            match key {
+                b"u" => {
+                    if let Some(urgency) = value
+                        .and_then(|v| std::str::from_utf8(v).ok())
+                        .and_then(|v| v.parse::<u8>().ok())
+                        .filter(|u| *u <= Self::MAX_URGENCY)
+                    {
+                        priority.urgency = urgency;
+                    }
+                }
+                b"i" => match value {
+                    None | Some(b"?1") => priority.incremental = true,
+                    Some(b"?0") => priority.incremental = false,
+                    Some(_) => {}
+                },
+                _ => {}
+            }
+        }
+
+        priority
+    }
+}
+
+/// Strips spaces and tabs around `s`
+fn trim_ows(mut s: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = s {
+        s = rest;
+    }
+    while let [rest @ .., b' ' | b'\t'] = s {
+        s = rest;
+    }
+    s
+}
+
+#[test]
+fn test_priority_parse() {
+    let p = |s: &str| Priority::parse(s.as_bytes());
+    let prio = |urgency, incremental| Priority {
+        urgency,
+        incremental,
+    };
+
+    assert_eq!(p(""), Priority::default());
+    assert_eq!(p("u=0"), prio(0, false));
+    assert_eq!(p("u=5, i"), prio(5, true));
+    assert_eq!(p("i,u=1"), prio(1, true));
+    assert_eq!(p("i=?1"), prio(3, true));
+    assert_eq!(p("u=2, i=?0"), prio(2, false));
+    assert_eq!(p("u=7;foo=bar, i;x"), prio(7, true));
+
+    // invalid or unknown members are ignored
+    assert_eq!(p("u=8"), prio(3, false));
+    assert_eq!(p("u=-1, i=1"), prio(3, false));
+    assert_eq!(p("u=1.5"), prio(3, false));
+    assert_eq!(p("x=2, u=4, zzz"), prio(4, false));
+}
+
 impl<T> IntoPiece for T
 where
     Piece: From<T>,
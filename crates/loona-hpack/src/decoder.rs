@@ -250,6 +250,20 @@ pub enum DecoderError {
     /// must be treating as a decoding error.
     #[error("Dynamic table size update at the end of a header block")]
     SizeUpdateAtEnd,
+    /// Dynamic table size updates can only occur at the beginning of a
+    /// header block (RFC 7541 section 4.2)
+    #[error("Dynamic table size update after a header field")]
+    SizeUpdateNotAtStart,
+    /// The max allowed table size went below the table's size, and the
+    /// header block that followed didn't start with a dynamic table size
+    /// update that brings it back under it (RFC 7541 section 4.2)
+    #[error("Missing dynamic table size update")]
+    MissingSizeUpdate,
+    /// The decoded header list is over the size set with
+    /// [Decoder::set_max_header_list_size]. The whole block was decoded
+    /// anyway, so the dynamic table is still usable.
+    #[error("Header list too large")]
+    HeaderListTooLarge,
 }
 
 /// Represents all errors that can be encountered while performing the decoding
@@ -279,6 +293,13 @@ pub struct Decoder<'a> {
 
     max_allowed_table_size: Option<usize>,
 
+    /// Set when `max_allowed_table_size` went below the dynamic table's
+    /// max size: the next block must start with a size update
+    size_update_required: bool,
+
+    /// cf. [Decoder::set_max_header_list_size]
+    max_header_list_size: Option<usize>,
+
     // Allow trailing size updates (used by tests)
     #[cfg(test)]
     pub(crate) allow_trailing_size_updates: bool,
@@ -314,6 +335,8 @@ impl<'a> Decoder<'a> {
         Decoder {
             header_table: HeaderTable::with_static_table(static_table),
             max_allowed_table_size: None,
+            size_update_required: false,
+            max_header_list_size: None,
             #[cfg(test)]
             allow_trailing_size_updates: false,
         }
@@ -339,11 +362,31 @@ impl<'a> Decoder<'a> {
             .set_max_table_size(new_max_size);
     }
 
+    /// Returns the current size of the decoder's dynamic table, in octets as
+    /// defined by the HPACK spec.
+    pub fn table_size(&self) -> usize {
+        self.header_table.dynamic_table.get_size()
+    }
+
     /// Sets max allowed table size: any "dynamic table size updates" that try
     /// to bring the table size over that value will error out with
-    /// [DecoderError::InvalidMaxDynamicSize]
+    /// [DecoderError::InvalidMaxDynamicSize]. If it's below the table's
+    /// current max size, the next block must start with a size update that
+    /// brings it under, or it errors out with
+    /// [DecoderError::MissingSizeUpdate].
     pub fn set_max_allowed_table_size(&mut self, max_allowed_size: usize) {
         self.max_allowed_table_size = Some(max_allowed_size);
+        self.size_update_required = max_allowed_size < self.header_table.dynamic_table.max_size;
+    }
+
+    /// Header blocks that decode to more than `max_size` bytes, counting 32
+    /// bytes of overhead per field like HTTP/2's SETTINGS_MAX_HEADER_LIST_SIZE
+    /// does, error out with [DecoderError::HeaderListTooLarge]. They're
+    /// still decoded to the end to keep the dynamic table in sync, but the
+    /// fields past the limit aren't passed to the callback, so indexing a
+    /// large entry over and over doesn't cost more than `max_size`.
+    pub fn set_max_header_list_size(&mut self, max_size: usize) {
+        self.max_header_list_size = Some(max_size);
     }
 
     /// Decodes the headers found in the given buffer `buf`. Invokes the
@@ -372,7 +415,17 @@ impl<'a> Decoder<'a> {
     ) -> Result<(), DecoderError> {
         let mut current_octet_index = 0;
 
+        let max_list_size = self.max_header_list_size.unwrap_or(usize::MAX);
+        let mut list_size = 0_usize;
+        let mut cb = |name: Cow<[u8]>, value: Cow<[u8]>| {
+            list_size = list_size.saturating_add(name.len() + value.len() + 32);
+            if list_size <= max_list_size {
+                cb(name, value);
+            }
+        };
+
         let mut last_was_size_update = false;
+        let mut saw_field = false;
         while current_octet_index < buf.len() {
             // At this point we are always at the beginning of the next block
             // within the HPACK data.
@@ -382,6 +435,18 @@ impl<'a> Decoder<'a> {
             let buffer_leftover = &buf[current_octet_index..];
             let field_representation = FieldRepresentation::new(initial_octet);
             last_was_size_update = matches!(field_representation, FieldRepresentation::SizeUpdate);
+            if last_was_size_update {
+                #[cfg(test)]
+                let saw_field = saw_field && !self.allow_trailing_size_updates;
+                if saw_field {
+                    return Err(DecoderError::SizeUpdateNotAtStart);
+                }
+            } else if !saw_field {
+                saw_field = true;
+                if self.size_update_required {
+                    return Err(DecoderError::MissingSizeUpdate);
+                }
+            }
 
             let consumed = match field_representation {
                 FieldRepresentation::Indexed => {
@@ -446,6 +511,10 @@ impl<'a> Decoder<'a> {
             return Err(DecoderError::SizeUpdateAtEnd);
         }
 
+        if list_size > max_list_size {
+            return Err(DecoderError::HeaderListTooLarge);
+        }
+
         Ok(())
     }
 
@@ -542,6 +611,7 @@ impl<'a> Decoder<'a> {
             }
         }
         self.header_table.dynamic_table.set_max_table_size(new_size);
+        self.size_update_required = false;
 
         trace!(
             "Decoder changed max table size from {} to {}",
@@ -1505,6 +1575,83 @@ mod tests {
             ))
         ));
     }
+
+    /// Tests that size updates are only accepted at the start of a block,
+    /// and required there once the allowed size drops below the table's.
+    #[test]
+    fn test_size_update_placement() {
+        let mut decoder = Decoder::new();
+        assert_eq!(
+            decoder.decode(&[0x82, 0x3f, 0xe1, 0x1f]),
+            Err(DecoderError::SizeUpdateNotAtStart)
+        );
+
+        let mut decoder = Decoder::new();
+        decoder.set_max_allowed_table_size(256);
+        assert_eq!(
+            decoder.decode(&[0x82]),
+            Err(DecoderError::MissingSizeUpdate)
+        );
+        // two in a row are fine, as long as they're first
+        assert_eq!(
+            decoder.decode(&[0x20, 0x3f, 0xe1, 0x01, 0x82]).unwrap(),
+            [(b":method".to_vec(), b"GET".to_vec())]
+        );
+        assert_eq!(decoder.header_table.dynamic_table.get_max_table_size(), 256);
+        assert_eq!(decoder.decode(&[0x82]).unwrap().len(), 1);
+    }
+
+    /// Tests that an entry larger than the table empties it instead of
+    /// being added, so it can't be referred to.
+    #[test]
+    fn test_oversized_entry() {
+        let mut decoder = Decoder::new();
+        decoder.set_max_allowed_table_size(64);
+        // shrink to 64, insert `x-a: b`, then a 40-byte value that doesn't fit
+        let mut block = vec![0x3f, 0x21, 0x40, 3, b'x', b'-', b'a', 1, b'b'];
+        block.extend([0x40, 3, b'x', b'-', b'b', 40]);
+        block.extend([b'v'; 40]);
+        let headers = decoder.decode(&block).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(decoder.table_size(), 0);
+        assert_eq!(
+            decoder.decode(&[0xbe]),
+            Err(DecoderError::HeaderIndexOutOfBounds)
+        );
+    }
+
+    /// Tests that a block indexing a large entry over and over stops
+    /// producing fields past the header list limit, but still decodes to the
+    /// end.
+    #[test]
+    fn test_header_list_size_limit() {
+        let mut decoder = Decoder::new();
+        decoder.set_max_header_list_size(16 * 1024);
+
+        // insert a ~4KiB field, then refer to it a thousand times
+        let mut block = vec![0x40, 3, b'x', b'-', b'a'];
+        block.extend(encode_integer(4000, 7));
+        block.extend([b'a'; 4000]);
+        block.extend([0xbe; 1000]);
+        // and insert another one at the end
+        block.extend([0x40, 3, b'x', b'-', b'b', 1, b'b']);
+
+        let mut decoded = 0;
+        assert_eq!(
+            decoder.decode_with_cb(&block, |name, value| decoded += name.len() + value.len()),
+            Err(DecoderError::HeaderListTooLarge)
+        );
+        assert!(decoded <= 16 * 1024, "decoded {decoded} bytes");
+
+        // the table is in sync
+        assert_eq!(
+            decoder.decode(&[0xbe, 0xbf]).unwrap(),
+            [
+                (b"x-b".to_vec(), b"b".to_vec()),
+                (b"x-a".to_vec(), vec![b'a'; 4000]),
+            ]
+        );
+    }
 }
 
 /// The module defines interop tests between this HPACK decoder
@@ -44,9 +44,13 @@
 //! // indicating that the indexed representation is used).
 //! assert_eq!(encoder.encode(headers), vec![2 | 0x80, 4 | 0x80]);
 //! ```
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::num::Wrapping;
 
+use super::huffman;
 use super::HeaderTable;
 use super::STATIC_TABLE;
 
@@ -169,9 +173,53 @@ pub fn encode_integer(value: usize, prefix_size: u8) -> Vec<u8> {
 /// // with a flag representing that the decoder should use the index.
 /// assert_eq!(vec![0x80 | 62], result);
 /// ```
+///
+/// A clone encodes the same way from then on, so it can be used to find out
+/// what a header block would look like without committing to it.
+#[derive(Clone)]
 pub struct Encoder<'a> {
     /// The header table represents the encoder's context
     header_table: HeaderTable<'a>,
+
+    /// Set by [Encoder::resize_table], signaled at the start of the next
+    /// header block
+    pending_size_update: Option<usize>,
+
+    stats: EncoderStats,
+
+    /// Hashes of the fields inserted last, oldest first, to notice fields
+    /// that come back after being evicted
+    recent_insertions: VecDeque<u64>,
+    recent_insertion_counts: HashMap<u64, u32>,
+
+    /// cf. [Encoder::set_indexing]
+    indexing: bool,
+
+    /// cf. [Encoder::set_never_indexed]
+    never_indexed: Vec<Vec<u8>>,
+
+    /// cf. [Encoder::set_huffman]
+    huffman: bool,
+}
+
+/// How many insertions [Encoder] remembers to count
+/// [EncoderStats::reinsertions]
+const RECENT_INSERTIONS: usize = 512;
+
+/// What an [Encoder] did so far, to judge how useful its dynamic table is
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderStats {
+    /// Header fields encoded
+    pub fields: u64,
+    /// Fields encoded as an index into the dynamic table
+    pub dynamic_hits: u64,
+    /// Fields inserted into the dynamic table
+    pub insertions: u64,
+    /// Insertions of a field that was inserted recently, and evicted since:
+    /// a larger table would have had a hit
+    pub reinsertions: u64,
+    /// Fields evicted from the dynamic table to make room
+    pub evictions: u64,
 }
 
 impl<'a> Default for Encoder<'a> {
@@ -186,9 +234,42 @@ impl<'a> Encoder<'a> {
     pub fn new() -> Encoder<'a> {
         Encoder {
             header_table: HeaderTable::with_static_table(STATIC_TABLE),
+            pending_size_update: None,
+            stats: Default::default(),
+            recent_insertions: Default::default(),
+            recent_insertion_counts: Default::default(),
+            indexing: true,
+            never_indexed: Vec::new(),
+            huffman: false,
         }
     }
 
+    /// Whether string literals are Huffman-encoded (cf. RFC 7541 section
+    /// 5.2) when that makes them shorter. Off by default.
+    pub fn set_huffman(&mut self, huffman: bool) {
+        self.huffman = huffman;
+    }
+
+    /// Whether fields that aren't in the header table get inserted into the
+    /// dynamic table, so they can be sent as an index next time. When
+    /// disabled, they're sent as literals without indexing, every time.
+    pub fn set_indexing(&mut self, indexing: bool) {
+        self.indexing = indexing;
+    }
+
+    /// Fields with any of these (lowercase) names are sent as never-indexed
+    /// literals (cf. RFC 7541 section 7.1.3): they stay out of the dynamic
+    /// table, and intermediaries re-encoding them must do the same. Meant
+    /// for values that must not be guessable by probing the table, like
+    /// cookies and credentials.
+    pub fn set_never_indexed<I, N>(&mut self, names: I)
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<Vec<u8>>,
+    {
+        self.never_indexed = names.into_iter().map(Into::into).collect();
+    }
+
     /// Sets a new maximum dynamic table size for the encoder.
     pub fn set_max_table_size(&mut self, new_max_size: usize) {
         self.header_table
@@ -196,6 +277,34 @@ impl<'a> Encoder<'a> {
             .set_max_table_size(new_max_size);
     }
 
+    /// Changes the dynamic table size, and tells the decoder with a dynamic
+    /// table size update at the start of the next header block, cf. RFC 7541
+    /// section 6.3. `new_max_size` must not exceed what the decoder allows
+    /// (e.g. `SETTINGS_HEADER_TABLE_SIZE` in HTTP/2).
+    pub fn resize_table(&mut self, new_max_size: usize) {
+        self.set_max_table_size(new_max_size);
+        self.pending_size_update = Some(new_max_size);
+    }
+
+    /// Returns the maximum size of the encoder's dynamic table, in octets.
+    pub fn max_table_size(&self) -> usize {
+        self.header_table.dynamic_table.max_size
+    }
+
+    /// What the encoder did so far
+    pub fn stats(&self) -> EncoderStats {
+        EncoderStats {
+            evictions: self.header_table.dynamic_table.evictions,
+            ..self.stats
+        }
+    }
+
+    /// Returns the current size of the encoder's dynamic table, in octets as
+    /// defined by the HPACK spec.
+    pub fn table_size(&self) -> usize {
+        self.header_table.dynamic_table.get_size()
+    }
+
     /// Encodes the given headers using the HPACK rules and returns a newly
     /// allocated `Vec` containing the bytes representing the encoded header
     /// set.
@@ -205,8 +314,10 @@ impl<'a> Encoder<'a> {
     /// already found in the header table and a literal otherwise. When a
     /// header isn't found in the table, it is added if the header name wasn't
     /// found either (i.e. there are never two header names with different
-    /// values in the produced header table). Strings are always encoded as
-    /// literals (Huffman encoding is not used).
+    /// values in the produced header table), unless indexing is disabled or
+    /// the name is never indexed (cf. [Encoder::set_never_indexed]). Strings
+    /// are Huffman-encoded if that's enabled and shortens them, cf.
+    /// [Encoder::set_huffman].
     pub fn encode<'b, I>(&mut self, headers: I) -> Vec<u8>
     where
         I: IntoIterator<Item = (&'b [u8], &'b [u8])>,
@@ -227,6 +338,9 @@ impl<'a> Encoder<'a> {
         I: IntoIterator<Item = (&'b [u8], &'b [u8])>,
         W: io::Write,
     {
+        if let Some(size) = self.pending_size_update.take() {
+            encode_integer_into(size, 5, 0x20, writer)?;
+        }
         for header in headers {
             self.encode_header_into(header, writer)?;
         }
@@ -243,11 +357,20 @@ impl<'a> Encoder<'a> {
         header: (&[u8], &[u8]),
         writer: &mut W,
     ) -> io::Result<()> {
+        self.stats.fields += 1;
+        let never_indexed = self.never_indexed.iter().any(|name| name == header.0);
         match self.header_table.find_header(header) {
+            None if never_indexed => {
+                self.encode_literal(&header, Literal::NeverIndexed, writer)?;
+            }
+            None if !self.indexing => {
+                self.encode_literal(&header, Literal::WithoutIndexing, writer)?;
+            }
             None => {
+                self.record_insertion(header);
                 // The name of the header is in no tables: need to encode
                 // it with both a literal name and value.
-                self.encode_literal(&header, true, writer)?;
+                self.encode_literal(&header, Literal::IncrementalIndexing, writer)?;
                 self.header_table
                     .add_header(header.0.to_vec(), header.1.to_vec());
             }
@@ -255,17 +378,49 @@ impl<'a> Encoder<'a> {
                 // The name of the header is at the given index, but the
                 // value does not match the current one: need to encode
                 // only the value as a literal.
-                self.encode_indexed_name((index, header.1), false, writer)?;
+                let literal = if never_indexed {
+                    Literal::NeverIndexed
+                } else {
+                    Literal::WithoutIndexing
+                };
+                self.encode_indexed_name((index, header.1), literal, writer)?;
             }
             Some((index, true)) => {
                 // The full header was found in one of the tables, so we
                 // just encode the index.
+                if index > STATIC_TABLE.len() {
+                    self.stats.dynamic_hits += 1;
+                }
                 self.encode_indexed(index, writer)?;
             }
         };
         Ok(())
     }
 
+    fn record_insertion(&mut self, header: (&[u8], &[u8])) {
+        self.stats.insertions += 1;
+
+        let mut hasher = DefaultHasher::new();
+        header.hash(&mut hasher);
+        let hash = hasher.finish();
+        let count = self.recent_insertion_counts.entry(hash).or_default();
+        if *count > 0 {
+            self.stats.reinsertions += 1;
+        }
+        *count += 1;
+
+        self.recent_insertions.push_back(hash);
+        if self.recent_insertions.len() > RECENT_INSERTIONS {
+            let oldest = self.recent_insertions.pop_front().unwrap();
+            if let Entry::Occupied(mut e) = self.recent_insertion_counts.entry(oldest) {
+                *e.get_mut() -= 1;
+                if *e.get() == 0 {
+                    e.remove();
+                }
+            }
+        }
+    }
+
     /// Encodes a header as a literal (i.e. both the name and the value are
     /// encoded as a string literal) and places the result in the given buffer
     /// `buf`.
@@ -273,16 +428,16 @@ impl<'a> Encoder<'a> {
     /// # Parameters
     ///
     /// - `header` - the header to be encoded
-    /// - `should_index` - indicates whether the given header should be indexed,
-    ///   i.e. inserted into the dynamic table
+    /// - `literal` - whether the decoder should insert the header into its
+    ///   dynamic table, cf. [Literal]
     /// - `buf` - The buffer into which the result is placed
     fn encode_literal<W: io::Write>(
         &mut self,
         header: &(&[u8], &[u8]),
-        should_index: bool,
+        literal: Literal,
         buf: &mut W,
     ) -> io::Result<()> {
-        let mask = if should_index { 0x40 } else { 0x0 };
+        let (mask, _) = literal.mask_and_prefix();
 
         buf.write_all(&[mask])?;
         self.encode_string_literal(header.0, buf)?;
@@ -291,16 +446,20 @@ impl<'a> Encoder<'a> {
     }
 
     /// Encodes a string literal and places the result in the given buffer
-    /// `buf`.
-    ///
-    /// The function does not consider Huffman encoding for now, but always
-    /// produces a string literal representations, according to the HPACK spec
-    /// section 5.2.
+    /// `buf`, according to the HPACK spec section 5.2: Huffman-encoded if
+    /// that's enabled and strictly shorter, as-is otherwise.
     fn encode_string_literal<W: io::Write>(
         &mut self,
         octet_str: &[u8],
         buf: &mut W,
     ) -> io::Result<()> {
+        if self.huffman {
+            let huffman_len = huffman::encoded_len(octet_str);
+            if huffman_len < octet_str.len() {
+                encode_integer_into(huffman_len, 7, 0x80, buf)?;
+                return huffman::encode_into(octet_str, buf);
+            }
+        }
         encode_integer_into(octet_str.len(), 7, 0, buf)?;
         buf.write_all(octet_str)?;
         Ok(())
@@ -311,10 +470,10 @@ impl<'a> Encoder<'a> {
     fn encode_indexed_name<W: io::Write>(
         &mut self,
         header: (usize, &[u8]),
-        should_index: bool,
+        literal: Literal,
         buf: &mut W,
     ) -> io::Result<()> {
-        let (mask, prefix) = if should_index { (0x40, 6) } else { (0x0, 4) };
+        let (mask, prefix) = literal.mask_and_prefix();
 
         encode_integer_into(header.0, prefix, mask, buf)?;
         // So far, we rely on just one strategy for encoding string literals.
@@ -334,6 +493,26 @@ impl<'a> Encoder<'a> {
     }
 }
 
+/// The literal header field representations, cf. RFC 7541 section 6.2
+#[derive(Clone, Copy)]
+enum Literal {
+    IncrementalIndexing,
+    WithoutIndexing,
+    NeverIndexed,
+}
+
+impl Literal {
+    /// The representation's leading bits, and the prefix size of the name
+    /// index that follows them
+    fn mask_and_prefix(self) -> (u8, u8) {
+        match self {
+            Literal::IncrementalIndexing => (0x40, 6),
+            Literal::WithoutIndexing => (0x00, 4),
+            Literal::NeverIndexed => (0x10, 4),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use tracing::debug;
@@ -477,4 +656,105 @@ mod tests {
 
         assert!(is_decodable(&result, &headers));
     }
+
+    /// Tests that resizing the table is signaled to the decoder, and that
+    /// stats count hits, insertions and evictions.
+    #[test]
+    fn test_resize_table_and_stats() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let headers = [(&b"x-request-id"[..], &b"aaaa"[..])];
+
+        decoder.decode(&encoder.encode(headers)).unwrap();
+        decoder.decode(&encoder.encode(headers)).unwrap();
+        let stats = encoder.stats();
+        assert_eq!(stats.fields, 2);
+        assert_eq!(stats.insertions, 1);
+        assert_eq!(stats.reinsertions, 0);
+        assert_eq!(stats.dynamic_hits, 1);
+        assert_eq!(stats.evictions, 0);
+
+        encoder.resize_table(0);
+        assert_eq!(encoder.max_table_size(), 0);
+        assert_eq!(encoder.stats().evictions, 1);
+        let result = encoder.encode(headers);
+        // size update to 0, then a literal
+        assert_eq!(result[0], 0x20);
+        assert_eq!(decoder.decode(&result).unwrap().len(), 1);
+        assert_eq!(decoder.table_size(), 0);
+
+        // only signaled once
+        assert_ne!(encoder.encode(headers)[0], 0x20);
+        assert_eq!(encoder.stats().reinsertions, 2);
+
+        encoder.resize_table(1337);
+        let result = encoder.encode(headers);
+        assert_eq!(&result[..3], [0x3f, 154, 10]);
+        assert_eq!(decoder.decode(&result).unwrap().len(), 1);
+        assert_eq!(encoder.table_size(), decoder.table_size());
+    }
+
+    /// Tests that never-indexed names and disabled indexing keep fields out
+    /// of the dynamic table, with the matching representation.
+    #[test]
+    fn test_never_indexed_and_no_indexing() {
+        let mut encoder = Encoder::new();
+        encoder.set_never_indexed([&b"set-cookie"[..], b"x-secret"]);
+        let mut decoder = Decoder::new();
+
+        for _ in 0..2 {
+            // `set-cookie` is in the static table, `x-secret` isn't
+            let headers = [(&b"set-cookie"[..], &b"a=b"[..]), (b"x-secret", b"hunter2")];
+            let result = encoder.encode(headers);
+            // name index 55, on a 4-bit prefix
+            assert_eq!(&result[..2], [0x1f, 55 - 15]);
+            assert_eq!(result[6], 0x10);
+            assert_eq!(decoder.decode(&result).unwrap().len(), 2);
+        }
+        assert_eq!(encoder.table_size(), 0);
+
+        let mut encoder = Encoder::new();
+        encoder.set_indexing(false);
+        let headers = [(&b"x-request-id"[..], &b"aaaa"[..])];
+        for _ in 0..2 {
+            let result = encoder.encode(headers);
+            assert_eq!(result[0], 0x00);
+            assert_eq!(decoder.decode(&result).unwrap().len(), 1);
+        }
+        assert_eq!(encoder.table_size(), 0);
+        assert_eq!(encoder.stats().insertions, 0);
+    }
+
+    /// Tests that Huffman encoding is only used where it shortens literals.
+    #[test]
+    fn test_huffman_when_shorter() {
+        let mut encoder = Encoder::new();
+        encoder.set_huffman(true);
+        let mut decoder = Decoder::new();
+
+        // strings from RFC 7541 appendix C.6.1
+        let headers = [
+            (&b":status"[..], &b"302"[..]),
+            (b"cache-control", b"private"),
+            (b"location", b"https://www.example.com"),
+        ];
+        let result = encoder.encode(headers);
+        let contains = |needle: &[u8]| result.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(&[0x82, 0x64, 0x02]));
+        assert!(contains(&[0x85, 0xae, 0xc3, 0x77, 0x1a, 0x4b]));
+        assert!(contains(&[
+            0x91, 0x9d, 0x29, 0xad, 0x17, 0x18, 0x63, 0xc7, 0x8f, 0x0b, 0x97, 0xc8, 0xe9, 0xae,
+            0x82, 0xae, 0x43, 0xd3,
+        ]));
+        assert_eq!(decoder.decode(&result).unwrap().len(), 3);
+
+        // rare octets get longer codes, so they're sent as-is
+        let headers = [(&b"x-bin"[..], &b"\x00\x01\x02\x03"[..])];
+        let result = encoder.encode(headers);
+        assert_eq!(&result[result.len() - 5..], b"\x04\x00\x01\x02\x03");
+        assert_eq!(
+            decoder.decode(&result).unwrap(),
+            [(b"x-bin".to_vec(), b"\x00\x01\x02\x03".to_vec())]
+        );
+    }
 }
@@ -3,6 +3,7 @@
 //! (HPACK-draft-10, Appendix B)
 
 use std::collections::HashMap;
+use std::io;
 
 /// Represents a symbol that can be inserted into a Huffman-encoded octet
 /// string.
@@ -219,6 +220,46 @@ where
     }
 }
 
+/// How many bytes `buf` takes once Huffman-encoded, padding included
+pub fn encoded_len(buf: &[u8]) -> usize {
+    let bits: usize = buf
+        .iter()
+        .map(|&b| HUFFMAN_CODE_TABLE[b as usize].1 as usize)
+        .sum();
+    bits.div_ceil(8)
+}
+
+/// Huffman-encodes `buf` into `writer`, padding the last byte with the most
+/// significant bits of EOS, i.e. ones.
+pub fn encode_into<W: io::Write>(buf: &[u8], writer: &mut W) -> io::Result<()> {
+    let mut out = Vec::with_capacity(encoded_len(buf));
+    // codes are at most 30 bits, and fewer than 8 are left over from the
+    // previous ones
+    let mut bits: u64 = 0;
+    let mut num_bits: u8 = 0;
+    for &b in buf {
+        let (code, code_len) = HUFFMAN_CODE_TABLE[b as usize];
+        bits = (bits << code_len) | code as u64;
+        num_bits += code_len;
+        while num_bits >= 8 {
+            num_bits -= 8;
+            out.push((bits >> num_bits) as u8);
+        }
+        bits &= (1 << num_bits) - 1;
+    }
+    if num_bits > 0 {
+        out.push(((bits << (8 - num_bits)) as u8) | (0xff >> num_bits));
+    }
+    writer.write_all(&out)
+}
+
+/// Huffman-encodes `buf` into a newly allocated `Vec`
+pub fn encode(buf: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(buf, &mut out).unwrap();
+    out
+}
+
 static HUFFMAN_CODE_TABLE: &[(u32, u8)] = &[
     (0x1ff8, 13),
     (0x7fffd8, 23),
@@ -484,6 +525,7 @@ mod tests {
     use super::BitIterator;
     use super::HuffmanDecoder;
     use super::HuffmanDecoderError;
+    use super::{encode, encoded_len};
 
     /// A helper function that converts the given slice containing values `1`
     /// and `0` to a `Vec` of `bool`s, according to the number.
@@ -705,4 +747,32 @@ mod tests {
             );
         }
     }
+
+    /// Encodes the examples of RFC 7541 appendix C.4, and round-trips every
+    /// octet.
+    #[test]
+    fn test_huffman_encode() {
+        let examples: [(&[u8], &[u8]); 3] = [
+            (
+                b"www.example.com",
+                &[
+                    0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff,
+                ],
+            ),
+            (b"no-cache", &[0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf]),
+            (
+                b"custom-value",
+                &[0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xb8, 0xe8, 0xb4, 0xbf],
+            ),
+        ];
+        for (plain, encoded) in examples {
+            assert_eq!(encode(plain), encoded);
+            assert_eq!(encoded_len(plain), encoded.len());
+        }
+
+        let all: Vec<u8> = (0..=255).collect();
+        let mut decoder = HuffmanDecoder::new();
+        assert_eq!(decoder.decode(&encode(&all)).unwrap(), all);
+        assert_eq!(encode(b""), b"");
+    }
 }
@@ -40,10 +40,13 @@ pub mod huffman;
 /// only cares about the maximum size as set by the HPACK {en,de}coder and lets
 /// *it* worry about making certain that the changes are valid according to
 /// the (current) constraints of the protocol.
+#[derive(Clone)]
 struct DynamicTable {
     table: VecDeque<(Vec<u8>, Vec<u8>)>,
     size: usize,
     max_size: usize,
+    /// How many headers were evicted to make room, over the table's lifetime
+    evictions: u64,
 }
 
 impl DynamicTable {
@@ -60,6 +63,7 @@ impl DynamicTable {
             table: VecDeque::new(),
             size: 0,
             max_size,
+            evictions: 0,
         }
     }
 
@@ -135,6 +139,7 @@ impl DynamicTable {
                 self.size -= last_header.0.len() + last_header.1.len() + 32;
             }
             self.table.pop_back();
+            self.evictions += 1;
         }
     }
 
@@ -175,6 +180,7 @@ type StaticTable<'a> = &'a [(&'a [u8], &'a [u8])];
 /// The struct represents the header table obtained by merging the static and
 /// dynamic tables into a single index address space, as described in section
 /// `2.3.3.` of the HPACK spec.
+#[derive(Clone)]
 struct HeaderTable<'a> {
     static_table: StaticTable<'a>,
     dynamic_table: DynamicTable,
@@ -0,0 +1,502 @@
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf};
+
+use eyre::{bail, WrapErr};
+use loona::proxy_protocol::ProxyProtocolMode;
+use serde::Deserialize;
+
+/// The whole config file, cf. `loona-serve.example.toml`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    #[serde(rename = "listener")]
+    pub(crate) listeners: Vec<ListenerConfig>,
+
+    pub(crate) upgrade: Option<UpgradeConfig>,
+
+    pub(crate) fd_budget: Option<FdBudgetConfig>,
+
+    pub(crate) conn_limit: Option<ConnLimitConfig>,
+
+    pub(crate) buffer_pool: Option<BufferPoolConfig>,
+}
+
+/// Lets a newer loona-serve take over the listeners of a running one: the
+/// new process connects to `socket`, the old one hands off its listening
+/// sockets, stops accepting, and exits once its connections are done (or
+/// after `drain_timeout_secs`).
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct UpgradeConfig {
+    pub(crate) socket: PathBuf,
+
+    #[serde(default = "default_drain_timeout_secs")]
+    pub(crate) drain_timeout_secs: u64,
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    30
+}
+
+/// Closes the oldest idle connections, across all listeners, when the
+/// process gets close to its file descriptor limit
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct FdBudgetConfig {
+    /// Defaults to the soft `RLIMIT_NOFILE`
+    pub(crate) limit: Option<usize>,
+
+    /// Start closing idle connections at this fraction of the limit...
+    pub(crate) prune_ratio: Option<f64>,
+
+    /// ...and stop once back under this one
+    pub(crate) target_ratio: Option<f64>,
+}
+
+/// Caps on connections, across all listeners: connections past them are
+/// closed right after being accepted
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ConnLimitConfig {
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) max_connections_per_ip: Option<usize>,
+
+    /// Connections past that rate wait in the listen backlog
+    pub(crate) accepts_per_sec: Option<f64>,
+    pub(crate) accept_burst: Option<u32>,
+}
+
+/// Sizes the pool of 4 KiB buffers that requests and responses go through,
+/// cf. [buffet::bufpool::PoolConf]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BufferPoolConfig {
+    /// Buffers mapped at startup
+    pub(crate) num_bufs: Option<u32>,
+
+    /// Buffers mapped at once
+    pub(crate) slab_bufs: Option<u32>,
+
+    /// If set, the pool grows a slab at a time up to that many buffers,
+    /// instead of sticking to `num_bufs`
+    pub(crate) max_bufs: Option<u32>,
+}
+
+impl From<&BufferPoolConfig> for buffet::bufpool::PoolConf {
+    fn from(config: &BufferPoolConfig) -> Self {
+        let mut conf = Self::default();
+        if let Some(num_bufs) = config.num_bufs {
+            conf.num_bufs = num_bufs;
+        }
+        if let Some(slab_bufs) = config.slab_bufs {
+            conf.slab_bufs = slab_bufs;
+        }
+        if let Some(max_bufs) = config.max_bufs {
+            conf.growth = buffet::bufpool::Growth::Slabs { max_bufs };
+        }
+        conf
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ListenerConfig {
+    pub(crate) addr: SocketAddr,
+
+    /// Ignored for TLS listeners, which negotiate it with ALPN
+    #[serde(default)]
+    pub(crate) protocol: Protocol,
+
+    /// Whether connections start with a PROXY protocol header (v1 or v2),
+    /// e.g. behind a load balancer. Logs and routes then see the client's
+    /// address instead of the load balancer's.
+    #[serde(default)]
+    pub(crate) accept_proxy_protocol: AcceptProxyProtocol,
+
+    pub(crate) tls: Option<TlsConfig>,
+
+    pub(crate) max_header_section_size: Option<u32>,
+    pub(crate) max_header_count: Option<usize>,
+
+    /// HTTP/2 only
+    pub(crate) max_streams: Option<u32>,
+
+    /// HTTP/2 only: send DATA frames no larger than this, even if clients
+    /// allow more
+    pub(crate) max_data_frame_size: Option<u32>,
+
+    /// HTTP/2 only: adapt the HPACK table size to traffic
+    pub(crate) hpack_table: Option<HpackTableConfig>,
+
+    /// HTTP/1.1 only: hang up after this many requests
+    pub(crate) max_requests_per_connection: Option<u32>,
+
+    /// Keep this many of the latest protocol errors clients caused, for
+    /// `protocol_errors` routes to show
+    pub(crate) protocol_error_log: Option<usize>,
+
+    /// Added to every response that doesn't have them already
+    #[serde(default)]
+    pub(crate) response_headers: BTreeMap<String, String>,
+
+    #[serde(rename = "route", default)]
+    pub(crate) routes: Vec<RouteConfig>,
+
+    /// TLS connections for these server names are relayed as-is instead of
+    /// being terminated. Listeners without `tls` can only pass connections
+    /// through, and close the ones that match no rule.
+    #[serde(rename = "passthrough", default)]
+    pub(crate) passthroughs: Vec<PassthroughConfig>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Protocol {
+    #[default]
+    H1,
+    H2c,
+    /// HTTP/1.1, switching to HTTP/2 for clients that send its preface or
+    /// ask with `upgrade: h2c`
+    Auto,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AcceptProxyProtocol {
+    #[default]
+    Off,
+    /// Only while moving behind a load balancer: any client can claim any
+    /// address
+    Optional,
+    Require,
+}
+
+impl From<AcceptProxyProtocol> for ProxyProtocolMode {
+    fn from(value: AcceptProxyProtocol) -> Self {
+        match value {
+            AcceptProxyProtocol::Off => ProxyProtocolMode::Off,
+            AcceptProxyProtocol::Optional => ProxyProtocolMode::Optional,
+            AcceptProxyProtocol::Require => ProxyProtocolMode::Require,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct HpackTableConfig {
+    pub(crate) min: u32,
+    pub(crate) max: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TlsConfig {
+    /// PEM file with the certificate chain, leaf first
+    pub(crate) cert: PathBuf,
+
+    /// PEM file with the private key
+    pub(crate) key: PathBuf,
+
+    /// Stateless session resumption, with keys that rotate on their own
+    pub(crate) session_tickets: Option<SessionTicketsConfig>,
+}
+
+/// Processes (and threads) that share `secret_file` accept each other's
+/// tickets. Without it, a random secret is made up at startup, and tickets
+/// stop working when the process exits.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SessionTicketsConfig {
+    /// At least 32 bytes, e.g. from `openssl rand 48`
+    pub(crate) secret_file: Option<PathBuf>,
+
+    /// Keys change this often, tickets stay valid for one to two periods
+    #[serde(default = "default_ticket_rotation_secs")]
+    pub(crate) rotation_secs: u64,
+}
+
+fn default_ticket_rotation_secs() -> u64 {
+    6 * 60 * 60
+}
+
+/// Picks an upstream by the server name in the TLS ClientHello (SNI)
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PassthroughConfig {
+    /// An exact name, or `*.` followed by a domain, which matches names one
+    /// label deeper
+    pub(crate) server_name: String,
+
+    pub(crate) upstream: SocketAddr,
+}
+
+/// Exactly one of `dir`, `proxy` and `protocol_errors` must be set
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RouteConfig {
+    pub(crate) prefix: String,
+
+    /// Serve files from this directory, with the prefix stripped from
+    /// request paths
+    pub(crate) dir: Option<PathBuf>,
+    pub(crate) index_file: Option<String>,
+
+    /// Forward requests to this HTTP/1.1 upstream, paths unchanged
+    pub(crate) proxy: Option<SocketAddr>,
+
+    /// Start every upstream connection with a PROXY protocol v2 header
+    /// describing the client's connection
+    #[serde(default)]
+    pub(crate) proxy_protocol: bool,
+
+    /// Show the listener's `protocol_error_log`, as plain text
+    #[serde(default)]
+    pub(crate) protocol_errors: bool,
+}
+
+impl Config {
+    pub(crate) fn load(path: &str) -> eyre::Result<Self> {
+        let contents = std::fs::read_to_string(path).wrap_err_with(|| format!("reading {path}"))?;
+        Self::parse(&contents).wrap_err_with(|| format!("in {path}"))
+    }
+
+    pub(crate) fn parse(contents: &str) -> eyre::Result<Self> {
+        let config: Config = toml::from_str(contents)?;
+        if config.listeners.is_empty() {
+            bail!("no listeners configured");
+        }
+
+        if let Some(budget) = &config.fd_budget {
+            let defaults = loona::fd_budget::FdBudgetConf::default();
+            let prune = budget.prune_ratio.unwrap_or(defaults.prune_ratio);
+            let target = budget.target_ratio.unwrap_or(defaults.target_ratio);
+            if !(0.0 < target && target <= prune && prune <= 1.0) {
+                bail!("fd_budget: need 0 < target_ratio ({target}) <= prune_ratio ({prune}) <= 1");
+            }
+        }
+
+        if let Some(rate) = config.conn_limit.as_ref().and_then(|l| l.accepts_per_sec) {
+            if rate.is_nan() || rate <= 0.0 {
+                bail!("conn_limit: accepts_per_sec ({rate}) must be positive");
+            }
+        }
+
+        if let Some(pool) = &config.buffer_pool {
+            let conf = buffet::bufpool::PoolConf::from(pool);
+            if conf.num_bufs == 0 || conf.slab_bufs == 0 {
+                bail!("buffer_pool: num_bufs and slab_bufs must be positive");
+            }
+            if let Some(max) = pool.max_bufs.filter(|max| *max < conf.num_bufs) {
+                bail!(
+                    "buffer_pool: max_bufs ({max}) is less than num_bufs ({})",
+                    conf.num_bufs
+                );
+            }
+        }
+
+        for listener in &config.listeners {
+            if listener.tls.is_none()
+                && !listener.passthroughs.is_empty()
+                && !listener.routes.is_empty()
+            {
+                bail!(
+                    "listener {}: without `tls`, connections can only be passed through, it can't have routes",
+                    listener.addr
+                );
+            }
+            for (name, value) in &listener.response_headers {
+                if loona::http::HeaderName::from_bytes(name.as_bytes()).is_err()
+                    || value.contains(['\r', '\n', '\0'])
+                {
+                    bail!(
+                        "listener {}: invalid response header {name:?}: {value:?}",
+                        listener.addr
+                    );
+                }
+            }
+            if let Some(tickets) = listener
+                .tls
+                .as_ref()
+                .and_then(|tls| tls.session_tickets.as_ref())
+            {
+                if tickets.rotation_secs == 0 {
+                    bail!(
+                        "listener {}: session_tickets.rotation_secs can't be zero",
+                        listener.addr
+                    );
+                }
+            }
+            if listener.max_data_frame_size == Some(0) {
+                bail!(
+                    "listener {}: max_data_frame_size can't be zero",
+                    listener.addr
+                );
+            }
+            if let Some(table) = listener.hpack_table {
+                if table.min > table.max {
+                    bail!(
+                        "listener {}: hpack_table.min ({}) is larger than hpack_table.max ({})",
+                        listener.addr,
+                        table.min,
+                        table.max
+                    );
+                }
+            }
+            for passthrough in &listener.passthroughs {
+                let name = passthrough
+                    .server_name
+                    .strip_prefix("*.")
+                    .unwrap_or(&passthrough.server_name);
+                if name.is_empty() || name.contains('*') {
+                    bail!(
+                        "listener {}: invalid passthrough server name {:?}",
+                        listener.addr,
+                        passthrough.server_name
+                    );
+                }
+            }
+            for route in &listener.routes {
+                if !route.prefix.starts_with('/') {
+                    bail!(
+                        "listener {}: route prefix {:?} must start with a slash",
+                        listener.addr,
+                        route.prefix
+                    );
+                }
+                match (&route.dir, &route.proxy, route.protocol_errors) {
+                    (Some(_), None, false) => {
+                        if route.proxy_protocol {
+                            bail!(
+                                "listener {}: route {:?} sets `proxy_protocol` but doesn't `proxy`",
+                                listener.addr,
+                                route.prefix
+                            );
+                        }
+                    }
+                    (None, Some(_), false) => {
+                        if route.index_file.is_some() {
+                            bail!(
+                                "listener {}: route {:?} sets `index_file` but doesn't serve a `dir`",
+                                listener.addr,
+                                route.prefix
+                            );
+                        }
+                    }
+                    (None, None, true) => {
+                        if listener.protocol_error_log.is_none() {
+                            bail!(
+                                "listener {}: route {:?} shows `protocol_errors` but the listener has no `protocol_error_log`",
+                                listener.addr,
+                                route.prefix
+                            );
+                        }
+                        if route.index_file.is_some() || route.proxy_protocol {
+                            bail!(
+                                "listener {}: route {:?} only shows `protocol_errors`, it can't set `index_file` or `proxy_protocol`",
+                                listener.addr,
+                                route.prefix
+                            );
+                        }
+                    }
+                    _ => bail!(
+                        "listener {}: route {:?} must set exactly one of `dir`, `proxy` and `protocol_errors`",
+                        listener.addr,
+                        route.prefix
+                    ),
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AcceptProxyProtocol, Config, HpackTableConfig, Protocol};
+
+    #[test]
+    fn test_example_config() {
+        let config = Config::parse(include_str!("../loona-serve.example.toml")).unwrap();
+        assert_eq!(config.listeners.len(), 2);
+
+        let plain = &config.listeners[0];
+        assert_eq!(plain.protocol, Protocol::H1);
+        assert_eq!(plain.accept_proxy_protocol, AcceptProxyProtocol::Off);
+        assert_eq!(plain.max_requests_per_connection, Some(1000));
+        assert_eq!(plain.response_headers["server"], "loona-serve");
+        assert!(plain.tls.is_none());
+        assert_eq!(plain.protocol_error_log, Some(100));
+        assert_eq!(plain.routes.len(), 3);
+        assert!(plain.routes[0].protocol_errors);
+        assert!(plain.routes[1].dir.is_some());
+        assert!(plain.routes[2].proxy.is_some());
+        assert!(plain.routes[2].proxy_protocol);
+
+        let tls = &config.listeners[1];
+        let session_tickets = tls.tls.as_ref().unwrap().session_tickets.as_ref().unwrap();
+        assert_eq!(
+            session_tickets.secret_file.as_ref().unwrap().to_str(),
+            Some("ticket.key")
+        );
+        assert_eq!(session_tickets.rotation_secs, 3600);
+        assert_eq!(tls.accept_proxy_protocol, AcceptProxyProtocol::Require);
+        assert_eq!(tls.max_streams, Some(64));
+        assert_eq!(tls.max_data_frame_size, Some(16384));
+        assert_eq!(
+            tls.hpack_table,
+            Some(HpackTableConfig {
+                min: 1024,
+                max: 16384
+            })
+        );
+        assert_eq!(tls.passthroughs.len(), 1);
+        assert_eq!(tls.passthroughs[0].server_name, "*.internal.example.org");
+
+        let upgrade = config.upgrade.unwrap();
+        assert_eq!(upgrade.socket.to_str(), Some("/tmp/loona-serve.sock"));
+        assert_eq!(upgrade.drain_timeout_secs, 30);
+
+        let fd_budget = config.fd_budget.unwrap();
+        assert_eq!(fd_budget.limit, None);
+        assert_eq!(fd_budget.prune_ratio, Some(0.9));
+        assert_eq!(fd_budget.target_ratio, Some(0.8));
+
+        let conn_limit = config.conn_limit.unwrap();
+        assert_eq!(conn_limit.max_connections, Some(10000));
+        assert_eq!(conn_limit.max_connections_per_ip, Some(100));
+        assert_eq!(conn_limit.accepts_per_sec, Some(1000.0));
+        assert_eq!(conn_limit.accept_burst, None);
+
+        let pool = buffet::bufpool::PoolConf::from(&config.buffer_pool.unwrap());
+        assert_eq!(pool.num_bufs, 16384);
+        assert_eq!(
+            pool.growth,
+            buffet::bufpool::Growth::Slabs { max_bufs: 262144 }
+        );
+    }
+
+    #[test]
+    fn test_invalid_configs() {
+        for contents in [
+            "",
+            "[[listener]]\naddr = \"127.0.0.1:80\"\nprotocol = \"h3\"",
+            "[[listener]]\naddr = \"127.0.0.1:80\"\nunknown = 1",
+            "[[listener]]\naddr = \"127.0.0.1:80\"\naccept_proxy_protocol = true",
+            "[[listener]]\naddr = \"127.0.0.1:80\"\n[listener.response_headers]\n\"bad name\" = \"x\"",
+            "[fd_budget]\nprune_ratio = 0.5\ntarget_ratio = 0.8\n[[listener]]\naddr = \"127.0.0.1:80\"",
+            "[conn_limit]\naccepts_per_sec = 0\n[[listener]]\naddr = \"127.0.0.1:80\"",
+            "[buffer_pool]\nnum_bufs = 1024\nmax_bufs = 512\n[[listener]]\naddr = \"127.0.0.1:80\"",
+            "[buffer_pool]\nslab_bufs = 0\n[[listener]]\naddr = \"127.0.0.1:80\"",
+            "[[listener]]\naddr = \"127.0.0.1:80\"\n[[listener.route]]\nprefix = \"/\"",
+            "[[listener]]\naddr = \"127.0.0.1:80\"\n[[listener.route]]\nprefix = \"/\"\ndir = \"a\"\nproxy = \"127.0.0.1:81\"",
+            "[[listener]]\naddr = \"127.0.0.1:80\"\n[[listener.route]]\nprefix = \"a\"\ndir = \"a\"",
+            "[[listener]]\naddr = \"127.0.0.1:80\"\n[[listener.route]]\nprefix = \"/\"\ndir = \"a\"\nproxy_protocol = true",
+            "[[listener]]\naddr = \"127.0.0.1:80\"\nmax_data_frame_size = 0",
+            "[[listener]]\naddr = \"127.0.0.1:80\"\n[[listener.route]]\nprefix = \"/\"\nprotocol_errors = true",
+            "[[listener]]\naddr = \"127.0.0.1:80\"\nprotocol_error_log = 10\n[[listener.route]]\nprefix = \"/\"\ndir = \"a\"\nprotocol_errors = true",
+            "[[listener]]\naddr = \"127.0.0.1:80\"\n[[listener.passthrough]]\nserver_name = \"a.*\"\nupstream = \"127.0.0.1:81\"",
+            "[[listener]]\naddr = \"127.0.0.1:80\"\n[[listener.passthrough]]\nserver_name = \"a\"\nupstream = \"127.0.0.1:81\"\n[[listener.route]]\nprefix = \"/\"\ndir = \"a\"",
+        ] {
+            assert!(Config::parse(contents).is_err(), "{contents:?} should fail");
+        }
+    }
+}
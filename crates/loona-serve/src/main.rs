@@ -0,0 +1,491 @@
+use std::{
+    net::SocketAddr,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use buffet::{
+    bufpool,
+    net::{
+        handoff::{self, Handoff},
+        TcpListener, TcpStream,
+    },
+    IntoHalves, RollMut,
+};
+use config::{Config, ListenerConfig, Protocol};
+use eyre::{bail, WrapErr};
+use loona::{
+    conn_limit::{ConnLimitConf, ConnLimiter, ConnPermit},
+    fd_budget::{FdBudget, FdBudgetConf},
+    h1, h2, h2c,
+    protocol_errors::ProtocolErrorLog,
+    proxy_protocol::ProxyProtocolMode,
+    ConnInfo,
+};
+use router::{PassthroughTable, Router};
+use tokio::sync::watch;
+use tracing::Level;
+use tracing_subscriber::{filter::Targets, layer::SubscriberExt, util::SubscriberInitExt};
+use upgrade::ConnCount;
+
+mod config;
+mod proxy_header;
+mod router;
+mod upgrade;
+
+#[cfg(target_os = "linux")]
+mod passthrough;
+#[cfg(target_os = "linux")]
+mod tickets;
+#[cfg(target_os = "linux")]
+mod tls;
+
+fn main() -> eyre::Result<()> {
+    setup_tracing_and_error_reporting();
+
+    let Some(path) = std::env::args().nth(1) else {
+        bail!("usage: loona-serve <config.toml>");
+    };
+    let config = Config::load(&path)?;
+    if let Some(pool) = &config.buffer_pool {
+        // before `buffet::start` sets up the default pool
+        bufpool::initialize_allocator_with_conf(&pool.into())?;
+    }
+    buffet::start(real_main(config))
+}
+
+async fn real_main(config: Config) -> eyre::Result<()> {
+    let (inherited, upgrade_ln) = match &config.upgrade {
+        Some(upgrade) => {
+            let (items, ln) = upgrade::take_over(upgrade)?;
+            (items, Some(ln))
+        }
+        None => (vec![], None),
+    };
+    let mut inherited_listeners = vec![];
+    let mut inherited_conns = vec![];
+    for item in inherited {
+        match item {
+            Handoff::Listener(ln) => inherited_listeners.push(ln),
+            Handoff::Connection { stream, buffered } => inherited_conns.push((stream, buffered)),
+        }
+    }
+
+    let conns = ConnCount::default();
+    let fd_budget = config.fd_budget.as_ref().map(|budget| {
+        let mut conf = FdBudgetConf {
+            limit: budget.limit,
+            ..Default::default()
+        };
+        if let Some(ratio) = budget.prune_ratio {
+            conf.prune_ratio = ratio;
+        }
+        if let Some(ratio) = budget.target_ratio {
+            conf.target_ratio = ratio;
+        }
+        Rc::new(FdBudget::new(conf))
+    });
+    if let Some(budget) = &fd_budget {
+        tracing::info!(limit = ?budget.stats().limit, "Closing idle connections when running out of file descriptors");
+    }
+    let conn_limiter = config.conn_limit.as_ref().map(|limit| {
+        let mut conf = ConnLimitConf {
+            max_connections: limit.max_connections,
+            max_connections_per_ip: limit.max_connections_per_ip,
+            accepts_per_sec: limit.accepts_per_sec,
+            ..Default::default()
+        };
+        if let Some(burst) = limit.accept_burst {
+            conf.accept_burst = burst;
+        }
+        Rc::new(ConnLimiter::new(conf, None))
+    });
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut tasks = vec![];
+    for listener_config in &config.listeners {
+        let addr = listener_config.addr;
+        let listener = Rc::new(Listener::new(
+            listener_config,
+            conns.clone(),
+            fd_budget.clone(),
+            conn_limiter.clone(),
+        )?);
+        let ln = match take_listener(&mut inherited_listeners, addr) {
+            Some(ln) => ln,
+            None => TcpListener::bind(addr)
+                .await
+                .wrap_err_with(|| format!("binding {addr}"))?,
+        };
+        tracing::info!("Listening on {}", ln.local_addr()?);
+
+        // connections handed off along with the listeners go to the listener
+        // they came in through
+        let (mine, others) = inherited_conns
+            .into_iter()
+            .partition(|(stream, _)| stream.local_addr().is_ok_and(|a| a.port() == addr.port()));
+        inherited_conns = others;
+        for (stream, buffered) in mine {
+            listener.take_over_conn(stream, buffered);
+        }
+
+        tasks.push(buffet::spawn(listener.run(ln, stop_rx.clone())));
+    }
+    if !inherited_listeners.is_empty() || !inherited_conns.is_empty() {
+        tracing::warn!(
+            "Closing {} listeners and {} connections we inherited but have no config for",
+            inherited_listeners.len(),
+            inherited_conns.len()
+        );
+    }
+
+    let Some(upgrade_ln) = upgrade_ln else {
+        for task in tasks {
+            task.await??;
+        }
+        return Ok(());
+    };
+
+    let successor = upgrade::wait_for_successor(&upgrade_ln).await?;
+    drop(upgrade_ln);
+    tracing::info!("Handing off listeners to new process");
+
+    // stop accepting first: connections that come in from now on wait in the
+    // backlog until the new process accepts them
+    stop_tx.send_replace(true);
+    let mut items = vec![];
+    for task in tasks {
+        items.push(Handoff::Listener(task.await??));
+    }
+    handoff::send(&successor, &items).wrap_err("handing off listeners")?;
+    drop(items);
+
+    let pool = bufpool::stats();
+    tracing::info!(
+        high_water_mark = pool.high_water_mark,
+        allocated = pool.allocated,
+        capacity = pool.capacity,
+        alloc_failures = pool.alloc_failures,
+        "Buffer pool usage"
+    );
+
+    let drain_timeout = Duration::from_secs(config.upgrade.map_or(0, |u| u.drain_timeout_secs));
+    tracing::info!("Draining {} connections", conns.get());
+    if tokio::time::timeout(drain_timeout, conns.drained())
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "{} connections still open after {drain_timeout:?}, exiting anyway",
+            conns.get()
+        );
+    }
+    Ok(())
+}
+
+/// Removes the listener bound to `addr` from `listeners`, if any
+fn take_listener(listeners: &mut Vec<TcpListener>, addr: SocketAddr) -> Option<TcpListener> {
+    let index = listeners
+        .iter()
+        .position(|ln| ln.local_addr().is_ok_and(|a| a == addr))?;
+    Some(listeners.swap_remove(index))
+}
+
+/// Everything needed to serve the connections accepted on one listener
+pub(crate) struct Listener {
+    protocol: Protocol,
+    proxy_protocol: ProxyProtocolMode,
+    h1_conf: Rc<h1::ServerConf>,
+    h2_conf: Rc<h2::ServerConf>,
+    router: Router,
+    passthrough: PassthroughTable,
+    conns: ConnCount,
+    fd_budget: Option<Rc<FdBudget>>,
+    conn_limiter: Option<Rc<ConnLimiter>>,
+
+    #[cfg(target_os = "linux")]
+    tls: Option<tokio_rustls::TlsAcceptor>,
+}
+
+impl Listener {
+    fn new(
+        config: &ListenerConfig,
+        conns: ConnCount,
+        fd_budget: Option<Rc<FdBudget>>,
+        conn_limiter: Option<Rc<ConnLimiter>>,
+    ) -> eyre::Result<Self> {
+        let mut h1_conf = h1::ServerConf::default();
+        let mut h2_conf = h2::ServerConf::default();
+        if let Some(size) = config.max_header_section_size {
+            h1_conf.max_header_section_size = size as usize;
+            h2_conf.max_header_section_size = size;
+        }
+        if let Some(count) = config.max_header_count {
+            h1_conf.max_header_count = count;
+            h2_conf.max_header_count = count;
+        }
+        if let Some(max_streams) = config.max_streams {
+            h2_conf.max_streams = Some(max_streams);
+        }
+        h2_conf.max_data_frame_size = config.max_data_frame_size;
+        if let Some(table) = config.hpack_table {
+            h2_conf.hpack_table_sizing = h2::HpackTableSizing::Adaptive {
+                min: table.min,
+                max: table.max,
+            };
+        }
+        h1_conf.max_requests_per_connection = config.max_requests_per_connection;
+        let mut default_response_headers = loona::Headers::default();
+        for (name, value) in &config.response_headers {
+            let name = loona::http::HeaderName::from_bytes(name.as_bytes())?;
+            default_response_headers.append(name, value.clone().into_bytes().into());
+        }
+        h1_conf.default_response_headers = default_response_headers.clone();
+        h2_conf.default_response_headers = default_response_headers;
+        h1_conf.fd_budget = fd_budget.clone();
+        h2_conf.fd_budget = fd_budget.clone();
+        let protocol_errors = config
+            .protocol_error_log
+            .map(|capacity| Rc::new(ProtocolErrorLog::new(capacity)));
+        h1_conf.protocol_errors = protocol_errors.clone();
+        h2_conf.protocol_errors = protocol_errors.clone();
+
+        #[cfg(target_os = "linux")]
+        let tls = config.tls.as_ref().map(tls::acceptor).transpose()?;
+        #[cfg(not(target_os = "linux"))]
+        if config.tls.is_some() {
+            bail!("TLS support is provided through kTLS, which we only support the Linux variant of right now");
+        }
+        #[cfg(not(target_os = "linux"))]
+        if !config.passthroughs.is_empty() {
+            bail!("TLS passthrough is only supported on Linux right now");
+        }
+
+        Ok(Self {
+            protocol: config.protocol,
+            proxy_protocol: config.accept_proxy_protocol.into(),
+            h1_conf: Rc::new(h1_conf),
+            h2_conf: Rc::new(h2_conf),
+            router: Router::new(&config.routes, protocol_errors.as_ref()),
+            passthrough: PassthroughTable::new(&config.passthroughs),
+            conns,
+            fd_budget,
+            conn_limiter,
+            #[cfg(target_os = "linux")]
+            tls,
+        })
+    }
+
+    /// Accepts connections until `stop` is set, then gives the listening
+    /// socket back
+    async fn run(
+        self: Rc<Self>,
+        ln: TcpListener,
+        mut stop: watch::Receiver<bool>,
+    ) -> eyre::Result<TcpListener> {
+        // EMFILE and ENFILE, on Linux
+        const OUT_OF_FDS: [i32; 2] = [24, 23];
+
+        loop {
+            let accept = async {
+                if let Some(limiter) = &self.conn_limiter {
+                    limiter.throttle().await;
+                }
+                ln.accept().await
+            };
+            let res = tokio::select! {
+                res = accept => res,
+                _ = stop.wait_for(|stop| *stop) => return Ok(ln),
+            };
+            let (stream, addr) = match (res, &self.fd_budget) {
+                (Ok(accepted), _) => accepted,
+                (Err(e), Some(budget))
+                    if e.raw_os_error()
+                        .is_some_and(|code| OUT_OF_FDS.contains(&code)) =>
+                {
+                    // make room, and give those connections a moment to close
+                    let pruned = budget.prune_oldest(16);
+                    tracing::warn!(%pruned, "Out of file descriptors, closing idle connections");
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    continue;
+                }
+                (Err(e), _) => return Err(e.into()),
+            };
+            tracing::debug!(%addr, "Accepted connection");
+            if let Some(budget) = &self.fd_budget {
+                budget.check();
+            }
+            let permit = match &self.conn_limiter {
+                Some(limiter) => match limiter.admit(Some(addr.ip())) {
+                    Ok(permit) => Some(permit),
+                    Err(rejection) => {
+                        tracing::debug!(%addr, "Closing connection: {rejection}");
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            self.spawn_conn(stream, vec![], self.proxy_protocol, permit);
+        }
+    }
+
+    /// Serves a connection a previous process handed off to us
+    fn take_over_conn(self: &Rc<Self>, stream: TcpStream, buffered: Vec<u8>) {
+        #[cfg(target_os = "linux")]
+        if self.tls.is_some() || !self.passthrough.is_empty() {
+            // the TLS session lives in the other process
+            tracing::warn!("Dropping TLS connection handed off by previous process");
+            return;
+        }
+        // the previous process already read its PROXY header, if any, and
+        // admitted it past the connection limits
+        self.spawn_conn(stream, buffered, ProxyProtocolMode::Off, None);
+    }
+
+    fn spawn_conn(
+        self: &Rc<Self>,
+        stream: TcpStream,
+        buffered: Vec<u8>,
+        proxy_protocol: ProxyProtocolMode,
+        permit: Option<ConnPermit>,
+    ) {
+        let conn_info = ConnInfo {
+            peer_addr: stream.peer_addr().ok(),
+            local_addr: stream.local_addr().ok(),
+            accepted_at: Some(Instant::now()),
+            ..Default::default()
+        };
+        let guard = self.conns.track();
+        let listener = self.clone();
+        buffet::spawn(async move {
+            let _guard = guard;
+            let _permit = permit;
+            let peer_addr = conn_info.peer_addr;
+            let res = listener
+                .handle_conn(stream, &buffered, proxy_protocol, conn_info)
+                .await;
+            if let Err(e) = res {
+                tracing::warn!(?peer_addr, "connection error: {e:?}");
+            }
+        });
+    }
+
+    async fn handle_conn(
+        &self,
+        stream: TcpStream,
+        buffered: &[u8],
+        proxy_protocol: ProxyProtocolMode,
+        mut conn_info: ConnInfo,
+    ) -> eyre::Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.tls.is_some() || !self.passthrough.is_empty() {
+            let mut stream = tls::to_tokio_tcp_stream(stream)?;
+            if let Some(header) =
+                proxy_header::read_from_socket(proxy_protocol, &mut stream).await?
+            {
+                header.apply(&mut conn_info);
+            }
+            let mut server_name = None;
+            if !self.passthrough.is_empty() {
+                server_name = passthrough::peek_server_name(&stream).await?;
+                if let Some(upstream) = server_name
+                    .as_deref()
+                    .and_then(|name| self.passthrough.upstream_for(name))
+                {
+                    tracing::debug!(?server_name, %upstream, "Passing connection through");
+                    return passthrough::relay(stream, upstream).await;
+                }
+            }
+            return match &self.tls {
+                Some(acceptor) => tls::handle_tls_conn(self, acceptor, stream, conn_info).await,
+                None => {
+                    tracing::debug!(?server_name, "No passthrough rule matches, closing");
+                    Ok(())
+                }
+            };
+        }
+
+        let mut client_buf = RollMut::alloc()?;
+        client_buf.put(buffered)?;
+        let (mut transport_r, transport_w) = stream.into_halves();
+        let (header, client_buf) =
+            proxy_header::read(proxy_protocol, client_buf, &mut transport_r).await?;
+        if let Some(header) = header {
+            header.apply(&mut conn_info);
+        }
+        let io = (transport_r, transport_w);
+        match self.protocol {
+            Protocol::H1 => self.serve_h1(io, client_buf, conn_info).await,
+            Protocol::H2c => self.serve_h2(io, client_buf, conn_info).await,
+            Protocol::Auto => self.serve_auto(io, client_buf, conn_info).await,
+        }
+    }
+
+    pub(crate) async fn serve_h1(
+        &self,
+        io: impl IntoHalves,
+        client_buf: RollMut,
+        conn_info: ConnInfo,
+    ) -> eyre::Result<()> {
+        let driver = self.router.clone();
+        let conf = self.h1_conf.clone();
+        h1::serve_with_conn_info(io.into_halves(), conf, client_buf, driver, conn_info)
+            .await
+            .map_err(|e| eyre::eyre!("http/1 server error: {e:?}"))?;
+        Ok(())
+    }
+
+    pub(crate) async fn serve_h2(
+        &self,
+        io: impl IntoHalves,
+        client_buf: RollMut,
+        conn_info: ConnInfo,
+    ) -> eyre::Result<()> {
+        let driver = Rc::new(self.router.clone());
+        let conf = self.h2_conf.clone();
+        h2::serve_with_conn_info(io.into_halves(), conf, client_buf, driver, conn_info)
+            .await
+            .map_err(|e| eyre::eyre!("http/2 server error: {e:?}"))?;
+        Ok(())
+    }
+
+    async fn serve_auto(
+        &self,
+        io: impl IntoHalves,
+        client_buf: RollMut,
+        conn_info: ConnInfo,
+    ) -> eyre::Result<()> {
+        let driver = Rc::new(self.router.clone());
+        let (h1_conf, h2_conf) = (self.h1_conf.clone(), self.h2_conf.clone());
+        h2c::serve_with_conn_info(
+            io.into_halves(),
+            h1_conf,
+            h2_conf,
+            client_buf,
+            driver,
+            conn_info,
+        )
+        .await
+        .map_err(|e| eyre::eyre!("h2c server error: {e:?}"))?;
+        Ok(())
+    }
+}
+
+fn setup_tracing_and_error_reporting() {
+    color_eyre::install().unwrap();
+
+    let targets = if let Ok(rust_log) = std::env::var("RUST_LOG") {
+        rust_log.parse::<Targets>().unwrap()
+    } else {
+        Targets::new().with_default(Level::INFO)
+    };
+
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_ansi(true)
+        .with_file(false)
+        .with_line_number(false);
+
+    tracing_subscriber::registry()
+        .with(targets)
+        .with(fmt_layer)
+        .init();
+}
@@ -0,0 +1,53 @@
+//! Relays TLS connections to an upstream without terminating them, picking
+//! the upstream by the server name the client asks for (SNI).
+
+use std::{net::SocketAddr, time::Duration};
+
+use eyre::WrapErr;
+use loona::sni::{self, Peeked};
+use tokio::net::TcpStream;
+
+/// How long clients get to send their whole ClientHello
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returns the server name in the client's ClientHello, if any, leaving it
+/// in the socket's receive buffer for whoever handles the connection next.
+/// Connections that don't start with a ClientHello have no server name.
+pub(crate) async fn peek_server_name(stream: &TcpStream) -> eyre::Result<Option<String>> {
+    let mut buf = vec![0u8; sni::MAX_CLIENT_HELLO_LEN];
+    let peek = async {
+        loop {
+            let n = stream.peek(&mut buf).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            match sni::client_hello_server_name(&buf[..n]) {
+                Ok(Peeked::ServerName(name)) => return Ok(name),
+                Ok(Peeked::Incomplete) => {
+                    // peeking again right away would return the same bytes:
+                    // give the rest of the ClientHello time to arrive
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                Err(e) => {
+                    tracing::debug!("Can't tell the server name: {e}");
+                    return Ok(None);
+                }
+            }
+        }
+    };
+    tokio::time::timeout(CLIENT_HELLO_TIMEOUT, peek)
+        .await
+        .wrap_err("waiting for the ClientHello")?
+}
+
+/// Copies bytes both ways between `stream` and `upstream`, until both sides
+/// are done
+pub(crate) async fn relay(mut stream: TcpStream, upstream: SocketAddr) -> eyre::Result<()> {
+    let mut upstream_stream = TcpStream::connect(upstream)
+        .await
+        .wrap_err_with(|| format!("connecting to passthrough upstream {upstream}"))?;
+    upstream_stream.set_nodelay(true)?;
+    let (sent, received) = tokio::io::copy_bidirectional(&mut stream, &mut upstream_stream).await?;
+    tracing::debug!(%upstream, %sent, %received, "Passthrough connection done");
+    Ok(())
+}
@@ -0,0 +1,76 @@
+//! Reads the PROXY protocol header load balancers start connections with,
+//! so routes and logs see the client's address rather than theirs, cf.
+//! [loona::proxy_protocol]
+
+use std::time::Duration;
+
+use buffet::{ReadOwned, RollMut};
+use eyre::WrapErr;
+use loona::proxy_protocol::{self, ProxyHeader, ProxyProtocolMode};
+
+/// How long clients (load balancers, really) get to send the whole header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// For plain connections: what was read past the header stays in
+/// `client_buf`
+pub(crate) async fn read(
+    mode: ProxyProtocolMode,
+    client_buf: RollMut,
+    transport_r: &mut impl ReadOwned,
+) -> eyre::Result<(Option<ProxyHeader>, RollMut)> {
+    let (res, client_buf) = tokio::time::timeout(
+        HEADER_TIMEOUT,
+        proxy_protocol::read_header(mode, client_buf, transport_r),
+    )
+    .await
+    .wrap_err("waiting for the PROXY header")?;
+    Ok((res?, client_buf))
+}
+
+/// For TLS connections, which rustls reads from the socket itself: only the
+/// header is taken off the socket, the ClientHello stays in its receive
+/// buffer.
+#[cfg(target_os = "linux")]
+pub(crate) async fn read_from_socket(
+    mode: ProxyProtocolMode,
+    stream: &mut tokio::net::TcpStream,
+) -> eyre::Result<Option<ProxyHeader>> {
+    use loona::proxy_protocol::{Parsed, ReadHeaderError};
+    use tokio::io::AsyncReadExt;
+
+    if mode == ProxyProtocolMode::Off {
+        return Ok(None);
+    }
+
+    let mut buf = vec![0u8; 512];
+    let peek = async {
+        loop {
+            let n = stream.peek(&mut buf).await?;
+            match proxy_protocol::parse_header(&buf[..n])? {
+                Parsed::Header { header, len } => {
+                    stream.read_exact(&mut buf[..len]).await?;
+                    return Ok::<_, eyre::Report>(Some(header));
+                }
+                Parsed::NotProxy if mode == ProxyProtocolMode::Require => {
+                    return Err(ReadHeaderError::Missing.into())
+                }
+                Parsed::NotProxy => return Ok(None),
+                Parsed::Incomplete if n == 0 => {
+                    // the handshake fails on its own
+                    return Ok(None);
+                }
+                Parsed::Incomplete if n == buf.len() => {
+                    buf.resize(proxy_protocol::MAX_HEADER_LEN, 0);
+                }
+                Parsed::Incomplete => {
+                    // peeking again right away would return the same bytes:
+                    // give the rest of the header time to arrive
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        }
+    };
+    tokio::time::timeout(HEADER_TIMEOUT, peek)
+        .await
+        .wrap_err("waiting for the PROXY header")?
+}
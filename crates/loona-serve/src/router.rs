@@ -0,0 +1,348 @@
+use std::{fmt::Write, net::SocketAddr, rc::Rc};
+
+use b_x::{BxForResults, BX};
+use buffet::{net::TcpStream, IntoHalves, ReadOwned, WriteOwned};
+use loona::{
+    fs::ServeDir,
+    http::{header, uri::PathAndQuery, StatusCode, Uri},
+    protocol_errors::ProtocolErrorLog,
+    proxy::proxy_request,
+    proxy_protocol::ProxyHeader,
+    Body, BodyChunk, Encoder, ExpectResponseHeaders, Headers, Request, Responder, Response,
+    ResponseDone, ServerDriver, SinglePieceBody,
+};
+
+use crate::config::{PassthroughConfig, RouteConfig};
+
+enum Target {
+    Dir(ServeDir),
+    Proxy {
+        addr: SocketAddr,
+        proxy_protocol: bool,
+    },
+    ProtocolErrors(Rc<ProtocolErrorLog>),
+}
+
+struct Route {
+    prefix: String,
+    target: Target,
+}
+
+/// Sends requests to the route with the longest matching prefix
+#[derive(Clone)]
+pub(crate) struct Router {
+    routes: Rc<[Route]>,
+}
+
+impl Router {
+    /// `protocol_errors` is the listener's log, which config validation
+    /// guarantees is there if a route shows it
+    pub(crate) fn new(
+        configs: &[RouteConfig],
+        protocol_errors: Option<&Rc<ProtocolErrorLog>>,
+    ) -> Self {
+        let mut routes = configs
+            .iter()
+            .map(|config| {
+                let target = match (&config.dir, config.proxy) {
+                    (Some(dir), _) => {
+                        let mut serve_dir = ServeDir::new(dir);
+                        if let Some(index_file) = &config.index_file {
+                            serve_dir.index_file = Some(index_file.clone());
+                        }
+                        Target::Dir(serve_dir)
+                    }
+                    (None, Some(addr)) => Target::Proxy {
+                        addr,
+                        proxy_protocol: config.proxy_protocol,
+                    },
+                    (None, None) => match protocol_errors {
+                        Some(log) if config.protocol_errors => Target::ProtocolErrors(log.clone()),
+                        _ => unreachable!("validated when loading the config"),
+                    },
+                };
+                Route {
+                    prefix: config.prefix.trim_end_matches('/').to_string(),
+                    target,
+                }
+            })
+            .collect::<Vec<_>>();
+        routes.sort_by_key(|r| std::cmp::Reverse(r.prefix.len()));
+        Self {
+            routes: routes.into(),
+        }
+    }
+
+    /// The route for `path`, and what's left of `path` after its prefix
+    fn route<'a>(&self, path: &'a str) -> Option<(&Route, &'a str)> {
+        self.routes.iter().find_map(|route| {
+            let rest = path.strip_prefix(route.prefix.as_str())?;
+            // `/static` matches `/static/a` but not `/staticky`
+            (rest.is_empty() || rest.starts_with('/')).then_some((route, rest))
+        })
+    }
+}
+
+/// Picks where to relay TLS connections we don't terminate, by server name
+#[derive(Clone, Default)]
+pub(crate) struct PassthroughTable {
+    exact: Rc<[(String, SocketAddr)]>,
+    /// Keyed by what follows `*.`
+    wildcards: Rc<[(String, SocketAddr)]>,
+}
+
+impl PassthroughTable {
+    pub(crate) fn new(configs: &[PassthroughConfig]) -> Self {
+        let (wildcards, exact): (Vec<_>, Vec<_>) = configs
+            .iter()
+            .map(|config| (config.server_name.to_ascii_lowercase(), config.upstream))
+            .partition(|(name, _)| name.starts_with("*."));
+        let wildcards = wildcards
+            .into_iter()
+            .map(|(name, upstream)| (name[2..].to_string(), upstream))
+            .collect::<Vec<_>>();
+        Self {
+            exact: exact.into(),
+            wildcards: wildcards.into(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcards.is_empty()
+    }
+
+    /// The upstream for `server_name` (lowercase), exact names first
+    pub(crate) fn upstream_for(&self, server_name: &str) -> Option<SocketAddr> {
+        if let Some((_, upstream)) = self.exact.iter().find(|(name, _)| name == server_name) {
+            return Some(*upstream);
+        }
+        let (_, parent) = server_name.split_once('.')?;
+        self.wildcards
+            .iter()
+            .find(|(domain, _)| domain == parent)
+            .map(|(_, upstream)| *upstream)
+    }
+}
+
+impl<E> ServerDriver<E> for Router
+where
+    E: Encoder,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        mut req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> Result<Responder<E, ResponseDone>, Self::Error> {
+        let Some((route, rest)) = self.route(req.uri.path()) else {
+            drain_body(req_body).await?;
+            return respond_with_status(respond, StatusCode::NOT_FOUND).await;
+        };
+
+        match &route.target {
+            Target::Dir(serve_dir) => {
+                req.uri = strip_path(&req.uri, rest)?;
+                serve_dir.handle(req, req_body, respond).await
+            }
+            Target::Proxy {
+                addr,
+                proxy_protocol,
+            } => {
+                let upstream = match connect_upstream(*addr, *proxy_protocol, &req).await {
+                    Ok(upstream) => upstream,
+                    Err(e) => {
+                        tracing::warn!("Could not connect to upstream {addr}: {e}");
+                        drain_body(req_body).await?;
+                        return respond_with_status(respond, StatusCode::BAD_GATEWAY).await;
+                    }
+                };
+                let (_, respond) = proxy_request(upstream, req, req_body, respond).await?;
+                Ok(respond)
+            }
+            Target::ProtocolErrors(log) => {
+                drain_body(req_body).await?;
+                let page = protocol_errors_page(log);
+                let mut headers = Headers::default();
+                headers.insert(header::CONTENT_TYPE, "text/plain; charset=utf-8".into());
+                headers.insert(header::CACHE_CONTROL, "no-store".into());
+                let res = Response {
+                    status: StatusCode::OK,
+                    headers,
+                    ..Default::default()
+                };
+                respond
+                    .write_final_response_with_body(res, &mut SinglePieceBody::from(page))
+                    .await
+                    .bx()
+            }
+        }
+    }
+}
+
+/// The total, then one line per error still in `log`, latest first
+fn protocol_errors_page(log: &ProtocolErrorLog) -> Vec<u8> {
+    let recent = log.recent();
+    let mut page = format!("{} protocol errors, {} kept\n", log.total(), recent.len());
+    for error in recent.iter().rev() {
+        _ = writeln!(page, "{error}");
+    }
+    page.into_bytes()
+}
+
+/// Connects to `addr`, and sends a PROXY protocol header describing the
+/// connection `req` came in on if asked to
+async fn connect_upstream(
+    addr: SocketAddr,
+    proxy_protocol: bool,
+    req: &Request,
+) -> Result<(impl ReadOwned, impl WriteOwned), BX> {
+    let (r, mut w) = TcpStream::connect(addr).await.bx()?.into_halves();
+    if proxy_protocol {
+        let header = match &req.conn {
+            Some(conn) => ProxyHeader::from_conn_info(conn),
+            None => ProxyHeader::default(),
+        };
+        w.write_all_owned(header.encode_v2().bx()?).await.bx()?;
+    }
+    Ok((r, w))
+}
+
+/// `uri`, with its path replaced by `rest` (`/` if empty)
+fn strip_path(uri: &Uri, rest: &str) -> Result<Uri, BX> {
+    let path = if rest.is_empty() { "/" } else { rest };
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse::<PathAndQuery>().bx()?);
+    Uri::from_parts(parts).bx()
+}
+
+async fn drain_body(body: &mut impl Body) -> Result<(), BX> {
+    loop {
+        if let BodyChunk::Done { .. } = body.next_chunk().await.bx()? {
+            return Ok(());
+        }
+    }
+}
+
+async fn respond_with_status<E: Encoder>(
+    respond: Responder<E, ExpectResponseHeaders>,
+    status: StatusCode,
+) -> Result<Responder<E, ResponseDone>, BX> {
+    let res = Response {
+        status,
+        ..Default::default()
+    };
+    respond
+        .write_final_response_with_body(res, &mut ())
+        .await
+        .bx()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use loona::{http::Uri, protocol_errors::ProtocolErrorLog};
+
+    use super::{protocol_errors_page, strip_path, PassthroughTable, Router, Target};
+    use crate::config::Config;
+
+    #[test]
+    fn test_route() {
+        let config = Config::parse(include_str!("../loona-serve.example.toml")).unwrap();
+        let log = Rc::new(ProtocolErrorLog::new(10));
+        let router = Router::new(&config.listeners[0].routes, Some(&log));
+
+        let (route, rest) = router.route("/_loona/protocol-errors").unwrap();
+        assert!(matches!(route.target, Target::ProtocolErrors(_)));
+        assert_eq!(rest, "");
+
+        let (route, rest) = router.route("/static/style.css").unwrap();
+        assert!(matches!(route.target, Target::Dir(_)));
+        assert_eq!(rest, "/style.css");
+
+        let (route, rest) = router.route("/static").unwrap();
+        assert!(matches!(route.target, Target::Dir(_)));
+        assert_eq!(rest, "");
+
+        for path in ["/staticky", "/", "/api/users"] {
+            let (route, rest) = router.route(path).unwrap();
+            assert!(
+                matches!(
+                    route.target,
+                    Target::Proxy {
+                        proxy_protocol: true,
+                        ..
+                    }
+                ),
+                "{path}"
+            );
+            assert_eq!(rest, path);
+        }
+    }
+
+    #[test]
+    fn test_passthrough_table() {
+        let config = Config::parse(
+            r#"
+            [[listener]]
+            addr = "127.0.0.1:443"
+            [[listener.passthrough]]
+            server_name = "*.example.org"
+            upstream = "127.0.0.1:1"
+            [[listener.passthrough]]
+            server_name = "Special.example.org"
+            upstream = "127.0.0.1:2"
+            "#,
+        )
+        .unwrap();
+        let table = PassthroughTable::new(&config.listeners[0].passthroughs);
+        assert!(!table.is_empty());
+
+        let port = |name| table.upstream_for(name).map(|addr| addr.port());
+        assert_eq!(port("a.example.org"), Some(1));
+        assert_eq!(port("special.example.org"), Some(2));
+        assert_eq!(port("example.org"), None);
+        assert_eq!(port("a.b.example.org"), None);
+        assert_eq!(port("example.com"), None);
+    }
+
+    #[test]
+    fn test_protocol_errors_page() {
+        let log = ProtocolErrorLog::new(1);
+        assert_eq!(protocol_errors_page(&log), b"0 protocol errors, 0 kept\n");
+
+        for kind in ["a", "b"] {
+            log.record(loona::protocol_errors::ProtocolError {
+                at: std::time::UNIX_EPOCH,
+                peer_addr: None,
+                version: loona::http::Version::HTTP_11,
+                code: loona::error::ConnectionError::MalformedRequest,
+                kind: kind.into(),
+                snippet: b"GET".to_vec(),
+            });
+        }
+        assert_eq!(
+            String::from_utf8(protocol_errors_page(&log)).unwrap(),
+            "2 protocol errors, 1 kept\n1970-01-01T00:00:00.000Z - HTTP/1.1 malformed_request b \"GET\"\n"
+        );
+    }
+
+    #[test]
+    fn test_strip_path() {
+        let uri: Uri = "https://example.org/static/a.css?v=2".parse().unwrap();
+        assert_eq!(
+            strip_path(&uri, "/a.css").unwrap(),
+            "https://example.org/a.css?v=2"
+        );
+
+        let uri: Uri = "/static".parse().unwrap();
+        assert_eq!(strip_path(&uri, "").unwrap(), "/");
+    }
+}
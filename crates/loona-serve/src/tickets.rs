@@ -0,0 +1,240 @@
+//! TLS session tickets whose keys are derived from a secret and the current
+//! time, so every process (and thread) that has the secret issues and
+//! accepts the same tickets, across restarts and upgrades.
+//!
+//! Time is cut into periods of `rotation_secs`. Tickets are encrypted with
+//! the current period's key, and decrypted with that one or the previous
+//! period's, so a ticket stays valid for at least one full period.
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use aws_lc_rs::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    hkdf::{self, KeyType, Salt, HKDF_SHA256},
+    rand,
+};
+use eyre::{bail, WrapErr};
+use tokio_rustls::rustls::server::ProducesTickets;
+
+use crate::config::SessionTicketsConfig;
+
+const KEY_NAME_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// Secrets shorter than that are refused
+const MIN_SECRET_LEN: usize = 32;
+
+pub(crate) struct RotatingTicketer {
+    prk: hkdf::Prk,
+    rotation_secs: u64,
+
+    /// The keys for the period they were derived for: current, then previous
+    keys: Mutex<Option<(u64, Arc<[TicketKey; 2]>)>>,
+}
+
+struct TicketKey {
+    name: [u8; KEY_NAME_LEN],
+    key: LessSafeKey,
+}
+
+impl RotatingTicketer {
+    /// Reads the secret from `config.secret_file`, or makes one up, in which
+    /// case tickets only work with this process.
+    pub(crate) fn from_config(config: &SessionTicketsConfig) -> eyre::Result<Self> {
+        let secret = match &config.secret_file {
+            Some(path) => std::fs::read(path)
+                .wrap_err_with(|| format!("reading session ticket secret {}", path.display()))?,
+            None => {
+                let mut secret = vec![0u8; MIN_SECRET_LEN];
+                rand::fill(&mut secret).map_err(|_| eyre::eyre!("no randomness available"))?;
+                secret
+            }
+        };
+        Self::new(&secret, config.rotation_secs)
+    }
+
+    pub(crate) fn new(secret: &[u8], rotation_secs: u64) -> eyre::Result<Self> {
+        if secret.len() < MIN_SECRET_LEN {
+            bail!(
+                "session ticket secret is {} bytes, it needs at least {MIN_SECRET_LEN}",
+                secret.len()
+            );
+        }
+        if rotation_secs == 0 {
+            bail!("session ticket rotation period can't be zero");
+        }
+        Ok(Self {
+            prk: Salt::new(HKDF_SHA256, b"loona-serve session tickets").extract(secret),
+            rotation_secs,
+            keys: Mutex::new(None),
+        })
+    }
+
+    fn current_period(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        now.as_secs() / self.rotation_secs
+    }
+
+    fn keys(&self, period: u64) -> Option<Arc<[TicketKey; 2]>> {
+        let mut keys = self.keys.lock().ok()?;
+        match &*keys {
+            Some((derived_for, keys)) if *derived_for == period => Some(keys.clone()),
+            _ => {
+                let derived =
+                    Arc::new([self.derive(period)?, self.derive(period.saturating_sub(1))?]);
+                *keys = Some((period, derived.clone()));
+                Some(derived)
+            }
+        }
+    }
+
+    fn derive(&self, period: u64) -> Option<TicketKey> {
+        let info = period.to_be_bytes();
+        let info = [&info[..]];
+        let okm = self.prk.expand(&info, Len(KEY_NAME_LEN + KEY_LEN)).ok()?;
+        let mut material = [0u8; KEY_NAME_LEN + KEY_LEN];
+        okm.fill(&mut material).ok()?;
+
+        let (name, key) = material.split_at(KEY_NAME_LEN);
+        Some(TicketKey {
+            name: name.try_into().ok()?,
+            key: LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).ok()?),
+        })
+    }
+
+    /// key name, nonce, then the sealed state and its tag
+    fn encrypt_at(&self, period: u64, plain: &[u8]) -> Option<Vec<u8>> {
+        let keys = self.keys(period)?;
+        let primary = &keys[0];
+
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::fill(&mut nonce).ok()?;
+        let mut sealed = plain.to_vec();
+        primary
+            .key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(&primary.name),
+                &mut sealed,
+            )
+            .ok()?;
+
+        let mut ticket = Vec::with_capacity(KEY_NAME_LEN + NONCE_LEN + sealed.len());
+        ticket.extend_from_slice(&primary.name);
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(&sealed);
+        Some(ticket)
+    }
+
+    fn decrypt_at(&self, period: u64, ticket: &[u8]) -> Option<Vec<u8>> {
+        if ticket.len() < KEY_NAME_LEN + NONCE_LEN {
+            return None;
+        }
+        let (name, rest) = ticket.split_at(KEY_NAME_LEN);
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+
+        let keys = self.keys(period)?;
+        let key = keys.iter().find(|k| k.name[..] == *name)?;
+        let mut plain = sealed.to_vec();
+        let len = key
+            .key
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).ok()?,
+                Aad::from(&key.name),
+                &mut plain,
+            )
+            .ok()?
+            .len();
+        plain.truncate(len);
+        Some(plain)
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.rotation_secs.try_into().unwrap_or(u32::MAX)
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.encrypt_at(self.current_period(), plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.decrypt_at(self.current_period(), cipher)
+    }
+}
+
+impl fmt::Debug for RotatingTicketer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RotatingTicketer")
+            .field("rotation_secs", &self.rotation_secs)
+            .finish_non_exhaustive()
+    }
+}
+
+struct Len(usize);
+
+impl KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RotatingTicketer;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_roundtrip_and_rotation() {
+        let ticketer = RotatingTicketer::new(SECRET, 3600).unwrap();
+        let ticket = ticketer.encrypt_at(100, b"session state").unwrap();
+        assert_eq!(ticketer.decrypt_at(100, &ticket).unwrap(), b"session state");
+
+        // still good during the next period, not after that
+        assert_eq!(ticketer.decrypt_at(101, &ticket).unwrap(), b"session state");
+        assert_eq!(ticketer.decrypt_at(102, &ticket), None);
+        // and not before it was issued either
+        assert_eq!(ticketer.decrypt_at(99, &ticket), None);
+
+        // another process with the same secret takes it
+        let restarted = RotatingTicketer::new(SECRET, 3600).unwrap();
+        assert_eq!(
+            restarted.decrypt_at(101, &ticket).unwrap(),
+            b"session state"
+        );
+
+        let other = RotatingTicketer::new(&[7; 32], 3600).unwrap();
+        assert_eq!(other.decrypt_at(100, &ticket), None);
+    }
+
+    #[test]
+    fn test_tampering() {
+        let ticketer = RotatingTicketer::new(SECRET, 3600).unwrap();
+        let ticket = ticketer.encrypt_at(100, b"session state").unwrap();
+        for i in [0, 20, ticket.len() - 1] {
+            let mut tampered = ticket.clone();
+            tampered[i] ^= 1;
+            assert_eq!(ticketer.decrypt_at(100, &tampered), None, "{i}");
+        }
+        assert_eq!(ticketer.decrypt_at(100, &ticket[..20]), None);
+        assert_eq!(ticketer.decrypt_at(100, &[]), None);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(RotatingTicketer::new(b"short", 3600).is_err());
+        assert!(RotatingTicketer::new(SECRET, 0).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    os::fd::{FromRawFd, IntoRawFd},
+    sync::Arc,
+    time::Instant,
+};
+
+use buffet::{net::TcpStream, IntoHalves, RollMut};
+use eyre::{eyre, WrapErr};
+use loona::{ConnInfo, TlsInfo};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+use crate::{config::TlsConfig, tickets::RotatingTicketer, Listener};
+
+#[cfg(feature = "ktls")]
+type HandshakeStream = ktls::CorkStream<tokio::net::TcpStream>;
+#[cfg(not(feature = "ktls"))]
+type HandshakeStream = tokio::net::TcpStream;
+
+type TlsStream = tokio_rustls::server::TlsStream<HandshakeStream>;
+
+/// Loads the certificate chain and key, offers HTTP/2 and HTTP/1.1 over
+/// ALPN, and sets up session tickets if configured
+pub(crate) fn acceptor(config: &TlsConfig) -> eyre::Result<TlsAcceptor> {
+    let open = |path: &std::path::Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .wrap_err_with(|| format!("opening {}", path.display()))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(&config.cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .wrap_err_with(|| format!("reading certificates from {}", config.cert.display()))?;
+    let key = rustls_pemfile::private_key(&mut open(&config.key)?)
+        .wrap_err_with(|| format!("reading private key from {}", config.key.display()))?
+        .ok_or_else(|| eyre!("no private key found in {}", config.key.display()))?;
+
+    let mut server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    // kTLS needs the session secrets once the handshake is done
+    server_config.enable_secret_extraction = true;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    if let Some(tickets) = &config.session_tickets {
+        server_config.ticketer = Arc::new(RotatingTicketer::from_config(tickets)?);
+    }
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Until we come up with `loona-rustls`, TLS connections need to
+/// temporarily go through a tokio TcpStream
+pub(crate) fn to_tokio_tcp_stream(stream: TcpStream) -> std::io::Result<tokio::net::TcpStream> {
+    let stream = unsafe { std::net::TcpStream::from_raw_fd(stream.into_raw_fd()) };
+    stream.set_nonblocking(true)?;
+    tokio::net::TcpStream::from_std(stream)
+}
+
+pub(crate) async fn handle_tls_conn(
+    listener: &Listener,
+    acceptor: &TlsAcceptor,
+    stream: tokio::net::TcpStream,
+    mut conn_info: ConnInfo,
+) -> eyre::Result<()> {
+    #[cfg(feature = "ktls")]
+    let stream = ktls::CorkStream::new(stream);
+    let stream = acceptor.accept(stream).await?;
+
+    let session = stream.get_ref().1;
+    let is_h2 = matches!(session.alpn_protocol(), Some(b"h2"));
+    tracing::debug!(%is_h2, "Performed TLS handshake");
+    #[cfg(feature = "ktls")]
+    let ktls = ktls_supports(session.negotiated_cipher_suite()).await;
+    #[cfg(not(feature = "ktls"))]
+    let ktls = false;
+    conn_info.tls_done_at = Some(Instant::now());
+    conn_info.alpn_protocol = session.alpn_protocol().map(|p| p.to_vec());
+    conn_info.tls = Some(TlsInfo {
+        server_name: session.server_name().map(|s| s.to_string()),
+        protocol_version: session.protocol_version().map(|v| format!("{v:?}")),
+        cipher_suite: session
+            .negotiated_cipher_suite()
+            .map(|s| format!("{:?}", s.suite())),
+        ktls,
+    });
+
+    #[cfg(feature = "ktls")]
+    if ktls {
+        let stream = ktls::config_ktls_server(stream).await?;
+        let (drained, stream) = stream.into_raw();
+        let drained = drained.unwrap_or_default();
+        tracing::debug!("{} bytes already decoded by rustls", drained.len());
+
+        // and back to a buffet TcpStream
+        let stream = to_uring_tcp_stream(stream)?;
+
+        let mut client_buf = RollMut::alloc()?;
+        client_buf.put(&drained[..])?;
+
+        return if is_h2 {
+            listener.serve_h2(stream, client_buf, conn_info).await
+        } else {
+            listener.serve_h1(stream, client_buf, conn_info).await
+        };
+    }
+
+    tracing::debug!("Handling TLS records in userspace");
+    let stream = UserspaceTls(stream);
+    let client_buf = RollMut::alloc()?;
+    if is_h2 {
+        listener.serve_h2(stream, client_buf, conn_info).await
+    } else {
+        listener.serve_h1(stream, client_buf, conn_info).await
+    }
+}
+
+/// Whether the kernel can take over sessions that use `suite`. Probing it
+/// takes a loopback connection, so it's only done once.
+#[cfg(feature = "ktls")]
+async fn ktls_supports(suite: Option<tokio_rustls::rustls::SupportedCipherSuite>) -> bool {
+    static CIPHERS: tokio::sync::OnceCell<Option<ktls::CompatibleCiphers>> =
+        tokio::sync::OnceCell::const_new();
+
+    let ciphers = CIPHERS
+        .get_or_init(|| async {
+            match ktls::CompatibleCiphers::new().await {
+                Ok(ciphers) => Some(ciphers),
+                Err(e) => {
+                    tracing::warn!("kTLS is unavailable, handling TLS in userspace: {e}");
+                    None
+                }
+            }
+        })
+        .await;
+    match (ciphers, suite) {
+        (Some(ciphers), Some(suite)) => ciphers.is_compatible(suite),
+        _ => false,
+    }
+}
+
+/// A TLS session that rustls keeps handling, for when the kernel can't
+struct UserspaceTls(TlsStream);
+
+impl IntoHalves for UserspaceTls {
+    type Read = tokio::io::ReadHalf<TlsStream>;
+    type Write = tokio::io::WriteHalf<TlsStream>;
+
+    fn into_halves(self) -> (Self::Read, Self::Write) {
+        tokio::io::split(self.0)
+    }
+}
+
+#[cfg(feature = "ktls")]
+fn to_uring_tcp_stream(stream: tokio::net::TcpStream) -> std::io::Result<TcpStream> {
+    use std::{mem::ManuallyDrop, os::fd::AsRawFd};
+
+    {
+        let sock = ManuallyDrop::new(unsafe { socket2::Socket::from_raw_fd(stream.as_raw_fd()) });
+        // tokio needs the socket to be "non-blocking" (as in: return EAGAIN),
+        // buffet needs it to be "blocking" (as in: let io_uring do the op async)
+        sock.set_nonblocking(false)?;
+    }
+    let uring_stream = unsafe { TcpStream::from_raw_fd(stream.as_raw_fd()) };
+    std::mem::forget(stream);
+    Ok(uring_stream)
+}
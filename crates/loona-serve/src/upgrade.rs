@@ -0,0 +1,89 @@
+use std::{cell::Cell, io::ErrorKind, os::unix::net::UnixStream, rc::Rc, time::Duration};
+
+use buffet::net::handoff::{self, Handoff};
+use eyre::WrapErr;
+use tokio::net::UnixListener;
+
+use crate::config::UpgradeConfig;
+
+/// If another loona-serve is listening on the upgrade socket, receives its
+/// sockets. Either way, binds the upgrade socket for the next process.
+pub(crate) fn take_over(config: &UpgradeConfig) -> eyre::Result<(Vec<Handoff>, UnixListener)> {
+    let path = &config.socket;
+    let items = match UnixStream::connect(path) {
+        Ok(sock) => {
+            let items = handoff::recv(&sock).wrap_err("receiving sockets from running process")?;
+            tracing::info!("Took over {} sockets from running process", items.len());
+            items
+        }
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            vec![]
+        }
+        Err(e) => return Err(e).wrap_err_with(|| format!("connecting to {}", path.display())),
+    };
+
+    // the socket file is either stale, or the old process is done with it
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).wrap_err_with(|| format!("removing {}", path.display()))
+        }
+        _ => {}
+    }
+    let ln = UnixListener::bind(path).wrap_err_with(|| format!("binding {}", path.display()))?;
+    Ok((items, ln))
+}
+
+/// Waits for a new process to ask for our sockets
+pub(crate) async fn wait_for_successor(ln: &UnixListener) -> eyre::Result<UnixStream> {
+    let (sock, _) = ln.accept().await?;
+    let sock = sock.into_std()?;
+    sock.set_nonblocking(false)?;
+    Ok(sock)
+}
+
+/// How many connections are still being served, so we know when we're done
+/// draining
+#[derive(Clone, Default)]
+pub(crate) struct ConnCount(Rc<Cell<usize>>);
+
+pub(crate) struct ConnGuard(ConnCount);
+
+impl ConnCount {
+    pub(crate) fn track(&self) -> ConnGuard {
+        self.0.set(self.0.get() + 1);
+        ConnGuard(self.clone())
+    }
+
+    pub(crate) fn get(&self) -> usize {
+        self.0.get()
+    }
+
+    pub(crate) async fn drained(&self) {
+        while self.get() > 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        let count = &self.0 .0;
+        count.set(count.get() - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnCount;
+
+    #[test]
+    fn test_conn_count() {
+        let count = ConnCount::default();
+        let a = count.track();
+        let b = count.track();
+        assert_eq!(count.get(), 2);
+        drop(a);
+        drop(b);
+        assert_eq!(count.get(), 0);
+    }
+}
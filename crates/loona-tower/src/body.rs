@@ -0,0 +1,180 @@
+use std::{
+    fmt,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::Bytes;
+use http_body::{Frame, SizeHint};
+use loona::http::HeaderMap;
+use tokio::sync::mpsc;
+
+enum Msg {
+    Frame(Frame<Bytes>),
+    End,
+    Error(String),
+}
+
+/// An [http_body::Body] fed by a [ChannelBodySender], for when code that
+/// wants to own its body (tower services, hyper) and a loona body (which
+/// lives in the driver) need to meet.
+pub struct ChannelBody {
+    rx: mpsc::Receiver<Msg>,
+    content_len: Option<u64>,
+    done: bool,
+}
+
+/// The writing end of a [ChannelBody]
+pub struct ChannelBodySender {
+    tx: mpsc::Sender<Msg>,
+}
+
+/// The [ChannelBody] was dropped: nobody is reading anymore
+#[derive(Debug, thiserror::Error)]
+#[error("channel body was dropped")]
+pub struct ChannelBodyDropped;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ChannelBodyError {
+    /// The sender was dropped before finishing the body
+    #[error("body ended before it was finished")]
+    Aborted,
+
+    /// Whatever the sender was reading from errored out
+    #[error("body source failed: {0}")]
+    Source(String),
+}
+
+/// Creates a [ChannelBody] announcing `content_len`, and its sender. The
+/// sender must write exactly that many bytes.
+pub fn channel_body(content_len: Option<u64>) -> (ChannelBodySender, ChannelBody) {
+    // a few frames of slack, so the sender doesn't wait on every read
+    let (tx, rx) = mpsc::channel(4);
+    let body = ChannelBody {
+        rx,
+        content_len,
+        done: false,
+    };
+    (ChannelBodySender { tx }, body)
+}
+
+impl ChannelBodySender {
+    pub async fn send_data(&self, data: Bytes) -> Result<(), ChannelBodyDropped> {
+        self.send(Msg::Frame(Frame::data(data))).await
+    }
+
+    /// Trailers go last: call [ChannelBodySender::finish] right after.
+    pub async fn send_trailers(&self, trailers: HeaderMap) -> Result<(), ChannelBodyDropped> {
+        self.send(Msg::Frame(Frame::trailers(trailers))).await
+    }
+
+    /// Marks the end of the body. Dropping the sender without calling this
+    /// makes the body error out.
+    pub async fn finish(self) -> Result<(), ChannelBodyDropped> {
+        self.send(Msg::End).await
+    }
+
+    /// Makes the body error out with `reason`
+    pub async fn abort(self, reason: String) {
+        _ = self.send(Msg::Error(reason)).await;
+    }
+
+    async fn send(&self, msg: Msg) -> Result<(), ChannelBodyDropped> {
+        self.tx.send(msg).await.map_err(|_| ChannelBodyDropped)
+    }
+}
+
+impl http_body::Body for ChannelBody {
+    type Data = Bytes;
+    type Error = ChannelBodyError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, ChannelBodyError>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        let res = match ready!(self.rx.poll_recv(cx)) {
+            Some(Msg::Frame(frame)) => return Poll::Ready(Some(Ok(frame))),
+            Some(Msg::End) => None,
+            Some(Msg::Error(reason)) => Some(Err(ChannelBodyError::Source(reason))),
+            None => Some(Err(ChannelBodyError::Aborted)),
+        };
+        self.done = true;
+        Poll::Ready(res)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.content_len {
+            Some(len) => SizeHint::with_exact(len),
+            None => SizeHint::default(),
+        }
+    }
+}
+
+impl fmt::Debug for ChannelBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChannelBody")
+            .field("content_len", &self.content_len)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use loona::{
+        http::{header, HeaderMap},
+        http_body_compat::FromHttpBody,
+    };
+
+    use super::channel_body;
+
+    mod http_body_conformance {
+        use super::*;
+
+        loona::body_test_suite!(|| {
+            let (tx, body) = channel_body(Some(11));
+            loona::buffet::spawn(async move {
+                tx.send_data(Bytes::from_static(b"hello ")).await.unwrap();
+                tx.send_data(Bytes::new()).await.unwrap();
+                tx.send_data(Bytes::from_static(b"world")).await.unwrap();
+
+                let mut trailers = HeaderMap::new();
+                trailers.insert("x-checksum", "abc".parse().unwrap());
+                // not allowed in trailers, must not make it through
+                trailers.insert(header::CONTENT_LENGTH, "11".parse().unwrap());
+                tx.send_trailers(trailers).await.unwrap();
+                tx.finish().await.unwrap();
+            });
+            let mut body = FromHttpBody::new(body);
+            body.announce_trailers([header::HeaderName::from_static("x-checksum")]);
+            body
+        });
+    }
+
+    #[test]
+    fn test_aborted_body() {
+        use loona::Body;
+
+        loona::buffet::start(async move {
+            let (tx, body) = channel_body(None);
+            let mut body = FromHttpBody::new(body);
+            tx.send_data(Bytes::from_static(b"partial")).await.unwrap();
+            drop(tx);
+
+            body.next_chunk().await.unwrap();
+            let Err(err) = body.next_chunk().await else {
+                panic!("body should have errored out");
+            };
+            assert!(err.to_string().contains("before it was finished"), "{err}");
+        });
+    }
+}
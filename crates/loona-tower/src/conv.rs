@@ -0,0 +1,74 @@
+use std::{error::Error as StdError, rc::Rc};
+
+use b_x::{BxForResults, BX};
+use loona::{
+    buffet::{Piece, PieceStr},
+    http::{self, header::InvalidHeaderValue, HeaderMap, HeaderValue},
+    ConnInfo, Headers, Method, Request, Response,
+};
+
+pub(crate) fn headers_from_http(map: &HeaderMap) -> Headers {
+    let mut headers = Headers::default();
+    for (name, value) in map {
+        headers.append(name.clone(), Piece::from(value.as_bytes().to_vec()));
+    }
+    headers
+}
+
+pub(crate) fn headers_to_http(headers: &Headers) -> Result<HeaderMap, InvalidHeaderValue> {
+    let mut map = HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        map.append(name.clone(), HeaderValue::from_bytes(&value[..])?);
+    }
+    Ok(map)
+}
+
+/// The connection info, if any, ends up in the request's extensions
+pub(crate) fn request_to_http<B>(req: Request, body: B) -> Result<http::Request<B>, BX> {
+    let mut http_req = http::Request::new(body);
+    *http_req.method_mut() = http::Method::from_bytes(&req.method.into_chunk()[..]).bx()?;
+    *http_req.uri_mut() = req.uri;
+    *http_req.version_mut() = req.version;
+    *http_req.headers_mut() = headers_to_http(&req.headers).bx()?;
+    if let Some(conn) = req.conn {
+        http_req.extensions_mut().insert(ConnInfo::clone(&conn));
+    }
+    Ok(http_req)
+}
+
+pub(crate) fn request_from_http(parts: http::request::Parts) -> Request {
+    Request {
+        method: Method::from(PieceStr::from(parts.method.as_str().to_string())),
+        uri: parts.uri,
+        version: parts.version,
+        headers: headers_from_http(&parts.headers),
+        conn: parts.extensions.get::<ConnInfo>().cloned().map(Rc::new),
+        stream_id: None,
+        protocol: None,
+    }
+}
+
+pub(crate) fn response_to_http<B>(
+    res: Response,
+    body: B,
+) -> Result<http::Response<B>, InvalidHeaderValue> {
+    let mut http_res = http::Response::new(body);
+    *http_res.status_mut() = res.status;
+    *http_res.version_mut() = res.version;
+    *http_res.headers_mut() = headers_to_http(&res.headers)?;
+    Ok(http_res)
+}
+
+pub(crate) fn response_from_http(parts: http::response::Parts) -> Response {
+    Response {
+        version: parts.version,
+        status: parts.status,
+        headers: headers_from_http(&parts.headers),
+    }
+}
+
+/// Errors from the `http_body`/tower world are `Send`, ours don't have to be
+pub(crate) fn box_err(e: impl Into<Box<dyn StdError + Send + Sync>>) -> BX {
+    let e: Box<dyn StdError + Send + Sync> = e.into();
+    BX::from_boxed(e)
+}
@@ -0,0 +1,293 @@
+use std::future::poll_fn;
+
+use b_x::BX;
+use bytes::Bytes;
+use loona::{
+    buffet::read_file_piece, http, http_body_compat::FromHttpBody, Body, BodyChunk, Encoder,
+    ExpectResponseHeaders, Request, Responder, ResponseDone, ServerDriver,
+};
+use tower_service::Service;
+
+use crate::{
+    body::{channel_body, ChannelBody, ChannelBodySender},
+    conv::{box_err, headers_to_http, request_to_http, response_from_http},
+};
+
+/// A [ServerDriver] that hands every request to a tower [Service]. The
+/// service is cloned for each request, like hyper does.
+///
+/// The request body is streamed to the service as a [ChannelBody] while it
+/// runs. If the service doesn't read it all, the rest is read and discarded,
+/// which HTTP/1.1 connections need to serve the next request.
+#[derive(Clone)]
+pub struct TowerDriver<S> {
+    service: S,
+}
+
+impl<S> TowerDriver<S> {
+    pub fn new(service: S) -> Self {
+        Self { service }
+    }
+}
+
+impl<E, S, ResBody> ServerDriver<E> for TowerDriver<S>
+where
+    E: Encoder,
+    S: Service<http::Request<ChannelBody>, Response = http::Response<ResBody>> + Clone,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    ResBody: http_body::Body,
+    ResBody::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> Result<Responder<E, ResponseDone>, Self::Error> {
+        let (tx, body) = channel_body(req_body.content_len());
+        let req = request_to_http(req, body)?;
+
+        let mut service = self.service.clone();
+        let respond = async move {
+            poll_fn(|cx| service.poll_ready(cx))
+                .await
+                .map_err(box_err)?;
+            let res = service.call(req).await.map_err(box_err)?;
+
+            let (parts, body) = res.into_parts();
+            respond
+                .write_final_response_with_body(
+                    response_from_http(parts),
+                    &mut FromHttpBody::new(body),
+                )
+                .await
+                .map_err(BX::from_err)
+        };
+
+        // services may well respond before they're done reading the request
+        // body (or stream it back), so both need to make progress at once
+        let (respond, pumped) = tokio::join!(respond, pump_body(req_body, tx));
+        let respond = respond?;
+        pumped?;
+        Ok(respond)
+    }
+}
+
+/// Copies `body` into `tx` until the end, then keeps reading (and dropping)
+/// whatever's left if the service drops its body early.
+async fn pump_body(body: &mut impl Body, tx: ChannelBodySender) -> Result<(), BX> {
+    let mut tx = Some(tx);
+    loop {
+        let chunk = match body.next_chunk().await {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                if let Some(tx) = tx.take() {
+                    tx.abort(e.to_string()).await;
+                }
+                return Err(BX::from_err(e));
+            }
+        };
+
+        let data = match chunk {
+            BodyChunk::Chunk(piece) => Bytes::copy_from_slice(&piece[..]),
+            BodyChunk::File { file, offset, len } => {
+                let mut data = Vec::with_capacity(len as usize);
+                while (data.len() as u64) < len {
+                    let read = data.len() as u64;
+                    let piece = read_file_piece(&file, offset + read, len - read)?;
+                    data.extend_from_slice(&piece[..]);
+                }
+                Bytes::from(data)
+            }
+            BodyChunk::Done { trailers } => {
+                let Some(tx) = tx else {
+                    return Ok(());
+                };
+                if let Some(trailers) = trailers {
+                    match headers_to_http(&trailers) {
+                        Ok(trailers) => _ = tx.send_trailers(trailers).await,
+                        Err(e) => tracing::debug!("dropping request trailers: {e}"),
+                    }
+                }
+                _ = tx.finish().await;
+                return Ok(());
+            }
+        };
+
+        if let Some(sender) = &tx {
+            if sender.send_data(data).await.is_err() {
+                tracing::trace!("service dropped the request body, draining the rest");
+                tx = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        convert::Infallible,
+        future::{ready, Ready},
+        pin::Pin,
+        rc::Rc,
+        task::{Context, Poll},
+    };
+
+    use bytes::Bytes;
+    use http_body::Frame;
+    use loona::{
+        buffet::{pipe, ReadOwned, RollMut, WriteOwned},
+        h1,
+        http::{self, header},
+    };
+    use tower_service::Service;
+
+    use super::TowerDriver;
+    use crate::ChannelBody;
+
+    /// Sends the request body back, with the method as a header
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Service<http::Request<ChannelBody>> for Echo {
+        type Response = http::Response<ChannelBody>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: http::Request<ChannelBody>) -> Self::Future {
+            let method = req.method().to_string();
+            let mut res = http::Response::new(req.into_body());
+            res.headers_mut()
+                .insert("x-method", method.parse().unwrap());
+            ready(Ok(res))
+        }
+    }
+
+    /// A body made of canned frames, with no size hint
+    struct Frames(VecDeque<Frame<Bytes>>);
+
+    impl http_body::Body for Frames {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+            Poll::Ready(self.0.pop_front().map(Ok))
+        }
+    }
+
+    /// Responds like a gRPC server would: the status comes in trailers that
+    /// the response doesn't announce
+    #[derive(Clone)]
+    struct GrpcLike;
+
+    impl Service<http::Request<ChannelBody>> for GrpcLike {
+        type Response = http::Response<Frames>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _req: http::Request<ChannelBody>) -> Self::Future {
+            let mut trailers = http::HeaderMap::new();
+            trailers.insert("grpc-status", "0".parse().unwrap());
+            let frames = [
+                Frame::data(Bytes::from_static(b"hello")),
+                Frame::trailers(trailers),
+            ];
+            ready(Ok(http::Response::new(Frames(frames.into()))))
+        }
+    }
+
+    #[test]
+    fn test_tower_driver() {
+        loona::buffet::start(async move {
+            let (mut client_write, server_read) = pipe();
+            let (server_write, mut client_read) = pipe();
+            let serve = loona::buffet::spawn(h1::serve(
+                (server_read, server_write),
+                Rc::new(Default::default()),
+                RollMut::alloc().unwrap(),
+                TowerDriver::new(Echo),
+            ));
+
+            client_write
+                .write_all_owned(
+                    "POST / HTTP/1.1\r\nconnection: close\r\ncontent-length: 5\r\n\r\nhello",
+                )
+                .await
+                .unwrap();
+
+            let mut res = Vec::new();
+            loop {
+                let (n, buf) = client_read.read_owned(vec![0u8; 1024]).await;
+                let n = n.unwrap();
+                if n == 0 {
+                    break;
+                }
+                res.extend_from_slice(&buf[..n]);
+            }
+            serve.await.unwrap().unwrap();
+
+            let res = String::from_utf8(res).unwrap();
+            assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{res}");
+            assert!(res.contains("x-method: POST\r\n"), "{res}");
+            assert!(
+                res.contains(&format!("{}: 5\r\n", header::CONTENT_LENGTH)),
+                "{res}"
+            );
+            assert!(res.ends_with("\r\n\r\nhello"), "{res}");
+        });
+    }
+
+    #[test]
+    fn test_tower_driver_unannounced_trailers() {
+        loona::buffet::start(async move {
+            let (mut client_write, server_read) = pipe();
+            let (server_write, mut client_read) = pipe();
+            let serve = loona::buffet::spawn(h1::serve(
+                (server_read, server_write),
+                Rc::new(Default::default()),
+                RollMut::alloc().unwrap(),
+                TowerDriver::new(GrpcLike),
+            ));
+
+            client_write
+                .write_all_owned(
+                    "POST / HTTP/1.1\r\nconnection: close\r\nte: trailers\r\ncontent-length: 0\r\n\r\n",
+                )
+                .await
+                .unwrap();
+
+            let mut res = Vec::new();
+            loop {
+                let (n, buf) = client_read.read_owned(vec![0u8; 1024]).await;
+                let n = n.unwrap();
+                if n == 0 {
+                    break;
+                }
+                res.extend_from_slice(&buf[..n]);
+            }
+            serve.await.unwrap().unwrap();
+
+            let res = String::from_utf8(res).unwrap();
+            assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{res}");
+            assert!(!res.contains("trailer:"), "{res}");
+            assert!(
+                res.ends_with("\r\n\r\n5\r\nhello\r\n0\r\ngrpc-status: 0\r\n\r\n"),
+                "{res}"
+            );
+        });
+    }
+}
@@ -0,0 +1,21 @@
+//! Adapters between loona and [tower](https://docs.rs/tower): run a
+//! `tower::Service` as a loona [ServerDriver](loona::ServerDriver) with
+//! [TowerDriver], or a driver as a service with [DriverService].
+//!
+//! Nothing needs to be `Send`: services run on the thread that serves the
+//! connection, and [DriverService] must be called from within a runtime
+//! created by [buffet::start](loona::buffet::start).
+//!
+//! Bodies are streamed both ways, but data is copied between loona's
+//! [Piece](loona::buffet::Piece)s and [bytes::Bytes] as it goes through.
+
+mod body;
+pub use body::*;
+
+mod conv;
+
+mod driver;
+pub use driver::*;
+
+mod service;
+pub use service::*;
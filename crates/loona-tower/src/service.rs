@@ -0,0 +1,312 @@
+use std::{
+    cell::Cell,
+    fs::File,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+use b_x::BX;
+use bytes::Bytes;
+use loona::{
+    buffet::{read_file_piece, Piece},
+    http::{self, header::InvalidHeaderValue},
+    http_body_compat::FromHttpBody,
+    Encoder, Headers, HeadersExt, Responder, Response, ServerDriver,
+};
+use tokio::sync::oneshot;
+use tower_service::Service;
+
+use crate::{
+    body::{channel_body, ChannelBody, ChannelBodySender},
+    conv::{headers_to_http, request_from_http, response_to_http},
+};
+
+type Head = Rc<Cell<Option<oneshot::Sender<Result<http::Response<ChannelBody>, BX>>>>>;
+
+/// A tower [Service] that runs a loona [ServerDriver] for every request.
+/// The response resolves as soon as the driver has written its final
+/// response headers: its body streams in as the driver writes it.
+///
+/// Each call spawns a task with [buffet::spawn](loona::buffet::spawn), so
+/// this must be called from within a buffet runtime.
+pub struct DriverService<D> {
+    driver: Rc<D>,
+}
+
+impl<D> DriverService<D> {
+    pub fn new(driver: D) -> Self {
+        Self {
+            driver: Rc::new(driver),
+        }
+    }
+}
+
+// not derived: `D` doesn't need to be `Clone`
+impl<D> Clone for DriverService<D> {
+    fn clone(&self) -> Self {
+        Self {
+            driver: self.driver.clone(),
+        }
+    }
+}
+
+impl<D, B> Service<http::Request<B>> for DriverService<D>
+where
+    D: ServerDriver<ChannelEncoder> + 'static,
+    B: http_body::Body + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Response = http::Response<ChannelBody>;
+    type Error = BX;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, BX>>>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), BX>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let (encoder, head_rx) = ChannelEncoder::new();
+        let head = encoder.head.clone();
+
+        let driver = self.driver.clone();
+        loona::buffet::spawn(async move {
+            let (parts, body) = req.into_parts();
+            let mut body = FromHttpBody::new(body);
+            let respond = Responder::new(encoder);
+            if let Err(e) = driver
+                .handle(request_from_http(parts), &mut body, respond)
+                .await
+            {
+                match head.take() {
+                    Some(head) => _ = head.send(Err(BX::from_err(e))),
+                    // the response body will error out, since the encoder
+                    // was dropped before it ended
+                    None => tracing::debug!("driver failed mid-response: {e}"),
+                }
+            }
+        });
+
+        Box::pin(async move {
+            match head_rx.await {
+                Ok(res) => res,
+                Err(_) => Err(BX::from_err(ChannelEncoderError::ResponseDropped)),
+            }
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ChannelEncoderError {
+    /// Whoever called the service isn't waiting for the response, or its
+    /// body, anymore
+    #[error("response was dropped")]
+    ResponseDropped,
+
+    #[error("invalid header value: {0}")]
+    InvalidHeader(#[from] InvalidHeaderValue),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// The [Encoder] that [DriverService] passes to drivers: it turns what they
+/// write into an `http::Response<ChannelBody>`. Interim (1xx) responses have
+/// nowhere to go and are dropped.
+pub struct ChannelEncoder {
+    head: Head,
+    body: Option<ChannelBodySender>,
+    body_ended: bool,
+}
+
+impl ChannelEncoder {
+    pub(crate) fn new() -> (
+        Self,
+        oneshot::Receiver<Result<http::Response<ChannelBody>, BX>>,
+    ) {
+        let (tx, rx) = oneshot::channel();
+        let encoder = Self {
+            head: Rc::new(Cell::new(Some(tx))),
+            body: None,
+            body_ended: false,
+        };
+        (encoder, rx)
+    }
+
+    fn body(&self) -> Result<&ChannelBodySender, ChannelEncoderError> {
+        self.body
+            .as_ref()
+            .ok_or(ChannelEncoderError::ResponseDropped)
+    }
+
+    async fn send(&self, data: Bytes) -> Result<(), ChannelEncoderError> {
+        self.body()?
+            .send_data(data)
+            .await
+            .map_err(|_| ChannelEncoderError::ResponseDropped)
+    }
+}
+
+impl Encoder for ChannelEncoder {
+    type Error = ChannelEncoderError;
+
+    async fn write_response(&mut self, res: Response) -> Result<(), Self::Error> {
+        if res.status.is_informational() {
+            return Ok(());
+        }
+
+        let (tx, body) = channel_body(res.headers.content_length());
+        let res = response_to_http(res, body)?;
+        let head = self
+            .head
+            .take()
+            .ok_or(ChannelEncoderError::ResponseDropped)?;
+        head.send(Ok(res))
+            .map_err(|_| ChannelEncoderError::ResponseDropped)?;
+        self.body = Some(tx);
+        Ok(())
+    }
+
+    async fn write_body_chunk(&mut self, chunk: Piece) -> Result<(), Self::Error> {
+        self.send(Bytes::copy_from_slice(&chunk[..])).await
+    }
+
+    async fn write_body_file(
+        &mut self,
+        file: Rc<File>,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), Self::Error> {
+        let mut written = 0;
+        while written < len {
+            let piece = read_file_piece(&file, offset + written, len - written)?;
+            written += piece.len() as u64;
+            self.send(Bytes::copy_from_slice(&piece[..])).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_body_end(&mut self) -> Result<(), Self::Error> {
+        // trailers may still follow: the body is finished when we're dropped
+        self.body_ended = true;
+        Ok(())
+    }
+
+    async fn write_trailers(&mut self, trailers: Box<Headers>) -> Result<(), Self::Error> {
+        let trailers = headers_to_http(&trailers)?;
+        let body = self
+            .body
+            .take()
+            .ok_or(ChannelEncoderError::ResponseDropped)?;
+        if !trailers.is_empty() {
+            body.send_trailers(trailers)
+                .await
+                .map_err(|_| ChannelEncoderError::ResponseDropped)?;
+        }
+        body.finish()
+            .await
+            .map_err(|_| ChannelEncoderError::ResponseDropped)
+    }
+
+    fn accepts_trailers(&self) -> bool {
+        // http_body bodies end with whatever trailers they like
+        true
+    }
+}
+
+impl Drop for ChannelEncoder {
+    fn drop(&mut self) {
+        // if the body didn't end, dropping the sender aborts it
+        if self.body_ended {
+            if let Some(body) = self.body.take() {
+                loona::buffet::spawn(async move {
+                    _ = body.finish().await;
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body::Body as _;
+    use loona::{
+        http::{self, StatusCode},
+        Body, Encoder, ExpectResponseHeaders, Request, Responder, Response, ResponseDone,
+        ServerDriver, SinglePieceBody,
+    };
+    use tower_service::Service;
+
+    use super::{ChannelEncoder, DriverService};
+    use crate::body::{channel_body, ChannelBody};
+
+    async fn read_body(mut body: ChannelBody) -> Vec<u8> {
+        let mut data = Vec::new();
+        while let Some(frame) =
+            std::future::poll_fn(|cx| std::pin::Pin::new(&mut body).poll_frame(cx)).await
+        {
+            if let Ok(chunk) = frame.unwrap().into_data() {
+                data.extend_from_slice(&chunk[..]);
+            }
+        }
+        data
+    }
+
+    mod channel_encoder_conformance {
+        use super::*;
+
+        loona::encoder_test_suite!(|| {
+            let (encoder, head) = ChannelEncoder::new();
+            loona::buffet::spawn(async move {
+                if let Ok(Ok(res)) = head.await {
+                    read_body(res.into_body()).await;
+                }
+            });
+            encoder
+        });
+    }
+
+    struct Hello;
+
+    impl<E: Encoder> ServerDriver<E> for Hello {
+        type Error = b_x::BX;
+
+        async fn handle(
+            &self,
+            req: Request,
+            req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> Result<Responder<E, ResponseDone>, Self::Error> {
+            while !matches!(
+                req_body.next_chunk().await.map_err(b_x::BX::from_err)?,
+                loona::BodyChunk::Done { .. }
+            ) {}
+
+            let res = Response {
+                status: StatusCode::CREATED,
+                ..Default::default()
+            };
+            let mut body = SinglePieceBody::from(format!("hello from {}", req.uri).into_bytes());
+            respond
+                .write_final_response_with_body(res, &mut body)
+                .await
+                .map_err(b_x::BX::from_err)
+        }
+    }
+
+    #[test]
+    fn test_driver_service() {
+        loona::buffet::start(async move {
+            let mut service = DriverService::new(Hello);
+            let (tx, body) = channel_body(Some(0));
+            tx.finish().await.unwrap();
+            let req = http::Request::builder().uri("/greet").body(body).unwrap();
+            let res = service.call(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::CREATED);
+            assert_eq!(res.headers()[http::header::CONTENT_LENGTH], "17");
+            assert_eq!(read_body(res.into_body()).await, b"hello from /greet");
+        });
+    }
+}
@@ -0,0 +1,104 @@
+//! Writes small responses with the h1 encoder, into a sink that only counts
+//! writes. Besides timing it, this reports how many writes each response
+//! took: the status line, the headers and the first body chunk are supposed
+//! to go out together.
+
+use std::{
+    cell::Cell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use buffet::{bufpool::BufResult, Piece, PieceList, WriteOwned};
+use codspeed_criterion_compat::{criterion_group, criterion_main, Criterion};
+use http::{header, StatusCode};
+use loona::{h1::encode::H1Encoder, Encoder, Response};
+
+#[derive(Clone, Default)]
+struct CountingSink {
+    writes: Rc<Cell<u64>>,
+}
+
+impl WriteOwned for CountingSink {
+    async fn write_owned(&mut self, buf: impl Into<Piece>) -> BufResult<usize, Piece> {
+        let buf = buf.into();
+        self.writes.set(self.writes.get() + 1);
+        (Ok(buf.len()), buf)
+    }
+
+    async fn writev_owned(&mut self, list: &PieceList) -> std::io::Result<usize> {
+        self.writes.set(self.writes.get() + 1);
+        Ok(list.len())
+    }
+
+    async fn shutdown(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn response(content_length: Option<u64>) -> Response {
+    let mut res = Response {
+        status: StatusCode::OK,
+        ..Default::default()
+    };
+    res.headers
+        .insert(header::CONTENT_TYPE, "text/plain".into());
+    if let Some(len) = content_length {
+        res.headers.insert(
+            header::CONTENT_LENGTH,
+            Piece::from_fmt(format_args!("{len}")),
+        );
+    }
+    res
+}
+
+/// Writes `num_responses` responses with a single body chunk, with a
+/// `content-length` or chunked. Returns how long that took, and how many
+/// writes the sink saw.
+fn write_responses(num_responses: u64, content_length: bool) -> (Duration, u64) {
+    buffet::start(async move {
+        let sink = CountingSink::default();
+        let body = "hello world";
+
+        let start = Instant::now();
+        for _ in 0..num_responses {
+            let mut enc = H1Encoder::new(sink.clone());
+            let len = content_length.then_some(body.len() as u64);
+            enc.write_response(response(len)).await.unwrap();
+            enc.write_body_chunk(body.into()).await.unwrap();
+            enc.write_body_end().await.unwrap();
+        }
+        (start.elapsed(), sink.writes.get())
+    })
+}
+
+pub fn h1_responses(c: &mut Criterion) {
+    let mut c = c.benchmark_group("h1_responses");
+
+    let mut report = Vec::new();
+    for (name, content_length) in [("content_length", true), ("chunked", false)] {
+        let mut responses = 0;
+        let mut writes = 0;
+        c.bench_function(format!("h1_responses/{name}"), |b| {
+            b.iter_custom(|iters| {
+                let (elapsed, iter_writes) = write_responses(iters, content_length);
+                responses += iters;
+                writes += iter_writes;
+                elapsed
+            })
+        });
+        report.push((name, responses, writes));
+    }
+
+    c.finish();
+
+    for (name, responses, writes) in report {
+        if responses > 0 {
+            let writes_per_response = writes as f64 / responses as f64;
+            println!("h1_responses/{name}: {writes_per_response:.2} writes per response");
+        }
+    }
+}
+
+criterion_group!(benches, h1_responses);
+criterion_main!(benches);
@@ -0,0 +1,169 @@
+//! Uploads request bodies to an h2 server over in-memory pipes. Besides
+//! timing it, this reports how many bytes the server copied per byte of DATA
+//! it received: payloads are supposed to be handed to the handler as slices
+//! of the connection's read buffer, so that should stay close to zero.
+
+use std::{
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use b_x::{BxForResults, BX};
+use buffet::{IntoHalves, ReadOwned, RollMut, WriteOwned};
+use codspeed_criterion_compat::{criterion_group, criterion_main, Criterion, Throughput};
+use loona::{
+    Body, BodyChunk, Encoder, ExpectResponseHeaders, Responder, Response, ResponseDone,
+    ServerDriver,
+};
+use loona_h2::{HeadersFlags, StreamId, WindowUpdate};
+
+const FRAMES_PER_STREAM: usize = 3;
+const FRAME_SIZE: usize = 16384;
+
+struct DrainDriver;
+
+impl<OurEncoder> ServerDriver<OurEncoder> for DrainDriver
+where
+    OurEncoder: Encoder,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        _req: loona::Request,
+        req_body: &mut impl Body,
+        res: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+        while let BodyChunk::Chunk(chunk) = req_body.next_chunk().await.bx()? {
+            std::hint::black_box(chunk);
+        }
+
+        let res = res
+            .write_final_response(Response::default())
+            .await?
+            .finish_body(None)
+            .await?;
+        Ok(res)
+    }
+}
+
+struct TwoHalves<W, R>(W, R);
+impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+    type Read = R;
+    type Write = W;
+
+    fn into_halves(self) -> (Self::Read, Self::Write) {
+        (self.1, self.0)
+    }
+}
+
+/// Uploads `num_streams` bodies of `FRAMES_PER_STREAM * FRAME_SIZE` bytes,
+/// one after the other. Returns how long that took, and how many bytes were
+/// copied along the way.
+fn upload(num_streams: u64) -> (Duration, u64) {
+    buffet::start(async move {
+        let (server_write, client_read) = buffet::pipe();
+        let (client_write, server_read) = buffet::pipe();
+
+        let serve_fut = buffet::spawn(async move {
+            let conf = Rc::new(loona::h2::ServerConf::default());
+            let client_buf = RollMut::alloc().unwrap();
+            loona::h2::serve(
+                (server_read, server_write),
+                conf,
+                client_buf,
+                Rc::new(DrainDriver),
+            )
+            .await
+            .unwrap();
+        });
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+
+        let mut headers = httpwg::Headers::default();
+        headers.append(":method", "POST");
+        headers.append(":scheme", "http");
+        headers.append(":path", "/");
+        headers.append(":authority", "localhost");
+        let data = vec![b'a'; FRAME_SIZE];
+
+        // we're not reading from the server in the background, so keep track
+        // of the connection window ourselves. stream windows are fresh for
+        // every stream and larger than a body.
+        let mut conn_window = 65535_i64;
+        let copied_before = buffet::num_bytes_copied();
+        let start = Instant::now();
+        for i in 0..num_streams {
+            let stream_id = StreamId(i as u32 * 2 + 1);
+            conn.encode_and_write_headers(stream_id, HeadersFlags::EndHeaders, &headers)
+                .await
+                .unwrap();
+            for j in 0..FRAMES_PER_STREAM {
+                while conn_window < FRAME_SIZE as i64 {
+                    let (frame, payload) = conn
+                        .wait_for_frame(httpwg::FrameT::WindowUpdate)
+                        .await
+                        .unwrap();
+                    if frame.stream_id == StreamId::CONNECTION {
+                        conn_window += WindowUpdate::parse(payload).unwrap().1.increment as i64;
+                    }
+                }
+                conn.write_data(stream_id, j == FRAMES_PER_STREAM - 1, data.clone())
+                    .await
+                    .unwrap();
+                conn_window -= FRAME_SIZE as i64;
+            }
+
+            loop {
+                let (frame, payload) = conn
+                    .wait_for_frame(
+                        httpwg::FrameT::Headers
+                            | httpwg::FrameT::Data
+                            | httpwg::FrameT::WindowUpdate,
+                    )
+                    .await
+                    .unwrap();
+                if frame.stream_id == StreamId::CONNECTION {
+                    conn_window += WindowUpdate::parse(payload).unwrap().1.increment as i64;
+                } else if frame.stream_id == stream_id && frame.is_end_stream() {
+                    break;
+                }
+            }
+        }
+        let elapsed = start.elapsed();
+        let copied = buffet::num_bytes_copied() - copied_before;
+
+        drop(conn);
+        serve_fut.await.unwrap();
+
+        (elapsed, copied)
+    })
+}
+
+pub fn h2_data(c: &mut Criterion) {
+    let mut c = c.benchmark_group("h2_data");
+    c.throughput(Throughput::Bytes((FRAMES_PER_STREAM * FRAME_SIZE) as u64));
+
+    let mut sent = 0;
+    let mut copied = 0;
+    c.bench_function("h2_data/upload", |b| {
+        b.iter_custom(|iters| {
+            let (elapsed, iter_copied) = upload(iters);
+            sent += iters * (FRAMES_PER_STREAM * FRAME_SIZE) as u64;
+            copied += iter_copied;
+            elapsed
+        })
+    });
+
+    c.finish();
+
+    if sent > 0 {
+        let copies_per_byte = copied as f64 / sent as f64;
+        println!("h2_data/upload: {copies_per_byte:.4} copies per byte received");
+    }
+}
+
+criterion_group!(benches, h2_data);
+criterion_main!(benches);
@@ -0,0 +1,90 @@
+//! Builds the header maps of a connection's worth of browser-like requests,
+//! parsing every name with `HeaderName::from_bytes`, or going through a
+//! `HeaderNameInterner` like the servers do. Besides timing it, this reports
+//! how many bytes of name storage each approach keeps alive.
+
+use std::collections::HashSet;
+
+use buffet::Piece;
+use codspeed_criterion_compat::{
+    black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput,
+};
+use http::HeaderName;
+use loona::{HeaderNameInterner, Headers};
+
+const REQUESTS: usize = 100;
+
+/// As they'd come off the wire: h1 clients often capitalize names
+const FIELDS: &[(&[u8], &[u8])] = &[
+    (b"Host", b"example.org"),
+    (
+        b"User-Agent",
+        b"Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0",
+    ),
+    (b"Accept", b"text/html,application/xhtml+xml,*/*;q=0.8"),
+    (b"Accept-Language", b"en-US,en;q=0.5"),
+    (b"Accept-Encoding", b"gzip, deflate, br, zstd"),
+    (b"Referer", b"https://example.org/"),
+    (b"Cookie", b"session=8f2d1c; theme=dark"),
+    (b"Sec-Fetch-Dest", b"document"),
+    (b"Sec-Fetch-Mode", b"navigate"),
+    (b"Sec-Fetch-Site", b"same-origin"),
+    (b"Sec-Fetch-User", b"?1"),
+    (b"Sec-GPC", b"1"),
+    (b"Priority", b"u=0, i"),
+    (b"X-Request-Id", b"c0ffee"),
+    (b"X-Forwarded-For", b"192.0.2.1"),
+    (b"X-Forwarded-Proto", b"https"),
+];
+
+fn build(mut name: impl FnMut(&[u8]) -> HeaderName) -> Vec<Headers> {
+    (0..REQUESTS)
+        .map(|_| {
+            let mut headers = Headers::default();
+            for (key, value) in FIELDS {
+                headers.append(name(key), Piece::from(*value));
+            }
+            headers
+        })
+        .collect()
+}
+
+/// Bytes of name storage, counting shared names once
+fn name_bytes(requests: &[Headers]) -> usize {
+    let mut seen = HashSet::new();
+    requests
+        .iter()
+        .flat_map(|headers| headers.keys())
+        .filter(|name| seen.insert(name.as_str().as_ptr()))
+        .map(|name| name.as_str().len())
+        .sum()
+}
+
+pub fn header_names(c: &mut Criterion) {
+    let mut c = c.benchmark_group("header_names");
+    c.throughput(Throughput::Elements((REQUESTS * FIELDS.len()) as u64));
+
+    c.bench_function("header_names/from_bytes", |b| {
+        b.iter(|| black_box(build(|key| HeaderName::from_bytes(key).unwrap())))
+    });
+
+    c.bench_function("header_names/interned", |b| {
+        b.iter_batched(
+            HeaderNameInterner::default,
+            |mut names| black_box(build(|key| names.intern(key).unwrap())),
+            BatchSize::SmallInput,
+        )
+    });
+
+    c.finish();
+
+    let plain = name_bytes(&build(|key| HeaderName::from_bytes(key).unwrap()));
+    let mut names = HeaderNameInterner::default();
+    let interned = name_bytes(&build(|key| names.intern(key).unwrap()));
+    println!(
+        "header_names: {plain} bytes of names for {REQUESTS} requests with from_bytes, {interned} interned"
+    );
+}
+
+criterion_group!(benches, header_names);
+criterion_main!(benches);
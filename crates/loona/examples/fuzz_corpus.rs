@@ -0,0 +1,28 @@
+//! Writes the seed inputs of every fuzz target to `<dir>/<target>/<seed>`,
+//! `crates/loona/fuzz/corpus` by default, where `cargo fuzz run` picks them
+//! up.
+
+use std::path::PathBuf;
+
+fn main() -> std::io::Result<()> {
+    let dir = std::env::args_os()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus"));
+
+    for target in loona::fuzz::TARGETS {
+        let target_dir = dir.join(target.name);
+        std::fs::create_dir_all(&target_dir)?;
+        let seeds = (target.seeds)();
+        for (name, input) in &seeds {
+            std::fs::write(target_dir.join(name), input)?;
+        }
+        println!(
+            "{}: {} seeds in {}",
+            target.name,
+            seeds.len(),
+            target_dir.display()
+        );
+    }
+    Ok(())
+}
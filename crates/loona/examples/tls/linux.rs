@@ -283,6 +283,11 @@ where
 
                     respond.write_chunk(chunk).await?;
                 }
+                loona::BodyChunk::File { file, offset, len } => {
+                    debug!("Client got file chunk of len {len}");
+
+                    respond.write_file(file, offset, len).await?;
+                }
                 loona::BodyChunk::Done { trailers } => {
                     break trailers;
                 }
@@ -318,6 +323,9 @@ impl h1::ClientDriver for SampleCDriver {
                 loona::BodyChunk::Chunk(chunk) => {
                     debug!("Client got chunk of len {}", chunk.len());
                 }
+                loona::BodyChunk::File { len, .. } => {
+                    debug!("Client got file chunk of len {len}");
+                }
                 loona::BodyChunk::Done { .. } => {
                     break;
                 }
@@ -344,6 +352,9 @@ async fn sample_http_request() -> b_x::Result<()> {
         uri: "http://httpbingo.org/image/jpeg".parse().unwrap(),
         version: Version::HTTP_11,
         headers: Default::default(),
+        conn: None,
+        stream_id: None,
+        protocol: None,
     };
 
     let (transport, _) = h1::request(transport.into_halves(), req, &mut (), driver).await?;
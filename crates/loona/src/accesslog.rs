@@ -0,0 +1,615 @@
+//! Access logs: one entry per response, see [AccessLogDriver]
+//!
+//! The driver hands an [AccessLogEntry] to an [AccessLog] once the response
+//! is done. [LogWriter] is the usual [AccessLog]: it formats entries with a
+//! [LogFormat] ([CommonLogFormat], [JsonLogFormat], or your own) and writes
+//! them from a background thread, so a slow log file never holds up a
+//! response.
+//!
+//! ```ignore
+//! let log = Rc::new(LogWriter::new(CommonLogFormat, std::io::stdout(), 4096));
+//! // for each connection:
+//! let driver = AccessLogDriver::new(MyApp, log.clone(), Some(peer_addr));
+//! h1::serve(io, conf, client_buf, driver).await?;
+//! ```
+
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::Write,
+    net::SocketAddr,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use buffet::Piece;
+use http::{header, StatusCode, Uri, Version};
+
+use crate::{
+    util::{fmt_clf_date, fmt_rfc3339},
+    Body, Encoder, ExpectResponseHeaders, Headers, Method, OnComplete, Request, Responder,
+    Response, ResponseDone, ServerDriver,
+};
+
+/// What happened to a single request
+#[derive(Clone)]
+pub struct AccessLogEntry {
+    /// Who sent the request, if the driver was told
+    pub peer_addr: Option<SocketAddr>,
+
+    pub method: Method,
+
+    pub uri: Uri,
+
+    pub version: Version,
+
+    /// The request's `referer` header
+    pub referer: Option<Piece>,
+
+    /// The request's `user-agent` header
+    pub user_agent: Option<Piece>,
+
+    /// The final response status
+    pub status: StatusCode,
+
+    /// Response body bytes, framing excluded
+    pub bytes_sent: u64,
+
+    /// When the request was handed to the driver
+    pub started_at: SystemTime,
+
+    /// How long it took to respond, body included
+    pub duration: Duration,
+}
+
+impl AccessLogEntry {
+    /// The request target as it would appear in an HTTP/1.1 request line:
+    /// the authority for CONNECT, the path and query otherwise
+    pub fn target(&self) -> &str {
+        if self.method == Method::Connect {
+            if let Some(authority) = self.uri.authority() {
+                return authority.as_str();
+            }
+        }
+        self.uri.path_and_query().map_or("/", |pq| pq.as_str())
+    }
+}
+
+/// Receives an entry for every completed response
+pub trait AccessLog {
+    fn log(&self, entry: &AccessLogEntry);
+}
+
+impl<L> AccessLog for Rc<L>
+where
+    L: AccessLog + ?Sized,
+{
+    fn log(&self, entry: &AccessLogEntry) {
+        (**self).log(entry)
+    }
+}
+
+/// Turns an entry into a line of text
+pub trait LogFormat {
+    /// Appends one line to `out`, newline included
+    fn format(&self, entry: &AccessLogEntry, out: &mut String);
+}
+
+/// The Common Log Format, as written by most web servers, e.g.
+/// `127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] "GET / HTTP/1.1" 200 2326`
+pub struct CommonLogFormat;
+
+impl LogFormat for CommonLogFormat {
+    fn format(&self, entry: &AccessLogEntry, out: &mut String) {
+        match entry.peer_addr {
+            Some(addr) => _ = write!(out, "{}", addr.ip()),
+            None => out.push('-'),
+        }
+        _ = write!(out, " - - [{}] \"", fmt_clf_date(entry.started_at));
+        push_clf_escaped(out, &entry.method.to_string());
+        out.push(' ');
+        push_clf_escaped(out, entry.target());
+        _ = write!(out, " {:?}\" {} ", entry.version, entry.status.as_u16());
+        // the format wants a dash rather than zero
+        match entry.bytes_sent {
+            0 => out.push('-'),
+            n => _ = write!(out, "{n}"),
+        }
+        out.push('\n');
+    }
+}
+
+/// Escapes quotes, backslashes and anything that isn't printable ASCII,
+/// like Apache does
+fn push_clf_escaped(out: &mut String, s: &str) {
+    for b in s.bytes() {
+        match b {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(b as char);
+            }
+            b' '..=b'~' => out.push(b as char),
+            _ => _ = write!(out, "\\x{b:02x}"),
+        }
+    }
+}
+
+/// One JSON object per line, with `time`, `peer`, `method`, `target`,
+/// `version`, `status`, `bytes`, `duration_ms`, `referer` and `user_agent`
+/// fields. Missing values are `null`.
+pub struct JsonLogFormat;
+
+impl LogFormat for JsonLogFormat {
+    fn format(&self, entry: &AccessLogEntry, out: &mut String) {
+        out.push_str("{\"time\":");
+        push_json_str(out, &fmt_rfc3339(entry.started_at));
+        out.push_str(",\"peer\":");
+        match entry.peer_addr {
+            Some(addr) => push_json_str(out, &addr.to_string()),
+            None => out.push_str("null"),
+        }
+        out.push_str(",\"method\":");
+        push_json_str(out, &entry.method.to_string());
+        out.push_str(",\"target\":");
+        push_json_str(out, entry.target());
+        out.push_str(",\"version\":");
+        push_json_str(out, &format!("{:?}", entry.version));
+        _ = write!(
+            out,
+            ",\"status\":{},\"bytes\":{},\"duration_ms\":{:.3}",
+            entry.status.as_u16(),
+            entry.bytes_sent,
+            entry.duration.as_secs_f64() * 1000.0
+        );
+        for (name, value) in [
+            ("referer", &entry.referer),
+            ("user_agent", &entry.user_agent),
+        ] {
+            _ = write!(out, ",\"{name}\":");
+            match value {
+                Some(value) => push_json_str(out, &String::from_utf8_lossy(&value[..])),
+                None => out.push_str("null"),
+            }
+        }
+        out.push_str("}\n");
+    }
+}
+
+fn push_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\u{7f}' => _ = write!(out, "\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Formats entries and writes them from a background thread.
+///
+/// Lines are queued without ever blocking: when the queue is full (because
+/// the output can't keep up), or the output failed, lines are dropped and
+/// counted, see [LogWriter::dropped]. The thread flushes the output whenever
+/// the queue runs empty, and stops once the writer is dropped.
+pub struct LogWriter<F> {
+    format: F,
+    tx: mpsc::SyncSender<String>,
+    dropped: AtomicU64,
+}
+
+impl<F> LogWriter<F>
+where
+    F: LogFormat,
+{
+    /// `capacity` is how many lines may be waiting to be written
+    pub fn new(format: F, out: impl Write + Send + 'static, capacity: usize) -> Self {
+        let (tx, rx) = mpsc::sync_channel::<String>(capacity);
+
+        std::thread::Builder::new()
+            .name("loona-accesslog".into())
+            .spawn(move || {
+                let mut out = std::io::BufWriter::new(out);
+                while let Ok(line) = rx.recv() {
+                    let mut res = out.write_all(line.as_bytes());
+                    while let (Ok(()), Ok(line)) = (&res, rx.try_recv()) {
+                        res = out.write_all(line.as_bytes());
+                    }
+                    if let Err(e) = res.and_then(|_| out.flush()) {
+                        tracing::warn!("access log writer stopping: {e}");
+                        return;
+                    }
+                }
+            })
+            .expect("failed to spawn access log thread");
+
+        Self {
+            format,
+            tx,
+            dropped: Default::default(),
+        }
+    }
+
+    /// How many lines were dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<F> AccessLog for LogWriter<F>
+where
+    F: LogFormat,
+{
+    fn log(&self, entry: &AccessLogEntry) {
+        let mut line = String::with_capacity(256);
+        self.format.format(entry, &mut line);
+        if self.tx.try_send(line).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// An [Encoder] that remembers the final status and counts body bytes, for
+/// [AccessLogDriver]
+pub struct AccessLogEncoder<E> {
+    inner: E,
+    status: StatusCode,
+    bytes_sent: u64,
+}
+
+impl<E> AccessLogEncoder<E>
+where
+    E: Encoder,
+{
+    pub fn new(inner: E) -> Self {
+        Self {
+            inner,
+            status: StatusCode::OK,
+            bytes_sent: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E> Encoder for AccessLogEncoder<E>
+where
+    E: Encoder,
+{
+    type Error = E::Error;
+
+    async fn write_response(&mut self, res: Response) -> Result<(), Self::Error> {
+        if !res.status.is_informational() {
+            self.status = res.status;
+        }
+        self.inner.write_response(res).await
+    }
+
+    async fn write_body_chunk(&mut self, chunk: Piece) -> Result<(), Self::Error> {
+        self.bytes_sent += chunk.len() as u64;
+        self.inner.write_body_chunk(chunk).await
+    }
+
+    async fn write_body_file(
+        &mut self,
+        file: Rc<File>,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), Self::Error> {
+        self.bytes_sent += len;
+        self.inner.write_body_file(file, offset, len).await
+    }
+
+    async fn write_body_end(&mut self) -> Result<(), Self::Error> {
+        self.inner.write_body_end().await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush().await
+    }
+
+    async fn write_trailers(&mut self, trailers: Box<Headers>) -> Result<(), Self::Error> {
+        self.inner.write_trailers(trailers).await
+    }
+    fn on_complete(&mut self, callback: OnComplete) {
+        self.inner.on_complete(callback)
+    }
+
+    fn is_head_response(&self) -> bool {
+        self.inner.is_head_response()
+    }
+
+    fn accepts_trailers(&self) -> bool {
+        self.inner.accepts_trailers()
+    }
+
+    fn close_delimited(&mut self) -> bool {
+        self.inner.close_delimited()
+    }
+}
+
+/// A [ServerDriver] that logs every response of the driver it wraps.
+/// Requests the wrapped driver errors out on aren't logged: there was no
+/// complete response.
+pub struct AccessLogDriver<D, L> {
+    inner: D,
+    log: L,
+    peer_addr: Option<SocketAddr>,
+}
+
+impl<D, L> AccessLogDriver<D, L> {
+    /// Drivers don't know who they're talking to: pass `peer_addr` to have
+    /// it in the log
+    pub fn new(inner: D, log: L, peer_addr: Option<SocketAddr>) -> Self {
+        Self {
+            inner,
+            log,
+            peer_addr,
+        }
+    }
+}
+
+impl<E, D, L> ServerDriver<E> for AccessLogDriver<D, L>
+where
+    E: Encoder,
+    D: ServerDriver<AccessLogEncoder<E>>,
+    L: AccessLog,
+{
+    type Error = D::Error;
+
+    async fn handle(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> Result<Responder<E, ResponseDone>, Self::Error> {
+        let started_at = SystemTime::now();
+        let start = Instant::now();
+        let method = req.method.clone();
+        let uri = req.uri.clone();
+        let version = req.version;
+        let referer = req.headers.get(header::REFERER).cloned();
+        let user_agent = req.headers.get(header::USER_AGENT).cloned();
+
+        let respond = Responder::new(AccessLogEncoder::new(respond.into_encoder()));
+        let encoder = self
+            .inner
+            .handle(req, req_body, respond)
+            .await?
+            .into_inner();
+
+        self.log.log(&AccessLogEntry {
+            peer_addr: self.peer_addr,
+            method,
+            uri,
+            version,
+            referer,
+            user_agent,
+            status: encoder.status,
+            bytes_sent: encoder.bytes_sent,
+            started_at,
+            duration: start.elapsed(),
+        });
+        Ok(Responder::done(encoder.into_inner()))
+    }
+
+    fn origins(&self) -> Option<Vec<String>> {
+        self.inner.origins()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        fs::File,
+        io::Write,
+        rc::Rc,
+        sync::{Arc, Mutex},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use b_x::BX;
+    use buffet::Piece;
+    use http::{header, StatusCode, Version};
+
+    use super::{
+        AccessLog, AccessLogDriver, AccessLogEntry, CommonLogFormat, JsonLogFormat, LogFormat,
+        LogWriter,
+    };
+    use crate::{
+        Body, Encoder, ExpectResponseHeaders, Headers, Method, Request, Responder, Response,
+        ResponseDone, ServerDriver, SinglePieceBody,
+    };
+
+    fn entry() -> AccessLogEntry {
+        AccessLogEntry {
+            peer_addr: Some("127.0.0.1:51234".parse().unwrap()),
+            method: Method::Get,
+            uri: "/search?q=%22hi%22".parse().unwrap(),
+            version: Version::HTTP_11,
+            referer: None,
+            user_agent: Some("curl/8.0 \"quoted\"".into()),
+            status: StatusCode::OK,
+            bytes_sent: 2326,
+            started_at: UNIX_EPOCH + Duration::from_secs(784111777),
+            duration: Duration::from_micros(1500),
+        }
+    }
+
+    fn format(format: impl LogFormat, entry: &AccessLogEntry) -> String {
+        let mut out = String::new();
+        format.format(entry, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_common_log_format() {
+        assert_eq!(
+            format(CommonLogFormat, &entry()),
+            "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"GET /search?q=%22hi%22 HTTP/1.1\" 200 2326\n"
+        );
+
+        let mut e = entry();
+        e.peer_addr = None;
+        e.method = Method::Connect;
+        e.uri = "example.org:443".parse().unwrap();
+        e.bytes_sent = 0;
+        assert_eq!(
+            format(CommonLogFormat, &e),
+            "- - - [06/Nov/1994:08:49:37 +0000] \"CONNECT example.org:443 HTTP/1.1\" 200 -\n"
+        );
+    }
+
+    #[test]
+    fn test_json_log_format() {
+        assert_eq!(
+            format(JsonLogFormat, &entry()),
+            concat!(
+                r#"{"time":"1994-11-06T08:49:37.000Z","peer":"127.0.0.1:51234","method":"GET","#,
+                r#""target":"/search?q=%22hi%22","version":"HTTP/1.1","status":200,"bytes":2326,"#,
+                r#""duration_ms":1.500,"referer":null,"user_agent":"curl/8.0 \"quoted\""}"#,
+                "\n"
+            )
+        );
+    }
+
+    /// Output that can be looked at while the writer thread owns it
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_log_writer() {
+        let output = SharedOutput::default();
+        let writer = LogWriter::new(CommonLogFormat, output.clone(), 16);
+        writer.log(&entry());
+        writer.log(&entry());
+        assert_eq!(writer.dropped(), 0);
+        drop(writer);
+
+        let expected = format(CommonLogFormat, &entry()).repeat(2);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while output.0.lock().unwrap().len() < expected.len() {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "log lines never made it"
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(&output.0.lock().unwrap()[..], expected.as_bytes());
+    }
+
+    #[derive(Clone, Copy)]
+    struct NullEncoder;
+
+    impl Encoder for NullEncoder {
+        type Error = BX;
+
+        async fn write_response(&mut self, _: Response) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_body_chunk(&mut self, _: Piece) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_body_file(
+            &mut self,
+            _: Rc<File>,
+            _: u64,
+            _: u64,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_body_end(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_trailers(&mut self, _: Box<Headers>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct Entries(RefCell<Vec<AccessLogEntry>>);
+
+    impl AccessLog for Entries {
+        fn log(&self, entry: &AccessLogEntry) {
+            self.0.borrow_mut().push(entry.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_driver() {
+        struct NotFound;
+
+        impl<E: Encoder> ServerDriver<E> for NotFound {
+            type Error = BX;
+
+            async fn handle(
+                &self,
+                _req: Request,
+                _req_body: &mut impl Body,
+                mut respond: Responder<E, ExpectResponseHeaders>,
+            ) -> Result<Responder<E, ResponseDone>, Self::Error> {
+                respond
+                    .write_interim_response(Response {
+                        status: StatusCode::CONTINUE,
+                        ..Default::default()
+                    })
+                    .await?;
+                let res = Response {
+                    status: StatusCode::NOT_FOUND,
+                    ..Default::default()
+                };
+                respond
+                    .write_final_response_with_body(res, &mut SinglePieceBody::from("not found"))
+                    .await
+                    .map_err(BX::from_err)
+            }
+        }
+
+        let entries = Rc::new(Entries::default());
+        let peer_addr = "[::1]:4000".parse().unwrap();
+        let driver = AccessLogDriver::new(NotFound, entries.clone(), Some(peer_addr));
+
+        let mut req = Request {
+            uri: "/missing".parse().unwrap(),
+            ..Default::default()
+        };
+        req.headers
+            .insert(header::REFERER, "https://example.org/".into());
+        driver
+            .handle(req, &mut (), Responder::new(NullEncoder))
+            .await
+            .unwrap();
+
+        let entries = entries.0.borrow();
+        assert_eq!(entries.len(), 1);
+        let e = &entries[0];
+        assert_eq!(e.peer_addr, Some(peer_addr));
+        assert_eq!(e.target(), "/missing");
+        assert_eq!(e.status, StatusCode::NOT_FOUND);
+        assert_eq!(e.bytes_sent, 9);
+        assert_eq!(e.referer.as_deref(), Some(&b"https://example.org/"[..]));
+        assert!(e.user_agent.is_none());
+    }
+}
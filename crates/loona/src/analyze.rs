@@ -0,0 +1,1016 @@
+//! Passive analysis of captured HTTP/1.1 and HTTP/2 connections.
+//!
+//! Give [analyze] what each side of a connection sent, as timestamped
+//! segments (e.g. the TCP payloads of a pcap, extracted with your tool of
+//! choice), and it runs them through loona's own parsers. The [Report] lists
+//! the exchanges it found, when each of their phases happened, and every
+//! protocol violation along the way, with the offset it happened at.
+//!
+//! Nothing is fixed up or retried: where the server would have rejected
+//! something, the analyzer records a violation, and stops reading that side
+//! if it can't tell where the next message starts.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    time::Duration,
+};
+
+use buffet::{Piece, Roll, RollMut};
+use http::{header, StatusCode, Version};
+use loona_h2::{
+    ContinuationFlags, DataFlags, Frame, FrameType, HeadersFlags, KnownErrorCode, Origin, StreamId,
+    PREFACE,
+};
+
+use crate::{h1::parse, Headers, Method};
+
+/// We want to see every header, not enforce a server's limits
+const MAX_HEADER_COUNT: usize = 1024;
+
+/// cf. <https://httpwg.org/specs/rfc9113.html#SettingValues>
+const DEFAULT_MAX_FRAME_SIZE: u32 = 16384;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+impl Direction {
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn peer(self) -> Self {
+        match self {
+            Direction::ClientToServer => Direction::ServerToClient,
+            Direction::ServerToClient => Direction::ClientToServer,
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::ClientToServer => f.write_str("client->server"),
+            Direction::ServerToClient => f.write_str("server->client"),
+        }
+    }
+}
+
+/// Bytes one side sent, and when they were captured (relative to whatever
+/// the capture started at)
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub direction: Direction,
+    pub at: Duration,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Anything that doesn't start with the HTTP/2 connection preface
+    Http1,
+    Http2,
+}
+
+/// A request, and its response if the capture has it. Times are those of
+/// the segment that completed each phase.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Exchange {
+    /// HTTP/2 only
+    pub stream_id: Option<u32>,
+    pub method: Option<Method>,
+    pub path: Option<String>,
+    /// The final status, informational responses are skipped
+    pub status: Option<StatusCode>,
+    /// When the request header section was complete
+    pub request_at: Option<Duration>,
+    /// When the final response header section was complete
+    pub response_at: Option<Duration>,
+    /// When the response body was complete
+    pub done_at: Option<Duration>,
+    /// HTTP/2 only: the stream was reset
+    pub reset: bool,
+}
+
+impl Exchange {
+    /// How long the server took to answer, once it had the request headers
+    pub fn time_to_response(&self) -> Option<Duration> {
+        self.response_at?.checked_sub(self.request_at?)
+    }
+}
+
+/// Something a side did that the spec doesn't allow
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub direction: Direction,
+    /// Offset in the bytes `direction` sent
+    pub offset: u64,
+    pub at: Option<Duration>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub protocol: Protocol,
+    pub exchanges: Vec<Exchange>,
+    pub violations: Vec<Violation>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let protocol = match self.protocol {
+            Protocol::Http1 => "HTTP/1.1",
+            Protocol::Http2 => "HTTP/2",
+        };
+        writeln!(f, "{protocol}, {} exchanges", self.exchanges.len())?;
+        for ex in &self.exchanges {
+            f.write_str("  ")?;
+            if let Some(stream_id) = ex.stream_id {
+                write!(f, "stream {stream_id}: ")?;
+            }
+            match &ex.method {
+                Some(method) => write!(f, "{method} ")?,
+                None => f.write_str("? ")?,
+            }
+            f.write_str(ex.path.as_deref().unwrap_or("?"))?;
+            match ex.status {
+                Some(status) => write!(f, " -> {}", status.as_u16())?,
+                None => f.write_str(" -> no response")?,
+            }
+            if let Some(ttr) = ex.time_to_response() {
+                write!(f, ", answered after {ttr:?}")?;
+            }
+            if let (Some(start), Some(done)) = (ex.request_at, ex.done_at) {
+                write!(f, ", done after {:?}", done.saturating_sub(start))?;
+            }
+            if ex.reset {
+                f.write_str(", reset")?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "{} violations", self.violations.len())?;
+        for v in &self.violations {
+            write!(f, "  {} at byte {}", v.direction, v.offset)?;
+            if let Some(at) = v.at {
+                write!(f, " ({at:?})")?;
+            }
+            writeln!(f, ": {}", v.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Analyzes one connection. Segments of each direction must be in the order
+/// they were sent.
+pub fn analyze(
+    segments: impl IntoIterator<Item = Segment>,
+) -> Result<Report, buffet::bufpool::Error> {
+    let segments: Vec<Segment> = segments.into_iter().collect();
+    let client = Capture::new(&segments, Direction::ClientToServer)?;
+    let server = Capture::new(&segments, Direction::ServerToClient)?;
+
+    let mut report = Report {
+        protocol: if client.data.starts_with(PREFACE) {
+            Protocol::Http2
+        } else {
+            Protocol::Http1
+        },
+        exchanges: vec![],
+        violations: vec![],
+    };
+    match report.protocol {
+        Protocol::Http1 => analyze_h1(&client, &server, &mut report),
+        Protocol::Http2 => analyze_h2(&client, &server, &mut report),
+    }
+    Ok(report)
+}
+
+/// Everything one side sent
+struct Capture {
+    direction: Direction,
+    data: Roll,
+    /// Where each segment ended, and when it was captured
+    marks: Vec<(u64, Duration)>,
+}
+
+impl Capture {
+    fn new(segments: &[Segment], direction: Direction) -> Result<Self, buffet::bufpool::Error> {
+        let mut buf = RollMut::alloc()?;
+        let mut marks = vec![];
+        for segment in segments.iter().filter(|s| s.direction == direction) {
+            if segment.data.is_empty() {
+                continue;
+            }
+            buf.reserve_at_least(segment.data.len())?;
+            buf.put(&segment.data)?;
+            marks.push((buf.len() as u64, segment.at));
+        }
+        Ok(Self {
+            direction,
+            data: buf.filled(),
+            marks,
+        })
+    }
+
+    /// Where `rest` starts
+    fn offset(&self, rest: &Roll) -> u64 {
+        (self.data.len() - rest.len()) as u64
+    }
+
+    /// When the byte just before `end` was captured
+    fn at(&self, end: u64) -> Option<Duration> {
+        self.marks
+            .iter()
+            .find(|(mark, _)| *mark >= end.max(1))
+            .map(|(_, at)| *at)
+    }
+
+    fn violation(&self, report: &mut Report, offset: u64, message: impl Into<String>) {
+        report.violations.push(Violation {
+            direction: self.direction,
+            offset,
+            at: self.at(offset + 1),
+            message: message.into(),
+        });
+    }
+}
+
+/// How an HTTP/1.1 message body is delimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    Length(u64),
+    Chunked,
+    /// Neither: requests have no body, responses end with the connection
+    Unspecified,
+}
+
+/// cf. <https://httpwg.org/specs/rfc9112.html#message.body.length>
+fn framing(headers: &Headers) -> Result<Framing, &'static str> {
+    let te = headers.get_all(header::TRANSFER_ENCODING);
+    let has_te = te.iter().next().is_some();
+    let lengths: Vec<&Piece> = headers.get_all(header::CONTENT_LENGTH).iter().collect();
+
+    if has_te && !lengths.is_empty() {
+        return Err("both transfer-encoding and content-length are set");
+    }
+    if has_te {
+        let last_coding = te
+            .iter()
+            .flat_map(|value| value.split(|&b| b == b','))
+            .map(|coding| coding.trim_ascii())
+            .rfind(|coding| !coding.is_empty());
+        return Ok(match last_coding {
+            Some(coding) if coding.eq_ignore_ascii_case(b"chunked") => Framing::Chunked,
+            _ => Framing::Unspecified,
+        });
+    }
+
+    let Some(first) = lengths.first() else {
+        return Ok(Framing::Unspecified);
+    };
+    if lengths.iter().any(|l| l != first) {
+        return Err("conflicting content-length values");
+    }
+    std::str::from_utf8(first)
+        .ok()
+        .filter(|s| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|s| s.parse().ok())
+        .map(Framing::Length)
+        .ok_or("invalid content-length")
+}
+
+/// Skips a body framed with `framing` (which can't be
+/// [Framing::Unspecified])
+fn skip_body(rest: Roll, framing: Framing) -> Result<Roll, &'static str> {
+    match framing {
+        Framing::Length(len) => {
+            if (rest.len() as u64) < len {
+                return Err("capture ends in the middle of a body");
+            }
+            Ok(rest.split_at(len as usize).1)
+        }
+        Framing::Chunked => {
+            let mut rest = rest;
+            loop {
+                let size;
+                (rest, size) = parse::chunk_size(rest).map_err(|e| {
+                    if e.is_incomplete() {
+                        "capture ends in the middle of a chunked body"
+                    } else {
+                        "malformed chunk size"
+                    }
+                })?;
+                if size == 0 {
+                    // trailers, if any, then a final CRLF
+                    let (rest, _) =
+                        parse::headers_and_crlf(MAX_HEADER_COUNT)(rest).map_err(|e| {
+                            if e.is_incomplete() {
+                                "capture ends in the middle of trailers"
+                            } else {
+                                "malformed trailers"
+                            }
+                        })?;
+                    return Ok(rest);
+                }
+                if (rest.len() as u64) < size + 2 {
+                    return Err("capture ends in the middle of a chunk");
+                }
+                let (_, after) = rest.split_at(size as usize);
+                if !after.starts_with(b"\r\n") {
+                    return Err("chunk data isn't followed by CRLF");
+                }
+                rest = after.split_at(2).1;
+            }
+        }
+        Framing::Unspecified => unreachable!("unspecified framing has no end to skip to"),
+    }
+}
+
+fn analyze_h1(client: &Capture, server: &Capture, report: &mut Report) {
+    // exchanges waiting for a response, oldest first
+    let mut pending = VecDeque::new();
+
+    let mut rest = client.data.clone();
+    while !rest.is_empty() {
+        let start = client.offset(&rest);
+        let req;
+        (rest, req) = match parse::request(MAX_HEADER_COUNT)(rest.clone()) {
+            Ok(t) => t,
+            Err(e) if e.is_incomplete() => {
+                client.violation(
+                    report,
+                    start,
+                    "capture ends in the middle of a request header section",
+                );
+                break;
+            }
+            Err(_) => {
+                client.violation(report, start, "malformed request header section");
+                break;
+            }
+        };
+        let head_end = client.offset(&rest);
+
+        if req.version == Version::HTTP_11 && !req.headers.contains_key(header::HOST) {
+            client.violation(report, start, "HTTP/1.1 request without a host header");
+        }
+        let framing = framing(&req.headers);
+        let connect = req.method == Method::Connect;
+        pending.push_back(report.exchanges.len());
+        report.exchanges.push(Exchange {
+            method: Some(req.method),
+            path: Some(req.uri.to_string()),
+            request_at: client.at(head_end),
+            ..Default::default()
+        });
+
+        if connect {
+            // if it's accepted, the rest is tunnel bytes
+            break;
+        }
+        let framing = match framing {
+            Ok(Framing::Unspecified) if req.headers.contains_key(header::TRANSFER_ENCODING) => {
+                client.violation(
+                    report,
+                    start,
+                    "request transfer-encoding doesn't end with chunked",
+                );
+                break;
+            }
+            Ok(Framing::Unspecified) => continue,
+            Ok(framing) => framing,
+            Err(message) => {
+                client.violation(report, start, message);
+                break;
+            }
+        };
+        match skip_body(rest.clone(), framing) {
+            Ok(after) => rest = after,
+            Err(message) => {
+                client.violation(report, head_end, message);
+                break;
+            }
+        }
+    }
+
+    let mut rest = server.data.clone();
+    while !rest.is_empty() {
+        let start = server.offset(&rest);
+        let res;
+        (rest, res) = match parse::response(rest.clone()) {
+            Ok(t) => t,
+            Err(e) if e.is_incomplete() => {
+                server.violation(
+                    report,
+                    start,
+                    "capture ends in the middle of a response header section",
+                );
+                break;
+            }
+            Err(_) => {
+                server.violation(report, start, "malformed response header section");
+                break;
+            }
+        };
+        let head_end = server.offset(&rest);
+
+        let Some(&index) = pending.front() else {
+            server.violation(report, start, "response without a request");
+            break;
+        };
+        if res.status == StatusCode::SWITCHING_PROTOCOLS {
+            // whatever follows isn't HTTP/1.1
+            break;
+        }
+        if res.status.is_informational() {
+            continue;
+        }
+        pending.pop_front();
+
+        let exchange = &mut report.exchanges[index];
+        exchange.status = Some(res.status);
+        exchange.response_at = server.at(head_end);
+        let method = exchange.method.clone();
+
+        if method == Some(Method::Connect) && res.status.is_success() {
+            break;
+        }
+        let framing = match framing(&res.headers) {
+            Ok(framing) => framing,
+            Err(message) => {
+                server.violation(report, start, message);
+                break;
+            }
+        };
+        if method == Some(Method::Head) || res.means_empty_body() {
+            if res.means_empty_body() && matches!(framing, Framing::Chunked) {
+                server.violation(
+                    report,
+                    start,
+                    format!("{} response with transfer-encoding", res.status.as_u16()),
+                );
+            }
+            report.exchanges[index].done_at = server.at(head_end);
+            continue;
+        }
+        if framing == Framing::Unspecified {
+            // close-delimited
+            report.exchanges[index].done_at = server.at(server.data.len() as u64);
+            break;
+        }
+        match skip_body(rest.clone(), framing) {
+            Ok(after) => {
+                rest = after;
+                report.exchanges[index].done_at = server.at(server.offset(&rest));
+            }
+            Err(message) => {
+                server.violation(report, head_end, message);
+                break;
+            }
+        }
+    }
+}
+
+/// A frame, with its payload and where it was in the capture
+struct CapturedFrame {
+    direction: Direction,
+    frame: Frame,
+    payload: Roll,
+    offset: u64,
+    at: Option<Duration>,
+}
+
+fn read_frames(capture: &Capture, mut rest: Roll, report: &mut Report) -> Vec<CapturedFrame> {
+    let mut frames = vec![];
+    while !rest.is_empty() {
+        let offset = capture.offset(&rest);
+        let frame;
+        (rest, frame) = match Frame::parse(rest.clone()) {
+            Ok(t) => t,
+            Err(_) => {
+                // frame headers can't be malformed, only cut short
+                capture.violation(
+                    report,
+                    offset,
+                    "capture ends in the middle of a frame header",
+                );
+                break;
+            }
+        };
+        if rest.len() < frame.len as usize {
+            capture.violation(
+                report,
+                offset,
+                "capture ends in the middle of a frame payload",
+            );
+            break;
+        }
+        let payload;
+        (payload, rest) = rest.split_at(frame.len as usize);
+        frames.push(CapturedFrame {
+            direction: capture.direction,
+            frame,
+            payload,
+            offset,
+            at: capture.at(capture.offset(&rest)),
+        });
+    }
+    frames
+}
+
+/// A header block that's waiting for CONTINUATION frames
+struct PendingBlock {
+    stream_id: StreamId,
+    fragment: Vec<u8>,
+    end_stream: bool,
+}
+
+struct H2Analysis<'a> {
+    report: &'a mut Report,
+    /// Indexed by [Direction::index]
+    decoders: [loona_hpack::Decoder<'static>; 2],
+    /// The largest frame each direction may send, as set by its peer
+    max_frame_size: [u32; 2],
+    pending_block: [Option<PendingBlock>; 2],
+    seen_settings: [bool; 2],
+    last_client_stream: u32,
+    exchanges: BTreeMap<u32, usize>,
+}
+
+fn analyze_h2(client: &Capture, server: &Capture, report: &mut Report) {
+    let after_preface = client.data.clone().split_at(PREFACE.len()).1;
+    let mut frames = read_frames(client, after_preface, report);
+    frames.extend(read_frames(server, server.data.clone(), report));
+    // each side's frames are in order already: this interleaves them, client
+    // first when they were captured at the same time
+    frames.sort_by_key(|f| f.at.unwrap_or(Duration::MAX));
+
+    let mut analysis = H2Analysis {
+        report,
+        decoders: Default::default(),
+        max_frame_size: [DEFAULT_MAX_FRAME_SIZE; 2],
+        pending_block: Default::default(),
+        seen_settings: [false; 2],
+        last_client_stream: 0,
+        exchanges: Default::default(),
+    };
+    for frame in frames {
+        analysis.on_frame(frame);
+    }
+}
+
+impl H2Analysis<'_> {
+    fn violation(&mut self, f: &CapturedFrame, message: impl Into<String>) {
+        self.report.violations.push(Violation {
+            direction: f.direction,
+            offset: f.offset,
+            at: f.at,
+            message: message.into(),
+        });
+    }
+
+    fn on_frame(&mut self, f: CapturedFrame) {
+        let dir = f.direction.index();
+        let stream_id = f.frame.stream_id;
+        let len = f.frame.len;
+
+        if !std::mem::replace(&mut self.seen_settings[dir], true)
+            && !matches!(f.frame.frame_type, FrameType::Settings(_))
+        {
+            self.violation(&f, "first frame isn't SETTINGS");
+        }
+        if len > self.max_frame_size[dir] {
+            let max = self.max_frame_size[dir];
+            self.violation(
+                &f,
+                format!("{len}-byte frame, over the peer's maximum of {max}"),
+            );
+        }
+        if let Some(block) = &self.pending_block[dir] {
+            let continues = matches!(f.frame.frame_type, FrameType::Continuation(_))
+                && stream_id == block.stream_id;
+            if !continues {
+                let expected = block.stream_id;
+                self.violation(&f, format!("expected CONTINUATION on stream {expected}"));
+                self.pending_block[dir] = None;
+            }
+        }
+
+        let on_connection = stream_id == StreamId::CONNECTION;
+        match f.frame.frame_type {
+            FrameType::Data(flags) => {
+                if on_connection {
+                    self.violation(&f, "DATA on stream 0");
+                } else if f.direction == Direction::ServerToClient
+                    && flags.contains(DataFlags::EndStream)
+                {
+                    self.on_response_end(stream_id, f.at);
+                }
+            }
+            FrameType::Headers(flags) => {
+                if on_connection {
+                    self.violation(&f, "HEADERS on stream 0");
+                    return;
+                }
+                let mut fragment = &f.payload[..];
+                if flags.contains(HeadersFlags::Padded) {
+                    let pad_len = fragment.first().copied().unwrap_or_default() as usize;
+                    if fragment.is_empty() || pad_len >= fragment.len() {
+                        self.violation(&f, "padding is as long as the frame");
+                        return;
+                    }
+                    fragment = &fragment[1..fragment.len() - pad_len];
+                }
+                if flags.contains(HeadersFlags::Priority) {
+                    if fragment.len() < 5 {
+                        self.violation(&f, "HEADERS too short for its priority fields");
+                        return;
+                    }
+                    fragment = &fragment[5..];
+                }
+                let block = PendingBlock {
+                    stream_id,
+                    fragment: fragment.to_vec(),
+                    end_stream: flags.contains(HeadersFlags::EndStream),
+                };
+                if flags.contains(HeadersFlags::EndHeaders) {
+                    self.on_header_block(&f, block);
+                } else {
+                    self.pending_block[dir] = Some(block);
+                }
+            }
+            FrameType::Continuation(flags) => {
+                let Some(mut block) = self.pending_block[dir].take() else {
+                    self.violation(&f, "CONTINUATION without HEADERS");
+                    return;
+                };
+                block.fragment.extend_from_slice(&f.payload[..]);
+                if flags.contains(ContinuationFlags::EndHeaders) {
+                    self.on_header_block(&f, block);
+                } else {
+                    self.pending_block[dir] = Some(block);
+                }
+            }
+            FrameType::Settings(_) => {
+                if !on_connection {
+                    self.violation(&f, "SETTINGS on a stream");
+                } else if f.frame.is_ack() && len != 0 {
+                    self.violation(&f, "SETTINGS ack with a payload");
+                } else if len % 6 != 0 {
+                    self.violation(&f, "SETTINGS payload isn't a multiple of 6 bytes");
+                } else {
+                    for setting in f.payload.chunks(6) {
+                        let id = u16::from_be_bytes([setting[0], setting[1]]);
+                        let value =
+                            u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                        if id != SETTINGS_MAX_FRAME_SIZE {
+                            continue;
+                        }
+                        if !(DEFAULT_MAX_FRAME_SIZE..=(1 << 24) - 1).contains(&value) {
+                            self.violation(&f, format!("SETTINGS_MAX_FRAME_SIZE of {value}"));
+                        } else {
+                            // this limits what the peer sends
+                            self.max_frame_size[f.direction.peer().index()] = value;
+                        }
+                    }
+                }
+            }
+            FrameType::Ping(_) => {
+                if !on_connection {
+                    self.violation(&f, "PING on a stream");
+                } else if len != 8 {
+                    self.violation(&f, "PING payload isn't 8 bytes");
+                }
+            }
+            FrameType::GoAway => {
+                if !on_connection {
+                    self.violation(&f, "GOAWAY on a stream");
+                } else if len < 8 {
+                    self.violation(&f, "GOAWAY payload is too short");
+                } else {
+                    let p = &f.payload[..];
+                    let code = u32::from_be_bytes([p[4], p[5], p[6], p[7]]);
+                    if code != KnownErrorCode::NoError.repr() {
+                        let name = KnownErrorCode::from_repr(code)
+                            .map_or_else(|| format!("{code:#x}"), |c| format!("{c:?}"));
+                        self.violation(&f, format!("GOAWAY with error {name}"));
+                    }
+                }
+            }
+            FrameType::RstStream => {
+                if on_connection {
+                    self.violation(&f, "RST_STREAM on stream 0");
+                } else if len != 4 {
+                    self.violation(&f, "RST_STREAM payload isn't 4 bytes");
+                } else if let Some(&index) = self.exchanges.get(&stream_id.0) {
+                    self.report.exchanges[index].reset = true;
+                }
+            }
+            FrameType::WindowUpdate => {
+                if len != 4 {
+                    self.violation(&f, "WINDOW_UPDATE payload isn't 4 bytes");
+                } else if u32::from_be_bytes([
+                    f.payload[0],
+                    f.payload[1],
+                    f.payload[2],
+                    f.payload[3],
+                ]) & 0x7fff_ffff
+                    == 0
+                {
+                    self.violation(&f, "WINDOW_UPDATE with an increment of 0");
+                }
+            }
+            FrameType::PushPromise => {
+                if f.direction == Direction::ClientToServer {
+                    self.violation(&f, "PUSH_PROMISE from the client");
+                }
+            }
+            FrameType::Priority => {
+                if len != 5 {
+                    self.violation(&f, "PRIORITY payload isn't 5 bytes");
+                }
+            }
+            FrameType::PriorityUpdate => {
+                if !on_connection {
+                    self.violation(&f, "PRIORITY_UPDATE on a stream");
+                } else if f.direction == Direction::ServerToClient {
+                    self.violation(&f, "PRIORITY_UPDATE from the server");
+                } else if len < 4 {
+                    self.violation(&f, "PRIORITY_UPDATE payload is too short");
+                }
+            }
+            FrameType::Origin => {
+                if !on_connection {
+                    self.violation(&f, "ORIGIN on a stream");
+                } else if f.direction == Direction::ClientToServer {
+                    self.violation(&f, "ORIGIN from the client");
+                } else if Origin::parse(f.payload.clone()).is_err() {
+                    self.violation(&f, "ORIGIN payload has a truncated entry");
+                }
+            }
+            // unknown frame types must be ignored
+            FrameType::Unknown(_) => {}
+        }
+    }
+
+    fn on_header_block(&mut self, f: &CapturedFrame, block: PendingBlock) {
+        let mut method = None;
+        let mut path = None;
+        let mut status = None;
+        let res =
+            self.decoders[f.direction.index()].decode_with_cb(&block.fragment, |name, value| {
+                match &name[..] {
+                    b":method" => method = Some(value.into_owned()),
+                    b":path" => path = Some(value.into_owned()),
+                    b":status" => status = Some(value.into_owned()),
+                    _ => {}
+                }
+            });
+        if let Err(e) = res {
+            self.violation(f, format!("HPACK decoding failed: {e:?}"));
+            return;
+        }
+
+        let stream_id = block.stream_id;
+        match f.direction {
+            Direction::ClientToServer => {
+                if self.exchanges.contains_key(&stream_id.0) {
+                    // trailers
+                    return;
+                }
+                if stream_id.is_server_initiated() || stream_id.0 <= self.last_client_stream {
+                    self.violation(
+                        f,
+                        format!("stream {stream_id} isn't a new client-initiated stream"),
+                    );
+                }
+                self.last_client_stream = self.last_client_stream.max(stream_id.0);
+                let method = method
+                    .and_then(|m| Piece::from(m).to_str().ok())
+                    .map(Method::from);
+                let path = path.map(|p| String::from_utf8_lossy(&p).into_owned());
+                self.exchanges
+                    .insert(stream_id.0, self.report.exchanges.len());
+                self.report.exchanges.push(Exchange {
+                    stream_id: Some(stream_id.0),
+                    method,
+                    path,
+                    request_at: f.at,
+                    ..Default::default()
+                });
+            }
+            Direction::ServerToClient => {
+                let Some(&index) = self.exchanges.get(&stream_id.0) else {
+                    self.violation(
+                        f,
+                        format!("response on stream {stream_id}, which has no request"),
+                    );
+                    return;
+                };
+                let status = status.and_then(|s| StatusCode::from_bytes(&s).ok());
+                let exchange = &mut self.report.exchanges[index];
+                match status {
+                    Some(status) if status.is_informational() => return,
+                    Some(status) if exchange.status.is_none() => {
+                        exchange.status = Some(status);
+                        exchange.response_at = f.at;
+                    }
+                    Some(_) => {}
+                    None if exchange.status.is_none() => {
+                        self.violation(f, "response without a valid :status");
+                    }
+                    // trailers
+                    None => {}
+                }
+                if block.end_stream {
+                    self.on_response_end(stream_id, f.at);
+                }
+            }
+        }
+    }
+
+    fn on_response_end(&mut self, stream_id: StreamId, at: Option<Duration>) {
+        if let Some(&index) = self.exchanges.get(&stream_id.0) {
+            self.report.exchanges[index].done_at = at;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::StatusCode;
+    use loona_h2::{Frame, FrameType, HeadersFlags, StreamId, PREFACE};
+
+    use super::{analyze, Direction, Protocol, Segment};
+    use crate::Method;
+
+    fn seg(direction: Direction, ms: u64, data: impl AsRef<[u8]>) -> Segment {
+        Segment {
+            direction,
+            at: Duration::from_millis(ms),
+            data: data.as_ref().to_vec(),
+        }
+    }
+
+    use Direction::{ClientToServer as C, ServerToClient as S};
+
+    #[test]
+    fn test_h1_exchanges() {
+        buffet::bufpool::initialize_allocator().unwrap();
+        let report = analyze([
+            seg(C, 0, "GET /a HTTP/1.1\r\nhost: x\r\n\r\n"),
+            seg(
+                C,
+                1,
+                "POST /b HTTP/1.1\r\nhost: x\r\ncontent-length: 3\r\n\r\nabc",
+            ),
+            seg(S, 10, "HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhel"),
+            seg(S, 12, "lo"),
+            seg(S, 20, "HTTP/1.1 100 Continue\r\n\r\n"),
+            seg(
+                S,
+                30,
+                "HTTP/1.1 201 Created\r\ntransfer-encoding: chunked\r\n\r\n",
+            ),
+            seg(S, 31, "3\r\nabc\r\n0\r\n\r\n"),
+        ])
+        .unwrap();
+        assert_eq!(report.protocol, Protocol::Http1);
+        assert_eq!(report.violations, vec![]);
+        assert_eq!(report.exchanges.len(), 2);
+
+        let a = &report.exchanges[0];
+        assert_eq!(a.method, Some(Method::Get));
+        assert_eq!(a.path.as_deref(), Some("/a"));
+        assert_eq!(a.status, Some(StatusCode::OK));
+        assert_eq!(a.time_to_response(), Some(Duration::from_millis(10)));
+        assert_eq!(a.done_at, Some(Duration::from_millis(12)));
+
+        let b = &report.exchanges[1];
+        assert_eq!(b.status, Some(StatusCode::CREATED));
+        assert_eq!(b.response_at, Some(Duration::from_millis(30)));
+        assert_eq!(b.done_at, Some(Duration::from_millis(31)));
+    }
+
+    #[test]
+    fn test_h1_violations() {
+        buffet::bufpool::initialize_allocator().unwrap();
+        let report = analyze([
+            seg(
+                C,
+                0,
+                "POST / HTTP/1.1\r\ncontent-length: 3\r\ntransfer-encoding: chunked\r\n\r\n",
+            ),
+            seg(S, 1, "HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nshort"),
+        ])
+        .unwrap();
+        let messages: Vec<_> = report
+            .violations
+            .iter()
+            .map(|v| (v.direction, v.message.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                (C, "HTTP/1.1 request without a host header"),
+                (C, "both transfer-encoding and content-length are set"),
+                (S, "capture ends in the middle of a body"),
+            ]
+        );
+        assert_eq!(report.violations[2].offset, 39);
+        assert_eq!(report.violations[2].at, Some(Duration::from_millis(1)));
+
+        let report = analyze([seg(S, 0, "HTTP/1.1 200 OK\r\n\r\n")]).unwrap();
+        assert_eq!(report.violations[0].message, "response without a request");
+    }
+
+    fn frame(frame_type: FrameType, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        Frame::new(frame_type, StreamId(stream_id))
+            .with_len(payload.len() as u32)
+            .write_into(&mut out)
+            .unwrap();
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn headers(stream_id: u32, fields: &[(&[u8], &[u8])], end_stream: bool) -> Vec<u8> {
+        let block = loona_hpack::Encoder::new().encode(fields.iter().copied());
+        let mut flags = HeadersFlags::EndHeaders.into();
+        if end_stream {
+            flags |= HeadersFlags::EndStream;
+        }
+        frame(FrameType::Headers(flags), stream_id, &block)
+    }
+
+    fn settings() -> Vec<u8> {
+        frame(FrameType::Settings(Default::default()), 0, &[])
+    }
+
+    #[test]
+    fn test_h2_exchanges() {
+        buffet::bufpool::initialize_allocator().unwrap();
+        let report = analyze([
+            seg(C, 0, [PREFACE.to_vec(), settings()].concat()),
+            seg(
+                C,
+                1,
+                headers(1, &[(b":method", b"GET"), (b":path", b"/x")], true),
+            ),
+            seg(S, 2, settings()),
+            seg(S, 5, headers(1, &[(b":status", b"204")], true)),
+        ])
+        .unwrap();
+        assert_eq!(report.protocol, Protocol::Http2);
+        assert_eq!(report.violations, vec![]);
+        assert_eq!(report.exchanges.len(), 1);
+
+        let x = &report.exchanges[0];
+        assert_eq!(x.stream_id, Some(1));
+        assert_eq!(x.method, Some(Method::Get));
+        assert_eq!(x.path.as_deref(), Some("/x"));
+        assert_eq!(x.status, Some(StatusCode::NO_CONTENT));
+        assert_eq!(x.time_to_response(), Some(Duration::from_millis(4)));
+        assert_eq!(x.done_at, Some(Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn test_h2_violations() {
+        buffet::bufpool::initialize_allocator().unwrap();
+        let report = analyze([
+            seg(C, 0, [PREFACE.to_vec(), settings()].concat()),
+            seg(
+                C,
+                1,
+                headers(2, &[(b":method", b"GET"), (b":path", b"/")], false),
+            ),
+            seg(
+                C,
+                2,
+                frame(FrameType::Data(Default::default()), 0, &[0; 20000]),
+            ),
+            seg(S, 3, frame(FrameType::Ping(Default::default()), 0, &[0; 8])),
+        ])
+        .unwrap();
+        let messages: Vec<_> = report
+            .violations
+            .iter()
+            .map(|v| (v.direction, v.message.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                (C, "stream 2 isn't a new client-initiated stream"),
+                (C, "20000-byte frame, over the peer's maximum of 16384"),
+                (C, "DATA on stream 0"),
+                (S, "first frame isn't SETTINGS"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,546 @@
+//! Wrappers that change how a [Body] behaves, built with [Body::limit],
+//! [Body::timeout], [Body::map_chunk], [Body::tee] and [Body::peekable], and
+//! meant to be stacked:
+//!
+//! ```ignore
+//! let mut body = req_body
+//!     .limit(1024 * 1024)
+//!     .timeout(Duration::from_secs(10))
+//!     .tee(|chunk: &Piece| hasher.update(&chunk[..]));
+//! ```
+//!
+//! A handler's `&mut impl Body` is a [Body] too, so request bodies can be
+//! wrapped without giving them up.
+
+use std::{collections::VecDeque, fmt, fs::File, os::unix::fs::FileExt, rc::Rc, time::Duration};
+
+use buffet::Piece;
+use http::HeaderName;
+
+use crate::{Body, BodyChunk};
+
+/// Returned by the adapters of this module
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum AdapterError<BodyError> {
+    #[error("body error: {0}")]
+    Body(BodyError),
+
+    /// Reading a [BodyChunk::File] into memory failed
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// cf. [Body::limit]
+    #[error("body is larger than {max} bytes")]
+    TooLarge { max: u64 },
+
+    /// cf. [Body::timeout]
+    #[error("no body chunk came in for {0:?}")]
+    TimedOut(Duration),
+}
+
+/// cf. [Body::limit]
+#[derive(Debug)]
+pub struct Limit<B> {
+    inner: B,
+    max: u64,
+    read: u64,
+}
+
+impl<B: Body> Limit<B> {
+    pub(crate) fn new(inner: B, max: u64) -> Self {
+        Self {
+            inner,
+            max,
+            read: 0,
+        }
+    }
+}
+
+impl<B: Body> Body for Limit<B> {
+    type Error = AdapterError<B::Error>;
+
+    fn content_len(&self) -> Option<u64> {
+        self.inner.content_len()
+    }
+
+    fn eof(&self) -> bool {
+        self.inner.eof()
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        let too_large = AdapterError::TooLarge { max: self.max };
+        if self.inner.content_len().is_some_and(|len| len > self.max) {
+            return Err(too_large);
+        }
+
+        let chunk = self.inner.next_chunk().await.map_err(AdapterError::Body)?;
+        self.read += match &chunk {
+            BodyChunk::Chunk(piece) => piece.len() as u64,
+            BodyChunk::File { len, .. } => *len,
+            BodyChunk::Done { .. } => 0,
+        };
+        if self.read > self.max {
+            return Err(too_large);
+        }
+        Ok(chunk)
+    }
+
+    fn flush_each_chunk(&self) -> bool {
+        self.inner.flush_each_chunk()
+    }
+
+    fn announced_trailers(&self) -> &[HeaderName] {
+        self.inner.announced_trailers()
+    }
+}
+
+/// cf. [Body::timeout]
+#[derive(Debug)]
+pub struct Timeout<B> {
+    inner: B,
+    duration: Duration,
+}
+
+impl<B: Body> Timeout<B> {
+    pub(crate) fn new(inner: B, duration: Duration) -> Self {
+        Self { inner, duration }
+    }
+}
+
+impl<B: Body> Body for Timeout<B> {
+    type Error = AdapterError<B::Error>;
+
+    fn content_len(&self) -> Option<u64> {
+        self.inner.content_len()
+    }
+
+    fn eof(&self) -> bool {
+        self.inner.eof()
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        match tokio::time::timeout(self.duration, self.inner.next_chunk()).await {
+            Ok(res) => res.map_err(AdapterError::Body),
+            Err(_) => Err(AdapterError::TimedOut(self.duration)),
+        }
+    }
+
+    fn flush_each_chunk(&self) -> bool {
+        self.inner.flush_each_chunk()
+    }
+
+    fn announced_trailers(&self) -> &[HeaderName] {
+        self.inner.announced_trailers()
+    }
+}
+
+/// Yields the chunks of a body as pieces, reading [BodyChunk::File]s into
+/// memory, for adapters that need to see the bytes
+#[derive(Debug)]
+struct Pieces<B> {
+    inner: B,
+    /// What's left of a [BodyChunk::File]
+    file: Option<(Rc<File>, u64, u64)>,
+}
+
+impl<B: Body> Pieces<B> {
+    fn new(inner: B) -> Self {
+        Self { inner, file: None }
+    }
+
+    fn eof(&self) -> bool {
+        self.file.is_none() && self.inner.eof()
+    }
+
+    /// A piece of the body, or the final [BodyChunk::Done], trailers and all
+    async fn next(&mut self) -> Result<Result<Piece, BodyChunk>, AdapterError<B::Error>> {
+        loop {
+            if let Some((file, offset, len)) = self.file.take() {
+                let piece = buffet::read_file_piece(&file, offset, len)?;
+                let read = piece.len() as u64;
+                if read < len {
+                    self.file = Some((file, offset + read, len - read));
+                }
+                return Ok(Ok(piece));
+            }
+            match self.inner.next_chunk().await.map_err(AdapterError::Body)? {
+                BodyChunk::Chunk(piece) => return Ok(Ok(piece)),
+                BodyChunk::File { file, offset, len } => self.file = Some((file, offset, len)),
+                done @ BodyChunk::Done { .. } => return Ok(Err(done)),
+            }
+        }
+    }
+}
+
+/// cf. [Body::map_chunk]
+pub struct MapChunk<B, F> {
+    inner: Pieces<B>,
+    f: F,
+}
+
+impl<B: Body, F> MapChunk<B, F> {
+    pub(crate) fn new(inner: B, f: F) -> Self {
+        Self {
+            inner: Pieces::new(inner),
+            f,
+        }
+    }
+}
+
+impl<B: Body, F> fmt::Debug for MapChunk<B, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MapChunk")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B, F> Body for MapChunk<B, F>
+where
+    B: Body,
+    F: FnMut(Piece) -> Piece,
+{
+    type Error = AdapterError<B::Error>;
+
+    /// Unknown: the mapping may change the length
+    fn content_len(&self) -> Option<u64> {
+        None
+    }
+
+    fn eof(&self) -> bool {
+        self.inner.eof()
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        Ok(match self.inner.next().await? {
+            Ok(piece) => BodyChunk::Chunk((self.f)(piece)),
+            Err(done) => done,
+        })
+    }
+
+    fn flush_each_chunk(&self) -> bool {
+        self.inner.inner.flush_each_chunk()
+    }
+
+    fn announced_trailers(&self) -> &[HeaderName] {
+        self.inner.inner.announced_trailers()
+    }
+}
+
+/// cf. [Body::tee]
+pub struct Tee<B, S> {
+    inner: Pieces<B>,
+    sink: S,
+}
+
+impl<B: Body, S> Tee<B, S> {
+    pub(crate) fn new(inner: B, sink: S) -> Self {
+        Self {
+            inner: Pieces::new(inner),
+            sink,
+        }
+    }
+}
+
+impl<B: Body, S> fmt::Debug for Tee<B, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tee")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B, S> Body for Tee<B, S>
+where
+    B: Body,
+    S: FnMut(&Piece),
+{
+    type Error = AdapterError<B::Error>;
+
+    fn content_len(&self) -> Option<u64> {
+        self.inner.inner.content_len()
+    }
+
+    fn eof(&self) -> bool {
+        self.inner.eof()
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        Ok(match self.inner.next().await? {
+            Ok(piece) => {
+                (self.sink)(&piece);
+                BodyChunk::Chunk(piece)
+            }
+            Err(done) => done,
+        })
+    }
+
+    fn flush_each_chunk(&self) -> bool {
+        self.inner.inner.flush_each_chunk()
+    }
+
+    fn announced_trailers(&self) -> &[HeaderName] {
+        self.inner.inner.announced_trailers()
+    }
+}
+
+/// cf. [Body::peekable]
+pub struct PeekableBody<B> {
+    inner: B,
+    max: usize,
+    prefix: Vec<u8>,
+    /// What was read from `inner` while peeking, and is yet to be handed out
+    pending: VecDeque<BodyChunk>,
+    /// Set once we've peeked, or started handing out chunks
+    peeked: bool,
+}
+
+impl<B: Body> PeekableBody<B> {
+    pub(crate) fn new(inner: B, max: usize) -> Self {
+        Self {
+            inner,
+            max,
+            prefix: Vec::new(),
+            pending: VecDeque::new(),
+            peeked: false,
+        }
+    }
+
+    /// The first bytes of the body: `max` of them, or fewer if the body is
+    /// shorter. What's read to get them is handed out again by
+    /// [Body::next_chunk], so nothing is lost.
+    ///
+    /// Only the first call reads anything: peeking after the body started
+    /// being read returns what was peeked before, if anything.
+    pub async fn peek(&mut self) -> Result<&[u8], AdapterError<B::Error>> {
+        if self.peeked {
+            return Ok(&self.prefix);
+        }
+        self.peeked = true;
+
+        while self.prefix.len() < self.max {
+            let wanted = self.max - self.prefix.len();
+            match self.inner.next_chunk().await.map_err(AdapterError::Body)? {
+                BodyChunk::Chunk(piece) => {
+                    self.prefix
+                        .extend_from_slice(&piece[..piece.len().min(wanted)]);
+                    self.pending.push_back(BodyChunk::Chunk(piece));
+                }
+                BodyChunk::File { file, offset, len } => {
+                    // only read what we need, the rest stays a file chunk
+                    let read = len.min(wanted as u64);
+                    let mut buf = vec![0u8; read as usize];
+                    file.read_exact_at(&mut buf, offset)?;
+                    self.prefix.extend_from_slice(&buf);
+                    self.pending.push_back(BodyChunk::Chunk(buf.into()));
+                    if read < len {
+                        self.pending.push_back(BodyChunk::File {
+                            file,
+                            offset: offset + read,
+                            len: len - read,
+                        });
+                    }
+                }
+                done @ BodyChunk::Done { .. } => {
+                    self.pending.push_back(done);
+                    break;
+                }
+            }
+        }
+        Ok(&self.prefix)
+    }
+}
+
+impl<B: Body> fmt::Debug for PeekableBody<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeekableBody")
+            .field("inner", &self.inner)
+            .field("prefix_len", &self.prefix.len())
+            .field("pending", &self.pending.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B: Body> Body for PeekableBody<B> {
+    type Error = AdapterError<B::Error>;
+
+    fn content_len(&self) -> Option<u64> {
+        self.inner.content_len()
+    }
+
+    fn eof(&self) -> bool {
+        self.pending.is_empty() && self.inner.eof()
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        self.peeked = true;
+        match self.pending.pop_front() {
+            Some(chunk) => Ok(chunk),
+            None => self.inner.next_chunk().await.map_err(AdapterError::Body),
+        }
+    }
+
+    fn flush_each_chunk(&self) -> bool {
+        self.inner.flush_each_chunk()
+    }
+
+    fn announced_trailers(&self) -> &[HeaderName] {
+        self.inner.announced_trailers()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::VecDeque, fmt, rc::Rc, time::Duration};
+
+    use buffet::Piece;
+
+    use super::AdapterError;
+    use crate::{
+        error::NeverError, testkit::anonymous_file, Body, BodyChunk, FileBody, SinglePieceBody,
+    };
+
+    mod limit {
+        use crate::{Body, SinglePieceBody};
+
+        crate::body_test_suite!(|| SinglePieceBody::from("hello").limit(5));
+    }
+
+    mod timeout {
+        use std::time::Duration;
+
+        use crate::{Body, SinglePieceBody};
+
+        crate::body_test_suite!(|| SinglePieceBody::from("hello").timeout(Duration::from_secs(1)));
+    }
+
+    mod tee {
+        use crate::{testkit::anonymous_file, Body, FileBody};
+
+        crate::body_test_suite!(|| FileBody::new(anonymous_file(b"hello world"))
+            .unwrap()
+            .tee(|_: &buffet::Piece| {}));
+    }
+
+    mod peekable {
+        use crate::{Body, SinglePieceBody};
+
+        crate::body_test_suite!(|| SinglePieceBody::from("hello").peekable(3));
+    }
+
+    /// Yields its chunks one by one, without announcing a length, then
+    /// never finishes
+    struct Chunks(VecDeque<&'static str>);
+
+    impl fmt::Debug for Chunks {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Chunks").finish_non_exhaustive()
+        }
+    }
+
+    impl Body for Chunks {
+        type Error = NeverError;
+
+        fn content_len(&self) -> Option<u64> {
+            None
+        }
+
+        fn eof(&self) -> bool {
+            false
+        }
+
+        async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+            match self.0.pop_front() {
+                Some(s) => Ok(BodyChunk::Chunk(s.into())),
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    #[test]
+    fn test_limit() {
+        buffet::start(async move {
+            let err = SinglePieceBody::from("hello")
+                .limit(4)
+                .next_chunk()
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(err, AdapterError::TooLarge { max: 4 }));
+
+            let mut chunks = Chunks(VecDeque::from(["hello", "world"]));
+            let mut body = (&mut chunks).limit(8);
+            assert!(matches!(body.next_chunk().await, Ok(BodyChunk::Chunk(_))));
+            let err = body.next_chunk().await.err().unwrap();
+            assert!(matches!(err, AdapterError::TooLarge { max: 8 }));
+        });
+    }
+
+    #[test]
+    fn test_timeout() {
+        buffet::start(async move {
+            let mut body = Chunks(VecDeque::from(["hello"])).timeout(Duration::from_millis(20));
+            assert!(matches!(body.next_chunk().await, Ok(BodyChunk::Chunk(_))));
+            let err = body.next_chunk().await.err().unwrap();
+            assert!(matches!(err, AdapterError::TimedOut(_)));
+        });
+    }
+
+    #[test]
+    fn test_map_chunk_and_tee() {
+        buffet::start(async move {
+            let seen = Rc::new(RefCell::new(Vec::new()));
+            let mut body = FileBody::new(anonymous_file(b"hello"))
+                .unwrap()
+                .map_chunk(|piece: Piece| piece[..].to_ascii_uppercase().into())
+                .tee({
+                    let seen = seen.clone();
+                    move |piece: &Piece| seen.borrow_mut().extend_from_slice(&piece[..])
+                });
+            assert_eq!(body.content_len(), None);
+            let collected = body.collect(5).await.unwrap();
+            assert_eq!(&collected[..], b"HELLO");
+            assert_eq!(&seen.borrow()[..], b"HELLO");
+        });
+    }
+
+    #[test]
+    fn test_peekable() {
+        buffet::start(async move {
+            let mut body = FileBody::new(anonymous_file(b"hello world"))
+                .unwrap()
+                .peekable(5);
+            assert_eq!(body.peek().await.unwrap(), b"hello");
+            assert_eq!(body.peek().await.unwrap(), b"hello");
+            assert!(matches!(body.next_chunk().await, Ok(BodyChunk::Chunk(_))));
+            // the rest of the file is still sent as a file
+            assert!(matches!(
+                body.next_chunk().await,
+                Ok(BodyChunk::File {
+                    offset: 5,
+                    len: 6,
+                    ..
+                })
+            ));
+            assert!(matches!(
+                body.next_chunk().await,
+                Ok(BodyChunk::Done { .. })
+            ));
+            assert!(body.eof());
+
+            let mut chunks = Chunks(VecDeque::from(["he", "llo", " world"]));
+            let mut body = (&mut chunks).peekable(4);
+            assert_eq!(body.peek().await.unwrap(), b"hell");
+            assert_eq!(chunks.0.len(), 1);
+            let mut body = (&mut chunks).peekable(4);
+            let _ = body.next_chunk().await;
+            assert_eq!(body.peek().await.unwrap(), b"");
+
+            let mut body = SinglePieceBody::from("hi").peekable(4);
+            assert_eq!(body.peek().await.unwrap(), b"hi");
+            let collected = body.collect(2).await.unwrap();
+            assert_eq!(&collected[..], b"hi");
+        });
+    }
+}
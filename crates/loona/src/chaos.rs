@@ -0,0 +1,701 @@
+//! Fault injection, to see how clients and their retry logic cope with a
+//! misbehaving server, see [ChaosDriver]
+//!
+//! Each response handled by the driver rolls the dice, with the
+//! probabilities in [ChaosConf], for:
+//!
+//!   - a delay before the response headers
+//!   - a connection reset (h1) or stream reset (h2) right after them
+//!   - a body cut short at a random point, after which the connection or
+//!     stream is torn down the same way
+//!   - a wrong chunk size, for HTTP/1.1 responses that use chunked encoding,
+//!     cf. [CorruptChunkSize]
+//!
+//! ```ignore
+//! let conf = ChaosConf { truncate_probability: 0.1, ..Default::default() };
+//! let driver = ChaosDriver::new(MyApp, conf)
+//!     .with_scope(|req: &Request| req.uri.path().starts_with("/flaky/"));
+//! h1::serve(io, conf, client_buf, driver).await?;
+//! ```
+//!
+//! Only available with the `test-util` feature.
+
+use std::{cell::Cell, fs::File, ops::Range, rc::Rc, time::Duration};
+
+use buffet::{Piece, PieceList, WriteOwned};
+
+use crate::{
+    h1::encode::{H1Encoder, H1EncoderError},
+    h2::encode::H2Encoder,
+    Body, Encoder, ExpectResponseHeaders, Headers, HeadersExt, OnComplete, Request, Responder,
+    Response, ResponseDone, ServerDriver,
+};
+
+/// How often each fault is injected, as probabilities between 0.0 (never)
+/// and 1.0 (every response). Everything is off by default.
+#[derive(Debug, Clone)]
+pub struct ChaosConf {
+    /// How often the response headers are held back
+    pub delay_probability: f64,
+
+    /// How long they're held back, picked uniformly from this range
+    pub delay: Range<Duration>,
+
+    /// How often the connection (or stream) is reset right after the
+    /// response headers
+    pub reset_probability: f64,
+
+    /// How often only part of the body is sent before the connection (or
+    /// stream) is reset
+    pub truncate_probability: f64,
+
+    /// How often the first chunk of a chunked HTTP/1.1 body announces one
+    /// byte more than it has
+    pub corrupt_chunk_size_probability: f64,
+
+    /// Seeds the dice, so a sequence of faults can be replayed
+    pub seed: u64,
+}
+
+impl Default for ChaosConf {
+    fn default() -> Self {
+        Self {
+            delay_probability: 0.0,
+            delay: Duration::from_millis(50)..Duration::from_millis(500),
+            reset_probability: 0.0,
+            truncate_probability: 0.0,
+            corrupt_chunk_size_probability: 0.0,
+            seed: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ChaosError<EncoderError> {
+    /// The wrapped encoder errored out
+    #[error("encoder error: {0}")]
+    Encoder(EncoderError),
+
+    /// We reset the connection (or stream) on purpose
+    #[error("chaos: reset after the response headers")]
+    Reset,
+
+    /// We cut the body short on purpose
+    #[error("chaos: body truncated after {written} bytes")]
+    Truncated { written: u64 },
+}
+
+/// Encoders whose framing may have chunk sizes to lie about, cf.
+/// [ChaosConf::corrupt_chunk_size_probability]. [ChaosDriver] needs the
+/// encoder it's handed to implement it: that's the protocol's own encoder
+/// when the driver wraps the whole stack.
+#[allow(async_fn_in_trait)] // we never require Send
+pub trait CorruptChunkSize: Encoder {
+    /// Writes `chunk` like [Encoder::write_body_chunk] would, but announces
+    /// one byte more than it has. Hands `chunk` back, unwritten, if this
+    /// response's framing has no chunk sizes, which is what the default
+    /// assumes.
+    async fn write_oversized_chunk(&mut self, chunk: Piece) -> Result<Option<Piece>, Self::Error> {
+        Ok(Some(chunk))
+    }
+}
+
+impl<W> CorruptChunkSize for H1Encoder<W>
+where
+    W: WriteOwned,
+{
+    async fn write_oversized_chunk(&mut self, chunk: Piece) -> Result<Option<Piece>, Self::Error> {
+        if !self.is_chunked() {
+            return Ok(Some(chunk));
+        }
+        let list: PieceList = self
+            .take_head()
+            .followed_by(Piece::from_fmt(format_args!("{:x}\r\n", chunk.len() + 1)))
+            .followed_by(chunk)
+            .followed_by("\r\n");
+        self.transport_w()
+            .writev_all_owned(list)
+            .await
+            .map_err(H1EncoderError::from)?;
+        self.head_written();
+        Ok(None)
+    }
+}
+
+impl CorruptChunkSize for H2Encoder {}
+
+/// xorshift64*: not fit for anything but rolling dice
+#[derive(Debug, Clone)]
+struct Dice(u64);
+
+impl Dice {
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck on zero
+        Self(seed.max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn roll(&mut self, probability: f64) -> bool {
+        // 53 bits is all an f64 can tell apart in [0, 1)
+        let sample = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        sample < probability
+    }
+
+    /// Picks a number in `0..n`, or 0 if `n` is 0
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            0
+        } else {
+            self.next_u64() % n
+        }
+    }
+}
+
+/// The faults picked for one response
+#[derive(Debug, Default, Clone, Copy)]
+struct Faults {
+    delay: Option<Duration>,
+    reset: bool,
+    truncate: bool,
+    corrupt_chunk_size: bool,
+}
+
+impl Faults {
+    fn pick(conf: &ChaosConf, dice: &mut Dice) -> Self {
+        let delay = dice.roll(conf.delay_probability).then(|| {
+            let span = conf.delay.end.saturating_sub(conf.delay.start);
+            conf.delay.start + Duration::from_nanos(dice.below(span.as_nanos() as u64))
+        });
+        // a response gets at most one of the body faults
+        let reset = dice.roll(conf.reset_probability);
+        let truncate = !reset && dice.roll(conf.truncate_probability);
+        let corrupt_chunk_size =
+            !reset && !truncate && dice.roll(conf.corrupt_chunk_size_probability);
+        Self {
+            delay,
+            reset,
+            truncate,
+            corrupt_chunk_size,
+        }
+    }
+}
+
+/// Where a truncated body stops
+#[derive(Debug, Clone, Copy)]
+enum Cut {
+    /// Not truncating this response
+    None,
+
+    /// The body length isn't known: cut somewhere in the first chunk
+    FirstChunk,
+
+    /// Stop once this many more bytes went out
+    After(u64),
+}
+
+/// An [Encoder] that injects the faults picked for its response before
+/// handing things off to another encoder
+pub struct ChaosEncoder<E> {
+    inner: E,
+    faults: Faults,
+    dice: Dice,
+    cut: Cut,
+    written: u64,
+}
+
+impl<E> ChaosEncoder<E>
+where
+    E: Encoder,
+{
+    fn new(inner: E, faults: Faults, dice: Dice) -> Self {
+        Self {
+            inner,
+            faults,
+            dice,
+            cut: Cut::None,
+            written: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+
+    /// How many bytes of a `len`-byte write may go out, if any of it is cut
+    fn cut_at(&mut self, len: u64) -> Option<u64> {
+        match self.cut {
+            Cut::None => None,
+            Cut::FirstChunk if len == 0 => None,
+            Cut::FirstChunk => Some(self.dice.below(len)),
+            Cut::After(remaining) if remaining < len => Some(remaining),
+            Cut::After(remaining) => {
+                self.cut = Cut::After(remaining - len);
+                None
+            }
+        }
+    }
+
+    fn truncated(&mut self, sent: u64) -> ChaosError<E::Error> {
+        let written = self.written + sent;
+        tracing::debug!("chaos: truncating body after {written} bytes");
+        ChaosError::Truncated { written }
+    }
+}
+
+impl<E> Encoder for ChaosEncoder<E>
+where
+    E: CorruptChunkSize,
+{
+    type Error = ChaosError<E::Error>;
+
+    async fn write_response(&mut self, res: Response) -> Result<(), Self::Error> {
+        if res.status.is_informational() {
+            return self
+                .inner
+                .write_response(res)
+                .await
+                .map_err(ChaosError::Encoder);
+        }
+
+        if let Some(delay) = self.faults.delay.take() {
+            tracing::debug!("chaos: delaying response by {delay:?}");
+            tokio::time::sleep(delay).await;
+        }
+        if self.faults.truncate && !res.means_empty_body() {
+            self.cut = match res.headers.content_length() {
+                Some(len) => Cut::After(self.dice.below(len)),
+                None => Cut::FirstChunk,
+            };
+        }
+        let reset = self.faults.reset;
+
+        self.inner
+            .write_response(res)
+            .await
+            .map_err(ChaosError::Encoder)?;
+
+        if reset {
+            tracing::debug!("chaos: resetting after the response headers");
+            return Err(ChaosError::Reset);
+        }
+        Ok(())
+    }
+
+    async fn write_body_chunk(&mut self, chunk: Piece) -> Result<(), Self::Error> {
+        let chunk = if std::mem::take(&mut self.faults.corrupt_chunk_size) {
+            let len = chunk.len() as u64;
+            match self
+                .inner
+                .write_oversized_chunk(chunk)
+                .await
+                .map_err(ChaosError::Encoder)?
+            {
+                Some(chunk) => chunk,
+                None => {
+                    tracing::debug!("chaos: corrupted a chunk size");
+                    self.written += len;
+                    return Ok(());
+                }
+            }
+        } else {
+            chunk
+        };
+
+        if let Some(keep) = self.cut_at(chunk.len() as u64) {
+            let (kept, _) = chunk.split_at(keep as usize);
+            if !kept.is_empty() {
+                self.inner
+                    .write_body_chunk(kept)
+                    .await
+                    .map_err(ChaosError::Encoder)?;
+            }
+            return Err(self.truncated(keep));
+        }
+
+        self.written += chunk.len() as u64;
+        self.inner
+            .write_body_chunk(chunk)
+            .await
+            .map_err(ChaosError::Encoder)
+    }
+
+    async fn write_body_file(
+        &mut self,
+        file: Rc<File>,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), Self::Error> {
+        if let Some(keep) = self.cut_at(len) {
+            if keep > 0 {
+                self.inner
+                    .write_body_file(file, offset, keep)
+                    .await
+                    .map_err(ChaosError::Encoder)?;
+            }
+            return Err(self.truncated(keep));
+        }
+
+        self.written += len;
+        self.inner
+            .write_body_file(file, offset, len)
+            .await
+            .map_err(ChaosError::Encoder)
+    }
+
+    async fn write_body_end(&mut self) -> Result<(), Self::Error> {
+        self.inner
+            .write_body_end()
+            .await
+            .map_err(ChaosError::Encoder)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush().await.map_err(ChaosError::Encoder)
+    }
+
+    async fn write_trailers(&mut self, trailers: Box<Headers>) -> Result<(), Self::Error> {
+        self.inner
+            .write_trailers(trailers)
+            .await
+            .map_err(ChaosError::Encoder)
+    }
+
+    fn on_complete(&mut self, callback: OnComplete) {
+        self.inner.on_complete(callback)
+    }
+
+    fn is_head_response(&self) -> bool {
+        self.inner.is_head_response()
+    }
+
+    fn accepts_trailers(&self) -> bool {
+        self.inner.accepts_trailers()
+    }
+
+    fn close_delimited(&mut self) -> bool {
+        self.inner.close_delimited()
+    }
+}
+
+/// A [ServerDriver] that injects faults into the responses of the driver it
+/// wraps, cf. [ChaosConf].
+///
+/// It should wrap the whole stack: only the protocol encoders know how to
+/// corrupt a chunk size, cf. [CorruptChunkSize].
+pub struct ChaosDriver<D, S = fn(&Request) -> bool> {
+    inner: D,
+    conf: ChaosConf,
+    scope: S,
+    seed: Cell<u64>,
+}
+
+impl<D> ChaosDriver<D> {
+    /// Injects faults into every response
+    pub fn new(inner: D, conf: ChaosConf) -> Self {
+        Self {
+            inner,
+            seed: Cell::new(conf.seed),
+            conf,
+            scope: |_| true,
+        }
+    }
+
+    /// Only injects faults into responses to requests for which `scope`
+    /// returns true
+    pub fn with_scope<S>(self, scope: S) -> ChaosDriver<D, S>
+    where
+        S: Fn(&Request) -> bool,
+    {
+        ChaosDriver {
+            inner: self.inner,
+            conf: self.conf,
+            scope,
+            seed: self.seed,
+        }
+    }
+}
+
+impl<E, D, S> ServerDriver<E> for ChaosDriver<D, S>
+where
+    E: CorruptChunkSize,
+    D: ServerDriver<ChaosEncoder<E>>,
+    S: Fn(&Request) -> bool,
+{
+    type Error = D::Error;
+
+    async fn handle(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> Result<Responder<E, ResponseDone>, Self::Error> {
+        // every request gets its own dice, so faults don't depend on how
+        // concurrent responses interleave
+        let mut dice = Dice::new(self.seed.get());
+        self.seed.set(dice.next_u64());
+        let faults = if (self.scope)(&req) {
+            Faults::pick(&self.conf, &mut dice)
+        } else {
+            Faults::default()
+        };
+
+        let respond = Responder::new(ChaosEncoder::new(respond.into_encoder(), faults, dice));
+        let done = self.inner.handle(req, req_body, respond).await?;
+        Ok(Responder::done(done.into_inner().into_inner()))
+    }
+
+    fn origins(&self) -> Option<Vec<String>> {
+        self.inner.origins()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        rc::Rc,
+        time::{Duration, Instant},
+    };
+
+    use b_x::BX;
+    use buffet::{Piece, ReadOwned};
+    use http::{header, StatusCode};
+
+    use super::{ChaosConf, ChaosDriver, ChaosEncoder, CorruptChunkSize, Dice, Faults};
+    use crate::{
+        h1::encode::H1Encoder, Body, Encoder, ExpectResponseHeaders, Headers, Request, Responder,
+        Response, ResponseDone, ServerDriver,
+    };
+
+    /// Records what it's given
+    #[derive(Default, Clone)]
+    struct RecordingEncoder {
+        responses: Rc<RefCell<Vec<Response>>>,
+        body: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Encoder for RecordingEncoder {
+        type Error = BX;
+
+        async fn write_response(&mut self, res: Response) -> Result<(), Self::Error> {
+            self.responses.borrow_mut().push(res);
+            Ok(())
+        }
+        async fn write_body_chunk(&mut self, chunk: Piece) -> Result<(), Self::Error> {
+            self.body.borrow_mut().extend_from_slice(&chunk[..]);
+            Ok(())
+        }
+        async fn write_body_file(
+            &mut self,
+            file: Rc<std::fs::File>,
+            offset: u64,
+            len: u64,
+        ) -> Result<(), Self::Error> {
+            let chunk = buffet::read_file_piece(&file, offset, len)?;
+            self.write_body_chunk(chunk).await
+        }
+        async fn write_body_end(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_trailers(&mut self, _: Box<Headers>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    impl CorruptChunkSize for RecordingEncoder {}
+
+    /// Sends 100 bytes, in chunks of 10, with a `content-length` if asked
+    struct Hundred {
+        content_length: bool,
+    }
+
+    impl<E: Encoder> ServerDriver<E> for Hundred {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            _req: Request,
+            _req_body: &mut impl Body,
+            respond: Responder<E, ExpectResponseHeaders>,
+        ) -> Result<Responder<E, ResponseDone>, Self::Error> {
+            let mut res = Response::default();
+            if self.content_length {
+                res.headers.insert(header::CONTENT_LENGTH, "100".into());
+            }
+            let mut respond = respond
+                .write_final_response(res)
+                .await
+                .map_err(BX::from_err)?;
+            for _ in 0..10 {
+                respond
+                    .write_chunk(vec![b'a'; 10].into())
+                    .await
+                    .map_err(BX::from_err)?;
+            }
+            respond.finish_body(None).await.map_err(BX::from_err)
+        }
+    }
+
+    async fn handle(conf: ChaosConf, content_length: bool) -> (RecordingEncoder, Result<(), BX>) {
+        let recorder = RecordingEncoder::default();
+        let driver = ChaosDriver::new(Hundred { content_length }, conf);
+        let res = driver
+            .handle(
+                Request::default(),
+                &mut (),
+                Responder::new(recorder.clone()),
+            )
+            .await
+            .map(|_| ());
+        (recorder, res)
+    }
+
+    #[test]
+    fn test_no_faults_by_default() {
+        buffet::start(async {
+            let (recorder, res) = handle(ChaosConf::default(), true).await;
+            res.unwrap();
+            assert_eq!(recorder.responses.borrow()[0].status, StatusCode::OK);
+            assert_eq!(recorder.body.borrow().len(), 100);
+        });
+    }
+
+    #[test]
+    fn test_reset() {
+        buffet::start(async {
+            let conf = ChaosConf {
+                reset_probability: 1.0,
+                ..Default::default()
+            };
+            let (recorder, res) = handle(conf, true).await;
+            assert!(res.unwrap_err().to_string().contains("reset"));
+            assert_eq!(recorder.responses.borrow().len(), 1);
+            assert!(recorder.body.borrow().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_truncate() {
+        buffet::start(async {
+            for content_length in [true, false] {
+                for seed in 1..20 {
+                    let conf = ChaosConf {
+                        truncate_probability: 1.0,
+                        seed,
+                        ..Default::default()
+                    };
+                    let (recorder, res) = handle(conf, content_length).await;
+                    assert!(res.unwrap_err().to_string().contains("truncated"));
+                    let len = recorder.body.borrow().len();
+                    if content_length {
+                        assert!(len < 100, "{len}");
+                    } else {
+                        // cut somewhere in the first chunk
+                        assert!(len < 10, "{len}");
+                    }
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_delay() {
+        buffet::start(async {
+            let conf = ChaosConf {
+                delay_probability: 1.0,
+                delay: Duration::from_millis(20)..Duration::from_millis(30),
+                ..Default::default()
+            };
+            let before = Instant::now();
+            let (recorder, res) = handle(conf, true).await;
+            res.unwrap();
+            assert!(before.elapsed() >= Duration::from_millis(20));
+            assert_eq!(recorder.body.borrow().len(), 100);
+        });
+    }
+
+    #[test]
+    fn test_scope() {
+        buffet::start(async {
+            let conf = ChaosConf {
+                reset_probability: 1.0,
+                ..Default::default()
+            };
+            let driver = ChaosDriver::new(
+                Hundred {
+                    content_length: true,
+                },
+                conf,
+            )
+            .with_scope(|req: &Request| req.uri.path().starts_with("/flaky/"));
+
+            for (path, fails) in [("/flaky/a", true), ("/steady", false)] {
+                let req = Request {
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                };
+                let res = driver
+                    .handle(req, &mut (), Responder::new(RecordingEncoder::default()))
+                    .await;
+                assert_eq!(res.is_err(), fails, "{path}");
+            }
+        });
+    }
+
+    #[test]
+    fn test_probabilities() {
+        let conf = ChaosConf {
+            truncate_probability: 0.25,
+            ..Default::default()
+        };
+        let mut dice = Dice::new(conf.seed);
+        let truncated = (0..10_000)
+            .filter(|_| Faults::pick(&conf, &mut dice).truncate)
+            .count();
+        assert!((2_000..3_000).contains(&truncated), "{truncated}");
+    }
+
+    #[test]
+    fn test_corrupt_chunk_size() {
+        buffet::start(async {
+            let (server_w, mut client_r) = buffet::pipe();
+            let faults = Faults {
+                corrupt_chunk_size: true,
+                ..Default::default()
+            };
+            let mut encoder = ChaosEncoder::new(H1Encoder::new(server_w), faults, Dice::new(1));
+            // the pipe only moves along as it's read from
+            buffet::spawn(async move {
+                encoder.write_response(Response::default()).await.unwrap();
+                encoder.write_body_chunk(b"hello"[..].into()).await.unwrap();
+                encoder.write_body_chunk(b"world"[..].into()).await.unwrap();
+                encoder.write_body_end().await.unwrap();
+            });
+
+            let mut out = Vec::new();
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let res;
+                (res, buf) = client_r.read_owned(buf).await;
+                let n = res.unwrap();
+                if n == 0 {
+                    break;
+                }
+                out.extend_from_slice(&buf[..n]);
+            }
+            let out = String::from_utf8(out).unwrap();
+            // only the first chunk lies about its size
+            assert!(
+                out.ends_with("\r\n\r\n6\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n"),
+                "{out:?}"
+            );
+        });
+    }
+}
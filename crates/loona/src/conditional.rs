@@ -0,0 +1,346 @@
+//! Conditional requests: whether `if-match`, `if-none-match`,
+//! `if-modified-since` and `if-unmodified-since` let a handler go ahead, cf.
+//! <https://httpwg.org/specs/rfc9110.html#conditional.requests>
+//!
+//! Handlers describe the current state of the resource with [Validators],
+//! call [evaluate] before doing anything else, and reply with
+//! [Evaluation::status] when there is one.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::{header, StatusCode};
+
+use crate::{util::parse_http_date, Headers, Method};
+
+/// What identifies the current version of a resource
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Validators<'a> {
+    /// The entity tag, quotes (and `W/` prefix, if weak) included, e.g.
+    /// `"5f3a-1c"`
+    pub etag: Option<&'a str>,
+
+    /// When the resource last changed
+    pub last_modified: Option<SystemTime>,
+}
+
+/// What to do with a request, given its preconditions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Evaluation {
+    /// Preconditions hold (or there are none): handle the request normally
+    Proceed,
+
+    /// The client's copy is current: reply 304 with the validators, and no
+    /// body
+    NotModified,
+
+    /// A precondition failed: reply 412 without doing anything
+    PreconditionFailed,
+}
+
+impl Evaluation {
+    /// The status to reply with instead of handling the request, if any
+    pub fn status(self) -> Option<StatusCode> {
+        match self {
+            Evaluation::Proceed => None,
+            Evaluation::NotModified => Some(StatusCode::NOT_MODIFIED),
+            Evaluation::PreconditionFailed => Some(StatusCode::PRECONDITION_FAILED),
+        }
+    }
+}
+
+/// Evaluates the preconditions of a request, in the order
+/// <https://httpwg.org/specs/rfc9110.html#evaluation> prescribes.
+pub fn evaluate(method: &Method, headers: &Headers, validators: &Validators) -> Evaluation {
+    let safe = matches!(method, Method::Get | Method::Head);
+
+    if let Some(if_match) = headers.get(header::IF_MATCH) {
+        if !list_matches(if_match, validators.etag, strong_eq) {
+            return Evaluation::PreconditionFailed;
+        }
+    } else if let (Some(ius), Some(last_modified)) = (
+        headers
+            .get(header::IF_UNMODIFIED_SINCE)
+            .and_then(|v| parse_http_date(v)),
+        validators.last_modified,
+    ) {
+        if truncate_to_secs(last_modified) > ius {
+            return Evaluation::PreconditionFailed;
+        }
+    }
+
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        if list_matches(if_none_match, validators.etag, weak_eq) {
+            return if safe {
+                Evaluation::NotModified
+            } else {
+                Evaluation::PreconditionFailed
+            };
+        }
+    } else if let (true, Some(ims), Some(last_modified)) = (
+        safe,
+        headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|v| parse_http_date(v)),
+        validators.last_modified,
+    ) {
+        // http dates have a one-second resolution
+        if truncate_to_secs(last_modified) <= ims {
+            return Evaluation::NotModified;
+        }
+    }
+
+    Evaluation::Proceed
+}
+
+/// Whether the `range` header should be honored, given `if-range`, cf.
+/// <https://httpwg.org/specs/rfc9110.html#field.if-range>
+pub fn if_range_matches(headers: &Headers, validators: &Validators) -> bool {
+    let Some(if_range) = headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    if if_range.starts_with(b"\"") || if_range.starts_with(b"W/") {
+        return validators
+            .etag
+            .is_some_and(|etag| strong_eq(if_range, etag.as_bytes()));
+    }
+    match (parse_http_date(if_range), validators.last_modified) {
+        (Some(date), Some(last_modified)) => truncate_to_secs(last_modified) == date,
+        _ => false,
+    }
+}
+
+/// Strong comparison: both tags are strong, and identical, cf.
+/// <https://httpwg.org/specs/rfc9110.html#entity.tag.comparison>
+pub fn strong_eq(a: &[u8], b: &[u8]) -> bool {
+    !a.starts_with(b"W/") && a == b
+}
+
+/// Weak comparison: tags are identical once their `W/` prefixes are removed
+pub fn weak_eq(a: &[u8], b: &[u8]) -> bool {
+    a.strip_prefix(b"W/").unwrap_or(a) == b.strip_prefix(b"W/").unwrap_or(b)
+}
+
+/// Whether an `if-match` or `if-none-match` value matches `etag`. `*`
+/// matches any current representation. A malformed list doesn't match
+/// anything.
+fn list_matches(list: &[u8], etag: Option<&str>, eq: fn(&[u8], &[u8]) -> bool) -> bool {
+    let Some(etag) = etag else {
+        return false;
+    };
+    if list.trim_ascii() == b"*" {
+        return true;
+    }
+    let Some(tags) = entity_tags(list) else {
+        return false;
+    };
+    tags.into_iter().any(|tag| eq(tag, etag.as_bytes()))
+}
+
+/// Splits a comma-separated list of entity tags. Tags are quoted and may
+/// contain commas themselves, so this can't just split on commas.
+fn entity_tags(mut input: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut tags = vec![];
+    loop {
+        input = input.trim_ascii_start();
+        while let Some(rest) = input.strip_prefix(b",") {
+            input = rest.trim_ascii_start();
+        }
+        if input.is_empty() {
+            return Some(tags);
+        }
+
+        let prefix = if input.starts_with(b"W/") { 2 } else { 0 };
+        if input.get(prefix) != Some(&b'"') {
+            return None;
+        }
+        let close = input[prefix + 1..].iter().position(|&b| b == b'"')?;
+        let end = prefix + 1 + close + 1;
+        tags.push(&input[..end]);
+        input = &input[end..];
+
+        let rest = input.trim_ascii_start();
+        if !rest.is_empty() && !rest.starts_with(b",") {
+            return None;
+        }
+    }
+}
+
+fn truncate_to_secs(t: SystemTime) -> SystemTime {
+    let secs = t
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    UNIX_EPOCH + std::time::Duration::from_secs(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use http::header;
+
+    use super::{
+        entity_tags, evaluate, if_range_matches, strong_eq, weak_eq, Evaluation, Validators,
+    };
+    use crate::{Headers, Method};
+
+    // Sun, 06 Nov 1994 08:49:37 GMT
+    const LAST_MODIFIED_SECS: u64 = 784111777;
+
+    fn validators() -> Validators<'static> {
+        Validators {
+            etag: Some("\"v2\""),
+            last_modified: Some(
+                UNIX_EPOCH + Duration::from_millis(LAST_MODIFIED_SECS * 1000 + 250),
+            ),
+        }
+    }
+
+    fn eval(method: Method, headers: &[(header::HeaderName, &'static str)]) -> Evaluation {
+        let mut map = Headers::default();
+        for (name, value) in headers {
+            map.append(name.clone(), (*value).into());
+        }
+        evaluate(&method, &map, &validators())
+    }
+
+    #[test]
+    fn test_comparison() {
+        assert!(strong_eq(b"\"1\"", b"\"1\""));
+        assert!(!strong_eq(b"W/\"1\"", b"W/\"1\""));
+        assert!(!strong_eq(b"\"1\"", b"\"2\""));
+        assert!(weak_eq(b"W/\"1\"", b"\"1\""));
+        assert!(weak_eq(b"W/\"1\"", b"W/\"1\""));
+        assert!(!weak_eq(b"W/\"1\"", b"\"2\""));
+
+        assert_eq!(
+            entity_tags(b" \"a,b\" , W/\"c\",,").unwrap(),
+            [&b"\"a,b\""[..], b"W/\"c\""]
+        );
+        assert_eq!(entity_tags(b"\"a\" x"), None);
+        assert_eq!(entity_tags(b"abc"), None);
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        use Evaluation::*;
+        let inm = header::IF_NONE_MATCH;
+
+        assert_eq!(eval(Method::Get, &[]), Proceed);
+        assert_eq!(
+            eval(Method::Get, &[(inm.clone(), "\"v1\", W/\"v2\"")]),
+            NotModified
+        );
+        assert_eq!(eval(Method::Head, &[(inm.clone(), "*")]), NotModified);
+        assert_eq!(eval(Method::Get, &[(inm.clone(), "\"v1\"")]), Proceed);
+        assert_eq!(eval(Method::Put, &[(inm.clone(), "*")]), PreconditionFailed);
+
+        // if-none-match wins over if-modified-since
+        assert_eq!(
+            eval(
+                Method::Get,
+                &[
+                    (inm, "\"v1\""),
+                    (header::IF_MODIFIED_SINCE, "Sun, 06 Nov 1994 08:49:37 GMT")
+                ]
+            ),
+            Proceed
+        );
+    }
+
+    #[test]
+    fn test_if_match() {
+        use Evaluation::*;
+        let im = header::IF_MATCH;
+
+        assert_eq!(eval(Method::Put, &[(im.clone(), "\"v2\"")]), Proceed);
+        assert_eq!(eval(Method::Put, &[(im.clone(), "*")]), Proceed);
+        // if-match uses the strong comparison
+        assert_eq!(
+            eval(Method::Put, &[(im.clone(), "W/\"v2\"")]),
+            PreconditionFailed
+        );
+        assert_eq!(
+            eval(Method::Get, &[(im.clone(), "\"v1\"")]),
+            PreconditionFailed
+        );
+
+        let mut headers = Headers::default();
+        headers.insert(im, "*".into());
+        let none = Validators::default();
+        assert_eq!(evaluate(&Method::Put, &headers, &none), PreconditionFailed);
+    }
+
+    #[test]
+    fn test_dates() {
+        use Evaluation::*;
+        let ims = header::IF_MODIFIED_SINCE;
+        let ius = header::IF_UNMODIFIED_SINCE;
+
+        assert_eq!(
+            eval(
+                Method::Get,
+                &[(ims.clone(), "Sun, 06 Nov 1994 08:49:37 GMT")]
+            ),
+            NotModified
+        );
+        assert_eq!(
+            eval(
+                Method::Get,
+                &[(ims.clone(), "Sun, 06 Nov 1994 08:49:36 GMT")]
+            ),
+            Proceed
+        );
+        // only for GET and HEAD
+        assert_eq!(
+            eval(
+                Method::Post,
+                &[(ims.clone(), "Sun, 06 Nov 1994 08:49:37 GMT")]
+            ),
+            Proceed
+        );
+        // invalid dates are ignored
+        assert_eq!(eval(Method::Get, &[(ims, "yesterday")]), Proceed);
+
+        assert_eq!(
+            eval(
+                Method::Put,
+                &[(ius.clone(), "Sun, 06 Nov 1994 08:49:36 GMT")]
+            ),
+            PreconditionFailed
+        );
+        assert_eq!(
+            eval(
+                Method::Put,
+                &[(ius.clone(), "Sun, 06 Nov 1994 08:49:37 GMT")]
+            ),
+            Proceed
+        );
+        // ignored when there's an if-match
+        assert_eq!(
+            eval(
+                Method::Put,
+                &[
+                    (header::IF_MATCH, "\"v2\""),
+                    (ius, "Sun, 06 Nov 1994 08:49:36 GMT")
+                ]
+            ),
+            Proceed
+        );
+    }
+
+    #[test]
+    fn test_if_range() {
+        let check = |value: &'static str| {
+            let mut headers = Headers::default();
+            headers.insert(header::IF_RANGE, value.into());
+            if_range_matches(&headers, &validators())
+        };
+        assert!(check("\"v2\""));
+        assert!(!check("W/\"v2\""));
+        assert!(!check("\"v1\""));
+        assert!(check("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(!check("Sun, 06 Nov 1994 08:49:38 GMT"));
+        assert!(if_range_matches(&Headers::default(), &validators()));
+    }
+}
@@ -0,0 +1,348 @@
+//! Keeps accept loops from taking on more than they can serve: a cap on
+//! concurrent connections, overall and per client address, and a limit on
+//! how fast connections are accepted.
+//!
+//! Share one [ConnLimiter] between the accept loops of a thread. Each loop
+//! awaits [ConnLimiter::throttle] before `accept`, so connections past the
+//! rate wait in the listen backlog, then asks [ConnLimiter::admit] about
+//! every connection it accepted, and closes the ones that are turned away
+//! without reading anything. Admitted connections count until their
+//! [ConnPermit] is dropped.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::metrics::{Counter, MetricsSink};
+
+/// What to limit. Everything is unlimited by default.
+#[derive(Debug, Clone)]
+pub struct ConnLimitConf {
+    /// Connections served at once, from all clients
+    pub max_connections: Option<usize>,
+
+    /// Connections served at once from a single client address
+    pub max_connections_per_ip: Option<usize>,
+
+    /// IPv6 clients usually get a whole /64: addresses that share this many
+    /// leading bits count as one for [Self::max_connections_per_ip]
+    pub ipv6_prefix_len: u8,
+
+    /// How many connections to accept per second, on average
+    pub accepts_per_sec: Option<f64>,
+
+    /// How many connections can be accepted in a row, after a quiet period,
+    /// before [Self::accepts_per_sec] kicks in
+    pub accept_burst: u32,
+}
+
+impl Default for ConnLimitConf {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            max_connections_per_ip: None,
+            ipv6_prefix_len: 64,
+            accepts_per_sec: None,
+            accept_burst: 64,
+        }
+    }
+}
+
+/// Why [ConnLimiter::admit] turned a connection away
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, thiserror::Error)]
+#[non_exhaustive]
+pub enum ConnRejection {
+    /// [ConnLimitConf::max_connections] are being served already
+    #[error("too many connections")]
+    MaxConnections,
+
+    /// [ConnLimitConf::max_connections_per_ip] are being served to that
+    /// client already
+    #[error("too many connections from this address")]
+    MaxConnectionsPerIp,
+}
+
+impl ConnRejection {
+    /// A stable name, for metric labels and logs
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConnRejection::MaxConnections => "max_connections",
+            ConnRejection::MaxConnectionsPerIp => "max_connections_per_ip",
+        }
+    }
+}
+
+/// Counters, cf. [ConnLimiter::stats]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnLimitStats {
+    /// Connections currently admitted
+    pub connections: usize,
+
+    /// Client addresses with connections currently admitted
+    pub clients: usize,
+
+    /// Connections turned away for [ConnRejection::MaxConnections]
+    pub rejected_max_connections: u64,
+
+    /// Connections turned away for [ConnRejection::MaxConnectionsPerIp]
+    pub rejected_max_connections_per_ip: u64,
+
+    /// Times [ConnLimiter::throttle] had to wait
+    pub throttled_total: u64,
+}
+
+/// Tracks the connections of the current thread, cf. the [module
+/// docs](self)
+pub struct ConnLimiter {
+    conf: ConnLimitConf,
+    metrics: Option<Rc<dyn MetricsSink>>,
+    state: RefCell<State>,
+}
+
+struct State {
+    connections: usize,
+    per_ip: HashMap<IpAddr, usize>,
+
+    /// Accepts we can do right away, refilled at `accepts_per_sec`
+    tokens: f64,
+    refilled_at: Instant,
+
+    stats: ConnLimitStats,
+}
+
+impl ConnLimiter {
+    /// Reports rejected connections to `metrics` as
+    /// [Counter::RejectedConnections]
+    pub fn new(conf: ConnLimitConf, metrics: Option<Rc<dyn MetricsSink>>) -> Self {
+        let state = State {
+            connections: 0,
+            per_ip: HashMap::new(),
+            tokens: conf.accept_burst.max(1) as f64,
+            refilled_at: Instant::now(),
+            stats: Default::default(),
+        };
+        Self {
+            conf,
+            metrics,
+            state: RefCell::new(state),
+        }
+    }
+
+    /// Waits until accepting another connection keeps us under
+    /// [ConnLimitConf::accepts_per_sec], and takes that accept out of the
+    /// budget: call it right before `accept`.
+    pub async fn throttle(&self) {
+        let Some(rate) = self.conf.accepts_per_sec.filter(|rate| *rate > 0.0) else {
+            return;
+        };
+        let burst = self.conf.accept_burst.max(1) as f64;
+
+        let mut waited = false;
+        loop {
+            let wait = {
+                let mut state = self.state.borrow_mut();
+                let now = Instant::now();
+                let elapsed = now - state.refilled_at;
+                state.tokens = (state.tokens + elapsed.as_secs_f64() * rate).min(burst);
+                state.refilled_at = now;
+                if state.tokens >= 1.0 {
+                    state.tokens -= 1.0;
+                    if waited {
+                        state.stats.throttled_total += 1;
+                    }
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - state.tokens) / rate)
+            };
+            waited = true;
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Counts a connection from `peer`, unless that's over one of the caps.
+    /// Connections without a known address only count toward
+    /// [ConnLimitConf::max_connections].
+    pub fn admit(self: &Rc<Self>, peer: Option<IpAddr>) -> Result<ConnPermit, ConnRejection> {
+        let key = peer.map(|ip| self.key(ip));
+        let mut state = self.state.borrow_mut();
+
+        let rejection = if self
+            .conf
+            .max_connections
+            .is_some_and(|max| state.connections >= max)
+        {
+            Some(ConnRejection::MaxConnections)
+        } else if let (Some(max), Some(key)) = (self.conf.max_connections_per_ip, key) {
+            let current = state.per_ip.get(&key).copied().unwrap_or_default();
+            (current >= max).then_some(ConnRejection::MaxConnectionsPerIp)
+        } else {
+            None
+        };
+        if let Some(rejection) = rejection {
+            match rejection {
+                ConnRejection::MaxConnections => state.stats.rejected_max_connections += 1,
+                ConnRejection::MaxConnectionsPerIp => {
+                    state.stats.rejected_max_connections_per_ip += 1
+                }
+            }
+            if let Some(metrics) = &self.metrics {
+                metrics.counter(Counter::RejectedConnections(rejection), 1);
+            }
+            return Err(rejection);
+        }
+
+        state.connections += 1;
+        if let Some(key) = key {
+            *state.per_ip.entry(key).or_default() += 1;
+        }
+        Ok(ConnPermit {
+            limiter: self.clone(),
+            key,
+        })
+    }
+
+    pub fn stats(&self) -> ConnLimitStats {
+        let state = self.state.borrow();
+        ConnLimitStats {
+            connections: state.connections,
+            clients: state.per_ip.len(),
+            ..state.stats
+        }
+    }
+
+    /// What `ip` counts as for [ConnLimitConf::max_connections_per_ip]
+    fn key(&self, ip: IpAddr) -> IpAddr {
+        match ip.to_canonical() {
+            IpAddr::V6(ip) => {
+                let prefix_len = self.conf.ipv6_prefix_len.min(128) as u32;
+                let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+            }
+            ip => ip,
+        }
+    }
+}
+
+/// An admitted connection's place in the [ConnLimiter]: gives it back when
+/// dropped
+pub struct ConnPermit {
+    limiter: Rc<ConnLimiter>,
+    key: Option<IpAddr>,
+}
+
+impl Drop for ConnPermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.borrow_mut();
+        state.connections -= 1;
+        if let Some(key) = self.key {
+            if let Some(count) = state.per_ip.get_mut(&key) {
+                *count -= 1;
+                if *count == 0 {
+                    state.per_ip.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        net::IpAddr,
+        rc::Rc,
+        time::{Duration, Instant},
+    };
+
+    use super::{ConnLimitConf, ConnLimiter, ConnRejection};
+    use crate::metrics::{Counter, Gauge, Histogram, MetricsSink};
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[derive(Default)]
+    struct Rejections(RefCell<Vec<Counter>>);
+
+    impl MetricsSink for Rejections {
+        fn counter(&self, counter: Counter, _n: u64) {
+            self.0.borrow_mut().push(counter);
+        }
+        fn gauge(&self, _gauge: Gauge, _delta: i64) {}
+        fn observe(&self, _histogram: Histogram, _value: f64) {}
+    }
+
+    #[test]
+    fn test_admit() {
+        let metrics = Rc::new(Rejections::default());
+        let limiter = Rc::new(ConnLimiter::new(
+            ConnLimitConf {
+                max_connections: Some(3),
+                max_connections_per_ip: Some(2),
+                ..Default::default()
+            },
+            Some(metrics.clone()),
+        ));
+
+        let a1 = limiter.admit(ip("192.0.2.1")).unwrap();
+        let _a2 = limiter.admit(ip("::ffff:192.0.2.1")).unwrap();
+        assert_eq!(
+            limiter.admit(ip("192.0.2.1")).err(),
+            Some(ConnRejection::MaxConnectionsPerIp)
+        );
+
+        // same /64
+        let b1 = limiter.admit(ip("2001:db8::1")).unwrap();
+        assert_eq!(
+            limiter.admit(ip("2001:db8::2")).err(),
+            Some(ConnRejection::MaxConnections)
+        );
+        let stats = limiter.stats();
+        assert_eq!((stats.connections, stats.clients), (3, 2));
+
+        drop(b1);
+        let _b2 = limiter.admit(ip("2001:db8::2")).unwrap();
+        drop(a1);
+        let _unknown = limiter.admit(None).unwrap();
+
+        let stats = limiter.stats();
+        assert_eq!((stats.connections, stats.clients), (3, 2));
+        assert_eq!(stats.rejected_max_connections, 1);
+        assert_eq!(stats.rejected_max_connections_per_ip, 1);
+        assert_eq!(
+            *metrics.0.borrow(),
+            vec![
+                Counter::RejectedConnections(ConnRejection::MaxConnectionsPerIp),
+                Counter::RejectedConnections(ConnRejection::MaxConnections),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_throttle() {
+        buffet::start(async move {
+            let limiter = ConnLimiter::new(
+                ConnLimitConf {
+                    accepts_per_sec: Some(100.0),
+                    accept_burst: 2,
+                    ..Default::default()
+                },
+                None,
+            );
+
+            let start = Instant::now();
+            limiter.throttle().await;
+            limiter.throttle().await;
+            assert_eq!(limiter.stats().throttled_total, 0);
+
+            // the burst is used up: 10ms until the next one
+            limiter.throttle().await;
+            assert!(start.elapsed() >= Duration::from_millis(9));
+            assert_eq!(limiter.stats().throttled_total, 1);
+        });
+    }
+}
@@ -0,0 +1,477 @@
+//! Groundwork for proxying UDP over HTTP/2, MASQUE-style (RFC 9298): a
+//! client sends an extended CONNECT request with `:protocol: connect-udp`,
+//! and once it's accepted, UDP payloads travel both ways on the stream as
+//! DATAGRAM capsules (RFC 9297).
+//!
+//! The HTTP/2 server only lets these requests through with
+//! [ServerConf::enable_connect_protocol](crate::h2::ServerConf::enable_connect_protocol).
+//! Drivers then check the request with [UdpTarget::from_request], and hand
+//! the stream to [relay] with something that implements [DatagramRelay],
+//! usually a connected UDP socket:
+//!
+//! ```ignore
+//! let Some(target) = UdpTarget::from_request(&req)? else {
+//!     return plain_connect(req, req_body, res).await;
+//! };
+//! let socket = connect_udp_socket(&target).await?;
+//! let res = res.write_final_response(connect_udp::response()).await?;
+//! Ok(connect_udp::relay(req_body, res, &socket).await?)
+//! ```
+
+use b_x::{BxForResults, BX};
+use buffet::Piece;
+use http::{header::HeaderName, StatusCode};
+use tracing::debug;
+
+use crate::{
+    urlencoded, Body, BodyChunk, Encoder, ExpectResponseBody, Method, Request, Responder,
+    ResponderError, Response, ResponseDone,
+};
+
+/// The `:protocol` of CONNECT-UDP requests
+pub const PROTOCOL: &str = "connect-udp";
+
+/// Where the default URI template of RFC 9298 puts the target, as in
+/// `/.well-known/masque/udp/{target_host}/{target_port}/`
+pub const PATH_PREFIX: &str = "/.well-known/masque/udp/";
+
+/// Sent by both sides to say the stream carries capsules (RFC 9297, section
+/// 3.4)
+pub static CAPSULE_PROTOCOL: HeaderName = HeaderName::from_static("capsule-protocol");
+
+/// The only capsule type [relay] acts on, others are skipped
+pub const DATAGRAM_CAPSULE: u64 = 0x00;
+
+/// The largest capsule [CapsuleDecoder] accepts by default: a UDP payload
+/// can't be larger than 64KiB anyway.
+pub const DEFAULT_MAX_CAPSULE_LEN: usize = 64 * 1024 + 16;
+
+/// The UDP target of a CONNECT-UDP request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpTarget {
+    /// A host name, or an IP address, without brackets for IPv6
+    pub host: String,
+    pub port: u16,
+}
+
+/// A CONNECT-UDP request whose target doesn't make sense
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum UdpTargetError {
+    #[error("path doesn't follow the CONNECT-UDP template: {0}")]
+    NotATemplatePath(String),
+
+    #[error("invalid target host")]
+    InvalidHost,
+
+    #[error("invalid target port")]
+    InvalidPort,
+}
+
+impl From<UdpTargetError> for BX {
+    fn from(e: UdpTargetError) -> Self {
+        BX::from_err(e)
+    }
+}
+
+impl UdpTarget {
+    /// Returns `None` if `req` isn't a CONNECT-UDP request
+    pub fn from_request(req: &Request) -> Result<Option<Self>, UdpTargetError> {
+        if req.method != Method::Connect || req.protocol.as_deref() != Some(PROTOCOL) {
+            return Ok(None);
+        }
+        Self::from_path(req.uri.path()).map(Some)
+    }
+
+    /// Parses a path that follows the default URI template of RFC 9298,
+    /// section 2. IPv6 addresses have their colons percent-encoded.
+    pub fn from_path(path: &str) -> Result<Self, UdpTargetError> {
+        let rest = path
+            .strip_prefix(PATH_PREFIX)
+            .ok_or_else(|| UdpTargetError::NotATemplatePath(path.to_string()))?;
+        let rest = rest.strip_suffix('/').unwrap_or(rest);
+        let (host, port) = rest
+            .split_once('/')
+            .ok_or_else(|| UdpTargetError::NotATemplatePath(path.to_string()))?;
+
+        let host = String::from_utf8(urlencoded::decode(host.as_bytes()).into_owned())
+            .map_err(|_| UdpTargetError::InvalidHost)?;
+        if host.is_empty() || host.contains(['/', ' ']) {
+            return Err(UdpTargetError::InvalidHost);
+        }
+
+        let port = match port.parse::<u16>() {
+            Ok(0) | Err(_) => return Err(UdpTargetError::InvalidPort),
+            Ok(port) => port,
+        };
+        Ok(Self { host, port })
+    }
+}
+
+/// A `200 OK` that accepts a CONNECT-UDP request. It has no
+/// `content-length`: the response body is the capsule stream.
+pub fn response() -> Response {
+    let mut res = Response {
+        status: StatusCode::OK,
+        ..Default::default()
+    };
+    res.headers.insert(CAPSULE_PROTOCOL.clone(), "?1".into());
+    res
+}
+
+/// A capsule, as in RFC 9297, section 3.2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capsule {
+    pub capsule_type: u64,
+    pub value: Vec<u8>,
+}
+
+/// A capsule stream went wrong
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum CapsuleError {
+    #[error("capsule of {len} bytes exceeds the limit of {max}")]
+    TooLarge { len: u64, max: usize },
+
+    #[error("stream ended in the middle of a capsule")]
+    Truncated,
+
+    #[error("DATAGRAM capsule without a context ID")]
+    MissingContextId,
+}
+
+impl Capsule {
+    /// A DATAGRAM capsule carrying a UDP payload, with context ID 0
+    pub fn datagram(payload: &[u8]) -> Self {
+        let mut value = Vec::with_capacity(payload.len() + 1);
+        encode_varint(&mut value, 0);
+        value.extend_from_slice(payload);
+        Self {
+            capsule_type: DATAGRAM_CAPSULE,
+            value,
+        }
+    }
+
+    /// For DATAGRAM capsules, the context ID and the rest of the payload.
+    /// Context ID 0 is a UDP payload, others are for extensions.
+    pub fn context(&self) -> Result<Option<(u64, &[u8])>, CapsuleError> {
+        if self.capsule_type != DATAGRAM_CAPSULE {
+            return Ok(None);
+        }
+        match decode_varint(&self.value) {
+            Some((context_id, len)) => Ok(Some((context_id, &self.value[len..]))),
+            None => Err(CapsuleError::MissingContextId),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.value.len() + 16);
+        encode_varint(&mut out, self.capsule_type);
+        encode_varint(&mut out, self.value.len() as u64);
+        out.extend_from_slice(&self.value);
+        out
+    }
+}
+
+/// Reassembles capsules from a stream split in arbitrary places
+#[derive(Debug)]
+pub struct CapsuleDecoder {
+    buf: Vec<u8>,
+    max_len: usize,
+}
+
+impl Default for CapsuleDecoder {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_CAPSULE_LEN)
+    }
+}
+
+impl CapsuleDecoder {
+    /// Capsules whose value is longer than `max_len` are an error
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            max_len,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The next complete capsule, if there's one
+    pub fn next_capsule(&mut self) -> Result<Option<Capsule>, CapsuleError> {
+        let Some((capsule_type, type_len)) = decode_varint(&self.buf) else {
+            return Ok(None);
+        };
+        let Some((len, len_len)) = decode_varint(&self.buf[type_len..]) else {
+            return Ok(None);
+        };
+        if len > self.max_len as u64 {
+            return Err(CapsuleError::TooLarge {
+                len,
+                max: self.max_len,
+            });
+        }
+
+        let start = type_len + len_len;
+        let end = start + len as usize;
+        if self.buf.len() < end {
+            return Ok(None);
+        }
+        let value = self.buf[start..end].to_vec();
+        self.buf.drain(..end);
+        Ok(Some(Capsule {
+            capsule_type,
+            value,
+        }))
+    }
+
+    /// Call once the stream is over: errors out if it stopped in the middle
+    /// of a capsule
+    pub fn finish(&self) -> Result<(), CapsuleError> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(CapsuleError::Truncated)
+        }
+    }
+}
+
+/// Encodes a QUIC variable-length integer (RFC 9000, section 16), which
+/// capsules use for their type and length
+fn encode_varint(out: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0x3f => out.push(value as u8),
+        0x40..=0x3fff => out.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => out.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes()),
+        _ => {
+            assert!(value < 1 << 62, "varint out of range: {value}");
+            out.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes())
+        }
+    }
+}
+
+/// Decodes a QUIC variable-length integer, returns it and how many bytes it
+/// took, or `None` if `buf` doesn't hold all of it yet
+fn decode_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let len = 1 << (first >> 6);
+    let bytes = buf.get(..len)?;
+    let mut value = (first & 0x3f) as u64;
+    for &b in &bytes[1..] {
+        value = (value << 8) | b as u64;
+    }
+    Some((value, len))
+}
+
+/// Where [relay] sends the UDP payloads it gets from the client, and gets
+/// the ones it sends back. Both can be called at the same time.
+#[allow(async_fn_in_trait)] // we never require Send
+pub trait DatagramRelay {
+    /// Sends a UDP payload to the target
+    async fn send_datagram(&self, payload: Piece) -> Result<(), BX>;
+
+    /// The next UDP payload from the target, `None` once there won't be any
+    /// more
+    async fn recv_datagram(&self) -> Result<Option<Piece>, BX>;
+}
+
+/// Relaying datagrams failed
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum RelayError<EncoderError> {
+    /// Writing capsules to the client failed
+    #[error("Error writing capsules downstream: {0}")]
+    Downstream(#[source] ResponderError<EncoderError>),
+
+    /// Reading capsules from the client failed
+    #[error("Error reading from downstream: {0}")]
+    DownstreamBody(#[source] BX),
+
+    /// The client sent something that isn't a valid capsule stream
+    #[error("Invalid capsule stream: {0}")]
+    Capsule(#[from] CapsuleError),
+
+    /// The [DatagramRelay] failed
+    #[error("Error relaying datagrams: {0}")]
+    Upstream(#[source] BX),
+}
+
+impl<EncoderError> From<RelayError<EncoderError>> for BX
+where
+    EncoderError: std::error::Error + 'static,
+{
+    fn from(e: RelayError<EncoderError>) -> Self {
+        BX::from_err(e)
+    }
+}
+
+/// Relays UDP payloads between the client of an accepted CONNECT-UDP
+/// request and `datagrams`, until either side is done: UDP has no half-close,
+/// so when the client ends its side of the stream, so does the response, and
+/// the other way around.
+///
+/// Capsules other than DATAGRAM, and DATAGRAM capsules with a context ID
+/// other than 0, are skipped, as RFC 9297 and RFC 9298 ask.
+pub async fn relay<E>(
+    req_body: &mut impl Body,
+    mut respond: Responder<E, ExpectResponseBody>,
+    datagrams: &impl DatagramRelay,
+) -> Result<Responder<E, ResponseDone>, RelayError<E::Error>>
+where
+    E: Encoder,
+{
+    let downstream_to_upstream = async {
+        let mut decoder = CapsuleDecoder::default();
+        loop {
+            match req_body
+                .next_chunk()
+                .await
+                .bx()
+                .map_err(RelayError::DownstreamBody)?
+            {
+                BodyChunk::Chunk(chunk) => decoder.push(&chunk[..]),
+                BodyChunk::File { .. } => {
+                    return Err(RelayError::DownstreamBody(BX::from_string(
+                        "unexpected file chunk in a request body".into(),
+                    )))
+                }
+                BodyChunk::Done { .. } => break,
+            }
+
+            while let Some(capsule) = decoder.next_capsule()? {
+                match capsule.context()? {
+                    Some((0, payload)) => datagrams
+                        .send_datagram(payload.to_vec().into())
+                        .await
+                        .map_err(RelayError::Upstream)?,
+                    _ => debug!(
+                        "skipping capsule of type {:#x} ({} bytes)",
+                        capsule.capsule_type,
+                        capsule.value.len()
+                    ),
+                }
+            }
+        }
+        decoder.finish()?;
+        debug!("downstream closed its side of the CONNECT-UDP stream");
+        Ok::<_, RelayError<E::Error>>(())
+    };
+
+    let upstream_to_downstream = async {
+        while let Some(payload) = datagrams
+            .recv_datagram()
+            .await
+            .map_err(RelayError::Upstream)?
+        {
+            respond
+                .write_chunk(Capsule::datagram(&payload).encode().into())
+                .await
+                .map_err(RelayError::Downstream)?;
+        }
+        debug!("upstream is done sending datagrams");
+        Ok::<_, RelayError<E::Error>>(())
+    };
+
+    tokio::select! {
+        res = downstream_to_upstream => res?,
+        res = upstream_to_downstream => res?,
+    }
+
+    respond
+        .finish_body(None)
+        .await
+        .map_err(RelayError::Downstream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_varint, encode_varint, Capsule, CapsuleDecoder, CapsuleError, UdpTarget};
+
+    #[test]
+    fn test_varint() {
+        // examples from RFC 9000, appendix A.1
+        for (value, encoded) in [
+            (
+                151_288_809_941_952_652,
+                &[0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c][..],
+            ),
+            (494_878_333, &[0x9d, 0x7f, 0x3e, 0x7d]),
+            (15_293, &[0x7b, 0xbd]),
+            (37, &[0x25]),
+        ] {
+            let mut out = Vec::new();
+            encode_varint(&mut out, value);
+            assert_eq!(out, encoded);
+            assert_eq!(decode_varint(encoded), Some((value, encoded.len())));
+            assert_eq!(decode_varint(&encoded[..encoded.len() - 1]), None);
+        }
+    }
+
+    #[test]
+    fn test_capsule_decoder() {
+        let datagram = Capsule::datagram(b"hello");
+        let unknown = Capsule {
+            capsule_type: 0x2a,
+            value: vec![7; 100],
+        };
+        let mut stream = datagram.encode();
+        stream.extend_from_slice(&unknown.encode());
+
+        // one byte at a time
+        let mut decoder = CapsuleDecoder::default();
+        let mut decoded = vec![];
+        for b in &stream {
+            decoder.push(&[*b]);
+            while let Some(capsule) = decoder.next_capsule().unwrap() {
+                decoded.push(capsule);
+            }
+        }
+        decoder.finish().unwrap();
+        assert_eq!(decoded, [datagram.clone(), unknown.clone()]);
+        assert_eq!(decoded[0].context().unwrap(), Some((0, &b"hello"[..])));
+        assert_eq!(decoded[1].context().unwrap(), None);
+
+        let mut decoder = CapsuleDecoder::default();
+        decoder.push(&stream[..3]);
+        assert!(decoder.next_capsule().unwrap().is_none());
+        assert!(matches!(decoder.finish(), Err(CapsuleError::Truncated)));
+
+        let mut decoder = CapsuleDecoder::new(16);
+        decoder.push(&unknown.encode());
+        assert!(matches!(
+            decoder.next_capsule(),
+            Err(CapsuleError::TooLarge { len: 100, max: 16 })
+        ));
+    }
+
+    #[test]
+    fn test_udp_target() {
+        let target = |path| UdpTarget::from_path(path);
+        assert_eq!(
+            target("/.well-known/masque/udp/192.0.2.6/443/").unwrap(),
+            UdpTarget {
+                host: "192.0.2.6".into(),
+                port: 443
+            }
+        );
+        assert_eq!(
+            target("/.well-known/masque/udp/2001%3Adb8%3A%3A42/53/").unwrap(),
+            UdpTarget {
+                host: "2001:db8::42".into(),
+                port: 53
+            }
+        );
+        assert_eq!(
+            target("/.well-known/masque/udp/example.org/53")
+                .unwrap()
+                .host,
+            "example.org"
+        );
+        assert!(target("/elsewhere/example.org/53/").is_err());
+        assert!(target("/.well-known/masque/udp/example.org/").is_err());
+        assert!(target("/.well-known/masque/udp/example.org/0/").is_err());
+        assert!(target("/.well-known/masque/udp//53/").is_err());
+    }
+}
@@ -2,6 +2,8 @@ use std::error::Error as StdError;
 use std::fmt;
 
 use b_x::BX;
+use http::StatusCode;
+use loona_h2::KnownErrorCode;
 
 use crate::h2::types::H2ConnectionError;
 
@@ -30,6 +32,209 @@ pub enum ServeError<DriverError> {
     Alloc(#[from] buffet::bufpool::Error),
 }
 
+impl<DriverError> ServeError<DriverError> {
+    /// Where this error fits in the [ConnectionError] taxonomy
+    pub fn kind(&self) -> ConnectionError {
+        match self {
+            ServeError::DownstreamWrite(e) => ConnectionError::from_io(e),
+            ServeError::Driver(_) => ConnectionError::HandlerFailed,
+            ServeError::ResponseHandlerBodyNotDrained => ConnectionError::Io,
+            ServeError::H2ConnectionError(e) => e.kind(),
+            ServeError::Alloc(_) => ConnectionError::MemoryPressure,
+        }
+    }
+}
+
+/// Why a connection failed (or, on HTTP/1.1, why a request was turned away),
+/// whatever the protocol. Metrics, the protocol error log, HTTP/2 GOAWAY
+/// codes and HTTP/1.1 canned responses all go by this.
+///
+/// Discriminants are stable: they're grouped by [ConnectionErrorCategory],
+/// a hundred apart, and never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, thiserror::Error)]
+#[non_exhaustive]
+#[repr(u16)]
+pub enum ConnectionError {
+    /// HTTP/1.1 request line or headers that don't parse
+    #[error("malformed request line or headers")]
+    MalformedRequest = 100,
+
+    /// HTTP/2 frames that don't parse, or are cut short
+    #[error("malformed frame")]
+    MalformedFrame = 101,
+
+    /// A request line for a version other than HTTP/1.0 or HTTP/1.1, e.g.
+    /// `HTTP/2.0`
+    #[error("unsupported HTTP version")]
+    UnsupportedVersion = 102,
+
+    /// A `content-length` that isn't a number, several that disagree, or one
+    /// alongside a `transfer-encoding`, cf.
+    /// <https://httpwg.org/specs/rfc9112.html#body.content-length>
+    #[error("invalid content-length")]
+    InvalidContentLength = 103,
+
+    /// A `transfer-encoding` other than just `chunked`, the only coding we
+    /// decode, cf. <https://httpwg.org/specs/rfc9112.html#field.transfer-encoding>
+    #[error("unsupported transfer-coding")]
+    UnsupportedTransferCoding = 104,
+
+    /// A header block HPACK couldn't decode
+    #[error("header compression error")]
+    HeaderCompression = 105,
+
+    /// Anything the peer did that the protocol forbids, and isn't covered
+    /// by a more specific variant
+    #[error("protocol violation")]
+    ProtocolViolation = 200,
+
+    /// A frame of the wrong size for its type, or over our limits
+    #[error("invalid frame size")]
+    FrameSize = 201,
+
+    /// The peer violated flow control
+    #[error("flow control error")]
+    FlowControl = 202,
+
+    /// A frame for a stream that was already closed
+    #[error("frame on closed stream")]
+    StreamClosed = 203,
+
+    /// A request asking to switch the connection to TLS, which we don't do,
+    /// cf. [crate::h1::TlsUpgradeRequests]
+    #[error("TLS upgrade refused")]
+    TlsUpgrade = 204,
+
+    /// The peer took too long
+    #[error("timed out")]
+    Timeout = 300,
+
+    /// Request headers over our limits
+    #[error("request headers too large")]
+    HeadersTooLarge = 400,
+
+    /// The buffer pool was running low, cf. [crate::pressure]
+    #[error("server is low on memory")]
+    MemoryPressure = 401,
+
+    /// The connection was idle while the process was running out of file
+    /// descriptors, cf. [crate::fd_budget]
+    #[error("closed idle connection to free up file descriptors")]
+    PrunedWhileIdle = 402,
+
+    /// Reading from or writing to the peer failed
+    #[error("I/O error")]
+    Io = 500,
+
+    /// The driver's handler panicked or returned an error
+    #[error("handler failed")]
+    HandlerFailed = 600,
+}
+
+/// Groups of [ConnectionError]s
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ConnectionErrorCategory {
+    /// The peer sent something we couldn't make sense of
+    Parse,
+    /// The peer sent something that made sense, but wasn't allowed
+    Protocol,
+    Timeout,
+    /// The peer, or the server as a whole, went over a limit
+    ResourceLimit,
+    Io,
+    /// The server's fault
+    Internal,
+}
+
+impl ConnectionError {
+    /// The stable discriminant, e.g. `100` for [ConnectionError::MalformedRequest]
+    pub fn code(&self) -> u16 {
+        *self as u16
+    }
+
+    /// A stable snake_case name, for metric labels and logs
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConnectionError::MalformedRequest => "malformed_request",
+            ConnectionError::MalformedFrame => "malformed_frame",
+            ConnectionError::UnsupportedVersion => "unsupported_version",
+            ConnectionError::InvalidContentLength => "invalid_content_length",
+            ConnectionError::UnsupportedTransferCoding => "unsupported_transfer_coding",
+            ConnectionError::HeaderCompression => "header_compression",
+            ConnectionError::ProtocolViolation => "protocol_violation",
+            ConnectionError::FrameSize => "frame_size",
+            ConnectionError::FlowControl => "flow_control",
+            ConnectionError::StreamClosed => "stream_closed",
+            ConnectionError::TlsUpgrade => "tls_upgrade",
+            ConnectionError::Timeout => "timeout",
+            ConnectionError::HeadersTooLarge => "headers_too_large",
+            ConnectionError::MemoryPressure => "memory_pressure",
+            ConnectionError::PrunedWhileIdle => "pruned_while_idle",
+            ConnectionError::Io => "io",
+            ConnectionError::HandlerFailed => "handler_failed",
+        }
+    }
+
+    pub fn category(&self) -> ConnectionErrorCategory {
+        match self.code() / 100 {
+            1 => ConnectionErrorCategory::Parse,
+            2 => ConnectionErrorCategory::Protocol,
+            3 => ConnectionErrorCategory::Timeout,
+            4 => ConnectionErrorCategory::ResourceLimit,
+            5 => ConnectionErrorCategory::Io,
+            _ => ConnectionErrorCategory::Internal,
+        }
+    }
+
+    /// The status of the canned response HTTP/1.1 sends for it
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ConnectionError::UnsupportedVersion => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            ConnectionError::UnsupportedTransferCoding => StatusCode::NOT_IMPLEMENTED,
+            ConnectionError::Timeout => StatusCode::REQUEST_TIMEOUT,
+            ConnectionError::HeadersTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            ConnectionError::MemoryPressure | ConnectionError::PrunedWhileIdle => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ConnectionError::Io | ConnectionError::HandlerFailed => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// The error code HTTP/2 sends in GOAWAY for it, cf.
+    /// <https://httpwg.org/specs/rfc9113.html#ErrorCodes>
+    pub fn h2_error_code(&self) -> u32 {
+        self.as_known_error_code().repr()
+    }
+
+    pub(crate) fn as_known_error_code(&self) -> KnownErrorCode {
+        match self {
+            ConnectionError::FrameSize => KnownErrorCode::FrameSizeError,
+            ConnectionError::FlowControl => KnownErrorCode::FlowControlError,
+            ConnectionError::HeaderCompression => KnownErrorCode::CompressionError,
+            // we didn't decode the block, so our HPACK state is out of sync
+            ConnectionError::HeadersTooLarge => KnownErrorCode::CompressionError,
+            ConnectionError::StreamClosed => KnownErrorCode::StreamClosed,
+            // not the peer's fault, we're just turning it away
+            ConnectionError::Timeout
+            | ConnectionError::MemoryPressure
+            | ConnectionError::PrunedWhileIdle => KnownErrorCode::NoError,
+            ConnectionError::Io | ConnectionError::HandlerFailed => KnownErrorCode::InternalError,
+            _ => KnownErrorCode::ProtocolError,
+        }
+    }
+
+    pub(crate) fn from_io(e: &std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::TimedOut => ConnectionError::Timeout,
+            _ => ConnectionError::Io,
+        }
+    }
+}
+
 impl<DriverError> From<ServeError<DriverError>> for BX
 where
     DriverError: std::error::Error + 'static,
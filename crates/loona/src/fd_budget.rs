@@ -0,0 +1,333 @@
+//! Keeps the process under its file descriptor limit: rather than having
+//! `accept` fail with `EMFILE` once every descriptor is taken, the oldest
+//! idle connections are closed as the limit gets close.
+//!
+//! Share one [FdBudget] between the [crate::h1::ServerConf] and
+//! [crate::h2::ServerConf] of a thread, and call [FdBudget::check] from the
+//! accept loop. Idle connections are HTTP/1.1 connections waiting for their
+//! next request, and HTTP/2 connections without any open stream. Asked to
+//! close, HTTP/1.1 connections just hang up, and HTTP/2 connections send a
+//! GOAWAY with `NO_ERROR` first.
+//!
+//! Descriptors are counted from `/proc/self/fd`, so this only does anything
+//! on Linux.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Notify;
+
+/// How close to the limit to get before closing idle connections
+#[derive(Debug, Clone)]
+pub struct FdBudgetConf {
+    /// How many descriptors the process may have open. `None` reads the soft
+    /// `RLIMIT_NOFILE` from `/proc/self/limits`.
+    pub limit: Option<usize>,
+
+    /// Idle connections start getting closed once this fraction of the
+    /// limit is in use
+    pub prune_ratio: f64,
+
+    /// ...and keep getting closed until we're back under this fraction
+    pub target_ratio: f64,
+
+    /// Counting descriptors means listing `/proc/self/fd`: in between,
+    /// [FdBudget::check] goes by the last count
+    pub recount_interval: Duration,
+}
+
+impl Default for FdBudgetConf {
+    fn default() -> Self {
+        Self {
+            limit: None,
+            prune_ratio: 0.9,
+            target_ratio: 0.8,
+            recount_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Counters, cf. [FdBudget::stats]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FdBudgetStats {
+    /// The descriptor limit we go by, if we know it
+    pub limit: Option<usize>,
+
+    /// Open descriptors, as of the last count
+    pub open_fds: Option<usize>,
+
+    /// Connections currently tracked
+    pub connections: usize,
+
+    /// Tracked connections that are idle
+    pub idle_connections: usize,
+
+    /// Idle connections asked to close so far
+    pub pruned_total: u64,
+}
+
+/// Tracks idle connections of the current thread, cf. the [module
+/// docs](self)
+pub struct FdBudget {
+    conf: FdBudgetConf,
+    limit: Option<usize>,
+    state: RefCell<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Bumped every time a connection goes idle: the lowest key is the
+    /// connection that's been idle the longest
+    next_seq: u64,
+    idle: BTreeMap<u64, Rc<Signal>>,
+
+    open_fds: Option<usize>,
+    counted_at: Option<Instant>,
+
+    connections: usize,
+    pruned_total: u64,
+}
+
+#[derive(Default)]
+struct Signal {
+    requested: Cell<bool>,
+    notify: Notify,
+}
+
+impl FdBudget {
+    pub fn new(conf: FdBudgetConf) -> Self {
+        let limit = conf.limit.or_else(soft_fd_limit);
+        Self {
+            conf,
+            limit,
+            state: Default::default(),
+        }
+    }
+
+    /// Starts tracking a connection, which is busy until it says otherwise.
+    pub(crate) fn track(self: &Rc<Self>) -> IdleTracker {
+        self.state.borrow_mut().connections += 1;
+        IdleTracker {
+            budget: self.clone(),
+            idle_seq: Cell::new(None),
+            signal: Default::default(),
+        }
+    }
+
+    /// Closes the oldest idle connections if we're over
+    /// [FdBudgetConf::prune_ratio] of the limit. Meant to be called before or
+    /// after each accept. Returns how many connections were asked to close.
+    pub fn check(&self) -> usize {
+        let Some(limit) = self.limit else {
+            return 0;
+        };
+
+        let open = {
+            let mut state = self.state.borrow_mut();
+            let now = Instant::now();
+            let stale = state
+                .counted_at
+                .map_or(true, |at| now - at >= self.conf.recount_interval);
+            if stale {
+                state.open_fds = count_open_fds();
+                state.counted_at = Some(now);
+            }
+            match state.open_fds {
+                Some(open) => open,
+                None => return 0,
+            }
+        };
+
+        if (open as f64) < limit as f64 * self.conf.prune_ratio {
+            return 0;
+        }
+        let target = (limit as f64 * self.conf.target_ratio) as usize;
+        let pruned = self.prune_oldest(open.saturating_sub(target).max(1));
+        if pruned > 0 {
+            tracing::debug!(%open, %limit, %pruned, "running out of file descriptors, closed idle connections");
+        }
+        pruned
+    }
+
+    /// Asks up to `n` idle connections to close, oldest first, whatever the
+    /// descriptor count. For when `accept` failed with `EMFILE` or `ENFILE`
+    /// anyway. Returns how many connections were asked to close.
+    pub fn prune_oldest(&self, n: usize) -> usize {
+        let mut state = self.state.borrow_mut();
+        let mut pruned = 0;
+        while pruned < n {
+            let Some((_, signal)) = state.idle.pop_first() else {
+                break;
+            };
+            signal.requested.set(true);
+            signal.notify.notify_waiters();
+            pruned += 1;
+        }
+        state.pruned_total += pruned as u64;
+        // those descriptors are about to be released, don't count them again
+        // until the next recount
+        if let Some(open) = &mut state.open_fds {
+            *open = open.saturating_sub(pruned);
+        }
+        pruned
+    }
+
+    pub fn stats(&self) -> FdBudgetStats {
+        let state = self.state.borrow();
+        FdBudgetStats {
+            limit: self.limit,
+            open_fds: state.open_fds,
+            connections: state.connections,
+            idle_connections: state.idle.len(),
+            pruned_total: state.pruned_total,
+        }
+    }
+}
+
+/// A connection's handle on the [FdBudget]: stops tracking it when dropped.
+pub(crate) struct IdleTracker {
+    budget: Rc<FdBudget>,
+    idle_seq: Cell<Option<u64>>,
+    signal: Rc<Signal>,
+}
+
+impl IdleTracker {
+    /// Marks the connection idle (and eligible for pruning) or busy. Going
+    /// busy forgets about any pending request to close.
+    pub(crate) fn set_idle(&self, idle: bool) {
+        if idle == self.idle_seq.get().is_some() {
+            return;
+        }
+        let mut state = self.budget.state.borrow_mut();
+        if idle {
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.idle.insert(seq, self.signal.clone());
+            self.idle_seq.set(Some(seq));
+        } else {
+            if let Some(seq) = self.idle_seq.take() {
+                state.idle.remove(&seq);
+            }
+            self.signal.requested.set(false);
+        }
+    }
+
+    /// Resolves once the budget asks this connection to close.
+    pub(crate) async fn pruned(&self) {
+        loop {
+            if self.signal.requested.get() {
+                return;
+            }
+            self.signal.notify.notified().await;
+        }
+    }
+}
+
+impl Drop for IdleTracker {
+    fn drop(&mut self) {
+        let mut state = self.budget.state.borrow_mut();
+        if let Some(seq) = self.idle_seq.take() {
+            state.idle.remove(&seq);
+        }
+        state.connections -= 1;
+    }
+}
+
+/// Resolves when `tracker` is asked to close, never if there's no tracker
+pub(crate) async fn pruned(tracker: Option<&IdleTracker>) {
+    match tracker {
+        Some(tracker) => tracker.pruned().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn count_open_fds() -> Option<usize> {
+    let dir = std::fs::read_dir("/proc/self/fd").ok()?;
+    // listing the directory takes a descriptor of its own
+    Some(dir.count().saturating_sub(1))
+}
+
+fn soft_fd_limit() -> Option<usize> {
+    parse_soft_fd_limit(&std::fs::read_to_string("/proc/self/limits").ok()?)
+}
+
+fn parse_soft_fd_limit(limits: &str) -> Option<usize> {
+    let line = limits.lines().find(|l| l.starts_with("Max open files"))?;
+    // "Max open files  <soft>  <hard>  files", soft may be "unlimited"
+    line["Max open files".len()..]
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{parse_soft_fd_limit, FdBudget, FdBudgetConf};
+
+    #[test]
+    fn test_prune_oldest_idle() {
+        let budget = Rc::new(FdBudget::new(FdBudgetConf {
+            limit: Some(usize::MAX / 2),
+            ..Default::default()
+        }));
+        let conns: Vec<_> = (0..4).map(|_| budget.track()).collect();
+        conns[2].set_idle(true);
+        conns[0].set_idle(true);
+        conns[3].set_idle(true);
+        conns[3].set_idle(false);
+
+        // plenty of room
+        assert_eq!(budget.check(), 0);
+        let stats = budget.stats();
+        assert_eq!((stats.connections, stats.idle_connections), (4, 2));
+        assert!(stats.open_fds.unwrap() > 0);
+
+        assert_eq!(budget.prune_oldest(1), 1);
+        assert!(conns[2].signal.requested.get());
+        assert!(!conns[0].signal.requested.get());
+
+        // going busy again cancels the request
+        conns[2].set_idle(false);
+        assert!(!conns[2].signal.requested.get());
+
+        drop(conns);
+        let stats = budget.stats();
+        assert_eq!((stats.connections, stats.idle_connections), (0, 0));
+        assert_eq!(stats.pruned_total, 1);
+    }
+
+    #[test]
+    fn test_check_over_limit() {
+        // stdin, stdout and stderr alone are over that
+        let budget = Rc::new(FdBudget::new(FdBudgetConf {
+            limit: Some(2),
+            ..Default::default()
+        }));
+        let idle: Vec<_> = (0..3).map(|_| budget.track()).collect();
+        for conn in &idle {
+            conn.set_idle(true);
+        }
+        let busy = budget.track();
+
+        assert!(budget.check() > 0);
+        assert!(idle[0].signal.requested.get());
+        assert!(!busy.signal.requested.get());
+    }
+
+    #[test]
+    fn test_soft_limit() {
+        let limits = "Limit                     Soft Limit           Hard Limit           Units     \n\
+                      Max open files            1024                 524288               files     \n";
+        assert_eq!(parse_soft_fd_limit(limits), Some(1024));
+        let limits =
+            "Max open files            unlimited            unlimited            files     \n";
+        assert_eq!(parse_soft_fd_limit(limits), None);
+    }
+}
@@ -0,0 +1,563 @@
+//! Who a request is really from when it came through proxies, going by the
+//! `Forwarded` header (<https://www.rfc-editor.org/rfc/rfc7239>) or the
+//! legacy `x-forwarded-for`, `x-forwarded-proto` and `x-forwarded-host`.
+//!
+//! Any client can send those headers, so they're only believed as far as
+//! the chain of proxies is trusted: [resolve] walks the hops back from the
+//! peer, and stops at the first address that isn't in [TrustedProxies]. A
+//! request straight from an untrusted peer gets the peer's address, and
+//! whatever its headers say is ignored.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use http::{header, uri::Scheme, HeaderName};
+
+use crate::{Headers, Request};
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+static X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+static X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ForwardedError {
+    #[error("malformed Forwarded header: {0}")]
+    Malformed(&'static str),
+
+    /// Not an address, or an address and prefix length, cf. [IpNet]
+    #[error("invalid address range: {0:?}")]
+    InvalidNet(String),
+}
+
+/// A range of addresses, e.g. `10.0.0.0/8` or `2001:db8::/32`. A lone
+/// address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Fails if `prefix_len` is longer than the address. Bits of `addr`
+    /// past the prefix are ignored.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, ForwardedError> {
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > bits {
+            return Err(ForwardedError::InvalidNet(format!("{addr}/{prefix_len}")));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // an IPv4 client of a dual-stack socket shows up as `::ffff:a.b.c.d`
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let (bytes, bits) = ((prefix_len / 8) as usize, prefix_len % 8);
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    bits == 0 || (a[bytes] ^ b[bytes]) >> (8 - bits) == 0
+}
+
+impl FromStr for IpNet {
+    type Err = ForwardedError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ForwardedError::InvalidNet(s.to_owned());
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) if len.bytes().all(|b| b.is_ascii_digit()) => {
+                (addr, Some(len.parse().map_err(|_| invalid())?))
+            }
+            Some(_) => return Err(invalid()),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix_len = prefix_len.unwrap_or(if addr.is_ipv4() { 32 } else { 128 });
+        Self::new(addr, prefix_len).map_err(|_| invalid())
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// The proxies whose forwarding headers are believed
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    pub nets: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// A node of a `Forwarded` element, e.g. the `for` of `for=192.0.2.60`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    /// The port is `None` if absent or obfuscated
+    Addr { ip: IpAddr, port: Option<u16> },
+
+    /// The proxy doesn't know
+    Unknown,
+
+    /// An identifier that's meaningful to the proxy only, e.g. `_hidden`
+    Obfuscated(String),
+}
+
+/// One element of a `Forwarded` header: what one proxy saw of the request
+/// it forwarded
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardedElement {
+    /// Who the proxy got the request from
+    pub for_node: Option<Node>,
+
+    /// The proxy itself
+    pub by: Option<Node>,
+
+    /// The scheme the proxy got the request over
+    pub proto: Option<String>,
+
+    /// The `host` the proxy got the request with
+    pub host: Option<String>,
+}
+
+/// Parses a `Forwarded` header value, a comma-separated list of elements:
+/// the first one is from the proxy closest to the client.
+pub fn parse_forwarded(value: &[u8]) -> Result<Vec<ForwardedElement>, ForwardedError> {
+    let value = std::str::from_utf8(value)
+        .ok()
+        .filter(|v| v.is_ascii())
+        .ok_or(ForwardedError::Malformed("not ASCII"))?;
+
+    let mut elements = Vec::new();
+    for element in split_unquoted(value, b',')? {
+        let mut parsed = ForwardedElement::default();
+        for pair in split_unquoted(element, b';')? {
+            let pair = pair.trim_matches([' ', '\t']);
+            if pair.is_empty() {
+                continue;
+            }
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or(ForwardedError::Malformed("pair without a value"))?;
+            let value = unquote(value)?;
+            let slot = match name.to_ascii_lowercase().as_str() {
+                "for" => Slot::Node(&mut parsed.for_node),
+                "by" => Slot::Node(&mut parsed.by),
+                "proto" => Slot::Str(&mut parsed.proto),
+                "host" => Slot::Str(&mut parsed.host),
+                // extensions
+                _ => continue,
+            };
+            let taken = match slot {
+                Slot::Node(node) => node.replace(parse_node(&value)).is_some(),
+                Slot::Str(s) => s.replace(value).is_some(),
+            };
+            if taken {
+                return Err(ForwardedError::Malformed(
+                    "parameter repeated in an element",
+                ));
+            }
+        }
+        elements.push(parsed);
+    }
+    Ok(elements)
+}
+
+enum Slot<'a> {
+    Node(&'a mut Option<Node>),
+    Str(&'a mut Option<String>),
+}
+
+/// Splits `s` on `delim`, except within quoted strings
+fn split_unquoted(s: &str, delim: u8) -> Result<Vec<&str>, ForwardedError> {
+    let mut parts = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, b) in s.bytes().enumerate() {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' if quoted => escaped = true,
+            b'"' => quoted = !quoted,
+            _ if b == delim && !quoted => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if quoted {
+        return Err(ForwardedError::Malformed("unterminated quoted string"));
+    }
+    parts.push(&s[start..]);
+    Ok(parts)
+}
+
+/// A token, or a quoted string without its quotes and escapes
+fn unquote(value: &str) -> Result<String, ForwardedError> {
+    let Some(inner) = value
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+    else {
+        if value.is_empty() || value.contains('"') {
+            return Err(ForwardedError::Malformed("invalid value"));
+        }
+        return Ok(value.to_owned());
+    };
+
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            _ => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+/// `192.0.2.60`, `192.0.2.60:8080`, `[2001:db8::1]:8080`, `unknown`,
+/// `_hidden`... Anything else is as good as unknown.
+fn parse_node(value: &str) -> Node {
+    if value.starts_with('_') {
+        return Node::Obfuscated(value.to_owned());
+    }
+    parse_addr(value)
+        .map(|(ip, port)| Node::Addr { ip, port })
+        .unwrap_or(Node::Unknown)
+}
+
+/// An address with an optional port, which may be obfuscated. IPv6
+/// addresses are bracketed if there's a port.
+fn parse_addr(value: &str) -> Option<(IpAddr, Option<u16>)> {
+    if let Ok(ip) = value.parse() {
+        return Some((ip, None));
+    }
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some((addr.ip(), Some(addr.port())));
+    }
+    // obfuscated port, or none
+    let ip = match value.strip_prefix('[') {
+        Some(rest) => rest.split_once(']')?.0,
+        None => value.split_once(':')?.0,
+    };
+    Some((ip.parse().ok()?, None))
+}
+
+/// Who a request is from, as far as the trusted proxies tell
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// The client's address, or that of the closest proxy to it that isn't
+    /// trusted. `None` if a trusted proxy didn't tell (`for=unknown`, or an
+    /// obfuscated identifier), or if the peer address isn't known.
+    pub addr: Option<IpAddr>,
+
+    /// The scheme the client used, e.g. `https` when a trusted proxy
+    /// terminated TLS
+    pub scheme: Scheme,
+
+    /// The `host` the client sent, if a trusted proxy replaced it
+    pub host: Option<String>,
+}
+
+/// Works out who sent the request that came in with `headers`, from `peer`
+/// over `scheme`.
+///
+/// `Forwarded` takes precedence over `x-forwarded-for`, which is read the
+/// same way. `x-forwarded-proto` and `x-forwarded-host` are only believed
+/// from the peer: their last value is used. Requests with a malformed
+/// `Forwarded` header are taken to come from the peer.
+pub fn resolve(
+    headers: &Headers,
+    peer: Option<IpAddr>,
+    scheme: Scheme,
+    trusted: &TrustedProxies,
+) -> ClientInfo {
+    let mut info = ClientInfo {
+        addr: peer,
+        scheme,
+        host: None,
+    };
+    if !peer.is_some_and(|peer| trusted.contains(peer)) {
+        return info;
+    }
+
+    let hops = if headers.contains_key(header::FORWARDED) {
+        let mut hops = Vec::new();
+        for value in headers.get_all(header::FORWARDED) {
+            match parse_forwarded(&value[..]) {
+                Ok(elements) => hops.extend(elements),
+                Err(_) => return info,
+            }
+        }
+        hops
+    } else {
+        let mut hops: Vec<ForwardedElement> = list_values(headers, &X_FORWARDED_FOR)
+            .map(|value| ForwardedElement {
+                for_node: Some(parse_node(value)),
+                ..Default::default()
+            })
+            .collect();
+        if let Some(last) = hops.last_mut() {
+            last.proto = list_values(headers, &X_FORWARDED_PROTO)
+                .last()
+                .map(str::to_owned);
+            last.host = list_values(headers, &X_FORWARDED_HOST)
+                .last()
+                .map(str::to_owned);
+        }
+        hops
+    };
+
+    // the last hop was added by the peer, which we trust
+    for hop in hops.into_iter().rev() {
+        if let Some(scheme) = hop.proto.and_then(|proto| proto.parse().ok()) {
+            info.scheme = scheme;
+        }
+        if let Some(host) = hop.host {
+            info.host = Some(host);
+        }
+        info.addr = match hop.for_node {
+            Some(Node::Addr { ip, .. }) => Some(ip),
+            // the proxy didn't say who it got the request from, so we
+            // can't tell either
+            _ => None,
+        };
+        match info.addr {
+            Some(ip) if trusted.contains(ip) => {}
+            _ => break,
+        }
+    }
+    info
+}
+
+/// [resolve] for a request a server accepted: the peer and scheme are the
+/// connection's
+pub fn resolve_request(req: &Request, trusted: &TrustedProxies) -> ClientInfo {
+    let conn = req.conn.as_deref();
+    let peer = conn.and_then(|conn| conn.peer_addr).map(|addr| addr.ip());
+    let scheme = if conn.is_some_and(|conn| conn.tls.is_some()) {
+        Scheme::HTTPS
+    } else {
+        Scheme::HTTP
+    };
+    resolve(&req.headers, peer, scheme, trusted)
+}
+
+/// The comma-separated values of all `name` headers, trimmed, in order
+fn list_values<'a>(headers: &'a Headers, name: &HeaderName) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .into_iter()
+        .flat_map(|value| {
+            std::str::from_utf8(&value[..])
+                .unwrap_or_default()
+                .split(',')
+        })
+        .map(|value| value.trim_matches([' ', '\t']))
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use http::uri::Scheme;
+
+    use super::{
+        parse_forwarded, resolve, ClientInfo, ForwardedElement, ForwardedError, IpNet, Node,
+        TrustedProxies,
+    };
+    use crate::Headers;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ip_net() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.200.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(net.contains(ip("::ffff:10.1.0.1")), "IPv4-mapped");
+
+        let net: IpNet = "2001:db8::/33".parse().unwrap();
+        assert!(net.contains(ip("2001:db8:7fff::1")));
+        assert!(!net.contains(ip("2001:db8:8000::1")));
+        assert!(!net.contains(ip("10.1.0.1")));
+
+        let net: IpNet = "192.0.2.7".parse().unwrap();
+        assert_eq!(net.to_string(), "192.0.2.7/32");
+        assert!(net.contains(ip("192.0.2.7")));
+        assert!(!net.contains(ip("192.0.2.6")));
+
+        assert!("0.0.0.0/0"
+            .parse::<IpNet>()
+            .unwrap()
+            .contains(ip("8.8.8.8")));
+        for bad in ["10.0.0.0/33", "10.0.0.0/", "10.0.0.0/+8", "example.org", ""] {
+            assert!(bad.parse::<IpNet>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_parse_forwarded() {
+        let elements = parse_forwarded(
+            br#"for=192.0.2.43;proto=https, For="[2001:db8:cafe::17]:4711";host="a\"b";ext=1, for=unknown;by=_hidden"#,
+        )
+        .unwrap();
+        assert_eq!(
+            elements,
+            vec![
+                ForwardedElement {
+                    for_node: Some(Node::Addr {
+                        ip: ip("192.0.2.43"),
+                        port: None
+                    }),
+                    proto: Some("https".into()),
+                    ..Default::default()
+                },
+                ForwardedElement {
+                    for_node: Some(Node::Addr {
+                        ip: ip("2001:db8:cafe::17"),
+                        port: Some(4711)
+                    }),
+                    host: Some("a\"b".into()),
+                    ..Default::default()
+                },
+                ForwardedElement {
+                    for_node: Some(Node::Unknown),
+                    by: Some(Node::Obfuscated("_hidden".into())),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        let elements = parse_forwarded(br#"for="192.0.2.1:_port", for=";,""#).unwrap();
+        assert_eq!(
+            elements[0].for_node,
+            Some(Node::Addr {
+                ip: ip("192.0.2.1"),
+                port: None
+            })
+        );
+        assert_eq!(elements[1].for_node, Some(Node::Unknown));
+
+        for bad in [
+            &b"for"[..],
+            b"for=",
+            b"for=1.2.3.4;for=5.6.7.8",
+            b"for=\"1.2.3.4",
+            b"for=a\"b",
+            "for=\u{e9}".as_bytes(),
+        ] {
+            assert!(
+                matches!(parse_forwarded(bad), Err(ForwardedError::Malformed(_))),
+                "{:?}",
+                String::from_utf8_lossy(bad)
+            );
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let trusted = TrustedProxies {
+            nets: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = Headers::default();
+            for (name, value) in pairs {
+                headers.append(*name, value.as_bytes().into());
+            }
+            headers
+        };
+        let resolve = |peer: &str, headers: &Headers| {
+            resolve(headers, Some(ip(peer)), Scheme::HTTP, &trusted)
+        };
+
+        // an untrusted peer's headers are ignored
+        let spoofed = headers(&[("forwarded", "for=10.0.0.1;proto=https")]);
+        assert_eq!(
+            resolve("192.0.2.1", &spoofed),
+            ClientInfo {
+                addr: Some(ip("192.0.2.1")),
+                scheme: Scheme::HTTP,
+                host: None,
+            }
+        );
+
+        // the client prepended its own hop, which we don't believe
+        let h = headers(&[
+            ("forwarded", "for=198.51.100.1;host=evil"),
+            (
+                "forwarded",
+                "for=192.0.2.1;proto=https;host=example.org, for=10.0.0.2",
+            ),
+        ]);
+        assert_eq!(
+            resolve("10.0.0.1", &h),
+            ClientInfo {
+                addr: Some(ip("192.0.2.1")),
+                scheme: Scheme::HTTPS,
+                host: Some("example.org".into()),
+            }
+        );
+
+        // a trusted proxy that doesn't know
+        let h = headers(&[("forwarded", "for=192.0.2.1, for=unknown")]);
+        assert_eq!(resolve("10.0.0.1", &h).addr, None);
+
+        // malformed: stick to the peer
+        let h = headers(&[("forwarded", "for=192.0.2.1;for=192.0.2.2")]);
+        assert_eq!(resolve("10.0.0.1", &h).addr, Some(ip("10.0.0.1")));
+
+        // `forwarded` wins over the legacy headers
+        let h = headers(&[
+            ("forwarded", "for=192.0.2.1"),
+            ("x-forwarded-for", "192.0.2.2"),
+        ]);
+        assert_eq!(resolve("10.0.0.1", &h).addr, Some(ip("192.0.2.1")));
+
+        let h = headers(&[
+            ("x-forwarded-for", "198.51.100.1, 192.0.2.1"),
+            ("x-forwarded-for", "10.0.0.3:1234"),
+            ("x-forwarded-proto", "http, https"),
+            ("x-forwarded-host", "example.org"),
+        ]);
+        assert_eq!(
+            resolve("10.0.0.1", &h),
+            ClientInfo {
+                addr: Some(ip("192.0.2.1")),
+                scheme: Scheme::HTTPS,
+                host: Some("example.org".into()),
+            }
+        );
+
+        // no headers at all
+        assert_eq!(
+            resolve("10.0.0.1", &Headers::default()).addr,
+            Some(ip("10.0.0.1"))
+        );
+    }
+}
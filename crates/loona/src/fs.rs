@@ -0,0 +1,409 @@
+//! Static file serving, see [ServeDir]
+
+use std::{
+    ffi::OsStr,
+    fs::{File, Metadata},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use b_x::BX;
+use buffet::Piece;
+use http::{header, StatusCode};
+
+use crate::{
+    conditional::{self, Evaluation, Validators},
+    error::NeverError,
+    percent_decode, range,
+    util::fmt_http_date,
+    Body, BodyChunk, Encoder, ExpectResponseHeaders, FileBody, Method, Request, Responder,
+    ResponderOrBodyError, Response, ResponseDone, ServerDriver, SinglePieceBody,
+};
+
+/// Serves the files in a directory.
+///
+/// Only `GET` and `HEAD` are allowed. Request paths are percent-decoded, and
+/// any path that tries to escape `root` (`..`, encoded slashes, NUL bytes)
+/// gets a 404. Symbolic links are followed, even if they point outside of
+/// `root`.
+///
+/// Responses carry an `etag` and `last-modified` derived from the file's
+/// metadata, conditional requests get a 304 or a 412 when appropriate, cf.
+/// [crate::conditional], and range requests are honored, cf.
+/// [crate::range]. File contents go through [FileBody], so they're spliced
+/// straight to the socket when possible.
+///
+/// It can be used as a [ServerDriver] directly, or from another driver with
+/// [ServeDir::serve].
+pub struct ServeDir {
+    /// The directory to serve files from
+    pub root: PathBuf,
+
+    /// The file to serve for requests that map to a directory, e.g.
+    /// `index.html`. When `None`, or when the file doesn't exist, those
+    /// requests get a 404: directory listings aren't supported.
+    pub index_file: Option<String>,
+}
+
+impl ServeDir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            index_file: Some("index.html".to_string()),
+        }
+    }
+
+    /// Responds to `req` with the file it maps to, or an error status.
+    pub async fn serve<E: Encoder>(
+        &self,
+        req: &Request,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> Result<Responder<E, ResponseDone>, ResponderOrBodyError<E::Error, NeverError>> {
+        if !matches!(req.method, Method::Get | Method::Head) {
+            let mut res = status_response(StatusCode::METHOD_NOT_ALLOWED);
+            res.headers.insert(header::ALLOW, "GET, HEAD".into());
+            return respond.write_final_response_with_body(res, &mut ()).await;
+        }
+
+        let Some(mut path) = sanitize_path(&self.root, req.uri.path()) else {
+            return respond_with_status(respond, StatusCode::NOT_FOUND).await;
+        };
+
+        let mut meta = match std::fs::metadata(&path) {
+            Ok(meta) => meta,
+            Err(e) => return respond_with_status(respond, io_error_status(&e)).await,
+        };
+
+        if meta.is_dir() {
+            // relative links in `index.html` only work if the URL ends with a slash
+            if !req.uri.path().ends_with('/') {
+                let mut location = dir_location(req.uri.path());
+                if let Some(query) = req.uri.query() {
+                    location.push('?');
+                    location.push_str(query);
+                }
+                let mut res = status_response(StatusCode::MOVED_PERMANENTLY);
+                res.headers
+                    .insert(header::LOCATION, location.into_bytes().into());
+                return respond.write_final_response_with_body(res, &mut ()).await;
+            }
+
+            let Some(index_file) = &self.index_file else {
+                return respond_with_status(respond, StatusCode::NOT_FOUND).await;
+            };
+            path.push(index_file);
+            meta = match std::fs::metadata(&path) {
+                Ok(meta) if meta.is_file() => meta,
+                Ok(_) => return respond_with_status(respond, StatusCode::NOT_FOUND).await,
+                Err(e) => return respond_with_status(respond, io_error_status(&e)).await,
+            };
+        }
+
+        let etag = etag(&meta);
+        let last_modified = meta.modified().ok();
+
+        let mut res = Response::default();
+        res.headers
+            .insert(header::ETAG, etag.clone().into_bytes().into());
+        if let Some(last_modified) = last_modified {
+            res.headers
+                .insert(header::LAST_MODIFIED, fmt_http_date(last_modified));
+        }
+
+        let validators = Validators {
+            etag: Some(&etag),
+            last_modified,
+        };
+        match conditional::evaluate(&req.method, &req.headers, &validators) {
+            Evaluation::Proceed => {}
+            Evaluation::NotModified => {
+                res.status = StatusCode::NOT_MODIFIED;
+                return respond.write_final_response_with_body(res, &mut ()).await;
+            }
+            Evaluation::PreconditionFailed => {
+                return respond_with_status(respond, StatusCode::PRECONDITION_FAILED).await;
+            }
+        }
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) => return respond_with_status(respond, io_error_status(&e)).await,
+        };
+        let body = match FileBody::new(file) {
+            Ok(body) => body,
+            Err(_) => return respond_with_status(respond, StatusCode::INTERNAL_SERVER_ERROR).await,
+        };
+        res.headers
+            .insert(header::CONTENT_TYPE, Piece::from(guess_mime_type(&path)));
+
+        if !conditional::if_range_matches(&req.headers, &validators) {
+            let mut req = req.clone();
+            req.headers.remove(header::RANGE);
+            return range::respond(respond, &req, res, &body).await;
+        }
+        range::respond(respond, req, res, &body).await
+    }
+}
+
+impl<E> ServerDriver<E> for ServeDir
+where
+    E: Encoder,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> Result<Responder<E, ResponseDone>, Self::Error> {
+        // we don't care about request bodies, but they must be drained
+        loop {
+            match req_body.next_chunk().await.map_err(BX::from_err)? {
+                BodyChunk::Done { .. } => break,
+                BodyChunk::Chunk(_) | BodyChunk::File { .. } => {}
+            }
+        }
+
+        self.serve(&req, respond).await.map_err(BX::from_err)
+    }
+}
+
+fn status_response(status: StatusCode) -> Response {
+    Response {
+        status,
+        ..Default::default()
+    }
+}
+
+async fn respond_with_status<E: Encoder>(
+    respond: Responder<E, ExpectResponseHeaders>,
+    status: StatusCode,
+) -> Result<Responder<E, ResponseDone>, ResponderOrBodyError<E::Error, NeverError>> {
+    let mut body = SinglePieceBody::from(status.canonical_reason().unwrap_or_default());
+    respond
+        .write_final_response_with_body(status_response(status), &mut body)
+        .await
+}
+
+fn io_error_status(e: &std::io::Error) -> StatusCode {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        std::io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Maps a request path to a path under `root`, or returns `None` if it
+/// doesn't map to anything we'd serve.
+fn sanitize_path(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    for segment in request_path.split('/') {
+        let segment = percent_decode(segment.as_bytes())?;
+        match &segment[..] {
+            b"" | b"." => continue,
+            b".." => return None,
+            s if s.contains(&b'/') || s.contains(&b'\\') || s.contains(&0) => return None,
+            s => path.push(OsStr::from_bytes(s)),
+        }
+    }
+    Some(path)
+}
+
+/// Where to redirect a request for a directory: the path with a trailing
+/// slash, rebuilt from its segments so it starts with exactly one slash.
+/// `//dir/` would be a protocol-relative URL pointing to another host.
+fn dir_location(request_path: &str) -> String {
+    let mut location = String::with_capacity(request_path.len() + 1);
+    for segment in request_path.split('/') {
+        if matches!(segment, "" | ".") {
+            continue;
+        }
+        location.push('/');
+        location.push_str(segment);
+    }
+    location.push('/');
+    location
+}
+
+/// A strong validator built from the file's size and modification time,
+/// like nginx does.
+fn etag(meta: &Metadata) -> String {
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", mtime, meta.len())
+}
+
+/// Guesses a content type from a file extension, falling back to
+/// `application/octet-stream`.
+pub fn guess_mime_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("map") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("md") => "text/markdown; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("tar") => "application/x-tar",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") => "audio/ogg",
+        Some("wav") => "audio/wav",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use http::header;
+
+    use super::{dir_location, guess_mime_type, sanitize_path, ServeDir};
+    use crate::{h1::encode::H1Encoder, Request, Responder};
+
+    #[test]
+    fn test_sanitize_path() {
+        let root = Path::new("/srv");
+        let ok = |p: &str| sanitize_path(root, p).map(|p| p.to_str().unwrap().to_owned());
+        assert_eq!(ok("/").as_deref(), Some("/srv"));
+        assert_eq!(ok("/a/b.txt").as_deref(), Some("/srv/a/b.txt"));
+        assert_eq!(ok("/a//./b%20c.txt").as_deref(), Some("/srv/a/b c.txt"));
+        assert_eq!(ok("/../etc/passwd"), None);
+        assert_eq!(ok("/a/%2e%2e/b"), None);
+        assert_eq!(ok("/a%2fb"), None);
+        assert_eq!(ok("/a%5cb"), None);
+        assert_eq!(ok("/a%00b"), None);
+        assert_eq!(ok("/a%zz"), None);
+    }
+
+    #[test]
+    fn test_dir_location() {
+        assert_eq!(dir_location("/sub"), "/sub/");
+        assert_eq!(dir_location("//sub"), "/sub/");
+        assert_eq!(dir_location("///sub//./a%20b"), "/sub/a%20b/");
+        assert_eq!(dir_location(""), "/");
+    }
+
+    #[test]
+    fn test_guess_mime_type() {
+        assert_eq!(
+            guess_mime_type(Path::new("index.HTML")),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(guess_mime_type(Path::new("a.tar.gz")), "application/gzip");
+        assert_eq!(
+            guess_mime_type(Path::new("Makefile")),
+            "application/octet-stream"
+        );
+    }
+
+    /// Serves `req` with an h1 encoder and returns what it wrote
+    async fn serve(dir: &ServeDir, req: Request) -> String {
+        let respond = Responder::new(H1Encoder::new(Vec::<u8>::new()));
+        let encoder = dir.serve(&req, respond).await.unwrap().into_inner();
+        String::from_utf8(encoder.into_transport().into_inner()).unwrap()
+    }
+
+    #[test]
+    fn test_serve_dir() {
+        let root =
+            std::env::temp_dir().join(format!("loona-test-serve-dir-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("hello.txt"), "hello world").unwrap();
+        std::fs::write(root.join("sub/index.html"), "<h1>hi</h1>").unwrap();
+
+        let dir = ServeDir::new(&root);
+        buffet::start(async {
+            let get = |path: &str| Request {
+                uri: path.parse().unwrap(),
+                ..Default::default()
+            };
+
+            let res = serve(&dir, get("/hello.txt")).await;
+            assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{res}");
+            assert!(res.contains("content-type: text/plain; charset=utf-8\r\n"));
+            assert!(res.contains("content-length: 11\r\n"));
+            assert!(res.ends_with("\r\n\r\nhello world"));
+
+            let etag = res
+                .lines()
+                .find_map(|l| l.strip_prefix("etag: "))
+                .unwrap()
+                .to_owned();
+            let mut req = get("/hello.txt");
+            req.headers
+                .insert(header::IF_NONE_MATCH, etag.into_bytes().into());
+            let res = serve(&dir, req).await;
+            assert!(res.starts_with("HTTP/1.1 304 Not Modified\r\n"), "{res}");
+
+            let mut req = get("/hello.txt");
+            req.headers.insert(header::IF_MATCH, "\"stale\"".into());
+            let res = serve(&dir, req).await;
+            assert!(
+                res.starts_with("HTTP/1.1 412 Precondition Failed\r\n"),
+                "{res}"
+            );
+
+            let mut req = get("/hello.txt");
+            req.headers.insert(header::RANGE, "bytes=6-".into());
+            let res = serve(&dir, req).await;
+            assert!(res.starts_with("HTTP/1.1 206 Partial Content\r\n"), "{res}");
+            assert!(res.contains("content-range: bytes 6-10/11\r\n"));
+            assert!(res.ends_with("\r\n\r\nworld"));
+
+            let res = serve(&dir, get("/sub")).await;
+            assert!(
+                res.starts_with("HTTP/1.1 301 Moved Permanently\r\n"),
+                "{res}"
+            );
+            assert!(res.contains("location: /sub/\r\n"));
+
+            let res = serve(&dir, get("//sub")).await;
+            assert!(
+                res.starts_with("HTTP/1.1 301 Moved Permanently\r\n"),
+                "{res}"
+            );
+            assert!(res.contains("location: /sub/\r\n"), "{res}");
+
+            let res = serve(&dir, get("/sub/")).await;
+            assert!(res.ends_with("<h1>hi</h1>"), "{res}");
+
+            let res = serve(&dir, get("/nope.txt")).await;
+            assert!(res.starts_with("HTTP/1.1 404 Not Found\r\n"), "{res}");
+
+            let res = serve(&dir, get("/../hello.txt")).await;
+            assert!(res.starts_with("HTTP/1.1 404 Not Found\r\n"), "{res}");
+        });
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
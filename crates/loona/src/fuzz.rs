@@ -0,0 +1,591 @@
+//! Entry points for the `cargo-fuzz` targets in `crates/loona/fuzz`, and
+//! for the regression tests that replay what they found. They feed
+//! arbitrary bytes through the same parsing paths the servers use, and
+//! panic if anything goes over its limits.
+//!
+//! The first byte of the input picks how the rest is split across reads,
+//! so the fuzzer also explores messages that arrive in pieces.
+//!
+//! The `*_server` targets go through the whole server instead: the input is
+//! one or more client connections, served with a trivial driver, and they
+//! also panic if a connection hangs or leaks buffers.
+
+use std::{borrow::Cow, cell::RefCell, future::poll_fn, rc::Rc, task::Poll};
+
+use b_x::{BxForResults, BX};
+use buffet::{
+    bufpool::{BufResult, IoBufMut},
+    Piece, ReadOwned, Roll, RollMut, WriteOwned,
+};
+use futures_util::{future::LocalBoxFuture, FutureExt};
+use loona_h2::{
+    ContinuationFlags, DataFlags, Frame, FrameType, GoAway, HeadersFlags, Origin, Priority,
+    PrioritySpec, PriorityUpdate, RstStream, Settings, SettingsFlags, StreamId, WindowUpdate,
+};
+use nom::IResult;
+
+use crate::{
+    h1, h2,
+    util::{read_and_parse, ReadAndParseError},
+    Body, BodyChunk, Encoder, ExpectResponseHeaders, HeaderNameInterner, Request, Responder,
+    Response, ResponseDone, ServerDriver, SinglePieceBody,
+};
+
+/// Same as the HTTP/1.1 server's default
+const H1_MAX_HEADER_SECTION_SIZE: usize = 64 * 1024;
+const H1_MAX_HEADER_COUNT: usize = 128;
+
+/// The smallest SETTINGS_MAX_FRAME_SIZE, which we advertise by default
+const H2_MAX_FRAME_SIZE: usize = 16 * 1024;
+/// Same as the HTTP/2 server's frame header read limit
+const H2_MAX_FRAME_HEADER_SIZE: usize = 128;
+/// SETTINGS_HEADER_TABLE_SIZE's initial value
+const HPACK_MAX_TABLE_SIZE: usize = 4096;
+/// Header blocks split over CONTINUATION frames are given up on past this
+const H2_MAX_HEADER_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Separates the client connections in the input of the `*_server` targets
+pub const CONNECTION_SEPARATOR: &[u8] = b"\xffNEXT\xff";
+/// Past this many connections, separators are part of the last one
+const MAX_CONNECTIONS: usize = 4;
+/// Connections are considered hung if they haven't all finished after
+/// this many scheduler rounds, plus [ROUNDS_PER_BYTE] for each byte of input
+const MIN_ROUNDS: usize = 1024;
+const ROUNDS_PER_BYTE: usize = 64;
+
+/// A fuzz target: what it runs, and inputs to start from
+pub struct Target {
+    /// Also the name of the `cargo fuzz` target, and of its corpus directory
+    pub name: &'static str,
+    pub run: fn(&[u8]),
+    pub seeds: fn() -> Vec<(&'static str, Vec<u8>)>,
+}
+
+pub const TARGETS: [Target; 4] = [
+    Target {
+        name: "h1_request",
+        run: h1_request,
+        seeds: h1_request_seeds,
+    },
+    Target {
+        name: "h2_frames",
+        run: h2_frames,
+        seeds: h2_frames_seeds,
+    },
+    Target {
+        name: "h1_server",
+        run: h1_server,
+        seeds: h1_server_seeds,
+    },
+    Target {
+        name: "h2_server",
+        run: h2_server,
+        seeds: h2_server_seeds,
+    },
+];
+
+/// Parses HTTP/1.1 requests out of `data` until it runs out, or something's
+/// wrong with it
+pub fn h1_request(data: &[u8]) {
+    buffet::bufpool::initialize_allocator().unwrap();
+    let (mut input, data) = SplitInput::new(data);
+    let mut buf = RollMut::alloc().unwrap();
+    // small, so it fills up, like the servers' would on a long connection
+    let names = RefCell::new(HeaderNameInterner::new(8));
+
+    loop {
+        let res = read_and_parse(
+            "Http1Request",
+            h1::parse::request_interning(H1_MAX_HEADER_COUNT, &names),
+            &mut input,
+            buf,
+            H1_MAX_HEADER_SECTION_SIZE,
+        )
+        .now_or_never()
+        .expect("reads over in-memory input never block");
+
+        match res {
+            Ok(Some((next_buf, req))) => {
+                assert!(req.headers.len() <= H1_MAX_HEADER_COUNT);
+                buf = next_buf;
+            }
+            Ok(None) => return,
+            Err(ReadAndParseError::BufferLimitReachedWhileParsing { limit }) => {
+                assert!(data.len() >= limit, "hit a {limit}-byte limit early");
+                return;
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+/// Deframes HTTP/2 frames out of `data` the way the server does, parses
+/// their payloads, and decodes header blocks with HPACK. `data` starts
+/// after the connection preface.
+pub fn h2_frames(data: &[u8]) {
+    buffet::bufpool::initialize_allocator().unwrap();
+    let (mut input, _data) = SplitInput::new(data);
+    let mut buf = RollMut::alloc().unwrap();
+
+    let mut decoder = loona_hpack::Decoder::new();
+    decoder.set_max_allowed_table_size(HPACK_MAX_TABLE_SIZE);
+    let mut header_block: Option<Vec<u8>> = None;
+
+    loop {
+        let frame;
+        (buf, frame) = match read(&mut input, buf, Frame::parse, H2_MAX_FRAME_HEADER_SIZE) {
+            Some(t) => t,
+            None => return,
+        };
+        let len = frame.len as usize;
+        if len > H2_MAX_FRAME_SIZE {
+            return;
+        }
+
+        let mut payload;
+        (buf, payload) = match read(&mut input, buf, nom::bytes::streaming::take(len), len) {
+            Some(t) => t,
+            None => return,
+        };
+        assert_eq!(payload.len(), len);
+
+        match frame.frame_type {
+            FrameType::Headers(flags) => {
+                if flags.contains(HeadersFlags::Padded) {
+                    payload = match strip_padding(payload) {
+                        Some(payload) => payload,
+                        None => return,
+                    };
+                }
+                if flags.contains(HeadersFlags::Priority) {
+                    payload = match PrioritySpec::parse(payload) {
+                        Ok((rest, _)) => rest,
+                        Err(_) => return,
+                    };
+                }
+                let mut block = payload[..].to_vec();
+                if flags.contains(HeadersFlags::EndHeaders) {
+                    decode_header_block(&mut decoder, &block);
+                } else {
+                    block.reserve(len);
+                    header_block = Some(block);
+                }
+            }
+            FrameType::Continuation(flags) => {
+                let Some(mut block) = header_block.take() else {
+                    return;
+                };
+                block.extend_from_slice(&payload[..]);
+                if block.len() > H2_MAX_HEADER_BLOCK_SIZE {
+                    return;
+                }
+                if flags.contains(ContinuationFlags::EndHeaders) {
+                    decode_header_block(&mut decoder, &block);
+                } else {
+                    header_block = Some(block);
+                }
+            }
+            FrameType::Settings(_) => {
+                if len % 6 != 0 {
+                    return;
+                }
+                let mut settings = Settings::default();
+                _ = Settings::parse(&payload[..], |code, value| settings.apply(code, value));
+            }
+            FrameType::Priority => _ = PrioritySpec::parse(payload),
+            FrameType::RstStream => _ = RstStream::parse(payload),
+            FrameType::GoAway => _ = GoAway::parse(payload),
+            FrameType::WindowUpdate => _ = WindowUpdate::parse(payload),
+            FrameType::PriorityUpdate => {
+                if let Ok((_, update)) = PriorityUpdate::parse(payload) {
+                    Priority::parse(&update.priority_field_value[..]);
+                }
+            }
+            FrameType::Origin => _ = Origin::parse(payload),
+            _ => {}
+        }
+    }
+}
+
+/// Serves the connections in `data` with the HTTP/1.1 server, cf.
+/// [serve_connections]
+pub fn h1_server(data: &[u8]) {
+    serve_connections(data, &[], |input| {
+        async move {
+            let conf = Rc::new(h1::ServerConf::default());
+            let client_buf = RollMut::alloc().unwrap();
+            _ = h1::serve((input, Discard), conf, client_buf, FuzzDriver).await;
+        }
+        .boxed_local()
+    });
+}
+
+/// Serves the connections in `data` with the HTTP/2 server, cf.
+/// [serve_connections]. Each connection starts after the preface.
+pub fn h2_server(data: &[u8]) {
+    serve_connections(data, loona_h2::PREFACE, |input| {
+        async move {
+            let conf = Rc::new(h2::ServerConf::default());
+            let client_buf = RollMut::alloc().unwrap();
+            _ = h2::serve((input, Discard), conf, client_buf, Rc::new(FuzzDriver)).await;
+        }
+        .boxed_local()
+    });
+}
+
+/// After the first byte, `data` is up to [MAX_CONNECTIONS] connections
+/// separated by [CONNECTION_SEPARATOR], each of which gets `preface` and
+/// then EOF. They're served concurrently, and must all be done within the
+/// scheduler round budget, without holding on to any buffer.
+fn serve_connections(
+    data: &[u8],
+    preface: &[u8],
+    serve: impl for<'a> Fn(SplitInput<'a>) -> LocalBoxFuture<'a, ()>,
+) {
+    buffet::bufpool::initialize_allocator().unwrap();
+    let (max_read, data) = max_read(data);
+    let inputs: Vec<Vec<u8>> = connections(data)
+        .into_iter()
+        .map(|conn| [preface, conn].concat())
+        .collect();
+    let rounds = MIN_ROUNDS + ROUNDS_PER_BYTE * data.len();
+    let num_free = buffet::bufpool::num_free();
+
+    buffet::start(async {
+        let conns = futures_util::future::join_all(
+            inputs
+                .iter()
+                .map(|input| serve(SplitInput::with_max_read(input, max_read))),
+        );
+        tokio::select! {
+            biased;
+            _ = conns => {}
+            _ = watchdog(rounds) => panic!("connections still going after {rounds} scheduler rounds"),
+        }
+    });
+
+    // the runtime is gone, and every task with it
+    assert_eq!(
+        buffet::bufpool::num_free(),
+        num_free,
+        "buffers leaked after serving {} connections",
+        inputs.len()
+    );
+}
+
+fn connections(mut data: &[u8]) -> Vec<&[u8]> {
+    let mut conns = Vec::new();
+    while conns.len() + 1 < MAX_CONNECTIONS {
+        let Some(at) = data
+            .windows(CONNECTION_SEPARATOR.len())
+            .position(|w| w == CONNECTION_SEPARATOR)
+        else {
+            break;
+        };
+        conns.push(&data[..at]);
+        data = &data[at + CONNECTION_SEPARATOR.len()..];
+    }
+    conns.push(data);
+    conns
+}
+
+/// Completes after yielding to the scheduler `rounds` times: the servers
+/// never wait on timers, so they get a chance to run in each of these.
+async fn watchdog(rounds: usize) {
+    for _ in 0..rounds {
+        let mut yielded = false;
+        poll_fn(|cx| {
+            if yielded {
+                return Poll::Ready(());
+            }
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await;
+    }
+}
+
+/// Reads the whole request body, then answers with a short one
+struct FuzzDriver;
+
+impl<OurEncoder> ServerDriver<OurEncoder> for FuzzDriver
+where
+    OurEncoder: Encoder,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        _req: Request,
+        req_body: &mut impl Body,
+        res: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> Result<Responder<OurEncoder, ResponseDone>, BX> {
+        while !matches!(req_body.next_chunk().await.bx()?, BodyChunk::Done { .. }) {}
+
+        res.write_final_response_with_body(Response::default(), &mut SinglePieceBody::from("ok"))
+            .await
+            .bx()
+    }
+}
+
+/// Well-formed requests, whole and split into small reads
+pub fn h1_request_seeds() -> Vec<(&'static str, Vec<u8>)> {
+    let requests: [(&str, &[u8]); 6] = [
+        ("get", b"GET / HTTP/1.1\r\nhost: example.org\r\n\r\n"),
+        (
+            "post",
+            b"POST /upload?x=1 HTTP/1.1\r\nhost: example.org\r\ncontent-length: 5\r\n\r\nhello",
+        ),
+        (
+            "chunked",
+            b"PUT /x HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n0\r\ntrailer: yes\r\n\r\n",
+        ),
+        (
+            "pipelined",
+            b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.0\r\nconnection: keep-alive\r\n\r\n",
+        ),
+        (
+            "connect",
+            b"CONNECT example.org:443 HTTP/1.1\r\nhost: example.org:443\r\n\r\n",
+        ),
+        (
+            "absolute_form",
+            b"OPTIONS http://example.org/a%20b HTTP/1.1\r\nx-empty:\r\nx-spaces:  a  b  \r\n\r\n",
+        ),
+    ];
+
+    let mut seeds = Vec::new();
+    for (name, request) in requests {
+        seeds.push((name, [&[0][..], request].concat()));
+    }
+    seeds.push(("get_split", [&[1][..], requests[0].1].concat()));
+    seeds.push(("chunked_split", [&[7][..], requests[2].1].concat()));
+    seeds
+}
+
+/// Frame sequences a client could send after the preface
+pub fn h2_frames_seeds() -> Vec<(&'static str, Vec<u8>)> {
+    let mut encoder = loona_hpack::Encoder::new();
+    let request = encoder.encode([
+        (&b":method"[..], &b"POST"[..]),
+        (b":scheme", b"https"),
+        (b":authority", b"example.org"),
+        (b":path", b"/upload"),
+        (b"user-agent", b"fuzz"),
+    ]);
+    // indexes into the dynamic table the first block filled
+    let second_request = encoder.encode([
+        (&b":method"[..], &b"POST"[..]),
+        (b":scheme", b"https"),
+        (b":authority", b"example.org"),
+        (b":path", b"/upload"),
+        (b"user-agent", b"fuzz"),
+    ]);
+
+    let settings = frame(
+        FrameType::Settings(Default::default()),
+        0,
+        &[0, 1, 0, 0, 0x10, 0, 0, 4, 0, 0, 0xff, 0xff],
+    );
+    let settings_ack = frame(FrameType::Settings(SettingsFlags::Ack.into()), 0, &[]);
+    let headers = frame(
+        FrameType::Headers(HeadersFlags::EndHeaders.into()),
+        1,
+        &request,
+    );
+
+    let (first, rest) = second_request.split_at(second_request.len() / 2);
+    let padded_headers = [&[4][..], &[0x80, 0, 0, 1, 15][..], first, &[0; 4]].concat();
+    let continued = [
+        frame(
+            FrameType::Headers(HeadersFlags::Padded | HeadersFlags::Priority),
+            3,
+            &padded_headers,
+        ),
+        frame(
+            FrameType::Continuation(ContinuationFlags::EndHeaders.into()),
+            3,
+            rest,
+        ),
+    ]
+    .concat();
+
+    let data = frame(
+        FrameType::Data(DataFlags::Padded | DataFlags::EndStream),
+        1,
+        b"\x02hello\0\0",
+    );
+    let misc = [
+        frame(FrameType::Ping(Default::default()), 0, &[1; 8]),
+        frame(FrameType::WindowUpdate, 0, &[0, 1, 0, 0]),
+        frame(FrameType::Priority, 5, &[0, 0, 0, 3, 200]),
+        frame(FrameType::PriorityUpdate, 0, b"\0\0\0\x05u=1, i"),
+        frame(FrameType::Origin, 0, b"\0\x13https://example.org"),
+        frame(FrameType::RstStream, 3, &[0, 0, 0, 8]),
+        frame(FrameType::GoAway, 0, b"\0\0\0\x03\0\0\0\0bye"),
+    ]
+    .concat();
+
+    let with_split = |split: u8, frames: &[&[u8]]| [&[split][..], &frames.concat()].concat();
+    vec![
+        ("settings", with_split(0, &[&settings, &settings_ack])),
+        ("request", with_split(0, &[&settings, &headers, &data])),
+        ("continuation", with_split(0, &[&headers, &continued])),
+        ("misc", with_split(0, &[&misc])),
+        (
+            "request_split",
+            with_split(3, &[&headers, &continued, &data]),
+        ),
+    ]
+}
+
+/// Requests on one connection or several, some cut short
+pub fn h1_server_seeds() -> Vec<(&'static str, Vec<u8>)> {
+    let requests = h1_request_seeds();
+    let request = |name: &str| {
+        let (_, request) = requests.iter().find(|(n, _)| *n == name).unwrap();
+        &request[1..]
+    };
+    let conns =
+        |split: u8, conns: &[&[u8]]| [&[split][..], &conns.join(CONNECTION_SEPARATOR)].concat();
+
+    let get = request("get");
+    let post = request("post");
+    let chunked = request("chunked");
+    vec![
+        ("get", conns(0, &[get])),
+        ("keep_alive", conns(0, &[&[get, post, chunked].concat()])),
+        ("pipelined_split", conns(5, &[request("pipelined")])),
+        ("two_conns", conns(0, &[post, chunked])),
+        (
+            "cut_short",
+            conns(3, &[&post[..post.len() - 2], &chunked[..20], get]),
+        ),
+    ]
+}
+
+/// Frame sequences on one connection or several
+pub fn h2_server_seeds() -> Vec<(&'static str, Vec<u8>)> {
+    let frames = h2_frames_seeds();
+    let frames = |name: &str| {
+        let (_, frames) = frames.iter().find(|(n, _)| *n == name).unwrap();
+        frames[1..].to_vec()
+    };
+    let conns =
+        |split: u8, conns: &[&[u8]]| [&[split][..], &conns.join(CONNECTION_SEPARATOR)].concat();
+
+    let request = frames("request");
+    let misc = frames("misc");
+    let continuation = frames("continuation");
+    vec![
+        ("request", conns(0, &[&request])),
+        ("request_split", conns(7, &[&request])),
+        ("continuation", conns(0, &[&continuation])),
+        ("two_conns", conns(0, &[&request, &misc])),
+        ("cut_short", conns(0, &[&request[..request.len() - 4]])),
+    ]
+}
+
+fn frame(frame_type: FrameType, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    Frame::new(frame_type, StreamId(stream_id))
+        .with_len(payload.len() as u32)
+        .write_into(&mut out)
+        .unwrap();
+    out.extend_from_slice(payload);
+    out
+}
+
+fn read<Output>(
+    input: &mut SplitInput<'_>,
+    buf: RollMut,
+    parser: impl Fn(Roll) -> IResult<Roll, Output>,
+    max_len: usize,
+) -> Option<(RollMut, Output)> {
+    let res = read_and_parse("Fuzz", parser, input, buf, max_len)
+        .now_or_never()
+        .expect("reads over in-memory input never block");
+    res.ok().flatten()
+}
+
+/// Same as the server: the first byte says how much padding there is at the
+/// end of the payload
+fn strip_padding(payload: Roll) -> Option<Roll> {
+    if payload.is_empty() {
+        return None;
+    }
+    let (padding_length, payload) = payload.split_at(1);
+    let at = payload.len().checked_sub(padding_length[0] as usize)?;
+    Some(payload.split_at(at).0)
+}
+
+fn decode_header_block(decoder: &mut loona_hpack::Decoder, block: &[u8]) {
+    // a field comes from the block itself, Huffman-decoded so up to 8/5 as
+    // large, or from the tables
+    let max_field_len = HPACK_MAX_TABLE_SIZE.max(block.len() * 8 / 5 + 1);
+    _ = decoder.decode_with_cb(block, |name: Cow<[u8]>, value: Cow<[u8]>| {
+        assert!(
+            name.len() + value.len() <= max_field_len,
+            "decoded a {}-byte field out of a {}-byte block",
+            name.len() + value.len(),
+            block.len()
+        );
+    });
+    assert!(decoder.table_size() <= HPACK_MAX_TABLE_SIZE);
+}
+
+/// Hands out the input in reads of a size picked by its first byte
+struct SplitInput<'a> {
+    rest: &'a [u8],
+    max_read: usize,
+}
+
+impl<'a> SplitInput<'a> {
+    fn new(data: &'a [u8]) -> (Self, &'a [u8]) {
+        let (max_read, rest) = max_read(data);
+        (Self::with_max_read(rest, max_read), rest)
+    }
+
+    fn with_max_read(rest: &'a [u8], max_read: usize) -> Self {
+        Self { rest, max_read }
+    }
+}
+
+/// Reads the read size off the first byte of `data`
+fn max_read(data: &[u8]) -> (usize, &[u8]) {
+    match data.split_first() {
+        None => (usize::MAX, data),
+        // 0 means "as much as fits"
+        Some((0, rest)) => (usize::MAX, rest),
+        Some((&n, rest)) => (n as usize, rest),
+    }
+}
+
+impl ReadOwned for SplitInput<'_> {
+    async fn read_owned<B: IoBufMut>(&mut self, mut buf: B) -> BufResult<usize, B> {
+        let n = self
+            .rest
+            .len()
+            .min(self.max_read)
+            .min(buf.io_buf_mut_capacity());
+        let (read, rest) = self.rest.split_at(n);
+        unsafe { buf.slice_mut()[..n].copy_from_slice(read) };
+        self.rest = rest;
+        (Ok(n), buf)
+    }
+}
+
+/// Where the servers' responses go
+struct Discard;
+
+impl WriteOwned for Discard {
+    async fn write_owned(&mut self, buf: impl Into<Piece>) -> BufResult<usize, Piece> {
+        let buf = buf.into();
+        (Ok(buf.len()), buf)
+    }
+
+    async fn shutdown(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,21 +1,38 @@
-use std::fmt;
+use std::{fmt, fs::File};
 
+use http::HeaderName;
 use tracing::debug;
 
-use crate::{util::read_and_parse, Body, BodyChunk, BodyError};
+use crate::{
+    metrics::MeteredRead, util::read_and_parse, Body, BodyChunk, BodyError, Headers,
+    FORBIDDEN_TRAILERS,
+};
 use buffet::{Piece, PieceList, ReadOwned, RollMut, WriteOwned};
 
-/// An HTTP/1.1 body, either chunked or content-length.
+/// Max length of a chunk size line, extensions included
+const MAX_CHUNK_SIZE_LINE_LEN: usize = 4 * 1024;
+
+/// Max length of the trailer section of a chunked body
+const MAX_TRAILERS_LEN: usize = 64 * 1024;
+
+/// Max number of fields in the trailer section of a chunked body
+const MAX_TRAILER_COUNT: usize = 128;
+
+/// An HTTP/1.1 body, either chunked, content-length, close-delimited, or the
+/// client's side of a CONNECT tunnel.
 pub(crate) struct H1Body<T> {
     transport_r: T,
     buf: Option<RollMut>,
     state: Decoder,
+    trailers: Vec<HeaderName>,
 }
 
 #[derive(Debug)]
 enum Decoder {
     Chunked(ChunkedDecoder),
     ContentLength(ContentLengthDecoder),
+    Tunnel(TunnelDecoder),
+    CloseDelimited(TunnelDecoder),
 }
 
 #[derive(Debug)]
@@ -33,10 +50,23 @@ struct ContentLengthDecoder {
     read: u64,
 }
 
+/// Raw bytes, until the peer closes its side of the connection
+#[derive(Debug, Default)]
+struct TunnelDecoder {
+    // whether anything was read: until then, the connection can still go
+    // back to HTTP, if the CONNECT request was refused
+    started: bool,
+    eof: bool,
+}
+
 #[derive(Debug)]
 pub(crate) enum H1BodyKind {
     Chunked,
     ContentLength(u64),
+    Tunnel,
+    /// A response with neither `content-length` nor chunked encoding: it
+    /// ends when the server closes the connection
+    CloseDelimited,
 }
 
 impl<T> fmt::Debug for H1Body<T> {
@@ -54,18 +84,33 @@ impl<T: ReadOwned> H1Body<T> {
             H1BodyKind::ContentLength(len) => {
                 Decoder::ContentLength(ContentLengthDecoder { len, read: 0 })
             }
+            H1BodyKind::Tunnel => Decoder::Tunnel(Default::default()),
+            H1BodyKind::CloseDelimited => Decoder::CloseDelimited(Default::default()),
         };
         H1Body {
             transport_r,
             buf: Some(buf),
             state,
+            trailers: Vec::new(),
         }
     }
 
+    /// Records the trailers the message's `trailer` header announced, cf.
+    /// [Body::announced_trailers]
+    pub(crate) fn announce_trailers(&mut self, names: impl IntoIterator<Item = HeaderName>) {
+        self.trailers.extend(names);
+    }
+
     /// Returns the inner buffer and transport, but only if the body has been
-    /// fully read.
+    /// fully read, or if it's a tunnel nobody read from. Close-delimited
+    /// bodies leave nothing to reuse.
     pub(crate) fn into_inner(self) -> Option<(RollMut, T)> {
-        if !self.eof() {
+        let reusable = match &self.state {
+            Decoder::Tunnel(state) => !state.started,
+            Decoder::CloseDelimited(_) => false,
+            _ => self.eof(),
+        };
+        if !reusable {
             return None;
         }
         let buf = self.buf?;
@@ -73,16 +118,29 @@ impl<T: ReadOwned> H1Body<T> {
     }
 }
 
+impl<R> H1Body<MeteredRead<R>> {
+    /// Returns how far into the transport we've consumed: bytes read from it,
+    /// minus those still sitting in our buffer
+    pub(crate) fn read_offset(&self) -> u64 {
+        let buffered = self.buf.as_ref().map_or(0, |buf| buf.len());
+        self.transport_r.total() - buffered as u64
+    }
+}
+
 impl<OurReadOwned: ReadOwned> Body for H1Body<OurReadOwned> {
     type Error = BodyError;
 
     fn content_len(&self) -> Option<u64> {
         match &self.state {
-            Decoder::Chunked(_) => None,
+            Decoder::Chunked(_) | Decoder::Tunnel(_) | Decoder::CloseDelimited(_) => None,
             Decoder::ContentLength(state) => Some(state.len),
         }
     }
 
+    fn announced_trailers(&self) -> &[HeaderName] {
+        &self.trailers
+    }
+
     async fn next_chunk(&mut self) -> Result<BodyChunk, BodyError> {
         if self.buf.is_none() {
             return Ok(BodyChunk::Done { trailers: None });
@@ -93,6 +151,9 @@ impl<OurReadOwned: ReadOwned> Body for H1Body<OurReadOwned> {
             Decoder::ContentLength(state) => {
                 state.next_chunk(&mut self.buf, &mut self.transport_r).await
             }
+            Decoder::Tunnel(state) | Decoder::CloseDelimited(state) => {
+                state.next_chunk(&mut self.buf, &mut self.transport_r).await
+            }
         }
     }
 
@@ -100,6 +161,7 @@ impl<OurReadOwned: ReadOwned> Body for H1Body<OurReadOwned> {
         match &self.state {
             Decoder::Chunked(state) => state.eof(),
             Decoder::ContentLength(state) => state.eof(),
+            Decoder::Tunnel(state) | Decoder::CloseDelimited(state) => state.eof,
         }
     }
 }
@@ -125,7 +187,7 @@ impl ContentLengthDecoder {
             buf.reserve()?;
 
             let res;
-            (res, buf) = buf.read_into(usize::MAX, transport).await;
+            (res, buf) = buf.read_into_vectored(remain as usize, transport).await;
             res.map_err(BodyError::ErrorWhileReadingChunkData)?;
         }
 
@@ -142,6 +204,40 @@ impl ContentLengthDecoder {
     }
 }
 
+impl TunnelDecoder {
+    async fn next_chunk(
+        &mut self,
+        buf_slot: &mut Option<RollMut>,
+        transport: &mut impl ReadOwned,
+    ) -> Result<BodyChunk, BodyError> {
+        if self.eof {
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+        self.started = true;
+
+        let mut buf = buf_slot
+            .take()
+            .ok_or(BodyError::CalledNextChunkAfterError)?;
+
+        if buf.is_empty() {
+            buf.reserve()?;
+
+            let res;
+            (res, buf) = buf.read_into(usize::MAX, transport).await;
+            if res.map_err(BodyError::ErrorWhileReadingChunkData)? == 0 {
+                debug!("peer closed its side of the connection");
+                self.eof = true;
+                buf_slot.replace(buf);
+                return Ok(BodyChunk::Done { trailers: None });
+            }
+        }
+
+        let chunk = buf.take_all();
+        buf_slot.replace(buf);
+        Ok(BodyChunk::Chunk(chunk.into()))
+    }
+}
+
 impl ChunkedDecoder {
     async fn next_chunk(
         &mut self,
@@ -155,8 +251,7 @@ impl ChunkedDecoder {
 
             if let ChunkedDecoder::Done = self {
                 buf_slot.replace(buf);
-                // TODO: prevent misuse when calling `next_chunk` after trailers
-                // were already read?
+                // trailers, if any, were returned along with the first `Done`
                 return Ok(BodyChunk::Done { trailers: None });
             }
 
@@ -166,7 +261,7 @@ impl ChunkedDecoder {
                     super::parse::chunk_size,
                     transport,
                     buf,
-                    16,
+                    MAX_CHUNK_SIZE_LINE_LEN,
                 )
                 .await
                 .map_err(|_| BodyError::InvalidChunkSize)?
@@ -174,23 +269,25 @@ impl ChunkedDecoder {
                 buf = next_buf;
 
                 if chunk_size == 0 {
-                    // that's the final chunk, look for the final CRLF
-                    let (next_buf, _) = read_and_parse(
-                        "Http1BodyChunkFinalTerminator",
-                        super::parse::crlf,
+                    // that's the last chunk, it's followed by the trailer
+                    // section (usually empty) and a final CRLF
+                    let (next_buf, trailers) = read_and_parse(
+                        "Http1BodyTrailers",
+                        super::parse::headers_and_crlf(MAX_TRAILER_COUNT),
                         transport,
                         buf,
-                        2,
+                        MAX_TRAILERS_LEN,
                     )
                     .await
-                    .map_err(BodyError::InvalidChunkTerminator)?
-                    .ok_or(BodyError::ClosedWhileReadingChunkTerminator)?;
+                    .map_err(BodyError::InvalidTrailers)?
+                    .ok_or(BodyError::ClosedWhileReadingTrailers)?;
                     buf = next_buf;
                     *self = ChunkedDecoder::Done;
                     buf_slot.replace(buf);
 
-                    // TODO: trailers
-                    return Ok(BodyChunk::Done { trailers: None });
+                    return Ok(BodyChunk::Done {
+                        trailers: allowed_trailers(trailers),
+                    });
                 }
 
                 *self = ChunkedDecoder::ReadingChunk { remain: chunk_size }
@@ -219,7 +316,7 @@ impl ChunkedDecoder {
                     buf.reserve()?;
 
                     let res;
-                    (res, buf) = buf.read_into(*remain as usize, transport).await;
+                    (res, buf) = buf.read_into_vectored(*remain as usize, transport).await;
                     res.map_err(BodyError::ErrorWhileReadingChunkData)?;
                 }
 
@@ -245,6 +342,22 @@ impl ChunkedDecoder {
     }
 }
 
+/// Drops fields that aren't allowed in trailers: we may not merge them
+/// with the header section, and they'd be confusing on their own, cf.
+/// <https://httpwg.org/specs/rfc9112.html#chunked.trailer.section>
+fn allowed_trailers(mut trailers: Headers) -> Option<Box<Headers>> {
+    for name in FORBIDDEN_TRAILERS {
+        if trailers.remove(name).is_some() {
+            debug!(%name, "dropping field not allowed in trailers");
+        }
+    }
+    if trailers.is_empty() {
+        None
+    } else {
+        Some(Box::new(trailers))
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BodyWriteMode {
     // we're doing chunked transfer encoding
@@ -256,6 +369,15 @@ pub enum BodyWriteMode {
     // we didn't set a content-length and we're not doing chunked transfer
     // encoding, so we're not sending a body at all.
     Empty,
+
+    // we accepted a CONNECT request: the body is raw tunnel bytes, and ends
+    // when we close our side of the connection
+    Tunnel,
+
+    // we didn't set a content-length, and the client can't do chunked
+    // transfer encoding (HTTP/1.0): the body ends when we close the
+    // connection
+    CloseDelimited,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -283,11 +405,16 @@ where
             .await
             .map_err(WriteBodyError::InnerBodyError)?
         {
-            BodyChunk::Chunk(chunk) => write_h1_body_chunk(transport, chunk, mode).await?,
+            BodyChunk::Chunk(chunk) => {
+                write_h1_body_chunk(transport, Default::default(), chunk, mode).await?
+            }
+            BodyChunk::File { file, offset, len } => {
+                write_h1_body_file(transport, &file, offset, len, mode).await?
+            }
             BodyChunk::Done { .. } => {
                 // TODO: check that we've sent what we announced in terms of
                 // content length
-                write_h1_body_end(transport, mode).await?;
+                write_h1_body_end(transport, Default::default(), mode).await?;
                 break;
             }
         }
@@ -296,29 +423,38 @@ where
     Ok(())
 }
 
+/// Writes `chunk`, framed according to `mode`, after `head` (whatever the
+/// encoder held back so far, usually nothing), in a single write
 pub(crate) async fn write_h1_body_chunk(
     transport: &mut impl WriteOwned,
+    mut head: PieceList,
     chunk: Piece,
     mode: BodyWriteMode,
 ) -> Result<(), BodyError> {
     match mode {
         BodyWriteMode::Chunked => {
+            head.push_back(Piece::from_fmt(format_args!("{:x}\r\n", chunk.len())));
+            head.push_back(chunk);
+            head.push_back("\r\n");
             transport
-                .writev_all_owned(
-                    PieceList::default()
-                        .followed_by(format!("{:x}\r\n", chunk.len()).into_bytes())
-                        .followed_by(chunk)
-                        .followed_by("\r\n"),
-                )
+                .writev_all_owned(head)
                 .await
                 .map_err(BodyError::WriteError)?;
         }
-        BodyWriteMode::ContentLength(_) => {
+        BodyWriteMode::ContentLength(_) | BodyWriteMode::Tunnel | BodyWriteMode::CloseDelimited
+            if head.is_empty() =>
+        {
             transport
                 .write_all_owned(chunk)
                 .await
                 .map_err(BodyError::WriteError)?;
         }
+        BodyWriteMode::ContentLength(_) | BodyWriteMode::Tunnel | BodyWriteMode::CloseDelimited => {
+            transport
+                .writev_all_owned(head.followed_by(chunk))
+                .await
+                .map_err(BodyError::WriteError)?;
+        }
         BodyWriteMode::Empty => {
             return Err(BodyError::CalledWriteBodyChunkWhenNoBodyWasExpected);
         }
@@ -326,24 +462,164 @@ pub(crate) async fn write_h1_body_chunk(
     Ok(())
 }
 
-pub(crate) async fn write_h1_body_end(
+pub(crate) async fn write_h1_body_file(
     transport: &mut impl WriteOwned,
+    file: &File,
+    offset: u64,
+    len: u64,
     mode: BodyWriteMode,
 ) -> Result<(), BodyError> {
-    debug!(?mode, "writing h1 body end");
     match mode {
         BodyWriteMode::Chunked => {
+            if len == 0 {
+                // a zero-length chunk would terminate the body
+                return Ok(());
+            }
+            transport
+                .write_all_owned(Piece::from_fmt(format_args!("{:x}\r\n", len)))
+                .await
+                .map_err(BodyError::WriteError)?;
+            transport
+                .write_file_all(file, offset, len)
+                .await
+                .map_err(BodyError::WriteError)?;
             transport
-                .write_all_owned("0\r\n\r\n")
+                .write_all_owned("\r\n")
                 .await
                 .map_err(BodyError::WriteError)?;
         }
-        BodyWriteMode::ContentLength(..) => {
-            // nothing to do
+        BodyWriteMode::ContentLength(_) | BodyWriteMode::Tunnel | BodyWriteMode::CloseDelimited => {
+            transport
+                .write_file_all(file, offset, len)
+                .await
+                .map_err(BodyError::WriteError)?;
         }
         BodyWriteMode::Empty => {
-            // nothing to do
+            return Err(BodyError::CalledWriteBodyChunkWhenNoBodyWasExpected);
         }
     }
     Ok(())
 }
+
+/// Ends the body according to `mode`, after writing `head` (whatever the
+/// encoder held back so far), in a single write if there's anything to write
+pub(crate) async fn write_h1_body_end(
+    transport: &mut impl WriteOwned,
+    mut head: PieceList,
+    mode: BodyWriteMode,
+) -> Result<(), BodyError> {
+    debug!(?mode, "writing h1 body end");
+    if mode == BodyWriteMode::Chunked {
+        head.push_back("0\r\n\r\n");
+    }
+    if !head.is_empty() {
+        transport
+            .writev_all_owned(head)
+            .await
+            .map_err(BodyError::WriteError)?;
+    }
+    match mode {
+        BodyWriteMode::Chunked | BodyWriteMode::ContentLength(..) | BodyWriteMode::Empty => {
+            // nothing more to do
+        }
+        BodyWriteMode::Tunnel | BodyWriteMode::CloseDelimited => {
+            transport.shutdown().await.map_err(BodyError::WriteError)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use buffet::{PipeRead, RollMut, WriteOwned};
+
+    use super::{H1Body, H1BodyKind};
+    use crate::{Body, BodyChunk, BodyError, Headers};
+
+    const WITH_TRAILERS: &[u8] = b"5;name=value\r\nhello\r\n6 ; quoted=\"a;b\"\r\n world\r\n0\r\nx-checksum: abc\r\nhost: elsewhere\r\n\r\n";
+
+    /// Returns a chunked body that reads `input`, one byte at a time
+    fn chunked_body(input: &'static [u8]) -> H1Body<PipeRead> {
+        let (mut tx, rx) = buffet::pipe();
+        buffet::spawn(async move {
+            for &b in input {
+                if tx.write_all_owned(vec![b]).await.is_err() {
+                    break;
+                }
+            }
+        });
+        H1Body::new(rx, RollMut::alloc().unwrap(), H1BodyKind::Chunked)
+    }
+
+    async fn read_to_end(
+        body: &mut impl Body<Error = BodyError>,
+    ) -> Result<(Vec<u8>, Option<Box<Headers>>), BodyError> {
+        let mut data = Vec::new();
+        loop {
+            match body.next_chunk().await? {
+                BodyChunk::Chunk(chunk) => data.extend_from_slice(&chunk[..]),
+                BodyChunk::File { .. } => unreachable!(),
+                BodyChunk::Done { trailers } => return Ok((data, trailers)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunked_trailers() {
+        buffet::start(async move {
+            let mut body = chunked_body(WITH_TRAILERS);
+            let (data, trailers) = read_to_end(&mut body).await.unwrap();
+            assert_eq!(&data[..], b"hello world");
+
+            // `host` is not allowed in trailers
+            let trailers = trailers.unwrap();
+            assert_eq!(trailers.len(), 1);
+            assert_eq!(&trailers.get("x-checksum").unwrap()[..], b"abc");
+
+            let mut body = chunked_body(b"3\r\nabc\r\n0\r\n\r\n");
+            let (data, trailers) = read_to_end(&mut body).await.unwrap();
+            assert_eq!(&data[..], b"abc");
+            assert!(trailers.is_none());
+            assert!(body.into_inner().is_some());
+        });
+    }
+
+    #[test]
+    fn test_chunked_malformed() {
+        buffet::start(async move {
+            for input in [
+                &b"zz\r\nhello\r\n0\r\n\r\n"[..],
+                b"5 \r\nhello\r\n0\r\n\r\n",
+                b"5;\r\nhello\r\n0\r\n\r\n",
+                b"1ffffffffffffffff\r\n",
+                b"3\r\nabc\r\n-1\r\n\r\n",
+            ] {
+                let res = read_to_end(&mut chunked_body(input)).await;
+                assert!(
+                    matches!(res, Err(BodyError::InvalidChunkSize)),
+                    "expected InvalidChunkSize for {:?}, got {:?}",
+                    input.escape_ascii(),
+                    res.err()
+                );
+            }
+
+            let res = read_to_end(&mut chunked_body(b"3\r\nabcd\r\n0\r\n\r\n")).await;
+            assert!(matches!(res, Err(BodyError::InvalidChunkTerminator(_))));
+
+            let res = read_to_end(&mut chunked_body(b"0\r\nno-colon\r\n\r\n")).await;
+            assert!(matches!(res, Err(BodyError::InvalidTrailers(_))));
+
+            let res = read_to_end(&mut chunked_body(b"0\r\n")).await;
+            assert!(matches!(res, Err(BodyError::ClosedWhileReadingTrailers)));
+
+            let res = read_to_end(&mut chunked_body(b"0\r\nx-checksum: abc\r\n")).await;
+            assert!(matches!(res, Err(BodyError::InvalidTrailers(_))));
+        });
+    }
+
+    crate::body_test_suite!(|| {
+        let mut body = chunked_body(WITH_TRAILERS);
+        body.announce_trailers([http::HeaderName::from_static("x-checksum")]);
+        body
+    });
+}
@@ -5,7 +5,7 @@ use tracing::debug;
 use crate::{
     types::Request,
     util::{read_and_parse, ReadAndParseError},
-    Body, HeadersExt, Response,
+    Body, HeadersExt, Method, Response,
 };
 use buffet::{
     PieceList, RollMut, {ReadOwned, WriteOwned},
@@ -74,20 +74,34 @@ where
     W: WriteOwned,
     D: ClientDriver,
 {
+    // the framing headers have to match how we actually write the body,
+    // whatever the caller left in there
     let mode = match body.content_len() {
-        Some(0) => BodyWriteMode::Empty,
+        Some(0) => {
+            req.headers.remove(header::TRANSFER_ENCODING);
+            BodyWriteMode::Empty
+        }
         Some(len) => {
             // TODO: we can probably save a heap allocation here - we could format
             // directly to a `RollMut`, without going through `format!` machinery
+            req.headers.remove(header::TRANSFER_ENCODING);
             req.headers
                 .insert(header::CONTENT_LENGTH, len.to_string().into_bytes().into());
             BodyWriteMode::ContentLength(len)
         }
-        None => BodyWriteMode::Chunked,
+        None => {
+            req.headers.remove(header::CONTENT_LENGTH);
+            req.headers
+                .insert(header::TRANSFER_ENCODING, "chunked".into());
+            BodyWriteMode::Chunked
+        }
     };
 
     let mut buf = RollMut::alloc()?;
 
+    // responses to HEAD requests never have a body, whatever their headers say
+    let head_request = req.method == Method::Head;
+
     let mut list = PieceList::default();
     encode_request(req, &mut list, &mut buf)
         .map_err(Http1ClientError::WhileWritingRequestHeaders)?;
@@ -138,7 +152,10 @@ where
             let chunked = res.headers.is_chunked_transfer_encoding();
 
             // TODO: handle 204/304 separately
-            let content_len = res.headers.content_length().unwrap_or_default();
+            let content_len = res.headers.content_length();
+            // cf. https://httpwg.org/specs/rfc9112.html#message.body.length
+            let close_delimited =
+                !chunked && content_len.is_none() && !head_request && !res.means_empty_body();
 
             let mut res_body = H1Body::new(
                 transport_r,
@@ -147,12 +164,15 @@ where
                     // TODO: even with chunked transfer-encoding, we can announce
                     // a content length - we should probably detect errors there?
                     H1BodyKind::Chunked
+                } else if close_delimited {
+                    H1BodyKind::CloseDelimited
                 } else {
-                    H1BodyKind::ContentLength(content_len)
+                    H1BodyKind::ContentLength(content_len.unwrap_or_default())
                 },
             );
+            res_body.announce_trailers(res.headers.announced_trailers());
 
-            let conn_close = res.headers.is_connection_close();
+            let conn_close = res.headers.is_connection_close() || close_delimited;
 
             let ret = driver
                 .on_final_response(res, &mut res_body)
@@ -1,14 +1,16 @@
-use std::io::Write;
+use std::{cell::RefCell, fs::File, io::Write, rc::Rc, time::Instant};
 
 use http::{header, StatusCode, Version};
 
 use crate::{
+    metrics::MeteredWrite,
     types::{Headers, Request, Response},
-    BodyError, Encoder, HeadersExt,
+    util::cached_http_date,
+    BodyError, Encoder, HeadersExt, OnComplete,
 };
 use buffet::{Piece, PieceList, RollMut, WriteOwned};
 
-use super::body::{write_h1_body_chunk, write_h1_body_end, BodyWriteMode};
+use super::body::{write_h1_body_chunk, write_h1_body_end, write_h1_body_file, BodyWriteMode};
 
 pub(crate) fn encode_request(
     req: Request,
@@ -19,7 +21,8 @@ pub(crate) fn encode_request(
     list.push_back(" ");
 
     assert_eq!(out_scratch.len(), 0);
-    out_scratch.write_all(req.uri.path().as_bytes())?;
+    let path_and_query = req.uri.path_and_query().map_or("/", |pq| pq.as_str());
+    out_scratch.write_all(path_and_query.as_bytes())?;
     list.push_back(out_scratch.take_all());
 
     match req.version {
@@ -38,7 +41,7 @@ pub(crate) fn encode_request(
     Ok(())
 }
 
-fn encode_response(res: Response, list: &mut PieceList) -> Result<(), std::io::Error> {
+pub(crate) fn encode_response(res: Response, list: &mut PieceList) -> Result<(), std::io::Error> {
     match res.version {
         Version::HTTP_10 => list.push_back(&b"HTTP/1.0 "[..]),
         Version::HTTP_11 => list.push_back(&b"HTTP/1.1 "[..]),
@@ -149,12 +152,91 @@ const CODE_DIGITS: &str = "\
 960961962963964965966967968969970971972973974975976977978979\
 980981982983984985986987988989990991992993994995996997998999";
 
+/// Where an [H1Encoder] leaves the transport when it's dropped without the
+/// server getting it back, because the handler panicked or returned an
+/// error: the server can still answer with a 500.
+pub(crate) type Leftover<OurWriteOwned> = Rc<RefCell<Option<LeftoverTransport<OurWriteOwned>>>>;
+
+pub(crate) struct LeftoverTransport<OurWriteOwned>
+where
+    OurWriteOwned: WriteOwned,
+{
+    pub(crate) transport_w: MeteredWrite<OurWriteOwned>,
+    /// whether the final response's header section went out, in which case
+    /// it's too late for a 500
+    pub(crate) final_response_written: bool,
+}
+
 pub struct H1Encoder<OurWriteOwned>
 where
     OurWriteOwned: WriteOwned,
 {
-    pub(crate) transport_w: OurWriteOwned,
+    /// only `None` once it was handed back, cf. [H1Encoder::into_transport]
+    transport_w: Option<MeteredWrite<OurWriteOwned>>,
     mode: BodyWriteMode,
+
+    /// set by the server when the request is a CONNECT: a 2xx response
+    /// turns the connection into a tunnel
+    pub(crate) connect_request: bool,
+
+    /// set by the server when the request is a HEAD: the response keeps
+    /// its header fields, but no body goes out, cf. [Encoder::is_head_response]
+    pub(crate) head_request: bool,
+
+    /// set by the server when the request has `te: trailers`: a chunked
+    /// body may then end with trailers the response didn't announce
+    pub(crate) te_trailers: bool,
+
+    /// set by the server: HTTP/1.0 clients don't do chunked transfer
+    /// encoding, so bodies of unknown length are close-delimited instead
+    pub(crate) request_version: Version,
+
+    /// set by the server when the connection has served as many requests as
+    /// it may: the final response gets `connection: close`
+    pub(crate) last_request: bool,
+
+    /// set by the server: final responses get a `date` header if they don't
+    /// have one
+    pub(crate) date_header: bool,
+
+    /// set by the server: added to final responses, cf.
+    /// [super::ServerConf::default_response_headers]
+    pub(crate) default_headers: Option<Rc<Headers>>,
+
+    /// set by the server, cf. [super::ServerConf::max_response_header_size]
+    pub(crate) max_header_size: Option<usize>,
+
+    /// set by the server, cf. [Leftover]
+    pub(crate) leftover: Option<Leftover<OurWriteOwned>>,
+
+    /// cf. [Encoder::close_delimited]
+    close_delimited: bool,
+
+    /// whether the final response is chunked, and announced trailers or
+    /// answers a request with `te: trailers`: the last chunk then leaves the trailer section open, for
+    /// [Encoder::write_trailers] to fill and end
+    trailer_section: bool,
+
+    /// a final response's header section, held back so it goes out in the
+    /// same write as the first body chunk (or the body end)
+    head: PieceList,
+
+    /// whether a final (non-1xx) response's header section was written
+    final_response_written: bool,
+
+    /// whether the response we wrote means the connection can't be reused
+    closes_connection: bool,
+
+    /// what `transport_w` had written before this response
+    written_before: u64,
+
+    /// bytes of header sections written for this response
+    headers_len: u64,
+
+    /// when the first header section of this response was written
+    pub(crate) first_byte_at: Option<Instant>,
+
+    pub(crate) on_complete: Option<OnComplete>,
 }
 
 impl<OurWriteOwned> H1Encoder<OurWriteOwned>
@@ -162,9 +244,110 @@ where
     OurWriteOwned: WriteOwned,
 {
     pub fn new(transport_w: OurWriteOwned) -> Self {
+        Self::metered(MeteredWrite::new(transport_w, None))
+    }
+
+    pub(crate) fn metered(transport_w: MeteredWrite<OurWriteOwned>) -> Self {
         Self {
-            transport_w,
+            written_before: transport_w.total(),
+            transport_w: Some(transport_w),
             mode: BodyWriteMode::Empty,
+            connect_request: false,
+            head_request: false,
+            te_trailers: false,
+            request_version: Version::HTTP_11,
+            last_request: false,
+            date_header: false,
+            default_headers: None,
+            max_header_size: None,
+            leftover: None,
+            close_delimited: false,
+            trailer_section: false,
+            head: PieceList::default(),
+            final_response_written: false,
+            closes_connection: false,
+            headers_len: 0,
+            first_byte_at: None,
+            on_complete: None,
+        }
+    }
+
+    /// Returns how many bytes of header sections and body went out for this
+    /// response, framing included
+    pub(crate) fn response_sizes(&self) -> (u64, u64) {
+        let total = self
+            .transport_w
+            .as_ref()
+            .map_or(self.written_before, |w| w.total())
+            - self.written_before;
+        (self.headers_len, total - self.headers_len)
+    }
+
+    /// Returns true if we accepted a CONNECT request, in which case the
+    /// connection can't go back to HTTP/1.1
+    pub(crate) fn is_tunnel(&self) -> bool {
+        self.mode == BodyWriteMode::Tunnel
+    }
+
+    /// Returns true if the final response we wrote had `connection: close`,
+    /// or a close-delimited body: either way, the server must hang up after
+    /// it
+    pub(crate) fn closes_connection(&self) -> bool {
+        self.closes_connection
+    }
+
+    /// Hands the transport back, for the next response
+    pub(crate) fn into_transport(mut self) -> MeteredWrite<OurWriteOwned> {
+        debug_assert!(self.head.is_empty(), "header section was never written");
+        self.transport_w.take().unwrap()
+    }
+
+    /// Returns true if the final response's body is chunked
+    pub(crate) fn is_chunked(&self) -> bool {
+        self.mode == BodyWriteMode::Chunked
+    }
+
+    /// Takes the held back header section, if any, for the caller to write
+    /// before anything else. Once it's written, call [Self::head_written].
+    pub(crate) fn take_head(&mut self) -> PieceList {
+        std::mem::take(&mut self.head)
+    }
+
+    /// Records that the header section taken with [Self::take_head] went out
+    pub(crate) fn head_written(&mut self) {
+        if !self.final_response_written {
+            self.first_byte_at.get_or_insert_with(Instant::now);
+            self.final_response_written = true;
+        }
+    }
+
+    /// Writes out the held back header section, if any
+    async fn write_head(&mut self) -> Result<(), H1EncoderError> {
+        let head = self.take_head();
+        if !head.is_empty() {
+            self.transport_w().writev_all_owned(head).await?;
+        }
+        self.head_written();
+        Ok(())
+    }
+
+    pub(crate) fn transport_w(&mut self) -> &mut MeteredWrite<OurWriteOwned> {
+        self.transport_w
+            .as_mut()
+            .expect("the transport is only taken when the encoder is done")
+    }
+}
+
+impl<OurWriteOwned> Drop for H1Encoder<OurWriteOwned>
+where
+    OurWriteOwned: WriteOwned,
+{
+    fn drop(&mut self) {
+        if let (Some(leftover), Some(transport_w)) = (&self.leftover, self.transport_w.take()) {
+            *leftover.borrow_mut() = Some(LeftoverTransport {
+                transport_w,
+                final_response_written: self.final_response_written,
+            });
         }
     }
 }
@@ -181,6 +364,14 @@ pub enum H1EncoderError {
     },
     #[error("Body error: {0}")]
     BodyError(#[from] BodyError),
+    /// The response's header section is larger than
+    /// [super::ServerConf::max_response_header_size]: none of it was written
+    #[error("Response header section of {len} bytes exceeds the limit of {max}")]
+    ResponseHeadersTooLarge { len: usize, max: usize },
+    /// Trailers can only follow a chunked body, whose response announced
+    /// them with a `trailer` header (or whose request had `te: trailers`)
+    #[error("Trailers weren't announced, or the body isn't chunked")]
+    TrailersNotAnnounced,
 }
 
 impl AsRef<dyn std::error::Error> for H1EncoderError {
@@ -196,10 +387,44 @@ where
     type Error = H1EncoderError;
 
     async fn write_response(&mut self, mut res: Response) -> Result<(), Self::Error> {
-        if !res.status.is_informational() && !res.means_empty_body() {
+        if !res.status.is_informational() {
+            // `identity` isn't a transfer coding anymore, cf.
+            // https://httpwg.org/specs/rfc9112.html#transfer.codings: it's
+            // taken to mean "no framing", and not sent
+            let identity = res
+                .headers
+                .get(header::TRANSFER_ENCODING)
+                .is_some_and(|value| value.eq_ignore_ascii_case(b"identity"));
+            if identity {
+                res.headers.remove(header::TRANSFER_ENCODING);
+                self.close_delimited = true;
+            }
+        }
+
+        if self.connect_request && res.status.is_success() {
+            // cf. https://httpwg.org/specs/rfc9110.html#CONNECT: the tunnel
+            // starts right after the header section
+            res.headers.remove(header::CONTENT_LENGTH);
+            res.headers.remove(header::TRANSFER_ENCODING);
+            self.mode = BodyWriteMode::Tunnel;
+        } else if self.head_request && !res.status.is_informational() {
+            // whatever framing the body would have had, there's none
+            self.mode = BodyWriteMode::Empty;
+        } else if self.close_delimited && !res.status.is_informational() && !res.means_empty_body()
+        {
+            res.headers.remove(header::CONTENT_LENGTH);
+            res.headers.remove(header::TRANSFER_ENCODING);
+            res.headers.insert(header::CONNECTION, "close".into());
+            self.mode = BodyWriteMode::CloseDelimited;
+        } else if !res.status.is_informational() && !res.means_empty_body() {
             self.mode = match res.headers.content_length() {
                 Some(0) => BodyWriteMode::Empty,
                 Some(length) => BodyWriteMode::ContentLength(length),
+                None if self.request_version == Version::HTTP_10 => {
+                    // cf. https://httpwg.org/specs/rfc9112.html#message.body.length
+                    res.headers.insert(header::CONNECTION, "close".into());
+                    BodyWriteMode::CloseDelimited
+                }
                 None => {
                     res.headers
                         .insert(header::TRANSFER_ENCODING, "chunked".into());
@@ -207,14 +432,53 @@ where
                 }
             };
         }
+        if !res.status.is_informational() {
+            self.trailer_section = self.is_chunked()
+                && (self.te_trailers || res.headers.contains_key(header::TRAILER));
+        }
+        if self.last_request && !res.status.is_informational() && !self.is_tunnel() {
+            res.headers.insert(header::CONNECTION, "close".into());
+        }
+        if !res.status.is_informational() && res.headers.is_connection_close() {
+            self.closes_connection = true;
+        }
+        if !res.status.is_informational() {
+            if let Some(defaults) = &self.default_headers {
+                res.headers.merge_defaults(defaults);
+            }
+            if self.date_header {
+                res.headers
+                    .entry(header::DATE)
+                    .or_insert_with(cached_http_date);
+            }
+        }
 
+        let informational = res.status.is_informational();
         let mut list = PieceList::default();
         encode_response(res, &mut list)?;
+        if let Some(max) = self.max_header_size {
+            if list.len() > max {
+                return Err(H1EncoderError::ResponseHeadersTooLarge {
+                    len: list.len(),
+                    max,
+                });
+            }
+        }
+        self.headers_len += list.len() as u64;
 
-        self.transport_w
+        if !informational && !self.is_tunnel() {
+            // goes out with the first body chunk, or the body end, cf.
+            // `write_h1_body_chunk`
+            self.head = list;
+            return Ok(());
+        }
+
+        self.transport_w()
             .writev_all_owned(list)
             .await
             .map_err(H1EncoderError::from)?;
+        self.first_byte_at.get_or_insert_with(Instant::now);
+        self.final_response_written |= !informational;
 
         Ok(())
     }
@@ -222,27 +486,93 @@ where
     async fn write_body_chunk(&mut self, chunk: Piece) -> Result<(), Self::Error> {
         // note: we don't check content length here, because it's done by the Responder,
         // note by encoders.
+        if self.head_request {
+            return Ok(());
+        }
+
+        let (head, mode) = (self.take_head(), self.mode);
+        write_h1_body_chunk(self.transport_w(), head, chunk, mode).await?;
+        self.head_written();
+        Ok(())
+    }
 
-        write_h1_body_chunk(&mut self.transport_w, chunk, self.mode)
+    async fn write_body_file(
+        &mut self,
+        file: Rc<File>,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), Self::Error> {
+        if self.head_request {
+            return Ok(());
+        }
+        self.write_head().await?;
+        // whether this goes through userspace or not is up to the transport,
+        // cf. `WriteOwned::write_file_all`
+        let mode = self.mode;
+        write_h1_body_file(self.transport_w(), &file, offset, len, mode)
             .await
             .map_err(H1EncoderError::from)
     }
 
     async fn write_body_end(&mut self) -> Result<(), Self::Error> {
-        write_h1_body_end(&mut self.transport_w, self.mode)
-            .await
-            .map_err(H1EncoderError::from)
+        if self.trailer_section {
+            // the trailer section, and the blank line after it, come next
+            let list = self.take_head().followed_by("0\r\n");
+            self.transport_w().writev_all_owned(list).await?;
+            self.head_written();
+            return Ok(());
+        }
+        let (head, mode) = (self.take_head(), self.mode);
+        write_h1_body_end(self.transport_w(), head, mode).await?;
+        self.head_written();
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.write_head().await
     }
 
     async fn write_trailers(&mut self, trailers: Box<Headers>) -> Result<(), Self::Error> {
+        if self.head_request {
+            // no body, no trailer section
+            return Ok(());
+        }
+        if !std::mem::take(&mut self.trailer_section) {
+            return Err(H1EncoderError::TrailersNotAnnounced);
+        }
         let mut list = PieceList::default();
         encode_headers(*trailers, &mut list)?;
+        list.push_back("\r\n");
 
-        self.transport_w
+        self.transport_w()
             .writev_all_owned(list)
             .await
             .map_err(H1EncoderError::from)?;
 
         Ok(())
     }
+
+    fn on_complete(&mut self, callback: OnComplete) {
+        self.on_complete = Some(callback);
+    }
+
+    fn is_head_response(&self) -> bool {
+        self.head_request
+    }
+
+    fn accepts_trailers(&self) -> bool {
+        self.te_trailers && self.is_chunked()
+    }
+
+    fn close_delimited(&mut self) -> bool {
+        self.close_delimited = true;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::H1Encoder;
+
+    crate::encoder_test_suite!(|| H1Encoder::new(Vec::<u8>::new()));
 }
@@ -0,0 +1,90 @@
+//! Requests the HTTP/1.1 server turns away on its own, without involving the
+//! driver, and the canned responses it sends for them.
+
+use std::rc::Rc;
+
+use buffet::{Piece, PieceList};
+use http::header;
+
+use crate::{
+    error::ConnectionError,
+    util::{cached_http_date, ReadAndParseError},
+    HeadersExt, Response, ServeOutcome,
+};
+
+use super::{encode::encode_response, parse::UNSUPPORTED_VERSION, ServerConf};
+
+/// For errors reading the request headers that are the client's fault
+pub(crate) fn request_error(e: &ReadAndParseError) -> Option<ConnectionError> {
+    match e {
+        ReadAndParseError::BufferLimitReachedWhileParsing { .. }
+        | ReadAndParseError::LimitExceeded { .. } => Some(ConnectionError::HeadersTooLarge),
+        ReadAndParseError::ParsingError { kind, .. } if *kind == UNSUPPORTED_VERSION => {
+            Some(ConnectionError::UnsupportedVersion)
+        }
+        ReadAndParseError::ParsingError { .. } => Some(ConnectionError::MalformedRequest),
+        _ => None,
+    }
+}
+
+/// How the connection ends after rejecting a request for `error`: we can't
+/// tell where the next request starts, so it's closed.
+pub(crate) fn outcome(error: ConnectionError) -> ServeOutcome {
+    match error {
+        ConnectionError::MalformedRequest | ConnectionError::UnsupportedVersion => {
+            ServeOutcome::ClientDidntSpeakHttp11
+        }
+        ConnectionError::HeadersTooLarge => ServeOutcome::RequestHeadersTooLargeOnHttp1Conn,
+        ConnectionError::MemoryPressure => ServeOutcome::RefusedUnderMemoryPressure,
+        ConnectionError::TlsUpgrade => ServeOutcome::RefusedTlsUpgrade,
+        _ => ServeOutcome::RejectedInvalidRequest,
+    }
+}
+
+/// The body of a canned response, cf. [ErrorBodyHook]
+#[derive(Clone)]
+pub struct ErrorBody {
+    pub content_type: Piece,
+    pub body: Piece,
+}
+
+/// Builds the body of the server's canned responses, cf.
+/// [super::ServerConf::error_body]. Returning `None` sends an empty body.
+pub type ErrorBodyHook = Rc<dyn Fn(&ConnectionError) -> Option<ErrorBody>>;
+
+/// The whole response for `error`, ready to be written. It announces that the
+/// connection closes unless `reusable`, and gets the same default fields as
+/// the responses drivers write.
+pub(crate) fn canned_response(
+    error: &ConnectionError,
+    conf: &ServerConf,
+    reusable: bool,
+) -> std::io::Result<PieceList> {
+    let body = conf.error_body.as_ref().and_then(|hook| hook(error));
+
+    let mut res = Response {
+        status: error.status(),
+        ..Default::default()
+    };
+    if !reusable {
+        res.headers.insert(header::CONNECTION, "close".into());
+    }
+    let len = body.as_ref().map_or(0, |b| b.body.len());
+    res.headers
+        .insert(header::CONTENT_LENGTH, len.to_string().into_bytes().into());
+    if let Some(body) = &body {
+        res.headers
+            .insert(header::CONTENT_TYPE, body.content_type.clone());
+    }
+    res.headers.merge_defaults(&conf.default_response_headers);
+    if conf.date_header {
+        res.headers.insert(header::DATE, cached_http_date());
+    }
+
+    let mut list = PieceList::default();
+    encode_response(res, &mut list)?;
+    if let Some(body) = body {
+        list.push_back(body.body);
+    }
+    Ok(list)
+}
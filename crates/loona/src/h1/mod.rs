@@ -7,6 +7,9 @@ pub use client::*;
 mod server;
 pub use server::*;
 
+mod error_responses;
+pub use error_responses::*;
+
 pub(crate) mod body;
 pub(crate) mod parse;
 
@@ -3,25 +3,105 @@
 //! HTTP/1.1 <https://httpwg.org/specs/rfc9112.html>
 //! HTTP semantics <https://httpwg.org/specs/rfc9110.html>
 
-use http::{header::HeaderName, StatusCode, Version};
+use std::cell::RefCell;
+
+use http::{header::HeaderName, StatusCode, Uri, Version};
 use nom::{
-    bytes::streaming::{tag, take, take_until, take_while1},
-    combinator::{map_res, opt},
+    bytes::streaming::{tag, take, take_until, take_while, take_while1, take_while_m_n},
+    combinator::{map_opt, map_res, opt},
+    error::ErrorKind,
     sequence::{preceded, terminated},
     IResult,
 };
 
 use crate::{
-    types::{Headers, Request, Response},
+    types::{HeaderNameInterner, Headers, Request, RequestTarget, Response},
     Method,
 };
 use buffet::{PieceStr, Roll, RollStr};
 
 const CRLF: &[u8] = b"\r\n";
 
-/// Parses a chunked transfer coding chunk size (hex text followed by CRLF)
+/// Parses a chunked transfer coding chunk size (hex text, then optional
+/// chunk extensions, then CRLF). Extensions are checked, then ignored.
+/// cf. <https://httpwg.org/specs/rfc9112.html#chunked.encoding>
 pub fn chunk_size(i: Roll) -> IResult<Roll, u64> {
-    terminated(u64_text_hex, tag(CRLF))(i)
+    let (i, size) = u64_text_hex(i)?;
+    let (i, _) = chunk_extensions(i)?;
+    let (i, _) = tag(CRLF)(i)?;
+    Ok((i, size))
+}
+
+/// `*( BWS ";" BWS chunk-ext-name [ BWS "=" BWS chunk-ext-val ] )`, cf.
+/// <https://httpwg.org/specs/rfc9112.html#chunked.extension>
+fn chunk_extensions(mut i: Roll) -> IResult<Roll, ()> {
+    loop {
+        // whitespace is only allowed before a `;`, so if there's no `;`,
+        // leave it for the CRLF parser to reject
+        let (rest, _) = bws(i.clone())?;
+        let (rest, semicolon) = opt(tag(&b";"[..]))(rest)?;
+        if semicolon.is_none() {
+            return Ok((i, ()));
+        }
+
+        let (rest, _) = bws(rest)?;
+        let (rest, _) = take_while1(is_tchar)(rest)?;
+
+        let (after_bws, _) = bws(rest.clone())?;
+        let (after_eq, eq) = opt(tag(&b"="[..]))(after_bws)?;
+        i = match eq {
+            Some(_) => {
+                let (rest, _) = bws(after_eq)?;
+                if rest.first() == Some(&b'"') {
+                    quoted_string(rest)?.0
+                } else {
+                    take_while1(is_tchar)(rest)?.0
+                }
+            }
+            None => rest,
+        };
+    }
+}
+
+/// "Bad" whitespace, which is allowed for historical reasons, cf.
+/// <https://httpwg.org/specs/rfc9110.html#whitespace>
+fn bws(i: Roll) -> IResult<Roll, ()> {
+    let (i, _) = take_while(|c| c == b' ' || c == b'\t')(i)?;
+    Ok((i, ()))
+}
+
+/// cf. <https://httpwg.org/specs/rfc9110.html#quoted.strings>
+fn quoted_string(i: Roll) -> IResult<Roll, ()> {
+    let (i, _) = tag(&b"\""[..])(i)?;
+
+    let mut idx = 0;
+    loop {
+        let Some(c) = i.get(idx).copied() else {
+            return Err(nom::Err::Incomplete(nom::Needed::Unknown));
+        };
+        match c {
+            b'"' => return Ok((take(idx + 1)(i)?.0, ())),
+            // quoted-pair: the next character stands for itself
+            b'\\' => match i.get(idx + 1).copied() {
+                Some(c) if is_quoted_char(c) => idx += 2,
+                Some(_) => break,
+                None => return Err(nom::Err::Incomplete(nom::Needed::Unknown)),
+            },
+            c if is_quoted_char(c) => idx += 1,
+            _ => break,
+        }
+    }
+
+    Err(nom::Err::Error(nom::error::Error::new(
+        i,
+        nom::error::ErrorKind::Char,
+    )))
+}
+
+/// Characters allowed in a quoted string (`qdtext`, or after a backslash),
+/// besides `"` and `\`
+fn is_quoted_char(c: u8) -> bool {
+    c == b'\t' || c == b' ' || c.is_ascii_graphic() || c >= 0x80
 }
 
 pub fn crlf(i: Roll) -> IResult<Roll, ()> {
@@ -29,20 +109,45 @@ pub fn crlf(i: Roll) -> IResult<Roll, ()> {
     Ok((i, ()))
 }
 
-// Looks like `GET /path HTTP/1.1\r\n`, then headers
-pub fn request(i: Roll) -> IResult<Roll, Request> {
+// Looks like `GET /path HTTP/1.1\r\n`, then at most `max_headers` headers
+pub fn request(max_headers: usize) -> impl Fn(Roll) -> IResult<Roll, Request> {
+    move |i| request_inner(i, max_headers, None)
+}
+
+/// Like [request], but header names go through `names`
+pub fn request_interning(
+    max_headers: usize,
+    names: &RefCell<HeaderNameInterner>,
+) -> impl Fn(Roll) -> IResult<Roll, Request> + '_ {
+    move |i| request_inner(i, max_headers, Some(names))
+}
+
+fn request_inner(
+    i: Roll,
+    max_headers: usize,
+    names: Option<&RefCell<HeaderNameInterner>>,
+) -> IResult<Roll, Request> {
     let (i, method) = terminated(method, space1)(i)?;
-    let (i, path) = terminated(path, space1)(i)?;
+    let (i, uri) = terminated(request_target, space1)(i)?;
     let (i, version) = terminated(http_version, tag(CRLF))(i)?;
-    let (i, headers) = headers_and_crlf(i)?;
+    let (i, headers) = headers_and_crlf_inner(i, max_headers, names)?;
+
+    if RequestTarget::new(&method, &uri).is_err() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            i,
+            ErrorKind::Verify,
+        )));
+    }
 
     let request = Request {
         method,
-        // TODO: should this take the host header into account?
-        // check what hyper does.
-        uri: path.parse().unwrap(),
+        uri,
         version,
         headers,
+        // filled in by the server
+        conn: None,
+        stream_id: None,
+        protocol: None,
     };
     Ok((i, request))
 }
@@ -71,10 +176,12 @@ fn is_delimiter(c: u8) -> bool {
     memchr::memchr(c, br#"(),/:;<=>?@[\]{}""#).is_some()
 }
 
-fn path(i: Roll) -> IResult<Roll, RollStr> {
-    let (i, path) = take_while1(is_uri_char)(i)?;
-    let path = unsafe { path.to_string_unchecked() };
-    Ok((i, path))
+/// Any of the forms in <https://httpwg.org/specs/rfc9112.html#request.target>,
+/// which one is allowed depends on the method, cf. [RequestTarget::new]
+fn request_target(i: Roll) -> IResult<Roll, Uri> {
+    map_res(take_while1(is_uri_char), |target: Roll| {
+        Uri::try_from(&target[..])
+    })(i)
 }
 
 /// Returns true if `c` is a character that can be found in an URI
@@ -93,7 +200,7 @@ pub fn response(i: Roll) -> IResult<Roll, Response> {
     let (i, version) = terminated(http_version, space1)(i)?;
     let (i, code) = terminated(status_code, space1)(i)?;
     let (i, _reason) = terminated(take_until(CRLF), tag(CRLF))(i)?;
-    let (i, headers) = headers_and_crlf(i)?;
+    let (i, headers) = headers_and_crlf(usize::MAX)(i)?;
 
     let response = Response {
         version,
@@ -111,25 +218,31 @@ fn status_code(i: Roll) -> IResult<Roll, StatusCode> {
 
 /// Parses text as a hex u64
 fn u64_text_hex(i: Roll) -> IResult<Roll, u64> {
-    // TODO: limit how many digits we read
-    let f = take_while1(nom::character::is_hex_digit);
-    // FIXME: this is inefficient (calling `to_vec` just to parse)
-    let f = map_res(f, |s: Roll| String::from_utf8(s.to_vec()));
-    let mut f = map_res(f, |s| u64::from_str_radix(&s, 16));
+    // 16 hex digits is all a u64 can hold: stop there, so a longer size fails
+    // to parse instead of overflowing (or buffering forever)
+    let f = take_while_m_n(1, 16, nom::character::is_hex_digit);
+    let mut f = map_opt(f, |s: Roll| u64::from_str_radix(s.as_str().ok()?, 16).ok());
     f(i)
 }
 
+/// What [http_version] fails with for well-formed versions other than
+/// HTTP/1.0 and HTTP/1.1, e.g. `HTTP/2.0`, so the server can reply with a
+/// 505 rather than a 400
+pub(crate) const UNSUPPORTED_VERSION: ErrorKind = ErrorKind::Not;
+
 pub fn http_version(i: Roll) -> IResult<Roll, Version> {
-    let (i, _) = tag(&b"HTTP/1."[..])(i)?;
-    let (i, version) = take(1usize)(i)?;
-    let version = match version.iter().next().unwrap() {
-        b'0' => Version::HTTP_10,
-        b'1' => Version::HTTP_11,
+    let (i, _) = tag(&b"HTTP/"[..])(i)?;
+    let start = i.clone();
+    let digit = |i| take_while_m_n(1, 1, |c: u8| c.is_ascii_digit())(i);
+    let (i, major) = terminated(digit, tag(&b"."[..]))(i)?;
+    let (i, minor) = digit(i)?;
+    let version = match (major[0], minor[0]) {
+        (b'1', b'0') => Version::HTTP_10,
+        (b'1', b'1') => Version::HTTP_11,
         _ => {
-            return Err(nom::Err::Error(nom::error::Error::new(
-                i,
-                // FIXME: this is not good error reporting
-                nom::error::ErrorKind::Digit,
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                start,
+                UNSUPPORTED_VERSION,
             )));
         }
     };
@@ -137,7 +250,17 @@ pub fn http_version(i: Roll) -> IResult<Roll, Version> {
     Ok((i, version))
 }
 
-pub fn headers_and_crlf(mut i: Roll) -> IResult<Roll, Headers> {
+/// Parses headers up to the empty line that ends them. Having more than
+/// `max_count` of them is a failure with [ErrorKind::TooLarge].
+pub fn headers_and_crlf(max_count: usize) -> impl Fn(Roll) -> IResult<Roll, Headers> {
+    move |i| headers_and_crlf_inner(i, max_count, None)
+}
+
+fn headers_and_crlf_inner(
+    mut i: Roll,
+    max_count: usize,
+    names: Option<&RefCell<HeaderNameInterner>>,
+) -> IResult<Roll, Headers> {
     let mut headers = Headers::default();
     loop {
         if let (i, Some(_)) = opt(tag(CRLF))(i.clone())? {
@@ -145,16 +268,27 @@ pub fn headers_and_crlf(mut i: Roll) -> IResult<Roll, Headers> {
             return Ok((i, headers));
         }
 
-        let (i_next, (name, value)) = header(i)?;
+        if headers.len() >= max_count {
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                i,
+                ErrorKind::TooLarge,
+            )));
+        }
+
+        let (i_next, (name, value)) = header(i, names)?;
         headers.append(name, value.into());
         i = i_next;
     }
 }
 
 /// Parse a single header line
-fn header(i: Roll) -> IResult<Roll, (HeaderName, Roll)> {
-    let (i, name) = map_res(take_until_and_consume(b":"), |s: Roll| {
-        HeaderName::from_bytes(&s[..])
+fn header(
+    i: Roll,
+    names: Option<&RefCell<HeaderNameInterner>>,
+) -> IResult<Roll, (HeaderName, Roll)> {
+    let (i, name) = map_res(take_until_and_consume(b":"), |s: Roll| match names {
+        Some(names) => names.borrow_mut().intern(&s[..]),
+        None => HeaderName::from_bytes(&s[..]),
     })(i)?;
     let (i, value) = preceded(space1, take_until_and_consume(CRLF))(i)?;
 
@@ -174,7 +308,9 @@ fn take_until_and_consume(needle: &[u8]) -> impl FnMut(Roll) -> IResult<Roll, Ro
 
 #[cfg(test)]
 mod tests {
-    use crate::h1::parse::is_delimiter;
+    use buffet::{Roll, RollMut};
+
+    use crate::h1::parse::{chunk_size, is_delimiter, request};
 
     #[test]
     fn test_h1_parse_various_lowlevel_functions() {
@@ -183,4 +319,147 @@ mod tests {
         assert!(is_delimiter(b'\\'));
         assert!(!is_delimiter(b'B'));
     }
+
+    fn roll(input: &[u8]) -> Roll {
+        let mut buf = RollMut::alloc().unwrap();
+        buf.put(input).unwrap();
+        buf.filled()
+    }
+
+    #[test]
+    fn test_chunk_size() {
+        buffet::bufpool::initialize_allocator().unwrap();
+
+        for (input, size) in [
+            (&b"0\r\n"[..], 0),
+            (b"1a\r\n", 0x1a),
+            (b"FFFF\r\n", 0xffff),
+            (b"ffffffffffffffff\r\n", u64::MAX),
+            (b"5;foo\r\n", 5),
+            (b"5;foo=bar\r\n", 5),
+            (b"5 ; foo = bar;baz\r\n", 5),
+            (b"5;foo=\"a \\\"quoted\\\" value;\"\r\n", 5),
+            (b"5;foo=\"\"\r\n", 5),
+        ] {
+            let (rest, parsed) = chunk_size(roll(input))
+                .unwrap_or_else(|e| panic!("failed to parse {:?}: {e:?}", input.escape_ascii()));
+            assert_eq!(parsed, size, "for {:?}", input.escape_ascii());
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_chunk_size_malformed() {
+        buffet::bufpool::initialize_allocator().unwrap();
+
+        for input in [
+            &b"\r\n"[..],
+            b"g\r\n",
+            b"-1\r\n",
+            b"+1\r\n",
+            b"0x10\r\n",
+            b"10000000000000000\r\n",
+            b"5 \r\n",
+            b"5;\r\n",
+            b"5;foo=\r\n",
+            b"5;=bar\r\n",
+            b"5;foo=bar baz\r\n",
+            b"5;foo=\"bar\"baz\r\n",
+            b"5;foo=\"b\nar\"\r\n",
+            b"5;f\x00oo\r\n",
+            b"5\n",
+            b"5\rx",
+        ] {
+            match chunk_size(roll(input)) {
+                Err(nom::Err::Error(_) | nom::Err::Failure(_)) => {}
+                res => panic!("{:?} should be invalid, got {res:?}", input.escape_ascii()),
+            }
+        }
+
+        // these could still turn out fine
+        for input in [&b"5"[..], b"5;foo", b"5;foo=\"bar", b"5;foo=\"\\", b"5\r"] {
+            assert!(
+                matches!(chunk_size(roll(input)), Err(nom::Err::Incomplete(_))),
+                "{:?} should be incomplete",
+                input.escape_ascii()
+            );
+        }
+    }
+
+    /// Throws random garbage made of the characters that matter to the
+    /// chunk size grammar at the parser: it must never panic, and whatever
+    /// it accepts must start with the size it returns.
+    #[test]
+    fn test_chunk_size_garbage() {
+        buffet::bufpool::initialize_allocator().unwrap();
+
+        const ALPHABET: &[u8] = b"0123456789abcdefABCDEFgx;=\" \t\r\n\\\x00\x7f\xff";
+
+        // xorshift, so failures are reproducible
+        let mut state: u64 = 0x2545f4914f6cdd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..20_000 {
+            let len = (next() % 24) as usize;
+            let mut input: Vec<u8> = (0..len)
+                .map(|_| ALPHABET[(next() % ALPHABET.len() as u64) as usize])
+                .collect();
+            if next() % 2 == 0 {
+                // give it a plausible start
+                input.insert(0, b'a');
+            }
+
+            if let Ok((rest, size)) = chunk_size(roll(&input)) {
+                let line = &input[..input.len() - rest.len()];
+                assert!(line.ends_with(b"\r\n"));
+                let digits: Vec<u8> = line
+                    .iter()
+                    .copied()
+                    .take_while(u8::is_ascii_hexdigit)
+                    .collect();
+                let digits = std::str::from_utf8(&digits).unwrap();
+                assert_eq!(
+                    u64::from_str_radix(digits, 16).unwrap(),
+                    size,
+                    "for {:?}",
+                    input.escape_ascii()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_request_target_forms() {
+        buffet::bufpool::initialize_allocator().unwrap();
+
+        let parse = |line: &str| request(16)(roll(format!("{line}\r\n\r\n").as_bytes()));
+        for line in [
+            "GET /where?q=now HTTP/1.1",
+            "GET http://www.example.org/pub HTTP/1.1",
+            "CONNECT www.example.com:80 HTTP/1.1",
+            "OPTIONS * HTTP/1.1",
+        ] {
+            let (_, req) =
+                parse(line).unwrap_or_else(|e| panic!("failed to parse {line:?}: {e:?}"));
+            assert!(req.target().is_ok(), "{line:?}");
+        }
+
+        for line in [
+            "GET www.example.com:80 HTTP/1.1",
+            "CONNECT /where HTTP/1.1",
+            "GET * HTTP/1.1",
+            "GET [ HTTP/1.1",
+            "GET http:// HTTP/1.1",
+        ] {
+            assert!(
+                matches!(parse(line), Err(nom::Err::Error(_))),
+                "{line:?} should be rejected"
+            );
+        }
+    }
 }
@@ -1,42 +1,153 @@
-use std::rc::Rc;
+use std::{cell::RefCell, rc::Rc, time::Instant};
 
-use tracing::debug;
+use tracing::{debug, debug_span, field, warn, Instrument};
 
 use crate::{
-    error::ServeError,
+    error::{ConnectionError, ServeError},
+    fd_budget::{pruned, FdBudget},
     h1::body::{H1Body, H1BodyKind},
-    util::{read_and_parse, ReadAndParseError},
-    HeadersExt, Responder, ServeOutcome, ServerDriver,
+    h2c::{self, H2cUpgrade},
+    metrics::{
+        count_connection_error, ConnGauges, Histogram, MeteredRead, MeteredWrite, MetricsSink,
+    },
+    pressure::PressureConf,
+    protocol_errors::ProtocolErrorLog,
+    util::{catch_handler_panic, read_and_parse_keeping_input, ReadAndParseError},
+    ConnInfo, HeaderNameInterner, Headers, HeadersExt, Method, Responder, ServeOutcome,
+    ServerDriver, ServerDriverFactory, Timings, WireSizes,
 };
 use buffet::{ReadOwned, RollMut, WriteOwned};
+use http::{header, Version};
 
-use super::encode::H1Encoder;
+use super::{
+    encode::{H1Encoder, Leftover, LeftoverTransport},
+    error_responses::{canned_response, outcome, request_error, ErrorBodyHook},
+};
 
 pub struct ServerConf {
-    /// Max length of the request line + HTTP headers
-    pub max_http_header_len: usize,
+    /// Max length of the request line + HTTP headers. Requests over it get
+    /// a 431 and the connection is closed.
+    pub max_header_section_size: usize,
 
     /// Max length of a single header record, e.g. `user-agent: foobar`
     pub max_header_record_len: usize,
 
-    /// Max number of header records
-    pub max_header_records: usize,
+    /// Max length of a response's status line + HTTP headers, as they'd go
+    /// on the wire. Handlers that go over it get
+    /// [H1EncoderError::ResponseHeadersTooLarge](super::encode::H1EncoderError::ResponseHeadersTooLarge)
+    /// and nothing is written.
+    pub max_response_header_size: usize,
+
+    /// Max number of header records. Requests over it get a 431 and the
+    /// connection is closed.
+    pub max_header_count: usize,
+
+    /// How many distinct header names a connection remembers, so repeats
+    /// share storage instead of being copied for every request, cf.
+    /// [HeaderNameInterner](crate::HeaderNameInterner). 0 turns it off.
+    pub max_interned_header_names: usize,
+
+    /// Where to report connection, byte and request duration metrics, if
+    /// anywhere.
+    pub metrics: Option<Rc<dyn MetricsSink>>,
+
+    /// When to start turning requests away because the buffer pool is
+    /// running low.
+    pub pressure: PressureConf,
+
+    /// How many requests a connection can serve: the response to the last
+    /// one has `connection: close`, and the server hangs up after it. Bounds
+    /// connection lifetime, so clients reconnect (and get balanced again)
+    /// once in a while.
+    pub max_requests_per_connection: Option<u32>,
+
+    /// Whether final responses get a `date` header if the handler didn't set
+    /// one, as origin servers must when they have a clock, cf.
+    /// <https://httpwg.org/specs/rfc9110.html#field.date>
+    pub date_header: bool,
+
+    /// Added to every final response, unless the handler set a field with
+    /// the same name: `server`, security headers, etc.
+    pub default_response_headers: Headers,
+
+    /// If set, connections waiting for their next request can be closed to
+    /// keep the process under its file descriptor limit, cf.
+    /// [crate::fd_budget]
+    pub fd_budget: Option<Rc<FdBudget>>,
+
+    /// If set, requests we couldn't parse (or that were too large) are
+    /// recorded there, cf. [crate::protocol_errors]
+    pub protocol_errors: Option<Rc<ProtocolErrorLog>>,
+
+    /// What to do with requests asking to switch the connection to TLS, cf.
+    /// [TlsUpgradeRequests]
+    pub tls_upgrade_requests: TlsUpgradeRequests,
+
+    /// Whether requests with `upgrade: h2c` switch the connection to
+    /// HTTP/2. Only connections served with [crate::h2c::serve] can switch,
+    /// the others answer them over HTTP/1.1.
+    pub h2c_upgrade: bool,
+
+    /// Builds the body of the responses we send when we reject a request on
+    /// our own, or when a handler fails before responding, cf.
+    /// [ConnectionError]. Without it, they're empty.
+    pub error_body: Option<ErrorBodyHook>,
 }
 
 impl Default for ServerConf {
     fn default() -> Self {
         Self {
-            max_http_header_len: 64 * 1024,
+            max_header_section_size: 64 * 1024,
             max_header_record_len: 4 * 1024,
-            max_header_records: 128,
+            max_response_header_size: 64 * 1024,
+            max_header_count: 128,
+            max_interned_header_names: HeaderNameInterner::DEFAULT_CAPACITY,
+            metrics: None,
+            pressure: Default::default(),
+            max_requests_per_connection: None,
+            date_header: true,
+            default_response_headers: Default::default(),
+            fd_budget: None,
+            protocol_errors: None,
+            tls_upgrade_requests: Default::default(),
+            h2c_upgrade: true,
+            error_body: None,
         }
     }
 }
 
+/// Some scanners and legacy clients send `upgrade: TLS/1.0` on cleartext
+/// connections, asking to switch to TLS in-band (RFC 2817). We never do
+/// that switch, this says what to do instead.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TlsUpgradeRequests {
+    /// Reply with a 400 and close the connection, without involving the
+    /// driver. Not a 426: that would have to advertise an upgrade we don't
+    /// do.
+    #[default]
+    Reject,
+
+    /// Drop the `upgrade` header and serve the request as usual, which RFC
+    /// 9110 allows
+    Strip,
+}
+
+/// Whether one of the protocols in `upgrade` is TLS, e.g. `TLS/1.0`
+fn asks_for_tls_upgrade(headers: &Headers) -> bool {
+    headers.get_all(header::UPGRADE).iter().any(|value| {
+        value.split(|&b| b == b',').any(|protocol| {
+            let protocol = protocol.trim_ascii();
+            let name = protocol.split(|&b| b == b'/').next().unwrap_or_default();
+            name.eq_ignore_ascii_case(b"TLS")
+        })
+    })
+}
+
 pub async fn serve<OurDriver, OurReadOwned, OurWriteOwned>(
-    (mut transport_r, mut transport_w): (OurReadOwned, OurWriteOwned),
+    transport: (OurReadOwned, OurWriteOwned),
     conf: Rc<ServerConf>,
-    mut client_buf: RollMut,
+    client_buf: RollMut,
     driver: OurDriver,
 ) -> Result<ServeOutcome, ServeError<OurDriver::Error>>
 where
@@ -44,14 +155,176 @@ where
     OurReadOwned: ReadOwned,
     OurWriteOwned: WriteOwned,
 {
+    serve_with_conn_info(transport, conf, client_buf, driver, Default::default()).await
+}
+
+/// Like [serve], but with what the caller knows about the connection (peer
+/// address, TLS details, etc.), which drivers get as [crate::Request::conn]
+pub async fn serve_with_conn_info<OurDriver, OurReadOwned, OurWriteOwned>(
+    transport: (OurReadOwned, OurWriteOwned),
+    conf: Rc<ServerConf>,
+    client_buf: RollMut,
+    driver: OurDriver,
+    mut conn_info: ConnInfo,
+) -> Result<ServeOutcome, ServeError<OurDriver::Error>>
+where
+    OurDriver: ServerDriver<H1Encoder<OurWriteOwned>>,
+    OurReadOwned: ReadOwned,
+    OurWriteOwned: WriteOwned,
+{
+    conn_info.version = Version::HTTP_11;
+    conn_info.assign_id();
+    let span = debug_span!("conn", proto = "h1", id = conn_info.id);
+    match serve_conn(
+        transport,
+        conf,
+        client_buf,
+        &driver,
+        Rc::new(conn_info),
+        false,
+    )
+    .instrument(span)
+    .await?
+    {
+        Served::Done(outcome) => Ok(outcome),
+        Served::H2cUpgrade { .. } => unreachable!("we didn't offer to switch to HTTP/2"),
+    }
+}
+
+/// Like [serve_with_conn_info], but hands the connection back if the client
+/// switches to HTTP/2, cf. [ServerConf::h2c_upgrade]
+pub(crate) async fn serve_offering_h2c<OurDriver, OurReadOwned, OurWriteOwned>(
+    transport: (OurReadOwned, OurWriteOwned),
+    conf: Rc<ServerConf>,
+    client_buf: RollMut,
+    driver: &OurDriver,
+    mut conn_info: ConnInfo,
+) -> Result<Served<OurReadOwned, OurWriteOwned>, ServeError<OurDriver::Error>>
+where
+    OurDriver: ServerDriver<H1Encoder<OurWriteOwned>>,
+    OurReadOwned: ReadOwned,
+    OurWriteOwned: WriteOwned,
+{
+    conn_info.version = Version::HTTP_11;
+    conn_info.assign_id();
+    let span = debug_span!("conn", proto = "h1", id = conn_info.id);
+    let h2c = conf.h2c_upgrade;
+    serve_conn(transport, conf, client_buf, driver, Rc::new(conn_info), h2c)
+        .instrument(span)
+        .await
+}
+
+/// How serving a connection over HTTP/1.1 ended
+pub(crate) enum Served<OurReadOwned, OurWriteOwned> {
+    Done(ServeOutcome),
+
+    /// We replied with a 101 to a request with `upgrade: h2c`: the rest of
+    /// the connection is HTTP/2, starting with what's in `client_buf`.
+    H2cUpgrade {
+        upgrade: Box<H2cUpgrade>,
+        client_buf: RollMut,
+        transport_r: OurReadOwned,
+        transport_w: OurWriteOwned,
+    },
+}
+
+impl<R, W> From<ServeOutcome> for Served<R, W> {
+    fn from(outcome: ServeOutcome) -> Self {
+        Self::Done(outcome)
+    }
+}
+
+/// Like [serve_with_conn_info], with a driver `factory` creates just for this
+/// connection
+pub async fn serve_with_factory<OurFactory, OurReadOwned, OurWriteOwned>(
+    transport: (OurReadOwned, OurWriteOwned),
+    conf: Rc<ServerConf>,
+    client_buf: RollMut,
+    factory: &OurFactory,
+    mut conn_info: ConnInfo,
+) -> Result<
+    ServeOutcome,
+    ServeError<<OurFactory::Driver as ServerDriver<H1Encoder<OurWriteOwned>>>::Error>,
+>
+where
+    OurFactory: ServerDriverFactory,
+    OurFactory::Driver: ServerDriver<H1Encoder<OurWriteOwned>>,
+    OurReadOwned: ReadOwned,
+    OurWriteOwned: WriteOwned,
+{
+    conn_info.version = Version::HTTP_11;
+    conn_info.assign_id();
+    let driver = factory.new_driver(&conn_info);
+    serve_with_conn_info(transport, conf, client_buf, driver, conn_info).await
+}
+
+async fn serve_conn<OurDriver, OurReadOwned, OurWriteOwned>(
+    (transport_r, transport_w): (OurReadOwned, OurWriteOwned),
+    conf: Rc<ServerConf>,
+    mut client_buf: RollMut,
+    driver: &OurDriver,
+    conn_info: Rc<ConnInfo>,
+    h2c: bool,
+) -> Result<Served<OurReadOwned, OurWriteOwned>, ServeError<OurDriver::Error>>
+where
+    OurDriver: ServerDriver<H1Encoder<OurWriteOwned>>,
+    OurReadOwned: ReadOwned,
+    OurWriteOwned: WriteOwned,
+{
+    let _gauges = conf.metrics.clone().map(ConnGauges::new);
+    let mut transport_r = MeteredRead::new(transport_r, conf.metrics.clone());
+    transport_r.count_buffered(client_buf.len() as u64);
+    let mut transport_w = MeteredWrite::new(transport_w, conf.metrics.clone());
+    let mut requests_served: u32 = 0;
+    let idle = conf.fd_budget.as_ref().map(|budget| budget.track());
+    let default_headers = (!conf.default_response_headers.is_empty())
+        .then(|| Rc::new(conf.default_response_headers.clone()));
+    let header_names = RefCell::new(HeaderNameInterner::new(conf.max_interned_header_names));
+    let leftover: Leftover<OurWriteOwned> = Default::default();
+
     loop {
-        let req;
-        (client_buf, req) = match read_and_parse(
+        let exchange_start = transport_r.total() - client_buf.len() as u64;
+
+        // between requests, with nothing buffered, the connection is idle
+        // until the client sends something
+        if let (Some(idle), true) = (&idle, requests_served > 0 && client_buf.is_empty()) {
+            if client_buf.cap() == 0 {
+                client_buf.reserve()?;
+            }
+            idle.set_idle(true);
+            let res;
+            (res, client_buf) = tokio::select! {
+                biased;
+
+                _ = pruned(Some(idle)) => {
+                    debug!("running out of file descriptors, closing idle connection");
+                    count_connection_error(&conf.metrics, ConnectionError::PrunedWhileIdle);
+                    return Ok(ServeOutcome::PrunedWhileIdle.into());
+                }
+                read = client_buf.read_into(conf.max_header_section_size, &mut transport_r) => read,
+            };
+            idle.set_idle(false);
+            match res {
+                Ok(0) => {
+                    debug!("client went away before sending request headers");
+                    return Ok(ServeOutcome::ClientClosedConnectionBetweenRequests.into());
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    debug!(?e, "error reading request header from downstream");
+                    count_connection_error(&conf.metrics, ConnectionError::from_io(&e));
+                    return Ok(ServeOutcome::ClientDidntSpeakHttp11.into());
+                }
+            }
+        }
+
+        let mut req;
+        (client_buf, req) = match read_and_parse_keeping_input(
             "Http1Request",
-            super::parse::request,
+            super::parse::request_interning(conf.max_header_count, &header_names),
             &mut transport_r,
             client_buf,
-            conf.max_http_header_len,
+            conf.max_header_section_size,
         )
         .await
         {
@@ -59,51 +332,195 @@ where
                 Some(t) => t,
                 None => {
                     debug!("client went away before sending request headers");
-                    return Ok(ServeOutcome::ClientClosedConnectionBetweenRequests);
+                    return Ok(ServeOutcome::ClientClosedConnectionBetweenRequests.into());
                 }
             },
-            Err(e) => match e {
-                ReadAndParseError::BufferLimitReachedWhileParsing { limit } => {
-                    debug!("request headers larger than {limit} bytes, replying with 431 and hanging up");
-                    let reply = b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n";
-                    transport_w
-                        .write_all_owned(reply)
-                        .await
-                        .map_err(ServeError::DownstreamWrite)?;
-
-                    return Ok(ServeOutcome::RequestHeadersTooLargeOnHttp1Conn);
-                }
-                _ => {
+            Err((e, input)) => {
+                let Some(error) = request_error(&e) else {
                     debug!(?e, "error reading request header from downstream");
-                    return Ok(ServeOutcome::ClientDidntSpeakHttp11);
+                    let error = match &e {
+                        ReadAndParseError::ReadError(e) => ConnectionError::from_io(e),
+                        _ => ConnectionError::MemoryPressure,
+                    };
+                    count_connection_error(&conf.metrics, error);
+                    return Ok(ServeOutcome::ClientDidntSpeakHttp11.into());
+                };
+                if let Some(log) = &conf.protocol_errors {
+                    log.record_now(conn_info.peer_addr, Version::HTTP_11, error, &e, &input[..]);
                 }
-            },
+                debug!(%e, "rejecting request with a {}", error.status());
+                return reject(&mut transport_w, &conf, error).await.map(Into::into);
+            }
         };
+        let mut timings = Timings::new(Instant::now());
+        timings.accepted_at = conn_info.accepted_at;
+        timings.tls_done_at = conn_info.tls_done_at;
+
         debug!("got request {req:?}");
+        req.conn = Some(conn_info.clone());
+
+        if conf.pressure.under_pressure() {
+            debug!("buffer pool is running low, replying with 503 and hanging up");
+            return reject(&mut transport_w, &conf, ConnectionError::MemoryPressure)
+                .await
+                .map(Into::into);
+        }
+
+        if asks_for_tls_upgrade(&req.headers) {
+            match conf.tls_upgrade_requests {
+                TlsUpgradeRequests::Reject => {
+                    debug!("client asked to upgrade to TLS, replying with 400 and hanging up");
+                    return reject(&mut transport_w, &conf, ConnectionError::TlsUpgrade)
+                        .await
+                        .map(Into::into);
+                }
+                TlsUpgradeRequests::Strip => {
+                    debug!("client asked to upgrade to TLS, ignoring it");
+                    req.headers.remove(header::UPGRADE);
+                }
+            }
+        }
 
-        let chunked = req.headers.is_chunked_transfer_encoding();
+        if let Some(error) = framing_error(&req.headers) {
+            debug!("rejecting request with a {}: {error}", error.status());
+            if let Some(log) = &conf.protocol_errors {
+                log.record_now(conn_info.peer_addr, req.version, error, error, &[]);
+            }
+            return reject(&mut transport_w, &conf, error).await.map(Into::into);
+        }
+
+        let headers_end = transport_r.total() - client_buf.len() as u64;
+
+        if h2c {
+            if let Some(settings) = h2c::upgrade_settings(&req) {
+                debug!("client asked to switch to HTTP/2, replying with 101");
+                let reply = b"HTTP/1.1 101 Switching Protocols\r\nconnection: Upgrade\r\nupgrade: h2c\r\n\r\n";
+                transport_w
+                    .write_all_owned(reply)
+                    .await
+                    .map_err(ServeError::DownstreamWrite)?;
+
+                return Ok(Served::H2cUpgrade {
+                    upgrade: Box::new(H2cUpgrade::new(req, settings, headers_end - exchange_start)),
+                    client_buf,
+                    transport_r: transport_r.into_inner(),
+                    transport_w: transport_w.into_inner(),
+                });
+            }
+        }
+
+        // `framing_error` made sure it's just `chunked`
+        let chunked = req.headers.contains_key(header::TRANSFER_ENCODING);
         let connection_close = req.headers.is_connection_close();
         let content_len = req.headers.content_length().unwrap_or_default();
+        let connect = req.method == Method::Connect;
+        let request_version = req.version;
 
         let mut req_body = H1Body::new(
             transport_r,
             client_buf,
-            if chunked {
+            if connect {
+                // if the handler accepts the request, the rest of the
+                // connection is tunnel bytes
+                H1BodyKind::Tunnel
+            } else if chunked {
                 H1BodyKind::Chunked
             } else {
                 H1BodyKind::ContentLength(content_len)
             },
         );
+        req_body.announce_trailers(req.headers.announced_trailers());
 
-        let responder = Responder::new(H1Encoder::new(transport_w));
+        let mut encoder = H1Encoder::metered(transport_w);
+        encoder.connect_request = connect;
+        encoder.head_request = req.method == Method::Head;
+        encoder.te_trailers = req.headers.accepts_trailers();
+        encoder.request_version = request_version;
+        requests_served = requests_served.saturating_add(1);
+        let last_request = conf
+            .max_requests_per_connection
+            .is_some_and(|max| requests_served >= max);
+        encoder.last_request = last_request;
+        encoder.date_header = conf.date_header;
+        encoder.default_headers = default_headers.clone();
+        encoder.max_header_size = Some(conf.max_response_header_size);
+        encoder.leftover = Some(leftover.clone());
+        let responder = Responder::new(encoder);
 
-        let resp = driver
-            .handle(req, &mut req_body, responder)
-            .await
-            .map_err(ServeError::Driver)?;
+        let span = debug_span!(
+            "request",
+            method = %req.method,
+            path = %req.uri.path(),
+            ttfb_us = field::Empty,
+            write_us = field::Empty,
+            total_us = field::Empty,
+        );
+        timings.driver_started_at = Instant::now();
+        let res = catch_handler_panic(
+            driver
+                .handle(req, &mut req_body, responder)
+                .instrument(span.clone()),
+        )
+        .await;
+        let resp = match res {
+            Ok(Ok(resp)) => Some(resp),
+            Ok(Err(e)) => {
+                debug!("handler returned an error: {e}");
+                None
+            }
+            Err(panic) => {
+                warn!("handler panicked: {panic}");
+                None
+            }
+        };
+        let Some(resp) = resp else {
+            count_connection_error(&conf.metrics, ConnectionError::HandlerFailed);
+            let keep_alive = !connection_close && !last_request;
+            match recover_from_handler_failure(&conf, &leftover, req_body, keep_alive).await? {
+                Some(transport) => {
+                    (client_buf, transport_r, transport_w) = transport;
+                    continue;
+                }
+                None => return Ok(ServeOutcome::HandlerFailed.into()),
+            }
+        };
+        timings.last_byte_at = Instant::now();
+        if let Some(sink) = &conf.metrics {
+            sink.observe(
+                Histogram::RequestDuration,
+                (timings.last_byte_at - timings.driver_started_at).as_secs_f64(),
+            );
+        }
 
-        // TODO: if we sent `connection: close` we should close now
-        transport_w = resp.into_inner().transport_w;
+        let mut encoder = resp.into_inner();
+        timings.first_byte_at = encoder.first_byte_at;
+        timings.record(&span);
+        if let Some(on_complete) = encoder.on_complete.take() {
+            let (response_headers, response_body) = encoder.response_sizes();
+            let sizes = WireSizes {
+                request_headers: headers_end - exchange_start,
+                request_body: req_body.read_offset() - headers_end,
+                response_headers,
+                response_body,
+            };
+            on_complete(sizes, timings);
+        }
+
+        if encoder.is_tunnel() {
+            debug!("CONNECT tunnel is done, closing connection");
+            return Ok(ServeOutcome::TunnelClosed.into());
+        }
+
+        if last_request {
+            debug!("served {requests_served} requests, closing connection");
+            return Ok(ServeOutcome::MaxRequestsPerConnectionReached.into());
+        }
+
+        if encoder.closes_connection() {
+            debug!("response asked for connection close");
+            return Ok(ServeOutcome::ServerRequestedConnectionClose.into());
+        }
+        transport_w = encoder.into_transport();
 
         (client_buf, transport_r) = req_body
             .into_inner()
@@ -111,7 +528,166 @@ where
 
         if connection_close {
             debug!("client requested connection close");
-            return Ok(ServeOutcome::ClientRequestedConnectionClose);
+            return Ok(ServeOutcome::ClientRequestedConnectionClose.into());
+        }
+    }
+}
+
+/// Replies with the canned 500 for [ConnectionError::HandlerFailed] if the
+/// failed handler hadn't started its response.
+/// Returns the transport if the connection can go on, which takes the
+/// handler having read the whole request body.
+async fn recover_from_handler_failure<OurReadOwned, OurWriteOwned, DriverError>(
+    conf: &ServerConf,
+    leftover: &Leftover<OurWriteOwned>,
+    req_body: H1Body<MeteredRead<OurReadOwned>>,
+    keep_alive: bool,
+) -> Result<
+    Option<(
+        RollMut,
+        MeteredRead<OurReadOwned>,
+        MeteredWrite<OurWriteOwned>,
+    )>,
+    ServeError<DriverError>,
+>
+where
+    OurReadOwned: ReadOwned,
+    OurWriteOwned: WriteOwned,
+{
+    let Some(LeftoverTransport {
+        mut transport_w,
+        final_response_written: false,
+    }) = leftover.take()
+    else {
+        // mid-response, all the client can be told is that we hang up
+        return Ok(None);
+    };
+
+    let reusable = req_body.into_inner().filter(|_| keep_alive);
+    let reply = canned_response(&ConnectionError::HandlerFailed, conf, reusable.is_some())
+        .map_err(ServeError::DownstreamWrite)?;
+    transport_w
+        .writev_all_owned(reply)
+        .await
+        .map_err(ServeError::DownstreamWrite)?;
+    Ok(reusable.map(|(client_buf, transport_r)| (client_buf, transport_r, transport_w)))
+}
+
+/// Sends the canned response for `error`, the connection is done after that
+async fn reject<OurWriteOwned, DriverError>(
+    transport_w: &mut OurWriteOwned,
+    conf: &ServerConf,
+    error: ConnectionError,
+) -> Result<ServeOutcome, ServeError<DriverError>>
+where
+    OurWriteOwned: WriteOwned,
+{
+    count_connection_error(&conf.metrics, error);
+    let reply = canned_response(&error, conf, false).map_err(ServeError::DownstreamWrite)?;
+    transport_w
+        .writev_all_owned(reply)
+        .await
+        .map_err(ServeError::DownstreamWrite)?;
+    Ok(outcome(error))
+}
+
+/// Whether we can't tell where the request body ends: we only decode
+/// `chunked`, alone, and `content-length` has to be a number, the same one
+/// if it's repeated. Both at once is a smuggling attempt as far as we're
+/// concerned. cf. <https://httpwg.org/specs/rfc9112.html#message.body.length>
+fn framing_error(headers: &Headers) -> Option<ConnectionError> {
+    if headers.contains_key(header::TRANSFER_ENCODING) {
+        let mut codings = headers
+            .get_all(header::TRANSFER_ENCODING)
+            .iter()
+            .flat_map(|value| value.split(|&b| b == b','))
+            .map(|coding| coding.trim_ascii())
+            .filter(|coding| !coding.is_empty());
+        let chunked = codings
+            .next()
+            .is_some_and(|coding| coding.eq_ignore_ascii_case(b"chunked"));
+        if !chunked || codings.next().is_some() {
+            return Some(ConnectionError::UnsupportedTransferCoding);
+        }
+        if headers.contains_key(header::CONTENT_LENGTH) {
+            return Some(ConnectionError::InvalidContentLength);
         }
     }
+
+    let mut lengths = headers.get_all(header::CONTENT_LENGTH).iter();
+    if let Some(first) = lengths.next() {
+        if headers.content_length().is_none() || lengths.any(|other| other != first) {
+            return Some(ConnectionError::InvalidContentLength);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header;
+
+    use super::{asks_for_tls_upgrade, framing_error};
+    use crate::{error::ConnectionError, Headers};
+
+    #[test]
+    fn test_asks_for_tls_upgrade() {
+        let asks = |values: &[&'static str]| {
+            let mut headers = Headers::default();
+            for value in values {
+                headers.append(header::UPGRADE, (*value).into());
+            }
+            asks_for_tls_upgrade(&headers)
+        };
+        assert!(asks(&["TLS/1.0"]));
+        assert!(asks(&["tls/1.2, HTTP/1.1"]));
+        assert!(asks(&["websocket", "TLS"]));
+        assert!(!asks(&[]));
+        assert!(!asks(&["websocket"]));
+        assert!(!asks(&["h2c"]));
+        assert!(!asks(&["TLSv2-ish"]));
+    }
+
+    #[test]
+    fn test_framing_error() {
+        let error = |fields: &[(header::HeaderName, &'static str)]| {
+            let mut headers = Headers::default();
+            for (name, value) in fields {
+                headers.append(name, (*value).into());
+            }
+            framing_error(&headers)
+        };
+        let te = header::TRANSFER_ENCODING;
+        let cl = header::CONTENT_LENGTH;
+
+        assert_eq!(error(&[]), None);
+        assert_eq!(error(&[(te.clone(), "chunked")]), None);
+        assert_eq!(error(&[(te.clone(), "Chunked , ")]), None);
+        assert_eq!(error(&[(cl.clone(), "42")]), None);
+        assert_eq!(error(&[(cl.clone(), "42"), (cl.clone(), "42")]), None);
+
+        let unsupported = Some(ConnectionError::UnsupportedTransferCoding);
+        assert_eq!(error(&[(te.clone(), "gzip")]), unsupported);
+        assert_eq!(error(&[(te.clone(), "gzip, chunked")]), unsupported);
+        assert_eq!(
+            error(&[(te.clone(), "chunked"), (te.clone(), "chunked")]),
+            unsupported
+        );
+        assert_eq!(error(&[(te.clone(), "")]), unsupported);
+
+        let invalid = Some(ConnectionError::InvalidContentLength);
+        assert_eq!(error(&[(cl.clone(), "-1")]), invalid);
+        assert_eq!(error(&[(cl.clone(), "4 2")]), invalid);
+        assert_eq!(error(&[(cl.clone(), "42, 42")]), invalid);
+        assert_eq!(error(&[(cl.clone(), "42"), (cl.clone(), "43")]), invalid);
+        assert_eq!(
+            error(&[(te.clone(), "chunked"), (cl.clone(), "42")]),
+            invalid
+        );
+        assert_eq!(
+            error(&[(cl.clone(), "0"), (te.clone(), "chunked")]),
+            invalid
+        );
+    }
 }
@@ -1,15 +1,24 @@
-use tokio::sync::mpsc;
+use std::{cell::Cell, rc::Rc};
+
+use loona_h2::{ErrorCode, KnownErrorCode, StreamId};
+use tokio::sync::mpsc::{self, error::TrySendError};
 
 use crate::{Body, BodyChunk, Headers};
 use buffet::Piece;
 
-use super::types::H2StreamError;
+use super::types::{H2Event, H2EventPayload, H2StreamError};
 
 /// Something we receive from an http/2 peer: pieces of the request
 /// body, the final trailers, or perhaps an error! if the client doesn't
 /// end up sending exactly the number of bytes they promised.
+///
+/// The end of the stream is always explicit: if the channel closes before
+/// we've seen [IncomingMessage::LastPiece] or [IncomingMessage::Trailers],
+/// the body is incomplete.
 pub(crate) enum IncomingMessage {
     Piece(Piece),
+    /// The piece that came with END_STREAM, possibly empty
+    LastPiece(Piece),
     Trailers(Box<Headers>),
 }
 
@@ -18,6 +27,22 @@ pub(crate) enum ChunkPosition {
     Last,
 }
 
+/// Creates the channel between the connection and an [H2Body].
+///
+/// Every non-empty piece uses up at least one byte of the stream's receive
+/// window, and we only give that byte back once the body has been read, so a
+/// peer that respects flow control can never fill the channel: a handler that
+/// stops reading stalls the peer, not the connection. The two extra slots are
+/// for the end of the stream and a termination cause.
+pub(crate) fn incoming_channel(
+    initial_window_size: u32,
+) -> (
+    mpsc::Sender<IncomingMessageResult>,
+    mpsc::Receiver<IncomingMessageResult>,
+) {
+    mpsc::channel(initial_window_size as usize + 2)
+}
+
 pub(crate) struct StreamIncoming {
     tx: mpsc::Sender<IncomingMessageResult>,
 
@@ -30,13 +55,10 @@ pub(crate) struct StreamIncoming {
     // incoming capacity (that we decide, we get to tell
     // the peer how much we can handle with window updates)
     pub(crate) capacity: i64,
-}
 
-#[derive(Debug, thiserror::Error)]
-#[non_exhaustive]
-pub enum StreamIncomingError {
-    #[error("stream reset")]
-    StreamReset,
+    // bytes the body has consumed that we haven't given back to the peer
+    // yet: we batch window updates
+    pub(crate) credit_owed: u32,
 }
 
 impl StreamIncoming {
@@ -50,10 +72,12 @@ impl StreamIncoming {
             total_received: 0,
             content_length,
             capacity: initial_window_size as i64,
+            credit_owed: 0,
         }
     }
 
-    pub(crate) async fn write_chunk(
+    /// Never waits, see [incoming_channel]
+    pub(crate) fn write_chunk(
         &mut self,
         chunk: Piece,
         which: ChunkPosition,
@@ -81,19 +105,23 @@ impl StreamIncoming {
             }
         }
 
-        if self
-            .tx
-            .send(Ok(IncomingMessage::Piece(chunk)))
-            .await
-            .is_err()
-        {
+        let msg = match which {
+            // nothing for the body to read, and no window to give back
+            ChunkPosition::NotLast if chunk.is_empty() => return Ok(()),
+            ChunkPosition::NotLast => IncomingMessage::Piece(chunk),
+            ChunkPosition::Last => IncomingMessage::LastPiece(chunk),
+        };
+        match self.tx.try_send(Ok(msg)) {
+            Ok(()) => Ok(()),
             // the stream is being ignored, so let's reset it
-            return Err(H2StreamError::Cancel);
+            Err(TrySendError::Closed(_)) => Err(H2StreamError::Cancel),
+            Err(TrySendError::Full(_)) => {
+                unreachable!("the channel is sized after the receive window, which was checked")
+            }
         }
-        Ok(())
     }
 
-    pub(crate) async fn write_trailers(&mut self, trailers: Headers) -> Result<(), H2StreamError> {
+    pub(crate) fn write_trailers(&mut self, trailers: Headers) -> Result<(), H2StreamError> {
         if let Some(content_length) = self.content_length {
             if self.total_received != content_length {
                 return Err(H2StreamError::DataLengthDoesNotMatchContentLength {
@@ -105,33 +133,103 @@ impl StreamIncoming {
 
         let _ = self
             .tx
-            .send(Ok(IncomingMessage::Trailers(Box::new(trailers))))
-            .await;
+            .try_send(Ok(IncomingMessage::Trailers(Box::new(trailers))));
 
         // TODO: keep track of what we've sent, panic if we're not in the right state.
 
         Ok(())
     }
 
-    pub(crate) async fn send_error(&mut self, err: StreamIncomingError) {
-        let _ = self.tx.send(Err(err)).await;
+    /// Lets the body know why it won't receive anything else. This never
+    /// waits: if the channel is full, the body finds out the channel closed
+    /// early and reports [H2BodyError::Closed] instead.
+    pub(crate) fn terminate(&mut self, cause: H2BodyError) {
+        let _ = self.tx.try_send(Err(cause));
     }
 }
 
-pub(crate) type IncomingMessageResult = Result<IncomingMessage, StreamIncomingError>;
+pub(crate) type IncomingMessageResult = Result<IncomingMessage, H2BodyError>;
 
+/// The request body of an http/2 stream.
+///
+/// DATA payloads are handed out as is: each chunk is a slice of the
+/// connection's read buffer, not a copy. Holding on to a chunk keeps the
+/// buffer it lives in alive, which is fine, since the connection never writes
+/// over bytes it has already handed out; it reads into a fresh buffer instead.
 #[derive(Debug)]
 pub(crate) struct H2Body {
     pub(crate) content_length: Option<u64>,
     pub(crate) eof: bool,
     pub(crate) rx: mpsc::Receiver<IncomingMessageResult>,
+
+    // used to give receive window back as we read
+    pub(crate) stream_id: StreamId,
+    pub(crate) ev_tx: mpsc::Sender<H2Event>,
+    /// Shared with the connection, which gives whatever's in there back to
+    /// the peer: dropping a body can't wait for room in the event channel
+    pub(crate) dropped_credit: Rc<Cell<u32>>,
 }
 
-#[derive(Debug, thiserror::Error)]
+impl H2Body {
+    /// Lets the connection know it can give `len` bytes of receive window
+    /// back to the peer. If the connection is gone, there's nobody to tell.
+    async fn give_back(&self, len: usize) {
+        if len > 0 {
+            let _ = self.ev_tx.send(self.consumed_event(len)).await;
+        }
+    }
+
+    fn consumed_event(&self, len: usize) -> H2Event {
+        H2Event {
+            stream_id: self.stream_id,
+            payload: H2EventPayload::RequestBodyConsumed(len as u32),
+        }
+    }
+}
+
+impl Drop for H2Body {
+    fn drop(&mut self) {
+        // whatever we didn't read still counts against the connection window
+        let mut unread = 0;
+        while let Ok(msg) = self.rx.try_recv() {
+            if let Ok(IncomingMessage::Piece(piece) | IncomingMessage::LastPiece(piece)) = msg {
+                unread += piece.len();
+            }
+        }
+        if unread > 0 {
+            self.dropped_credit
+                .set(self.dropped_credit.get().saturating_add(unread as u32));
+            // if the channel is full, the connection has events to process
+            // anyway, and picks up the credit after the next one
+            let _ = self.ev_tx.try_send(H2Event {
+                stream_id: self.stream_id,
+                payload: H2EventPayload::RequestBodyDropped,
+            });
+        }
+    }
+}
+
+/// Why an HTTP/2 request body ended before the peer sent END_STREAM
+#[derive(Debug, Clone, thiserror::Error)]
 #[non_exhaustive]
-pub(crate) enum H2BodyError {
-    #[error("Stream reset")]
-    StreamReset,
+pub enum H2BodyError {
+    /// The peer reset the stream with RST_STREAM
+    #[error("Stream reset by peer with error code {error_code:?}")]
+    ResetByPeer { error_code: ErrorCode },
+
+    /// We reset the stream, because the peer misbehaved (sent more data than
+    /// announced, ignored flow control, etc.)
+    #[error("Stream reset with error code {error_code:?}")]
+    Reset { error_code: KnownErrorCode },
+
+    /// The connection errored out, and we sent a GOAWAY
+    #[error("Connection error {error_code:?}")]
+    ConnectionError { error_code: KnownErrorCode },
+
+    /// The stream or connection closed without us knowing more, for example
+    /// because the peer hung up
+    #[error("Stream closed before the request body was complete")]
+    Closed,
 }
 
 impl AsRef<dyn std::error::Error> for H2BodyError {
@@ -152,26 +250,201 @@ impl Body for H2Body {
     }
 
     async fn next_chunk(&mut self) -> Result<BodyChunk, H2BodyError> {
-        let chunk = if self.eof {
-            BodyChunk::Done { trailers: None }
-        } else {
-            match self.rx.recv().await {
-                Some(msg) => match msg {
-                    Ok(IncomingMessage::Piece(piece)) => BodyChunk::Chunk(piece),
-                    Ok(IncomingMessage::Trailers(trailers)) => {
-                        self.eof = true;
-                        BodyChunk::Done {
-                            trailers: Some(trailers),
-                        }
-                    }
-                    Err(StreamIncomingError::StreamReset) => return Err(H2BodyError::StreamReset),
-                },
-                None => {
-                    self.eof = true;
+        if self.eof {
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+
+        let chunk = match self.rx.recv().await {
+            Some(Ok(IncomingMessage::Piece(piece))) => {
+                self.give_back(piece.len()).await;
+                BodyChunk::Chunk(piece)
+            }
+            Some(Ok(IncomingMessage::LastPiece(piece))) => {
+                self.eof = true;
+                self.give_back(piece.len()).await;
+                if piece.is_empty() {
                     BodyChunk::Done { trailers: None }
+                } else {
+                    BodyChunk::Chunk(piece)
+                }
+            }
+            Some(Ok(IncomingMessage::Trailers(trailers))) => {
+                self.eof = true;
+                BodyChunk::Done {
+                    trailers: Some(trailers),
                 }
             }
+            Some(Err(e)) => return Err(e),
+            None => return Err(H2BodyError::Closed),
         };
         Ok(chunk)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use loona_h2::{ErrorCode, KnownErrorCode, StreamId};
+    use tokio::sync::mpsc;
+
+    use super::{H2Body, H2BodyError, IncomingMessage, IncomingMessageResult};
+    use crate::{
+        h2::types::{H2Event, H2EventPayload},
+        Body, BodyChunk,
+    };
+
+    fn body_with_rx(
+        rx: mpsc::Receiver<IncomingMessageResult>,
+        content_length: Option<u64>,
+    ) -> (H2Body, mpsc::Receiver<H2Event>) {
+        let (ev_tx, ev_rx) = mpsc::channel(16);
+        let body = H2Body {
+            content_length,
+            eof: false,
+            rx,
+            stream_id: StreamId(1),
+            ev_tx,
+            dropped_credit: Default::default(),
+        };
+        (body, ev_rx)
+    }
+
+    fn body(messages: Vec<IncomingMessage>, content_length: Option<u64>) -> H2Body {
+        let (tx, rx) = mpsc::channel(messages.len().max(1));
+        for msg in messages {
+            tx.try_send(Ok(msg)).unwrap();
+        }
+        body_with_rx(rx, content_length).0
+    }
+
+    mod last_piece {
+        crate::body_test_suite!(|| super::body(
+            vec![
+                super::IncomingMessage::Piece("hello ".into()),
+                super::IncomingMessage::LastPiece("world".into()),
+            ],
+            Some(11)
+        ));
+    }
+
+    mod empty_last_piece {
+        crate::body_test_suite!(|| super::body(
+            vec![
+                super::IncomingMessage::Piece("hello".into()),
+                super::IncomingMessage::LastPiece("".into()),
+            ],
+            None
+        ));
+    }
+
+    #[test]
+    fn test_closed_before_end_stream() {
+        buffet::start(async move {
+            let mut body = body(vec![IncomingMessage::Piece("hello".into())], None);
+            assert!(matches!(body.next_chunk().await, Ok(BodyChunk::Chunk(_))));
+            assert!(matches!(body.next_chunk().await, Err(H2BodyError::Closed)));
+        });
+    }
+
+    #[test]
+    fn test_termination_cause() {
+        buffet::start(async move {
+            let (tx, rx) = super::incoming_channel(65535);
+            let mut incoming = super::StreamIncoming::new(65535, None, tx);
+            let (mut body, _ev_rx) = body_with_rx(rx, None);
+
+            incoming.terminate(H2BodyError::ResetByPeer {
+                error_code: ErrorCode::from(KnownErrorCode::Cancel),
+            });
+            drop(incoming);
+
+            match body.next_chunk().await {
+                Err(H2BodyError::ResetByPeer { error_code }) => {
+                    assert_eq!(error_code.as_repr(), KnownErrorCode::Cancel.repr())
+                }
+                other => panic!("expected a reset, got {:?}", other.map(|_| ())),
+            }
+        });
+    }
+
+    #[test]
+    fn test_gives_back_what_it_reads() {
+        buffet::start(async move {
+            let (tx, rx) = super::incoming_channel(65535);
+            let mut incoming = super::StreamIncoming::new(65535, None, tx);
+            let (mut body, mut ev_rx) = body_with_rx(rx, None);
+
+            for _ in 0..3 {
+                incoming
+                    .write_chunk("hello".into(), super::ChunkPosition::NotLast)
+                    .unwrap();
+            }
+            // empty frames don't reach the body
+            incoming
+                .write_chunk("".into(), super::ChunkPosition::NotLast)
+                .unwrap();
+
+            // nothing is given back until the body is read
+            assert!(ev_rx.try_recv().is_err());
+
+            body.next_chunk().await.unwrap();
+            match ev_rx.try_recv().unwrap().payload {
+                H2EventPayload::RequestBodyConsumed(len) => assert_eq!(len, 5),
+                other => panic!("unexpected event {other:?}"),
+            }
+
+            // the rest is given back when the body is dropped
+            let dropped_credit = body.dropped_credit.clone();
+            drop(body);
+            assert_eq!(dropped_credit.get(), 10);
+            match ev_rx.try_recv().unwrap().payload {
+                H2EventPayload::RequestBodyDropped => {}
+                other => panic!("unexpected event {other:?}"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_dropped_credit_survives_full_channel() {
+        buffet::start(async move {
+            let (ev_tx, mut ev_rx) = mpsc::channel(1);
+            let dropped_credit = Rc::new(Cell::new(0));
+            ev_tx
+                .try_send(H2Event {
+                    stream_id: StreamId(1),
+                    payload: H2EventPayload::Flush,
+                })
+                .unwrap();
+
+            for i in 0..100 {
+                let (tx, rx) = super::incoming_channel(65535);
+                let mut incoming = super::StreamIncoming::new(65535, None, tx);
+                let mut body = H2Body {
+                    content_length: None,
+                    eof: false,
+                    rx,
+                    stream_id: StreamId(2 * i + 1),
+                    ev_tx: ev_tx.clone(),
+                    dropped_credit: dropped_credit.clone(),
+                };
+                for _ in 0..3 {
+                    incoming
+                        .write_chunk("hello".into(), super::ChunkPosition::NotLast)
+                        .unwrap();
+                }
+                // reading would wait for room in the channel
+                drop(body.rx.try_recv().unwrap());
+                drop(body);
+            }
+
+            // none of the drops got through, but the credit is all there
+            assert!(matches!(
+                ev_rx.try_recv().unwrap().payload,
+                H2EventPayload::Flush
+            ));
+            assert!(ev_rx.try_recv().is_err());
+            assert_eq!(dropped_credit.get(), 100 * 10);
+        });
+    }
+}
@@ -1,10 +1,12 @@
+use std::{fs::File, rc::Rc};
+
 use buffet::Piece;
-use http::{StatusCode, Version};
-use tokio::sync::mpsc;
+use http::{header, StatusCode, Version};
+use tokio::sync::{mpsc, oneshot};
 use tracing::debug;
 
-use super::types::{H2Event, H2EventPayload};
-use crate::{Encoder, Response};
+use super::types::{H2Event, H2EventPayload, HeadersBudget, SendWindow, StreamWire};
+use crate::{util::cached_http_date, Encoder, Headers, HeadersExt, OnComplete, Response};
 use loona_h2::StreamId;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -12,6 +14,9 @@ use loona_h2::StreamId;
 pub enum EncoderState {
     ExpectResponseHeaders,
     ExpectResponseBody,
+    /// The body is done, and the response announced trailers: the stream
+    /// ends with them
+    ExpectTrailers,
     ResponseDone,
 }
 
@@ -20,14 +25,44 @@ pub struct H2Encoder {
     stream_id: StreamId,
     tx: mpsc::Sender<H2Event>,
     state: EncoderState,
+    wire: Rc<StreamWire>,
+
+    /// set by the server when the request is a HEAD: body chunks are
+    /// dropped, cf. [Encoder::is_head_response]
+    pub(crate) head_request: bool,
+
+    /// set by the server: responses get a `date` header if they don't have
+    /// one
+    pub(crate) date_header: bool,
+
+    /// set by the server: added to responses, cf.
+    /// [super::ServerConf::default_response_headers]
+    pub(crate) default_headers: Option<Rc<Headers>>,
+
+    /// set by the server, cf. [super::ServerConf::max_response_header_size]
+    pub(crate) max_header_size: Option<usize>,
+
+    /// set by the server: body chunks wait for the peer to have room for
+    /// what's already queued
+    pub(crate) send_window: Option<Rc<SendWindow>>,
 }
 
 impl H2Encoder {
-    pub(crate) fn new(stream_id: StreamId, tx: mpsc::Sender<H2Event>) -> Self {
+    pub(crate) fn new(
+        stream_id: StreamId,
+        tx: mpsc::Sender<H2Event>,
+        wire: Rc<StreamWire>,
+    ) -> Self {
         Self {
             stream_id,
             tx,
             state: EncoderState::ExpectResponseHeaders,
+            wire,
+            head_request: false,
+            date_header: false,
+            default_headers: None,
+            max_header_size: None,
+            send_window: None,
         }
     }
 
@@ -59,6 +94,16 @@ pub enum H2EncoderError {
 
     #[error("Stream reset")]
     StreamReset,
+
+    /// Reading a file-backed body chunk failed
+    #[error("Error reading file: {0}")]
+    FileReadError(#[from] std::io::Error),
+
+    /// The response's header block is larger than
+    /// [super::ServerConf::max_response_header_size] once HPACK-encoded:
+    /// nothing was sent, and the stream still expects a response
+    #[error("Response header block of {len} bytes exceeds the limit of {max}")]
+    ResponseHeadersTooLarge { len: usize, max: usize },
 }
 
 impl AsRef<dyn std::error::Error> for H2EncoderError {
@@ -70,7 +115,7 @@ impl AsRef<dyn std::error::Error> for H2EncoderError {
 impl Encoder for H2Encoder {
     type Error = H2EncoderError;
 
-    async fn write_response(&mut self, res: Response) -> Result<(), Self::Error> {
+    async fn write_response(&mut self, mut res: Response) -> Result<(), Self::Error> {
         // FIXME: HTTP/2 _does_ support informational responses, cf. https://github.com/bearcove/loona/issues/190
         assert!(
             !res.status.is_informational(),
@@ -84,7 +129,36 @@ impl Encoder for H2Encoder {
             });
         }
 
-        self.send(H2EventPayload::Headers(res)).await?;
+        if let Some(defaults) = &self.default_headers {
+            res.headers.merge_defaults(defaults);
+        }
+        if self.date_header {
+            res.headers
+                .entry(header::DATE)
+                .or_insert_with(cached_http_date);
+        }
+
+        // most responses can't go over, whatever HPACK makes of them: only
+        // ask the connection how large the others end up.
+        match self.max_header_size {
+            Some(max) if hpack_len_bound(&res) > max => {
+                let (reply, reply_rx) = oneshot::channel();
+                let budget = HeadersBudget {
+                    max_len: max,
+                    reply,
+                };
+                self.send(H2EventPayload::Headers(res, Some(budget)))
+                    .await?;
+                match reply_rx.await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(len)) => {
+                        return Err(H2EncoderError::ResponseHeadersTooLarge { len, max })
+                    }
+                    Err(_) => return Err(H2EncoderError::StreamReset),
+                }
+            }
+            _ => self.send(H2EventPayload::Headers(res, None)).await?,
+        }
         self.state = EncoderState::ExpectResponseBody;
 
         Ok(())
@@ -99,10 +173,53 @@ impl Encoder for H2Encoder {
             });
         }
 
+        if self.head_request {
+            return Ok(());
+        }
+        if let Some(window) = &self.send_window {
+            if !window.wait_for_room().await {
+                return Err(H2EncoderError::StreamReset);
+            }
+            window.queued.set(window.queued.get() + chunk.len() as u64);
+        }
         self.send(H2EventPayload::BodyChunk(chunk)).await?;
         Ok(())
     }
 
+    async fn write_body_file(
+        &mut self,
+        file: Rc<File>,
+        mut offset: u64,
+        len: u64,
+    ) -> Result<(), Self::Error> {
+        if self.head_request {
+            return self.write_body_chunk(Piece::empty()).await;
+        }
+
+        // DATA frames are built from pieces, so we have to read the file
+        // into memory.
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk = buffet::read_file_piece(&file, offset, remaining)?;
+            offset += chunk.len() as u64;
+            remaining -= chunk.len() as u64;
+            self.write_body_chunk(chunk).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        if self.state != EncoderState::ExpectResponseBody {
+            return Err(H2EncoderError::WrongState {
+                expected: EncoderState::ExpectResponseBody,
+                actual: self.state,
+            });
+        }
+
+        self.send(H2EventPayload::Flush).await?;
+        Ok(())
+    }
+
     // TODO: BodyWriteMode is not relevant for h2
     async fn write_body_end(&mut self) -> Result<(), Self::Error> {
         if self.state != EncoderState::ExpectResponseBody {
@@ -112,23 +229,81 @@ impl Encoder for H2Encoder {
             });
         }
 
+        if self.accepts_trailers() {
+            // the stream ends with the trailers' HEADERS frame instead
+            self.state = EncoderState::ExpectTrailers;
+            return Ok(());
+        }
         self.send(H2EventPayload::BodyEnd).await?;
         self.state = EncoderState::ResponseDone;
 
         Ok(())
     }
 
-    // TODO: handle trailers
-    async fn write_trailers(&mut self, _trailers: Box<crate::Headers>) -> Result<(), Self::Error> {
-        if self.state != EncoderState::ResponseDone {
+    async fn write_trailers(&mut self, trailers: Box<crate::Headers>) -> Result<(), Self::Error> {
+        if self.head_request && self.state == EncoderState::ResponseDone {
+            // no body, no trailers
+            return Ok(());
+        }
+        if self.state != EncoderState::ExpectTrailers {
             return Err(H2EncoderError::WrongState {
-                expected: EncoderState::ResponseDone,
+                expected: EncoderState::ExpectTrailers,
                 actual: self.state,
             });
         }
 
-        todo!("write trailers")
+        let payload = if trailers.is_empty() {
+            H2EventPayload::BodyEnd
+        } else {
+            H2EventPayload::Trailers(trailers)
+        };
+        self.send(payload).await?;
+        self.state = EncoderState::ResponseDone;
+        Ok(())
     }
+
+    fn on_complete(&mut self, callback: OnComplete) {
+        self.wire.on_complete.set(Some(callback));
+    }
+
+    fn is_head_response(&self) -> bool {
+        self.head_request
+    }
+
+    fn accepts_trailers(&self) -> bool {
+        !self.head_request
+    }
+}
+
+/// The most bytes HPACK can turn `res` into: our encoder never uses
+/// Huffman coding, so at worst each field is a literal with a new name,
+/// and the block may start with a dynamic table size update.
+fn hpack_len_bound(res: &Response) -> usize {
+    /// A 7-bit prefix integer, cf. RFC 7541 section 5.1
+    fn int_len(mut n: usize) -> usize {
+        if n < 0x7f {
+            return 1;
+        }
+        n -= 0x7f;
+        let mut len = 2;
+        while n >= 0x80 {
+            n >>= 7;
+            len += 1;
+        }
+        len
+    }
+    let field_len = |name: &[u8], value: &[u8]| {
+        1 + int_len(name.len()) + name.len() + int_len(value.len()) + value.len()
+    };
+
+    const SIZE_UPDATE_LEN: usize = 8;
+    SIZE_UPDATE_LEN
+        + field_len(b":status", res.status.as_str().as_bytes())
+        + res
+            .headers
+            .iter()
+            .map(|(name, value)| field_len(name.as_str().as_bytes(), &value[..]))
+            .sum::<usize>()
 }
 
 impl Drop for H2Encoder {
@@ -137,15 +312,22 @@ impl Drop for H2Encoder {
 
         match self.state {
             EncoderState::ExpectResponseHeaders => {
-                evs.push(self.event(H2EventPayload::Headers(Response {
-                    version: Version::HTTP_11,
-                    status: StatusCode::INTERNAL_SERVER_ERROR,
-                    headers: Default::default(),
-                })));
+                evs.push(self.event(H2EventPayload::Headers(
+                    Response {
+                        version: Version::HTTP_11,
+                        status: StatusCode::INTERNAL_SERVER_ERROR,
+                        headers: Default::default(),
+                    },
+                    None,
+                )));
                 evs.push(self.event(H2EventPayload::BodyEnd));
             }
             EncoderState::ExpectResponseBody => {
-                // TODO: this should probably be RST_STREAM instead
+                // ending the body would pass a truncated one off as complete
+                evs.push(self.event(H2EventPayload::Reset));
+            }
+            EncoderState::ExpectTrailers => {
+                // the body is complete, only the trailers are missing
                 evs.push(self.event(H2EventPayload::BodyEnd));
             }
             EncoderState::ResponseDone => {
@@ -166,3 +348,83 @@ impl Drop for H2Encoder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use loona_h2::StreamId;
+    use tokio::sync::mpsc;
+
+    use super::{hpack_len_bound, H2Encoder, H2EncoderError};
+    use crate::{
+        h2::types::{H2EventPayload, StreamWire},
+        Encoder, Response,
+    };
+
+    crate::encoder_test_suite!(|| {
+        // stand-in for the connection task
+        let (tx, mut rx) = mpsc::channel(1);
+        buffet::spawn(async move { while rx.recv().await.is_some() {} });
+        H2Encoder::new(StreamId(1), tx, Rc::new(StreamWire::untracked()))
+    });
+
+    #[tokio::test]
+    async fn test_flush() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let mut enc = H2Encoder::new(StreamId(1), tx, Rc::new(StreamWire::untracked()));
+
+        assert!(matches!(
+            enc.flush().await,
+            Err(H2EncoderError::WrongState { .. })
+        ));
+
+        enc.write_response(Response::default()).await.unwrap();
+        enc.write_body_chunk("hello".into()).await.unwrap();
+        enc.flush().await.unwrap();
+
+        let payloads = [
+            rx.recv().await.unwrap().payload,
+            rx.recv().await.unwrap().payload,
+            rx.recv().await.unwrap().payload,
+        ];
+        assert!(matches!(
+            payloads,
+            [
+                H2EventPayload::Headers(..),
+                H2EventPayload::BodyChunk(_),
+                H2EventPayload::Flush
+            ]
+        ));
+
+        enc.write_body_end().await.unwrap();
+        enc.write_trailers(Default::default()).await.unwrap();
+    }
+
+    #[test]
+    fn test_hpack_len_bound() {
+        let mut res = Response::default();
+        for (name, len) in [
+            ("x-short", 3),
+            ("x-126", 126),
+            ("x-127", 127),
+            ("x-long", 20_000),
+        ] {
+            res.headers
+                .append(http::HeaderName::from_static(name), vec![b'a'; len].into());
+        }
+
+        let mut enc = loona_hpack::Encoder::new();
+        enc.resize_table(1024);
+        let block = enc.encode(
+            std::iter::once((&b":status"[..], res.status.as_str().as_bytes())).chain(
+                res.headers
+                    .iter()
+                    .map(|(n, v)| (n.as_str().as_bytes(), &v[..])),
+            ),
+        );
+        let bound = hpack_len_bound(&res);
+        assert!(block.len() <= bound, "{} > {bound}", block.len());
+        assert!(bound - block.len() < 32, "bound is too loose: {bound}");
+    }
+}
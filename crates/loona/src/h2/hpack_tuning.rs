@@ -0,0 +1,133 @@
+//! Adjusts the size of the HPACK dynamic table we encode responses with,
+//! cf. [super::HpackTableSizing::Adaptive]
+
+use loona_hpack::{encoder::EncoderStats, Encoder};
+use tracing::debug;
+
+/// How many header fields we look at before deciding anything
+const WINDOW_FIELDS: u64 = 256;
+
+pub(crate) struct HpackTuner {
+    min: usize,
+    max: usize,
+
+    /// SETTINGS_HEADER_TABLE_SIZE, as last set by the peer
+    peer_max: usize,
+
+    /// Encoder stats when the current window started
+    window_start: EncoderStats,
+}
+
+impl HpackTuner {
+    /// Starts with the largest table allowed, and tells the encoder.
+    pub(crate) fn new(min: u32, max: u32, peer_max: u32, enc: &mut Encoder) -> Self {
+        let max = max as usize;
+        let tuner = Self {
+            min: (min as usize).min(max),
+            max,
+            peer_max: peer_max as usize,
+            window_start: enc.stats(),
+        };
+        let size = tuner.ceiling();
+        if size != enc.max_table_size() {
+            enc.resize_table(size);
+        }
+        tuner
+    }
+
+    fn ceiling(&self) -> usize {
+        self.max.min(self.peer_max)
+    }
+
+    /// The peer changed SETTINGS_HEADER_TABLE_SIZE: stay under it
+    pub(crate) fn on_peer_max(&mut self, peer_max: u32, enc: &mut Encoder) {
+        let grew = peer_max as usize > self.peer_max;
+        self.peer_max = peer_max as usize;
+        let size = if grew {
+            // start over from the largest table, the window will tell
+            self.ceiling()
+        } else {
+            enc.max_table_size().min(self.ceiling())
+        };
+        // even if the size doesn't change, a size update tells the peer
+        // we've seen the new setting
+        enc.resize_table(size);
+        self.window_start = enc.stats();
+    }
+
+    /// Called after each header block: once a window's worth of fields went
+    /// through, grows the table if fields keep coming back after being
+    /// evicted, and shrinks it if fields are mostly seen once.
+    pub(crate) fn after_block(&mut self, enc: &mut Encoder) {
+        let stats = enc.stats();
+        let fields = stats.fields - self.window_start.fields;
+        if fields < WINDOW_FIELDS {
+            return;
+        }
+        let hits = stats.dynamic_hits - self.window_start.dynamic_hits;
+        let insertions = stats.insertions - self.window_start.insertions;
+        let reinsertions = stats.reinsertions - self.window_start.reinsertions;
+        self.window_start = stats;
+
+        let current = enc.max_table_size();
+        let next = if reinsertions * 4 >= fields {
+            // repetitive traffic that doesn't fit: keep more of it around
+            (current.max(256) * 2).min(self.ceiling())
+        } else if hits * 10 < fields && reinsertions * 10 < fields && insertions * 2 >= fields {
+            // every response looks different: the table is all cost
+            (current / 2).max(self.min)
+        } else {
+            current
+        };
+        if next != current {
+            debug!(%current, %next, %fields, %hits, %insertions, %reinsertions, "resizing hpack encoder table");
+            enc.resize_table(next);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use loona_hpack::{Decoder, Encoder};
+
+    use super::HpackTuner;
+
+    #[test]
+    fn test_tuning() {
+        let mut enc = Encoder::new();
+        let mut dec = Decoder::new();
+        let mut tuner = HpackTuner::new(512, 16384, 4096, &mut enc);
+        assert_eq!(enc.max_table_size(), 4096);
+
+        // the encoder only indexes fields whose name isn't in the table yet,
+        // so these vary the name
+        let mut roundtrip = |enc: &mut Encoder, tuner: &mut HpackTuner, name: String| {
+            let block = enc.encode([(name.as_bytes(), &[b'v'; 40][..])]);
+            assert_eq!(dec.decode(&block).unwrap()[0].0, name.as_bytes());
+            tuner.after_block(enc);
+        };
+
+        // every field is new: shrink down to `min`
+        for i in 0..2048 {
+            roundtrip(&mut enc, &mut tuner, format!("x-{i}"));
+        }
+        assert_eq!(enc.max_table_size(), 512);
+
+        // a small set of values that doesn't fit in 512 bytes: grow, up to
+        // what the peer allows
+        for i in 0..4096 {
+            roundtrip(&mut enc, &mut tuner, format!("y-{}", i % 30));
+        }
+        assert_eq!(enc.max_table_size(), 4096);
+        let hits = enc.stats().dynamic_hits;
+        for i in 0..300 {
+            roundtrip(&mut enc, &mut tuner, format!("y-{}", i % 30));
+        }
+        assert_eq!(enc.stats().dynamic_hits - hits, 300);
+
+        // the peer lowers its limit
+        tuner.on_peer_max(1024, &mut enc);
+        roundtrip(&mut enc, &mut tuner, "after".into());
+        assert_eq!(enc.max_table_size(), 1024);
+    }
+}
@@ -5,7 +5,12 @@ mod server;
 pub use server::*;
 
 mod body;
-mod encode;
+pub(crate) mod encode;
+mod hpack_tuning;
+pub use body::H2BodyError;
 pub use encode::H2EncoderError;
 
 pub mod types;
+
+#[cfg(feature = "test-util")]
+pub mod observe;
@@ -0,0 +1,119 @@
+//! Lets tests watch streams open and close from outside the connection, to
+//! assert on concurrency directly rather than infer it from frame timing.
+//! Only available with the `test-util` feature.
+
+use std::{cell::RefCell, collections::BTreeSet, rc::Rc};
+
+use loona_h2::StreamId;
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamEvent {
+    Opened(StreamId),
+    Closed(StreamId),
+}
+
+/// Set as [super::ServerConf::stream_observer]. Stream IDs aren't told apart
+/// across connections, so give each connection its own conf & observer.
+#[derive(Clone, Default)]
+pub struct StreamObserver {
+    inner: Rc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: RefCell<State>,
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    events: Vec<StreamEvent>,
+    open: BTreeSet<StreamId>,
+    max_open: usize,
+}
+
+impl StreamObserver {
+    /// Everything that happened so far, in order
+    pub fn events(&self) -> Vec<StreamEvent> {
+        self.inner.state.borrow().events.clone()
+    }
+
+    /// How many streams are open right now
+    pub fn concurrency(&self) -> usize {
+        self.inner.state.borrow().open.len()
+    }
+
+    /// The most streams that were ever open at once
+    pub fn max_concurrency(&self) -> usize {
+        self.inner.state.borrow().max_open
+    }
+
+    /// Waits until exactly `n` streams are open
+    pub async fn wait_for_concurrency(&self, n: usize) {
+        loop {
+            let changed = self.inner.changed.notified();
+            if self.concurrency() == n {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Records the difference between the streams we know are open and
+    /// `streams`, which are the ones open now.
+    pub(crate) fn sync(&self, streams: impl Iterator<Item = StreamId>) {
+        let now: BTreeSet<StreamId> = streams.collect();
+
+        let mut state = self.inner.state.borrow_mut();
+        let closed: Vec<_> = state.open.difference(&now).copied().collect();
+        let opened: Vec<_> = now.difference(&state.open).copied().collect();
+        if closed.is_empty() && opened.is_empty() {
+            return;
+        }
+
+        // closes first, so `max_open` doesn't count streams that were
+        // replaced between two syncs
+        state
+            .events
+            .extend(closed.into_iter().map(StreamEvent::Closed));
+        state
+            .events
+            .extend(opened.into_iter().map(StreamEvent::Opened));
+        state.max_open = state.max_open.max(now.len());
+        state.open = now;
+        drop(state);
+
+        self.inner.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use loona_h2::StreamId;
+
+    use super::{StreamEvent, StreamObserver};
+
+    #[test]
+    fn test_stream_observer() {
+        let observer = StreamObserver::default();
+        observer.sync([StreamId(1), StreamId(3)].into_iter());
+        observer.sync([StreamId(3)].into_iter());
+        observer.sync([StreamId(3), StreamId(5)].into_iter());
+        observer.sync(std::iter::empty());
+
+        assert_eq!(observer.concurrency(), 0);
+        assert_eq!(observer.max_concurrency(), 2);
+        assert_eq!(
+            observer.events(),
+            [
+                StreamEvent::Opened(StreamId(1)),
+                StreamEvent::Opened(StreamId(3)),
+                StreamEvent::Closed(StreamId(1)),
+                StreamEvent::Opened(StreamId(5)),
+                StreamEvent::Closed(StreamId(3)),
+                StreamEvent::Closed(StreamId(5)),
+            ]
+        );
+    }
+}
@@ -1,62 +1,253 @@
 use std::{
     borrow::Cow,
+    cell::Cell,
     collections::{hash_map::Entry, HashSet},
     io::Write,
     rc::Rc,
     sync::atomic::{AtomicU32, Ordering},
+    time::Instant,
 };
 
 use buffet::{Piece, PieceList, PieceStr, ReadOwned, Roll, RollMut, WriteOwned};
-use byteorder::{BigEndian, WriteBytesExt};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use http::{
     header,
     uri::{Authority, PathAndQuery, Scheme},
     HeaderName, StatusCode, Version,
 };
 use loona_h2::{
-    self as parse, enumflags2::BitFlags, nom::Finish, ContinuationFlags, DataFlags, Frame,
-    FrameType, HeadersFlags, PingFlags, PrioritySpec, Setting, SettingPairs, Settings,
-    SettingsFlags, StreamId, WindowUpdate,
+    self as parse, enumflags2::BitFlags, nom::Finish, ContinuationFlags, DataFlags, ErrorCode,
+    Frame, FrameType, HeadersFlags, KnownErrorCode, Origin, PingFlags, Priority, PrioritySpec,
+    PriorityUpdate, Setting, SettingPairs, Settings, SettingsFlags, StreamId, WindowUpdate,
 };
+use loona_hpack::decoder::DecoderError;
 use parse::IntoPiece;
 use smallvec::{smallvec, SmallVec};
 use tokio::sync::mpsc;
-use tracing::{debug, trace};
+use tracing::{debug, debug_span, field, trace, warn, Instrument, Span};
 
 use crate::{
-    error::ServeError,
+    error::{ConnectionError, ServeError},
+    fd_budget::{pruned, FdBudget, IdleTracker},
     h2::{
-        body::{H2Body, IncomingMessageResult, StreamIncoming, StreamIncomingError},
+        body::{incoming_channel, H2Body, H2BodyError, StreamIncoming},
         encode::H2Encoder,
         types::{
-            BodyOutgoing, ConnState, H2ConnectionError, H2Event, H2EventPayload, H2RequestError,
-            H2StreamError, HeadersOrTrailers, HeadersOutgoing, StreamOutgoing, StreamState,
+            read_error_kind, BodyOutgoing, ConnState, H2ConnectionError, H2Event, H2EventPayload,
+            H2RequestError, H2StreamError, HeadersOrTrailers, HeadersOutgoing, StreamOutgoing,
+            StreamState, StreamWire,
         },
     },
-    util::{read_and_parse, ReadAndParseError},
-    Headers, Method, Request, Responder, ResponderOrBodyError, ServeOutcome, ServerDriver,
-    SinglePieceBody,
+    h2c::H2cUpgrade,
+    metrics::{
+        count_connection_error, ConnGauges, Gauge, Histogram, MeteredRead, MeteredWrite,
+        MetricsSink,
+    },
+    pressure::PressureConf,
+    protocol_errors::ProtocolErrorLog,
+    util::{catch_handler_panic, read_and_parse, read_and_parse_keeping_input, ReadAndParseError},
+    ConnInfo, HeaderNameInterner, Headers, Method, Request, Responder, ResponderOrBodyError,
+    ServeOutcome, ServerDriver, ServerDriverFactory, SinglePieceBody, Timings, WireSizes,
 };
 
-use super::{body::ChunkPosition, types::H2ErrorLevel};
+use super::{body::ChunkPosition, hpack_tuning::HpackTuner, types::H2ErrorLevel};
 
 pub const MAX_WINDOW_SIZE: i64 = u32::MAX as i64;
 
+/// Length of a frame header, cf. RFC 9113 section 4.1
+const FRAME_HEADER_LEN: u64 = 9;
+
 /// HTTP/2 server configuration
 pub struct ServerConf {
     pub max_streams: Option<u32>,
+
+    /// How much request body a stream can send before we've read any of
+    /// it. Advertised as SETTINGS_INITIAL_WINDOW_SIZE, at most 2^31-1. The
+    /// connection window is raised to match if it's larger than the default
+    /// 65535.
+    pub initial_window_size: u32,
+
+    /// The largest frame payload we accept. Advertised as
+    /// SETTINGS_MAX_FRAME_SIZE, clamped between 2^14 and 2^24-1. Frames we
+    /// send go by the client's own SETTINGS_MAX_FRAME_SIZE instead, cf.
+    /// [ServerConf::max_data_frame_size].
+    pub max_frame_size: u32,
+
+    /// Max size of the HPACK dynamic table we decode requests with.
+    /// Advertised as SETTINGS_HEADER_TABLE_SIZE.
+    pub header_table_size: u32,
+
+    /// Max size of a request's header section, counted as in RFC 9113
+    /// section 6.5.2: name and value lengths, plus 32 bytes per field.
+    /// Advertised as SETTINGS_MAX_HEADER_LIST_SIZE. Requests over it get a
+    /// 431, header blocks that are larger than this even before decoding
+    /// are a connection error.
+    pub max_header_section_size: u32,
+
+    /// Max number of header fields in a request, pseudo-headers excluded.
+    /// Requests over it get a 431.
+    pub max_header_count: usize,
+
+    /// How many distinct header names a connection remembers, so repeats
+    /// share storage instead of being copied for every request, cf.
+    /// [HeaderNameInterner](crate::HeaderNameInterner). 0 turns it off.
+    pub max_interned_header_names: usize,
+
+    /// Max size of a response's header block, once HPACK-encoded. Handlers
+    /// that go over it get
+    /// [H2EncoderError::ResponseHeadersTooLarge](super::H2EncoderError::ResponseHeadersTooLarge)
+    /// and nothing is sent.
+    pub max_response_header_size: usize,
+
+    /// Where to report connection, stream, byte, HPACK and request duration
+    /// metrics, if anywhere.
+    pub metrics: Option<Rc<dyn MetricsSink>>,
+
+    /// When to start turning connections and streams away, and shrinking
+    /// flow control windows, because the buffer pool is running low.
+    pub pressure: PressureConf,
+
+    /// What to do with connection-specific headers in requests, cf.
+    /// [ConnectionSpecificHeaders]
+    pub connection_specific_headers: ConnectionSpecificHeaders,
+
+    /// What to do with uppercase letters in request field names, cf.
+    /// [UppercaseHeaderNames]
+    pub uppercase_header_names: UppercaseHeaderNames,
+
+    /// Whether responses get a `date` header if the handler didn't set one,
+    /// cf. <https://httpwg.org/specs/rfc9110.html#field.date>
+    pub date_header: bool,
+
+    /// Added to every final response, unless the handler set a field with
+    /// the same name: `server`, security headers, etc.
+    pub default_response_headers: Headers,
+
+    /// cf. [HpackTableSizing]
+    pub hpack_table_sizing: HpackTableSizing,
+
+    /// Response fields that are HPACK-encoded as never-indexed literals
+    /// (cf. RFC 7541 section 7.1.3), so they never enter the dynamic table,
+    /// and intermediaries know to keep them out of theirs too. Defaults to
+    /// `set-cookie`, `authorization` and `proxy-authorization`.
+    pub never_indexed_headers: Vec<HeaderName>,
+
+    /// Whether response field names and values are Huffman-encoded where
+    /// that makes them shorter, which it usually does for text
+    pub hpack_huffman: bool,
+
+    /// Caps the payload of the DATA frames we send, even if the peer's
+    /// SETTINGS_MAX_FRAME_SIZE allows larger ones: smaller frames let
+    /// streams take turns more often, and other frames get through sooner.
+    pub max_data_frame_size: Option<u32>,
+
+    /// If set, connections without open streams can be sent a GOAWAY and
+    /// closed to keep the process under its file descriptor limit, cf.
+    /// [crate::fd_budget]
+    pub fd_budget: Option<Rc<FdBudget>>,
+
+    /// If set, connection errors clients cause are recorded there, cf.
+    /// [crate::protocol_errors]
+    pub protocol_errors: Option<Rc<ProtocolErrorLog>>,
+
+    /// Advertises SETTINGS_ENABLE_CONNECT_PROTOCOL and accepts extended
+    /// CONNECT requests (RFC 8441), whose `:protocol` ends up in
+    /// [Request::protocol]: `connect-udp`, cf. [crate::connect_udp],
+    /// `websocket`, etc. Off by default: handlers that don't look at
+    /// [Request::protocol] would mistake them for plain CONNECT requests.
+    pub enable_connect_protocol: bool,
+
+    /// Told about streams opening and closing, for tests
+    #[cfg(feature = "test-util")]
+    pub stream_observer: Option<super::observe::StreamObserver>,
 }
 
 impl Default for ServerConf {
     fn default() -> Self {
         Self {
             max_streams: Some(32),
+            initial_window_size: Settings::default().initial_window_size,
+            max_frame_size: Settings::default().max_frame_size,
+            header_table_size: Settings::default().header_table_size,
+            max_header_section_size: 64 * 1024,
+            max_header_count: 128,
+            max_interned_header_names: HeaderNameInterner::DEFAULT_CAPACITY,
+            max_response_header_size: 64 * 1024,
+            metrics: None,
+            pressure: Default::default(),
+            connection_specific_headers: Default::default(),
+            uppercase_header_names: Default::default(),
+            date_header: true,
+            default_response_headers: Default::default(),
+            hpack_table_sizing: Default::default(),
+            never_indexed_headers: vec![
+                header::SET_COOKIE,
+                header::AUTHORIZATION,
+                header::PROXY_AUTHORIZATION,
+            ],
+            hpack_huffman: true,
+            max_data_frame_size: None,
+            fd_budget: None,
+            protocol_errors: None,
+            enable_connect_protocol: false,
+            #[cfg(feature = "test-util")]
+            stream_observer: None,
         }
     }
 }
 
+/// HTTP/2 requests must not carry `connection`, `keep-alive`,
+/// `proxy-connection`, `transfer-encoding` or `upgrade` headers, nor a `te`
+/// header other than `te: trailers` (RFC 9113, section 8.2.2).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConnectionSpecificHeaders {
+    /// Treat such requests as malformed, and reset their stream
+    #[default]
+    Reject,
+
+    /// Drop those headers and serve the request anyway. Meant for proxies
+    /// ingesting traffic from clients that translate HTTP/1.1 a bit too
+    /// literally.
+    Strip,
+}
+
+/// HTTP/2 field names must be lowercase (RFC 9113, section 8.2). Whatever
+/// this is set to, responses only ever carry lowercase names.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UppercaseHeaderNames {
+    /// Treat such requests as malformed, and reset their stream
+    #[default]
+    Reject,
+
+    /// Lowercase those names and serve the request anyway
+    Lowercase,
+}
+
+/// How large the HPACK dynamic table we encode response headers with is.
+/// Whatever this is set to, it never exceeds the client's
+/// SETTINGS_HEADER_TABLE_SIZE.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HpackTableSizing {
+    /// As large as the client allows
+    #[default]
+    Fixed,
+
+    /// Between `min` and `max` bytes, depending on traffic: the table grows
+    /// while fields keep getting evicted and coming back (repetitive API
+    /// traffic), and shrinks while most fields are only ever sent once.
+    Adaptive { min: u32, max: u32 },
+
+    /// Nothing goes into the table: fields that aren't in the static table
+    /// are sent as literals every time. Header blocks get larger, but the
+    /// connection doesn't hold on to a table's worth of memory.
+    Disabled,
+}
+
 pub async fn serve<OurDriver, OurReadOwned, OurWriteOwned>(
-    (transport_r, transport_w): (OurReadOwned, OurWriteOwned),
+    transport: (OurReadOwned, OurWriteOwned),
     conf: Rc<ServerConf>,
     client_buf: RollMut,
     driver: Rc<OurDriver>,
@@ -66,17 +257,95 @@ where
     OurReadOwned: ReadOwned,
     OurWriteOwned: WriteOwned,
 {
-    let mut state = ConnState::default();
-    state.self_settings.max_concurrent_streams = conf.max_streams;
+    serve_with_conn_info(transport, conf, client_buf, driver, Default::default()).await
+}
 
-    let mut cx =
-        ServerContext::new(driver.clone(), state, transport_w).map_err(ServeError::Alloc)?;
-    cx.work(client_buf, transport_r).await?;
+/// Like [serve], but with what the caller knows about the connection (peer
+/// address, TLS details, etc.), which drivers get as [crate::Request::conn]
+pub async fn serve_with_conn_info<OurDriver, OurReadOwned, OurWriteOwned>(
+    transport: (OurReadOwned, OurWriteOwned),
+    conf: Rc<ServerConf>,
+    client_buf: RollMut,
+    driver: Rc<OurDriver>,
+    conn_info: ConnInfo,
+) -> Result<(), ServeError<OurDriver::Error>>
+where
+    OurDriver: ServerDriver<H2Encoder> + 'static,
+    OurReadOwned: ReadOwned,
+    OurWriteOwned: WriteOwned,
+{
+    serve_conn(transport, conf, client_buf, driver, conn_info, None).await?;
 
     debug!("finished serving");
     Ok(())
 }
 
+/// Serves a connection, after answering `upgrade` on stream 1 if the client
+/// switched to HTTP/2 from HTTP/1.1, cf. [crate::h2c]
+pub(crate) async fn serve_conn<OurDriver, OurReadOwned, OurWriteOwned>(
+    (transport_r, transport_w): (OurReadOwned, OurWriteOwned),
+    conf: Rc<ServerConf>,
+    client_buf: RollMut,
+    driver: Rc<OurDriver>,
+    mut conn_info: ConnInfo,
+    upgrade: Option<H2cUpgrade>,
+) -> Result<ServeOutcome, ServeError<OurDriver::Error>>
+where
+    OurDriver: ServerDriver<H2Encoder> + 'static,
+    OurReadOwned: ReadOwned,
+    OurWriteOwned: WriteOwned,
+{
+    conn_info.version = Version::HTTP_2;
+    conn_info.assign_id();
+
+    let mut state = ConnState::default();
+    state.self_settings.max_concurrent_streams = conf.max_streams;
+    state.self_settings.initial_window_size = conf.initial_window_size.min((1 << 31) - 1);
+    state.self_settings.max_frame_size = conf
+        .max_frame_size
+        .clamp(Settings::default().max_frame_size, (1 << 24) - 1);
+    state.self_settings.header_table_size = conf.header_table_size;
+    state.self_settings.max_header_list_size = conf.max_header_section_size;
+    state.self_settings.enable_connect_protocol = conf.enable_connect_protocol;
+
+    let transport_r = MeteredRead::new(transport_r, conf.metrics.clone());
+    let transport_w = MeteredWrite::new(transport_w, conf.metrics.clone());
+
+    let span = debug_span!("conn", proto = "h2", id = conn_info.id);
+    let mut cx = ServerContext::new(driver.clone(), conf, state, transport_w, Rc::new(conn_info))
+        .map_err(ServeError::Alloc)?;
+    cx.upgrade = upgrade;
+    let res = cx.work(client_buf, transport_r).instrument(span).await;
+
+    // whatever streams were left died with the connection
+    #[cfg(feature = "test-util")]
+    if let Some(observer) = &cx.conf.stream_observer {
+        observer.sync(std::iter::empty());
+    }
+    res
+}
+
+/// Like [serve_with_conn_info], with a driver `factory` creates just for this
+/// connection
+pub async fn serve_with_factory<OurFactory, OurReadOwned, OurWriteOwned>(
+    transport: (OurReadOwned, OurWriteOwned),
+    conf: Rc<ServerConf>,
+    client_buf: RollMut,
+    factory: &OurFactory,
+    mut conn_info: ConnInfo,
+) -> Result<(), ServeError<<OurFactory::Driver as ServerDriver<H2Encoder>>::Error>>
+where
+    OurFactory: ServerDriverFactory,
+    OurFactory::Driver: ServerDriver<H2Encoder> + 'static,
+    OurReadOwned: ReadOwned,
+    OurWriteOwned: WriteOwned,
+{
+    conn_info.version = Version::HTTP_2;
+    conn_info.assign_id();
+    let driver = Rc::new(factory.new_driver(&conn_info));
+    serve_with_conn_info(transport, conf, client_buf, driver, conn_info).await
+}
+
 /// Reads and processes h2 frames from the client.
 pub(crate) struct ServerContext<OurDriver, OurWriter>
 where
@@ -84,10 +353,13 @@ where
     OurWriter: WriteOwned,
 {
     driver: Rc<OurDriver>,
+    conf: Rc<ServerConf>,
     state: ConnState,
 
     hpack_dec: loona_hpack::Decoder<'static>,
     hpack_enc: loona_hpack::Encoder<'static>,
+    /// Only there with [HpackTableSizing::Adaptive]
+    hpack_tuner: Option<HpackTuner>,
     out_scratch: RollMut,
 
     /// Whether we've received a GOAWAY frame.
@@ -99,6 +371,28 @@ where
 
     ev_tx: mpsc::Sender<H2Event>,
     ev_rx: mpsc::Receiver<H2Event>,
+
+    /// Receive window owed for request bodies that were dropped unread, see
+    /// [H2EventPayload::RequestBodyDropped]
+    dropped_body_credit: Rc<Cell<u32>>,
+
+    /// Only there if we're reporting metrics
+    gauges: Option<ConnGauges>,
+
+    /// Handed to drivers with every request
+    conn_info: Rc<ConnInfo>,
+
+    /// Only there with [ServerConf::fd_budget]
+    idle: Option<IdleTracker>,
+
+    /// [ServerConf::default_response_headers], if there are any
+    default_headers: Option<Rc<Headers>>,
+    /// Names of the request headers and trailers we've decoded so far
+    header_names: HeaderNameInterner,
+
+    /// The request that switched the connection to HTTP/2, if it came in
+    /// over HTTP/1.1: answered on stream 1 once our settings are out
+    upgrade: Option<H2cUpgrade>,
 }
 
 impl<OurDriver, OurWriteOwned> ServerContext<OurDriver, OurWriteOwned>
@@ -108,28 +402,74 @@ where
 {
     pub(crate) fn new(
         driver: Rc<OurDriver>,
+        conf: Rc<ServerConf>,
         state: ConnState,
         transport_w: OurWriteOwned,
+        conn_info: Rc<ConnInfo>,
     ) -> Result<Self, buffet::bufpool::Error> {
+        // until the peer acknowledges our settings, its encoder may still
+        // use the default table size
         let mut hpack_dec = loona_hpack::Decoder::new();
-        hpack_dec
-            .set_max_allowed_table_size(Settings::default().header_table_size.try_into().unwrap());
-
-        let hpack_enc = loona_hpack::Encoder::new();
+        hpack_dec.set_max_allowed_table_size(
+            state
+                .self_settings
+                .header_table_size
+                .max(Settings::default().header_table_size) as _,
+        );
+        hpack_dec.set_max_header_list_size(conf.max_header_section_size as _);
+
+        let mut hpack_enc = loona_hpack::Encoder::new();
+        hpack_enc.set_never_indexed(
+            conf.never_indexed_headers
+                .iter()
+                .map(|name| name.as_str().as_bytes()),
+        );
+        hpack_enc.set_huffman(conf.hpack_huffman);
+        let hpack_tuner = match conf.hpack_table_sizing {
+            HpackTableSizing::Fixed => None,
+            HpackTableSizing::Disabled => {
+                hpack_enc.set_indexing(false);
+                None
+            }
+            HpackTableSizing::Adaptive { min, max } => Some(HpackTuner::new(
+                min,
+                max,
+                Settings::default().header_table_size,
+                &mut hpack_enc,
+            )),
+        };
 
-        let h2_server_chan_size: usize = std::env::var("H2_SERVER_CHAN_SIZE").unwrap_or("32".to_string()).parse().unwrap();
+        let h2_server_chan_size: usize = std::env::var("H2_SERVER_CHAN_SIZE")
+            .unwrap_or("32".to_string())
+            .parse()
+            .unwrap();
         let (ev_tx, ev_rx) = tokio::sync::mpsc::channel::<H2Event>(h2_server_chan_size);
 
+        let gauges = conf.metrics.clone().map(ConnGauges::new);
+        let idle = conf.fd_budget.as_ref().map(|budget| budget.track());
+        let default_headers = (!conf.default_response_headers.is_empty())
+            .then(|| Rc::new(conf.default_response_headers.clone()));
+        let header_names = HeaderNameInterner::new(conf.max_interned_header_names);
+
         Ok(Self {
             driver,
+            conf,
+            gauges,
+            conn_info,
             ev_tx,
             ev_rx,
+            dropped_body_credit: Default::default(),
             state,
             hpack_dec,
             hpack_enc,
+            hpack_tuner,
             out_scratch: RollMut::alloc()?,
             goaway_recv: false,
             transport_w,
+            idle,
+            default_headers,
+            header_names,
+            upgrade: None,
         })
     }
 
@@ -141,16 +481,22 @@ where
     ) -> Result<ServeOutcome, ServeError<OurDriver::Error>> {
         // first read the preface
         {
-            (client_buf, _) = match read_and_parse(
+            let res = read_and_parse_keeping_input(
                 "Http2Preface",
                 parse::preface,
                 &mut transport_r,
                 client_buf,
                 parse::PREFACE.len(),
             )
-            .await
-            .map_err(H2ConnectionError::ReadAndParse)?
-            {
+            .await;
+            (client_buf, _) = match res.map_err(|(e, input)| {
+                let kind = read_error_kind(&e);
+                count_connection_error(&self.conf.metrics, kind);
+                if e.is_protocol_error() {
+                    self.record_protocol_error(kind, &e, &input[..]);
+                }
+                H2ConnectionError::ReadAndParse(e)
+            })? {
                 Some((client_buf, frame)) => (client_buf, frame),
                 None => {
                     return Ok(ServeOutcome::ClientDidntSpeakHttp2);
@@ -163,7 +509,7 @@ where
             debug!("Sending initial settings");
             let setting_payload = {
                 let s = &self.state.self_settings;
-                SettingPairs(&[
+                let mut pairs = vec![
                     (Setting::EnablePush, 0),
                     (Setting::HeaderTableSize, s.header_table_size),
                     (Setting::InitialWindowSize, s.initial_window_size),
@@ -173,9 +519,13 @@ where
                     ),
                     (Setting::MaxFrameSize, s.max_frame_size),
                     (Setting::MaxHeaderListSize, s.max_header_list_size),
-                ])
-                .into_piece(&mut self.out_scratch)
-                .map_err(ServeError::DownstreamWrite)?
+                ];
+                if s.enable_connect_protocol {
+                    pairs.push((Setting::EnableConnectProtocol, 1));
+                }
+                SettingPairs(&pairs)
+                    .into_piece(&mut self.out_scratch)
+                    .map_err(ServeError::DownstreamWrite)?
             };
             let frame = Frame::new(
                 FrameType::Settings(Default::default()),
@@ -183,11 +533,33 @@ where
             );
             self.write_frame(frame, PieceList::single(setting_payload))
                 .await?;
+
+            // SETTINGS_INITIAL_WINDOW_SIZE only applies to streams, the
+            // connection window has to be raised separately
+            let extra =
+                self.state.self_settings.initial_window_size as i64 - self.state.incoming_capacity;
+            if extra > 0 {
+                self.state.incoming_capacity += extra;
+                self.write_window_update(StreamId::CONNECTION, extra as u32)
+                    .await?;
+            }
+        }
+
+        if let Some(origins) = self.driver.origins() {
+            self.send_origins(origins).await?;
+        }
+
+        if let Some(upgrade) = self.upgrade.take() {
+            self.accept_upgrade(upgrade)?;
         }
 
         let mut goaway_err: Option<H2ConnectionError> = None;
 
-        {
+        let refused = self.conf.pressure.under_pressure();
+        if refused {
+            debug!("buffer pool is running low, turning the connection away");
+            goaway_err = Some(H2ConnectionError::MemoryPressure);
+        } else {
             let (tx, rx) = mpsc::channel::<(Frame, Roll)>(32);
 
             // store max frame size setting as an atomic so we can share it across tasks
@@ -200,6 +572,11 @@ where
                 tx,
                 max_frame_size
             ));
+            let (protocol_errors, metrics, peer_addr) = (
+                self.conf.protocol_errors.clone(),
+                self.conf.metrics.clone(),
+                self.conn_info.peer_addr,
+            );
             let mut process_task = std::pin::pin!(self.process_loop(rx));
 
             debug!("Starting both deframe & process tasks");
@@ -221,7 +598,12 @@ where
                                 }
 
                                 debug!(%should_ignore_err, "deciding whether or not to propagate deframer error");
+                                let kind = read_error_kind(&e);
+                                if let Some(log) = protocol_errors.filter(|_| e.is_protocol_error()) {
+                                    log.record_now(peer_addr, Version::HTTP_2, kind, &e, &[]);
+                                }
                                 if !should_ignore_err {
+                                    count_connection_error(&metrics, kind);
                                     return Err(H2ConnectionError::ReadAndParse(e).into());
                                 }
                             },
@@ -236,6 +618,7 @@ where
                         // what about the GOAWAY?
 
                         debug!("h2 process task finished with error: {e}");
+                        count_connection_error(&metrics, e.kind());
                         return Err(e.into());
                     }
                 }
@@ -249,12 +632,33 @@ where
             }
         }
 
+        // let request bodies still being received know why they won't get
+        // anything else
+        let cause = match &goaway_err {
+            Some(err) => H2BodyError::ConnectionError {
+                error_code: err.as_known_error_code(),
+            },
+            None => H2BodyError::Closed,
+        };
+        self.terminate_incoming_streams(cause);
+        for (_, wire) in self.state.wire.drain() {
+            wire.complete();
+        }
+
+        let pruned_while_idle = matches!(goaway_err, Some(H2ConnectionError::PrunedWhileIdle));
         if let Some(err) = goaway_err {
-            let error_code = err.as_known_error_code();
+            let kind = err.kind();
+            let error_code = kind.as_known_error_code();
             debug!("Connection error: {err} ({err:?}) (code {error_code:?})");
+            count_connection_error(&self.conf.metrics, kind);
+            // running low on memory isn't the client's fault
+            if error_code != KnownErrorCode::NoError && kind != ConnectionError::MemoryPressure {
+                self.record_protocol_error(kind, &err, &[]);
+            }
 
             // TODO: don't heap-allocate here
-            let additional_debug_data = format!("{err}").into_bytes();
+            let mut additional_debug_data = format!("{err}").into_bytes();
+            additional_debug_data.truncate(self.state.peer_settings.max_frame_size as usize - 8);
 
             // TODO: figure out graceful shutdown: this would involve sending a goaway
             // before this point, and processing all the connections we've accepted
@@ -276,9 +680,40 @@ where
                 .map_err(ServeError::H2ConnectionError)?;
         }
 
+        if refused {
+            return Ok(ServeOutcome::RefusedUnderMemoryPressure);
+        }
+        if pruned_while_idle {
+            return Ok(ServeOutcome::PrunedWhileIdle);
+        }
         Ok(ServeOutcome::SuccessfulHttp2GracefulShutdown)
     }
 
+    fn record_protocol_error(
+        &self,
+        code: ConnectionError,
+        kind: impl std::fmt::Display,
+        snippet: &[u8],
+    ) {
+        if let Some(log) = &self.conf.protocol_errors {
+            log.record_now(
+                self.conn_info.peer_addr,
+                Version::HTTP_2,
+                code,
+                kind,
+                snippet,
+            );
+        }
+    }
+
+    fn terminate_incoming_streams(&mut self, cause: H2BodyError) {
+        for ss in self.state.streams.values_mut() {
+            if let Some(incoming) = ss.incoming_mut() {
+                incoming.terminate(cause.clone());
+            }
+        }
+    }
+
     async fn deframe_loop(
         mut client_buf: RollMut,
         mut transport_r: impl ReadOwned,
@@ -324,6 +759,18 @@ where
                 });
             }
 
+            // payloads are handed out as slices of `client_buf`, so they have
+            // to be contiguous in it. if this one doesn't fit in what's left,
+            // make room now, while only its first few bytes have been read:
+            // letting `read_and_parse` compact and grow as it goes would copy
+            // most of the payload, sometimes more than once.
+            let missing = (frame.len as usize).saturating_sub(client_buf.len());
+            if client_buf.cap() < missing {
+                client_buf
+                    .reserve_at_least(missing)
+                    .map_err(|e| H2ConnectionError::ReadAndParse(e.into()))?;
+            }
+
             trace!(
                 "Reading payload of size {}... Buffer length: {}",
                 frame.len,
@@ -401,7 +848,8 @@ where
 
                 maybe_frame = rx.recv() => {
                     if let Some((frame, payload)) = maybe_frame {
-                        self.process_frame(frame, payload, &mut rx).await?;
+                        let span = stream_span(frame.stream_id);
+                        self.process_frame(frame, payload, &mut rx).instrument(span).await?;
                     } else {
                         debug!("h2 process task: peer hung up");
                         break;
@@ -410,7 +858,10 @@ where
 
                 ev = self.ev_rx.recv() => {
                     match ev {
-                        Some(ev) => self.handle_event(ev).await?,
+                        Some(ev) => {
+                            let span = stream_span(ev.stream_id);
+                            self.handle_event(ev).instrument(span).await?
+                        }
                         None => unreachable!("the context owns a copy of the sender, and this method has &mut self, so the sender can't be dropped while this method is running"),
                     }
                 }
@@ -418,12 +869,83 @@ where
                 _ = self.state.send_data_maybe.notified() => {
                     self.send_data_maybe().await?;
                 }
+
+                _ = pruned(self.idle.as_ref()), if self.state.streams.is_empty() => {
+                    debug!("running out of file descriptors, closing idle connection");
+                    return Err(H2ConnectionError::PrunedWhileIdle);
+                }
+            }
+
+            if let Some(idle) = &self.idle {
+                idle.set_idle(self.state.streams.is_empty());
             }
+            self.update_gauges();
+            #[cfg(feature = "test-util")]
+            if let Some(observer) = &self.conf.stream_observer {
+                observer.sync(self.state.streams.keys().copied());
+            }
+            self.give_back_dropped_body_credit().await?;
+            self.shed_maybe().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Under memory pressure, resets the stream with the most response data
+    /// waiting to be sent, if we're configured to.
+    async fn shed_maybe(&mut self) -> Result<(), H2ConnectionError> {
+        let pressure = &self.conf.pressure;
+        if !pressure.shed_largest_response || !pressure.under_pressure() {
+            return Ok(());
         }
 
+        let largest = self
+            .state
+            .streams
+            .iter_mut()
+            .filter_map(|(&id, ss)| Some((id, ss.outgoing_mut()?.body.buffered_len())))
+            .filter(|&(_, len)| len > 0)
+            .max_by_key(|&(_, len)| len);
+        if let Some((stream_id, len)) = largest {
+            debug!(%stream_id, %len, "buffer pool is running low, shedding stream");
+            self.state.streams_with_pending_data.remove(&stream_id);
+            self.rst(stream_id, H2StreamError::Shed).await?;
+        }
         Ok(())
     }
 
+    /// The peer now goes by our SETTINGS: streams it opened before that get
+    /// our initial window, and its HPACK encoder our table size.
+    fn on_self_settings_acked(&mut self) {
+        let delta = self.state.self_settings.initial_window_size as i64
+            - self.state.incoming_initial_window_size() as i64;
+        self.state.self_settings_acked = true;
+        if delta != 0 {
+            for stream in self.state.streams.values_mut() {
+                if let Some(incoming) = stream.incoming_mut() {
+                    incoming.capacity += delta;
+                }
+            }
+        }
+        self.hpack_dec
+            .set_max_allowed_table_size(self.state.self_settings.header_table_size as _);
+    }
+
+    fn update_gauges(&mut self) {
+        let Some(gauges) = &mut self.gauges else {
+            return;
+        };
+        gauges.set(Gauge::OpenH2Streams, self.state.streams.len() as _);
+        gauges.set(
+            Gauge::HpackDecoderTableSize,
+            self.hpack_dec.table_size() as _,
+        );
+        gauges.set(
+            Gauge::HpackEncoderTableSize,
+            self.hpack_enc.table_size() as _,
+        );
+    }
+
     async fn send_data_maybe(&mut self) -> Result<(), H2ConnectionError> {
         let mut not_pending: HashSet<StreamId> = Default::default();
 
@@ -433,20 +955,32 @@ where
         let mut frames: Vec<(Frame, PieceList)> = vec![];
 
         let max_fram = self.state.peer_settings.max_frame_size as usize;
+        let max_data_fram = match self.conf.max_data_frame_size {
+            Some(cap) => max_fram.min(cap.max(1) as usize),
+            None => max_fram,
+        };
 
-        let streams_with_pending_data: HashSet<_> = self
+        // most urgent first, so they get the connection window before the
+        // others, cf. RFC 9218, section 10. within an urgency, responses
+        // that aren't incremental go first, in the order they were requested.
+        let mut streams_with_pending_data: Vec<_> = self
             .state
             .streams_with_pending_data
             .iter()
-            .copied()
+            .map(|&id| {
+                let priority = self
+                    .state
+                    .streams
+                    .get(&id)
+                    .and_then(|ss| ss.outgoing())
+                    .map(|outgoing| outgoing.priority)
+                    .unwrap_or_default();
+                (priority.urgency, priority.incremental, id)
+            })
             .collect();
+        streams_with_pending_data.sort_unstable();
 
-        'each_stream: for id in streams_with_pending_data {
-            if self.state.outgoing_capacity <= 0 {
-                // that's all we can do
-                break 'each_stream;
-            }
-
+        'each_stream: for (_, _, id) in streams_with_pending_data {
             let outgoing = self
                 .state
                 .streams
@@ -454,7 +988,15 @@ where
                 .and_then(|ss| ss.outgoing_mut())
                 .expect("stream should not be in streams_with_pending_data if it's already closed / not in an outgoing state");
 
-            debug!(conn_cap = %self.state.outgoing_capacity, strm_cap = %outgoing.capacity, %max_fram, "ready to write");
+            // trailers aren't flow-controlled
+            let only_trailers_left =
+                !outgoing.headers.has_more_to_write() && !outgoing.body.has_more_to_write();
+            if self.state.outgoing_capacity.get() <= 0 && !only_trailers_left {
+                // that's all we can do for this one
+                continue 'each_stream;
+            }
+
+            debug!(conn_cap = %self.state.outgoing_capacity.get(), strm_cap = %outgoing.capacity(), %max_fram, %max_data_fram, "ready to write");
 
             if outgoing.headers.has_more_to_write() {
                 debug!("writing headers...");
@@ -508,7 +1050,7 @@ where
                 }
             }
 
-            let capacity = self.state.outgoing_capacity.min(outgoing.capacity) as usize;
+            let capacity = self.state.outgoing_capacity.get().min(outgoing.capacity()) as usize;
             // bytes written this turn, possibly over multiple frames
             let mut total_bytes_written = 0;
 
@@ -533,7 +1075,7 @@ where
                         let fram_size_if_full_piece = frame_len + piece_len;
 
                         let cap_left = capacity - total_bytes_written;
-                        let max_this_fram = max_fram.min(cap_left);
+                        let max_this_fram = max_data_fram.min(cap_left);
 
                         if fram_size_if_full_piece > max_this_fram {
                             // we can't fit this piece in the current frame, so
@@ -557,7 +1099,7 @@ where
                     }
 
                     let mut flags: BitFlags<DataFlags> = Default::default();
-                    if outgoing.body.might_receive_more() {
+                    if outgoing.body.might_receive_more() || outgoing.trailers.is_some() {
                         if frame_len == 0 {
                             // the only time we want to send a zero-length frame
                             // is if we have to send END_STREAM separately from
@@ -578,6 +1120,38 @@ where
                     }
                 }
             }
+
+            if !outgoing.body.has_more_to_write() {
+                if let Some(mut block) = outgoing.trailers.take() {
+                    // not flow-controlled, and split like response headers
+                    let mut flags: BitFlags<HeadersFlags> = HeadersFlags::EndStream.into();
+                    let mut is_continuation = false;
+                    loop {
+                        let (piece, rest) = if block.len() > max_fram {
+                            let (piece, rest) = block.split_at(max_fram);
+                            (piece, Some(rest))
+                        } else {
+                            (block, None)
+                        };
+                        let frame_type = match (is_continuation, rest.is_some()) {
+                            (false, false) => FrameType::Headers(flags | HeadersFlags::EndHeaders),
+                            (false, true) => FrameType::Headers(flags),
+                            (true, false) => FrameType::Continuation(
+                                BitFlags::<ContinuationFlags>::default()
+                                    | ContinuationFlags::EndHeaders,
+                            ),
+                            (true, true) => FrameType::Continuation(Default::default()),
+                        };
+                        frames.push((Frame::new(frame_type, id), PieceList::single(piece)));
+                        match rest {
+                            Some(rest) => block = rest,
+                            None => break,
+                        }
+                        is_continuation = true;
+                        flags = Default::default();
+                    }
+                }
+            }
         }
 
         for (frame, plist) in frames {
@@ -596,7 +1170,7 @@ where
         trace!(?ev, "handling event");
 
         match ev.payload {
-            H2EventPayload::Headers(res) => {
+            H2EventPayload::Headers(res, budget) => {
                 let outgoing = match self
                     .state
                     .streams
@@ -622,7 +1196,6 @@ where
                 // wants is an `IntoIter`, we can definitely have a custom iterator
                 // that operates on all this instead of using a `Vec`.
 
-                // TODO: enforce max header size
                 let mut headers: Vec<(&[u8], &[u8])> = vec![];
                 // TODO: prevent overwriting pseudo-headers, especially :status?
                 headers.push((b":status", res.status.as_str().as_bytes()));
@@ -632,18 +1205,43 @@ where
                         // do not set transfer-encoding: chunked when doing HTTP/2
                         continue;
                     }
+                    // `HeaderName`s are always lowercase, no matter how
+                    // drivers built them, as HTTP/2 requires (RFC 9113,
+                    // section 8.2)
+                    debug_assert!(!name.as_str().bytes().any(|b| b.is_ascii_uppercase()));
                     headers.push((name.as_str().as_bytes(), value));
                 }
 
                 assert_eq!(self.out_scratch.len(), 0);
-                self.hpack_enc
-                    .encode_into(headers, &mut self.out_scratch)
-                    .map_err(H2ConnectionError::WriteError)?;
+                if let Some(budget) = budget {
+                    // encoding changes the dynamic table, and the peer must
+                    // see every change: try it on a copy first.
+                    let mut hpack_enc = self.hpack_enc.clone();
+                    hpack_enc
+                        .encode_into(headers, &mut self.out_scratch)
+                        .map_err(H2ConnectionError::WriteError)?;
+                    let len = self.out_scratch.len();
+                    if len > budget.max_len {
+                        debug!(%len, max = %budget.max_len, "response headers too large, not sending them");
+                        self.out_scratch.take_all();
+                        _ = budget.reply.send(Err(len));
+                        return Ok(());
+                    }
+                    self.hpack_enc = hpack_enc;
+                    _ = budget.reply.send(Ok(()));
+                } else {
+                    self.hpack_enc
+                        .encode_into(headers, &mut self.out_scratch)
+                        .map_err(H2ConnectionError::WriteError)?;
+                }
+                if let Some(tuner) = &mut self.hpack_tuner {
+                    tuner.after_block(&mut self.hpack_enc);
+                }
                 let payload = self.out_scratch.take_all();
 
                 outgoing.headers = HeadersOutgoing::WroteNone(payload.into());
                 self.state.streams_with_pending_data.insert(ev.stream_id);
-                if self.state.outgoing_capacity > 0 && outgoing.capacity > 0 {
+                if self.state.outgoing_capacity.get() > 0 && outgoing.capacity() > 0 {
                     // worth revisiting then!
                     self.state.send_data_maybe.notify_one();
                 }
@@ -671,12 +1269,31 @@ where
                 outgoing.body.push_back(chunk);
 
                 self.state.streams_with_pending_data.insert(ev.stream_id);
-                if self.state.outgoing_capacity > 0 && outgoing.capacity > 0 {
+                if self.state.outgoing_capacity.get() > 0 && outgoing.capacity() > 0 {
                     // worth revisiting then!
                     self.state.send_data_maybe.notify_one();
                 }
             }
-            H2EventPayload::BodyEnd => {
+            H2EventPayload::Flush => {
+                // chunks pile up when we're busy with other events: write
+                // out whatever this stream has pending (and others while
+                // we're at it), as far as flow control lets us
+                if self.state.streams_with_pending_data.contains(&ev.stream_id) {
+                    self.send_data_maybe().await?;
+                }
+            }
+            H2EventPayload::RequestBodyConsumed(len) => {
+                self.give_back_capacity(ev.stream_id, len).await?;
+            }
+            H2EventPayload::RequestBodyDropped => {
+                self.give_back_dropped_body_credit().await?;
+            }
+            H2EventPayload::Reset => {
+                if self.state.streams.contains_key(&ev.stream_id) {
+                    self.rst(ev.stream_id, H2StreamError::HandlerFailed).await?;
+                }
+            }
+            H2EventPayload::BodyEnd | H2EventPayload::Trailers(_) => {
                 let outgoing = match self
                     .state
                     .streams
@@ -687,6 +1304,18 @@ where
                     Some(outgoing) => outgoing,
                 };
 
+                if let H2EventPayload::Trailers(trailers) = ev.payload {
+                    let fields: Vec<(&[u8], &[u8])> = trailers
+                        .iter()
+                        .map(|(name, value)| (name.as_str().as_bytes(), &value[..]))
+                        .collect();
+                    assert_eq!(self.out_scratch.len(), 0);
+                    self.hpack_enc
+                        .encode_into(fields, &mut self.out_scratch)
+                        .map_err(H2ConnectionError::WriteError)?;
+                    outgoing.trailers = Some(self.out_scratch.take_all().into());
+                }
+
                 match &mut outgoing.body {
                     BodyOutgoing::StillReceiving(pieces) => {
                         let pieces = std::mem::take(pieces);
@@ -715,6 +1344,29 @@ where
         mut frame: Frame,
         payload: PieceList,
     ) -> Result<(), H2ConnectionError> {
+        let wire_len = FRAME_HEADER_LEN + payload.len() as u64;
+        match &frame.frame_type {
+            FrameType::Headers(flags) if flags.contains(HeadersFlags::EndStream) => {
+                // trailers count as body, cf. [WireSizes]
+                self.state
+                    .count_wire(frame.stream_id, |w| w.response_body += wire_len);
+            }
+            FrameType::Headers(_) => {
+                self.state
+                    .count_wire(frame.stream_id, |w| w.response_headers += wire_len);
+                self.state.note_first_byte(frame.stream_id);
+            }
+            FrameType::Continuation(_) => {
+                self.state
+                    .count_wire(frame.stream_id, |w| w.response_headers += wire_len);
+            }
+            FrameType::Data(_) => {
+                self.state
+                    .count_wire(frame.stream_id, |w| w.response_body += wire_len);
+            }
+            _ => {}
+        }
+
         match &frame.frame_type {
             FrameType::Data(flags) => {
                 let mut ss = match self.state.streams.entry(frame.stream_id) {
@@ -733,58 +1385,41 @@ where
                         }
                     };
                     let payload_len: u32 = payload.len().try_into().unwrap();
-                    let next_cap = outgoing.capacity - payload_len as i64;
+                    let next_cap = outgoing.capacity() - payload_len as i64;
 
                     if next_cap < 0 {
                         unreachable!(
                             "should never write a frame that makes the stream capacity negative: outgoing.capacity = {}, payload_len = {}",
-                            outgoing.capacity, payload.len()
+                            outgoing.capacity(), payload.len()
                         )
                     }
-                    outgoing.capacity = next_cap;
+                    outgoing.set_capacity(next_cap);
+                    // the encoder may queue more now
+                    outgoing.window.written(payload_len as u64);
                 }
 
                 // now update connection flow control window
                 {
                     let payload_len: u32 = payload.len().try_into().unwrap();
-                    let next_cap = self.state.outgoing_capacity - payload_len as i64;
+                    let next_cap = self.state.outgoing_capacity.get() - payload_len as i64;
 
                     if next_cap < 0 {
                         unreachable!(
                             "should never write a frame that makes the connection capacity negative: outgoing_capacity = {}, payload_len = {}",
-                            self.state.outgoing_capacity, payload.len()
+                            self.state.outgoing_capacity.get(), payload.len()
                         )
                     }
-                    self.state.outgoing_capacity = next_cap;
+                    self.state.outgoing_capacity.set(next_cap);
                 }
 
                 if flags.contains(DataFlags::EndStream) {
-                    // we won't be sending any more data on this stream
-                    self.state
-                        .streams_with_pending_data
-                        .remove(&frame.stream_id);
-
-                    match ss.get_mut() {
-                        StreamState::Open { .. } => {
-                            let incoming = match std::mem::take(ss.get_mut()) {
-                                StreamState::Open { incoming, .. } => incoming,
-                                _ => unreachable!(),
-                            };
-                            // this avoid having to re-insert the stream in the map
-                            *ss.get_mut() = StreamState::HalfClosedLocal { incoming };
-                        }
-                        _ => {
-                            // transition to closed
-                            ss.remove();
-                            debug!(
-                                "Closed stream {} (wrote data w/EndStream), now have {} streams",
-                                frame.stream_id,
-                                self.state.streams.len()
-                            );
-                        }
-                    }
+                    self.state.end_local_stream(frame.stream_id);
                 }
             }
+            FrameType::Headers(flags) if flags.contains(HeadersFlags::EndStream) => {
+                // trailers
+                self.state.end_local_stream(frame.stream_id);
+            }
             FrameType::Settings(_) => {
                 // TODO: keep track of whether our new settings have been
                 // acknowledged
@@ -794,15 +1429,18 @@ where
             }
         };
 
-        // TODO: enforce max_frame_size from the peer settings, not just u32::max
-        frame.len = payload
-            .len()
-            .try_into()
-            .map_err(|_| H2ConnectionError::FrameTooLarge {
-                frame_type: frame.frame_type,
-                frame_size: payload.len() as _,
-                max_frame_size: u32::MAX,
-            })?;
+        // callers split HEADERS and DATA, nothing else gets close
+        let max_frame_size = self.state.peer_settings.max_frame_size;
+        frame.len = match u32::try_from(payload.len()) {
+            Ok(len) if len <= max_frame_size => len,
+            _ => {
+                return Err(H2ConnectionError::FrameTooLarge {
+                    frame_type: frame.frame_type,
+                    frame_size: payload.len() as _,
+                    max_frame_size,
+                })
+            }
+        };
         debug!(?frame, ">");
         let frame_roll = frame
             .into_piece(&mut self.out_scratch)
@@ -839,6 +1477,20 @@ where
                     });
                 }
 
+                // the whole frame counts against flow control, padding included,
+                // whether or not the stream is still open
+                let flow_len = frame.len;
+                let next_conn_cap = self.state.incoming_capacity - flow_len as i64;
+                if next_conn_cap < 0 {
+                    return Err(H2ConnectionError::WindowUnderflow {
+                        stream_id: StreamId::CONNECTION,
+                    });
+                }
+                self.state.incoming_capacity = next_conn_cap;
+                self.state.count_wire(frame.stream_id, |w| {
+                    w.request_body += FRAME_HEADER_LEN + frame.len as u64
+                });
+
                 let ss = self.state.streams.get_mut(&frame.stream_id).ok_or(
                     H2ConnectionError::StreamClosed {
                         stream_id: frame.stream_id,
@@ -848,7 +1500,7 @@ where
                 match ss {
                     StreamState::Open { incoming, .. }
                     | StreamState::HalfClosedLocal { incoming } => {
-                        let next_cap = incoming.capacity - payload.len() as i64;
+                        let next_cap = incoming.capacity - flow_len as i64;
                         if next_cap < 0 {
                             return Err(H2ConnectionError::WindowUnderflow {
                                 stream_id: frame.stream_id,
@@ -862,10 +1514,22 @@ where
                             ChunkPosition::NotLast
                         };
 
-                        // TODO: give back capacity to peer at some point
-                        if let Err(e) = incoming.write_chunk(payload.into(), which).await {
+                        // the body gives back what it reads, but padding and
+                        // empty frames never reach it
+                        let payload_len = payload.len() as u32;
+                        let unseen = if payload_len == 0 {
+                            flow_len
+                        } else {
+                            flow_len - payload_len
+                        };
+
+                        if let Err(e) = incoming.write_chunk(payload.into(), which) {
                             self.rst(frame.stream_id, e).await?;
-                        } else if flags.contains(DataFlags::EndStream) {
+                            self.give_back_capacity(frame.stream_id, flow_len).await?;
+                            return Ok(());
+                        }
+
+                        if flags.contains(DataFlags::EndStream) {
                             if let StreamState::Open { .. } = ss {
                                 let outgoing = match std::mem::take(ss) {
                                     StreamState::Open { outgoing, .. } => outgoing,
@@ -873,6 +1537,7 @@ where
                                 };
                                 *ss = StreamState::HalfClosedRemote { outgoing };
                             } else if self.state.streams.remove(&frame.stream_id).is_some() {
+                                self.state.complete_stream(frame.stream_id);
                                 debug!(
                                     "Closed stream (read data w/EndStream) {}, now have {} streams",
                                     frame.stream_id,
@@ -880,6 +1545,8 @@ where
                                 );
                             }
                         }
+
+                        self.give_back_capacity(frame.stream_id, unseen).await?;
                     }
                     StreamState::HalfClosedRemote { .. } => {
                         debug!(
@@ -888,6 +1555,7 @@ where
                         );
                         self.rst(frame.stream_id, H2StreamError::StreamClosed)
                             .await?;
+                        self.give_back_capacity(frame.stream_id, flow_len).await?;
                     }
                     StreamState::Transition => unreachable!(),
                 }
@@ -895,10 +1563,13 @@ where
             FrameType::Headers(flags) => {
                 if flags.contains(HeadersFlags::Priority) {
                     let pri_spec;
-                    (payload, pri_spec) = PrioritySpec::parse(payload).finish().map_err(|_| {
-                        H2ConnectionError::ReadAndParse(ReadAndParseError::ParsingError {
-                            parser: "PrioritySpec",
-                        })
+                    let input = payload.clone();
+                    (payload, pri_spec) = PrioritySpec::parse(payload).finish().map_err(|e| {
+                        H2ConnectionError::ReadAndParse(ReadAndParseError::parsing(
+                            "PrioritySpec",
+                            &input[..],
+                            &e,
+                        ))
                     })?;
                     debug!(exclusive = %pri_spec.exclusive, stream_dependency = ?pri_spec.stream_dependency, weight = %pri_spec.weight, "received priority, exclusive");
 
@@ -993,14 +1664,7 @@ where
                 }
 
                 if let Err(e) = self
-                    .read_headers(
-                        headers_or_trailers,
-                        mode,
-                        flags,
-                        frame.stream_id,
-                        payload,
-                        rx,
-                    )
+                    .read_headers(headers_or_trailers, mode, flags, frame, payload, rx)
                     .await
                 {
                     match e {
@@ -1023,8 +1687,11 @@ where
                             // TODO: inserting/removing here is probably unnecessary.
 
                             // respond with status code
-                            let responder =
-                                Responder::new(H2Encoder::new(frame.stream_id, self.ev_tx.clone()));
+                            let responder = Responder::new(H2Encoder::new(
+                                frame.stream_id,
+                                self.ev_tx.clone(),
+                                Rc::new(StreamWire::untracked()),
+                            ));
                             responder
                                 .write_final_response_with_body(
                                     crate::Response {
@@ -1086,7 +1753,11 @@ where
                     .await?;
                     return Ok(());
                 }
-                // TODO: do something with the error code?
+                let error_code = ErrorCode(
+                    (&payload[..])
+                        .read_u32::<BigEndian>()
+                        .expect("frame length was checked above"),
+                );
 
                 match self.state.streams.remove(&frame.stream_id) {
                     None => {
@@ -1095,6 +1766,10 @@ where
                         })
                     }
                     Some(ss) => {
+                        self.state.complete_stream(frame.stream_id);
+                        self.state
+                            .streams_with_pending_data
+                            .remove(&frame.stream_id);
                         debug!(
                             "Closed stream (read RstStream) {}, now have {} streams",
                             frame.stream_id,
@@ -1103,7 +1778,7 @@ where
                         match ss {
                             StreamState::Open { mut incoming, .. }
                             | StreamState::HalfClosedLocal { mut incoming, .. } => {
-                                incoming.send_error(StreamIncomingError::StreamReset).await;
+                                incoming.terminate(H2BodyError::ResetByPeer { error_code });
                             }
                             StreamState::HalfClosedRemote { .. } => {
                                 // good
@@ -1133,50 +1808,11 @@ where
                             len: payload.len() as _,
                         });
                     }
-                } else {
-                    let original_initial_window_size = self.state.peer_settings.initial_window_size;
-                    let s = &mut self.state.peer_settings;
-
-                    Settings::parse(&payload[..], |code, value| {
-                        s.apply(code, value)?;
-                        match code {
-                            Setting::HeaderTableSize => {
-                                self.hpack_enc.set_max_table_size(value as _);
-                            }
-                            _ => {
-                                // nothing to do
-                            }
-                        }
-                        Ok(())
-                    })
-                    .map_err(H2ConnectionError::BadSettingValue)?;
-
-                    let initial_window_size_delta =
-                        (s.initial_window_size as i64) - (original_initial_window_size as i64);
-
-                    let mut maybe_send_data = false;
-                    if initial_window_size_delta != 0 {
-                        // apply that delta to all streams
-                        for (id, stream) in self.state.streams.iter_mut() {
-                            if let Some(outgoing) = stream.outgoing_mut() {
-                                let next_cap = outgoing.capacity + initial_window_size_delta;
-                                if next_cap > MAX_WINDOW_SIZE {
-                                    return Err(
-                                        H2ConnectionError::StreamWindowSizeOverflowDueToSettings {
-                                            stream_id: *id,
-                                        },
-                                    );
-                                }
-                                // if capacity was negative or zero, and is now greater than zero,
-                                // we need to maybe send data
-                                if next_cap > 0 && outgoing.capacity <= 0 {
-                                    debug!(?id, %next_cap, "stream capacity was <= 0, now > 0");
-                                    maybe_send_data = true;
-                                }
-                                outgoing.capacity = next_cap;
-                            }
-                        }
+                    if !self.state.self_settings_acked {
+                        self.on_self_settings_acked();
                     }
+                } else {
+                    let maybe_send_data = self.apply_peer_settings(&payload[..])?;
 
                     let frame = Frame::new(
                         FrameType::Settings(SettingsFlags::Ack.into()),
@@ -1235,10 +1871,13 @@ where
                     });
                 }
 
-                let (_, update) = WindowUpdate::parse(payload).finish().map_err(|_| {
-                    H2ConnectionError::ReadAndParse(ReadAndParseError::ParsingError {
-                        parser: "WindowUpdate",
-                    })
+                let input = payload.clone();
+                let (_, update) = WindowUpdate::parse(payload).finish().map_err(|e| {
+                    H2ConnectionError::ReadAndParse(ReadAndParseError::parsing(
+                        "WindowUpdate",
+                        &input[..],
+                        &e,
+                    ))
                 })?;
                 debug!(?update, "Received window update");
 
@@ -1247,13 +1886,13 @@ where
                 }
 
                 if frame.stream_id == StreamId::CONNECTION {
-                    let new_capacity = self.state.outgoing_capacity + update.increment as i64;
+                    let new_capacity = self.state.outgoing_capacity.get() + update.increment as i64;
                     if new_capacity > MAX_WINDOW_SIZE {
                         return Err(H2ConnectionError::WindowUpdateOverflow);
                     };
 
-                    debug!(old_capacity = %self.state.outgoing_capacity, %new_capacity, "connection window update");
-                    self.state.outgoing_capacity = new_capacity;
+                    debug!(old_capacity = %self.state.outgoing_capacity.get(), %new_capacity, "connection window update");
+                    self.state.outgoing_capacity.set(new_capacity);
                     self.state.send_data_maybe.notify_one();
                 } else {
                     let outgoing = match self
@@ -1263,6 +1902,12 @@ where
                         .and_then(|ss| ss.outgoing_mut())
                     {
                         Some(ss) => ss,
+                        // the peer may not have seen the end of the
+                        // stream yet, cf. RFC 9113 section 5.1, "closed"
+                        None if frame.stream_id <= self.state.last_stream_id => {
+                            debug!(stream_id = %frame.stream_id, "ignoring window update for closed stream");
+                            return Ok(());
+                        }
                         None => {
                             return Err(H2ConnectionError::WindowUpdateForUnknownOrClosedStream {
                                 stream_id: frame.stream_id,
@@ -1270,7 +1915,7 @@ where
                         }
                     };
 
-                    let new_capacity = outgoing.capacity + update.increment as i64;
+                    let new_capacity = outgoing.capacity() + update.increment as i64;
                     if new_capacity > MAX_WINDOW_SIZE {
                         // reset the stream
                         self.rst(frame.stream_id, H2StreamError::WindowUpdateOverflow)
@@ -1278,18 +1923,18 @@ where
                         return Ok(());
                     }
 
-                    let old_capacity = outgoing.capacity;
+                    let old_capacity = outgoing.capacity();
                     debug!(stream_id = %frame.stream_id, %old_capacity, %new_capacity, "stream window update");
-                    outgoing.capacity = new_capacity;
+                    outgoing.set_capacity(new_capacity);
 
                     // insert into streams_with_pending_data if the old capacity was <= zero
                     // and the new capacity is > zero
                     if old_capacity <= 0 && new_capacity > 0 {
-                        debug!(conn_capacity = %self.state.outgoing_capacity, "stream capacity is newly positive, inserting in streams_with_pending_data");
+                        debug!(conn_capacity = %self.state.outgoing_capacity.get(), "stream capacity is newly positive, inserting in streams_with_pending_data");
                         self.state.streams_with_pending_data.insert(frame.stream_id);
 
                         // if the connection has capacity, notify!
-                        if self.state.outgoing_capacity > 0 {
+                        if self.state.outgoing_capacity.get() > 0 {
                             debug!(stream_id = ?frame.stream_id, "stream window update, maybe send data");
                             self.state.send_data_maybe.notify_one();
                         }
@@ -1301,6 +1946,58 @@ where
                     stream_id: frame.stream_id,
                 });
             }
+            FrameType::PriorityUpdate => {
+                if frame.stream_id != StreamId::CONNECTION {
+                    return Err(H2ConnectionError::PriorityUpdateWithNonZeroStreamId {
+                        stream_id: frame.stream_id,
+                    });
+                }
+                let update = match PriorityUpdate::parse(payload) {
+                    Ok((_rest, update)) => update,
+                    Err(_) => {
+                        return Err(H2ConnectionError::PriorityUpdateInvalidLength {
+                            len: frame.len,
+                        })
+                    }
+                };
+                let id = update.prioritized_stream_id;
+                // we never push, so even streams can't be in a valid state
+                if id == StreamId::CONNECTION || id.is_server_initiated() {
+                    return Err(H2ConnectionError::PriorityUpdateInvalidStreamId {
+                        prioritized_stream_id: id,
+                    });
+                }
+
+                let priority = Priority::parse(&update.priority_field_value[..]);
+                debug!(stream_id = %id, ?priority, "received priority update");
+                if let Some(outgoing) = self
+                    .state
+                    .streams
+                    .get_mut(&id)
+                    .and_then(|ss| ss.outgoing_mut())
+                {
+                    outgoing.priority = priority;
+                } else if id > self.state.last_stream_id {
+                    // the stream is idle: its HEADERS may still be on their
+                    // way. we remember as many of those as there may be
+                    // concurrent streams, and ignore the rest.
+                    let max = self
+                        .state
+                        .self_settings
+                        .max_concurrent_streams
+                        .unwrap_or(u32::MAX) as usize;
+                    let pending = &mut self.state.pending_priorities;
+                    if pending.len() < max || pending.contains_key(&id) {
+                        pending.insert(id, priority);
+                    }
+                }
+                // otherwise it's closed or half-closed (local), and there's
+                // nothing left to schedule
+            }
+            FrameType::Origin => {
+                // only servers send those (RFC 8336, Section 2.1)
+                trace!("ignoring ORIGIN frame from client");
+            }
             FrameType::Unknown(ft) => {
                 trace!(
                     "ignoring unknown frame with type 0x{:x}, flags 0x{:x}",
@@ -1313,15 +2010,187 @@ where
         Ok(())
     }
 
+    /// Records that `len` bytes received on `stream_id` won't be buffered
+    /// anymore, and sends WINDOW_UPDATE frames once enough of them have
+    /// piled up: for the stream if the peer may still send data on it, and
+    /// for the connection regardless.
+    ///
+    /// Under memory pressure, windows are only topped up to
+    /// [PressureConf::h2_window_size]: the rest stays owed until the
+    /// pressure goes away.
+    async fn give_back_capacity(
+        &mut self,
+        stream_id: StreamId,
+        len: u32,
+    ) -> Result<(), H2ConnectionError> {
+        if len == 0 {
+            return Ok(());
+        }
+        let mut threshold = self.state.self_settings.initial_window_size / 2;
+        let max_window = if self.conf.pressure.under_pressure() {
+            let max_window = self.conf.pressure.h2_window_size;
+            threshold = threshold.min(max_window / 2).max(1);
+            Some(max_window as i64)
+        } else {
+            None
+        };
+
+        let stream_increment = match self
+            .state
+            .streams
+            .get_mut(&stream_id)
+            .and_then(|ss| ss.incoming_mut())
+        {
+            Some(incoming) => {
+                incoming.credit_owed += len;
+                if incoming.credit_owed >= threshold {
+                    let increment =
+                        window_increment(incoming.credit_owed, incoming.capacity, max_window);
+                    incoming.credit_owed -= increment;
+                    incoming.capacity += increment as i64;
+                    Some(increment)
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+        if let Some(increment) = stream_increment.filter(|&i| i > 0) {
+            self.write_window_update(stream_id, increment).await?;
+        }
+
+        self.state.incoming_credit_owed += len;
+        if self.state.incoming_credit_owed >= threshold {
+            let increment = window_increment(
+                self.state.incoming_credit_owed,
+                self.state.incoming_capacity,
+                max_window,
+            );
+            if increment > 0 {
+                self.state.incoming_credit_owed -= increment;
+                self.state.incoming_capacity += increment as i64;
+                self.write_window_update(StreamId::CONNECTION, increment)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gives back the receive window of request bodies that were dropped
+    /// unread. Only the connection window matters: the streams don't take
+    /// any more data once their body is gone.
+    async fn give_back_dropped_body_credit(&mut self) -> Result<(), H2ConnectionError> {
+        let len = self.dropped_body_credit.take();
+        self.give_back_capacity(StreamId::CONNECTION, len).await
+    }
+
+    async fn write_window_update(
+        &mut self,
+        stream_id: StreamId,
+        increment: u32,
+    ) -> Result<(), H2ConnectionError> {
+        debug!(%stream_id, %increment, "Sending WindowUpdate");
+        let payload = WindowUpdate {
+            reserved: 0,
+            increment,
+        }
+        .into_piece(&mut self.out_scratch)
+        .map_err(H2ConnectionError::WriteError)?;
+        let frame = Frame::new(FrameType::WindowUpdate, stream_id);
+        self.write_frame(frame, PieceList::single(payload)).await
+    }
+
+    /// Applies the settings in a SETTINGS `payload` from the peer, returns
+    /// whether that let streams send data they were holding.
+    fn apply_peer_settings(&mut self, payload: &[u8]) -> Result<bool, H2ConnectionError> {
+        let original_initial_window_size = self.state.peer_settings.initial_window_size;
+        let s = &mut self.state.peer_settings;
+
+        Settings::parse(payload, |code, value| {
+            s.apply(code, value)?;
+            match code {
+                Setting::HeaderTableSize => match &mut self.hpack_tuner {
+                    Some(tuner) => tuner.on_peer_max(value, &mut self.hpack_enc),
+                    // a smaller table must be signaled to the
+                    // peer's decoder, cf. RFC 7541 section 4.2
+                    None => self.hpack_enc.resize_table(value as _),
+                },
+                _ => {
+                    // nothing to do
+                }
+            }
+            Ok(())
+        })
+        .map_err(H2ConnectionError::BadSettingValue)?;
+
+        let initial_window_size_delta =
+            (s.initial_window_size as i64) - (original_initial_window_size as i64);
+
+        let mut maybe_send_data = false;
+        if initial_window_size_delta != 0 {
+            // apply that delta to all streams
+            for (id, stream) in self.state.streams.iter_mut() {
+                if let Some(outgoing) = stream.outgoing_mut() {
+                    let next_cap = outgoing.capacity() + initial_window_size_delta;
+                    if next_cap > MAX_WINDOW_SIZE {
+                        return Err(H2ConnectionError::StreamWindowSizeOverflowDueToSettings {
+                            stream_id: *id,
+                        });
+                    }
+                    // if capacity was negative or zero, and is now greater than zero,
+                    // we need to maybe send data
+                    if next_cap > 0 && outgoing.capacity() <= 0 {
+                        debug!(?id, %next_cap, "stream capacity was <= 0, now > 0");
+                        maybe_send_data = true;
+                    }
+                    outgoing.set_capacity(next_cap);
+                }
+            }
+        }
+
+        Ok(maybe_send_data)
+    }
+
+    /// Send an ORIGIN frame listing `origins`, leaving out those that don't
+    /// fit in a single frame.
+    async fn send_origins(&mut self, origins: Vec<String>) -> Result<(), H2ConnectionError> {
+        let max_frame_size = self.state.peer_settings.max_frame_size as usize;
+        let mut payload = Origin::default();
+        let mut len = 0;
+        for origin in origins {
+            if len + 2 + origin.len() > max_frame_size {
+                debug!(%origin, "Origin doesn't fit in the ORIGIN frame, leaving it out");
+                continue;
+            }
+            len += 2 + origin.len();
+            payload.origins.push(origin.into_bytes().into());
+        }
+
+        debug!(origins = %payload.origins.len(), "Sending Origin");
+        let payload = payload
+            .into_piece(&mut self.out_scratch)
+            .map_err(H2ConnectionError::WriteError)?;
+        let frame = Frame::new(FrameType::Origin, StreamId::CONNECTION);
+        self.write_frame(frame, PieceList::single(payload)).await
+    }
+
     /// Send a RST_STREAM frame to the peer.
     async fn rst(
         &mut self,
         stream_id: StreamId,
         e: H2StreamError,
     ) -> Result<(), H2ConnectionError> {
-        self.state.streams.remove(&stream_id);
-
         let error_code = e.as_known_error_code();
+        if let Some(mut ss) = self.state.streams.remove(&stream_id) {
+            if let Some(incoming) = ss.incoming_mut() {
+                incoming.terminate(H2BodyError::Reset { error_code });
+            }
+        }
+        self.state.complete_stream(stream_id);
+        // whatever it had queued isn't going out anymore
+        self.state.streams_with_pending_data.remove(&stream_id);
+
         debug!("Sending rst because: {e} (known error code: {error_code:?})");
 
         debug!(%stream_id, ?error_code, "Sending RstStream");
@@ -1346,16 +2215,169 @@ where
         Ok(())
     }
 
+    /// Takes over the request that asked to switch to HTTP/2, as stream 1,
+    /// half-closed (remote): it had no body. Its `HTTP2-Settings` count as
+    /// the client's first SETTINGS frame, and the 101 acknowledged them.
+    fn accept_upgrade(&mut self, upgrade: H2cUpgrade) -> Result<(), H2ConnectionError> {
+        let H2cUpgrade {
+            mut req,
+            settings,
+            wire_len,
+        } = upgrade;
+        self.apply_peer_settings(&settings)?;
+
+        let stream_id = StreamId(1);
+        self.state.last_stream_id = stream_id;
+        req.version = Version::HTTP_2;
+        req.stream_id = Some(stream_id.0);
+        req.conn = Some(self.conn_info.clone());
+
+        let _span = stream_span(stream_id).entered();
+        debug!("Answering upgraded request on stream 1");
+        self.accept_request(stream_id, req, true, Some(0), wire_len);
+        Ok(())
+    }
+
+    /// Hands a request to the driver, on a stream that's open (or
+    /// half-closed, if `end_stream`) from then on.
+    fn accept_request(
+        &mut self,
+        stream_id: StreamId,
+        req: Request,
+        end_stream: bool,
+        content_length: Option<u64>,
+        wire_len: u64,
+    ) {
+        // we're in the stream's span here, so this one nests under it
+        let span = debug_span!(
+            "request",
+            method = %req.method,
+            path = %req.uri.path(),
+            ttfb_us = field::Empty,
+            write_us = field::Empty,
+            total_us = field::Empty,
+        );
+        let mut timings = Timings::new(Instant::now());
+        timings.accepted_at = self.conn_info.accepted_at;
+        timings.tls_done_at = self.conn_info.tls_done_at;
+        let sizes = WireSizes {
+            request_headers: wire_len,
+            ..Default::default()
+        };
+        let wire = Rc::new(StreamWire::new(sizes, timings, span.clone()));
+        self.state.wire.insert(stream_id, wire.clone());
+        let mut outgoing: StreamOutgoing = self.state.mk_stream_outgoing();
+        // a PRIORITY_UPDATE received before the request is more
+        // recent than its header
+        self.state
+            .pending_priorities
+            .retain(|id, _| *id >= stream_id);
+        outgoing.priority = match self.state.pending_priorities.remove(&stream_id) {
+            Some(priority) => priority,
+            None => req
+                .headers
+                .get("priority")
+                .map(|value| Priority::parse(&value[..]))
+                .unwrap_or_default(),
+        };
+        let mut encoder = H2Encoder::new(stream_id, self.ev_tx.clone(), wire.clone());
+        encoder.head_request = req.method == Method::Head;
+        encoder.date_header = self.conf.date_header;
+        encoder.default_headers = self.default_headers.clone();
+        encoder.max_header_size = Some(self.conf.max_response_header_size);
+        encoder.send_window = Some(outgoing.window.clone());
+        let responder = Responder::new(encoder);
+
+        // large enough for the window the stream gets once the peer
+        // acknowledges our settings, if it hasn't yet
+        let (piece_tx, piece_rx) = incoming_channel(
+            self.state
+                .self_settings
+                .initial_window_size
+                .max(self.state.incoming_initial_window_size()),
+        );
+
+        let req_body = H2Body {
+            content_length,
+            eof: end_stream,
+            rx: piece_rx,
+            stream_id,
+            ev_tx: self.ev_tx.clone(),
+            dropped_credit: self.dropped_body_credit.clone(),
+        };
+
+        let incoming = StreamIncoming::new(
+            self.state.incoming_initial_window_size(),
+            content_length,
+            piece_tx,
+        );
+        self.state.streams.insert(
+            stream_id,
+            if end_stream {
+                StreamState::HalfClosedRemote { outgoing }
+            } else {
+                StreamState::Open { incoming, outgoing }
+            },
+        );
+        debug!(
+            "Just accepted stream, now have {} streams",
+            self.state.streams.len()
+        );
+
+        // FIXME: don't spawn, just add to an unordered futures
+        // instead and poll it in our main loop, to do intra-task
+        // concurrency.
+        //
+        // this lets us freeze the entire http2 server and explore
+        // its entire state.
+        buffet::spawn({
+            let driver = self.driver.clone();
+            let metrics = self.conf.metrics.clone();
+            async move {
+                let mut req_body = req_body;
+                let responder = responder;
+
+                let started_at = Instant::now();
+                wire.update_timings(|t| t.driver_started_at = started_at);
+                let res = catch_handler_panic(driver.handle(req, &mut req_body, responder)).await;
+                if let Some(sink) = metrics {
+                    sink.observe(
+                        Histogram::RequestDuration,
+                        started_at.elapsed().as_secs_f64(),
+                    );
+                }
+
+                // either way, dropping the responder answered with a
+                // 500, or reset the stream if it was too late for that
+                match res {
+                    Ok(Ok(_responder)) => {
+                        debug!("Handler completed successfully, gave us a responder");
+                    }
+                    Ok(Err(e)) => {
+                        debug!("Handler returned an error: {e}")
+                    }
+                    Err(panic) => {
+                        warn!("Handler panicked: {panic}")
+                    }
+                }
+            }
+            .instrument(span)
+        });
+    }
+
     async fn read_headers(
         &mut self,
         headers_or_trailers: HeadersOrTrailers,
         mode: ReadHeadersMode,
         flags: BitFlags<HeadersFlags, u8>,
-        stream_id: StreamId,
+        frame: Frame,
         payload: Roll,
         rx: &mut mpsc::Receiver<(Frame, Roll)>,
     ) -> Result<(), H2ErrorLevel> {
+        let stream_id = frame.stream_id;
         let end_stream = flags.contains(HeadersFlags::EndStream);
+        // the whole block, frame headers and padding included
+        let mut wire_len = FRAME_HEADER_LEN + frame.len as u64;
 
         enum Data {
             Single(Roll),
@@ -1371,6 +2393,8 @@ where
             #[allow(unused, clippy::let_unit_value)]
             let flags = (); // don't accidentally use the `flags` variable
 
+            let max_size = self.conf.max_header_section_size as usize;
+            let mut size = payload.len();
             let mut fragments = smallvec![payload];
 
             loop {
@@ -1407,6 +2431,20 @@ where
                     }
                 };
 
+                // an encoder has no reason to make a block larger than the
+                // decoded section, so this one is over the limit anyway. we're
+                // not buffering the rest just to find out by how much.
+                size += continuation_payload.len();
+                wire_len += FRAME_HEADER_LEN + continuation_frame.len as u64;
+                if size > max_size {
+                    return Err(H2ConnectionError::HeaderBlockTooLarge {
+                        stream_id,
+                        size,
+                        max_size,
+                    }
+                    .into());
+                }
+
                 // add fragment
                 fragments.push(continuation_payload);
 
@@ -1429,6 +2467,7 @@ where
         let mut scheme: Option<Scheme> = None;
         let mut path: Option<PieceStr> = None;
         let mut authority: Option<Authority> = None;
+        let mut protocol: Option<PieceStr> = None;
 
         let mut headers = Headers::default();
 
@@ -1440,8 +2479,25 @@ where
             let mut req_error: Option<H2StreamError> = None;
             let mut saw_regular_header = false;
 
+            // the section size is up to the decoder, cf. RFC 9113, section 6.5.2
+            let max_count = self.conf.max_header_count;
+            let strip_connection_specific =
+                self.conf.connection_specific_headers == ConnectionSpecificHeaders::Strip;
+            let lowercase_names =
+                self.conf.uppercase_header_names == UppercaseHeaderNames::Lowercase;
+            let enable_connect_protocol = self.conf.enable_connect_protocol;
+            let mut count = 0_usize;
+            let mut too_large = false;
+
             let on_header_pair = |key: Cow<[u8]>, value: Cow<[u8]>| {
-                if req_error.is_some() {
+                if key.first() != Some(&b':') {
+                    count += 1;
+                }
+                if count > max_count {
+                    too_large = true;
+                }
+
+                if req_error.is_some() || too_large {
                     return;
                 }
 
@@ -1470,10 +2526,9 @@ where
                     }
 
                     // it's a pseudo-header!
-                    // TODO: reject headers that occur after pseudo-headers
                     match &key[1..] {
                         b"method" => {
-                            let value: PieceStr = match Piece::from(value.to_vec()).to_str() {
+                            let value: PieceStr = match Piece::copy_from_slice(&value).to_str() {
                                 Ok(p) => p,
                                 Err(_) => {
                                     req_error = Some(H2StreamError::BadRequest(
@@ -1487,7 +2542,7 @@ where
                             }
                         }
                         b"scheme" => {
-                            let value: PieceStr = match Piece::from(value.to_vec()).to_str() {
+                            let value: PieceStr = match Piece::copy_from_slice(&value).to_str() {
                                 Ok(p) => p,
                                 Err(_) => {
                                     req_error = Some(H2StreamError::BadRequest(
@@ -1501,7 +2556,7 @@ where
                             }
                         }
                         b"path" => {
-                            let value: PieceStr = match Piece::from(value.to_vec()).to_str() {
+                            let value: PieceStr = match Piece::copy_from_slice(&value).to_str() {
                                 Ok(val) => val,
                                 Err(_) => {
                                     req_error = Some(H2StreamError::BadRequest("invalid ':path' pseudo-header (not valid utf-8, which is _certainly_ not a valid URI, as defined by RFC 3986, section 2. See also RFC 9113, section 8.3.1). "));
@@ -1514,7 +2569,7 @@ where
                             }
                         }
                         b"authority" => {
-                            let value: PieceStr = match Piece::from(value.to_vec()).to_str() {
+                            let value: PieceStr = match Piece::copy_from_slice(&value).to_str() {
                                 Ok(p) => p,
                                 Err(_) => {
                                     req_error = Some(H2StreamError::BadRequest(
@@ -1534,16 +2589,37 @@ where
                                 req_error = Some(H2StreamError::BadRequest("duplicate ':authority' pseudo-header. All HTTP/2 requests MUST include _exactly one_ valid value for the ':method', ':scheme', and ':path' pseudo-header fields, unless they are CONNECT requests (RFC 9113, section 8.3.1)"));
                             }
                         }
+                        b"protocol" if enable_connect_protocol => {
+                            let value: PieceStr = match Piece::copy_from_slice(&value).to_str() {
+                                Ok(p) => p,
+                                Err(_) => {
+                                    req_error = Some(H2StreamError::BadRequest(
+                                        "invalid ':protocol' pseudo-header: not valid utf-8",
+                                    ));
+                                    return;
+                                }
+                            };
+                            if protocol.replace(value).is_some() {
+                                req_error = Some(H2StreamError::BadRequest(
+                                    "duplicate ':protocol' pseudo-header",
+                                ));
+                            }
+                        }
+                        b"status" => {
+                            req_error = Some(H2StreamError::BadRequest(
+                                "':status' is a response pseudo-header, it MUST NOT appear in requests (RFC 9113, section 8.3)",
+                            ));
+                        }
                         _ => {
                             req_error = Some(H2StreamError::BadRequest(
-                                "received invalid pseudo-header. the only defined pseudo-headers are: ':method', ':scheme', ':path', ':authority', ':status' (RFC 9113, section 8.1)",
+                                "received invalid pseudo-header. the only pseudo-headers defined for requests are: ':method', ':scheme', ':path', ':authority' (RFC 9113, section 8.3.1)",
                             ));
                         }
                     }
                 } else {
                     saw_regular_header = true;
 
-                    let name = match HeaderName::from_bytes(&key[..]) {
+                    let name = match self.header_names.intern(&key[..]) {
                         Ok(name) => name,
                         Err(_) => {
                             req_error = Some(H2StreamError::BadRequest(
@@ -1556,7 +2632,9 @@ where
                     // Note: An implementation that validates fields according to the definitions in
                     // Sections 5.1 and 5.5 of HTTP only needs an additional check that field
                     // names do not include uppercase characters.
-                    if key.iter().any(|b: &u8| b.is_ascii_uppercase()) {
+                    // (interned names are lowercase, so there's
+                    // nothing more to do if we're not rejecting them)
+                    if !lowercase_names && key.iter().any(|b: &u8| b.is_ascii_uppercase()) {
                         req_error = Some(H2StreamError::BadRequest(
                             "A field name MUST NOT contain characters in the ranges 0x00-0x20, 0x41-0x5a, or 0x7f-0xff (all ranges inclusive). This specifically excludes all non-visible ASCII characters, ASCII SP (0x20), and uppercase characters ('A' to 'Z', ASCII 0x41 to 0x5a). See RFC9113, section 8.2.1, 'Field Validity'",
                         ));
@@ -1574,6 +2652,10 @@ where
                         || name == http::header::TRANSFER_ENCODING
                         || name == http::header::UPGRADE
                     {
+                        if strip_connection_specific {
+                            debug!(%name, "stripping connection-specific header");
+                            return;
+                        }
                         req_error = Some(H2StreamError::BadRequest(
                             "connection-specific headers are forbidden. see RFC 9113, section 8.1.2",
                         ));
@@ -1581,6 +2663,10 @@ where
                     }
 
                     if name == http::header::TE && &value[..] != b"trailers" {
+                        if strip_connection_specific {
+                            debug!("stripping 'te' header that isn't 'trailers'");
+                            return;
+                        }
                         req_error = Some(H2StreamError::BadRequest(
                             "The only exception to this is the TE header field, which MAY be present in an HTTP/2 request; when it is, it MUST NOT contain any value other than 'trailers'. cf. RFC9113, Section 8.2.2",
                         ));
@@ -1611,16 +2697,13 @@ where
                         return;
                     }
 
-                    let value: Piece = value.to_vec().into();
-                    headers.append(name, value);
+                    headers.append(name, Piece::copy_from_slice(&value));
                 }
             };
 
-            match data {
+            let res = match data {
                 Data::Single(payload) => {
-                    self.hpack_dec
-                        .decode_with_cb(&payload[..], on_header_pair)
-                        .map_err(|e| H2ErrorLevel::Connection(e.into()))?;
+                    self.hpack_dec.decode_with_cb(&payload[..], on_header_pair)
                 }
                 Data::Multi(fragments) => {
                     let total_len = fragments.iter().map(|f| f.len()).sum();
@@ -1631,15 +2714,40 @@ where
                     for frag in &fragments {
                         payload.extend_from_slice(&frag[..]);
                     }
-                    self.hpack_dec
-                        .decode_with_cb(&payload[..], on_header_pair)
-                        .map_err(|e| H2ErrorLevel::Connection(e.into()))?;
+                    self.hpack_dec.decode_with_cb(&payload[..], on_header_pair)
                 }
             };
+            match res {
+                Ok(()) => {}
+                // the block was decoded all the way, the table is fine
+                Err(DecoderError::HeaderListTooLarge) => too_large = true,
+                Err(e) => return Err(H2ErrorLevel::Connection(e.into())),
+            }
+
+            if too_large {
+                return Err(match headers_or_trailers {
+                    HeadersOrTrailers::Headers => H2RequestError {
+                        status: StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                        message: "request header section too large".into(),
+                    }
+                    .into(),
+                    HeadersOrTrailers::Trailers => H2StreamError::TrailersTooLarge.into(),
+                });
+            }
 
             if let Some(req_error) = req_error {
                 return Err(req_error.into());
             }
+
+            if matches!(headers_or_trailers, HeadersOrTrailers::Headers)
+                && self.conf.pressure.under_pressure()
+            {
+                return Err(H2RequestError {
+                    status: StatusCode::SERVICE_UNAVAILABLE,
+                    message: "server is low on memory".into(),
+                }
+                .into());
+            }
         }
 
         match headers_or_trailers {
@@ -1651,7 +2759,16 @@ where
 
                 let method = match method {
                     Some(method) => {
-                        if method == Method::Connect {
+                        if protocol.is_some() && method != Method::Connect {
+                            // RFC 8441, section 4: only extended CONNECT
+                            // requests carry a ':protocol'
+                            return Err(H2StreamError::BadRequest(
+                                "':protocol' pseudo-header is only allowed in CONNECT requests",
+                            )
+                            .into());
+                        }
+
+                        if method == Method::Connect && protocol.is_none() {
                             // RFC 9113, section 8.5 'The CONNECT method': The ":scheme" and ":path"
                             // pseudo-header fields MUST be omitted.
                             if scheme.is_some() {
@@ -1672,13 +2789,6 @@ where
                                 )
                                 .into());
                             }
-
-                            // well, also, we just don't support the `CONNECT` method.
-                            return Err(H2RequestError {
-                                status: StatusCode::NOT_IMPLEMENTED,
-                                message: "CONNECT method is not supported".into(),
-                            }
-                            .into());
                         }
 
                         method
@@ -1690,86 +2800,103 @@ where
                     }
                 };
 
-                let scheme = match scheme {
-                    Some(scheme) => scheme,
-                    None => {
-                        return Err(
-                            H2StreamError::BadRequest("missing :scheme pseudo-header").into()
-                        );
-                    }
-                };
-
-                let path = match path {
-                    Some(path) => path,
-                    None => {
-                        return Err(
-                            H2StreamError::BadRequest("missing :path pseudo-header, cf. RFC9113, section 8.3.1: This pseudo-header field MUST NOT be empty for 'http' or 'https' URIs; 'http' or 'https' URIs that do not contain a path component MUST include a value of '/'.").into()
-                        );
-                    }
-                };
+                // RFC 8441, section 4: extended CONNECT requests have a
+                // ':scheme' and a ':path', like any other request
+                let uri = if method == Method::Connect && protocol.is_none() {
+                    // RFC 9113, section 8.5: the target is just the authority,
+                    // DATA frames are tunnel bytes
+                    let mut uri_parts: http::uri::Parts = Default::default();
+                    uri_parts.authority = authority;
+                    http::uri::Uri::from_parts(uri_parts).map_err(|_| H2RequestError {
+                        status: StatusCode::BAD_REQUEST,
+                        message: "invalid URI parts".into(),
+                    })?
+                } else {
+                    let scheme = match scheme {
+                        Some(scheme) => scheme,
+                        None => {
+                            return Err(
+                                H2StreamError::BadRequest("missing :scheme pseudo-header").into()
+                            );
+                        }
+                    };
 
-                if path.len() == 0 && (scheme == Scheme::HTTP || scheme == Scheme::HTTPS) {
-                    return Err(H2StreamError::BadRequest(
-                        "as per RFC9113, section 8.3.1, ':path' header value MUST NOT be empty for 'http' and 'https' URIs",
-                    ).into());
-                }
+                    let path = match path {
+                        Some(path) => path,
+                        None => {
+                            return Err(
+                                H2StreamError::BadRequest("missing :path pseudo-header, cf. RFC9113, section 8.3.1: This pseudo-header field MUST NOT be empty for 'http' or 'https' URIs; 'http' or 'https' URIs that do not contain a path component MUST include a value of '/'.").into()
+                            );
+                        }
+                    };
 
-                let path_and_query: PathAndQuery = match path.parse() {
-                    Ok(p) => p,
-                    Err(_) => {
+                    if path.len() == 0 && (scheme == Scheme::HTTP || scheme == Scheme::HTTPS) {
                         return Err(H2StreamError::BadRequest(
-                            "':path' header value is not a valid PathAndQuery",
-                        )
-                        .into());
+                            "as per RFC9113, section 8.3.1, ':path' header value MUST NOT be empty for 'http' and 'https' URIs",
+                        ).into());
                     }
-                };
 
-                let authority = match authority {
-                    Some(authority) => {
-                        // if there's a `host` header, it must match the `:authority` pseudo-header
-                        if let Some(host) = headers.get(header::HOST) {
-                            let host = std::str::from_utf8(host).map_err(|_| {
-                                H2StreamError::BadRequest("'host' header value is not utf-8")
-                            })?;
-                            let host_authority: Authority = host.parse().map_err(|_| {
-                                H2StreamError::BadRequest("'host' header value is not a valid URI")
-                            })?;
-                            if host_authority != authority {
-                                return Err(H2StreamError::BadRequest(
-                                    "'host' header value does not match ':authority' pseudo-header value, cf. RFC9113, Section 8.3.1: A server SHOULD treat a request as malformed if it contains a Host header field that identifies an entity that differs from the entity in the ':authority' pseudo-header field"
-                                ).into());
-                            }
+                    let path_and_query: PathAndQuery = match path.parse() {
+                        Ok(p) => p,
+                        Err(_) => {
+                            return Err(H2StreamError::BadRequest(
+                                "':path' header value is not a valid PathAndQuery",
+                            )
+                            .into());
                         }
+                    };
+
+                    let authority = match authority {
+                        Some(authority) => {
+                            // if there's a `host` header, it must match the `:authority` pseudo-header
+                            if let Some(host) = headers.get(header::HOST) {
+                                let host = std::str::from_utf8(host).map_err(|_| {
+                                    H2StreamError::BadRequest("'host' header value is not utf-8")
+                                })?;
+                                let host_authority: Authority = host.parse().map_err(|_| {
+                                    H2StreamError::BadRequest(
+                                        "'host' header value is not a valid URI",
+                                    )
+                                })?;
+                                if host_authority != authority {
+                                    return Err(H2StreamError::BadRequest(
+                                        "'host' header value does not match ':authority' pseudo-header value, cf. RFC9113, Section 8.3.1: A server SHOULD treat a request as malformed if it contains a Host header field that identifies an entity that differs from the entity in the ':authority' pseudo-header field"
+                                    ).into());
+                                }
+                            }
 
-                        Some(authority)
-                    }
-                    None => match headers.get(header::HOST) {
-                        Some(host) => {
-                            let host = std::str::from_utf8(host).map_err(|_| {
-                                H2StreamError::BadRequest("'host' header value is not utf-8")
-                            })?;
-                            let authority: Authority = host.parse().map_err(|_| {
-                                H2StreamError::BadRequest("'host' header value is not a valid URI")
-                            })?;
                             Some(authority)
                         }
-                        None => None,
-                    },
-                };
+                        None => match headers.get(header::HOST) {
+                            Some(host) => {
+                                let host = std::str::from_utf8(host).map_err(|_| {
+                                    H2StreamError::BadRequest("'host' header value is not utf-8")
+                                })?;
+                                let authority: Authority = host.parse().map_err(|_| {
+                                    H2StreamError::BadRequest(
+                                        "'host' header value is not a valid URI",
+                                    )
+                                })?;
+                                Some(authority)
+                            }
+                            None => None,
+                        },
+                    };
 
-                let mut uri_parts: http::uri::Parts = Default::default();
-                uri_parts.scheme = Some(scheme);
-                uri_parts.authority = authority;
-                uri_parts.path_and_query = Some(path_and_query);
+                    let mut uri_parts: http::uri::Parts = Default::default();
+                    uri_parts.scheme = Some(scheme);
+                    uri_parts.authority = authority;
+                    uri_parts.path_and_query = Some(path_and_query);
 
-                let uri = match http::uri::Uri::from_parts(uri_parts) {
-                    Ok(uri) => uri,
-                    Err(_) => {
-                        return Err(H2RequestError {
-                            status: StatusCode::BAD_REQUEST,
-                            message: "invalid URI parts".into(),
+                    match http::uri::Uri::from_parts(uri_parts) {
+                        Ok(uri) => uri,
+                        Err(_) => {
+                            return Err(H2RequestError {
+                                status: StatusCode::BAD_REQUEST,
+                                message: "invalid URI parts".into(),
+                            }
+                            .into())
                         }
-                        .into())
                     }
                 };
 
@@ -1778,6 +2905,9 @@ where
                     uri,
                     version: Version::HTTP_2,
                     headers,
+                    conn: Some(self.conn_info.clone()),
+                    stream_id: Some(stream_id.0),
+                    protocol,
                 };
                 let content_length: Option<u64> = match req
                     .headers
@@ -1803,64 +2933,15 @@ where
                     }
                 };
 
-                let responder = Responder::new(H2Encoder::new(stream_id, self.ev_tx.clone()));
-
-                let (piece_tx, piece_rx) = mpsc::channel::<IncomingMessageResult>(1); // TODO: is 1 a sensible value here?
-
-                let req_body = H2Body {
-                    content_length,
-                    eof: end_stream,
-                    rx: piece_rx,
-                };
-
-                let incoming = StreamIncoming::new(
-                    self.state.self_settings.initial_window_size as _,
-                    content_length,
-                    piece_tx,
-                );
-                let outgoing: StreamOutgoing = self.state.mk_stream_outgoing();
-                self.state.streams.insert(
-                    stream_id,
-                    if end_stream {
-                        StreamState::HalfClosedRemote { outgoing }
-                    } else {
-                        StreamState::Open { incoming, outgoing }
-                    },
-                );
-                debug!(
-                    "Just accepted stream, now have {} streams",
-                    self.state.streams.len()
-                );
-
-                // FIXME: don't spawn, just add to an unordered futures
-                // instead and poll it in our main loop, to do intra-task
-                // concurrency.
-                //
-                // this lets us freeze the entire http2 server and explore
-                // its entire state.
-                buffet::spawn({
-                    let driver = self.driver.clone();
-                    async move {
-                        let mut req_body = req_body;
-                        let responder = responder;
-
-                        match driver.handle(req, &mut req_body, responder).await {
-                            Ok(_responder) => {
-                                debug!("Handler completed successfully, gave us a responder");
-                            }
-                            Err(e) => {
-                                // TODO: actually handle that error.
-                                debug!("Handler returned an error: {e}")
-                            }
-                        }
-                    }
-                });
+                self.accept_request(stream_id, req, end_stream, content_length, wire_len);
             }
             HeadersOrTrailers::Trailers => {
+                self.state
+                    .count_wire(stream_id, |w| w.request_body += wire_len);
                 match self.state.streams.entry(stream_id) {
                     Entry::Occupied(mut slot) => match slot.get_mut() {
                         StreamState::Open { incoming, .. } => {
-                            incoming.write_trailers(headers).await?;
+                            incoming.write_trailers(headers)?;
 
                             // set stream state to half closed remote. we do a little
                             // dance to avoid re-inserting.
@@ -1896,3 +2977,23 @@ enum ReadHeadersMode {
     // we're refusing the stream, we want to skip over the headers we read.
     Skip,
 }
+
+/// The span to process frames and events for `stream_id` in: none for the
+/// connection itself, so they stay in the connection's span.
+fn stream_span(stream_id: StreamId) -> Span {
+    if stream_id == StreamId::CONNECTION {
+        Span::none()
+    } else {
+        debug_span!("stream", id = %stream_id)
+    }
+}
+
+/// How much of the `owed` credit to grant to a window currently at
+/// `capacity`: all of it, unless that would take the window past
+/// `max_window`.
+fn window_increment(owed: u32, capacity: i64, max_window: Option<i64>) -> u32 {
+    match max_window {
+        Some(max_window) => (max_window - capacity).clamp(0, owed as i64) as u32,
+        None => owed,
+    }
+}
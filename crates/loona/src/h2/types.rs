@@ -1,17 +1,24 @@
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
+    cell::Cell,
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
     fmt,
+    rc::Rc,
+    time::Instant,
 };
 
 use buffet::Piece;
 use http::StatusCode;
 use loona_hpack::decoder::DecoderError;
-use tokio::sync::Notify;
+use tokio::sync::{oneshot, Notify};
+use tracing::{debug, Span};
 
-use crate::{util::ReadAndParseError, ResponderError, Response};
+use crate::{
+    error::ConnectionError, util::ReadAndParseError, Headers, OnComplete, ResponderError, Response,
+    Timings, WireSizes,
+};
 
 use super::{body::StreamIncoming, encode::H2EncoderError};
-use loona_h2::{FrameType, KnownErrorCode, Settings, SettingsError, StreamId};
+use loona_h2::{FrameType, KnownErrorCode, Priority, Settings, SettingsError, StreamId};
 
 pub(crate) struct ConnState {
     pub(crate) streams: HashMap<StreamId, StreamState>,
@@ -19,6 +26,9 @@ pub(crate) struct ConnState {
 
     pub(crate) self_settings: Settings,
     pub(crate) peer_settings: Settings,
+    /// Whether the peer acknowledged `self_settings`: until then, it goes by
+    /// the defaults
+    pub(crate) self_settings_acked: bool,
 
     /// notified when we have data to send, like when:
     /// - an H2Body has been written to, AND
@@ -30,8 +40,20 @@ pub(crate) struct ConnState {
     pub(crate) send_data_maybe: Notify,
     pub(crate) streams_with_pending_data: HashSet<StreamId>,
 
+    /// PRIORITY_UPDATE frames received for streams that are still idle,
+    /// applied when their HEADERS arrive
+    pub(crate) pending_priorities: HashMap<StreamId, Priority>,
+
     pub(crate) incoming_capacity: i64,
-    pub(crate) outgoing_capacity: i64,
+    /// shared with the streams' [SendWindow]s
+    pub(crate) outgoing_capacity: Rc<Cell<i64>>,
+
+    /// request body bytes read by handlers (or dropped) that we haven't
+    /// given back to the peer yet
+    pub(crate) incoming_credit_owed: u32,
+
+    /// wire bytes for the streams we handed to the driver, until they close
+    pub(crate) wire: HashMap<StreamId, Rc<StreamWire>>,
 }
 
 impl Default for ConnState {
@@ -42,27 +64,153 @@ impl Default for ConnState {
 
             self_settings: Default::default(),
             peer_settings: Default::default(),
+            self_settings_acked: false,
 
             send_data_maybe: Default::default(),
             streams_with_pending_data: Default::default(),
+            pending_priorities: Default::default(),
 
             incoming_capacity: 0,
-            outgoing_capacity: 0,
+            outgoing_capacity: Default::default(),
+
+            incoming_credit_owed: 0,
+
+            wire: Default::default(),
         };
         s.incoming_capacity = s.self_settings.initial_window_size as _;
-        s.outgoing_capacity = s.peer_settings.initial_window_size as _;
+        s.outgoing_capacity
+            .set(s.peer_settings.initial_window_size as _);
 
         s
     }
 }
 
 impl ConnState {
+    /// The initial stream window the peer goes by, cf.
+    /// <https://httpwg.org/specs/rfc9113.html#SettingsSync>
+    pub(crate) fn incoming_initial_window_size(&self) -> u32 {
+        if self.self_settings_acked {
+            self.self_settings.initial_window_size
+        } else {
+            Settings::default().initial_window_size
+        }
+    }
+
     /// create a new [StreamOutgoing] based on our current settings
     pub(crate) fn mk_stream_outgoing(&self) -> StreamOutgoing {
         StreamOutgoing {
             headers: HeadersOutgoing::WaitingForHeaders,
             body: BodyOutgoing::StillReceiving(Default::default()),
-            capacity: self.peer_settings.initial_window_size as _,
+            trailers: None,
+            window: Rc::new(SendWindow {
+                stream: Cell::new(self.peer_settings.initial_window_size as _),
+                conn: self.outgoing_capacity.clone(),
+                queued: Default::default(),
+                closed: Default::default(),
+                changed: Default::default(),
+            }),
+            priority: Default::default(),
+        }
+    }
+
+    /// Counts wire bytes for a stream, if it's one we handed to the driver
+    pub(crate) fn count_wire(&self, stream_id: StreamId, f: impl FnOnce(&mut WireSizes)) {
+        if let Some(wire) = self.wire.get(&stream_id) {
+            let mut sizes = wire.sizes.get();
+            f(&mut sizes);
+            wire.sizes.set(sizes);
+        }
+    }
+
+    /// Notes that a response header section went out on a stream, if it's
+    /// one we handed to the driver
+    pub(crate) fn note_first_byte(&self, stream_id: StreamId) {
+        if let Some(wire) = self.wire.get(&stream_id) {
+            wire.update_timings(|t| {
+                t.first_byte_at.get_or_insert_with(Instant::now);
+            });
+        }
+    }
+
+    /// Lets the driver know how many bytes a stream took, now that it's
+    /// closed
+    /// We won't be sending anything more on this stream: it's half-closed
+    /// (local), or closed if the peer was done too
+    pub(crate) fn end_local_stream(&mut self, stream_id: StreamId) {
+        self.streams_with_pending_data.remove(&stream_id);
+
+        let Entry::Occupied(mut ss) = self.streams.entry(stream_id) else {
+            return;
+        };
+        match ss.get_mut() {
+            StreamState::Open { .. } => {
+                let incoming = match std::mem::take(ss.get_mut()) {
+                    StreamState::Open { incoming, .. } => incoming,
+                    _ => unreachable!(),
+                };
+                // this avoid having to re-insert the stream in the map
+                *ss.get_mut() = StreamState::HalfClosedLocal { incoming };
+            }
+            _ => {
+                // transition to closed
+                ss.remove();
+                self.complete_stream(stream_id);
+                debug!(
+                    "Closed stream {stream_id} (wrote END_STREAM), now have {} streams",
+                    self.streams.len()
+                );
+            }
+        }
+    }
+
+    pub(crate) fn complete_stream(&mut self, stream_id: StreamId) {
+        if let Some(wire) = self.wire.remove(&stream_id) {
+            wire.complete();
+        }
+    }
+}
+
+/// Wire bytes exchanged on a stream and when things happened on it, shared
+/// with its encoder so the driver can register a callback
+pub(crate) struct StreamWire {
+    pub(crate) sizes: Cell<WireSizes>,
+    pub(crate) timings: Cell<Timings>,
+    pub(crate) on_complete: Cell<Option<OnComplete>>,
+    /// the stream's request span, to record timings on
+    pub(crate) span: Span,
+}
+
+impl StreamWire {
+    pub(crate) fn new(sizes: WireSizes, timings: Timings, span: Span) -> Self {
+        Self {
+            sizes: Cell::new(sizes),
+            timings: Cell::new(timings),
+            on_complete: Default::default(),
+            span,
+        }
+    }
+
+    /// For responses we write ourselves, nobody's going to look at it
+    pub(crate) fn untracked() -> Self {
+        Self::new(
+            Default::default(),
+            Timings::new(Instant::now()),
+            Span::none(),
+        )
+    }
+
+    pub(crate) fn update_timings(&self, f: impl FnOnce(&mut Timings)) {
+        let mut timings = self.timings.get();
+        f(&mut timings);
+        self.timings.set(timings);
+    }
+
+    pub(crate) fn complete(&self) {
+        self.update_timings(|t| t.last_byte_at = Instant::now());
+        let timings = self.timings.get();
+        timings.record(&self.span);
+        if let Some(on_complete) = self.on_complete.take() {
+            on_complete(self.sizes.get(), timings);
         }
     }
 }
@@ -143,15 +291,103 @@ impl StreamState {
             _ => None,
         }
     }
+
+    /// Get the inner `StreamOutgoing` if the state is `Open` or
+    /// `HalfClosedRemote`.
+    pub(crate) fn outgoing(&self) -> Option<&StreamOutgoing> {
+        match self {
+            StreamState::Open { outgoing, .. } => Some(outgoing),
+            StreamState::HalfClosedRemote { outgoing, .. } => Some(outgoing),
+            _ => None,
+        }
+    }
+
+    /// Get the inner `StreamIncoming` if the state is `Open` or
+    /// `HalfClosedLocal`.
+    pub(crate) fn incoming_mut(&mut self) -> Option<&mut StreamIncoming> {
+        match self {
+            StreamState::Open { incoming, .. } => Some(incoming),
+            StreamState::HalfClosedLocal { incoming, .. } => Some(incoming),
+            _ => None,
+        }
+    }
 }
 
 pub(crate) struct StreamOutgoing {
     pub(crate) headers: HeadersOutgoing,
     pub(crate) body: BodyOutgoing,
 
+    // HPACK-encoded trailers, sent with END_STREAM once the body is out
+    pub(crate) trailers: Option<Piece>,
+
     // window size of the stream, ie. how many bytes
     // we can send to the receiver before waiting.
-    pub(crate) capacity: i64,
+    pub(crate) window: Rc<SendWindow>,
+
+    // cf. RFC 9218: streams are written in order of urgency
+    pub(crate) priority: Priority,
+}
+
+impl StreamOutgoing {
+    pub(crate) fn capacity(&self) -> i64 {
+        self.window.stream.get()
+    }
+
+    pub(crate) fn set_capacity(&self, capacity: i64) {
+        self.window.stream.set(capacity);
+        self.window.changed.notify_waiters();
+    }
+}
+
+impl Drop for StreamOutgoing {
+    fn drop(&mut self) {
+        // whether the stream was reset or we're done sending, the encoder
+        // has nothing left to wait for
+        self.window.closed.set(true);
+        self.window.changed.notify_waiters();
+    }
+}
+
+/// A stream's send window, shared with its encoder: body chunks wait until
+/// the peer is ready for them, instead of piling up in the connection. cf.
+/// <https://httpwg.org/specs/rfc9113.html#FlowControl>
+pub(crate) struct SendWindow {
+    /// How many bytes the peer lets us send on the stream
+    pub(crate) stream: Cell<i64>,
+    /// Same, for the whole connection
+    pub(crate) conn: Rc<Cell<i64>>,
+    /// Body bytes handed to the connection that weren't written yet
+    pub(crate) queued: Cell<u64>,
+    /// Set once nothing more will be written on the stream
+    pub(crate) closed: Cell<bool>,
+    /// Wakes up the encoder whenever any of the above changes
+    pub(crate) changed: Notify,
+}
+
+impl SendWindow {
+    /// Waits until what's queued fits in both windows, so another chunk
+    /// may follow, or until nothing is queued. Returns false if the stream
+    /// was closed in the meantime.
+    pub(crate) async fn wait_for_room(&self) -> bool {
+        loop {
+            let changed = self.changed.notified();
+            if self.closed.get() {
+                return false;
+            }
+            let room = self.stream.get().min(self.conn.get());
+            let queued = self.queued.get();
+            if queued == 0 || (queued as i64) < room {
+                return true;
+            }
+            changed.await;
+        }
+    }
+
+    /// Records that `len` queued bytes were written out
+    pub(crate) fn written(&self, len: u64) {
+        self.queued.set(self.queued.get().saturating_sub(len));
+        self.changed.notify_waiters();
+    }
 }
 
 #[derive(Default)]
@@ -238,6 +474,16 @@ impl BodyOutgoing {
         }
     }
 
+    /// How many bytes of body we're holding on to, waiting to send them
+    pub(crate) fn buffered_len(&self) -> usize {
+        match self {
+            BodyOutgoing::StillReceiving(pieces) | BodyOutgoing::DoneReceiving(pieces) => {
+                pieces.iter().map(|p| p.len()).sum()
+            }
+            BodyOutgoing::DoneSending => 0,
+        }
+    }
+
     #[inline(always)]
     pub(crate) fn pop_front(&mut self) -> Option<Piece> {
         match self {
@@ -366,6 +612,13 @@ pub enum H2ConnectionError {
     #[error("hpack decoding error: {0:?}")]
     HpackDecodingError(#[from] DecoderError),
 
+    #[error("on stream {stream_id}, header block of at least {size} bytes exceeds the max header section size of {max_size}")]
+    HeaderBlockTooLarge {
+        stream_id: StreamId,
+        size: usize,
+        max_size: usize,
+    },
+
     #[error("client sent a push promise frame, clients aren't allowed to do that, cf. RFC9113 section 8.4")]
     ClientSentPushPromise,
 
@@ -405,6 +658,15 @@ pub enum H2ConnectionError {
     #[error("received goaway frame with non-zero stream id")]
     GoAwayWithNonZeroStreamId { stream_id: StreamId },
 
+    #[error("received priority update frame with non-zero stream id")]
+    PriorityUpdateWithNonZeroStreamId { stream_id: StreamId },
+
+    #[error("received priority update frame with invalid length {len}")]
+    PriorityUpdateInvalidLength { len: u32 },
+
+    #[error("received priority update for stream {prioritized_stream_id}, which can't be one of the client's requests")]
+    PriorityUpdateInvalidStreamId { prioritized_stream_id: StreamId },
+
     #[error("zero increment in window update frame for stream")]
     WindowUpdateZeroIncrement,
 
@@ -422,40 +684,57 @@ pub enum H2ConnectionError {
 
     #[error("bad setting value: {0}")]
     BadSettingValue(SettingsError),
+
+    #[error("server is low on memory, not accepting new connections")]
+    MemoryPressure,
+
+    #[error("server is running out of file descriptors, closing idle connection")]
+    PrunedWhileIdle,
 }
 
 impl H2ConnectionError {
     pub(crate) fn as_known_error_code(&self) -> KnownErrorCode {
+        self.kind().as_known_error_code()
+    }
+
+    /// Where this error fits in the [ConnectionError] taxonomy
+    pub fn kind(&self) -> ConnectionError {
         match self {
-            // frame size errors
-            H2ConnectionError::FrameTooLarge { .. } => KnownErrorCode::FrameSizeError,
-            H2ConnectionError::PaddedFrameEmpty { .. } => KnownErrorCode::FrameSizeError,
-            H2ConnectionError::PingFrameInvalidLength { .. } => KnownErrorCode::FrameSizeError,
-            H2ConnectionError::SettingsInvalidLength { .. } => KnownErrorCode::FrameSizeError,
-            H2ConnectionError::WindowUpdateInvalidLength { .. } => KnownErrorCode::FrameSizeError,
-            // flow control errors
-            H2ConnectionError::WindowUpdateOverflow => KnownErrorCode::FlowControlError,
-            H2ConnectionError::WindowUnderflow { .. } => KnownErrorCode::FlowControlError,
-            H2ConnectionError::StreamWindowSizeOverflowDueToSettings { .. } => {
-                KnownErrorCode::FlowControlError
-            }
-            H2ConnectionError::BadSettingValue(SettingsError::InitialWindowSizeTooLarge {
+            H2ConnectionError::FrameTooLarge { .. }
+            | H2ConnectionError::PaddedFrameEmpty { .. }
+            | H2ConnectionError::PingFrameInvalidLength { .. }
+            | H2ConnectionError::SettingsInvalidLength { .. }
+            | H2ConnectionError::WindowUpdateInvalidLength { .. }
+            | H2ConnectionError::PriorityUpdateInvalidLength { .. } => ConnectionError::FrameSize,
+            H2ConnectionError::WindowUpdateOverflow
+            | H2ConnectionError::WindowUnderflow { .. }
+            | H2ConnectionError::StreamWindowSizeOverflowDueToSettings { .. }
+            | H2ConnectionError::BadSettingValue(SettingsError::InitialWindowSizeTooLarge {
                 ..
-            }) => KnownErrorCode::FlowControlError,
-            // compression errors
-            H2ConnectionError::HpackDecodingError(_) => KnownErrorCode::CompressionError,
-            // stream closed error
-            H2ConnectionError::StreamClosed { .. } => KnownErrorCode::StreamClosed,
-            // protocol errors
-            H2ConnectionError::PaddedFrameTooShort { .. } => KnownErrorCode::ProtocolError,
-            H2ConnectionError::StreamSpecificFrameToConnection { .. } => {
-                KnownErrorCode::ProtocolError
-            }
-            _ => KnownErrorCode::ProtocolError,
+            }) => ConnectionError::FlowControl,
+            H2ConnectionError::HpackDecodingError(_) => ConnectionError::HeaderCompression,
+            H2ConnectionError::HeaderBlockTooLarge { .. } => ConnectionError::HeadersTooLarge,
+            H2ConnectionError::StreamClosed { .. } => ConnectionError::StreamClosed,
+            H2ConnectionError::IncompleteFrame { .. } => ConnectionError::MalformedFrame,
+            H2ConnectionError::ReadAndParse(e) => read_error_kind(e),
+            H2ConnectionError::WriteError(e) => ConnectionError::from_io(e),
+            H2ConnectionError::ResponderError(_) => ConnectionError::HandlerFailed,
+            H2ConnectionError::MemoryPressure => ConnectionError::MemoryPressure,
+            H2ConnectionError::PrunedWhileIdle => ConnectionError::PrunedWhileIdle,
+            _ => ConnectionError::ProtocolViolation,
         }
     }
 }
 
+/// For errors reading the preface or frames
+pub(crate) fn read_error_kind(e: &ReadAndParseError) -> ConnectionError {
+    match e {
+        ReadAndParseError::ReadError(e) => ConnectionError::from_io(e),
+        ReadAndParseError::Alloc(_) => ConnectionError::MemoryPressure,
+        _ => ConnectionError::MalformedFrame,
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 #[non_exhaustive]
 pub(crate) enum H2StreamError {
@@ -489,8 +768,17 @@ pub(crate) enum H2StreamError {
     #[error("bad request: {0}")]
     BadRequest(&'static str),
 
+    #[error("trailer section exceeds the configured size or field count limits")]
+    TrailersTooLarge,
+
     #[error("stream reset")]
     Cancel,
+
+    #[error("stream reset to free up memory")]
+    Shed,
+
+    #[error("handler failed after sending response headers")]
+    HandlerFailed,
 }
 
 impl H2StreamError {
@@ -509,6 +797,10 @@ impl H2StreamError {
             InvalidRstStreamFrameSize { .. } => Code::FrameSizeError,
             // flow control errors
             WindowUpdateOverflow => Code::FlowControlError,
+            // we're shedding load
+            Shed => Code::EnhanceYourCalm,
+            // not the peer's fault
+            HandlerFailed => Code::InternalError,
             _ => Code::ProtocolError,
         }
     }
@@ -527,17 +819,48 @@ pub(crate) struct H2Event {
 }
 
 pub(crate) enum H2EventPayload {
-    Headers(Response),
+    Headers(Response, Option<HeadersBudget>),
     BodyChunk(Piece),
     BodyEnd,
+    /// Like [H2EventPayload::BodyEnd], but the stream ends with these
+    Trailers(Box<Headers>),
+    /// The handler wants the body chunks it sent so far written out now,
+    /// rather than whenever we get around to it
+    Flush,
+    /// The handler read this many bytes of the request body, so we can
+    /// give them back to the peer with a WINDOW_UPDATE
+    RequestBodyConsumed(u32),
+    /// A request body was dropped before it was read in full: the unread
+    /// bytes are waiting in the connection's dropped body credit
+    RequestBodyDropped,
+    /// The handler went away in the middle of the response body, after
+    /// panicking or returning an error: the peer can't be told the body is
+    /// complete, so the stream gets reset
+    Reset,
+}
+
+/// Comes with response headers that might not fit in
+/// [super::ServerConf::max_response_header_size]: the connection only knows
+/// for sure once they're HPACK-encoded, and tells the encoder how it went.
+pub(crate) struct HeadersBudget {
+    pub(crate) max_len: usize,
+    /// `Err` has the length of the header block, which wasn't sent
+    pub(crate) reply: oneshot::Sender<Result<(), usize>>,
 }
 
 impl fmt::Debug for H2EventPayload {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Headers(_) => f.debug_tuple("Headers").finish(),
+            Self::Headers(..) => f.debug_tuple("Headers").finish(),
             Self::BodyChunk(_) => f.debug_tuple("BodyChunk").finish(),
             Self::BodyEnd => write!(f, "BodyEnd"),
+            Self::Trailers(trailers) => f.debug_tuple("Trailers").field(&trailers.len()).finish(),
+            Self::Flush => write!(f, "Flush"),
+            Self::RequestBodyConsumed(len) => {
+                f.debug_tuple("RequestBodyConsumed").field(len).finish()
+            }
+            Self::RequestBodyDropped => write!(f, "RequestBodyDropped"),
+            Self::Reset => write!(f, "Reset"),
         }
     }
 }
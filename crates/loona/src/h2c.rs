@@ -0,0 +1,390 @@
+//! Cleartext HTTP/2 on a port that also speaks HTTP/1.1. Clients either
+//! start with the HTTP/2 preface ("prior knowledge"), or send an HTTP/1.1
+//! request with `upgrade: h2c`, which gets a 101 and is answered over HTTP/2
+//! on stream 1, cf. <https://httpwg.org/specs/rfc7540.html#discover-http>
+//!
+//! Requests with a body are answered over HTTP/1.1 instead: the client
+//! would have to send all of it before switching, and we'd have to hold on
+//! to it for the driver.
+
+use std::rc::Rc;
+
+use buffet::{ReadOwned, RollMut, WriteOwned};
+use http::{
+    header,
+    uri::{Authority, Scheme},
+    HeaderName, Uri,
+};
+use loona_h2::{Settings, PREFACE};
+use tracing::debug;
+
+use crate::{
+    error::{ConnectionError, ServeError},
+    h1::{self, encode::H1Encoder, Served},
+    h2::{self, encode::H2Encoder},
+    metrics::{count_connection_error, MeteredRead},
+    ConnInfo, HeadersExt, Method, Request, ServeOutcome, ServerDriver,
+};
+
+static HTTP2_SETTINGS: HeaderName = HeaderName::from_static("http2-settings");
+
+pub async fn serve<OurDriver, OurReadOwned, OurWriteOwned, DriverError>(
+    transport: (OurReadOwned, OurWriteOwned),
+    h1_conf: Rc<h1::ServerConf>,
+    h2_conf: Rc<h2::ServerConf>,
+    client_buf: RollMut,
+    driver: Rc<OurDriver>,
+) -> Result<ServeOutcome, ServeError<DriverError>>
+where
+    OurDriver: ServerDriver<H1Encoder<OurWriteOwned>, Error = DriverError>
+        + ServerDriver<H2Encoder, Error = DriverError>
+        + 'static,
+    OurReadOwned: ReadOwned,
+    OurWriteOwned: WriteOwned,
+{
+    serve_with_conn_info(
+        transport,
+        h1_conf,
+        h2_conf,
+        client_buf,
+        driver,
+        Default::default(),
+    )
+    .await
+}
+
+/// Like [serve], but with what the caller knows about the connection (peer
+/// address, TLS details, etc.), which drivers get as [crate::Request::conn]
+pub async fn serve_with_conn_info<OurDriver, OurReadOwned, OurWriteOwned, DriverError>(
+    (transport_r, transport_w): (OurReadOwned, OurWriteOwned),
+    h1_conf: Rc<h1::ServerConf>,
+    h2_conf: Rc<h2::ServerConf>,
+    client_buf: RollMut,
+    driver: Rc<OurDriver>,
+    mut conn_info: ConnInfo,
+) -> Result<ServeOutcome, ServeError<DriverError>>
+where
+    OurDriver: ServerDriver<H1Encoder<OurWriteOwned>, Error = DriverError>
+        + ServerDriver<H2Encoder, Error = DriverError>
+        + 'static,
+    OurReadOwned: ReadOwned,
+    OurWriteOwned: WriteOwned,
+{
+    // the same whichever protocol the connection ends up speaking
+    conn_info.assign_id();
+
+    let mut metered_r = MeteredRead::new(transport_r, h1_conf.metrics.clone());
+    let (res, client_buf) = sniff_preface(client_buf, &mut metered_r).await;
+    let transport_r = metered_r.into_inner();
+    match res {
+        Ok(true) => {
+            debug!("client sent the HTTP/2 preface");
+            return h2::serve_conn(
+                (transport_r, transport_w),
+                h2_conf,
+                client_buf,
+                driver,
+                conn_info,
+                None,
+            )
+            .await;
+        }
+        Ok(false) => {}
+        Err(SniffError::Alloc(e)) => return Err(ServeError::Alloc(e)),
+        Err(SniffError::Read(e)) => {
+            debug!(?e, "error reading from downstream");
+            count_connection_error(&h1_conf.metrics, ConnectionError::from_io(&e));
+            return Ok(ServeOutcome::ClientDidntSpeakHttp11);
+        }
+    }
+
+    let served = h1::serve_offering_h2c(
+        (transport_r, transport_w),
+        h1_conf,
+        client_buf,
+        &*driver,
+        conn_info.clone(),
+    )
+    .await?;
+    match served {
+        Served::Done(outcome) => Ok(outcome),
+        Served::H2cUpgrade {
+            upgrade,
+            client_buf,
+            transport_r,
+            transport_w,
+        } => {
+            h2::serve_conn(
+                (transport_r, transport_w),
+                h2_conf,
+                client_buf,
+                driver,
+                conn_info,
+                Some(*upgrade),
+            )
+            .await
+        }
+    }
+}
+
+enum SniffError {
+    Alloc(buffet::bufpool::Error),
+    Read(std::io::Error),
+}
+
+/// Reads until `client_buf` holds the whole HTTP/2 preface, or something
+/// that isn't it. Returns whether it's the preface: either way, what was
+/// read stays in `client_buf`.
+async fn sniff_preface(
+    mut client_buf: RollMut,
+    transport_r: &mut impl ReadOwned,
+) -> (Result<bool, SniffError>, RollMut) {
+    loop {
+        let len = client_buf.len().min(PREFACE.len());
+        if client_buf[..len] != PREFACE[..len] {
+            return (Ok(false), client_buf);
+        }
+        if len == PREFACE.len() {
+            return (Ok(true), client_buf);
+        }
+
+        if client_buf.cap() == 0 {
+            if let Err(e) = client_buf.reserve() {
+                return (Err(SniffError::Alloc(e)), client_buf);
+            }
+        }
+        let res;
+        (res, client_buf) = client_buf.read_into(PREFACE.len() - len, transport_r).await;
+        match res {
+            // the HTTP/1.1 server tells the client went away
+            Ok(0) => return (Ok(false), client_buf),
+            Ok(_) => {}
+            Err(e) => return (Err(SniffError::Read(e)), client_buf),
+        }
+    }
+}
+
+/// A request the HTTP/1.1 server switched to HTTP/2 for, which the HTTP/2
+/// server answers on stream 1
+pub(crate) struct H2cUpgrade {
+    pub(crate) req: Request,
+
+    /// The client's `HTTP2-Settings`, decoded: a SETTINGS frame payload
+    pub(crate) settings: Vec<u8>,
+
+    /// The size of the request's header section, on the wire
+    pub(crate) wire_len: u64,
+}
+
+impl H2cUpgrade {
+    pub(crate) fn new(mut req: Request, settings: Vec<u8>, wire_len: u64) -> Self {
+        // those were about the HTTP/1.1 connection
+        for name in [&header::CONNECTION, &header::UPGRADE, &HTTP2_SETTINGS] {
+            req.headers.remove(name);
+        }
+
+        // like the other HTTP/2 requests, it gets a scheme and an authority
+        if req.uri.scheme().is_none() {
+            let mut parts = req.uri.clone().into_parts();
+            parts.scheme = Some(Scheme::HTTP);
+            parts.authority = req
+                .headers
+                .get(header::HOST)
+                .and_then(|host| Authority::try_from(&host[..]).ok());
+            if let Ok(uri) = Uri::from_parts(parts) {
+                req.uri = uri;
+            }
+        }
+
+        Self {
+            req,
+            settings,
+            wire_len,
+        }
+    }
+}
+
+/// The client's settings, if `req` asks to switch to HTTP/2 in a way we can
+/// go along with: no body, `h2c` in `upgrade`, `upgrade` and
+/// `http2-settings` in `connection`, and a single, valid `http2-settings`.
+pub(crate) fn upgrade_settings(req: &Request) -> Option<Vec<u8>> {
+    let headers = &req.headers;
+    let has_token = |name: &HeaderName, token: &[u8]| {
+        headers.get_all(name).iter().any(|value| {
+            value
+                .split(|&b| b == b',')
+                .any(|t| t.trim_ascii().eq_ignore_ascii_case(token))
+        })
+    };
+    if !has_token(&header::UPGRADE, b"h2c")
+        || !has_token(&header::CONNECTION, b"upgrade")
+        || !has_token(&header::CONNECTION, b"http2-settings")
+    {
+        return None;
+    }
+    if req.method == Method::Connect
+        || headers.contains_key(header::TRANSFER_ENCODING)
+        || headers.content_length().unwrap_or_default() > 0
+    {
+        debug!("not switching to HTTP/2 for a request with a body");
+        return None;
+    }
+
+    let mut values = headers.get_all(&HTTP2_SETTINGS).iter();
+    let (Some(value), None) = (values.next(), values.next()) else {
+        return None;
+    };
+    let settings = decode_base64url(value)?;
+    // the 101 acknowledges them, so they'd better be valid
+    let mut check = Settings::default();
+    if settings.len() % 6 != 0
+        || Settings::parse(&settings, |code, value| check.apply(code, value)).is_err()
+    {
+        debug!("not switching to HTTP/2, invalid http2-settings");
+        return None;
+    }
+    Some(settings)
+}
+
+/// Decodes base64url without padding (tolerating it anyway), cf.
+/// <https://www.rfc-editor.org/rfc/rfc4648#section-5>
+fn decode_base64url(src: &[u8]) -> Option<Vec<u8>> {
+    let end = src.iter().rposition(|&b| b != b'=').map_or(0, |i| i + 1);
+    let mut out = Vec::with_capacity(end * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for &c in &src[..end] {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | sextet as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    // six bits left over can't be the start of a byte
+    (bits < 6).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header;
+
+    use super::{decode_base64url, upgrade_settings};
+    use crate::{Headers, Method, Request};
+
+    #[test]
+    fn test_decode_base64url() {
+        assert_eq!(decode_base64url(b"").unwrap(), b"");
+        assert_eq!(decode_base64url(b"aGk").unwrap(), b"hi");
+        assert_eq!(decode_base64url(b"aGk=").unwrap(), b"hi");
+        assert_eq!(decode_base64url(b"aGVsbG8").unwrap(), b"hello");
+        assert_eq!(decode_base64url(b"-_8").unwrap(), [0xfb, 0xff]);
+        assert_eq!(decode_base64url(b"AAMAAABkAAQAAP__").unwrap().len(), 12);
+        assert!(decode_base64url(b"aGVsbG8h_").is_none());
+        assert!(decode_base64url(b"aG+k").is_none());
+        assert!(decode_base64url(b"aG k").is_none());
+    }
+
+    #[test]
+    fn test_upgrade_settings() {
+        let settings = |method: Method, fields: &[(header::HeaderName, &'static str)]| {
+            let mut headers = Headers::default();
+            for (name, value) in fields {
+                headers.append(name, (*value).into());
+            }
+            let req = Request {
+                method,
+                headers,
+                ..Default::default()
+            };
+            upgrade_settings(&req)
+        };
+        let upgrade = || (header::UPGRADE, "h2c");
+        let connection = || (header::CONNECTION, "Upgrade, HTTP2-Settings");
+        let http2_settings = |value| (header::HeaderName::from_static("http2-settings"), value);
+
+        // SETTINGS_MAX_CONCURRENT_STREAMS = 100
+        assert_eq!(
+            settings(
+                Method::Get,
+                &[upgrade(), connection(), http2_settings("AAMAAABk")]
+            )
+            .unwrap(),
+            [0, 3, 0, 0, 0, 100]
+        );
+        assert_eq!(
+            settings(
+                Method::Get,
+                &[
+                    (header::UPGRADE, "websocket, H2C"),
+                    connection(),
+                    http2_settings("")
+                ]
+            )
+            .unwrap(),
+            []
+        );
+
+        assert!(settings(Method::Get, &[connection(), http2_settings("")]).is_none());
+        assert!(settings(
+            Method::Get,
+            &[
+                upgrade(),
+                (header::CONNECTION, "upgrade"),
+                http2_settings("")
+            ]
+        )
+        .is_none());
+        assert!(settings(Method::Get, &[upgrade(), connection()]).is_none());
+        assert!(settings(
+            Method::Get,
+            &[
+                upgrade(),
+                connection(),
+                http2_settings(""),
+                http2_settings("")
+            ]
+        )
+        .is_none());
+        // not a multiple of six bytes
+        assert!(settings(
+            Method::Get,
+            &[upgrade(), connection(), http2_settings("AAMA")]
+        )
+        .is_none());
+        // SETTINGS_ENABLE_PUSH = 2
+        assert!(settings(
+            Method::Get,
+            &[upgrade(), connection(), http2_settings("AAIAAAAC")]
+        )
+        .is_none());
+        assert!(settings(
+            Method::Post,
+            &[
+                upgrade(),
+                connection(),
+                http2_settings(""),
+                (header::CONTENT_LENGTH, "5")
+            ]
+        )
+        .is_none());
+        assert!(settings(
+            Method::Post,
+            &[
+                upgrade(),
+                connection(),
+                http2_settings(""),
+                (header::CONTENT_LENGTH, "0")
+            ]
+        )
+        .is_some());
+    }
+}
@@ -0,0 +1,396 @@
+//! Adapters between loona's [Body] and [http_body::Body], the body trait
+//! hyper, axum and friends use, so bodies can go from one to the other
+//! without being buffered. Only available with the `http-body` feature.
+//!
+//! Data is copied once on the way through: [Piece]s aren't `Send`, and
+//! [Bytes] must be.
+
+use std::{
+    fmt,
+    fs::File,
+    future::{poll_fn, Future},
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+use b_x::BX;
+use buffet::{read_file_piece, Piece};
+use bytes::{Buf, Bytes};
+use http::{header::InvalidHeaderValue, HeaderMap, HeaderName, HeaderValue};
+use http_body::{Frame, SizeHint};
+
+use crate::{Body, BodyChunk, Headers, FORBIDDEN_TRAILERS};
+
+/// A loona [Body] that reads from an [http_body::Body]. Trailers that aren't
+/// allowed in trailers (cf. [FORBIDDEN_TRAILERS]) are dropped.
+pub struct FromHttpBody<B> {
+    inner: Pin<Box<B>>,
+    content_len: Option<u64>,
+    done: bool,
+    trailers: Vec<HeaderName>,
+}
+
+impl<B: http_body::Body> FromHttpBody<B> {
+    pub fn new(inner: B) -> Self {
+        // what's left to read, which is everything, for now
+        let content_len = inner.size_hint().exact();
+        Self {
+            inner: Box::pin(inner),
+            content_len,
+            done: false,
+            trailers: Vec::new(),
+        }
+    }
+
+    /// Lets the response announce the trailers `inner` ends with, cf.
+    /// [Body::announced_trailers]. Without it, they only reach clients that
+    /// take unannounced trailers, cf. [crate::Responder::accepts_trailers].
+    pub fn announce_trailers(&mut self, names: impl IntoIterator<Item = HeaderName>) {
+        self.trailers.extend(names);
+    }
+}
+
+impl<B> fmt::Debug for FromHttpBody<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FromHttpBody")
+            .field("content_len", &self.content_len)
+            .field("done", &self.done)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B> Body for FromHttpBody<B>
+where
+    B: http_body::Body,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Error = BX;
+
+    fn content_len(&self) -> Option<u64> {
+        self.content_len
+    }
+
+    fn eof(&self) -> bool {
+        self.done || self.inner.is_end_stream()
+    }
+
+    fn announced_trailers(&self) -> &[HeaderName] {
+        &self.trailers
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        loop {
+            if self.done {
+                return Ok(BodyChunk::Done { trailers: None });
+            }
+
+            let frame = match poll_fn(|cx| self.inner.as_mut().poll_frame(cx)).await {
+                Some(frame) => frame.map_err(|e| {
+                    let e: Box<dyn std::error::Error + Send + Sync> = e.into();
+                    BX::from_boxed(e)
+                })?,
+                None => {
+                    self.done = true;
+                    continue;
+                }
+            };
+            let frame = match frame.into_data() {
+                Ok(mut data) => {
+                    let len = data.remaining();
+                    if len == 0 {
+                        continue;
+                    }
+                    let data = data.copy_to_bytes(len);
+                    return Ok(BodyChunk::Chunk(Piece::from(data.to_vec())));
+                }
+                Err(frame) => frame,
+            };
+            if let Ok(trailers) = frame.into_trailers() {
+                self.done = true;
+                let mut headers = Headers::default();
+                for (name, value) in &trailers {
+                    if !FORBIDDEN_TRAILERS.contains(name) {
+                        headers.append(name.clone(), Piece::from(value.as_bytes().to_vec()));
+                    }
+                }
+                return Ok(BodyChunk::Done {
+                    trailers: Some(Box::new(headers)),
+                });
+            }
+            // some other kind of frame we don't know about: skip it
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum IntoHttpBodyError {
+    /// The loona body errored out. Only its message is kept, since
+    /// `http_body` users want errors that are `Send`.
+    #[error("body error: {0}")]
+    Body(String),
+
+    /// Reading a [BodyChunk::File] failed
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid trailer value: {0}")]
+    InvalidTrailer(#[from] InvalidHeaderValue),
+}
+
+type NextChunk<B> = Pin<Box<dyn Future<Output = (B, Result<BodyChunk, <B as Body>::Error>)>>>;
+
+enum State<B: Body> {
+    Idle(B),
+    Reading(NextChunk<B>),
+    File {
+        body: B,
+        file: Rc<File>,
+        offset: u64,
+        len: u64,
+    },
+    Done,
+}
+
+/// An [http_body::Body] that reads from a loona [Body]. File chunks are read
+/// 64KiB at a time.
+pub struct IntoHttpBody<B: Body> {
+    state: State<B>,
+    content_len: Option<u64>,
+}
+
+impl<B: Body + 'static> IntoHttpBody<B> {
+    pub fn new(body: B) -> Self {
+        let content_len = body.content_len();
+        Self {
+            state: State::Idle(body),
+            content_len,
+        }
+    }
+}
+
+impl<B: Body> fmt::Debug for IntoHttpBody<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IntoHttpBody")
+            .field("content_len", &self.content_len)
+            .field("done", &matches!(self.state, State::Done))
+            .finish_non_exhaustive()
+    }
+}
+
+// nothing is pinned in place: the read future is boxed
+impl<B: Body> Unpin for IntoHttpBody<B> {}
+
+impl<B: Body + 'static> http_body::Body for IntoHttpBody<B> {
+    type Data = Bytes;
+    type Error = IntoHttpBodyError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, IntoHttpBodyError>>> {
+        loop {
+            match std::mem::replace(&mut self.state, State::Done) {
+                State::Idle(mut body) => {
+                    self.state = State::Reading(Box::pin(async move {
+                        let res = body.next_chunk().await;
+                        (body, res)
+                    }));
+                }
+                State::Reading(mut fut) => {
+                    let (body, res) = match fut.as_mut().poll(cx) {
+                        Poll::Ready(res) => res,
+                        Poll::Pending => {
+                            self.state = State::Reading(fut);
+                            return Poll::Pending;
+                        }
+                    };
+                    match res {
+                        Ok(BodyChunk::Chunk(piece)) => {
+                            self.state = State::Idle(body);
+                            if !piece.is_empty() {
+                                let data = Bytes::copy_from_slice(&piece[..]);
+                                return Poll::Ready(Some(Ok(Frame::data(data))));
+                            }
+                        }
+                        Ok(BodyChunk::File { file, offset, len }) => {
+                            self.state = State::File {
+                                body,
+                                file,
+                                offset,
+                                len,
+                            };
+                        }
+                        Ok(BodyChunk::Done { trailers }) => {
+                            let Some(trailers) = trailers else {
+                                return Poll::Ready(None);
+                            };
+                            let mut map = HeaderMap::with_capacity(trailers.len());
+                            for (name, value) in trailers.iter() {
+                                match HeaderValue::from_bytes(&value[..]) {
+                                    Ok(value) => _ = map.append(name.clone(), value),
+                                    Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                                }
+                            }
+                            return Poll::Ready(Some(Ok(Frame::trailers(map))));
+                        }
+                        Err(e) => {
+                            let e = IntoHttpBodyError::Body(e.to_string());
+                            return Poll::Ready(Some(Err(e)));
+                        }
+                    }
+                }
+                State::File {
+                    body,
+                    file,
+                    offset,
+                    len,
+                } => {
+                    if len == 0 {
+                        self.state = State::Idle(body);
+                        continue;
+                    }
+                    let piece = match read_file_piece(&file, offset, len) {
+                        Ok(piece) => piece,
+                        Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                    };
+                    let read = piece.len() as u64;
+                    self.state = State::File {
+                        body,
+                        file,
+                        offset: offset + read,
+                        len: len - read,
+                    };
+                    let data = Bytes::copy_from_slice(&piece[..]);
+                    return Poll::Ready(Some(Ok(Frame::data(data))));
+                }
+                State::Done => return Poll::Ready(None),
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        // an idle body may still have trailers to give, so don't ask `eof()`
+        matches!(self.state, State::Done)
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.content_len {
+            Some(len) if matches!(self.state, State::Idle(_)) => SizeHint::with_exact(len),
+            _ => SizeHint::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::poll_fn, pin::Pin};
+
+    use http_body::Body as _;
+
+    use super::{FromHttpBody, IntoHttpBody};
+    use crate::{testkit::anonymous_file, Body, BodyChunk, FileBody, Headers};
+
+    /// Some data, then some trailers, one of which isn't allowed
+    #[derive(Debug)]
+    struct WithTrailers {
+        data: Option<&'static str>,
+        done: bool,
+    }
+
+    impl Body for WithTrailers {
+        type Error = crate::error::NeverError;
+
+        fn content_len(&self) -> Option<u64> {
+            None
+        }
+
+        fn eof(&self) -> bool {
+            self.done
+        }
+
+        async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+            if let Some(data) = self.data.take() {
+                return Ok(BodyChunk::Chunk(data.into()));
+            }
+            if self.done {
+                return Ok(BodyChunk::Done { trailers: None });
+            }
+            self.done = true;
+            let mut trailers = Headers::default();
+            trailers.insert("x-checksum", "abc".into());
+            trailers.insert(http::header::CONTENT_LENGTH, "5".into());
+            Ok(BodyChunk::Done {
+                trailers: Some(Box::new(trailers)),
+            })
+        }
+    }
+
+    mod round_trip_conformance {
+        use super::*;
+
+        crate::body_test_suite!(|| {
+            let mut body = FromHttpBody::new(IntoHttpBody::new(WithTrailers {
+                data: Some("hello"),
+                done: false,
+            }));
+            body.announce_trailers([http::HeaderName::from_static("x-checksum")]);
+            body
+        });
+    }
+
+    mod round_trip_file_conformance {
+        use super::*;
+
+        crate::body_test_suite!(|| {
+            let contents = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let body = FileBody::new(anonymous_file(&contents)).unwrap();
+            FromHttpBody::new(IntoHttpBody::new(body.with_range(10..150_010).unwrap()))
+        });
+    }
+
+    #[test]
+    fn test_into_http_body() {
+        buffet::start(async move {
+            let contents = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let body = FileBody::new(anonymous_file(&contents)).unwrap();
+
+            let mut body = IntoHttpBody::new(body);
+            assert_eq!(body.size_hint().exact(), Some(100_000));
+
+            let mut read = Vec::new();
+            let mut frames = 0;
+            while let Some(frame) = poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+                read.extend_from_slice(&frame.unwrap().into_data().unwrap());
+                frames += 1;
+            }
+            assert_eq!(read, contents);
+            // no more than 64KiB at a time
+            assert_eq!(frames, 2);
+            assert!(body.is_end_stream());
+        });
+    }
+
+    #[test]
+    fn test_trailers_filtered() {
+        buffet::start(async move {
+            let mut body = FromHttpBody::new(IntoHttpBody::new(WithTrailers {
+                data: Some("hello"),
+                done: false,
+            }));
+            assert!(matches!(
+                body.next_chunk().await.unwrap(),
+                BodyChunk::Chunk(chunk) if &chunk[..] == b"hello"
+            ));
+            let BodyChunk::Done {
+                trailers: Some(trailers),
+            } = body.next_chunk().await.unwrap()
+            else {
+                panic!("expected trailers");
+            };
+            assert_eq!(&trailers["x-checksum"][..], b"abc");
+            assert!(!trailers.contains_key(http::header::CONTENT_LENGTH));
+        });
+    }
+}
@@ -5,6 +5,7 @@ pub use types::*;
 
 pub mod h1;
 pub mod h2;
+pub mod h2c;
 
 mod responder;
 pub use responder::*;
@@ -16,6 +17,61 @@ pub use http;
 
 pub mod error;
 
+pub mod range;
+
+pub mod urlencoded;
+
+pub mod multipart;
+
+pub mod body_adapters;
+
+pub mod conditional;
+
+pub mod fs;
+
+pub mod proxy;
+
+pub mod proxy_protocol;
+
+pub mod forwarded;
+
+pub mod connect_udp;
+
+pub mod sni;
+
+pub mod sse;
+
+pub mod stream;
+
+pub mod analyze;
+
+pub mod accesslog;
+
+pub mod transform;
+
+pub mod rate_limit;
+
+pub mod metrics;
+
+pub mod pressure;
+
+pub mod fd_budget;
+
+pub mod conn_limit;
+
+pub mod protocol_errors;
+
+#[cfg(feature = "test-util")]
+pub mod chaos;
+
+#[cfg(feature = "http-body")]
+pub mod http_body_compat;
+
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+
+pub mod testkit;
+
 #[allow(async_fn_in_trait)] // we never require Send
 pub trait ServerDriver<OurEncoder>
 where
@@ -29,4 +85,32 @@ where
         req_body: &mut impl Body,
         respond: Responder<OurEncoder, ExpectResponseHeaders>,
     ) -> Result<Responder<OurEncoder, ResponseDone>, Self::Error>;
+
+    /// Origins this server is authoritative for, serialized like
+    /// `https://example.org`: HTTP/2 clients are sent them in an ORIGIN frame
+    /// (RFC 8336) right after the server's settings. `None` sends no ORIGIN
+    /// frame.
+    fn origins(&self) -> Option<Vec<String>> {
+        None
+    }
+}
+
+/// Creates a [ServerDriver] for each connection, so per-connection state
+/// (caches, rate limiters, request counters) can live in the driver itself.
+/// Closures that take a [ConnInfo] are factories.
+pub trait ServerDriverFactory {
+    type Driver;
+
+    fn new_driver(&self, conn: &ConnInfo) -> Self::Driver;
+}
+
+impl<F, D> ServerDriverFactory for F
+where
+    F: Fn(&ConnInfo) -> D,
+{
+    type Driver = D;
+
+    fn new_driver(&self, conn: &ConnInfo) -> D {
+        self(conn)
+    }
 }
@@ -0,0 +1,389 @@
+//! Counters, gauges and histograms about what the server is doing, handed to
+//! a [MetricsSink] so they can end up in prometheus, metrics-rs, or anything
+//! else.
+//!
+//! Set `metrics` in [crate::h1::ServerConf] or [crate::h2::ServerConf] to
+//! turn them on. Gauges are reported as deltas: each connection adds what it
+//! contributes and takes it back out when it goes away, so a sink only needs
+//! to sum them up, no matter how many connections share it.
+
+use std::{fmt, rc::Rc};
+
+use buffet::{
+    bufpool::{BufResult, IoBufMut},
+    Piece, PieceList, ReadOwned, WriteOwned,
+};
+
+use crate::{conn_limit::ConnRejection, error::ConnectionError};
+
+/// Things that only go up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Counter {
+    /// Bytes read from clients
+    BytesIn,
+
+    /// Bytes written to clients
+    BytesOut,
+
+    /// Connections that failed, or HTTP/1.1 requests that were turned away,
+    /// by [ConnectionError::name], for labels
+    ConnectionErrors(ConnectionError),
+
+    /// Connections a [crate::conn_limit::ConnLimiter] turned away, by [ConnRejection::name],
+    /// for labels
+    RejectedConnections(ConnRejection),
+}
+
+impl Counter {
+    /// A prometheus-style name for this counter
+    pub fn name(&self) -> &'static str {
+        match self {
+            Counter::BytesIn => "loona_bytes_in_total",
+            Counter::BytesOut => "loona_bytes_out_total",
+            Counter::ConnectionErrors(_) => "loona_connection_errors_total",
+            Counter::RejectedConnections(_) => "loona_rejected_connections_total",
+        }
+    }
+}
+
+/// Things that go up and down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Gauge {
+    /// Connections being served, HTTP/1.1 and HTTP/2
+    ActiveConnections,
+
+    /// HTTP/2 streams that aren't closed yet
+    OpenH2Streams,
+
+    /// Size of the HPACK dynamic tables we decode requests with, in octets
+    /// as defined by RFC 7541 section 4.1
+    HpackDecoderTableSize,
+
+    /// Size of the HPACK dynamic tables we encode responses with, in octets
+    HpackEncoderTableSize,
+}
+
+impl Gauge {
+    const COUNT: usize = 4;
+
+    /// A prometheus-style name for this gauge
+    pub fn name(&self) -> &'static str {
+        match self {
+            Gauge::ActiveConnections => "loona_active_connections",
+            Gauge::OpenH2Streams => "loona_open_h2_streams",
+            Gauge::HpackDecoderTableSize => "loona_hpack_decoder_table_size_bytes",
+            Gauge::HpackEncoderTableSize => "loona_hpack_encoder_table_size_bytes",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Distributions of observed values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Histogram {
+    /// Time spent in the driver's `handle`, in seconds
+    RequestDuration,
+}
+
+impl Histogram {
+    /// A prometheus-style name for this histogram
+    pub fn name(&self) -> &'static str {
+        match self {
+            Histogram::RequestDuration => "loona_request_duration_seconds",
+        }
+    }
+}
+
+/// Receives metrics as they happen. Calls happen on the hot path, so they
+/// should be cheap: bump an atomic, not take a lock.
+pub trait MetricsSink {
+    /// `counter` went up by `n`
+    fn counter(&self, counter: Counter, n: u64);
+
+    /// `gauge` went up (or down) by `delta`
+    fn gauge(&self, gauge: Gauge, delta: i64);
+
+    /// `value` was observed for `histogram`
+    fn observe(&self, histogram: Histogram, value: f64);
+}
+
+impl<S> MetricsSink for Rc<S>
+where
+    S: MetricsSink + ?Sized,
+{
+    fn counter(&self, counter: Counter, n: u64) {
+        (**self).counter(counter, n)
+    }
+
+    fn gauge(&self, gauge: Gauge, delta: i64) {
+        (**self).gauge(gauge, delta)
+    }
+
+    fn observe(&self, histogram: Histogram, value: f64) {
+        (**self).observe(histogram, value)
+    }
+}
+
+/// Counts `error` under [Counter::ConnectionErrors], if there's a sink
+pub(crate) fn count_connection_error(sink: &Option<Rc<dyn MetricsSink>>, error: ConnectionError) {
+    if let Some(sink) = sink {
+        sink.counter(Counter::ConnectionErrors(error), 1);
+    }
+}
+
+/// The gauges a single connection contributes to: reports changes as
+/// deltas, and takes everything back out when dropped.
+pub(crate) struct ConnGauges {
+    sink: Rc<dyn MetricsSink>,
+    values: [i64; Gauge::COUNT],
+}
+
+impl ConnGauges {
+    /// Counts one more active connection, until this is dropped
+    pub(crate) fn new(sink: Rc<dyn MetricsSink>) -> Self {
+        let mut gauges = Self {
+            sink,
+            values: [0; Gauge::COUNT],
+        };
+        gauges.set(Gauge::ActiveConnections, 1);
+        gauges
+    }
+
+    /// Sets this connection's share of `gauge` to `value`
+    pub(crate) fn set(&mut self, gauge: Gauge, value: i64) {
+        let last = &mut self.values[gauge.index()];
+        if *last != value {
+            self.sink.gauge(gauge, value - *last);
+            *last = value;
+        }
+    }
+}
+
+impl Drop for ConnGauges {
+    fn drop(&mut self) {
+        for gauge in [
+            Gauge::ActiveConnections,
+            Gauge::OpenH2Streams,
+            Gauge::HpackDecoderTableSize,
+            Gauge::HpackEncoderTableSize,
+        ] {
+            self.set(gauge, 0);
+        }
+    }
+}
+
+/// Counts bytes read from the client, and reports them as
+/// [Counter::BytesIn]
+pub(crate) struct MeteredRead<R> {
+    inner: R,
+    sink: Option<Rc<dyn MetricsSink>>,
+    total: u64,
+}
+
+impl<R> MeteredRead<R> {
+    pub(crate) fn new(inner: R, sink: Option<Rc<dyn MetricsSink>>) -> Self {
+        Self {
+            inner,
+            sink,
+            total: 0,
+        }
+    }
+
+    /// How many bytes were read so far
+    pub(crate) fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Counts `n` bytes read before we got the transport (and already
+    /// reported), so offsets worked out from [Self::total] line up with the
+    /// buffer they're in.
+    pub(crate) fn count_buffered(&mut self, n: u64) {
+        self.total += n;
+    }
+
+    pub(crate) fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn count(&mut self, n: usize) {
+        self.total += n as u64;
+        if let Some(sink) = &self.sink {
+            sink.counter(Counter::BytesIn, n as u64);
+        }
+    }
+}
+
+impl<R> ReadOwned for MeteredRead<R>
+where
+    R: ReadOwned,
+{
+    async fn read_owned<B: IoBufMut>(&mut self, buf: B) -> BufResult<usize, B> {
+        let (res, buf) = self.inner.read_owned(buf).await;
+        if let Ok(n) = &res {
+            self.count(*n);
+        }
+        (res, buf)
+    }
+
+    async fn readv_owned<B: IoBufMut>(&mut self, bufs: Vec<B>) -> BufResult<usize, Vec<B>> {
+        let (res, bufs) = self.inner.readv_owned(bufs).await;
+        if let Ok(n) = &res {
+            self.count(*n);
+        }
+        (res, bufs)
+    }
+}
+
+/// Counts bytes written to the client, and reports them as
+/// [Counter::BytesOut]
+pub(crate) struct MeteredWrite<W> {
+    inner: W,
+    sink: Option<Rc<dyn MetricsSink>>,
+    total: u64,
+}
+
+impl<W> MeteredWrite<W> {
+    pub(crate) fn new(inner: W, sink: Option<Rc<dyn MetricsSink>>) -> Self {
+        Self {
+            inner,
+            sink,
+            total: 0,
+        }
+    }
+
+    /// How many bytes were written so far
+    pub(crate) fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub(crate) fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn count(&mut self, n: u64) {
+        self.total += n;
+        if let Some(sink) = &self.sink {
+            sink.counter(Counter::BytesOut, n);
+        }
+    }
+}
+
+impl<W> fmt::Debug for MeteredWrite<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MeteredWrite")
+            .field("metered", &self.sink.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W> WriteOwned for MeteredWrite<W>
+where
+    W: WriteOwned,
+{
+    async fn write_owned(&mut self, buf: impl Into<Piece>) -> BufResult<usize, Piece> {
+        let (res, buf) = self.inner.write_owned(buf).await;
+        if let Ok(n) = &res {
+            self.count(*n as u64);
+        }
+        (res, buf)
+    }
+
+    async fn writev_owned(&mut self, list: &PieceList) -> std::io::Result<usize> {
+        let n = self.inner.writev_owned(list).await?;
+        self.count(n as u64);
+        Ok(n)
+    }
+
+    async fn write_file_all(
+        &mut self,
+        file: &std::fs::File,
+        offset: u64,
+        len: u64,
+    ) -> std::io::Result<()> {
+        self.inner.write_file_all(file, offset, len).await?;
+        self.count(len);
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> std::io::Result<()> {
+        self.inner.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+    use buffet::{PieceList, WriteOwned};
+
+    use super::{ConnGauges, Counter, Gauge, Histogram, MeteredWrite, MetricsSink};
+
+    #[derive(Default)]
+    struct Recorder {
+        counters: RefCell<HashMap<Counter, u64>>,
+        gauges: RefCell<HashMap<Gauge, i64>>,
+    }
+
+    impl MetricsSink for Recorder {
+        fn counter(&self, counter: Counter, n: u64) {
+            *self.counters.borrow_mut().entry(counter).or_default() += n;
+        }
+
+        fn gauge(&self, gauge: Gauge, delta: i64) {
+            *self.gauges.borrow_mut().entry(gauge).or_default() += delta;
+        }
+
+        fn observe(&self, _histogram: Histogram, _value: f64) {}
+    }
+
+    #[test]
+    fn test_conn_gauges() {
+        let recorder = Rc::new(Recorder::default());
+        let gauge = |g| {
+            recorder
+                .gauges
+                .borrow()
+                .get(&g)
+                .copied()
+                .unwrap_or_default()
+        };
+
+        let mut a = ConnGauges::new(recorder.clone());
+        let mut b = ConnGauges::new(recorder.clone());
+        assert_eq!(gauge(Gauge::ActiveConnections), 2);
+
+        a.set(Gauge::OpenH2Streams, 3);
+        b.set(Gauge::OpenH2Streams, 2);
+        a.set(Gauge::OpenH2Streams, 1);
+        assert_eq!(gauge(Gauge::OpenH2Streams), 3);
+
+        drop(a);
+        assert_eq!(gauge(Gauge::ActiveConnections), 1);
+        assert_eq!(gauge(Gauge::OpenH2Streams), 2);
+
+        drop(b);
+        assert_eq!(gauge(Gauge::ActiveConnections), 0);
+        assert_eq!(gauge(Gauge::OpenH2Streams), 0);
+    }
+
+    #[test]
+    fn test_metered_write() {
+        buffet::start(async move {
+            let recorder = Rc::new(Recorder::default());
+            let mut w = MeteredWrite::new(Vec::<u8>::new(), Some(recorder.clone()));
+            w.write_all_owned("hello ").await.unwrap();
+            w.writev_all_owned(PieceList::single("wor").followed_by("ld"))
+                .await
+                .unwrap();
+
+            assert_eq!(&w.into_inner()[..], b"hello world");
+            assert_eq!(recorder.counters.borrow()[&Counter::BytesOut], 11);
+        });
+    }
+}
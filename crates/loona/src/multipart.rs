@@ -0,0 +1,683 @@
+//! `multipart/form-data` request bodies, cf.
+//! <https://www.rfc-editor.org/rfc/rfc7578> and
+//! <https://www.rfc-editor.org/rfc/rfc2046#section-5.1>
+//!
+//! [Multipart] wraps a request [Body] and yields its parts one by one. Part
+//! bodies are streamed: their chunks are slices of what the request body
+//! yields, only the few bytes that might start a boundary are held back.
+//!
+//! ```ignore
+//! let mut multipart = Multipart::from_request(&req, req_body, MultipartConf::default())?;
+//! while let Some(mut part) = multipart.next_part().await? {
+//!     if part.filename().is_some() {
+//!         while let Some(chunk) = part.chunk().await? {
+//!             file.write_all(&chunk[..])?;
+//!         }
+//!     }
+//! }
+//! ```
+
+use std::{fs::File, rc::Rc};
+
+use http::{header, HeaderName};
+use memchr::memmem;
+
+use buffet::Piece;
+
+use crate::{Body, BodyChunk, Headers, Request};
+
+/// Limits for [Multipart]. A part that goes over them fails the whole body.
+#[derive(Debug, Clone)]
+pub struct MultipartConf {
+    /// How large each part's body may be
+    pub max_part_size: u64,
+
+    /// How large the whole request body may be, boundaries and headers
+    /// included
+    pub max_total_size: u64,
+
+    /// How large each part's header section may be
+    pub max_headers_size: usize,
+
+    /// How many parts the body may have
+    pub max_parts: usize,
+}
+
+impl Default for MultipartConf {
+    fn default() -> Self {
+        Self {
+            max_part_size: 16 * 1024 * 1024,
+            max_total_size: 64 * 1024 * 1024,
+            max_headers_size: 8 * 1024,
+            max_parts: 256,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum MultipartError<BodyError> {
+    #[error("body error: {0}")]
+    Body(BodyError),
+
+    /// Reading a [BodyChunk::File] failed
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The request's `content-type` isn't `multipart/form-data` with a
+    /// boundary
+    #[error("not a multipart/form-data request")]
+    NotMultipart,
+
+    /// Boundaries are 1 to 70 characters
+    #[error("invalid boundary")]
+    InvalidBoundary,
+
+    /// Something other than whitespace followed a boundary on its line
+    #[error("malformed boundary line")]
+    MalformedBoundaryLine,
+
+    /// A part's header section isn't `name: value` lines
+    #[error("malformed part headers")]
+    MalformedHeaders,
+
+    /// The body ended before the closing boundary
+    #[error("body ended before the closing boundary")]
+    UnexpectedEof,
+
+    #[error("part is over {max} bytes")]
+    PartTooLarge { max: u64 },
+
+    #[error("body is over {max} bytes")]
+    BodyTooLarge { max: u64 },
+
+    #[error("part header section is over {max} bytes")]
+    HeadersTooLarge { max: usize },
+
+    #[error("body has more than {max} parts")]
+    TooManyParts { max: usize },
+}
+
+type Result<T, B> = std::result::Result<T, MultipartError<<B as Body>::Error>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// In the preamble, or in a part's body: looking for the next delimiter
+    Body,
+    /// Right after a delimiter: either `--`, or the end of the line
+    AfterDelimiter,
+    /// Between the boundary line and the empty line that ends the headers
+    Headers,
+    /// Past the closing boundary: the epilogue, if any, is ignored
+    Done,
+}
+
+/// Splits a `multipart/form-data` body into parts, cf. the [module
+/// docs](self)
+pub struct Multipart<'b, B: Body> {
+    body: &'b mut B,
+    conf: MultipartConf,
+
+    /// `CRLF--boundary`
+    delimiter: Vec<u8>,
+    state: State,
+
+    /// What we've read but not handed out yet
+    buf: Piece,
+    /// Read from the body, but not looked at yet
+    pending: Option<Piece>,
+    /// What's left of a [BodyChunk::File]
+    file: Option<(Rc<File>, u64, u64)>,
+
+    read_total: u64,
+    part_size: u64,
+    parts: usize,
+}
+
+impl<'b, B: Body> Multipart<'b, B> {
+    /// Uses the boundary from the request's `content-type`
+    pub fn from_request(req: &Request, body: &'b mut B, conf: MultipartConf) -> Result<Self, B> {
+        let boundary = req
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| boundary(value))
+            .ok_or(MultipartError::NotMultipart)?;
+        Self::new(body, boundary.as_bytes(), conf)
+    }
+
+    pub fn new(body: &'b mut B, boundary: &[u8], conf: MultipartConf) -> Result<Self, B> {
+        if boundary.is_empty() || boundary.len() > 70 {
+            return Err(MultipartError::InvalidBoundary);
+        }
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary);
+        Ok(Self {
+            body,
+            conf,
+            delimiter,
+            state: State::Body,
+            // the first boundary doesn't follow a line break: pretend it
+            // does, so the preamble can be skipped like any part body
+            buf: Piece::from(&b"\r\n"[..]),
+            pending: None,
+            file: None,
+            read_total: 0,
+            part_size: 0,
+            parts: 0,
+        })
+    }
+
+    /// The next part, once the previous one's body (read or not) is out of
+    /// the way. `None` after the closing boundary.
+    pub async fn next_part(&mut self) -> Result<Option<Part<'_, 'b, B>>, B> {
+        while self.state == State::Body {
+            self.next_body_chunk().await?;
+        }
+
+        if self.state == State::AfterDelimiter {
+            while self.buf.len() < 2 {
+                self.fill_whole().await?;
+            }
+            if self.buf.starts_with(b"--") {
+                self.state = State::Done;
+            } else {
+                // transport padding, then the end of the line
+                let padding = self.read_line(1024).await?;
+                if !padding.iter().all(|&b| b == b' ' || b == b'\t') {
+                    return Err(MultipartError::MalformedBoundaryLine);
+                }
+                self.state = State::Headers;
+            }
+        }
+        if self.state == State::Done {
+            return Ok(None);
+        }
+
+        self.parts += 1;
+        if self.parts > self.conf.max_parts {
+            return Err(MultipartError::TooManyParts {
+                max: self.conf.max_parts,
+            });
+        }
+        let headers = self.read_headers().await?;
+        self.state = State::Body;
+        self.part_size = 0;
+
+        let disposition = headers
+            .get(header::CONTENT_DISPOSITION)
+            .map(|value| &value[..])
+            .unwrap_or_default();
+        Ok(Some(Part {
+            name: param(disposition, "name"),
+            filename: param(disposition, "filename"),
+            headers,
+            multipart: self,
+        }))
+    }
+
+    /// Reads a line, CRLF excluded, that may only be `limit` bytes long. Only
+    /// for short lines: the line is copied.
+    async fn read_line(&mut self, limit: usize) -> Result<Vec<u8>, B> {
+        loop {
+            if let Some(pos) = memmem::find(&self.buf, b"\r\n") {
+                let buf = std::mem::replace(&mut self.buf, Piece::empty());
+                let (line, rest) = buf.split_at(pos);
+                self.buf = rest.split_at(2).1;
+                return Ok(line[..].to_vec());
+            }
+            if self.buf.len() > limit {
+                return Err(MultipartError::MalformedBoundaryLine);
+            }
+            self.fill_whole().await?;
+        }
+    }
+
+    async fn read_headers(&mut self) -> Result<Headers, B> {
+        let max = self.conf.max_headers_size;
+        let block = loop {
+            if self.buf.starts_with(b"\r\n") {
+                // no headers at all
+                self.buf = std::mem::replace(&mut self.buf, Piece::empty())
+                    .split_at(2)
+                    .1;
+                break Vec::new();
+            }
+            if let Some(pos) = memmem::find(&self.buf, b"\r\n\r\n") {
+                if pos > max {
+                    return Err(MultipartError::HeadersTooLarge { max });
+                }
+                let buf = std::mem::replace(&mut self.buf, Piece::empty());
+                let (block, rest) = buf.split_at(pos);
+                self.buf = rest.split_at(4).1;
+                break block[..].to_vec();
+            }
+            if self.buf.len() > max {
+                return Err(MultipartError::HeadersTooLarge { max });
+            }
+            self.fill_whole().await?;
+        };
+
+        let mut headers = Headers::default();
+        for line in block.split(|&b| b == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() {
+                continue;
+            }
+            let colon = memchr::memchr(b':', line).ok_or(MultipartError::MalformedHeaders)?;
+            let name = HeaderName::from_bytes(&line[..colon])
+                .map_err(|_| MultipartError::MalformedHeaders)?;
+            let value = line[colon + 1..].trim_ascii();
+            headers.append(name, value.to_vec().into());
+        }
+        Ok(headers)
+    }
+
+    /// The next slice of the current part's body (or of the preamble).
+    /// `None` once the part is over, and `state` moved on.
+    async fn next_body_chunk(&mut self) -> Result<Option<Piece>, B> {
+        let delimiter_len = self.delimiter.len();
+        loop {
+            if self.state != State::Body {
+                return Ok(None);
+            }
+
+            let buf = std::mem::replace(&mut self.buf, Piece::empty());
+            if let Some(pos) = memmem::find(&buf, &self.delimiter) {
+                let (data, rest) = buf.split_at(pos);
+                self.buf = rest.split_at(delimiter_len).1;
+                self.state = State::AfterDelimiter;
+                if data.is_empty() {
+                    return Ok(None);
+                }
+                return self.count_part_bytes(data).map(Some);
+            }
+
+            // the end of `buf` could be the start of a delimiter
+            let keep = buf.len().min(delimiter_len - 1);
+            if buf.len() > keep {
+                let len = buf.len();
+                let (data, rest) = buf.split_at(len - keep);
+                self.buf = rest;
+                return self.count_part_bytes(data).map(Some);
+            }
+            self.buf = buf;
+            self.fill_lookahead().await?;
+        }
+    }
+
+    fn count_part_bytes(&mut self, data: Piece) -> Result<Piece, B> {
+        if self.parts > 0 {
+            self.part_size += data.len() as u64;
+            if self.part_size > self.conf.max_part_size {
+                return Err(MultipartError::PartTooLarge {
+                    max: self.conf.max_part_size,
+                });
+            }
+        }
+        Ok(data)
+    }
+
+    /// Appends the next input to `buf`, copying both
+    async fn fill_whole(&mut self) -> Result<(), B> {
+        let next = self.next_input().await?;
+        self.buf = concat(&self.buf, &next);
+        Ok(())
+    }
+
+    /// Appends enough of the next input to `buf` to tell whether a
+    /// delimiter starts in it, and keeps the rest for later: `buf` is
+    /// shorter than a delimiter, so that's all that gets copied.
+    async fn fill_lookahead(&mut self) -> Result<(), B> {
+        let next = self.next_input().await?;
+        if self.buf.is_empty() {
+            self.buf = next;
+        } else if next.len() < self.delimiter.len() {
+            self.buf = concat(&self.buf, &next);
+        } else {
+            let (head, tail) = next.split_at(self.delimiter.len());
+            self.buf = concat(&self.buf, &head);
+            self.pending = Some(tail);
+        }
+        Ok(())
+    }
+
+    async fn next_input(&mut self) -> Result<Piece, B> {
+        if let Some(pending) = self.pending.take() {
+            return Ok(pending);
+        }
+        loop {
+            let piece = if let Some((file, offset, len)) = self.file.take() {
+                let piece = buffet::read_file_piece(&file, offset, len)?;
+                let read = piece.len() as u64;
+                if read < len {
+                    self.file = Some((file, offset + read, len - read));
+                }
+                piece
+            } else {
+                match self.body.next_chunk().await.map_err(MultipartError::Body)? {
+                    BodyChunk::Chunk(piece) => piece,
+                    BodyChunk::File { file, offset, len } => {
+                        self.file = Some((file, offset, len));
+                        continue;
+                    }
+                    BodyChunk::Done { .. } => return Err(MultipartError::UnexpectedEof),
+                }
+            };
+            if piece.is_empty() {
+                continue;
+            }
+
+            self.read_total += piece.len() as u64;
+            if self.read_total > self.conf.max_total_size {
+                return Err(MultipartError::BodyTooLarge {
+                    max: self.conf.max_total_size,
+                });
+            }
+            return Ok(piece);
+        }
+    }
+}
+
+/// One part of a [Multipart] body. Its body can be read with
+/// [Part::chunk], or skipped by asking for the next part.
+pub struct Part<'m, 'b, B: Body> {
+    multipart: &'m mut Multipart<'b, B>,
+    headers: Headers,
+    name: Option<String>,
+    filename: Option<String>,
+}
+
+impl<B: Body> Part<'_, '_, B> {
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// The form field name, from `content-disposition`
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The original file name, from `content-disposition`, for file
+    /// uploads. It's whatever the client says: don't use it as a path.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    pub fn content_type(&self) -> Option<&[u8]> {
+        self.headers
+            .get(header::CONTENT_TYPE)
+            .map(|value| &value[..])
+    }
+
+    /// The next chunk of the part's body, `None` once it's over
+    pub async fn chunk(&mut self) -> Result<Option<Piece>, B> {
+        self.multipart.next_body_chunk().await
+    }
+
+    /// Reads the rest of the part's body into memory: for form fields, not
+    /// for files
+    pub async fn bytes(mut self) -> Result<Vec<u8>, B> {
+        let mut out = Vec::new();
+        while let Some(chunk) = self.chunk().await? {
+            out.extend_from_slice(&chunk[..]);
+        }
+        Ok(out)
+    }
+}
+
+/// The `boundary` parameter of a `multipart/form-data` content-type
+pub fn boundary(content_type: &[u8]) -> Option<String> {
+    let essence = content_type.split(|&b| b == b';').next()?;
+    if !essence
+        .trim_ascii()
+        .eq_ignore_ascii_case(b"multipart/form-data")
+    {
+        return None;
+    }
+    param(content_type, "boundary")
+}
+
+/// Finds parameter `name` in a header value like `form-data; name="a"`,
+/// unquoting it if needed
+fn param(value: &[u8], name: &str) -> Option<String> {
+    let mut rest = value;
+    // skip the value itself (the media type, or the disposition type)
+    let pos = memchr::memchr(b';', rest)?;
+    rest = &rest[pos + 1..];
+
+    loop {
+        rest = rest.trim_ascii_start();
+        let eq = memchr::memchr(b'=', rest)?;
+        let key = rest[..eq].trim_ascii();
+        rest = &rest[eq + 1..];
+
+        let value = if let Some(quoted) = rest.strip_prefix(b"\"") {
+            let mut value = Vec::new();
+            let mut i = 0;
+            loop {
+                match *quoted.get(i)? {
+                    b'"' => break,
+                    b'\\' => {
+                        value.push(*quoted.get(i + 1)?);
+                        i += 2;
+                    }
+                    b => {
+                        value.push(b);
+                        i += 1;
+                    }
+                }
+            }
+            rest = &quoted[i + 1..];
+            rest = match memchr::memchr(b';', rest) {
+                Some(pos) => &rest[pos + 1..],
+                None => &[],
+            };
+            value
+        } else {
+            let end = memchr::memchr(b';', rest).unwrap_or(rest.len());
+            let value = rest[..end].trim_ascii().to_vec();
+            rest = rest.get(end + 1..).unwrap_or_default();
+            value
+        };
+
+        if key.eq_ignore_ascii_case(name.as_bytes()) {
+            return String::from_utf8(value).ok();
+        }
+        if rest.is_empty() {
+            return None;
+        }
+    }
+}
+
+fn concat(a: &[u8], b: &[u8]) -> Piece {
+    let mut out = Vec::with_capacity(a.len() + b.len());
+    out.extend_from_slice(a);
+    out.extend_from_slice(b);
+    out.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, fmt};
+
+    use buffet::Piece;
+    use http::header;
+
+    use super::{boundary, param, Multipart, MultipartConf, MultipartError};
+    use crate::{error::NeverError, Body, BodyChunk, Request};
+
+    /// Yields its input in chunks of a given size
+    struct Chunks(VecDeque<Piece>);
+
+    impl Chunks {
+        fn new(input: &[u8], size: usize) -> Self {
+            Self(input.chunks(size).map(|c| c.to_vec().into()).collect())
+        }
+    }
+
+    impl fmt::Debug for Chunks {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Chunks").finish_non_exhaustive()
+        }
+    }
+
+    impl Body for Chunks {
+        type Error = NeverError;
+
+        fn content_len(&self) -> Option<u64> {
+            None
+        }
+
+        fn eof(&self) -> bool {
+            self.0.is_empty()
+        }
+
+        async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+            Ok(match self.0.pop_front() {
+                Some(piece) => BodyChunk::Chunk(piece),
+                None => BodyChunk::Done { trailers: None },
+            })
+        }
+    }
+
+    const BODY: &[u8] = b"preamble, ignored\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\
+        \r\n\
+        hello --XyZ world\r\n\
+        --XyZ  \r\n\
+        Content-Disposition: form-data; name=\"upload\"; filename=\"a \\\"b\\\".txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        line one\r\nline two\r\n\r\n\
+        --XyZ\r\n\
+        \r\n\
+        no headers\r\n\
+        --XyZ--\r\n\
+        epilogue, ignored";
+
+    type Parsed = Vec<(Option<String>, Option<String>, Vec<u8>)>;
+
+    async fn parse(
+        body: &mut Chunks,
+        conf: MultipartConf,
+    ) -> Result<Parsed, MultipartError<NeverError>> {
+        let mut multipart = Multipart::new(body, b"XyZ", conf)?;
+        let mut parts = Vec::new();
+        while let Some(part) = multipart.next_part().await? {
+            let name = part.name().map(String::from);
+            let filename = part.filename().map(String::from);
+            parts.push((name, filename, part.bytes().await?));
+        }
+        Ok(parts)
+    }
+
+    #[test]
+    fn test_parts() {
+        buffet::start(async move {
+            // whatever the chunk size, boundaries can straddle chunks
+            for size in [1, 2, 3, 7, 8, 13, 64, BODY.len()] {
+                let parts = parse(&mut Chunks::new(BODY, size), Default::default())
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    parts,
+                    [
+                        (Some("title".into()), None, b"hello --XyZ world".to_vec()),
+                        (
+                            Some("upload".into()),
+                            Some("a \"b\".txt".into()),
+                            b"line one\r\nline two\r\n".to_vec()
+                        ),
+                        (None, None, b"no headers".to_vec()),
+                    ],
+                    "chunk size {size}"
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_skip_parts() {
+        buffet::start(async move {
+            let mut body = Chunks::new(BODY, 5);
+            let mut multipart = Multipart::new(&mut body, b"XyZ", Default::default()).unwrap();
+            let mut seen = Vec::new();
+            while let Some(mut part) = multipart.next_part().await.unwrap() {
+                seen.push(part.content_type().map(|ct| ct.to_vec()));
+                // read a bit of the body, leave the rest
+                part.chunk().await.unwrap();
+            }
+            assert_eq!(seen, [None, Some(b"text/plain".to_vec()), None]);
+        });
+    }
+
+    #[test]
+    fn test_limits() {
+        buffet::start(async move {
+            let conf = MultipartConf {
+                max_part_size: 10,
+                ..Default::default()
+            };
+            let err = parse(&mut Chunks::new(BODY, 4), conf).await.unwrap_err();
+            assert!(matches!(err, MultipartError::PartTooLarge { max: 10 }));
+
+            let conf = MultipartConf {
+                max_total_size: 100,
+                ..Default::default()
+            };
+            let err = parse(&mut Chunks::new(BODY, 4), conf).await.unwrap_err();
+            assert!(matches!(err, MultipartError::BodyTooLarge { max: 100 }));
+
+            let conf = MultipartConf {
+                max_parts: 2,
+                ..Default::default()
+            };
+            let err = parse(&mut Chunks::new(BODY, 4), conf).await.unwrap_err();
+            assert!(matches!(err, MultipartError::TooManyParts { max: 2 }));
+
+            let conf = MultipartConf {
+                max_headers_size: 16,
+                ..Default::default()
+            };
+            let err = parse(&mut Chunks::new(BODY, 4), conf).await.unwrap_err();
+            assert!(matches!(err, MultipartError::HeadersTooLarge { max: 16 }));
+
+            let truncated = &BODY[..BODY.len() - 30];
+            let err = parse(&mut Chunks::new(truncated, 4), Default::default())
+                .await
+                .unwrap_err();
+            assert!(matches!(err, MultipartError::UnexpectedEof));
+        });
+    }
+
+    #[test]
+    fn test_boundary() {
+        assert_eq!(
+            boundary(b"multipart/form-data; boundary=----WebKitFormBoundary7MA4"),
+            Some("----WebKitFormBoundary7MA4".into())
+        );
+        assert_eq!(
+            boundary(b"Multipart/Form-Data;charset=utf-8; boundary=\"a b\""),
+            Some("a b".into())
+        );
+        assert_eq!(boundary(b"multipart/mixed; boundary=x"), None);
+        assert_eq!(boundary(b"multipart/form-data"), None);
+        assert_eq!(
+            param(b"form-data; name=a;filename=b", "filename"),
+            Some("b".into())
+        );
+
+        let mut req = Request::default();
+        let mut body = Chunks::new(b"", 1);
+        assert!(matches!(
+            Multipart::from_request(&req, &mut body, Default::default()),
+            Err(MultipartError::NotMultipart)
+        ));
+        req.headers.insert(
+            header::CONTENT_TYPE,
+            "multipart/form-data; boundary=XyZ".into(),
+        );
+        assert!(Multipart::from_request(&req, &mut body, Default::default()).is_ok());
+    }
+}
@@ -0,0 +1,86 @@
+//! A defensive mode for when the buffer pool runs low: rather than failing
+//! with allocation errors halfway through responses, servers start turning
+//! work away while there's still room to do it cleanly.
+//!
+//! Under pressure:
+//!
+//!   * HTTP/1.1 requests get a `503` and the connection is closed
+//!   * new HTTP/2 connections get a GOAWAY, new streams get a `503`
+//!   * HTTP/2 request body windows shrink to [PressureConf::h2_window_size]
+//!   * if [PressureConf::shed_largest_response] is set, the HTTP/2 stream
+//!     with the most response data waiting to be sent is reset
+
+use buffet::bufpool;
+
+/// When the buffer pool counts as under pressure, and what to do about it
+#[derive(Debug, Clone)]
+pub struct PressureConf {
+    /// The pool is under pressure when less than this fraction of its
+    /// buffers is free. Zero turns the defensive mode off.
+    pub min_free_ratio: f64,
+
+    /// HTTP/2 only: how much request body data clients may have in flight,
+    /// per stream and for the whole connection, while under pressure
+    pub h2_window_size: u32,
+
+    /// HTTP/2 only: reset the stream with the most buffered response data,
+    /// each time we notice we're under pressure
+    pub shed_largest_response: bool,
+}
+
+impl Default for PressureConf {
+    fn default() -> Self {
+        Self {
+            min_free_ratio: 0.05,
+            h2_window_size: 16 * 1024,
+            shed_largest_response: false,
+        }
+    }
+}
+
+impl PressureConf {
+    /// Returns true if the current thread's buffer pool is running low.
+    /// Pools that can grow count the buffers they haven't mapped yet as
+    /// free.
+    pub fn under_pressure(&self) -> bool {
+        if self.min_free_ratio <= 0.0 || !bufpool::is_allocator_initialized() {
+            return false;
+        }
+        let stats = bufpool::stats();
+        let free = stats.capacity - stats.in_use;
+        (free as f64) < self.min_free_ratio * stats.capacity as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use buffet::bufpool::{self, BufMut};
+
+    use super::PressureConf;
+
+    #[test]
+    fn test_under_pressure() {
+        bufpool::initialize_allocator().unwrap();
+
+        let off = PressureConf {
+            min_free_ratio: 0.0,
+            ..Default::default()
+        };
+        assert!(!off.under_pressure());
+
+        let conf = PressureConf::default();
+        assert!(!conf.under_pressure());
+
+        // take all but 1% of the pool
+        let keep = bufpool::num_bufs() / 100;
+        let mut held = Vec::new();
+        while bufpool::num_free() > keep {
+            held.push(BufMut::alloc().unwrap());
+        }
+        assert!(conf.under_pressure());
+        assert!(!off.under_pressure());
+
+        drop(held);
+        assert!(!conf.under_pressure());
+    }
+}
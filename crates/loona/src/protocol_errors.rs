@@ -0,0 +1,168 @@
+//! Keeps the last few protocol errors clients caused: malformed or oversized
+//! HTTP/1.1 requests, HTTP/2 connection errors. Answers "why do some
+//! requests get a 400" without turning logging up for everyone.
+//!
+//! Share one [ProtocolErrorLog] between the [crate::h1::ServerConf] and
+//! [crate::h2::ServerConf] of a listener, and show [ProtocolErrorLog::recent]
+//! wherever it's convenient, a debug endpoint for example.
+
+use std::{cell::RefCell, collections::VecDeque, fmt, net::SocketAddr, time::SystemTime};
+
+use http::Version;
+
+use crate::{error::ConnectionError, util::fmt_rfc3339};
+
+/// How much of the offending input is kept
+pub const SNIPPET_LEN: usize = 64;
+
+/// One error, cf. [ProtocolErrorLog]
+#[derive(Debug, Clone)]
+pub struct ProtocolError {
+    pub at: SystemTime,
+    pub peer_addr: Option<SocketAddr>,
+    pub version: Version,
+
+    /// What kind of error it was
+    pub code: ConnectionError,
+
+    /// What went wrong, e.g. "Parsing error in parser Http1Request: Verify
+    /// at offset 0: ..."
+    pub kind: String,
+
+    /// The start of the input that caused it, if we have it, at most
+    /// [SNIPPET_LEN] bytes
+    pub snippet: Vec<u8>,
+}
+
+impl fmt::Display for ProtocolError {
+    /// One line: time, peer, version, code, kind, and the snippet, escaped
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", fmt_rfc3339(self.at))?;
+        match self.peer_addr {
+            Some(addr) => write!(f, "{addr} ")?,
+            None => write!(f, "- ")?,
+        }
+        write!(f, "{:?} {} {}", self.version, self.code.name(), self.kind)?;
+        if !self.snippet.is_empty() {
+            write!(f, " \"{}\"", self.snippet.escape_ascii())?;
+        }
+        Ok(())
+    }
+}
+
+/// A ring buffer of the last `capacity` [ProtocolError]s
+pub struct ProtocolErrorLog {
+    capacity: usize,
+    state: RefCell<State>,
+}
+
+#[derive(Default)]
+struct State {
+    recent: VecDeque<ProtocolError>,
+    total: u64,
+}
+
+impl ProtocolErrorLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Default::default(),
+        }
+    }
+
+    /// Adds an error, forgetting the oldest one if the log is full. Drivers
+    /// can record their own, e.g. requests they turn away as malformed.
+    pub fn record(&self, mut error: ProtocolError) {
+        error.snippet.truncate(SNIPPET_LEN);
+        let mut state = self.state.borrow_mut();
+        state.total += 1;
+        if self.capacity == 0 {
+            return;
+        }
+        if state.recent.len() == self.capacity {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(error);
+    }
+
+    /// Builds a [ProtocolError] that happened just now and records it
+    pub(crate) fn record_now(
+        &self,
+        peer_addr: Option<SocketAddr>,
+        version: Version,
+        code: ConnectionError,
+        kind: impl fmt::Display,
+        snippet: &[u8],
+    ) {
+        self.record(ProtocolError {
+            at: SystemTime::now(),
+            peer_addr,
+            version,
+            code,
+            kind: kind.to_string(),
+            snippet: snippet[..snippet.len().min(SNIPPET_LEN)].to_vec(),
+        });
+    }
+
+    /// The errors still in the log, oldest first
+    pub fn recent(&self) -> Vec<ProtocolError> {
+        self.state.borrow().recent.iter().cloned().collect()
+    }
+
+    /// How many errors were ever recorded, including the ones that fell off
+    pub fn total(&self) -> u64 {
+        self.state.borrow().total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use http::Version;
+
+    use crate::error::ConnectionError;
+
+    use super::{ProtocolError, ProtocolErrorLog, SNIPPET_LEN};
+
+    #[test]
+    fn test_ring() {
+        let log = ProtocolErrorLog::new(2);
+        for kind in ["a", "b", "c"] {
+            log.record_now(
+                None,
+                Version::HTTP_11,
+                ConnectionError::MalformedRequest,
+                kind,
+                &[b'x'; 100],
+            );
+        }
+        let recent = log.recent();
+        assert_eq!(log.total(), 3);
+        assert_eq!(
+            recent.iter().map(|e| &e.kind[..]).collect::<Vec<_>>(),
+            ["b", "c"]
+        );
+        assert_eq!(recent[0].snippet.len(), SNIPPET_LEN);
+
+        let nothing = ProtocolErrorLog::new(0);
+        nothing.record_now(None, Version::HTTP_2, ConnectionError::FrameSize, "a", b"");
+        assert_eq!((nothing.total(), nothing.recent().len()), (1, 0));
+    }
+
+    #[test]
+    fn test_display() {
+        let error = ProtocolError {
+            at: UNIX_EPOCH + Duration::from_secs(784111777),
+            peer_addr: Some("127.0.0.1:4321".parse().unwrap()),
+            version: Version::HTTP_11,
+            code: ConnectionError::MalformedRequest,
+            kind: "Parsing error in parser: Http1Request".into(),
+            snippet: b"GET /\"\r\n".to_vec(),
+        };
+        assert_eq!(
+            error.to_string(),
+            r#"1994-11-06T08:49:37.000Z 127.0.0.1:4321 HTTP/1.1 malformed_request Parsing error in parser: Http1Request "GET /\"\r\n""#
+        );
+    }
+}
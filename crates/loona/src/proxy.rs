@@ -0,0 +1,419 @@
+//! Building blocks for gateways: forwarding requests received over HTTP/1.1
+//! or HTTP/2 to an HTTP/1.1 upstream, and relaying the response back.
+//!
+//! [proxy_request] does the whole round-trip. The `prepare_*` functions are
+//! exposed for drivers that want to tweak messages before forwarding them.
+//! [tunnel] relays the bytes of an accepted CONNECT request. Upstreams that
+//! expect a PROXY protocol header can be sent one with
+//! [crate::proxy_protocol] before the first request.
+//!
+//! Hop-by-hop fields (cf. <https://httpwg.org/specs/rfc9110.html#field.connection>)
+//! are never forwarded, in either direction.
+
+use b_x::{BxForResults, BX};
+use buffet::{ReadOwned, RollMut, WriteOwned};
+use http::{header, uri::PathAndQuery, HeaderName, StatusCode, Uri, Version};
+use tracing::{debug, warn};
+
+use crate::{
+    h1, Body, BodyChunk, Encoder, ExpectResponseBody, ExpectResponseHeaders, Headers, HeadersExt,
+    Request, Responder, ResponderError, Response, ResponseDone, FORBIDDEN_TRAILERS,
+};
+
+/// Fields that only make sense for a single connection. Fields listed in the
+/// `connection` header are stripped as well.
+pub const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+    "te",
+];
+
+/// Removes hop-by-hop fields, including the ones named in the `connection`
+/// header.
+pub fn strip_hop_by_hop_headers(headers: &mut Headers) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .flat_map(|value| value.split(|&b| b == b','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim_ascii()).ok())
+        .collect();
+
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+}
+
+/// Turns a request received over HTTP/1.x or HTTP/2 into an HTTP/1.1 request
+/// suitable for an upstream:
+///
+///   - hop-by-hop fields and `expect` are removed (we answer `100-continue`
+///     ourselves)
+///   - `host` is set from the authority if missing (HTTP/2 requests carry
+///     `:authority` instead)
+///   - the target is turned into origin-form
+///   - `te: trailers` is kept, since it's end-to-end in spirit
+pub fn prepare_upstream_request(mut req: Request) -> Request {
+    let wants_trailers = req.headers.accepts_trailers();
+    strip_hop_by_hop_headers(&mut req.headers);
+    req.headers.remove(header::EXPECT);
+
+    if !req.headers.contains_key(header::HOST) {
+        if let Some(authority) = req.uri.authority() {
+            req.headers.insert(
+                header::HOST,
+                authority.as_str().to_owned().into_bytes().into(),
+            );
+        }
+    }
+
+    let path_and_query = req
+        .uri
+        .path_and_query()
+        .cloned()
+        .unwrap_or_else(|| PathAndQuery::from_static("/"));
+    req.uri = Uri::from(path_and_query);
+    req.version = Version::HTTP_11;
+
+    if wants_trailers {
+        req.headers.insert(header::TE, "trailers".into());
+    }
+    req
+}
+
+/// Turns a response received from an HTTP/1.1 upstream into one that can be
+/// written by any [Encoder]: hop-by-hop fields are removed, and the encoder
+/// picks its own framing.
+pub fn prepare_downstream_response(mut res: Response) -> Response {
+    strip_hop_by_hop_headers(&mut res.headers);
+    res.version = Version::HTTP_11;
+    res
+}
+
+/// Removes fields that can't appear in trailers, or that only concern the
+/// upstream connection. Returns `None` if nothing is left.
+pub fn prepare_trailers(mut trailers: Box<Headers>) -> Option<Box<Headers>> {
+    strip_hop_by_hop_headers(&mut trailers);
+    for name in FORBIDDEN_TRAILERS {
+        trailers.remove(name);
+    }
+    if trailers.is_empty() {
+        None
+    } else {
+        Some(trailers)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ProxyError<EncoderError> {
+    /// Writing the response to the downstream client failed
+    #[error("Error writing response downstream: {0}")]
+    Downstream(#[source] ResponderError<EncoderError>),
+
+    /// The upstream failed after we started relaying its response, so we
+    /// couldn't reply with a 502 anymore
+    #[error("Upstream failed mid-response: {0}")]
+    Upstream(#[source] BX),
+
+    /// Reading tunnel bytes from the downstream client failed
+    #[error("Error reading from downstream: {0}")]
+    DownstreamBody(#[source] BX),
+}
+
+impl<EncoderError> From<ProxyError<EncoderError>> for BX
+where
+    EncoderError: std::error::Error + 'static,
+{
+    fn from(e: ProxyError<EncoderError>) -> Self {
+        BX::from_err(e)
+    }
+}
+
+/// Forwards `req` to an HTTP/1.1 upstream over `transport`, and relays the
+/// response through `respond`.
+///
+/// If the upstream fails before sending response headers, a
+/// `502 Bad Gateway` is sent downstream instead, and the transport is not
+/// returned. Otherwise, the transport is returned if it can be reused.
+pub async fn proxy_request<R, W, E>(
+    transport: (R, W),
+    req: Request,
+    req_body: &mut impl Body,
+    mut respond: Responder<E, ExpectResponseHeaders>,
+) -> Result<(Option<(R, W)>, Responder<E, ResponseDone>), ProxyError<E::Error>>
+where
+    R: ReadOwned,
+    W: WriteOwned,
+    E: Encoder,
+{
+    let downstream_version = req.version;
+
+    // our HTTP/2 encoder doesn't do interim responses, and HTTP/2 clients
+    // don't wait for them anyway
+    if downstream_version != Version::HTTP_2 && req.headers.expects_100_continue() {
+        debug!("Sending 100-continue");
+        let res = Response {
+            status: StatusCode::CONTINUE,
+            ..Default::default()
+        };
+        respond
+            .write_interim_response(res)
+            .await
+            .map_err(ProxyError::Downstream)?;
+    }
+
+    // trailers are only relayed to clients that asked for them
+    let relay_trailers = req.headers.accepts_trailers();
+
+    let req = prepare_upstream_request(req);
+    let mut slot = Some(respond);
+    let driver = ProxyClientDriver {
+        respond: &mut slot,
+        relay_trailers,
+    };
+
+    match h1::request(transport, req, req_body, driver).await {
+        Ok((transport, respond)) => Ok((transport, respond)),
+        Err(h1::Http1ClientError::DriverError(e)) => Err(e),
+        Err(e) => match slot.take() {
+            Some(respond) => {
+                warn!("Upstream failed before sending a response: {e}");
+                let mut res = Response {
+                    status: StatusCode::BAD_GATEWAY,
+                    ..Default::default()
+                };
+                res.headers.insert(header::CONTENT_LENGTH, "0".into());
+                let respond = respond
+                    .write_final_response(res)
+                    .await
+                    .map_err(ProxyError::Downstream)?
+                    .finish_body(None)
+                    .await
+                    .map_err(ProxyError::Downstream)?;
+                Ok((None, respond))
+            }
+            None => Err(ProxyError::Upstream(BX::from_err(e))),
+        },
+    }
+}
+
+/// Copies bytes both ways between the client of an accepted CONNECT request
+/// and `upstream`, until both directions are done. Each side's end of stream
+/// is forwarded: a client closing its side shuts down our writes to
+/// `upstream`, and `upstream` closing its side finishes the response.
+///
+/// The request is accepted by writing a 2xx final response without a body
+/// length: over HTTP/1.1, the rest of the connection is handed over to the
+/// tunnel, over HTTP/2 the tunnel bytes are carried in DATA frames.
+pub async fn tunnel<R, W, E>(
+    req_body: &mut impl Body,
+    mut respond: Responder<E, ExpectResponseBody>,
+    (mut upstream_r, mut upstream_w): (R, W),
+) -> Result<Responder<E, ResponseDone>, ProxyError<E::Error>>
+where
+    R: ReadOwned,
+    W: WriteOwned,
+    E: Encoder,
+{
+    let downstream_to_upstream = async {
+        loop {
+            match req_body
+                .next_chunk()
+                .await
+                .bx()
+                .map_err(ProxyError::DownstreamBody)?
+            {
+                BodyChunk::Chunk(chunk) => upstream_w.write_all_owned(chunk).await,
+                BodyChunk::File { file, offset, len } => {
+                    upstream_w.write_file_all(&file, offset, len).await
+                }
+                BodyChunk::Done { .. } => break,
+            }
+            .map_err(|e| ProxyError::Upstream(BX::from_err(e)))?;
+        }
+        debug!("downstream closed its side of the tunnel");
+        upstream_w
+            .shutdown()
+            .await
+            .map_err(|e| ProxyError::Upstream(BX::from_err(e)))
+    };
+
+    let upstream_to_downstream = async {
+        let mut buf = RollMut::alloc().map_err(|e| ProxyError::Upstream(BX::from_err(e)))?;
+        loop {
+            buf.reserve()
+                .map_err(|e| ProxyError::Upstream(BX::from_err(e)))?;
+            let res;
+            (res, buf) = buf.read_into(usize::MAX, &mut upstream_r).await;
+            if res.map_err(|e| ProxyError::Upstream(BX::from_err(e)))? == 0 {
+                break;
+            }
+            respond
+                .write_chunk(buf.take_all().into())
+                .await
+                .map_err(ProxyError::Downstream)?;
+        }
+        debug!("upstream closed its side of the tunnel");
+        respond
+            .finish_body(None)
+            .await
+            .map_err(ProxyError::Downstream)
+    };
+
+    let (_, respond) = tokio::try_join!(downstream_to_upstream, upstream_to_downstream)?;
+    Ok(respond)
+}
+
+struct ProxyClientDriver<'a, E>
+where
+    E: Encoder,
+{
+    respond: &'a mut Option<Responder<E, ExpectResponseHeaders>>,
+    relay_trailers: bool,
+}
+
+impl<E> h1::ClientDriver for ProxyClientDriver<'_, E>
+where
+    E: Encoder,
+{
+    type Return = Responder<E, ResponseDone>;
+    type Error = ProxyError<E::Error>;
+
+    async fn on_informational_response(&mut self, res: Response) -> Result<(), Self::Error> {
+        debug!("Got informational response {}", res.status);
+        Ok(())
+    }
+
+    async fn on_final_response(
+        self,
+        res: Response,
+        body: &mut impl Body,
+    ) -> Result<Self::Return, Self::Error> {
+        let respond = self
+            .respond
+            .take()
+            .expect("final response is only received once");
+        let res = prepare_downstream_response(res);
+        let announced = res.headers.announced_trailers();
+        let mut respond = respond
+            .write_final_response(res)
+            .await
+            .map_err(ProxyError::Downstream)?;
+
+        let trailers = loop {
+            match body.next_chunk().await.bx().map_err(ProxyError::Upstream)? {
+                BodyChunk::Chunk(chunk) => {
+                    respond
+                        .write_chunk(chunk)
+                        .await
+                        .map_err(ProxyError::Downstream)?;
+                }
+                BodyChunk::File { file, offset, len } => {
+                    respond
+                        .write_file(file, offset, len)
+                        .await
+                        .map_err(ProxyError::Downstream)?;
+                }
+                BodyChunk::Done { trailers } => break trailers,
+            }
+        };
+
+        let trailers = match trailers.and_then(prepare_trailers) {
+            Some(trailers) if !self.relay_trailers => {
+                debug!("Dropping {} upstream trailers", trailers.len());
+                None
+            }
+            Some(trailers) if respond.accepts_trailers() => Some(trailers),
+            Some(mut trailers) => {
+                // the response is already out: unannounced ones can't be added
+                let unannounced: Vec<HeaderName> = trailers
+                    .keys()
+                    .filter(|name| !announced.contains(name))
+                    .cloned()
+                    .collect();
+                for name in unannounced {
+                    debug!("Dropping unannounced upstream trailer {name}");
+                    trailers.remove(name);
+                }
+                Some(trailers).filter(|trailers| !trailers.is_empty())
+            }
+            None => None,
+        };
+
+        respond
+            .finish_body(trailers)
+            .await
+            .map_err(ProxyError::Downstream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header, Uri, Version};
+
+    use super::{prepare_trailers, prepare_upstream_request, strip_hop_by_hop_headers};
+    use crate::{Headers, Request};
+
+    #[test]
+    fn test_strip_hop_by_hop_headers() {
+        let mut headers = Headers::default();
+        headers.insert(header::CONNECTION, "keep-alive, x-secret".into());
+        headers.insert("keep-alive", "timeout=5".into());
+        headers.insert("x-secret", "hunter2".into());
+        headers.insert(header::TRANSFER_ENCODING, "chunked".into());
+        headers.insert(header::ACCEPT, "*/*".into());
+
+        strip_hop_by_hop_headers(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key(header::ACCEPT));
+    }
+
+    #[test]
+    fn test_prepare_upstream_request_from_h2() {
+        let mut req = Request {
+            uri: Uri::from_static("https://example.org/search?q=loona"),
+            version: Version::HTTP_2,
+            ..Default::default()
+        };
+        req.headers.insert(header::TE, "trailers".into());
+        req.headers.insert(header::EXPECT, "100-continue".into());
+
+        let req = prepare_upstream_request(req);
+        assert_eq!(req.version, Version::HTTP_11);
+        assert_eq!(req.uri, "/search?q=loona");
+        assert_eq!(&req.headers[header::HOST][..], b"example.org");
+        assert_eq!(&req.headers[header::TE][..], b"trailers");
+        assert!(!req.headers.contains_key(header::EXPECT));
+    }
+
+    #[test]
+    fn test_prepare_upstream_request_keeps_host() {
+        let mut req = Request {
+            uri: Uri::from_static("/"),
+            ..Default::default()
+        };
+        req.headers.insert(header::HOST, "upstream.local".into());
+        req.headers.insert(header::TE, "gzip".into());
+
+        let req = prepare_upstream_request(req);
+        assert_eq!(&req.headers[header::HOST][..], b"upstream.local");
+        assert!(!req.headers.contains_key(header::TE));
+    }
+
+    #[test]
+    fn test_prepare_trailers() {
+        let mut trailers = Headers::default();
+        trailers.insert(header::CONTENT_LENGTH, "3".into());
+        assert!(prepare_trailers(Box::new(trailers.clone())).is_none());
+
+        trailers.insert("x-checksum", "abcd".into());
+        let trailers = prepare_trailers(Box::new(trailers)).unwrap();
+        assert_eq!(trailers.len(), 1);
+    }
+}
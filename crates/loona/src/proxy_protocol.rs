@@ -0,0 +1,734 @@
+//! The PROXY protocol, which lets a proxy tell its upstream where the
+//! connection it's relaying requests from came from, cf.
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>
+//!
+//! Only version 2 (the binary one) is emitted. Upstreams that expect it
+//! read the header before anything else on the connection, so it must be
+//! written right after connecting, before the first request.
+//!
+//! Both versions are accepted, for servers behind a load balancer: they
+//! call [read_header] (or [parse_header] on peeked bytes) before handing
+//! the connection to a server, and [ProxyHeader::apply] what they got to the
+//! [ConnInfo].
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use buffet::{ReadOwned, RollMut};
+
+use crate::ConnInfo;
+
+/// Every version 2 header starts with this
+pub const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Signature, version and command, family, length
+const V2_FIXED_LEN: usize = 16;
+
+/// The longest header [parse_header] may need to see
+pub const MAX_HEADER_LEN: usize = V2_FIXED_LEN + u16::MAX as usize;
+
+/// Every version 1 header starts with this
+const V1_SIGNATURE: &[u8] = b"PROXY ";
+
+/// Version 1 headers are a single line, at most this long with its CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Version 2, `PROXY` command: the connection was relayed on behalf of
+/// another party
+const V2_VERSION_COMMAND_PROXY: u8 = 0x21;
+
+/// Version 2, `LOCAL` command: the proxy connected on its own behalf, e.g.
+/// for health checks
+const V2_VERSION_COMMAND_LOCAL: u8 = 0x20;
+
+const FAMILY_UNSPEC: u8 = 0x00;
+const FAMILY_TCP4: u8 = 0x11;
+const FAMILY_TCP6: u8 = 0x21;
+
+/// Address families, in the high nibble of the family byte
+const AF_UNSPEC: u8 = 0x0;
+const AF_INET: u8 = 0x1;
+const AF_INET6: u8 = 0x2;
+const AF_UNIX: u8 = 0x3;
+
+/// Types of TLVs (type-length-value fields) that follow the addresses
+pub mod tlv {
+    /// The protocol negotiated with ALPN
+    pub const ALPN: u8 = 0x01;
+    /// The host name the client asked for, e.g. with SNI
+    pub const AUTHORITY: u8 = 0x02;
+    /// An opaque identifier for the original connection
+    pub const UNIQUE_ID: u8 = 0x05;
+    /// TLS details, with sub-TLVs
+    pub const SSL: u8 = 0x20;
+    /// Sub-TLV of [SSL]: the TLS version, e.g. `TLSv1_3`
+    pub const SSL_VERSION: u8 = 0x21;
+    /// Sub-TLV of [SSL]: the cipher suite
+    pub const SSL_CIPHER: u8 = 0x23;
+}
+
+/// Bit of the [tlv::SSL] client field: the client connected over TLS
+const SSL_CLIENT_SSL: u8 = 0x01;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ProxyHeaderError {
+    /// TLV values are at most 65535 bytes long
+    #[error("TLV {kind:#04x} is {len} bytes long, the maximum is 65535")]
+    TlvTooLong { kind: u8, len: usize },
+
+    /// Addresses and TLVs must fit in 65535 bytes together
+    #[error("PROXY header is {len} bytes long, the maximum is 65535")]
+    HeaderTooLong { len: usize },
+
+    #[error("malformed PROXY header: {0}")]
+    Malformed(&'static str),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ReadHeaderError {
+    /// The mode is [ProxyProtocolMode::Require], and the connection doesn't
+    /// start with a header
+    #[error("connection doesn't start with a PROXY header")]
+    Missing,
+
+    #[error(transparent)]
+    Invalid(#[from] ProxyHeaderError),
+
+    #[error("connection closed in the middle of the PROXY header")]
+    Eof,
+
+    #[error("reading the PROXY header: {0}")]
+    Read(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Alloc(#[from] buffet::bufpool::Error),
+}
+
+/// Whether connections start with a PROXY protocol header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProxyProtocolMode {
+    /// They don't, a header would be taken for the start of a request
+    #[default]
+    Off,
+
+    /// They may: for moving a server behind a proxy. Any client can claim
+    /// any address while it's on.
+    Optional,
+
+    /// They must, the others are closed
+    Require,
+}
+
+/// What [parse_header] could tell from the bytes so far
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Parsed {
+    /// They could still be the start of a header
+    Incomplete,
+
+    /// They're not a PROXY header, of either version
+    NotProxy,
+
+    /// A whole header, `len` bytes long
+    Header { header: ProxyHeader, len: usize },
+}
+
+/// What a PROXY protocol header says about the original connection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// The client's address, and the address it connected to. If unknown,
+    /// the header says so (`AF_UNSPEC`) and upstreams use the proxy's
+    /// address instead.
+    pub addrs: Option<(SocketAddr, SocketAddr)>,
+
+    /// Extra fields, as `(type, value)`, cf. [tlv]
+    pub tlvs: Vec<(u8, Vec<u8>)>,
+}
+
+impl ProxyHeader {
+    /// Describes the connection `conn` is about: its addresses, the ALPN
+    /// protocol and SNI name, its id, and TLS details if any.
+    pub fn from_conn_info(conn: &ConnInfo) -> Self {
+        let addrs = conn.peer_addr.zip(conn.local_addr);
+
+        let mut tlvs = Vec::new();
+        if let Some(alpn) = &conn.alpn_protocol {
+            tlvs.push((tlv::ALPN, alpn.clone()));
+        }
+        if let Some(tls) = &conn.tls {
+            if let Some(name) = &tls.server_name {
+                tlvs.push((tlv::AUTHORITY, name.as_bytes().to_vec()));
+            }
+        }
+        if conn.id != 0 {
+            tlvs.push((tlv::UNIQUE_ID, conn.id.to_string().into_bytes()));
+        }
+        if let Some(tls) = &conn.tls {
+            // client, then verify: we don't ask for client certificates, so
+            // there's nothing that could have failed verification
+            let mut value = vec![SSL_CLIENT_SSL, 0, 0, 0, 0];
+            if let Some(version) = &tls.protocol_version {
+                put_tlv(&mut value, tlv::SSL_VERSION, version.as_bytes());
+            }
+            if let Some(cipher) = &tls.cipher_suite {
+                put_tlv(&mut value, tlv::SSL_CIPHER, cipher.as_bytes());
+            }
+            tlvs.push((tlv::SSL, value));
+        }
+
+        Self { addrs, tlvs }
+    }
+
+    /// Encodes the header as PROXY protocol version 2. If one address is
+    /// IPv4 and the other IPv6, the IPv4 one is sent as IPv4-mapped IPv6.
+    pub fn encode_v2(&self) -> Result<Vec<u8>, ProxyHeaderError> {
+        let mut body = Vec::new();
+        let family = match self.addrs {
+            None => FAMILY_UNSPEC,
+            Some((SocketAddr::V4(src), SocketAddr::V4(dst))) => {
+                body.extend_from_slice(&src.ip().octets());
+                body.extend_from_slice(&dst.ip().octets());
+                body.extend_from_slice(&src.port().to_be_bytes());
+                body.extend_from_slice(&dst.port().to_be_bytes());
+                FAMILY_TCP4
+            }
+            Some((src, dst)) => {
+                body.extend_from_slice(&to_ipv6(src.ip()));
+                body.extend_from_slice(&to_ipv6(dst.ip()));
+                body.extend_from_slice(&src.port().to_be_bytes());
+                body.extend_from_slice(&dst.port().to_be_bytes());
+                FAMILY_TCP6
+            }
+        };
+
+        for (kind, value) in &self.tlvs {
+            if value.len() > u16::MAX as usize {
+                return Err(ProxyHeaderError::TlvTooLong {
+                    kind: *kind,
+                    len: value.len(),
+                });
+            }
+            put_tlv(&mut body, *kind, value);
+        }
+
+        let len = u16::try_from(body.len())
+            .map_err(|_| ProxyHeaderError::HeaderTooLong { len: body.len() })?;
+
+        let mut out = Vec::with_capacity(V2_SIGNATURE.len() + 4 + body.len());
+        out.extend_from_slice(&V2_SIGNATURE);
+        out.push(V2_VERSION_COMMAND_PROXY);
+        out.push(family);
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(&body);
+        Ok(out)
+    }
+}
+
+impl ProxyHeader {
+    /// Records what the header says in `conn`: `peer_addr` and `local_addr`
+    /// become the original connection's, and the proxy's address moves to
+    /// `proxied_by`. Headers without addresses leave `conn` as is.
+    pub fn apply(&self, conn: &mut ConnInfo) {
+        if let Some((src, dst)) = self.addrs {
+            conn.proxied_by = conn.peer_addr;
+            conn.peer_addr = Some(src);
+            conn.local_addr = Some(dst);
+        }
+    }
+}
+
+/// Looks for a PROXY header, of either version, at the start of `buf`
+pub fn parse_header(buf: &[u8]) -> Result<Parsed, ProxyHeaderError> {
+    let prefix = |sig: &[u8]| buf[..buf.len().min(sig.len())] == sig[..buf.len().min(sig.len())];
+    if prefix(V1_SIGNATURE) {
+        if buf.len() < V1_SIGNATURE.len() {
+            return Ok(Parsed::Incomplete);
+        }
+        parse_v1(buf)
+    } else if prefix(&V2_SIGNATURE) {
+        if buf.len() < V2_FIXED_LEN {
+            return Ok(Parsed::Incomplete);
+        }
+        parse_v2(buf)
+    } else {
+        Ok(Parsed::NotProxy)
+    }
+}
+
+/// e.g. `PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\n`
+fn parse_v1(buf: &[u8]) -> Result<Parsed, ProxyHeaderError> {
+    let searched = &buf[..buf.len().min(V1_MAX_LEN)];
+    let Some(end) = memchr::memmem::find(searched, b"\r\n") else {
+        if searched.len() == V1_MAX_LEN {
+            return Err(ProxyHeaderError::Malformed("v1 header is too long"));
+        }
+        return Ok(Parsed::Incomplete);
+    };
+    let line = std::str::from_utf8(&buf[V1_SIGNATURE.len()..end])
+        .map_err(|_| ProxyHeaderError::Malformed("v1 header is not ASCII"))?;
+
+    let mut fields = line.split(' ');
+    let addrs = match fields.next() {
+        // whatever follows is to be ignored
+        Some("UNKNOWN") => None,
+        Some(proto @ ("TCP4" | "TCP6")) => {
+            let mut field = || {
+                fields
+                    .next()
+                    .ok_or(ProxyHeaderError::Malformed("v1 header is missing fields"))
+            };
+            let (src, dst) = if proto == "TCP4" {
+                (v1_ip::<Ipv4Addr>(field()?)?, v1_ip::<Ipv4Addr>(field()?)?)
+            } else {
+                (v1_ip::<Ipv6Addr>(field()?)?, v1_ip::<Ipv6Addr>(field()?)?)
+            };
+            let (src_port, dst_port) = (v1_port(field()?)?, v1_port(field()?)?);
+            if fields.next().is_some() {
+                return Err(ProxyHeaderError::Malformed("v1 header has extra fields"));
+            }
+            Some((
+                SocketAddr::new(src, src_port),
+                SocketAddr::new(dst, dst_port),
+            ))
+        }
+        _ => return Err(ProxyHeaderError::Malformed("unknown v1 protocol")),
+    };
+
+    Ok(Parsed::Header {
+        header: ProxyHeader {
+            addrs,
+            tlvs: vec![],
+        },
+        len: end + 2,
+    })
+}
+
+fn v1_ip<A: FromStr + Into<IpAddr>>(field: &str) -> Result<IpAddr, ProxyHeaderError> {
+    A::from_str(field)
+        .map(Into::into)
+        .map_err(|_| ProxyHeaderError::Malformed("invalid v1 address"))
+}
+
+fn v1_port(field: &str) -> Result<u16, ProxyHeaderError> {
+    // `u16::from_str` also takes a leading `+`
+    if !field.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ProxyHeaderError::Malformed("invalid v1 port"));
+    }
+    field
+        .parse()
+        .map_err(|_| ProxyHeaderError::Malformed("invalid v1 port"))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Parsed, ProxyHeaderError> {
+    let version_command = buf[12];
+    let family = buf[13];
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    let Some(mut body) = buf.get(V2_FIXED_LEN..V2_FIXED_LEN + len) else {
+        return Ok(Parsed::Incomplete);
+    };
+    let len = V2_FIXED_LEN + len;
+
+    match version_command {
+        V2_VERSION_COMMAND_PROXY => {}
+        // the addresses are the proxy's own, and the connection's
+        V2_VERSION_COMMAND_LOCAL => {
+            return Ok(Parsed::Header {
+                header: ProxyHeader::default(),
+                len,
+            })
+        }
+        _ if version_command >> 4 != 2 => {
+            return Err(ProxyHeaderError::Malformed("unsupported version"))
+        }
+        _ => return Err(ProxyHeaderError::Malformed("unknown command")),
+    }
+
+    let addrs_len = match family >> 4 {
+        AF_UNSPEC => 0,
+        AF_INET => 12,
+        AF_INET6 => 36,
+        AF_UNIX => 216,
+        _ => return Err(ProxyHeaderError::Malformed("unknown address family")),
+    };
+    if body.len() < addrs_len {
+        return Err(ProxyHeaderError::Malformed("addresses are truncated"));
+    }
+    let addrs = &body[..addrs_len];
+    let port = |at: usize| u16::from_be_bytes([addrs[at], addrs[at + 1]]);
+    let addrs = match family >> 4 {
+        AF_INET => {
+            let ip = |at: usize| IpAddr::from(<[u8; 4]>::try_from(&addrs[at..at + 4]).unwrap());
+            Some((
+                SocketAddr::new(ip(0), port(8)),
+                SocketAddr::new(ip(4), port(10)),
+            ))
+        }
+        AF_INET6 => {
+            let ip = |at: usize| IpAddr::from(<[u8; 16]>::try_from(&addrs[at..at + 16]).unwrap());
+            Some((
+                SocketAddr::new(ip(0), port(32)),
+                SocketAddr::new(ip(16), port(34)),
+            ))
+        }
+        // unix sockets don't tell us anything useful about the client
+        _ => None,
+    };
+    body = &body[addrs_len..];
+
+    let mut tlvs = Vec::new();
+    while !body.is_empty() {
+        let [kind, len_hi, len_lo, ..] = *body else {
+            return Err(ProxyHeaderError::Malformed("truncated TLV"));
+        };
+        let value_len = u16::from_be_bytes([len_hi, len_lo]) as usize;
+        let Some(value) = body.get(3..3 + value_len) else {
+            return Err(ProxyHeaderError::Malformed("truncated TLV"));
+        };
+        tlvs.push((kind, value.to_vec()));
+        body = &body[3 + value_len..];
+    }
+
+    Ok(Parsed::Header {
+        header: ProxyHeader { addrs, tlvs },
+        len,
+    })
+}
+
+/// Reads the PROXY header at the start of the connection, if `mode` lets
+/// it have one, and skips it in `client_buf`: the bytes read past it stay
+/// there for the server. Version 2 headers sent with the `LOCAL` command
+/// come back without addresses.
+pub async fn read_header(
+    mode: ProxyProtocolMode,
+    mut client_buf: RollMut,
+    transport_r: &mut impl ReadOwned,
+) -> (Result<Option<ProxyHeader>, ReadHeaderError>, RollMut) {
+    if mode == ProxyProtocolMode::Off {
+        return (Ok(None), client_buf);
+    }
+
+    loop {
+        match parse_header(&client_buf[..]) {
+            Ok(Parsed::Header { header, len }) => {
+                client_buf.skip(len);
+                return (Ok(Some(header)), client_buf);
+            }
+            Ok(Parsed::NotProxy) => {
+                let res = match mode {
+                    ProxyProtocolMode::Require => Err(ReadHeaderError::Missing),
+                    _ => Ok(None),
+                };
+                return (res, client_buf);
+            }
+            Ok(Parsed::Incomplete) => {}
+            Err(e) => return (Err(e.into()), client_buf),
+        }
+
+        if client_buf.cap() == 0 {
+            if let Err(e) = client_buf.reserve() {
+                return (Err(e.into()), client_buf);
+            }
+        }
+        let res;
+        (res, client_buf) = client_buf.read_into(usize::MAX, transport_r).await;
+        match res {
+            Ok(0) => {
+                let res = match mode {
+                    // it's for the server to tell the client went away
+                    ProxyProtocolMode::Optional if client_buf.is_empty() => Ok(None),
+                    _ => Err(ReadHeaderError::Eof),
+                };
+                return (res, client_buf);
+            }
+            Ok(_) => {}
+            Err(e) => return (Err(e.into()), client_buf),
+        }
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// Callers check that `value` fits in a u16 length
+fn put_tlv(out: &mut Vec<u8>, kind: u8, value: &[u8]) {
+    out.push(kind);
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use buffet::{RollMut, WriteOwned};
+
+    use super::{
+        parse_header, read_header, tlv, Parsed, ProxyHeader, ProxyHeaderError, ProxyProtocolMode,
+        ReadHeaderError, V2_SIGNATURE,
+    };
+    use crate::{ConnInfo, TlsInfo};
+
+    #[test]
+    fn test_encode_v2_ipv4() {
+        let header = ProxyHeader {
+            addrs: Some((
+                "192.0.2.1:56324".parse().unwrap(),
+                "198.51.100.7:443".parse().unwrap(),
+            )),
+            tlvs: vec![],
+        };
+        let out = header.encode_v2().unwrap();
+        assert_eq!(&out[..12], &V2_SIGNATURE);
+        assert_eq!(
+            &out[12..],
+            &[
+                0x21, 0x11, 0, 12, // version/command, family, length
+                192, 0, 2, 1, 198, 51, 100, 7, // addresses
+                0xdc, 0x04, 0x01, 0xbb, // ports
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_v2_mixed_families() {
+        let header = ProxyHeader {
+            addrs: Some((
+                "192.0.2.1:1234".parse().unwrap(),
+                "[::1]:80".parse().unwrap(),
+            )),
+            tlvs: vec![],
+        };
+        let out = header.encode_v2().unwrap();
+        assert_eq!(&out[12..16], &[0x21, 0x21, 0, 36]);
+        // IPv4-mapped
+        assert_eq!(
+            &out[16..32],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 192, 0, 2, 1]
+        );
+        assert_eq!(out[47], 1);
+        assert_eq!(&out[48..], &[0x04, 0xd2, 0, 80]);
+    }
+
+    #[test]
+    fn test_encode_v2_unspec_with_tlvs() {
+        let header = ProxyHeader {
+            addrs: None,
+            tlvs: vec![(tlv::ALPN, b"h2".to_vec())],
+        };
+        let out = header.encode_v2().unwrap();
+        assert_eq!(&out[12..], &[0x21, 0x00, 0, 5, 0x01, 0, 2, b'h', b'2']);
+
+        let header = ProxyHeader {
+            addrs: None,
+            tlvs: vec![(tlv::UNIQUE_ID, vec![0; 70000])],
+        };
+        assert!(matches!(
+            header.encode_v2(),
+            Err(ProxyHeaderError::TlvTooLong { len: 70000, .. })
+        ));
+    }
+
+    #[test]
+    fn test_from_conn_info() {
+        let conn = ConnInfo {
+            id: 42,
+            peer_addr: Some("192.0.2.1:1234".parse().unwrap()),
+            local_addr: Some("198.51.100.7:443".parse().unwrap()),
+            alpn_protocol: Some(b"h2".to_vec()),
+            tls: Some(TlsInfo {
+                server_name: Some("example.org".into()),
+                protocol_version: Some("TLSv1_3".into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let header = ProxyHeader::from_conn_info(&conn);
+        assert_eq!(header.addrs, conn.peer_addr.zip(conn.local_addr));
+        assert_eq!(
+            header.tlvs,
+            vec![
+                (tlv::ALPN, b"h2".to_vec()),
+                (tlv::AUTHORITY, b"example.org".to_vec()),
+                (tlv::UNIQUE_ID, b"42".to_vec()),
+                (
+                    tlv::SSL,
+                    [&[1, 0, 0, 0, 0, tlv::SSL_VERSION, 0, 7][..], b"TLSv1_3"].concat()
+                ),
+            ]
+        );
+
+        // no addresses, no TLS
+        let header = ProxyHeader::from_conn_info(&ConnInfo::default());
+        assert_eq!(header, ProxyHeader::default());
+    }
+
+    fn header(buf: &[u8]) -> (ProxyHeader, usize) {
+        match parse_header(buf).unwrap() {
+            Parsed::Header { header, len } => (header, len),
+            other => panic!("expected a header, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_v1() {
+        let (h, len) = header(b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\nGET /");
+        assert_eq!(len, 45);
+        assert_eq!(
+            h.addrs,
+            Some((
+                "192.0.2.1:56324".parse().unwrap(),
+                "198.51.100.7:443".parse().unwrap()
+            ))
+        );
+
+        let (h, _) = header(b"PROXY TCP6 2001:db8::1 ::1 1234 80\r\n");
+        assert_eq!(h.addrs.unwrap().0, "[2001:db8::1]:1234".parse().unwrap());
+
+        let (h, len) = header(b"PROXY UNKNOWN whatever\r\n");
+        assert_eq!((h, len), (ProxyHeader::default(), 24));
+
+        for partial in [
+            &b""[..],
+            b"PRO",
+            b"PROXY TCP4 192.0.2.1",
+            b"PROXY UNKNOWN\r",
+        ] {
+            assert_eq!(parse_header(partial).unwrap(), Parsed::Incomplete);
+        }
+        assert_eq!(parse_header(b"GET / HTTP/1.1").unwrap(), Parsed::NotProxy);
+        assert_eq!(parse_header(b"PROXYZ").unwrap(), Parsed::NotProxy);
+
+        for bad in [
+            &b"PROXY TCP4 192.0.2.1 ::1 1 2\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 1\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 1 2 3\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 +1 2\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 1 65536\r\n",
+            b"PROXY UDP4 192.0.2.1 192.0.2.2 1 2\r\n",
+            &[b"PROXY UNKNOWN ".as_slice(), &[b'a'; 100]].concat(),
+        ] {
+            assert!(
+                matches!(parse_header(bad), Err(ProxyHeaderError::Malformed(_))),
+                "{:?}",
+                String::from_utf8_lossy(bad)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let sent = ProxyHeader {
+            addrs: Some((
+                "[2001:db8::1]:1234".parse().unwrap(),
+                "[::1]:443".parse().unwrap(),
+            )),
+            tlvs: vec![(tlv::ALPN, b"h2".to_vec()), (tlv::UNIQUE_ID, vec![])],
+        };
+        let mut buf = sent.encode_v2().unwrap();
+        let encoded_len = buf.len();
+        buf.extend_from_slice(b"\x16\x03\x01");
+        assert_eq!(header(&buf), (sent, encoded_len));
+
+        let sent = ProxyHeader {
+            addrs: Some((
+                "192.0.2.1:56324".parse().unwrap(),
+                "198.51.100.7:443".parse().unwrap(),
+            )),
+            tlvs: vec![],
+        };
+        let buf = sent.encode_v2().unwrap();
+        for len in 0..buf.len() {
+            assert_eq!(parse_header(&buf[..len]).unwrap(), Parsed::Incomplete);
+        }
+        assert_eq!(header(&buf).0, sent);
+
+        // LOCAL: the proxy's own connection, addresses are ignored
+        let mut local = buf.clone();
+        local[12] = 0x20;
+        assert_eq!(header(&local), (ProxyHeader::default(), buf.len()));
+
+        let mut bad_version = buf.clone();
+        bad_version[12] = 0x31;
+        assert!(parse_header(&bad_version).is_err());
+
+        // one byte short of a TLV header
+        let mut truncated = buf.clone();
+        truncated[15] += 2;
+        truncated.extend_from_slice(&[tlv::ALPN, 0]);
+        assert!(matches!(
+            parse_header(&truncated),
+            Err(ProxyHeaderError::Malformed("truncated TLV"))
+        ));
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut conn = ConnInfo {
+            peer_addr: Some("10.0.0.2:40000".parse().unwrap()),
+            local_addr: Some("10.0.0.3:80".parse().unwrap()),
+            ..Default::default()
+        };
+        ProxyHeader::default().apply(&mut conn);
+        assert_eq!(conn.proxied_by, None);
+
+        let header = ProxyHeader {
+            addrs: Some((
+                "192.0.2.1:56324".parse().unwrap(),
+                "198.51.100.7:443".parse().unwrap(),
+            )),
+            tlvs: vec![],
+        };
+        header.apply(&mut conn);
+        assert_eq!(conn.proxied_by, Some("10.0.0.2:40000".parse().unwrap()));
+        assert_eq!(conn.peer_addr.zip(conn.local_addr), header.addrs);
+    }
+
+    #[test]
+    fn test_read_header() {
+        async fn read(
+            mode: ProxyProtocolMode,
+            input: &'static [u8],
+        ) -> (Result<Option<ProxyHeader>, ReadHeaderError>, Vec<u8>) {
+            let (mut tx, mut rx) = buffet::pipe();
+            buffet::spawn(async move {
+                // a byte at a time, so the header spans reads
+                for &b in input {
+                    if tx.write_all_owned(vec![b]).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            let (res, client_buf) = read_header(mode, RollMut::alloc().unwrap(), &mut rx).await;
+            (res, client_buf[..].to_vec())
+        }
+
+        buffet::start(async move {
+            let input = b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\nG";
+            let (res, rest) = read(ProxyProtocolMode::Require, input).await;
+            assert!(res.unwrap().unwrap().addrs.is_some());
+            // it stops reading right after the header
+            assert!(rest.is_empty());
+
+            let (res, rest) = read(ProxyProtocolMode::Optional, b"GET /").await;
+            assert!(res.unwrap().is_none());
+            assert_eq!(rest, b"G");
+
+            let (res, _) = read(ProxyProtocolMode::Require, b"GET /").await;
+            assert!(matches!(res, Err(ReadHeaderError::Missing)));
+
+            let (res, rest) = read(ProxyProtocolMode::Off, input).await;
+            assert!(res.unwrap().is_none());
+            assert!(rest.is_empty());
+
+            let (res, _) = read(ProxyProtocolMode::Optional, b"PROXY TCP4").await;
+            assert!(matches!(res, Err(ReadHeaderError::Eof)));
+            let (res, _) = read(ProxyProtocolMode::Optional, b"").await;
+            assert!(res.unwrap().is_none());
+        });
+    }
+}
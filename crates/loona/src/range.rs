@@ -0,0 +1,569 @@
+//! Range requests, cf. <https://httpwg.org/specs/rfc9110.html#range.requests>
+//!
+//! [respond] does everything: it looks at the request's `range` header and
+//! writes a 200, 206 (single or `multipart/byteranges`) or 416 response for
+//! the given [RangeSource]. The building blocks are public too, for drivers
+//! that need to do something in-between.
+//!
+//! `if-range` is not evaluated here: drivers that support validators should
+//! drop the `range` header themselves when it doesn't match.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    hash::{BuildHasher, Hasher},
+};
+
+use buffet::Piece;
+use http::{header, StatusCode};
+
+use crate::{
+    error::NeverError, Body, BodyChunk, Encoder, ExpectResponseHeaders, FileBody, Headers, Method,
+    Request, Responder, ResponderOrBodyError, Response, ResponseDone, SinglePieceBody,
+};
+
+/// How many ranges we're willing to serve in a single response. Requests
+/// with more than that get the full representation instead, and so do
+/// requests whose ranges add up to more than the representation, cf.
+/// <https://httpwg.org/specs/rfc9110.html#overlapping.ranges>
+pub const MAX_RANGES: usize = 32;
+
+/// One element of a `range: bytes=...` header, before it's resolved against
+/// the representation's length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeSpec {
+    /// `first-last`, both inclusive
+    FromTo(u64, u64),
+    /// `first-`
+    From(u64),
+    /// `-suffix_length`: the last `suffix_length` bytes
+    Suffix(u64),
+}
+
+/// A satisfiable range, resolved against a representation's length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    /// First byte, inclusive
+    pub start: u64,
+    /// Last byte, exclusive
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// The value of the `content-range` header for this range
+    pub fn content_range(&self, complete_len: u64) -> String {
+        format!("bytes {}-{}/{complete_len}", self.start, self.end - 1)
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RangeParseError {
+    /// The range unit isn't `bytes`
+    #[error("unsupported range unit")]
+    UnsupportedUnit,
+
+    /// The header isn't a valid `ranges-specifier`
+    #[error("invalid range specifier")]
+    Invalid,
+}
+
+/// Parses the value of a `range` header, cf. <https://httpwg.org/specs/rfc9110.html#field.range>
+pub fn parse_range_header(value: &[u8]) -> Result<Vec<RangeSpec>, RangeParseError> {
+    let value = std::str::from_utf8(value).map_err(|_| RangeParseError::Invalid)?;
+    let (unit, set) = value.split_once('=').ok_or(RangeParseError::Invalid)?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Err(RangeParseError::UnsupportedUnit);
+    }
+
+    let mut specs = Vec::new();
+    // empty list elements are allowed, cf. https://httpwg.org/specs/rfc9110.html#abnf.extension
+    for spec in set.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (first, last) = spec.split_once('-').ok_or(RangeParseError::Invalid)?;
+        let spec = match (parse_digits(first), parse_digits(last)) {
+            (Some(first), Some(last)) if first <= last => RangeSpec::FromTo(first, last),
+            (Some(first), None) if last.is_empty() => RangeSpec::From(first),
+            (None, Some(suffix)) if first.is_empty() => RangeSpec::Suffix(suffix),
+            _ => return Err(RangeParseError::Invalid),
+        };
+        specs.push(spec);
+    }
+
+    if specs.is_empty() {
+        return Err(RangeParseError::Invalid);
+    }
+    Ok(specs)
+}
+
+fn parse_digits(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Resolves range specs against a representation of `complete_len` bytes,
+/// dropping the unsatisfiable ones, and merging the ones that overlap or are
+/// adjacent. The result is sorted, and empty if none are satisfiable.
+pub fn resolve(specs: &[RangeSpec], complete_len: u64) -> Vec<ByteRange> {
+    coalesce(resolve_each(specs, complete_len).collect())
+}
+
+fn resolve_each(specs: &[RangeSpec], complete_len: u64) -> impl Iterator<Item = ByteRange> + '_ {
+    specs.iter().filter_map(move |spec| {
+        let (start, end) = match *spec {
+            RangeSpec::FromTo(first, last) => (first, last.saturating_add(1).min(complete_len)),
+            RangeSpec::From(first) => (first, complete_len),
+            RangeSpec::Suffix(suffix) => (complete_len.saturating_sub(suffix), complete_len),
+        };
+        (start < end).then_some(ByteRange { start, end })
+    })
+}
+
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_unstable_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// What to do about a request's `range` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeOutcome {
+    /// Serve the full representation, with a 200. This is also what happens
+    /// when the `range` header is missing, malformed, or not applicable.
+    Full,
+    /// Serve these ranges, with a 206
+    Partial(Vec<ByteRange>),
+    /// None of the ranges are satisfiable: 416
+    Unsatisfiable,
+}
+
+/// Decides how to answer `req` for a representation of `complete_len` bytes.
+pub fn evaluate(req: &Request, complete_len: u64) -> RangeOutcome {
+    // range handling is only defined for GET, cf. https://httpwg.org/specs/rfc9110.html#field.range
+    if req.method != Method::Get {
+        return RangeOutcome::Full;
+    }
+    let Some(value) = req.headers.get(header::RANGE) else {
+        return RangeOutcome::Full;
+    };
+    let Ok(specs) = parse_range_header(value) else {
+        return RangeOutcome::Full;
+    };
+    if specs.len() > MAX_RANGES {
+        return RangeOutcome::Full;
+    }
+
+    let ranges: Vec<_> = resolve_each(&specs, complete_len).collect();
+    let requested = ranges
+        .iter()
+        .fold(0u64, |sum, r| sum.saturating_add(r.len()));
+    if requested > complete_len {
+        // overlapping ranges: asking for more than there is isn't worth a
+        // multipart response, cf. https://httpwg.org/specs/rfc9110.html#overlapping.ranges
+        return RangeOutcome::Full;
+    }
+
+    let ranges = coalesce(ranges);
+    if ranges.is_empty() {
+        RangeOutcome::Unsatisfiable
+    } else {
+        RangeOutcome::Partial(ranges)
+    }
+}
+
+/// Something we can take byte ranges of
+pub trait RangeSource {
+    type Body: Body;
+
+    /// Length of the full representation
+    fn complete_len(&self) -> u64;
+
+    /// A body for the given range, which is within `0..complete_len()`
+    fn range_body(&self, range: ByteRange) -> Self::Body;
+}
+
+impl RangeSource for Piece {
+    type Body = SinglePieceBody;
+
+    fn complete_len(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn range_body(&self, range: ByteRange) -> Self::Body {
+        let (_, rest) = self.clone().split_at(range.start as usize);
+        let (piece, _) = rest.split_at(range.len() as usize);
+        piece.into()
+    }
+}
+
+impl RangeSource for FileBody {
+    type Body = FileBody;
+
+    fn complete_len(&self) -> u64 {
+        self.content_len().unwrap_or_default()
+    }
+
+    fn range_body(&self, range: ByteRange) -> Self::Body {
+        self.clone()
+            .with_range(range.start..range.end)
+            .expect("ranges are resolved against the file's length")
+    }
+}
+
+/// A `multipart/byteranges` body, cf. <https://httpwg.org/specs/rfc9110.html#multipart.byteranges>
+pub struct MultipartByteRanges<B> {
+    parts: VecDeque<(Piece, B)>,
+    current: Option<B>,
+    trailer: Option<Piece>,
+    content_len: u64,
+}
+
+impl<B> fmt::Debug for MultipartByteRanges<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MultipartByteRanges")
+            .field("parts_remaining", &self.parts.len())
+            .field("content_len", &self.content_len)
+            .finish()
+    }
+}
+
+impl<B: Body> MultipartByteRanges<B> {
+    /// Builds a body with one part per range. `content_type` is the content
+    /// type of the full representation, if any.
+    pub fn new<S>(
+        source: &S,
+        ranges: &[ByteRange],
+        content_type: Option<&[u8]>,
+        boundary: &str,
+    ) -> Self
+    where
+        S: RangeSource<Body = B>,
+    {
+        let complete_len = source.complete_len();
+        let mut content_len = 0;
+        let mut parts = VecDeque::with_capacity(ranges.len());
+
+        for (i, range) in ranges.iter().enumerate() {
+            let mut header = Vec::new();
+            if i > 0 {
+                header.extend_from_slice(b"\r\n");
+            }
+            header.extend_from_slice(format!("--{boundary}\r\n").as_bytes());
+            if let Some(content_type) = content_type {
+                header.extend_from_slice(b"content-type: ");
+                header.extend_from_slice(content_type);
+                header.extend_from_slice(b"\r\n");
+            }
+            header.extend_from_slice(
+                format!(
+                    "content-range: {}\r\n\r\n",
+                    range.content_range(complete_len)
+                )
+                .as_bytes(),
+            );
+
+            content_len += header.len() as u64 + range.len();
+            parts.push_back((header.into(), source.range_body(*range)));
+        }
+
+        let trailer: Piece = format!("\r\n--{boundary}--\r\n").into_bytes().into();
+        content_len += trailer.len() as u64;
+
+        Self {
+            parts,
+            current: None,
+            trailer: Some(trailer),
+            content_len,
+        }
+    }
+}
+
+impl<B: Body> Body for MultipartByteRanges<B> {
+    type Error = B::Error;
+
+    fn content_len(&self) -> Option<u64> {
+        Some(self.content_len)
+    }
+
+    fn eof(&self) -> bool {
+        self.trailer.is_none()
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        if let Some(current) = self.current.as_mut() {
+            match current.next_chunk().await? {
+                BodyChunk::Done { .. } => {
+                    self.current = None;
+                }
+                chunk => return Ok(chunk),
+            }
+        }
+
+        if let Some((header, body)) = self.parts.pop_front() {
+            self.current = Some(body);
+            return Ok(BodyChunk::Chunk(header));
+        }
+
+        Ok(match self.trailer.take() {
+            Some(trailer) => BodyChunk::Chunk(trailer),
+            None => BodyChunk::Done { trailers: None },
+        })
+    }
+}
+
+/// Generates a multipart boundary that's very unlikely to appear in a body
+pub fn generate_boundary() -> String {
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u64(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64,
+    );
+    format!("loona-{:016x}", hasher.finish())
+}
+
+/// Writes a 200, 206 or 416 response for `source`, depending on `req`'s
+/// `range` header. `res` is the response we'd send for the full
+/// representation: its status is overwritten, `content-length`,
+/// `content-range` and `accept-ranges` are set as needed.
+pub async fn respond<E, S>(
+    respond: Responder<E, ExpectResponseHeaders>,
+    req: &Request,
+    mut res: Response,
+    source: &S,
+) -> Result<Responder<E, ResponseDone>, ResponderOrBodyError<E::Error, <S::Body as Body>::Error>>
+where
+    E: Encoder,
+    S: RangeSource,
+{
+    let complete_len = source.complete_len();
+    res.headers.remove(header::CONTENT_LENGTH);
+    res.headers
+        .insert(header::ACCEPT_RANGES, Piece::from("bytes"));
+
+    match evaluate(req, complete_len) {
+        RangeOutcome::Full => {
+            res.status = StatusCode::OK;
+            let mut body = source.range_body(ByteRange {
+                start: 0,
+                end: complete_len,
+            });
+            respond.write_final_response_with_body(res, &mut body).await
+        }
+        RangeOutcome::Partial(ranges) => {
+            res.status = StatusCode::PARTIAL_CONTENT;
+            if let [range] = &ranges[..] {
+                res.headers.insert(
+                    header::CONTENT_RANGE,
+                    range.content_range(complete_len).into_bytes().into(),
+                );
+                let mut body = source.range_body(*range);
+                respond.write_final_response_with_body(res, &mut body).await
+            } else {
+                let boundary = generate_boundary();
+                let content_type = res.headers.remove(header::CONTENT_TYPE);
+                let mut body =
+                    MultipartByteRanges::new(source, &ranges, content_type.as_deref(), &boundary);
+                res.headers.insert(
+                    header::CONTENT_TYPE,
+                    format!("multipart/byteranges; boundary={boundary}")
+                        .into_bytes()
+                        .into(),
+                );
+                respond.write_final_response_with_body(res, &mut body).await
+            }
+        }
+        RangeOutcome::Unsatisfiable => {
+            let res = unsatisfiable_response(res.headers, complete_len);
+            respond
+                .write_final_response_with_body(res, &mut ())
+                .await
+                .map_err(|e| match e {
+                    ResponderOrBodyError::Responder(e) => ResponderOrBodyError::Responder(e),
+                    ResponderOrBodyError::Body(NeverError) => unreachable!(),
+                })
+        }
+    }
+}
+
+/// A 416 response, with the `content-range` header clients need to figure out
+/// what they can ask for instead.
+pub fn unsatisfiable_response(mut headers: Headers, complete_len: u64) -> Response {
+    headers.remove(header::CONTENT_TYPE);
+    headers.insert(
+        header::CONTENT_RANGE,
+        format!("bytes */{complete_len}").into_bytes().into(),
+    );
+    Response {
+        status: StatusCode::RANGE_NOT_SATISFIABLE,
+        headers,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range_header() {
+        use RangeSpec::*;
+
+        assert_eq!(parse_range_header(b"bytes=0-499"), Ok(vec![FromTo(0, 499)]));
+        assert_eq!(
+            parse_range_header(b"bytes=0-0, -1 ,9500-,,"),
+            Ok(vec![FromTo(0, 0), Suffix(1), From(9500)])
+        );
+        assert_eq!(parse_range_header(b"Bytes=1-2"), Ok(vec![FromTo(1, 2)]));
+        assert_eq!(
+            parse_range_header(b"items=0-1"),
+            Err(RangeParseError::UnsupportedUnit)
+        );
+        for invalid in [
+            &b"bytes=2-1"[..],
+            b"bytes=",
+            b"bytes=-",
+            b"bytes=1",
+            b"bytes=a-b",
+            b"bytes=+1-2",
+            b"bytes=1-2-3",
+            b"0-1",
+        ] {
+            assert_eq!(
+                parse_range_header(invalid),
+                Err(RangeParseError::Invalid),
+                "{:?}",
+                std::str::from_utf8(invalid)
+            );
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        use RangeSpec::*;
+
+        let r = |start, end| ByteRange { start, end };
+        assert_eq!(
+            resolve(&[FromTo(0, 9), FromTo(5, 1000), From(95), Suffix(10)], 100),
+            vec![r(0, 100)]
+        );
+        assert_eq!(
+            resolve(
+                &[Suffix(10), FromTo(0, 9), FromTo(20, 29), FromTo(10, 14)],
+                100
+            ),
+            vec![r(0, 15), r(20, 30), r(90, 100)]
+        );
+        assert_eq!(resolve(&[Suffix(1000)], 100), vec![r(0, 100)]);
+        assert_eq!(
+            resolve(&[FromTo(100, 200), From(100), Suffix(0)], 100),
+            vec![]
+        );
+        assert_eq!(resolve(&[Suffix(1)], 0), vec![]);
+        assert_eq!(r(0, 10).content_range(100), "bytes 0-9/100");
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut req = Request::default();
+        assert_eq!(evaluate(&req, 100), RangeOutcome::Full);
+
+        req.headers.insert(header::RANGE, "bytes=0-9".into());
+        assert_eq!(
+            evaluate(&req, 100),
+            RangeOutcome::Partial(vec![ByteRange { start: 0, end: 10 }])
+        );
+        assert_eq!(evaluate(&req, 0), RangeOutcome::Unsatisfiable);
+
+        req.method = Method::Head;
+        assert_eq!(evaluate(&req, 100), RangeOutcome::Full);
+
+        req.method = Method::Get;
+        req.headers.insert(header::RANGE, "bytes=nope".into());
+        assert_eq!(evaluate(&req, 100), RangeOutcome::Full);
+
+        // overlapping ranges are merged, unless they add up to more than
+        // the whole representation
+        req.headers
+            .insert(header::RANGE, "bytes=50-59,0-9,5-14".into());
+        assert_eq!(
+            evaluate(&req, 100),
+            RangeOutcome::Partial(vec![
+                ByteRange { start: 0, end: 15 },
+                ByteRange { start: 50, end: 60 }
+            ])
+        );
+        let amplified = vec!["0-"; MAX_RANGES].join(",");
+        req.headers.insert(
+            header::RANGE,
+            format!("bytes={amplified}").into_bytes().into(),
+        );
+        assert_eq!(evaluate(&req, 100), RangeOutcome::Full);
+        req.headers.insert(header::RANGE, "bytes=0-,0-".into());
+        assert_eq!(evaluate(&req, 100), RangeOutcome::Full);
+    }
+
+    mod multipart_byteranges_body {
+        use super::*;
+
+        crate::body_test_suite!(|| {
+            let ranges = [
+                ByteRange { start: 0, end: 2 },
+                ByteRange { start: 4, end: 10 },
+            ];
+            MultipartByteRanges::new(&Piece::from("0123456789"), &ranges, None, "BOUNDARY")
+        });
+    }
+
+    #[test]
+    fn test_multipart_byteranges() {
+        buffet::start(async move {
+            let source = Piece::from("0123456789");
+            let ranges = [
+                ByteRange { start: 0, end: 2 },
+                ByteRange { start: 8, end: 10 },
+            ];
+            let mut body =
+                MultipartByteRanges::new(&source, &ranges, Some(b"text/plain"), "BOUNDARY");
+
+            let mut out = Vec::new();
+            loop {
+                match body.next_chunk().await.unwrap() {
+                    BodyChunk::Chunk(chunk) => out.extend_from_slice(&chunk[..]),
+                    BodyChunk::File { .. } => unreachable!(),
+                    BodyChunk::Done { .. } => break,
+                }
+            }
+            assert!(body.eof());
+            assert_eq!(body.content_len(), Some(out.len() as u64));
+            assert_eq!(
+                std::str::from_utf8(&out).unwrap(),
+                "--BOUNDARY\r\n\
+                 content-type: text/plain\r\n\
+                 content-range: bytes 0-1/10\r\n\r\n\
+                 01\r\n\
+                 --BOUNDARY\r\n\
+                 content-type: text/plain\r\n\
+                 content-range: bytes 8-9/10\r\n\r\n\
+                 89\r\n\
+                 --BOUNDARY--\r\n"
+            );
+        });
+    }
+}
@@ -0,0 +1,310 @@
+//! Request rate limiting, with a token bucket per key (a client address, an
+//! API key...): requests over the budget get a 429 with `retry-after`
+//! instead of reaching the driver.
+//!
+//! [RateLimitDriver] wraps another [ServerDriver], and shows how drivers
+//! compose: it answers some requests itself and hands the others down,
+//! responder and all. Its [RateLimiter] can be shared between the drivers
+//! of every connection on a thread:
+//!
+//! ```ignore
+//! let limiter = Rc::new(RateLimiter::new(RateLimitConf::default()));
+//! let driver = RateLimitDriver::new(MyApp, limiter.clone(), rate_limit::peer_ip);
+//! h1::serve(io, conf, client_buf, driver).await?;
+//! ```
+//!
+//! Behind proxies, key requests by what [crate::forwarded::resolve_request]
+//! makes of them instead.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use b_x::BX;
+use buffet::Piece;
+use http::{header, HeaderName, StatusCode};
+
+use crate::{
+    Body, BodyChunk, Encoder, ExpectResponseHeaders, Request, Responder, Response, ResponseDone,
+    ServerDriver, SinglePieceBody,
+};
+
+/// How many requests each key gets
+#[derive(Debug, Clone)]
+pub struct RateLimitConf {
+    /// On average
+    pub requests_per_sec: f64,
+
+    /// In a row, after a quiet period
+    pub burst: u32,
+
+    /// How many keys to keep buckets for. Past that, buckets that are full
+    /// again are forgotten (we look for them at most once a second), and
+    /// if all of them are in use, requests with new keys are limited.
+    pub max_keys: usize,
+}
+
+impl Default for RateLimitConf {
+    fn default() -> Self {
+        Self {
+            requests_per_sec: 10.0,
+            burst: 20,
+            max_keys: 100_000,
+        }
+    }
+}
+
+/// How often a full [RateLimiter] looks for buckets to forget, at most
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The token buckets of every key, cf. the [module docs](self)
+pub struct RateLimiter<K> {
+    conf: RateLimitConf,
+    buckets: RefCell<HashMap<K, Bucket>>,
+
+    /// When we may next look for buckets to forget, cf.
+    /// [RateLimitConf::max_keys]
+    next_sweep: Cell<Instant>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(conf: RateLimitConf) -> Self {
+        Self {
+            conf,
+            buckets: Default::default(),
+            next_sweep: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Takes a token from `key`'s bucket, or returns how long until there's
+    /// one
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        let (rate, burst) = (self.conf.requests_per_sec, self.conf.burst.max(1) as f64);
+        let now = Instant::now();
+        let mut buckets = self.buckets.borrow_mut();
+
+        if !buckets.contains_key(&key) && buckets.len() >= self.conf.max_keys {
+            let next_sweep = self.next_sweep.get();
+            if now < next_sweep {
+                return Err(next_sweep - now);
+            }
+            buckets.retain(|_, bucket| bucket.refill(now, rate, burst) < burst);
+            if buckets.len() >= self.conf.max_keys {
+                // the earliest a drained bucket is full again, but no less
+                // than once a second
+                let interval = if rate > 0.0 {
+                    Duration::from_secs_f64(burst / rate).min(SWEEP_INTERVAL)
+                } else {
+                    SWEEP_INTERVAL
+                };
+                self.next_sweep.set(now + interval);
+                return Err(interval);
+            }
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            refilled_at: now,
+        });
+        let tokens = bucket.refill(now, rate, burst);
+        if tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        if rate <= 0.0 {
+            // the burst is all there is
+            return Err(Duration::MAX);
+        }
+        Err(Duration::from_secs_f64((1.0 - tokens) / rate))
+    }
+
+    /// How many keys have a bucket
+    pub fn len(&self) -> usize {
+        self.buckets.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.borrow().is_empty()
+    }
+}
+
+impl Bucket {
+    /// Adds the tokens earned since the last refill, returns how many there
+    /// are now
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) -> f64 {
+        let earned = (now - self.refilled_at).as_secs_f64() * rate.max(0.0);
+        self.tokens = (self.tokens + earned).min(burst);
+        self.refilled_at = now;
+        self.tokens
+    }
+}
+
+/// Keys requests by the address of the client they came from, cf.
+/// [crate::ConnInfo::peer_addr]
+pub fn peer_ip(req: &Request) -> Option<IpAddr> {
+    req.conn.as_ref()?.peer_addr.map(|addr| addr.ip())
+}
+
+/// Keys requests by the value of their `name` header, e.g. an API key.
+/// Requests without one aren't limited.
+pub fn header_value(name: HeaderName) -> impl Fn(&Request) -> Option<Piece> {
+    move |req| req.headers.get(&name).cloned()
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum RateLimitError<DriverError> {
+    #[error(transparent)]
+    Driver(DriverError),
+
+    /// Sending the 429, or draining the request body before that
+    #[error("rejecting rate-limited request: {0}")]
+    Reject(BX),
+}
+
+/// A [ServerDriver] that answers requests over their key's budget with a
+/// 429, and passes the others on to the driver it wraps. Requests `key`
+/// returns `None` for aren't limited.
+pub struct RateLimitDriver<D, K, F> {
+    inner: D,
+    limiter: Rc<RateLimiter<K>>,
+    key: F,
+}
+
+impl<D, K, F> RateLimitDriver<D, K, F> {
+    pub fn new(inner: D, limiter: Rc<RateLimiter<K>>, key: F) -> Self {
+        Self {
+            inner,
+            limiter,
+            key,
+        }
+    }
+}
+
+impl<E, D, K, F> ServerDriver<E> for RateLimitDriver<D, K, F>
+where
+    E: Encoder,
+    D: ServerDriver<E>,
+    K: Hash + Eq,
+    F: Fn(&Request) -> Option<K>,
+{
+    type Error = RateLimitError<D::Error>;
+
+    async fn handle(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> Result<Responder<E, ResponseDone>, Self::Error> {
+        let Some(retry_after) = (self.key)(&req).and_then(|key| self.limiter.check(key).err())
+        else {
+            return self
+                .inner
+                .handle(req, req_body, respond)
+                .await
+                .map_err(RateLimitError::Driver);
+        };
+
+        // the body must be drained before the connection can go on
+        loop {
+            match req_body
+                .next_chunk()
+                .await
+                .map_err(|e| RateLimitError::Reject(BX::from_err(e)))?
+            {
+                BodyChunk::Done { .. } => break,
+                BodyChunk::Chunk(_) | BodyChunk::File { .. } => {}
+            }
+        }
+
+        // whole seconds, rounded up
+        let secs = retry_after
+            .as_secs()
+            .saturating_add(u64::from(retry_after.subsec_nanos() > 0));
+        let mut res = Response {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            ..Default::default()
+        };
+        res.headers.insert(
+            header::RETRY_AFTER,
+            Piece::from_fmt(format_args!("{}", secs.max(1))),
+        );
+        let mut body = SinglePieceBody::from(
+            StatusCode::TOO_MANY_REQUESTS
+                .canonical_reason()
+                .unwrap_or_default(),
+        );
+        respond
+            .write_final_response_with_body(res, &mut body)
+            .await
+            .map_err(|e| RateLimitError::Reject(BX::from_err(e)))
+    }
+
+    fn origins(&self) -> Option<Vec<String>> {
+        self.inner.origins()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{RateLimitConf, RateLimiter};
+
+    #[test]
+    fn test_token_buckets() {
+        let limiter = RateLimiter::new(RateLimitConf {
+            requests_per_sec: 1.0,
+            burst: 2,
+            max_keys: 2,
+        });
+
+        assert_eq!(limiter.check("a"), Ok(()));
+        assert_eq!(limiter.check("a"), Ok(()));
+        let wait = limiter.check("a").unwrap_err();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+
+        // each key has its own bucket
+        assert_eq!(limiter.check("b"), Ok(()));
+        assert_eq!(limiter.len(), 2);
+
+        // no room for "c", "b" isn't full again yet: limited, not tracked
+        for _ in 0..3 {
+            let wait = limiter.check("c").unwrap_err();
+            assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+        }
+        assert_eq!(limiter.len(), 2);
+
+        // once buckets are full again, a sweep makes room
+        let limiter = RateLimiter::new(RateLimitConf {
+            requests_per_sec: 1000.0,
+            burst: 2,
+            max_keys: 2,
+        });
+        assert_eq!(limiter.check("a"), Ok(()));
+        assert_eq!(limiter.check("b"), Ok(()));
+        let wait = limiter.check("c").unwrap_err();
+        assert_eq!(wait, Duration::from_millis(2));
+        std::thread::sleep(wait);
+        assert_eq!(limiter.check("c"), Ok(()));
+        assert_eq!(limiter.len(), 1);
+
+        let limiter = RateLimiter::new(RateLimitConf {
+            requests_per_sec: 0.0,
+            burst: 1,
+            max_keys: 1,
+        });
+        assert_eq!(limiter.check(1), Ok(()));
+        assert_eq!(limiter.check(1), Err(Duration::MAX));
+    }
+}
@@ -1,8 +1,13 @@
+use std::{fs::File, rc::Rc};
+
 use b_x::BX;
 use buffet::Piece;
-use http::{header, StatusCode};
+use http::{header, HeaderName, StatusCode};
 
-use crate::{Body, BodyChunk, Headers, HeadersExt, Response};
+use crate::{
+    Body, BodyChunk, Headers, HeadersExt, OnComplete, Response, Timings, WireSizes,
+    FORBIDDEN_TRAILERS,
+};
 
 pub trait ResponseState {}
 
@@ -12,6 +17,8 @@ impl ResponseState for ExpectResponseHeaders {}
 pub struct ExpectResponseBody {
     pub announced_content_length: Option<u64>,
     pub bytes_written: u64,
+    /// cf. [HeadersExt::announced_trailers]
+    pub announced_trailers: Vec<HeaderName>,
 }
 impl ResponseState for ExpectResponseBody {}
 
@@ -32,6 +39,12 @@ pub enum ResponderError<EncoderError> {
     )]
     BodyLengthDoesNotMatchAnnouncedContentLength { actual: u64, expected: u64 },
 
+    #[error("trailer field {name} wasn't announced in the response's `trailer` header")]
+    UnannouncedTrailer { name: HeaderName },
+
+    #[error("{name} can't be sent as a trailer field")]
+    ForbiddenTrailer { name: HeaderName },
+
     #[error("encoder error: {0}")]
     EncoderError(#[from] EncoderError),
 }
@@ -74,6 +87,31 @@ where
         }
     }
 
+    /// Gives the encoder back, for drivers that wrap it to hand a different
+    /// responder to the driver they wrap, cf. [crate::transform]
+    pub(crate) fn into_encoder(self) -> OurEncoder {
+        self.encoder
+    }
+
+    /// Calls `callback` with how many bytes this exchange took on the wire,
+    /// and when each of its phases happened, once the response is written
+    /// and the request body is read (or the stream is reset). Replaces any
+    /// callback registered earlier.
+    pub fn on_complete(&mut self, callback: impl FnOnce(WireSizes, Timings) + 'static) {
+        self.encoder.on_complete(Box::new(callback));
+    }
+
+    /// Sends the final response's body without framing, and closes the
+    /// connection after it, for clients that can't deal with chunked
+    /// transfer coding. Any `content-length` or `transfer-encoding` the
+    /// response has is dropped, and it gets `connection: close`.
+    ///
+    /// Only HTTP/1 does that: returns false for HTTP/2, where streams end on
+    /// their own, and the response goes out as usual.
+    pub fn close_delimited(&mut self) -> bool {
+        self.encoder.close_delimited()
+    }
+
     /// Send an informational status code, cf. <https://httpwg.org/specs/rfc9110.html#status.1xx>
     /// Errors out if the response status is not 1xx
     pub async fn write_interim_response(
@@ -105,6 +143,7 @@ where
                 },
             );
         }
+        let announced_trailers = res.headers.announced_trailers();
         self.encoder
             .write_response(res)
             .await
@@ -113,6 +152,7 @@ where
             state: ExpectResponseBody {
                 announced_content_length,
                 bytes_written: 0,
+                announced_trailers,
             },
             encoder: self.encoder,
         })
@@ -131,7 +171,10 @@ where
     }
 
     /// Writes a response with the given body. Sets `content-length` or
-    /// `transfer-encoding` as needed.
+    /// `transfer-encoding` as needed, and announces the body's trailers
+    /// (cf. [Body::announced_trailers]) in the `trailer` header, unless the
+    /// response already has one. Bodies with trailers don't get a
+    /// `content-length`, so HTTP/1.1 can send them chunked.
     pub async fn write_final_response_with_body<TheirBody>(
         self,
         mut res: Response,
@@ -143,19 +186,21 @@ where
     where
         TheirBody: Body,
     {
-        if let Some(clen) = body.content_len() {
+        let trailers = body.announced_trailers();
+        if !trailers.is_empty() && !res.headers.contains_key(header::TRAILER) {
+            let names: Vec<&str> = trailers.iter().map(|name| name.as_str()).collect();
+            res.headers
+                .insert(header::TRAILER, names.join(", ").into_bytes().into());
+        }
+
+        if let Some(clen) = body.content_len().filter(|_| trailers.is_empty()) {
             res.headers
                 .entry(header::CONTENT_LENGTH)
                 .or_insert_with(|| {
-                    // TODO: can probably get rid of this heap allocation, also
-                    // use `itoa`, cf. https://docs.rs/itoa/latest/itoa/
-                    // TODO: also, for `write_final_response`, we could avoid
+                    // TODO: for `write_final_response`, we could avoid
                     // parsing the content-length header, since we have the
                     // content-length already.
-                    // TODO: also, instead of doing a heap allocation with a `Vec<u8>`, we
-                    // could probably take a tiny bit of `RollMut` which is cheaper to
-                    // allocate (or at least was, last I measured).
-                    format!("{clen}").into_bytes().into()
+                    Piece::from_fmt(format_args!("{clen}"))
                 });
         }
 
@@ -174,6 +219,16 @@ where
                     this.write_chunk(chunk)
                         .await
                         .map_err(ResponderOrBodyError::Responder)?;
+                    if body.flush_each_chunk() {
+                        this.flush()
+                            .await
+                            .map_err(ResponderOrBodyError::Responder)?;
+                    }
+                }
+                BodyChunk::File { file, offset, len } => {
+                    this.write_file(file, offset, len)
+                        .await
+                        .map_err(ResponderOrBodyError::Responder)?;
                 }
                 BodyChunk::Done { trailers } => {
                     return this
@@ -201,18 +256,73 @@ where
             .map_err(ResponderError::EncoderError)
     }
 
+    /// Send `len` bytes of `file`, starting at `offset`, as part of the body.
+    /// Depending on the encoder, this may avoid copying the file's contents
+    /// through userspace.
+    #[inline]
+    pub async fn write_file(
+        &mut self,
+        file: Rc<File>,
+        offset: u64,
+        len: u64,
+    ) -> ResponderResult<(), E::Error> {
+        self.state.bytes_written += len;
+        self.encoder
+            .write_body_file(file, offset, len)
+            .await
+            .map_err(ResponderError::EncoderError)
+    }
+
+    /// Makes sure everything written so far is on its way to the peer,
+    /// rather than held back to be sent along with later chunks. Streaming
+    /// handlers can call this when latency matters more than throughput.
+    ///
+    /// On HTTP/2, this writes the stream's pending DATA frames right away,
+    /// as far as flow control allows.
+    pub async fn flush(&mut self) -> ResponderResult<(), E::Error> {
+        self.encoder
+            .flush()
+            .await
+            .map_err(ResponderError::EncoderError)
+    }
+
+    /// Returns true if [Responder::finish_body] takes trailers that weren't
+    /// announced in the response's `trailer` header, cf.
+    /// [Encoder::accepts_trailers]
+    pub fn accepts_trailers(&self) -> bool {
+        self.encoder.accepts_trailers()
+    }
+
     /// Finish the body, with optional trailers, cf. <https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/TE>
     /// Errors out if the sent body doesn't match the announced content-length.
-    /// Errors out if trailers that weren't announced are being sent, or if the
-    /// client didn't explicitly announce it accepted trailers, or if the
-    /// response is a 204, 205 or 304, or if the body wasn't sent with
-    /// chunked transfer encoding.
+    /// Errors out if trailers that weren't announced in the response's
+    /// `trailer` header are being sent where the protocol doesn't allow it
+    /// (cf. [Responder::accepts_trailers]), or if they can't be trailers at
+    /// all (cf. [FORBIDDEN_TRAILERS]). The encoder may refuse trailers its
+    /// framing can't carry, e.g. after a body with a `content-length` on
+    /// HTTP/1.1.
+    ///
+    /// Responses to HEAD requests may skip the body altogether, whatever
+    /// their `content-length`.
     pub async fn finish_body(
         mut self,
         trailers: Option<Box<Headers>>,
     ) -> ResponderResult<Responder<E, ResponseDone>, E::Error> {
+        let accepts_trailers = self.encoder.accepts_trailers();
+        if let Some(trailers) = &trailers {
+            for name in trailers.keys() {
+                if FORBIDDEN_TRAILERS.contains(name) {
+                    return Err(ResponderError::ForbiddenTrailer { name: name.clone() });
+                }
+                if !accepts_trailers && !self.state.announced_trailers.contains(name) {
+                    return Err(ResponderError::UnannouncedTrailer { name: name.clone() });
+                }
+            }
+        }
+
+        let skipped_head_body = self.state.bytes_written == 0 && self.encoder.is_head_response();
         if let Some(announced_content_length) = self.state.announced_content_length {
-            if self.state.bytes_written != announced_content_length {
+            if self.state.bytes_written != announced_content_length && !skipped_head_body {
                 return Err(
                     ResponderError::BodyLengthDoesNotMatchAnnouncedContentLength {
                         actual: self.state.bytes_written,
@@ -226,9 +336,11 @@ where
             .await
             .map_err(ResponderError::EncoderError)?;
 
-        if let Some(trailers) = trailers {
+        // once trailers are announced (or allowed), the encoder expects them,
+        // if only to end the message
+        if accepts_trailers || !self.state.announced_trailers.is_empty() {
             self.encoder
-                .write_trailers(trailers)
+                .write_trailers(trailers.unwrap_or_default())
                 .await
                 .map_err(ResponderError::EncoderError)?;
         }
@@ -247,20 +359,115 @@ where
     pub fn into_inner(self) -> E {
         self.encoder
     }
+
+    /// The other half of [Responder::into_encoder]: `encoder` went through
+    /// a whole response under another responder.
+    pub(crate) fn done(encoder: E) -> Self {
+        Self {
+            encoder,
+            state: ResponseDone,
+        }
+    }
 }
 
 pub type ResponderResult<T, EncoderError> = Result<T, ResponderError<EncoderError>>;
 
+/// Turns responses into bytes on the wire (or frames, or events).
+///
+/// Handlers never call an encoder directly: they go through a [Responder],
+/// which enforces the ordering below. Encoders can rely on it:
+///
+///   1. zero or more calls to [Encoder::write_response] with a 1xx status
+///   2. exactly one call to [Encoder::write_response] with a final status
+///   3. any number of calls to [Encoder::write_body_chunk],
+///      [Encoder::write_body_file] and [Encoder::flush], interleaved in any
+///      order
+///   4. exactly one call to [Encoder::write_body_end]
+///   5. exactly one call to [Encoder::write_trailers] if the final response
+///      announced trailers in its `trailer` header, or if
+///      [Encoder::accepts_trailers] (possibly with no trailers at all), none
+///      otherwise
+///
+/// The sequence may stop at any point, if the handler errors out or is
+/// dropped: encoders should clean up after themselves in that case (h1 closes
+/// the connection, h2 ends or resets the stream).
+///
+/// Errors are fatal: once a method has returned an error, the encoder doesn't
+/// have to accept any more calls, and the connection (or stream) is torn down.
+///
+/// Encoders may hold on to a final response's header section and body chunks
+/// to coalesce them into fewer writes (h1 sends the header section along with
+/// the first body chunk), but only until the next call to [Encoder::flush] or
+/// [Encoder::write_body_end]: once either resolves, everything written so
+/// far must have been handed off to the transport (or to whatever task owns
+/// it).
+///
+/// The [encoder_test_suite!](crate::encoder_test_suite) macro checks that an
+/// implementation behaves like the built-in ones.
 #[allow(async_fn_in_trait)] // we never require Send
 pub trait Encoder {
     type Error: std::error::Error + 'static;
 
+    /// Writes the response status and headers. The encoder is free to add
+    /// the headers its framing requires (`transfer-encoding: chunked` for
+    /// HTTP/1.1 responses without a `content-length`, for example).
     async fn write_response(&mut self, res: Response) -> Result<(), Self::Error>;
     /// Note: encoders do not have a duty to check for matching content-length:
     /// the responder takes care of that for HTTP/1.1 and HTTP/2
     async fn write_body_chunk(&mut self, chunk: Piece) -> Result<(), Self::Error>;
+    /// Writes `len` bytes of `file`, starting at `offset`. Encoders that can't
+    /// hand the file to the kernel must read it and write it like any other
+    /// chunk, cf. [buffet::read_file_piece]
+    async fn write_body_file(
+        &mut self,
+        file: Rc<File>,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), Self::Error>;
+    /// Marks the end of the body. Also called for responses that don't have
+    /// a body (204, 304, `content-length: 0`).
     async fn write_body_end(&mut self) -> Result<(), Self::Error>;
+    /// Hands off any body data held back so far. Encoders that never hold
+    /// anything back can rely on the default, which does nothing.
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+    /// Writes trailers, after the body end. Encoders whose framing can't
+    /// carry trailers must return an error rather than drop them silently.
+    /// Only called for responses that announced trailers, or when
+    /// [Encoder::accepts_trailers], so encoders can tell from
+    /// [Encoder::write_response] how the body will end.
     async fn write_trailers(&mut self, trailers: Box<Headers>) -> Result<(), Self::Error>;
+    /// Registers a callback to call with the exchange's [WireSizes] and
+    /// [Timings] once it's over. Encoders that don't know about the wire
+    /// drop it.
+    fn on_complete(&mut self, callback: OnComplete) {
+        _ = callback;
+    }
+    /// Whether this response answers a HEAD request, cf.
+    /// <https://httpwg.org/specs/rfc9110.html#HEAD>. If so, handlers can
+    /// respond as they would to a GET: the responder still counts body bytes
+    /// against `content-length`, but the encoder keeps them off the wire.
+    /// Encoders that wrap another one must forward it.
+    fn is_head_response(&self) -> bool {
+        false
+    }
+    /// Whether the final response written so far may end with trailers it
+    /// didn't announce in its `trailer` header: h2 streams always can, and
+    /// so can chunked HTTP/1.1 bodies if the client sent `te: trailers`. If
+    /// so, [Encoder::write_trailers] is always called, the default assumes
+    /// it isn't. Encoders that wrap another one must forward it.
+    fn accepts_trailers(&self) -> bool {
+        false
+    }
+    /// Asks for the next final response's body to be delimited by closing
+    /// the connection, rather than by `content-length` or chunked transfer
+    /// coding, cf. [Responder::close_delimited]. Returns false if this
+    /// encoder's framing has no such thing, which is what the default
+    /// assumes. Encoders that wrap another one must forward it.
+    fn close_delimited(&mut self) -> bool {
+        false
+    }
 }
 
 #[cfg(test)]
@@ -281,6 +488,14 @@ mod tests {
         async fn write_body_chunk(&mut self, _: Piece) -> Result<(), Self::Error> {
             Ok(())
         }
+        async fn write_body_file(
+            &mut self,
+            _: Rc<File>,
+            _: u64,
+            _: u64,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
         async fn write_body_end(&mut self) -> Result<(), Self::Error> {
             Ok(())
         }
@@ -289,6 +504,104 @@ mod tests {
         }
     }
 
+    /// Like [MockEncoder], for responses to HEAD requests
+    struct MockHeadEncoder;
+
+    impl Encoder for MockHeadEncoder {
+        type Error = BX;
+
+        async fn write_response(&mut self, _: Response) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_body_chunk(&mut self, _: Piece) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_body_file(
+            &mut self,
+            _: Rc<File>,
+            _: u64,
+            _: u64,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_body_end(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_trailers(&mut self, _: Box<Headers>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        fn is_head_response(&self) -> bool {
+            true
+        }
+    }
+
+    /// Like [MockEncoder], for protocols that take unannounced trailers.
+    /// Counts the calls to [Encoder::write_trailers].
+    #[derive(Default)]
+    struct MockTrailersEncoder {
+        trailer_writes: Rc<std::cell::Cell<usize>>,
+    }
+
+    impl Encoder for MockTrailersEncoder {
+        type Error = BX;
+
+        async fn write_response(&mut self, _: Response) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_body_chunk(&mut self, _: Piece) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_body_file(
+            &mut self,
+            _: Rc<File>,
+            _: u64,
+            _: u64,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_body_end(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn write_trailers(&mut self, _: Box<Headers>) -> Result<(), Self::Error> {
+            self.trailer_writes.set(self.trailer_writes.get() + 1);
+            Ok(())
+        }
+        fn accepts_trailers(&self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn test_head_response_body() {
+        let mut res = Response::default();
+        res.headers.insert(header::CONTENT_LENGTH, "10".into());
+
+        // handlers can skip the body, or write it whole
+        let responder = Responder::new(MockHeadEncoder)
+            .write_final_response(res.clone())
+            .await
+            .unwrap();
+        responder.finish_body(None).await.unwrap();
+
+        let mut responder = Responder::new(MockHeadEncoder)
+            .write_final_response(res.clone())
+            .await
+            .unwrap();
+        responder.write_chunk(b"1234567890".into()).await.unwrap();
+        responder.finish_body(None).await.unwrap();
+
+        // but not write part of it
+        let mut responder = Responder::new(MockHeadEncoder)
+            .write_final_response(res)
+            .await
+            .unwrap();
+        responder.write_chunk(b"12345".into()).await.unwrap();
+        assert!(matches!(
+            responder.finish_body(None).await,
+            Err(ResponderError::BodyLengthDoesNotMatchAnnouncedContentLength { .. })
+        ));
+    }
+
     #[tokio::test]
     async fn test_content_length_mismatch() {
         let encoder = MockEncoder;
@@ -328,4 +641,80 @@ mod tests {
             ));
         }
     }
+
+    #[tokio::test]
+    async fn test_trailers_must_be_announced() {
+        let trailers = |name: &'static str| {
+            let mut trailers = Headers::default();
+            trailers.insert(name, "1".into());
+            Some(Box::new(trailers))
+        };
+
+        let responder = Responder::new(MockEncoder)
+            .write_final_response(Response::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            responder.finish_body(trailers("x-checksum")).await,
+            Err(ResponderError::UnannouncedTrailer { .. })
+        ));
+
+        let mut res = Response::default();
+        res.headers
+            .insert(header::TRAILER, "x-checksum, content-type".into());
+        let responder = Responder::new(MockEncoder)
+            .write_final_response(res.clone())
+            .await
+            .unwrap();
+        assert!(matches!(
+            responder.finish_body(trailers("content-type")).await,
+            Err(ResponderError::ForbiddenTrailer { .. })
+        ));
+
+        let responder = Responder::new(MockEncoder)
+            .write_final_response(res)
+            .await
+            .unwrap();
+        responder.finish_body(trailers("x-checksum")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unannounced_trailers_where_accepted() {
+        let trailers = |name: &'static str| {
+            let mut trailers = Headers::default();
+            trailers.insert(name, "1".into());
+            Some(Box::new(trailers))
+        };
+
+        let encoder = MockTrailersEncoder::default();
+        let trailer_writes = encoder.trailer_writes.clone();
+        let responder = Responder::new(encoder)
+            .write_final_response(Response::default())
+            .await
+            .unwrap();
+        responder
+            .finish_body(trailers("grpc-status"))
+            .await
+            .unwrap();
+        assert_eq!(trailer_writes.get(), 1);
+
+        // the encoder ends the message with the (empty) trailers
+        let encoder = MockTrailersEncoder::default();
+        let trailer_writes = encoder.trailer_writes.clone();
+        let responder = Responder::new(encoder)
+            .write_final_response(Response::default())
+            .await
+            .unwrap();
+        responder.finish_body(None).await.unwrap();
+        assert_eq!(trailer_writes.get(), 1);
+
+        let responder = Responder::new(MockTrailersEncoder::default())
+            .write_final_response(Response::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            responder.finish_body(trailers("content-type")).await,
+            Err(ResponderError::ForbiddenTrailer { .. })
+        ));
+    }
 }
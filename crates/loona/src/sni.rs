@@ -0,0 +1,260 @@
+//! Finds the server name (SNI) a TLS client asks for in its ClientHello,
+//! without terminating TLS, cf. <https://datatracker.ietf.org/doc/html/rfc8446#section-4.1.2>
+//! and <https://datatracker.ietf.org/doc/html/rfc6066#section-3>.
+//!
+//! Gateways use it to pick an upstream for connections they pass through
+//! as-is, so they read (or peek) the first bytes of the connection and call
+//! [client_hello_server_name] until it stops returning [Peeked::Incomplete].
+
+/// Content type of TLS records that carry handshake messages
+const RECORD_HANDSHAKE: u8 = 0x16;
+const RECORD_HEADER_LEN: usize = 5;
+
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const HANDSHAKE_HEADER_LEN: usize = 4;
+
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// The longest ClientHello we look at: records carry at most 16KiB of
+/// plaintext
+pub const MAX_CLIENT_HELLO_LEN: usize = RECORD_HEADER_LEN + 16 * 1024;
+
+/// What we could tell from the bytes so far
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Peeked {
+    /// The first record isn't all there yet
+    Incomplete,
+
+    /// The ClientHello is complete, this is the name it asks for, if any,
+    /// lowercased
+    ServerName(Option<String>),
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SniError {
+    /// The connection doesn't start with a TLS handshake record
+    #[error("not a TLS handshake")]
+    NotTls,
+
+    /// The first handshake message isn't a ClientHello
+    #[error("first handshake message is not a ClientHello")]
+    NotClientHello,
+
+    /// The ClientHello is split across several records, which we don't
+    /// reassemble
+    #[error("ClientHello spans several records")]
+    Fragmented,
+
+    #[error("malformed ClientHello: {0}")]
+    Malformed(&'static str),
+}
+
+/// Looks for the server name in the ClientHello `buf` starts with
+pub fn client_hello_server_name(buf: &[u8]) -> Result<Peeked, SniError> {
+    if buf.first().is_some_and(|&b| b != RECORD_HANDSHAKE) {
+        return Err(SniError::NotTls);
+    }
+    if buf.len() < RECORD_HEADER_LEN {
+        return Ok(Peeked::Incomplete);
+    }
+    if buf[1] != 0x03 {
+        return Err(SniError::NotTls);
+    }
+    let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    let Some(record) = buf.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + record_len) else {
+        return Ok(Peeked::Incomplete);
+    };
+
+    let mut r = Reader(record);
+    if r.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return Err(SniError::NotClientHello);
+    }
+    let hello_len = r.u24()?;
+    if hello_len > record_len - HANDSHAKE_HEADER_LEN {
+        return Err(SniError::Fragmented);
+    }
+    let mut hello = Reader(r.take(hello_len)?);
+
+    // legacy_version, random
+    hello.take(2 + 32)?;
+    // legacy_session_id, cipher_suites, legacy_compression_methods
+    hello.u8_prefixed()?;
+    hello.u16_prefixed()?;
+    hello.u8_prefixed()?;
+    if hello.0.is_empty() {
+        // no extensions at all
+        return Ok(Peeked::ServerName(None));
+    }
+
+    let mut extensions = Reader(hello.u16_prefixed()?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let data = extensions.u16_prefixed()?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = Reader(Reader(data).u16_prefixed()?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.u16_prefixed()?;
+            if name_type != NAME_TYPE_HOST_NAME {
+                continue;
+            }
+            let name = std::str::from_utf8(name)
+                .ok()
+                .filter(|name| !name.is_empty() && name.is_ascii())
+                .ok_or(SniError::Malformed("server name is not an ASCII host name"))?;
+            return Ok(Peeked::ServerName(Some(name.to_ascii_lowercase())));
+        }
+        return Ok(Peeked::ServerName(None));
+    }
+    Ok(Peeked::ServerName(None))
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SniError> {
+        if self.0.len() < n {
+            return Err(SniError::Malformed(
+                "length exceeds the enclosing structure",
+            ));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, SniError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SniError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Result<usize, SniError> {
+        let b = self.take(3)?;
+        Ok(u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+
+    fn u8_prefixed(&mut self) -> Result<&'a [u8], SniError> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn u16_prefixed(&mut self) -> Result<&'a [u8], SniError> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_hello_server_name, Peeked, SniError};
+
+    fn u16_prefixed(data: &[u8]) -> Vec<u8> {
+        [&(data.len() as u16).to_be_bytes()[..], data].concat()
+    }
+
+    /// A ClientHello record with the given extensions, as `(type, data)`
+    fn client_hello(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[7; 32]);
+        hello.extend_from_slice(&[0]); // no session id
+        hello.extend_from_slice(&u16_prefixed(&[0x13, 0x01]));
+        hello.extend_from_slice(&[1, 0]); // null compression
+        let extensions: Vec<u8> = extensions
+            .iter()
+            .flat_map(|(kind, data)| [&kind.to_be_bytes()[..], &u16_prefixed(data)].concat())
+            .collect();
+        hello.extend_from_slice(&u16_prefixed(&extensions));
+
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&u16_prefixed(&handshake));
+        record
+    }
+
+    fn server_name_extension(name: &str) -> (u16, Vec<u8>) {
+        let entry = [&[0][..], &u16_prefixed(name.as_bytes())].concat();
+        (0, u16_prefixed(&entry))
+    }
+
+    #[test]
+    fn test_server_name() {
+        let hello = client_hello(&[
+            (0x000a, vec![0, 2, 0, 0x1d]),
+            server_name_extension("Example.ORG"),
+        ]);
+        assert_eq!(
+            client_hello_server_name(&hello),
+            Ok(Peeked::ServerName(Some("example.org".into())))
+        );
+
+        // trailing bytes (the client's next record) are fine
+        let mut more = hello.clone();
+        more.extend_from_slice(&[0x14, 0x03, 0x03]);
+        assert_eq!(
+            client_hello_server_name(&more),
+            Ok(Peeked::ServerName(Some("example.org".into())))
+        );
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let hello = client_hello(&[server_name_extension("example.org")]);
+        for len in [0, 1, 4, 5, hello.len() - 1] {
+            assert_eq!(
+                client_hello_server_name(&hello[..len]),
+                Ok(Peeked::Incomplete),
+                "{len}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_no_server_name() {
+        let hello = client_hello(&[(0x000a, vec![0, 2, 0, 0x1d])]);
+        assert_eq!(
+            client_hello_server_name(&hello),
+            Ok(Peeked::ServerName(None))
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            client_hello_server_name(b"GET / HTTP/1.1\r\n"),
+            Err(SniError::NotTls)
+        );
+
+        let mut hello = client_hello(&[server_name_extension("example.org")]);
+        hello[5] = 0x02;
+        assert_eq!(
+            client_hello_server_name(&hello),
+            Err(SniError::NotClientHello)
+        );
+
+        // the handshake message claims to be longer than the record
+        let mut hello = client_hello(&[server_name_extension("example.org")]);
+        hello[8] += 1;
+        assert_eq!(client_hello_server_name(&hello), Err(SniError::Fragmented));
+
+        // a server name longer than the extension
+        let mut hello = client_hello(&[server_name_extension("example.org")]);
+        let len = hello.len();
+        hello[len - 12] += 1;
+        assert!(matches!(
+            client_hello_server_name(&hello),
+            Err(SniError::Malformed(_))
+        ));
+    }
+}
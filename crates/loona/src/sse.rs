@@ -0,0 +1,246 @@
+//! Server-Sent Events, cf. <https://html.spec.whatwg.org/multipage/server-sent-events.html>
+//!
+//! [SseBody] turns a stream of [SseEvent]s into a `text/event-stream` body.
+//! It asks for every event to be flushed as soon as it's written (see
+//! [Body::flush_each_chunk]), so clients see events as they happen, not
+//! whenever the encoder decides to write.
+//!
+//! ```ignore
+//! let (tx, rx) = tokio::sync::mpsc::channel(16);
+//! let mut body = SseBody::new(tokio_stream::wrappers::ReceiverStream::new(rx));
+//! res.write_final_response_with_body(sse::response(), &mut body).await?;
+//! ```
+
+use std::{fmt, time::Duration};
+
+use futures_util::{Stream, StreamExt};
+use http::header;
+
+use crate::{error::NeverError, Body, BodyChunk, Response};
+
+/// A single event. Only `data` is required.
+#[derive(Debug, Clone, Default)]
+pub struct SseEvent {
+    /// The event type, clients treat events without one as `message`.
+    /// Line breaks are replaced with spaces.
+    pub event: Option<String>,
+
+    /// The payload. May span several lines.
+    pub data: String,
+
+    /// Sets the last event ID, which clients send back in `last-event-id`
+    /// when they reconnect. Line breaks are replaced with spaces.
+    pub id: Option<String>,
+
+    /// How long clients should wait before reconnecting
+    pub retry: Option<Duration>,
+}
+
+impl SseEvent {
+    /// Formats this event, including the blank line that ends it
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.data.len() + 16);
+        if let Some(event) = &self.event {
+            encode_field(&mut out, "event", event);
+        }
+        if let Some(id) = &self.id {
+            encode_field(&mut out, "id", id);
+        }
+        if let Some(retry) = self.retry {
+            encode_field(&mut out, "retry", &retry.as_millis().to_string());
+        }
+
+        // every kind of line break ends a line, and each line of the payload
+        // needs its own field
+        let data = self.data.replace("\r\n", "\n").replace('\r', "\n");
+        for line in data.split('\n') {
+            encode_field(&mut out, "data", line);
+        }
+
+        out.push(b'\n');
+        out
+    }
+}
+
+fn encode_field(out: &mut Vec<u8>, name: &str, value: &str) {
+    out.extend_from_slice(name.as_bytes());
+    out.extend_from_slice(b": ");
+    for b in value.bytes() {
+        out.push(match b {
+            b'\r' | b'\n' => b' ',
+            b => b,
+        });
+    }
+    out.push(b'\n');
+}
+
+/// A `200 OK` with the headers an event stream needs. It has no
+/// `content-length`, the body goes on until the event stream ends.
+pub fn response() -> Response {
+    let mut res = Response::default();
+    res.headers
+        .insert(header::CONTENT_TYPE, "text/event-stream".into());
+    res.headers.insert(header::CACHE_CONTROL, "no-cache".into());
+    res
+}
+
+/// A body made of server-sent events: one chunk per event, until `events`
+/// ends.
+pub struct SseBody<S> {
+    events: S,
+    done: bool,
+}
+
+impl<S> SseBody<S>
+where
+    S: Stream<Item = SseEvent> + Unpin,
+{
+    pub fn new(events: S) -> Self {
+        Self {
+            events,
+            done: false,
+        }
+    }
+}
+
+impl<S> fmt::Debug for SseBody<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SseBody")
+            .field("done", &self.done)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> Body for SseBody<S>
+where
+    S: Stream<Item = SseEvent> + Unpin,
+{
+    type Error = NeverError;
+
+    fn content_len(&self) -> Option<u64> {
+        None
+    }
+
+    fn eof(&self) -> bool {
+        self.done
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        if self.done {
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+
+        match self.events.next().await {
+            Some(ev) => Ok(BodyChunk::Chunk(ev.encode().into())),
+            None => {
+                self.done = true;
+                Ok(BodyChunk::Done { trailers: None })
+            }
+        }
+    }
+
+    fn flush_each_chunk(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, fs::File, rc::Rc, time::Duration};
+
+    use b_x::BX;
+    use buffet::Piece;
+    use futures_util::stream;
+
+    use super::{SseBody, SseEvent};
+    use crate::{Encoder, Headers, Responder, Response};
+
+    fn events() -> Vec<SseEvent> {
+        vec![
+            SseEvent {
+                data: "hello".into(),
+                ..Default::default()
+            },
+            SseEvent {
+                event: Some("update".into()),
+                data: "a\nb".into(),
+                id: Some("42".into()),
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn test_encode() {
+        let ev = SseEvent {
+            event: Some("up\ndate".into()),
+            data: "one\r\ntwo\rthree\n".into(),
+            id: Some("7".into()),
+            retry: Some(Duration::from_secs(3)),
+        };
+        assert_eq!(
+            std::str::from_utf8(&ev.encode()).unwrap(),
+            "event: up date\nid: 7\nretry: 3000\ndata: one\ndata: two\ndata: three\ndata: \n\n"
+        );
+
+        let ev = SseEvent::default();
+        assert_eq!(&ev.encode()[..], b"data: \n\n");
+    }
+
+    /// Records what the responder asks of it
+    #[derive(Default, Clone)]
+    struct RecordingEncoder {
+        calls: Rc<RefCell<Vec<&'static str>>>,
+    }
+
+    impl Encoder for RecordingEncoder {
+        type Error = BX;
+
+        async fn write_response(&mut self, _: Response) -> Result<(), Self::Error> {
+            self.calls.borrow_mut().push("response");
+            Ok(())
+        }
+        async fn write_body_chunk(&mut self, _: Piece) -> Result<(), Self::Error> {
+            self.calls.borrow_mut().push("chunk");
+            Ok(())
+        }
+        async fn write_body_file(
+            &mut self,
+            _: Rc<File>,
+            _: u64,
+            _: u64,
+        ) -> Result<(), Self::Error> {
+            self.calls.borrow_mut().push("file");
+            Ok(())
+        }
+        async fn write_body_end(&mut self) -> Result<(), Self::Error> {
+            self.calls.borrow_mut().push("end");
+            Ok(())
+        }
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            self.calls.borrow_mut().push("flush");
+            Ok(())
+        }
+        async fn write_trailers(&mut self, _: Box<Headers>) -> Result<(), Self::Error> {
+            self.calls.borrow_mut().push("trailers");
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flushes_each_event() {
+        let encoder = RecordingEncoder::default();
+        let mut body = SseBody::new(stream::iter(events()));
+        Responder::new(encoder.clone())
+            .write_final_response_with_body(super::response(), &mut body)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            &encoder.calls.borrow()[..],
+            ["response", "chunk", "flush", "chunk", "flush", "end"]
+        );
+    }
+
+    crate::body_test_suite!(|| SseBody::new(stream::iter(events())));
+}
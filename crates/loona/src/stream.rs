@@ -0,0 +1,300 @@
+//! Adapters between loona's [Body] and [Stream]s of [Piece]s, so bodies can
+//! come from, or go into, async-stream / tokio-stream pipelines.
+//!
+//! ```ignore
+//! let chunks = async_stream::stream! {
+//!     for row in rows {
+//!         yield Ok::<_, std::io::Error>(Piece::from(render(row)));
+//!     }
+//! };
+//! let mut body = StreamBody::new(chunks);
+//! ```
+
+use std::{
+    fmt,
+    fs::File,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+use buffet::{read_file_piece, Piece};
+use futures_util::{Stream, StreamExt};
+
+use crate::{Body, BodyChunk, Headers};
+
+/// A [Body] whose chunks come from a [Stream]. The body ends when the
+/// stream does, without trailers, and its length is never known upfront.
+///
+/// Like [SseBody](crate::sse::SseBody), it asks for each chunk to be flushed
+/// as soon as it's written, cf. [Body::flush_each_chunk].
+pub struct StreamBody<S> {
+    inner: Pin<Box<S>>,
+    done: bool,
+}
+
+impl<S, E> StreamBody<S>
+where
+    S: Stream<Item = Result<Piece, E>>,
+{
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            done: false,
+        }
+    }
+}
+
+impl<S> fmt::Debug for StreamBody<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamBody")
+            .field("done", &self.done)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, E> Body for StreamBody<S>
+where
+    S: Stream<Item = Result<Piece, E>>,
+    E: std::error::Error + 'static,
+{
+    type Error = E;
+
+    fn content_len(&self) -> Option<u64> {
+        None
+    }
+
+    fn eof(&self) -> bool {
+        self.done
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        if self.done {
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+
+        match self.inner.next().await {
+            Some(chunk) => Ok(BodyChunk::Chunk(chunk?)),
+            None => {
+                self.done = true;
+                Ok(BodyChunk::Done { trailers: None })
+            }
+        }
+    }
+
+    fn flush_each_chunk(&self) -> bool {
+        true
+    }
+}
+
+/// Yielded by [BodyStream] when reading the body fails
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum BodyStreamError<BodyError> {
+    #[error("body error: {0}")]
+    Body(BodyError),
+
+    /// Reading a [BodyChunk::File] failed
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+type NextChunk<B> = Pin<Box<dyn Future<Output = (B, Result<BodyChunk, <B as Body>::Error>)>>>;
+
+enum State<B: Body> {
+    Idle(B),
+    Reading(NextChunk<B>),
+    File {
+        body: B,
+        file: Rc<File>,
+        offset: u64,
+        len: u64,
+    },
+    Done,
+}
+
+/// A [Stream] of the [Piece]s of a loona [Body]. File chunks are read into
+/// memory 64KiB at a time, and trailers are kept aside, cf.
+/// [BodyStream::take_trailers]. The stream ends after the first error.
+pub struct BodyStream<B: Body> {
+    state: State<B>,
+    trailers: Option<Box<Headers>>,
+}
+
+impl<B: Body + 'static> BodyStream<B> {
+    pub fn new(body: B) -> Self {
+        Self {
+            state: State::Idle(body),
+            trailers: None,
+        }
+    }
+
+    /// The body's trailers, once the stream has ended, if it had any
+    pub fn take_trailers(&mut self) -> Option<Box<Headers>> {
+        self.trailers.take()
+    }
+}
+
+impl<B: Body> fmt::Debug for BodyStream<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyStream")
+            .field("done", &matches!(self.state, State::Done))
+            .finish_non_exhaustive()
+    }
+}
+
+// nothing is pinned in place: the read future is boxed
+impl<B: Body> Unpin for BodyStream<B> {}
+
+impl<B: Body + 'static> Stream for BodyStream<B> {
+    type Item = Result<Piece, BodyStreamError<B::Error>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match std::mem::replace(&mut self.state, State::Done) {
+                State::Idle(mut body) => {
+                    self.state = State::Reading(Box::pin(async move {
+                        let res = body.next_chunk().await;
+                        (body, res)
+                    }));
+                }
+                State::Reading(mut fut) => {
+                    let (body, res) = match fut.as_mut().poll(cx) {
+                        Poll::Ready(res) => res,
+                        Poll::Pending => {
+                            self.state = State::Reading(fut);
+                            return Poll::Pending;
+                        }
+                    };
+                    match res {
+                        Ok(BodyChunk::Chunk(piece)) => {
+                            self.state = State::Idle(body);
+                            if !piece.is_empty() {
+                                return Poll::Ready(Some(Ok(piece)));
+                            }
+                        }
+                        Ok(BodyChunk::File { file, offset, len }) => {
+                            self.state = State::File {
+                                body,
+                                file,
+                                offset,
+                                len,
+                            };
+                        }
+                        Ok(BodyChunk::Done { trailers }) => {
+                            self.trailers = trailers;
+                            return Poll::Ready(None);
+                        }
+                        Err(e) => return Poll::Ready(Some(Err(BodyStreamError::Body(e)))),
+                    }
+                }
+                State::File {
+                    body,
+                    file,
+                    offset,
+                    len,
+                } => {
+                    if len == 0 {
+                        self.state = State::Idle(body);
+                        continue;
+                    }
+                    let piece = match read_file_piece(&file, offset, len) {
+                        Ok(piece) => piece,
+                        Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                    };
+                    let read = piece.len() as u64;
+                    self.state = State::File {
+                        body,
+                        file,
+                        offset: offset + read,
+                        len: len - read,
+                    };
+                    return Poll::Ready(Some(Ok(piece)));
+                }
+                State::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use buffet::Piece;
+    use futures_util::{stream, StreamExt};
+    use http::header;
+
+    use super::{BodyStream, BodyStreamError, StreamBody};
+    use crate::{
+        error::NeverError, testkit::anonymous_file, Body, BodyChunk, ChannelBody, FileBody,
+        Headers, SinglePieceBody,
+    };
+
+    fn pieces() -> Vec<Result<Piece, NeverError>> {
+        vec![Ok("hello ".into()), Ok("world".into())]
+    }
+
+    crate::body_test_suite!(|| StreamBody::new(stream::iter(pieces())));
+
+    mod round_trip {
+        use crate::{stream::*, testkit::anonymous_file, FileBody};
+
+        crate::body_test_suite!(|| {
+            let contents = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            StreamBody::new(BodyStream::new(
+                FileBody::new(anonymous_file(&contents)).unwrap(),
+            ))
+        });
+    }
+
+    #[test]
+    fn test_stream_body() {
+        buffet::start(async move {
+            let mut body = StreamBody::new(stream::iter(pieces()));
+            let collected = body.collect(11).await.unwrap();
+            assert_eq!(&collected[..], b"hello world");
+            assert!(body.eof());
+
+            #[derive(Debug, thiserror::Error)]
+            #[error("nope")]
+            struct Nope;
+
+            let mut body = StreamBody::new(stream::iter([Ok("a".into()), Err(Nope)]));
+            assert!(matches!(body.next_chunk().await, Ok(BodyChunk::Chunk(_))));
+            assert!(matches!(body.next_chunk().await, Err(Nope)));
+        });
+    }
+
+    #[test]
+    fn test_body_stream() {
+        buffet::start(async move {
+            let contents = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let body = FileBody::new(anonymous_file(&contents)).unwrap();
+
+            let mut read = Vec::new();
+            let mut stream = BodyStream::new(body);
+            while let Some(piece) = stream.next().await {
+                read.extend_from_slice(&piece.unwrap()[..]);
+            }
+            assert_eq!(read, contents);
+            assert!(stream.next().await.is_none());
+
+            let mut stream = BodyStream::new(SinglePieceBody::from("hi"));
+            let piece: Result<Piece, BodyStreamError<NeverError>> = stream.next().await.unwrap();
+            assert_eq!(&piece.unwrap()[..], b"hi");
+            assert!(stream.next().await.is_none());
+            assert!(stream.take_trailers().is_none());
+
+            let (tx, body) = ChannelBody::new(1);
+            buffet::spawn(async move {
+                let mut trailers = Headers::default();
+                trailers.insert(header::ETAG, "\"b\"".into());
+                tx.finish(Some(Box::new(trailers))).await.unwrap();
+            });
+            let mut stream = BodyStream::new(body);
+            assert!(stream.next().await.is_none());
+            let trailers = stream.take_trailers().unwrap();
+            assert_eq!(&trailers[header::ETAG][..], b"\"b\"");
+        });
+    }
+}
@@ -0,0 +1,181 @@
+//! Checks for [Body] implementations, see [crate::body_test_suite].
+
+use std::future::Future;
+
+use crate::{Body, BodyChunk, Headers, FORBIDDEN_TRAILERS};
+
+/// Generates one `#[test]` function per body check. Takes an expression
+/// that evaluates to a `Fn() -> impl Body`, which is called from within a
+/// buffet runtime, once per check. The body must be readable to the end
+/// without errors.
+///
+/// ```ignore
+/// mod my_body_conformance {
+///     loona::body_test_suite!(|| MyBody::new(b"hello"));
+/// }
+/// ```
+#[macro_export]
+macro_rules! body_test_suite {
+    ($make_body:expr) => {
+        #[test]
+        fn body_content_len_matches() {
+            $crate::testkit::body::content_len_matches($make_body);
+        }
+
+        #[test]
+        fn body_eof_after_done() {
+            $crate::testkit::body::eof_after_done($make_body);
+        }
+
+        #[test]
+        fn body_done_is_idempotent() {
+            $crate::testkit::body::done_is_idempotent($make_body);
+        }
+
+        #[test]
+        fn body_eof_is_truthful() {
+            $crate::testkit::body::eof_is_truthful($make_body);
+        }
+
+        #[test]
+        fn body_trailers_are_allowed() {
+            $crate::testkit::body::trailers_are_allowed($make_body);
+        }
+
+        #[test]
+        fn body_trailers_were_announced() {
+            $crate::testkit::body::trailers_were_announced($make_body);
+        }
+    };
+}
+
+/// What reading a body to the end yielded
+struct Drained {
+    len: u64,
+    trailers: Option<Box<Headers>>,
+}
+
+fn run<B, Fut>(make_body: impl Fn() -> B, check: impl FnOnce(B) -> Fut)
+where
+    B: Body,
+    Fut: Future<Output = ()>,
+{
+    buffet::start(async move {
+        let body = make_body();
+        check(body).await;
+    });
+}
+
+async fn drain(body: &mut impl Body) -> Drained {
+    let mut len = 0;
+    loop {
+        let chunk = match body.next_chunk().await {
+            Ok(chunk) => chunk,
+            Err(e) => panic!("body errored out after {len} bytes: {e}"),
+        };
+        match chunk {
+            BodyChunk::Chunk(chunk) => len += chunk.len() as u64,
+            BodyChunk::File { len: file_len, .. } => len += file_len,
+            BodyChunk::Done { trailers } => return Drained { len, trailers },
+        }
+    }
+}
+
+/// If `content_len()` returns something, that's exactly how many bytes the
+/// body yields. It's checked before reading anything, since that's when
+/// responders look at it.
+pub fn content_len_matches<B: Body>(make_body: impl Fn() -> B) {
+    run(make_body, |mut body| async move {
+        let announced = body.content_len();
+        let drained = drain(&mut body).await;
+        if let Some(announced) = announced {
+            assert_eq!(
+                drained.len, announced,
+                "body yielded {} bytes but announced a content-length of {announced}",
+                drained.len
+            );
+        }
+    })
+}
+
+/// Once `next_chunk()` has returned [BodyChunk::Done], `eof()` returns true
+pub fn eof_after_done<B: Body>(make_body: impl Fn() -> B) {
+    run(make_body, |mut body| async move {
+        drain(&mut body).await;
+        assert!(body.eof(), "eof() returned false after BodyChunk::Done");
+    })
+}
+
+/// Calling `next_chunk()` after [BodyChunk::Done] returns `Done` again,
+/// without trailers, rather than erroring out or panicking
+pub fn done_is_idempotent<B: Body>(make_body: impl Fn() -> B) {
+    run(make_body, |mut body| async move {
+        drain(&mut body).await;
+        for _ in 0..3 {
+            match body.next_chunk().await {
+                Ok(BodyChunk::Done { trailers }) => {
+                    assert!(trailers.is_none(), "trailers were returned more than once");
+                }
+                Ok(_) => panic!("body yielded data after BodyChunk::Done"),
+                Err(e) => panic!("body errored out when called after BodyChunk::Done: {e}"),
+            }
+        }
+    })
+}
+
+/// When `eof()` returns true, the next chunk is [BodyChunk::Done]
+pub fn eof_is_truthful<B: Body>(make_body: impl Fn() -> B) {
+    run(make_body, |mut body| async move {
+        let mut len = 0;
+        loop {
+            let eof = body.eof();
+            let chunk = match body.next_chunk().await {
+                Ok(chunk) => chunk,
+                Err(e) => panic!("body errored out after {len} bytes: {e}"),
+            };
+            match chunk {
+                BodyChunk::Done { .. } => break,
+                BodyChunk::Chunk(chunk) => {
+                    assert!(!eof, "eof() returned true, but a chunk followed");
+                    len += chunk.len() as u64;
+                }
+                BodyChunk::File { len: file_len, .. } => {
+                    assert!(!eof, "eof() returned true, but a file chunk followed");
+                    len += file_len;
+                }
+            }
+        }
+    })
+}
+
+/// Trailers, if any, don't contain fields that aren't allowed in trailers
+pub fn trailers_are_allowed<B: Body>(make_body: impl Fn() -> B) {
+    run(make_body, |mut body| async move {
+        let Some(trailers) = drain(&mut body).await.trailers else {
+            return;
+        };
+        for name in trailers.keys() {
+            assert!(
+                !FORBIDDEN_TRAILERS.contains(name),
+                "{name} is not allowed in trailers"
+            );
+        }
+    })
+}
+
+/// Trailers, if any, are among those `announced_trailers()` listed before
+/// the first chunk: the response's `trailer` header is built from it
+pub fn trailers_were_announced<B: Body>(make_body: impl Fn() -> B) {
+    run(make_body, |mut body| async move {
+        let announced = body.announced_trailers().to_vec();
+        let Some(trailers) = drain(&mut body).await.trailers else {
+            return;
+        };
+        for name in trailers.keys() {
+            assert!(
+                announced.contains(name),
+                "{name} was sent as a trailer, but wasn't announced"
+            );
+        }
+    })
+}
@@ -0,0 +1,239 @@
+//! Checks for [Encoder] implementations, see [crate::encoder_test_suite].
+
+use std::{future::Future, rc::Rc};
+
+use http::{header, StatusCode};
+
+use crate::{Encoder, Headers, Response};
+
+/// Generates one `#[test]` function per encoder check. Takes an expression
+/// that evaluates to a `Fn() -> impl Encoder`, which is called from within a
+/// buffet runtime, once per check.
+///
+/// The checks exercise every valid call sequence (see [Encoder]), and
+/// make sure each call succeeds. They can't look at what the encoder actually
+/// writes: that's for the implementation's own tests.
+///
+/// ```ignore
+/// mod my_encoder_conformance {
+///     loona::encoder_test_suite!(|| MyEncoder::new(Vec::<u8>::new()));
+/// }
+/// ```
+#[macro_export]
+macro_rules! encoder_test_suite {
+    ($make_encoder:expr) => {
+        #[test]
+        fn encoder_final_response_with_content_length() {
+            $crate::testkit::encoder::final_response_with_content_length($make_encoder);
+        }
+
+        #[test]
+        fn encoder_final_response_without_content_length() {
+            $crate::testkit::encoder::final_response_without_content_length($make_encoder);
+        }
+
+        #[test]
+        fn encoder_empty_body() {
+            $crate::testkit::encoder::empty_body($make_encoder);
+        }
+
+        #[test]
+        fn encoder_no_content() {
+            $crate::testkit::encoder::no_content($make_encoder);
+        }
+
+        #[test]
+        fn encoder_file_chunks() {
+            $crate::testkit::encoder::file_chunks($make_encoder);
+        }
+
+        #[test]
+        fn encoder_flushes() {
+            $crate::testkit::encoder::flushes($make_encoder);
+        }
+
+        #[test]
+        fn encoder_announced_trailers() {
+            $crate::testkit::encoder::announced_trailers($make_encoder);
+        }
+
+        #[test]
+        fn encoder_trailers_dont_panic() {
+            $crate::testkit::encoder::trailers_dont_panic($make_encoder);
+        }
+
+        #[test]
+        fn encoder_dropped_mid_body() {
+            $crate::testkit::encoder::dropped_mid_body($make_encoder);
+        }
+    };
+}
+
+fn run<E, Fut>(make_encoder: impl Fn() -> E, check: impl FnOnce(E) -> Fut)
+where
+    E: Encoder,
+    Fut: Future<Output = Result<(), E::Error>>,
+{
+    buffet::start(async move {
+        let encoder = make_encoder();
+        if let Err(e) = check(encoder).await {
+            panic!("encoder returned an error for a valid call sequence: {e}");
+        }
+    });
+}
+
+/// Ends the body the way [crate::Responder] does for a response that didn't
+/// announce trailers, cf. [Encoder::accepts_trailers]
+async fn end_body<E: Encoder>(enc: &mut E) -> Result<(), E::Error> {
+    enc.write_body_end().await?;
+    if enc.accepts_trailers() {
+        enc.write_trailers(Default::default()).await?;
+    }
+    Ok(())
+}
+
+fn response(status: StatusCode, content_length: Option<u64>) -> Response {
+    let mut res = Response {
+        status,
+        ..Default::default()
+    };
+    if let Some(content_length) = content_length {
+        res.headers.insert(
+            header::CONTENT_LENGTH,
+            format!("{content_length}").into_bytes().into(),
+        );
+    }
+    res
+}
+
+/// Final response with a `content-length`, body in two chunks
+pub fn final_response_with_content_length<E: Encoder>(make_encoder: impl Fn() -> E) {
+    run(make_encoder, |mut enc| async move {
+        enc.write_response(response(StatusCode::OK, Some(11)))
+            .await?;
+        enc.write_body_chunk("hello ".into()).await?;
+        enc.write_body_chunk("world".into()).await?;
+        end_body(&mut enc).await?;
+        Ok(())
+    })
+}
+
+/// Final response without a `content-length`: the encoder picks the framing
+pub fn final_response_without_content_length<E: Encoder>(make_encoder: impl Fn() -> E) {
+    run(make_encoder, |mut enc| async move {
+        enc.write_response(response(StatusCode::OK, None)).await?;
+        for _ in 0..16 {
+            enc.write_body_chunk("this is a chunk".into()).await?;
+        }
+        end_body(&mut enc).await?;
+        Ok(())
+    })
+}
+
+/// `content-length: 0`, no body chunks at all
+pub fn empty_body<E: Encoder>(make_encoder: impl Fn() -> E) {
+    run(make_encoder, |mut enc| async move {
+        enc.write_response(response(StatusCode::OK, Some(0)))
+            .await?;
+        end_body(&mut enc).await?;
+        Ok(())
+    })
+}
+
+/// A 204, which can't have a body
+pub fn no_content<E: Encoder>(make_encoder: impl Fn() -> E) {
+    run(make_encoder, |mut enc| async move {
+        enc.write_response(response(StatusCode::NO_CONTENT, None))
+            .await?;
+        end_body(&mut enc).await?;
+        Ok(())
+    })
+}
+
+/// File-backed chunks, interleaved with regular ones, with and without a
+/// `content-length`
+pub fn file_chunks<E: Encoder>(make_encoder: impl Fn() -> E) {
+    let contents = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let file = Rc::new(super::anonymous_file(&contents));
+
+    for content_length in [Some(100_000 + 5), None] {
+        let file = file.clone();
+        run(&make_encoder, |mut enc| async move {
+            enc.write_response(response(StatusCode::OK, content_length))
+                .await?;
+            enc.write_body_file(file.clone(), 10, 50_000).await?;
+            enc.write_body_chunk("hello".into()).await?;
+            enc.write_body_file(file, 150_000, 50_000).await?;
+            end_body(&mut enc).await?;
+            Ok(())
+        })
+    }
+}
+
+/// Flushing after every chunk, twice in a row, and before any chunk
+pub fn flushes<E: Encoder>(make_encoder: impl Fn() -> E) {
+    run(make_encoder, |mut enc| async move {
+        enc.write_response(response(StatusCode::OK, None)).await?;
+        enc.flush().await?;
+        for _ in 0..4 {
+            enc.write_body_chunk("data: tick\n\n".into()).await?;
+            enc.flush().await?;
+        }
+        enc.flush().await?;
+        end_body(&mut enc).await?;
+        Ok(())
+    })
+}
+
+/// Announced trailers after a body without a `content-length`, then
+/// announced trailers that end up not being sent
+pub fn announced_trailers<E: Encoder>(make_encoder: impl Fn() -> E) {
+    for send_them in [true, false] {
+        run(&make_encoder, |mut enc| async move {
+            let mut res = response(StatusCode::OK, None);
+            res.headers.insert(header::TRAILER, "x-checksum".into());
+            enc.write_response(res).await?;
+            enc.write_body_chunk("hello".into()).await?;
+            enc.write_body_end().await?;
+
+            let mut trailers = Headers::default();
+            if send_them {
+                trailers.insert("x-checksum", "1234".into());
+            }
+            enc.write_trailers(Box::new(trailers)).await?;
+            Ok(())
+        })
+    }
+}
+
+/// Announced trailers after a body with a `content-length`: whether they
+/// can follow is up to the framing, so they may be refused, but with an
+/// error, not a panic
+pub fn trailers_dont_panic<E: Encoder>(make_encoder: impl Fn() -> E) {
+    run(make_encoder, |mut enc| async move {
+        let mut res = response(StatusCode::OK, Some(5));
+        res.headers.insert(header::TRAILER, "x-checksum".into());
+        enc.write_response(res).await?;
+        enc.write_body_chunk("hello".into()).await?;
+        enc.write_body_end().await?;
+
+        let mut trailers = Headers::default();
+        trailers.insert("x-checksum", "1234".into());
+        if let Err(e) = enc.write_trailers(Box::new(trailers)).await {
+            tracing::debug!("encoder refused trailers: {e}");
+        }
+        Ok(())
+    })
+}
+
+/// The handler may go away at any point: dropping the encoder mid-body must
+/// not panic
+pub fn dropped_mid_body<E: Encoder>(make_encoder: impl Fn() -> E) {
+    run(make_encoder, |mut enc| async move {
+        enc.write_response(response(StatusCode::OK, Some(100)))
+            .await?;
+        enc.write_body_chunk("partial".into()).await?;
+        drop(enc);
+        Ok(())
+    })
+}
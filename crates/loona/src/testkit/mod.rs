@@ -0,0 +1,35 @@
+//! Conformance checks for implementations of loona's traits that live outside
+//! of this crate.
+//!
+//! These are meant to be used through the macros,
+//! [encoder_test_suite!](crate::encoder_test_suite) and
+//! [body_test_suite!](crate::body_test_suite), which generate one
+//! `#[test]` per check. Each check starts its own runtime with
+//! [buffet::start] and panics if the implementation misbehaves.
+
+use std::{
+    fs::File,
+    io::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+pub mod body;
+pub mod encoder;
+
+/// Creates a file with the given contents, deletes it, and returns a handle
+/// to it.
+pub(crate) fn anonymous_file(contents: &[u8]) -> File {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let path = std::env::temp_dir().join(format!(
+        "loona-testkit-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    File::create(&path)
+        .and_then(|mut f| f.write_all(contents))
+        .expect("could not create temporary file");
+    let file = File::open(&path).expect("could not open temporary file");
+    std::fs::remove_file(&path).expect("could not remove temporary file");
+    file
+}
@@ -0,0 +1,632 @@
+//! Response body rewriting, see [BodyTransform]
+//!
+//! Changing a body after the handler picked its framing is how you end up
+//! with a `content-length` that doesn't match what's on the wire. A
+//! [TransformEncoder] sits between a [Responder] and the real encoder, and
+//! fixes up the framing of every response it rewrites: either it drops
+//! `content-length` (HTTP/1.1 then switches to chunked), or, for transforms
+//! that ask for it, it holds the whole body back and announces its new
+//! length.
+//!
+//! [TransformDriver] does the wrapping for a whole [ServerDriver]:
+//!
+//! ```ignore
+//! let driver = TransformDriver::new(MyApp, |_req: &Request| InjectScript::default());
+//! h1::serve(io, conf, client_buf, driver).await?;
+//! ```
+
+use std::{fs::File, rc::Rc};
+
+use buffet::Piece;
+use http::{header, HeaderName};
+
+use crate::{
+    Body, Encoder, ExpectResponseHeaders, Headers, HeadersExt, OnComplete, Request, Responder,
+    Response, ResponseDone, ServerDriver,
+};
+
+/// Rewrites response bodies, chunk by chunk
+pub trait BodyTransform {
+    /// Looks at the final response headers, before any of the body, and
+    /// returns false to leave this response alone. Transforms may change
+    /// headers here, e.g. drop an `etag` that won't match the new body.
+    ///
+    /// Not called for 1xx, 204 and 304 responses. Transforms that can't
+    /// make sense of compressed bodies should check `content-encoding`.
+    fn start(&mut self, res: &mut Response) -> bool;
+
+    /// Rewrites a chunk of the body, pushing the result to `out`. Chunk
+    /// boundaries are arbitrary: a transform looking for a pattern can hold
+    /// on to the end of a chunk, and push it along with the next one.
+    fn transform(&mut self, chunk: Piece, out: &mut Vec<Piece>);
+
+    /// Called at the end of the body, pushes anything that was held back
+    fn finish(&mut self, out: &mut Vec<Piece>);
+
+    /// The request headers this transform's output depends on, e.g.
+    /// `accept-encoding` for compression. They're added to the `vary` header
+    /// of every final response, rewritten or not, since caches have to tell
+    /// them apart either way.
+    fn vary(&self) -> &[HeaderName] {
+        &[]
+    }
+
+    /// When true, the whole body goes through the transform before anything
+    /// is written, so the response can announce its new `content-length`
+    /// instead of being chunked. Costs memory and latency, and flushes are
+    /// ignored.
+    fn buffer_body(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum TransformError<EncoderError> {
+    /// The wrapped encoder errored out
+    #[error("encoder error: {0}")]
+    Encoder(EncoderError),
+
+    /// Reading a file chunk, so it could be transformed, failed
+    #[error("error reading file body: {0}")]
+    ReadFile(std::io::Error),
+}
+
+enum TransformState {
+    /// Not rewriting the current response
+    Passthrough,
+
+    /// Rewriting chunks as they come
+    Streaming,
+
+    /// Rewriting chunks, holding back the response until the end
+    Buffering {
+        res: Box<Response>,
+        chunks: Vec<Piece>,
+    },
+}
+
+/// An [Encoder] that runs the final response's body through a
+/// [BodyTransform] before handing it to another encoder
+pub struct TransformEncoder<E, T> {
+    inner: E,
+    transform: T,
+    state: TransformState,
+    out: Vec<Piece>,
+}
+
+impl<E, T> TransformEncoder<E, T>
+where
+    E: Encoder,
+    T: BodyTransform,
+{
+    pub fn new(inner: E, transform: T) -> Self {
+        Self {
+            inner,
+            transform,
+            state: TransformState::Passthrough,
+            out: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+
+    /// Writes what the transform pushed to `self.out`, or holds on to it
+    async fn write_out(&mut self) -> Result<(), TransformError<E::Error>> {
+        match &mut self.state {
+            TransformState::Buffering { chunks, .. } => {
+                chunks.extend(self.out.drain(..).filter(|c| !c.is_empty()));
+            }
+            _ => {
+                for chunk in self.out.drain(..) {
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    self.inner
+                        .write_body_chunk(chunk)
+                        .await
+                        .map_err(TransformError::Encoder)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<E, T> Encoder for TransformEncoder<E, T>
+where
+    E: Encoder,
+    T: BodyTransform,
+{
+    type Error = TransformError<E::Error>;
+
+    async fn write_response(&mut self, mut res: Response) -> Result<(), Self::Error> {
+        if !res.status.is_informational() {
+            for name in self.transform.vary() {
+                res.headers.append_vary(name);
+            }
+        }
+
+        if !res.status.is_informational()
+            && !res.means_empty_body()
+            && self.transform.start(&mut res)
+        {
+            // whatever the handler announced is about the body before we
+            // got to it
+            res.headers.remove(header::CONTENT_LENGTH);
+            if self.transform.buffer_body() {
+                self.state = TransformState::Buffering {
+                    res: Box::new(res),
+                    chunks: Vec::new(),
+                };
+                return Ok(());
+            }
+            self.state = TransformState::Streaming;
+        }
+
+        self.inner
+            .write_response(res)
+            .await
+            .map_err(TransformError::Encoder)
+    }
+
+    async fn write_body_chunk(&mut self, chunk: Piece) -> Result<(), Self::Error> {
+        if let TransformState::Passthrough = self.state {
+            return self
+                .inner
+                .write_body_chunk(chunk)
+                .await
+                .map_err(TransformError::Encoder);
+        }
+
+        self.transform.transform(chunk, &mut self.out);
+        self.write_out().await
+    }
+
+    async fn write_body_file(
+        &mut self,
+        file: Rc<File>,
+        mut offset: u64,
+        len: u64,
+    ) -> Result<(), Self::Error> {
+        if let TransformState::Passthrough = self.state {
+            return self
+                .inner
+                .write_body_file(file, offset, len)
+                .await
+                .map_err(TransformError::Encoder);
+        }
+
+        // the transform needs to see the bytes, so they go through memory
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk = buffet::read_file_piece(&file, offset, remaining)
+                .map_err(TransformError::ReadFile)?;
+            offset += chunk.len() as u64;
+            remaining -= chunk.len() as u64;
+            self.write_body_chunk(chunk).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_body_end(&mut self) -> Result<(), Self::Error> {
+        if !matches!(self.state, TransformState::Passthrough) {
+            self.transform.finish(&mut self.out);
+            self.write_out().await?;
+        }
+
+        if let TransformState::Buffering { mut res, chunks } =
+            std::mem::replace(&mut self.state, TransformState::Passthrough)
+        {
+            let len: usize = chunks.iter().map(|c| c.len()).sum();
+            res.headers
+                .insert(header::CONTENT_LENGTH, len.to_string().into_bytes().into());
+            self.inner
+                .write_response(*res)
+                .await
+                .map_err(TransformError::Encoder)?;
+            for chunk in chunks {
+                self.inner
+                    .write_body_chunk(chunk)
+                    .await
+                    .map_err(TransformError::Encoder)?;
+            }
+        }
+        self.state = TransformState::Passthrough;
+
+        self.inner
+            .write_body_end()
+            .await
+            .map_err(TransformError::Encoder)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        if let TransformState::Buffering { .. } = self.state {
+            return Ok(());
+        }
+        self.inner.flush().await.map_err(TransformError::Encoder)
+    }
+
+    async fn write_trailers(&mut self, trailers: Box<Headers>) -> Result<(), Self::Error> {
+        self.inner
+            .write_trailers(trailers)
+            .await
+            .map_err(TransformError::Encoder)
+    }
+    fn on_complete(&mut self, callback: OnComplete) {
+        self.inner.on_complete(callback)
+    }
+
+    fn is_head_response(&self) -> bool {
+        self.inner.is_head_response()
+    }
+
+    fn accepts_trailers(&self) -> bool {
+        self.inner.accepts_trailers()
+    }
+
+    fn close_delimited(&mut self) -> bool {
+        self.inner.close_delimited()
+    }
+}
+
+/// A [ServerDriver] that runs every response of the driver it wraps
+/// through a fresh [BodyTransform]
+pub struct TransformDriver<D, F> {
+    inner: D,
+    make_transform: F,
+}
+
+impl<D, F> TransformDriver<D, F> {
+    /// `make_transform` is called once per request
+    pub fn new(inner: D, make_transform: F) -> Self {
+        Self {
+            inner,
+            make_transform,
+        }
+    }
+}
+
+impl<E, D, F, T> ServerDriver<E> for TransformDriver<D, F>
+where
+    E: Encoder,
+    D: ServerDriver<TransformEncoder<E, T>>,
+    F: Fn(&Request) -> T,
+    T: BodyTransform,
+{
+    type Error = D::Error;
+
+    async fn handle(
+        &self,
+        req: Request,
+        req_body: &mut impl Body,
+        respond: Responder<E, ExpectResponseHeaders>,
+    ) -> Result<Responder<E, ResponseDone>, Self::Error> {
+        let transform = (self.make_transform)(&req);
+        let respond = Responder::new(TransformEncoder::new(respond.into_encoder(), transform));
+        let done = self.inner.handle(req, req_body, respond).await?;
+        Ok(Responder::done(done.into_inner().into_inner()))
+    }
+
+    fn origins(&self) -> Option<Vec<String>> {
+        self.inner.origins()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, fs::File, rc::Rc};
+
+    use b_x::BX;
+    use buffet::Piece;
+    use http::{header, HeaderName, StatusCode};
+
+    use super::{BodyTransform, TransformDriver, TransformEncoder};
+    use crate::{
+        Body, Encoder, ExpectResponseHeaders, Headers, HeadersExt, Request, Responder, Response,
+        ResponseDone, ServerDriver, SinglePieceBody,
+    };
+
+    /// Records the responses and body it's given
+    #[derive(Default, Clone)]
+    struct RecordingEncoder {
+        responses: Rc<RefCell<Vec<Response>>>,
+        body: Rc<RefCell<Vec<u8>>>,
+        ended: Rc<RefCell<bool>>,
+    }
+
+    impl Encoder for RecordingEncoder {
+        type Error = BX;
+
+        async fn write_response(&mut self, res: Response) -> Result<(), Self::Error> {
+            self.responses.borrow_mut().push(res);
+            Ok(())
+        }
+        async fn write_body_chunk(&mut self, chunk: Piece) -> Result<(), Self::Error> {
+            self.body.borrow_mut().extend_from_slice(&chunk[..]);
+            Ok(())
+        }
+        async fn write_body_file(
+            &mut self,
+            file: Rc<File>,
+            offset: u64,
+            len: u64,
+        ) -> Result<(), Self::Error> {
+            let chunk = buffet::read_file_piece(&file, offset, len)?;
+            self.write_body_chunk(chunk).await
+        }
+        async fn write_body_end(&mut self) -> Result<(), Self::Error> {
+            *self.ended.borrow_mut() = true;
+            Ok(())
+        }
+        async fn write_trailers(&mut self, _: Box<Headers>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    /// Injects a script before `</body>`, even when the tag is split across
+    /// chunks
+    #[derive(Default)]
+    struct InjectScript {
+        held: Vec<u8>,
+        buffer: bool,
+    }
+
+    const TAG: &[u8] = b"</body>";
+    const SCRIPT: &[u8] = b"<script>reload()</script>";
+
+    impl BodyTransform for InjectScript {
+        fn start(&mut self, res: &mut Response) -> bool {
+            res.headers
+                .get(header::CONTENT_TYPE)
+                .map_or(true, |ct| ct.starts_with(b"text/html"))
+        }
+
+        fn transform(&mut self, chunk: Piece, out: &mut Vec<Piece>) {
+            self.held.extend_from_slice(&chunk[..]);
+            if let Some(pos) = memchr::memmem::find(&self.held, TAG) {
+                let mut injected = self.held[..pos].to_vec();
+                injected.extend_from_slice(SCRIPT);
+                injected.extend_from_slice(&self.held[pos..]);
+                self.held.clear();
+                out.push(injected.into());
+                return;
+            }
+            // keep what could be the start of the tag
+            let keep = self.held.len().min(TAG.len() - 1);
+            let rest = self.held.split_off(self.held.len() - keep);
+            out.push(std::mem::replace(&mut self.held, rest).into());
+        }
+
+        fn finish(&mut self, out: &mut Vec<Piece>) {
+            out.push(std::mem::take(&mut self.held).into());
+        }
+
+        fn buffer_body(&self) -> bool {
+            self.buffer
+        }
+    }
+
+    fn html_response(content_type: &str, len: usize) -> Response {
+        let mut res = Response::default();
+        res.headers.insert(
+            header::CONTENT_TYPE,
+            content_type.to_string().into_bytes().into(),
+        );
+        res.headers
+            .insert(header::CONTENT_LENGTH, len.to_string().into_bytes().into());
+        res
+    }
+
+    async fn respond(
+        transform: InjectScript,
+        content_type: &str,
+        chunks: &[&'static [u8]],
+    ) -> RecordingEncoder {
+        let recorder = RecordingEncoder::default();
+        let len = chunks.iter().map(|c| c.len()).sum();
+        let mut res = Responder::new(TransformEncoder::new(recorder.clone(), transform))
+            .write_final_response(html_response(content_type, len))
+            .await
+            .unwrap();
+        for &chunk in chunks {
+            res.write_chunk(chunk.into()).await.unwrap();
+        }
+        res.finish_body(None).await.unwrap();
+        assert!(*recorder.ended.borrow());
+        recorder
+    }
+
+    const PAGE: [&[u8]; 3] = [b"<html><body>hi</bo", b"dy", b"></html>"];
+    const TRANSFORMED: &[u8] = b"<html><body>hi<script>reload()</script></body></html>";
+
+    #[tokio::test]
+    async fn test_streaming_drops_content_length() {
+        let recorder = respond(InjectScript::default(), "text/html", &PAGE).await;
+
+        assert_eq!(&recorder.body.borrow()[..], TRANSFORMED);
+        let responses = recorder.responses.borrow();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].headers.content_length(), None);
+    }
+
+    #[tokio::test]
+    async fn test_buffering_recomputes_content_length() {
+        let transform = InjectScript {
+            buffer: true,
+            ..Default::default()
+        };
+        let recorder = respond(transform, "text/html", &PAGE).await;
+
+        assert_eq!(&recorder.body.borrow()[..], TRANSFORMED);
+        let responses = recorder.responses.borrow();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(
+            responses[0].headers.content_length(),
+            Some(TRANSFORMED.len() as u64)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_passthrough() {
+        let recorder = respond(InjectScript::default(), "text/plain", &PAGE).await;
+
+        assert_eq!(&recorder.body.borrow()[..], PAGE.concat());
+        let responses = recorder.responses.borrow();
+        assert_eq!(
+            responses[0].headers.content_length(),
+            Some(PAGE.concat().len() as u64)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_interim_and_empty_responses_untouched() {
+        let recorder = RecordingEncoder::default();
+        let mut res = Responder::new(TransformEncoder::new(
+            recorder.clone(),
+            InjectScript::default(),
+        ));
+        res.write_interim_response(Response {
+            status: StatusCode::CONTINUE,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let mut not_modified = html_response("text/html", 0);
+        not_modified.status = StatusCode::NOT_MODIFIED;
+        res.write_final_response_with_body(not_modified, &mut SinglePieceBody::from(""))
+            .await
+            .unwrap();
+
+        let responses = recorder.responses.borrow();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[1].headers.content_length(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_driver() {
+        struct Page;
+
+        impl<E: Encoder> ServerDriver<E> for Page {
+            type Error = BX;
+
+            async fn handle(
+                &self,
+                _req: Request,
+                _req_body: &mut impl Body,
+                respond: Responder<E, ExpectResponseHeaders>,
+            ) -> Result<Responder<E, ResponseDone>, Self::Error> {
+                let page = PAGE.concat();
+                let res = html_response("text/html", page.len());
+                respond
+                    .write_final_response_with_body(res, &mut SinglePieceBody::from(page))
+                    .await
+                    .map_err(BX::from_err)
+            }
+        }
+
+        let driver = TransformDriver::new(Page, |_: &Request| InjectScript::default());
+        let recorder = RecordingEncoder::default();
+        driver
+            .handle(
+                Request::default(),
+                &mut (),
+                Responder::new(recorder.clone()),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(&recorder.body.borrow()[..], TRANSFORMED);
+        assert_eq!(
+            recorder.responses.borrow()[0].headers.content_length(),
+            None
+        );
+    }
+
+    /// Uppercases bodies for clients that ask for it with `accept-encoding`
+    struct Shout {
+        enabled: bool,
+    }
+
+    impl BodyTransform for Shout {
+        fn start(&mut self, _res: &mut Response) -> bool {
+            self.enabled
+        }
+
+        fn transform(&mut self, chunk: Piece, out: &mut Vec<Piece>) {
+            out.push(chunk.to_ascii_uppercase().into());
+        }
+
+        fn finish(&mut self, _out: &mut Vec<Piece>) {}
+
+        fn vary(&self) -> &[HeaderName] {
+            &[header::ACCEPT_ENCODING]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_vary() {
+        for (accept_encoding, status, body) in [
+            ("shout", StatusCode::OK, &b"HELLO"[..]),
+            ("identity", StatusCode::OK, b"hello"),
+            ("shout", StatusCode::NOT_MODIFIED, b""),
+        ] {
+            let recorder = RecordingEncoder::default();
+            let mut respond = Responder::new(TransformEncoder::new(
+                recorder.clone(),
+                Shout {
+                    enabled: accept_encoding == "shout",
+                },
+            ));
+            respond
+                .write_interim_response(Response {
+                    status: StatusCode::CONTINUE,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+
+            let mut res = Response {
+                status,
+                ..Default::default()
+            };
+            res.headers.insert(header::VARY, "origin".into());
+            let mut piece_body = SinglePieceBody::from(if body.is_empty() { "" } else { "hello" });
+            respond
+                .write_final_response_with_body(res, &mut piece_body)
+                .await
+                .unwrap();
+
+            let responses = recorder.responses.borrow();
+            assert!(responses[0].headers.get(header::VARY).is_none());
+            assert_eq!(
+                &responses[1].headers.get(header::VARY).unwrap()[..],
+                b"origin, accept-encoding"
+            );
+            assert_eq!(&recorder.body.borrow()[..], body);
+        }
+    }
+
+    mod streaming_conformance {
+        use super::{InjectScript, RecordingEncoder, TransformEncoder};
+
+        crate::encoder_test_suite!(|| TransformEncoder::new(
+            RecordingEncoder::default(),
+            InjectScript::default()
+        ));
+    }
+
+    mod buffering_conformance {
+        use super::{InjectScript, RecordingEncoder, TransformEncoder};
+
+        crate::encoder_test_suite!(|| TransformEncoder::new(
+            RecordingEncoder::default(),
+            InjectScript {
+                buffer: true,
+                ..Default::default()
+            }
+        ));
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt;
+
+use buffet::Piece;
+use http::HeaderName;
+use tokio::sync::mpsc;
+
+use super::{Body, BodyChunk, Headers};
+
+enum Message {
+    Chunk(Piece),
+    Done(Option<Box<Headers>>),
+}
+
+/// A body whose chunks come from a [BodySender], e.g. one that a task
+/// spawned by the handler writes to while the response goes out.
+///
+/// ```ignore
+/// let (tx, mut body) = ChannelBody::new(8);
+/// buffet::spawn(async move {
+///     for row in rows {
+///         tx.send(render(row).into()).await?;
+///     }
+///     tx.finish(None).await
+/// });
+/// respond.write_final_response_with_body(res, &mut body).await?;
+/// ```
+///
+/// Chunks are flushed as they're written, cf. [Body::flush_each_chunk].
+pub struct ChannelBody {
+    rx: mpsc::Receiver<Message>,
+    done: bool,
+    trailers: Vec<HeaderName>,
+}
+
+/// The sending half of a [ChannelBody]
+pub struct BodySender {
+    tx: mpsc::Sender<Message>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ChannelBodyError {
+    /// The [BodySender] went away without calling [BodySender::finish]: the
+    /// body is incomplete, and mustn't look like it ended normally
+    #[error("body sender dropped before finishing the body")]
+    SenderDropped,
+}
+
+/// Returned by [BodySender] when the [ChannelBody] is gone, e.g. because
+/// the peer went away and the response was abandoned
+#[derive(Debug, thiserror::Error)]
+#[error("channel body was dropped")]
+pub struct BodyReceiverDropped;
+
+impl ChannelBody {
+    /// Up to `capacity` chunks can be waiting to be written: past that,
+    /// [BodySender::send] waits for room.
+    pub fn new(capacity: usize) -> (BodySender, ChannelBody) {
+        let (tx, rx) = mpsc::channel(capacity);
+        (
+            BodySender { tx },
+            ChannelBody {
+                rx,
+                done: false,
+                trailers: Vec::new(),
+            },
+        )
+    }
+
+    /// Lets the response announce the trailers [BodySender::finish] will
+    /// send, cf. [Body::announced_trailers]
+    pub fn announce_trailers(&mut self, names: impl IntoIterator<Item = HeaderName>) {
+        self.trailers.extend(names);
+    }
+}
+
+impl BodySender {
+    /// Queues a chunk, waiting if the channel is full
+    pub async fn send(&self, chunk: Piece) -> Result<(), BodyReceiverDropped> {
+        self.tx
+            .send(Message::Chunk(chunk))
+            .await
+            .map_err(|_| BodyReceiverDropped)
+    }
+
+    /// Ends the body, with trailers if the protocol allows for them
+    pub async fn finish(self, trailers: Option<Box<Headers>>) -> Result<(), BodyReceiverDropped> {
+        self.tx
+            .send(Message::Done(trailers))
+            .await
+            .map_err(|_| BodyReceiverDropped)
+    }
+
+    /// Whether the [ChannelBody] is gone, in which case there's no point
+    /// producing more chunks
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+impl fmt::Debug for ChannelBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChannelBody")
+            .field("done", &self.done)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for BodySender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodySender").finish_non_exhaustive()
+    }
+}
+
+impl Body for ChannelBody {
+    type Error = ChannelBodyError;
+
+    fn content_len(&self) -> Option<u64> {
+        None
+    }
+
+    fn eof(&self) -> bool {
+        self.done
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        if self.done {
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+
+        match self.rx.recv().await {
+            Some(Message::Chunk(chunk)) => Ok(BodyChunk::Chunk(chunk)),
+            Some(Message::Done(trailers)) => {
+                self.done = true;
+                Ok(BodyChunk::Done { trailers })
+            }
+            None => Err(ChannelBodyError::SenderDropped),
+        }
+    }
+
+    fn flush_each_chunk(&self) -> bool {
+        true
+    }
+
+    fn announced_trailers(&self) -> &[HeaderName] {
+        &self.trailers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header;
+
+    use super::{ChannelBody, ChannelBodyError};
+    use crate::{Body, BodyChunk, Headers};
+
+    crate::body_test_suite!(|| {
+        let (tx, body) = ChannelBody::new(1);
+        buffet::spawn(async move {
+            tx.send("hello ".into()).await.unwrap();
+            tx.send("world".into()).await.unwrap();
+            tx.finish(None).await.unwrap();
+        });
+        body
+    });
+
+    #[test]
+    fn test_channel_body() {
+        buffet::start(async move {
+            let (tx, mut body) = ChannelBody::new(1);
+            tx.send("a".into()).await.unwrap();
+            // the channel is full until the body reads from it
+            let send = buffet::spawn(async move {
+                tx.send("b".into()).await.unwrap();
+                let mut trailers = Headers::default();
+                trailers.insert(header::ETAG, "\"b\"".into());
+                tx.finish(Some(Box::new(trailers))).await.unwrap();
+            });
+
+            let collected = body.collect(2).await.unwrap();
+            assert_eq!(&collected[..], b"ab");
+            send.await.unwrap();
+
+            let (tx, mut body) = ChannelBody::new(1);
+            tx.send("a".into()).await.unwrap();
+            drop(tx);
+            assert!(matches!(body.next_chunk().await, Ok(BodyChunk::Chunk(_))));
+            assert!(matches!(
+                body.next_chunk().await,
+                Err(ChannelBodyError::SenderDropped)
+            ));
+
+            let (tx, body) = ChannelBody::new(1);
+            drop(body);
+            assert!(tx.is_closed());
+            assert!(tx.send("a".into()).await.is_err());
+        });
+    }
+}
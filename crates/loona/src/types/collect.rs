@@ -0,0 +1,161 @@
+use std::os::unix::fs::FileExt;
+
+use buffet::Piece;
+
+use super::{Body, BodyChunk};
+
+/// Returned by [Body::collect]
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum CollectError<BodyError> {
+    #[error("body error: {0}")]
+    Body(BodyError),
+
+    /// Reading a [BodyChunk::File] failed
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The body is (or announced it would be) larger than allowed. Whatever
+    /// is left of it wasn't read.
+    #[error("body is larger than {max} bytes")]
+    TooLarge { max: u64 },
+}
+
+pub(crate) async fn collect<B: Body>(
+    body: &mut B,
+    max_bytes: u64,
+) -> Result<Piece, CollectError<B::Error>> {
+    let too_large = CollectError::TooLarge { max: max_bytes };
+    if body.content_len().is_some_and(|len| len > max_bytes) {
+        return Err(too_large);
+    }
+
+    // a body that comes in one chunk is handed back as-is, the others are
+    // copied as they come in
+    let mut first: Option<Piece> = None;
+    let mut out: Vec<u8> = Vec::new();
+    let mut total = 0u64;
+
+    loop {
+        match body.next_chunk().await.map_err(CollectError::Body)? {
+            BodyChunk::Chunk(piece) => {
+                if piece.is_empty() {
+                    continue;
+                }
+                total += piece.len() as u64;
+                if total > max_bytes {
+                    return Err(too_large);
+                }
+                if let Some(first) = first.take() {
+                    out.extend_from_slice(&first[..]);
+                }
+                if out.is_empty() {
+                    first = Some(piece);
+                } else {
+                    out.extend_from_slice(&piece[..]);
+                }
+            }
+            BodyChunk::File { file, offset, len } => {
+                total += len;
+                if total > max_bytes {
+                    return Err(too_large);
+                }
+                if let Some(first) = first.take() {
+                    out.extend_from_slice(&first[..]);
+                }
+                let start = out.len();
+                out.resize(start + len as usize, 0);
+                file.read_exact_at(&mut out[start..], offset)?;
+            }
+            BodyChunk::Done { .. } => break,
+        }
+    }
+
+    Ok(match first {
+        Some(piece) => piece,
+        None => out.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, fmt, rc::Rc};
+
+    use buffet::Piece;
+
+    use super::CollectError;
+    use crate::{error::NeverError, testkit::anonymous_file, Body, BodyChunk, SinglePieceBody};
+
+    /// Yields its chunks one by one, without announcing a length
+    struct Chunks(VecDeque<BodyChunk>);
+
+    impl fmt::Debug for Chunks {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Chunks").finish_non_exhaustive()
+        }
+    }
+
+    impl Body for Chunks {
+        type Error = NeverError;
+
+        fn content_len(&self) -> Option<u64> {
+            None
+        }
+
+        fn eof(&self) -> bool {
+            self.0.is_empty()
+        }
+
+        async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+            Ok(self
+                .0
+                .pop_front()
+                .unwrap_or(BodyChunk::Done { trailers: None }))
+        }
+    }
+
+    #[test]
+    fn test_collect() {
+        buffet::start(async move {
+            let piece = SinglePieceBody::from("hello").collect(5).await.unwrap();
+            assert_eq!(&piece[..], b"hello");
+
+            let file = Rc::new(anonymous_file(b"--world--"));
+            let chunk = |s: &'static str| BodyChunk::Chunk(Piece::from(s));
+            let mut body = Chunks(VecDeque::from([
+                chunk("hello"),
+                chunk(""),
+                chunk(", "),
+                BodyChunk::File {
+                    file,
+                    offset: 2,
+                    len: 5,
+                },
+                chunk("!"),
+            ]));
+            let piece = body.collect(13).await.unwrap();
+            assert_eq!(&piece[..], b"hello, world!");
+
+            let piece = Chunks(VecDeque::new()).collect(0).await.unwrap();
+            assert!(piece.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_collect_too_large() {
+        buffet::start(async move {
+            // the announced length is enough to tell
+            let mut body = SinglePieceBody::from("hello");
+            let err = body.collect(4).await.err().unwrap();
+            assert!(matches!(err, CollectError::TooLarge { max: 4 }));
+            assert!(!body.eof());
+
+            let chunk = |s: &'static str| BodyChunk::Chunk(Piece::from(s));
+            let mut body = Chunks(VecDeque::from([chunk("hello"), chunk("world"), chunk("!")]));
+            let err = body.collect(8).await.err().unwrap();
+            assert!(matches!(err, CollectError::TooLarge { max: 8 }));
+            // we stopped reading as soon as we knew
+            assert_eq!(body.0.len(), 1);
+        });
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use http::Version;
+
+/// What we know about the connection a request came in on.
+///
+/// Servers hand it to drivers as [crate::Request::conn]. Whoever accepts
+/// connections fills in the addresses and TLS details, cf.
+/// [crate::h1::serve_with_conn_info] and [crate::h2::serve_with_conn_info],
+/// the server fills in `version`.
+#[derive(Debug, Clone, Default)]
+pub struct ConnInfo {
+    /// Unique within the process, so logs and traces can tell connections
+    /// apart. Servers assign one if it's still 0 when they get the
+    /// connection.
+    pub id: u64,
+
+    /// The client's address
+    pub peer_addr: Option<SocketAddr>,
+
+    /// The address the client connected to
+    pub local_addr: Option<SocketAddr>,
+
+    /// For connections relayed by a proxy that sent a PROXY protocol header,
+    /// the proxy's address: the header's addresses are in `peer_addr` and
+    /// `local_addr`, cf. [crate::proxy_protocol::ProxyHeader::apply]
+    pub proxied_by: Option<SocketAddr>,
+
+    /// Only set for connections that came in over TLS
+    pub tls: Option<TlsInfo>,
+
+    /// When the connection was accepted
+    pub accepted_at: Option<Instant>,
+
+    /// When the TLS handshake was done, for TLS connections
+    pub tls_done_at: Option<Instant>,
+
+    /// The protocol picked with ALPN, e.g. `h2` or `http/1.1`
+    pub alpn_protocol: Option<Vec<u8>>,
+
+    /// HTTP/1.1 or HTTP/2, depending on which server the connection is
+    /// handled by
+    pub version: Version,
+}
+
+impl ConnInfo {
+    pub(crate) fn assign_id(&mut self) {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        if self.id == 0 {
+            self.id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Details of a TLS session
+#[derive(Debug, Clone, Default)]
+pub struct TlsInfo {
+    /// The name the client asked for with SNI
+    pub server_name: Option<String>,
+
+    /// e.g. `TLSv1_3`
+    pub protocol_version: Option<String>,
+
+    /// e.g. `TLS13_AES_256_GCM_SHA384`
+    pub cipher_suite: Option<String>,
+
+    /// Whether the kernel encrypts and decrypts records (kTLS), rather than
+    /// the TLS library. If so, file bodies can still be spliced to the socket.
+    pub ktls: bool,
+}
@@ -0,0 +1,296 @@
+//! `cookie` and `set-cookie`, cf. <https://httpwg.org/specs/rfc6265.html>
+
+use std::{fmt::Write, time::Duration};
+
+use http::header;
+
+use buffet::Piece;
+
+use super::Headers;
+
+/// Iterates over the cookies a client sent, cf. [super::HeadersExt::cookies]
+pub struct Cookies<'a> {
+    lines: header::ValueIter<'a, Piece>,
+    pairs: std::slice::Split<'a, u8, fn(&u8) -> bool>,
+}
+
+impl<'a> Cookies<'a> {
+    pub(crate) fn new(headers: &'a Headers) -> Self {
+        Self {
+            lines: headers.get_all(header::COOKIE).into_iter(),
+            pairs: split_pairs(&[]),
+        }
+    }
+}
+
+fn split_pairs(line: &[u8]) -> std::slice::Split<'_, u8, fn(&u8) -> bool> {
+    line.split(|&b| b == b';')
+}
+
+impl<'a> Iterator for Cookies<'a> {
+    /// Name and value, the latter without its double quotes if it had any
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some(pair) = self.pairs.next() else {
+                // HTTP/2 clients may send one field per cookie
+                self.pairs = split_pairs(&self.lines.next()?[..]);
+                continue;
+            };
+            let Some(pos) = memchr::memchr(b'=', pair) else {
+                // not a cookie, skip it like browsers do
+                continue;
+            };
+            let name = pair[..pos].trim_ascii();
+            if name.is_empty() {
+                continue;
+            }
+            let mut value = pair[pos + 1..].trim_ascii();
+            if let [b'"', inner @ .., b'"'] = value {
+                value = inner;
+            }
+            return Some((name, value));
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SetCookieError {
+    /// Names are tokens, cf. <https://httpwg.org/specs/rfc9110.html#tokens>
+    #[error("invalid cookie name {0:?}")]
+    InvalidName(String),
+
+    /// Values can't have controls, whitespace, double quotes, commas,
+    /// semicolons or backslashes
+    #[error("invalid value for cookie {0:?}")]
+    InvalidValue(String),
+
+    /// Attribute values can't have controls or semicolons
+    #[error("invalid {0} attribute")]
+    InvalidAttribute(&'static str),
+
+    /// Browsers drop `SameSite=None` cookies that aren't `Secure`
+    #[error("SameSite=None cookies must be Secure")]
+    SameSiteNoneWithoutSecure,
+}
+
+/// The `SameSite` attribute: whether browsers send the cookie along with
+/// cross-site requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    /// Only same-site requests
+    Strict,
+    /// Same-site requests, and top-level navigations from other sites
+    Lax,
+    /// All requests: requires [SetCookie::secure]
+    None,
+}
+
+/// Builds a `set-cookie` value, e.g.
+/// `SetCookie::new("id", "a3fWa").path("/").http_only().secure()`.
+///
+/// Nothing is checked until [SetCookie::build], which reports the first
+/// problem.
+#[derive(Debug, Clone)]
+pub struct SetCookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    same_site: Option<SameSite>,
+    secure: bool,
+    http_only: bool,
+}
+
+impl SetCookie {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+            path: None,
+            domain: None,
+            max_age: None,
+            same_site: None,
+            secure: false,
+            http_only: false,
+        }
+    }
+
+    /// A cookie that makes the client forget `name`: empty, and expired
+    pub fn removal(name: impl Into<String>) -> Self {
+        Self::new(name, "").max_age(Duration::ZERO)
+    }
+
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    /// How long the client should keep the cookie, in whole seconds. Without
+    /// it, the cookie goes away when the browser session ends.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// Only send the cookie over HTTPS
+    pub fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    /// Don't let scripts see the cookie
+    pub fn http_only(mut self) -> Self {
+        self.http_only = true;
+        self
+    }
+
+    /// Checks everything, and renders the header value
+    pub fn build(self) -> Result<Piece, SetCookieError> {
+        if self.name.is_empty() || !self.name.bytes().all(is_token_char) {
+            return Err(SetCookieError::InvalidName(self.name));
+        }
+        if !self.value.bytes().all(is_cookie_octet) {
+            return Err(SetCookieError::InvalidValue(self.name));
+        }
+        if self.same_site == Some(SameSite::None) && !self.secure {
+            return Err(SetCookieError::SameSiteNoneWithoutSecure);
+        }
+
+        let mut out = format!("{}={}", self.name, self.value);
+        for (attr, value) in [("Path", &self.path), ("Domain", &self.domain)] {
+            let Some(value) = value else {
+                continue;
+            };
+            if value.is_empty() || !value.bytes().all(is_attribute_char) {
+                return Err(SetCookieError::InvalidAttribute(attr));
+            }
+            _ = write!(out, "; {attr}={value}");
+        }
+        if let Some(max_age) = self.max_age {
+            _ = write!(out, "; Max-Age={}", max_age.as_secs());
+        }
+        if let Some(same_site) = self.same_site {
+            let same_site = match same_site {
+                SameSite::Strict => "Strict",
+                SameSite::Lax => "Lax",
+                SameSite::None => "None",
+            };
+            _ = write!(out, "; SameSite={same_site}");
+        }
+        if self.secure {
+            out.push_str("; Secure");
+        }
+        if self.http_only {
+            out.push_str("; HttpOnly");
+        }
+        Ok(out.into_bytes().into())
+    }
+}
+
+fn is_token_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+}
+
+fn is_attribute_char(b: u8) -> bool {
+    !b.is_ascii_control() && b != b';' && b < 0x80
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::header;
+
+    use super::{SameSite, SetCookie, SetCookieError};
+    use crate::{Headers, HeadersExt};
+
+    #[test]
+    fn test_cookies() {
+        let mut headers = Headers::default();
+        headers.append(
+            header::COOKIE,
+            "sid=38afes7a8; theme=\"dark\";  lang = en ;;garbage".into(),
+        );
+        headers.append(header::COOKIE, "empty=".into());
+        let cookies: Vec<_> = headers.cookies().collect();
+        assert_eq!(
+            cookies,
+            [
+                (&b"sid"[..], &b"38afes7a8"[..]),
+                (b"theme", b"dark"),
+                (b"lang", b"en"),
+                (b"empty", b""),
+            ]
+        );
+
+        assert_eq!(Headers::default().cookies().count(), 0);
+    }
+
+    #[test]
+    fn test_set_cookie() {
+        let cookie = SetCookie::new("sid", "38afes7a8")
+            .path("/")
+            .domain("example.org")
+            .max_age(Duration::from_secs(3600))
+            .same_site(SameSite::Lax)
+            .secure()
+            .http_only()
+            .build()
+            .unwrap();
+        assert_eq!(
+            &cookie[..],
+            b"sid=38afes7a8; Path=/; Domain=example.org; Max-Age=3600; SameSite=Lax; Secure; HttpOnly"
+        );
+
+        let cookie = SetCookie::removal("sid").build().unwrap();
+        assert_eq!(&cookie[..], b"sid=; Max-Age=0");
+
+        let mut headers = Headers::default();
+        headers.append_set_cookie(SetCookie::new("a", "1")).unwrap();
+        headers.append_set_cookie(SetCookie::new("b", "2")).unwrap();
+        assert_eq!(headers.get_all(header::SET_COOKIE).iter().count(), 2);
+    }
+
+    #[test]
+    fn test_set_cookie_errors() {
+        let err = |cookie: SetCookie| cookie.build().err().unwrap();
+        assert_eq!(
+            err(SetCookie::new("a b", "1")),
+            SetCookieError::InvalidName("a b".into())
+        );
+        assert_eq!(
+            err(SetCookie::new("", "1")),
+            SetCookieError::InvalidName("".into())
+        );
+        assert_eq!(
+            err(SetCookie::new("a", "x;y")),
+            SetCookieError::InvalidValue("a".into())
+        );
+        assert_eq!(
+            err(SetCookie::new("a", "1").path("/x; Secure")),
+            SetCookieError::InvalidAttribute("Path")
+        );
+        assert_eq!(
+            err(SetCookie::new("a", "1").same_site(SameSite::None)),
+            SetCookieError::SameSiteNoneWithoutSecure
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    fmt,
+    fs::File,
+    ops::{Bound, RangeBounds},
+    path::Path,
+    rc::Rc,
+};
+
+use crate::{error::NeverError, Body, BodyChunk};
+
+/// A body that serves (part of) a file.
+///
+/// It yields a single [BodyChunk::File], so the encoder gets to decide how
+/// to move the data: over HTTP/1.1, on io_uring, it's spliced from the file
+/// to the socket without going through userspace, whether the socket is
+/// plaintext or kTLS. Everywhere else, it's read into memory first.
+#[derive(Clone)]
+pub struct FileBody {
+    file: Rc<File>,
+    offset: u64,
+    len: u64,
+    done: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum FileBodyError {
+    /// Opening the file, or getting its metadata, failed
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The requested range doesn't fit in the file
+    #[error("range {start}..{end} is out of bounds for a file of {file_len} bytes")]
+    RangeOutOfBounds { start: u64, end: u64, file_len: u64 },
+}
+
+b_x::make_bxable!(FileBodyError);
+
+impl FileBody {
+    /// Opens the file at `path` and serves all of it
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, FileBodyError> {
+        Self::new(File::open(path)?)
+    }
+
+    /// Serves all of `file`. Its length is the length it has now: if it
+    /// shrinks before being fully written, writing fails.
+    pub fn new(file: File) -> Result<Self, FileBodyError> {
+        let len = file.metadata()?.len();
+        Ok(Self {
+            file: Rc::new(file),
+            offset: 0,
+            len,
+            done: false,
+        })
+    }
+
+    /// Only serve `range` (relative to what this body currently serves).
+    /// The announced content-length is adjusted accordingly.
+    pub fn with_range(mut self, range: impl RangeBounds<u64>) -> Result<Self, FileBodyError> {
+        let start = match range.start_bound() {
+            Bound::Included(&n) => n,
+            Bound::Excluded(&n) => n.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&n) => n.saturating_add(1),
+            Bound::Excluded(&n) => n,
+            Bound::Unbounded => self.len,
+        };
+        if start > end || end > self.len {
+            return Err(FileBodyError::RangeOutOfBounds {
+                start,
+                end,
+                file_len: self.len,
+            });
+        }
+
+        self.offset += start;
+        self.len = end - start;
+        Ok(self)
+    }
+
+    /// The offset in the file this body starts at
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl fmt::Debug for FileBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileBody")
+            .field("offset", &self.offset)
+            .field("len", &self.len)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl Body for FileBody {
+    type Error = NeverError;
+
+    fn content_len(&self) -> Option<u64> {
+        Some(self.len)
+    }
+
+    fn eof(&self) -> bool {
+        self.done
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        if self.done || self.len == 0 {
+            self.done = true;
+            return Ok(BodyChunk::Done { trailers: None });
+        }
+
+        self.done = true;
+        Ok(BodyChunk::File {
+            file: self.file.clone(),
+            offset: self.offset,
+            len: self.len,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileBody;
+    use crate::testkit::anonymous_file;
+
+    mod full {
+        use super::*;
+
+        crate::body_test_suite!(|| FileBody::new(anonymous_file(b"hello world")).unwrap());
+    }
+
+    mod range {
+        use super::*;
+
+        crate::body_test_suite!(|| {
+            FileBody::new(anonymous_file(b"hello world"))
+                .unwrap()
+                .with_range(6..)
+                .unwrap()
+        });
+    }
+
+    mod empty {
+        use super::*;
+
+        crate::body_test_suite!(|| FileBody::new(anonymous_file(b"")).unwrap());
+    }
+
+    #[test]
+    fn test_with_range_out_of_bounds() {
+        let body = || FileBody::new(anonymous_file(b"hello world")).unwrap();
+        assert!(body().with_range(0..12).is_err());
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 5..3;
+        assert!(body().with_range(reversed).is_err());
+        assert!(body().with_range(11..).is_ok());
+    }
+}
@@ -0,0 +1,199 @@
+//! Interning of header names, so a connection doesn't allocate the same
+//! names over and over, cf. [HeaderNameInterner]
+
+use http::{header::InvalidHeaderName, HeaderName};
+
+/// Turns raw header names into [HeaderName]s for the lifetime of a
+/// connection, matching them case-insensitively against the names it has
+/// already seen. The first occurrence of a name goes through
+/// [HeaderName::from_bytes], which maps well-known names to http's static
+/// table; repeats are found with a single probe, and a repeated custom name
+/// (`x-request-id`, `sec-fetch-mode`, ...) shares its first occurrence's
+/// storage instead of being copied again for every request.
+///
+/// It remembers at most `capacity` names, and nothing over 64 bytes: past
+/// that, names are parsed with [HeaderName::from_bytes] as usual.
+#[derive(Debug, Clone)]
+pub struct HeaderNameInterner {
+    names: Vec<Entry>,
+    /// Open-addressed index into `names`, plus one (zero is a free slot).
+    /// Allocated on first use, at least twice as large as `capacity`.
+    slots: Vec<u16>,
+    capacity: usize,
+}
+
+impl Default for HeaderNameInterner {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+impl HeaderNameInterner {
+    pub const DEFAULT_CAPACITY: usize = 64;
+
+    /// Names longer than this are never remembered
+    const MAX_NAME_LEN: usize = 64;
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            names: Vec::new(),
+            slots: Vec::new(),
+            capacity: capacity.min(u16::MAX as usize / 2),
+        }
+    }
+
+    /// Returns the (lowercase) [HeaderName] for `src`, failing like
+    /// [HeaderName::from_bytes] for invalid names.
+    pub fn intern(&mut self, src: &[u8]) -> Result<HeaderName, InvalidHeaderName> {
+        if src.len() > Self::MAX_NAME_LEN || self.capacity == 0 {
+            return HeaderName::from_bytes(src);
+        }
+        if self.slots.is_empty() {
+            self.slots = vec![0; (self.capacity * 2).next_power_of_two()];
+        }
+
+        let key = key(src);
+        let mask = self.slots.len() - 1;
+        let mut slot = hash(key) & mask;
+        loop {
+            match self.slots[slot] {
+                0 => break,
+                index => {
+                    let entry = &self.names[index as usize - 1];
+                    // interned names are lowercase and valid, so any
+                    // case-insensitive match is valid too
+                    if entry.key == key && lowercases_to(src, entry.name.as_str().as_bytes()) {
+                        return Ok(entry.name.clone());
+                    }
+                }
+            }
+            slot = (slot + 1) & mask;
+        }
+
+        let name = HeaderName::from_bytes(src)?;
+        if self.names.len() < self.capacity {
+            self.names.push(Entry {
+                key,
+                name: name.clone(),
+            });
+            self.slots[slot] = self.names.len() as u16;
+        }
+        Ok(name)
+    }
+
+    /// How many names were remembered so far
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    /// cf. [key]
+    key: u32,
+    name: HeaderName,
+}
+
+/// Length, first and last byte of a name, case-insensitively: cheap to
+/// compare, and enough to tell most names apart without looking at them.
+fn key(src: &[u8]) -> u32 {
+    let (first, last) = match src {
+        [] => (0, 0),
+        [first, .., last] => (*first, *last),
+        [only] => (*only, *only),
+    };
+    ((src.len() as u32) << 16)
+        | ((first.to_ascii_lowercase() as u32) << 8)
+        | last.to_ascii_lowercase() as u32
+}
+
+fn hash(key: u32) -> usize {
+    (key.wrapping_mul(0x9e37_79b1) >> 16) as usize
+}
+
+/// Whether `src` is `lower` with any case, eight bytes at a time: much
+/// faster than [slice::eq_ignore_ascii_case] for names.
+fn lowercases_to(src: &[u8], lower: &[u8]) -> bool {
+    if src.len() != lower.len() {
+        return false;
+    }
+    let src = src.chunks_exact(8);
+    let lower = lower.chunks_exact(8);
+    let (src_rest, lower_rest) = (src.remainder(), lower.remainder());
+    src.zip(lower)
+        .all(|(src, lower)| to_lowercase(load(src)) == load(lower))
+        && src_rest
+            .iter()
+            .zip(lower_rest)
+            .all(|(src, lower)| src.to_ascii_lowercase() == *lower)
+}
+
+fn load(chunk: &[u8]) -> u64 {
+    u64::from_le_bytes(chunk.try_into().unwrap())
+}
+
+/// ASCII-lowercases each byte of `x`
+fn to_lowercase(x: u64) -> u64 {
+    const ONES: u64 = 0x0101_0101_0101_0101;
+    const HIGH: u64 = ONES * 0x80;
+    // none of these additions carry over to the next byte
+    let low = x & !HIGH;
+    let at_least_a = low + ONES * (0x80 - b'A' as u64);
+    let past_z = low + ONES * (0x80 - b'Z' as u64 - 1);
+    let upper = at_least_a & !past_z & !x & HIGH;
+    x | (upper >> 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header;
+
+    use super::{to_lowercase, HeaderNameInterner};
+
+    #[test]
+    fn test_to_lowercase() {
+        for b in 0..=255u8 {
+            let x = u64::from_le_bytes([b, b'A', b'z', 0xc1, b'Z', b'@', b'[', b]);
+            let expected =
+                [b, b'A', b'z', 0xc1, b'Z', b'@', b'[', b].map(|b| b.to_ascii_lowercase());
+            assert_eq!(to_lowercase(x).to_le_bytes(), expected, "byte {b:#x}");
+        }
+    }
+
+    #[test]
+    fn test_intern_header_names() {
+        let mut names = HeaderNameInterner::new(4);
+
+        assert_eq!(names.intern(b"Host").unwrap(), header::HOST);
+        assert_eq!(names.intern(b"host").unwrap(), header::HOST);
+        assert_eq!(names.len(), 1);
+
+        let first = names.intern(b"X-Request-Id").unwrap();
+        assert_eq!(first, "x-request-id");
+        let again = names.intern(b"x-request-ID").unwrap();
+        assert_eq!(again, "x-request-id");
+        assert_eq!(
+            first.as_str().as_ptr(),
+            again.as_str().as_ptr(),
+            "repeats share storage"
+        );
+        assert_eq!(names.len(), 2);
+
+        // same length, first and last byte
+        assert_eq!(names.intern(b"Sec-Fetch-Mode").unwrap(), "sec-fetch-mode");
+        assert_eq!(names.intern(b"Sec-Fetch-Site").unwrap(), "sec-fetch-site");
+        assert_eq!(names.intern(b"sec-fetch-mode").unwrap(), "sec-fetch-mode");
+        assert_eq!(names.len(), 4);
+
+        assert!(names.intern(b"bad name").is_err());
+        assert!(names.intern(b"").is_err());
+        assert!(names.intern(&[b'a'; 65]).is_ok());
+        assert_eq!(names.intern(b"x-one").unwrap(), "x-one");
+        assert_eq!(names.len(), 4, "capacity is respected");
+        assert_eq!(names.intern(b"X-ONE").unwrap(), "x-one");
+    }
+}
@@ -1,11 +1,37 @@
 //! Types for HTTP headers
 
-use http::{header, HeaderMap};
+use http::{header, HeaderMap, HeaderName};
 
 use buffet::Piece;
 
+use super::{Cookies, SetCookie, SetCookieError};
+
 pub type Headers = HeaderMap<Piece>;
 
+/// Fields that must not be sent as trailers, cf. <https://httpwg.org/specs/rfc9110.html#trailers.limitations>
+pub const FORBIDDEN_TRAILERS: &[HeaderName] = &[
+    header::TRANSFER_ENCODING,
+    header::CONTENT_LENGTH,
+    header::HOST,
+    header::CACHE_CONTROL,
+    header::EXPECT,
+    header::MAX_FORWARDS,
+    header::PRAGMA,
+    header::RANGE,
+    header::TE,
+    header::IF_MATCH,
+    header::IF_NONE_MATCH,
+    header::IF_MODIFIED_SINCE,
+    header::IF_UNMODIFIED_SINCE,
+    header::IF_RANGE,
+    header::AUTHORIZATION,
+    header::SET_COOKIE,
+    header::CONTENT_ENCODING,
+    header::CONTENT_TYPE,
+    header::CONTENT_RANGE,
+    header::TRAILER,
+];
+
 pub trait HeadersExt {
     /// Returns the content-length header
     fn content_length(&self) -> Option<u64>;
@@ -18,6 +44,30 @@ pub trait HeadersExt {
 
     /// Returns true if the client expects a `100-continue` response
     fn expects_100_continue(&self) -> bool;
+
+    /// Returns true if the `te` header contains `trailers`, i.e. the client
+    /// takes trailers it wasn't told about in advance
+    fn accepts_trailers(&self) -> bool;
+
+    /// The field names listed in the `trailer` header, cf.
+    /// <https://httpwg.org/specs/rfc9110.html#field.trailer>. Names that
+    /// don't parse are skipped.
+    fn announced_trailers(&self) -> Vec<HeaderName>;
+
+    /// Adds `name` to the `vary` header, merging all `vary` lines into one.
+    /// Does nothing if it's already there, or if the response varies on
+    /// everything (`vary: *`).
+    fn append_vary(&mut self, name: &HeaderName);
+
+    /// Adds the fields of `defaults` whose name we don't have at all, with
+    /// all their values.
+    fn merge_defaults(&mut self, defaults: &Self);
+
+    /// The cookies the client sent, over all `cookie` fields
+    fn cookies(&self) -> Cookies<'_>;
+
+    /// Builds `cookie` and adds it as a `set-cookie` field
+    fn append_set_cookie(&mut self, cookie: SetCookie) -> Result<(), SetCookieError>;
 }
 
 impl HeadersExt for HeaderMap<Piece> {
@@ -41,6 +91,66 @@ impl HeadersExt for HeaderMap<Piece> {
         self.get(header::EXPECT)
             .map_or(false, |value| value.eq_ignore_ascii_case(b"100-continue"))
     }
+
+    fn accepts_trailers(&self) -> bool {
+        self.get_all(header::TE)
+            .iter()
+            .flat_map(|value| value.split(|&b| b == b','))
+            .any(|token| token.trim_ascii().eq_ignore_ascii_case(b"trailers"))
+    }
+
+    fn announced_trailers(&self) -> Vec<HeaderName> {
+        self.get_all(header::TRAILER)
+            .iter()
+            .flat_map(|value| value.split(|&b| b == b','))
+            .filter_map(|name| HeaderName::from_bytes(name.trim_ascii()).ok())
+            .collect()
+    }
+
+    fn append_vary(&mut self, name: &HeaderName) {
+        let name = name.as_str().as_bytes();
+        if name == b"*" {
+            self.insert(header::VARY, "*".into());
+            return;
+        }
+
+        let mut merged: Vec<u8> = Vec::new();
+        for value in self.get_all(header::VARY) {
+            for member in value.split(|&b| b == b',') {
+                let member = member.trim_ascii();
+                if member.is_empty() {
+                    continue;
+                }
+                if member == b"*" || member.eq_ignore_ascii_case(name) {
+                    return;
+                }
+                merged.extend_from_slice(member);
+                merged.extend_from_slice(b", ");
+            }
+        }
+        merged.extend_from_slice(name);
+        self.insert(header::VARY, merged.into());
+    }
+
+    fn merge_defaults(&mut self, defaults: &Self) {
+        for name in defaults.keys() {
+            if self.contains_key(name) {
+                continue;
+            }
+            for value in defaults.get_all(name) {
+                self.append(name.clone(), value.clone());
+            }
+        }
+    }
+
+    fn cookies(&self) -> Cookies<'_> {
+        Cookies::new(self)
+    }
+
+    fn append_set_cookie(&mut self, cookie: SetCookie) -> Result<(), SetCookieError> {
+        self.append(header::SET_COOKIE, cookie.build()?);
+        Ok(())
+    }
 }
 
 fn from_digits(bytes: &[u8]) -> Option<u64> {
@@ -69,3 +179,78 @@ fn from_digits(bytes: &[u8]) -> Option<u64> {
 
     Some(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use http::{header, HeaderName};
+
+    use super::{Headers, HeadersExt};
+
+    fn vary(headers: &Headers) -> Vec<&[u8]> {
+        headers
+            .get_all(header::VARY)
+            .iter()
+            .map(|v| &v[..])
+            .collect()
+    }
+
+    #[test]
+    fn test_append_vary() {
+        let mut headers = Headers::default();
+        headers.append_vary(&header::ACCEPT_ENCODING);
+        assert_eq!(vary(&headers), [&b"accept-encoding"[..]]);
+
+        // handler-provided values are kept, and merged into a single line
+        let mut headers = Headers::default();
+        headers.append(header::VARY, "Origin,, Cookie".into());
+        headers.append(header::VARY, "accept-language".into());
+        headers.append_vary(&header::ACCEPT_ENCODING);
+        assert_eq!(
+            vary(&headers),
+            [&b"Origin, Cookie, accept-language, accept-encoding"[..]]
+        );
+
+        // members are case-insensitive, and not repeated
+        headers.append_vary(&header::ORIGIN);
+        headers.append_vary(&header::ACCEPT_ENCODING);
+        assert_eq!(
+            vary(&headers),
+            [&b"Origin, Cookie, accept-language, accept-encoding"[..]]
+        );
+
+        // `*` trumps everything
+        headers.append_vary(&HeaderName::from_static("*"));
+        assert_eq!(vary(&headers), [&b"*"[..]]);
+        headers.append_vary(&header::ACCEPT_ENCODING);
+        assert_eq!(vary(&headers), [&b"*"[..]]);
+    }
+
+    #[test]
+    fn test_merge_defaults() {
+        let mut defaults = Headers::default();
+        defaults.insert(header::SERVER, "loona".into());
+        defaults.append(header::LINK, "</a.css>; rel=preload".into());
+        defaults.append(header::LINK, "</b.js>; rel=preload".into());
+        defaults.insert(header::X_FRAME_OPTIONS, "DENY".into());
+
+        let mut headers = Headers::default();
+        headers.insert(header::SERVER, "custom".into());
+        headers.merge_defaults(&defaults);
+        assert_eq!(&headers[header::SERVER][..], b"custom");
+        assert_eq!(headers.get_all(header::LINK).iter().count(), 2);
+        assert_eq!(&headers[header::X_FRAME_OPTIONS][..], b"DENY");
+    }
+
+    #[test]
+    fn test_announced_trailers() {
+        let mut headers = Headers::default();
+        assert!(headers.announced_trailers().is_empty());
+
+        headers.append(header::TRAILER, "X-Checksum, server-timing".into());
+        headers.append(header::TRAILER, ",bad name,".into());
+        assert_eq!(
+            headers.announced_trailers(),
+            ["x-checksum", "server-timing"].map(HeaderName::from_static)
+        );
+    }
+}
@@ -1,17 +1,55 @@
-use std::fmt::{self, Debug};
+use std::{
+    fmt::{self, Debug},
+    rc::Rc,
+    time::Duration,
+};
 
-use http::{StatusCode, Uri, Version};
+use http::{HeaderName, StatusCode, Uri, Version};
 use tracing::debug;
 
-use buffet::Piece;
+use buffet::{Piece, PieceStr};
 
 mod headers;
 pub use headers::*;
 
+mod header_names;
+pub use header_names::*;
+
 mod method;
 pub use method::*;
 
-use crate::{error::NeverError, util::ReadAndParseError};
+mod file_body;
+pub use file_body::*;
+
+mod channel_body;
+pub use channel_body::*;
+
+mod wire_sizes;
+pub use wire_sizes::*;
+
+mod conn_info;
+pub use conn_info::*;
+
+mod timings;
+pub use timings::*;
+
+mod request_builder;
+pub use request_builder::*;
+
+mod target;
+pub use target::*;
+
+mod cookie;
+pub use cookie::*;
+
+mod collect;
+pub use collect::*;
+
+use crate::{
+    body_adapters::{Limit, MapChunk, PeekableBody, Tee, Timeout},
+    error::NeverError,
+    util::ReadAndParseError,
+};
 
 /// An HTTP request
 #[derive(Clone)]
@@ -26,6 +64,20 @@ pub struct Request {
 
     /// Request headers
     pub headers: Headers,
+
+    /// The connection this request came in on: always set by servers,
+    /// ignored by clients
+    pub conn: Option<Rc<ConnInfo>>,
+
+    /// The HTTP/2 stream this request came in on. Along with
+    /// [ConnInfo::id], that's enough to find it in client-side captures.
+    /// Always `None` for HTTP/1.1.
+    pub stream_id: Option<u32>,
+
+    /// The `:protocol` of an HTTP/2 extended CONNECT request (RFC 8441),
+    /// e.g. `connect-udp`, cf. [crate::connect_udp]. Always `None` for
+    /// HTTP/1.1.
+    pub protocol: Option<PieceStr>,
 }
 
 impl Default for Request {
@@ -35,6 +87,9 @@ impl Default for Request {
             uri: "/".parse().unwrap(),
             version: Version::HTTP_11,
             headers: Default::default(),
+            conn: None,
+            stream_id: None,
+            protocol: None,
         }
     }
 }
@@ -45,6 +100,8 @@ impl fmt::Debug for Request {
             .field("method", &self.method)
             .field("uri", &self.uri)
             .field("version", &self.version)
+            .field("stream_id", &self.stream_id)
+            .field("protocol", &self.protocol)
             .finish()?;
 
         for (name, value) in &self.headers {
@@ -99,6 +156,16 @@ impl Response {
 pub enum BodyChunk {
     Chunk(Piece),
 
+    /// `len` bytes of `file`, starting at `offset`. Encoders that write
+    /// straight to a socket hand this to the kernel instead of copying it
+    /// through userspace, the others read it into memory first: see
+    /// [crate::Encoder::write_body_file].
+    File {
+        file: Rc<std::fs::File>,
+        offset: u64,
+        len: u64,
+    },
+
     /// The body finished, and it matched the announced content-length,
     /// or we were using a framed protocol
     Done {
@@ -148,6 +215,16 @@ pub enum BodyError {
     #[error("invalid chunk terminator: {0}")]
     InvalidChunkTerminator(#[from] ReadAndParseError),
 
+    /// while doing chunked transfer-encoding, the connection was closed
+    /// in the middle of reading the trailer section
+    #[error("connection closed while reading trailers")]
+    ClosedWhileReadingTrailers,
+
+    /// while doing chunked transfer-encoding, the trailer section after the
+    /// last chunk was malformed or too large
+    #[error("invalid trailers: {0}")]
+    InvalidTrailers(ReadAndParseError),
+
     /// `write_chunk` was called but no content-length was announced, and
     /// no chunked transfer-encoding was announced
     #[error("write_chunk called when no body was expected")]
@@ -178,6 +255,95 @@ where
     fn content_len(&self) -> Option<u64>;
     fn eof(&self) -> bool;
     async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error>;
+
+    /// Whether each chunk should reach the peer as soon as it's written,
+    /// rather than possibly being held back by the encoder to be coalesced
+    /// with the next one. Bodies that trickle in over time, like
+    /// [SseBody](crate::sse::SseBody), want this.
+    fn flush_each_chunk(&self) -> bool {
+        false
+    }
+
+    /// The names of the trailer fields this body ends with, if it knows
+    /// them before the first chunk.
+    /// [Responder::write_final_response_with_body] lists them in the
+    /// response's `trailer` header: trailers that weren't announced there
+    /// can only be sent where the protocol allows it, cf.
+    /// [Responder::accepts_trailers].
+    ///
+    /// [Responder::write_final_response_with_body]: crate::Responder::write_final_response_with_body
+    /// [Responder::accepts_trailers]: crate::Responder::accepts_trailers
+    fn announced_trailers(&self) -> &[HeaderName] {
+        &[]
+    }
+
+    /// Reads the whole body into one contiguous [Piece], for handlers that
+    /// need all of it at once (to parse JSON, say). Trailers are dropped.
+    /// Gives up, without reading the rest, as soon as the body turns out to
+    /// be larger than `max_bytes`.
+    async fn collect(&mut self, max_bytes: u64) -> Result<Piece, CollectError<Self::Error>> {
+        collect::collect(self, max_bytes).await
+    }
+
+    /// Fails with [TooLarge](crate::body_adapters::AdapterError::TooLarge) once more than `max_bytes` came
+    /// in, or right away if the body announced more than that
+    fn limit(self, max_bytes: u64) -> Limit<Self> {
+        Limit::new(self, max_bytes)
+    }
+
+    /// Fails with [TimedOut](crate::body_adapters::AdapterError::TimedOut) if any one chunk takes longer
+    /// than `duration` to come in
+    fn timeout(self, duration: Duration) -> Timeout<Self> {
+        Timeout::new(self, duration)
+    }
+
+    /// Runs every chunk through `f`. File chunks are read into memory first,
+    /// and the body no longer announces a length.
+    fn map_chunk<F>(self, f: F) -> MapChunk<Self, F>
+    where
+        F: FnMut(Piece) -> Piece,
+    {
+        MapChunk::new(self, f)
+    }
+
+    /// Shows every chunk to `sink` on its way through, e.g. to hash or log
+    /// the body. File chunks are read into memory first.
+    fn tee<S>(self, sink: S) -> Tee<Self, S>
+    where
+        S: FnMut(&Piece),
+    {
+        Tee::new(self, sink)
+    }
+
+    /// Lets the first `max_bytes` of the body be looked at with
+    /// [PeekableBody::peek], to sniff what it is, before handing it on whole
+    fn peekable(self, max_bytes: usize) -> PeekableBody<Self> {
+        PeekableBody::new(self, max_bytes)
+    }
+}
+
+impl<B: Body> Body for &mut B {
+    type Error = B::Error;
+
+    fn content_len(&self) -> Option<u64> {
+        (**self).content_len()
+    }
+
+    fn eof(&self) -> bool {
+        (**self).eof()
+    }
+
+    async fn next_chunk(&mut self) -> Result<BodyChunk, Self::Error> {
+        (**self).next_chunk().await
+    }
+
+    fn flush_each_chunk(&self) -> bool {
+        (**self).flush_each_chunk()
+    }
+
+    fn announced_trailers(&self) -> &[HeaderName] {
+        (**self).announced_trailers()
+    }
 }
 
 impl Body for () {
@@ -201,9 +367,15 @@ pub enum ServeOutcome {
     /// HTTP/1.1 only: The request we handled had a `connection: close` header
     ClientRequestedConnectionClose,
 
-    /// HTTP/1.1 only: The request we handled had a `connection: close` header
+    /// HTTP/1.1 only: The response we sent had a `connection: close` header,
+    /// or a body that ended when we closed the connection
     ServerRequestedConnectionClose,
 
+    /// HTTP/1.1 only: The connection served
+    /// [crate::h1::ServerConf::max_requests_per_connection] requests, the
+    /// last response had `connection: close`
+    MaxRequestsPerConnectionReached,
+
     // Client closed connection before sending a second request
     /// (without requesting connection close)
     ClientClosedConnectionBetweenRequests,
@@ -216,12 +388,41 @@ pub enum ServeOutcome {
     /// we had to close the entire connection.
     RequestHeadersTooLargeOnHttp1Conn,
 
+    /// HTTP/1.1 only: We accepted a CONNECT request, and the tunnel is done.
+    /// The connection can't go back to HTTP/1.1 after that.
+    TunnelClosed,
+
     /// HTTP/2 only: Client didn't speak HTTP/2 (missing/invalid request line)
     ClientDidntSpeakHttp2,
 
     /// HTTP/2 only: Client sent a GOAWAY frame, and we've sent a response to
     /// the client
     SuccessfulHttp2GracefulShutdown,
+
+    /// The buffer pool was running low, so we turned the client away: with a
+    /// 503 on HTTP/1.1, with a GOAWAY on HTTP/2.
+    RefusedUnderMemoryPressure,
+
+    /// The connection was idle (between requests on HTTP/1.1, without open
+    /// streams on HTTP/2), and the process was running out of file
+    /// descriptors, cf. [crate::fd_budget]
+    PrunedWhileIdle,
+
+    /// HTTP/1.1 only: The request's framing was invalid or used a
+    /// transfer-coding we don't support: we replied with a 400 or a 501 and
+    /// closed the connection, cf. [crate::error::ConnectionError]
+    RejectedInvalidRequest,
+
+    /// HTTP/1.1 only: The client asked to switch the connection to TLS,
+    /// which we don't do: we replied with a 400 and closed the connection,
+    /// cf. [crate::h1::TlsUpgradeRequests]
+    RefusedTlsUpgrade,
+
+    /// HTTP/1.1 only: The handler panicked or returned an error, and the
+    /// connection couldn't be reused: it was in the middle of the response,
+    /// or hadn't read the whole request body. We replied with a 500 if it
+    /// wasn't too late, and closed the connection.
+    HandlerFailed,
 }
 
 pub struct SinglePieceBody {
@@ -286,3 +487,16 @@ impl Body for SinglePieceBody {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    mod unit_body {
+        crate::body_test_suite!(|| ());
+    }
+
+    mod single_piece_body {
+        use crate::SinglePieceBody;
+
+        crate::body_test_suite!(|| SinglePieceBody::from("hello"));
+    }
+}
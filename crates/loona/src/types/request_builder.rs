@@ -0,0 +1,259 @@
+use http::{header, uri::Authority, HeaderName, Uri, Version};
+
+use buffet::Piece;
+
+use super::{Method, Request};
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum RequestBuilderError {
+    #[error("invalid uri: {0}")]
+    InvalidUri(#[from] http::uri::InvalidUri),
+
+    #[error("invalid header name: {0}")]
+    InvalidHeaderName(#[from] header::InvalidHeaderName),
+
+    /// Header values can't contain CR, LF or NUL
+    #[error("invalid value for header {name}")]
+    InvalidHeaderValue { name: HeaderName },
+
+    #[error("unsupported HTTP version {0:?}")]
+    UnsupportedVersion(Version),
+
+    /// HTTP/1.1 requests need a `host` header, or a URI to derive it from
+    #[error("HTTP/1.1 request without a host")]
+    MissingHost,
+
+    /// The `host` header says something else than the URI's authority
+    #[error("host header {host:?} doesn't match the URI's authority {authority}")]
+    HostMismatch { host: String, authority: Authority },
+
+    /// CONNECT requests name the host and port to connect to, and nothing
+    /// else, e.g. `example.org:443`
+    #[error("CONNECT request target must be a host and port")]
+    ConnectWithoutAuthority,
+}
+
+/// Builds a [Request], see [Request::builder].
+///
+/// Errors are kept until [RequestBuilder::build] (or
+/// [RequestBuilder::body]), so calls can be chained: the first one wins.
+#[derive(Debug)]
+pub struct RequestBuilder {
+    req: Request,
+    error: Option<RequestBuilderError>,
+}
+
+impl Request {
+    /// Starts building a `GET /` HTTP/1.1 request. When the URI has an
+    /// authority, `host` is derived from it, and HTTP/2 encoders send it as
+    /// `:authority`.
+    pub fn builder() -> RequestBuilder {
+        RequestBuilder {
+            req: Default::default(),
+            error: None,
+        }
+    }
+}
+
+impl RequestBuilder {
+    pub fn method(mut self, method: Method) -> Self {
+        self.req.method = method;
+        self
+    }
+
+    /// The request target, e.g. `/search?q=loona`,
+    /// `https://example.org/search`, or `example.org:443` for CONNECT
+    pub fn uri<U>(mut self, uri: U) -> Self
+    where
+        U: TryInto<Uri>,
+        U::Error: Into<RequestBuilderError>,
+    {
+        match uri.try_into() {
+            Ok(uri) => self.req.uri = uri,
+            Err(e) => self.fail(e.into()),
+        }
+        self
+    }
+
+    pub fn version(mut self, version: Version) -> Self {
+        self.req.version = version;
+        self
+    }
+
+    /// Appends a header: calling it twice with the same name sends both
+    /// values.
+    pub fn header<K>(mut self, name: K, value: impl Into<Piece>) -> Self
+    where
+        K: TryInto<HeaderName>,
+        K::Error: Into<RequestBuilderError>,
+    {
+        let name = match name.try_into() {
+            Ok(name) => name,
+            Err(e) => {
+                self.fail(e.into());
+                return self;
+            }
+        };
+        let value = value.into();
+        if value.iter().any(|&b| matches!(b, b'\r' | b'\n' | b'\0')) {
+            self.fail(RequestBuilderError::InvalidHeaderValue { name });
+            return self;
+        }
+        self.req.headers.append(name, value);
+        self
+    }
+
+    /// Validates the request and returns it.
+    pub fn build(mut self) -> Result<Request, RequestBuilderError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let req = &mut self.req;
+
+        if !matches!(
+            req.version,
+            Version::HTTP_10 | Version::HTTP_11 | Version::HTTP_2
+        ) {
+            return Err(RequestBuilderError::UnsupportedVersion(req.version));
+        }
+
+        if req.method == Method::Connect
+            && (req.uri.scheme().is_some()
+                || !req.uri.authority().is_some_and(|a| a.port().is_some())
+                || req.uri.path_and_query().is_some_and(|pq| pq != "/"))
+        {
+            return Err(RequestBuilderError::ConnectWithoutAuthority);
+        }
+
+        match (req.uri.authority(), req.headers.get(header::HOST)) {
+            (Some(authority), Some(host)) => {
+                if !host.eq_ignore_ascii_case(authority.as_str().as_bytes()) {
+                    return Err(RequestBuilderError::HostMismatch {
+                        host: String::from_utf8_lossy(host).into_owned(),
+                        authority: authority.clone(),
+                    });
+                }
+            }
+            (Some(authority), None) => {
+                // HTTP/2 has `:authority` for that
+                if req.version != Version::HTTP_2 {
+                    let host = authority.as_str().to_owned().into_bytes();
+                    req.headers.insert(header::HOST, host.into());
+                }
+            }
+            (None, None) if req.version == Version::HTTP_11 => {
+                return Err(RequestBuilderError::MissingHost);
+            }
+            (None, _) => {}
+        }
+
+        Ok(self.req)
+    }
+
+    /// Validates the request and returns it, along with the body to send
+    /// with it.
+    pub fn body<B>(self, body: B) -> Result<(Request, B), RequestBuilderError> {
+        Ok((self.build()?, body))
+    }
+
+    fn fail(&mut self, e: RequestBuilderError) {
+        self.error.get_or_insert(e);
+    }
+}
+
+impl From<std::convert::Infallible> for RequestBuilderError {
+    fn from(e: std::convert::Infallible) -> Self {
+        match e {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header, Version};
+
+    use super::RequestBuilderError;
+    use crate::{Method, Request};
+
+    #[test]
+    fn test_host_from_uri() {
+        let req = Request::builder()
+            .method(Method::Post)
+            .uri("https://example.org:8443/upload?x=1")
+            .header(header::CONTENT_TYPE, "text/plain")
+            .header("x-trace", "a")
+            .header("x-trace", "b")
+            .build()
+            .unwrap();
+        assert_eq!(req.method, Method::Post);
+        assert_eq!(req.uri.path(), "/upload");
+        assert_eq!(&req.headers[header::HOST][..], b"example.org:8443");
+        assert_eq!(req.headers.get_all("x-trace").iter().count(), 2);
+
+        let req = Request::builder()
+            .version(Version::HTTP_2)
+            .uri("https://example.org/")
+            .build()
+            .unwrap();
+        assert!(!req.headers.contains_key(header::HOST));
+
+        let (req, body) = Request::builder()
+            .uri("/")
+            .header(header::HOST, "localhost")
+            .body("hello")
+            .unwrap();
+        assert_eq!(&req.headers[header::HOST][..], b"localhost");
+        assert_eq!(body, "hello");
+    }
+
+    #[test]
+    fn test_validation() {
+        let err = |builder: super::RequestBuilder| builder.build().unwrap_err();
+
+        assert!(matches!(
+            err(Request::builder().uri("/")),
+            RequestBuilderError::MissingHost
+        ));
+        assert!(matches!(
+            err(Request::builder()
+                .uri("http://a.example/")
+                .header(header::HOST, "b.example")),
+            RequestBuilderError::HostMismatch { .. }
+        ));
+        assert!(matches!(
+            err(Request::builder().uri("not a uri")),
+            RequestBuilderError::InvalidUri(_)
+        ));
+        assert!(matches!(
+            err(Request::builder()
+                .uri("http://a.example/")
+                .header("bad name", "x")),
+            RequestBuilderError::InvalidHeaderName(_)
+        ));
+        assert!(matches!(
+            err(Request::builder()
+                .uri("http://a.example/")
+                .header("x-smuggle", "a\r\nb: c")),
+            RequestBuilderError::InvalidHeaderValue { .. }
+        ));
+        assert!(matches!(
+            err(Request::builder()
+                .method(Method::Connect)
+                .uri("http://a.example/")),
+            RequestBuilderError::ConnectWithoutAuthority
+        ));
+        assert!(matches!(
+            err(Request::builder()
+                .version(Version::HTTP_3)
+                .uri("http://a.example/")),
+            RequestBuilderError::UnsupportedVersion(_)
+        ));
+
+        let req = Request::builder()
+            .method(Method::Connect)
+            .uri("a.example:443")
+            .build()
+            .unwrap();
+        assert_eq!(&req.headers[header::HOST][..], b"a.example:443");
+    }
+}
@@ -0,0 +1,251 @@
+use std::borrow::Cow;
+
+use http::Uri;
+
+use super::{Method, Request};
+
+/// The request-target, in one of the four forms of
+/// <https://httpwg.org/specs/rfc9112.html#request.target>. Components are
+/// as sent: see [percent_decode] for the decoded bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestTarget<'a> {
+    /// `/where?q=now`: most requests
+    Origin {
+        path: &'a str,
+        query: Option<&'a str>,
+    },
+
+    /// `http://www.example.org/pub?q=now`: requests to proxies, and every
+    /// HTTP/2 request, since `:scheme` and `:authority` are always known
+    Absolute {
+        scheme: &'a str,
+        authority: &'a str,
+        path: &'a str,
+        query: Option<&'a str>,
+    },
+
+    /// `www.example.com:443`: CONNECT requests, and only them
+    Authority { host: &'a str, port: u16 },
+
+    /// `*`: server-wide OPTIONS requests, and only them
+    Asterisk,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TargetError {
+    #[error("CONNECT request target must be a host and port")]
+    ConnectWithoutAuthorityForm,
+
+    #[error("only CONNECT requests have a host and port as their target")]
+    AuthorityFormWithoutConnect,
+
+    #[error("only OPTIONS requests can target `*`")]
+    AsteriskWithoutOptions,
+
+    #[error("absolute request target without an authority")]
+    AbsoluteWithoutAuthority,
+
+    #[error("request target path must start with a slash")]
+    RelativePath,
+}
+
+impl<'a> RequestTarget<'a> {
+    /// Works out the form of `uri`, and checks that `method` allows it
+    pub fn new(method: &Method, uri: &'a Uri) -> Result<Self, TargetError> {
+        let connect = *method == Method::Connect;
+
+        if let Some(scheme) = uri.scheme_str() {
+            if connect {
+                return Err(TargetError::ConnectWithoutAuthorityForm);
+            }
+            let authority = uri
+                .authority()
+                .map(|a| a.as_str())
+                .filter(|a| !a.is_empty())
+                .ok_or(TargetError::AbsoluteWithoutAuthority)?;
+            return Ok(RequestTarget::Absolute {
+                scheme,
+                authority,
+                path: uri.path(),
+                query: uri.query(),
+            });
+        }
+
+        if let Some(authority) = uri.authority() {
+            if !connect {
+                return Err(TargetError::AuthorityFormWithoutConnect);
+            }
+            let port = authority
+                .port_u16()
+                .ok_or(TargetError::ConnectWithoutAuthorityForm)?;
+            // userinfo isn't allowed in authority-form
+            if authority.as_str().contains('@') {
+                return Err(TargetError::ConnectWithoutAuthorityForm);
+            }
+            return Ok(RequestTarget::Authority {
+                host: authority.host(),
+                port,
+            });
+        }
+
+        if connect {
+            return Err(TargetError::ConnectWithoutAuthorityForm);
+        }
+        if uri.path() == "*" {
+            if *method != Method::Options || uri.query().is_some() {
+                return Err(TargetError::AsteriskWithoutOptions);
+            }
+            return Ok(RequestTarget::Asterisk);
+        }
+        if !uri.path().starts_with('/') {
+            return Err(TargetError::RelativePath);
+        }
+        Ok(RequestTarget::Origin {
+            path: uri.path(),
+            query: uri.query(),
+        })
+    }
+
+    /// The path, still percent-encoded. Empty for authority-form, `*` for
+    /// asterisk-form.
+    pub fn path(&self) -> &'a str {
+        match self {
+            RequestTarget::Origin { path, .. } | RequestTarget::Absolute { path, .. } => path,
+            RequestTarget::Authority { .. } => "",
+            RequestTarget::Asterisk => "*",
+        }
+    }
+
+    /// What follows the `?`, if anything did
+    pub fn query(&self) -> Option<&'a str> {
+        match self {
+            RequestTarget::Origin { query, .. } | RequestTarget::Absolute { query, .. } => *query,
+            _ => None,
+        }
+    }
+
+    /// The path split on `/` and percent-decoded, leading slash excluded:
+    /// `/a%2Fb/c` gives `a/b` and `c`. `None` if the path has malformed
+    /// escapes.
+    pub fn decoded_path_segments(&self) -> Option<Vec<Cow<'a, [u8]>>> {
+        let path = self.path();
+        let path = path.strip_prefix('/').unwrap_or(path);
+        path.split('/')
+            .map(|segment| percent_decode(segment.as_bytes()))
+            .collect()
+    }
+}
+
+impl Request {
+    /// The request-target, checked against the method, cf. [RequestTarget]
+    pub fn target(&self) -> Result<RequestTarget<'_>, TargetError> {
+        RequestTarget::new(&self.method, &self.uri)
+    }
+}
+
+/// Decodes `%XX` escapes. Returns `None` if a `%` isn't followed by two hex
+/// digits, and borrows `input` if there's nothing to decode.
+pub fn percent_decode(input: &[u8]) -> Option<Cow<'_, [u8]>> {
+    if !input.contains(&b'%') {
+        return Some(Cow::Borrowed(input));
+    }
+
+    let mut out = Vec::with_capacity(input.len());
+    let mut bytes = input.iter();
+    while let Some(&b) = bytes.next() {
+        if b == b'%' {
+            let hi = (*bytes.next()? as char).to_digit(16)?;
+            let lo = (*bytes.next()? as char).to_digit(16)?;
+            out.push((hi * 16 + lo) as u8);
+        } else {
+            out.push(b);
+        }
+    }
+    Some(Cow::Owned(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Uri;
+
+    use super::{percent_decode, RequestTarget, TargetError};
+    use crate::Method;
+
+    fn target(method: Method, uri: &'static str) -> Result<RequestTarget<'static>, TargetError> {
+        let uri: &'static Uri = Box::leak(Box::new(uri.parse().unwrap()));
+        RequestTarget::new(&method, uri)
+    }
+
+    #[test]
+    fn test_forms() {
+        assert_eq!(
+            target(Method::Get, "/where?q=now"),
+            Ok(RequestTarget::Origin {
+                path: "/where",
+                query: Some("q=now")
+            })
+        );
+        assert_eq!(
+            target(Method::Get, "http://www.example.org/pub/WWW/"),
+            Ok(RequestTarget::Absolute {
+                scheme: "http",
+                authority: "www.example.org",
+                path: "/pub/WWW/",
+                query: None
+            })
+        );
+        assert_eq!(
+            target(Method::Connect, "www.example.com:80"),
+            Ok(RequestTarget::Authority {
+                host: "www.example.com",
+                port: 80
+            })
+        );
+        assert_eq!(target(Method::Options, "*"), Ok(RequestTarget::Asterisk));
+    }
+
+    #[test]
+    fn test_form_errors() {
+        use TargetError::*;
+
+        assert_eq!(
+            target(Method::Connect, "/"),
+            Err(ConnectWithoutAuthorityForm)
+        );
+        assert_eq!(
+            target(Method::Connect, "www.example.com"),
+            Err(ConnectWithoutAuthorityForm)
+        );
+        assert_eq!(
+            target(Method::Connect, "http://www.example.com:80/"),
+            Err(ConnectWithoutAuthorityForm)
+        );
+        assert_eq!(
+            target(Method::Connect, "user@www.example.com:80"),
+            Err(ConnectWithoutAuthorityForm)
+        );
+        assert_eq!(
+            target(Method::Get, "www.example.com:80"),
+            Err(AuthorityFormWithoutConnect)
+        );
+        assert_eq!(target(Method::Get, "*"), Err(AsteriskWithoutOptions));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        let target = target(Method::Get, "/a%2Fb/%C3%A9t%C3%A9/?x=%20");
+        let target = target.unwrap();
+        assert_eq!(target.path(), "/a%2Fb/%C3%A9t%C3%A9/");
+        assert_eq!(target.query(), Some("x=%20"));
+        let segments = target.decoded_path_segments().unwrap();
+        assert_eq!(segments, [&b"a/b"[..], "été".as_bytes(), b""]);
+
+        assert!(matches!(
+            percent_decode(b"plain"),
+            Some(std::borrow::Cow::Borrowed(b"plain"))
+        ));
+        assert_eq!(percent_decode(b"100%"), None);
+        assert_eq!(percent_decode(b"%zz"), None);
+    }
+}
@@ -0,0 +1,126 @@
+use std::time::{Duration, Instant};
+
+use tracing::Span;
+
+/// When each phase of a request/response exchange happened, so latency can
+/// be attributed to the network, the TLS handshake, the driver, or writing
+/// the response.
+///
+/// Drivers get it along with [crate::WireSizes] by registering a callback
+/// with [crate::Responder::on_complete].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timings {
+    /// When the connection was accepted, if whoever accepted it said so, cf.
+    /// [crate::ConnInfo::accepted_at]
+    pub accepted_at: Option<Instant>,
+
+    /// When the TLS handshake was done, for TLS connections, cf.
+    /// [crate::ConnInfo::tls_done_at]
+    pub tls_done_at: Option<Instant>,
+
+    /// When the request's header section was parsed
+    pub headers_parsed_at: Instant,
+
+    /// When the driver was handed the request
+    pub driver_started_at: Instant,
+
+    /// When the first response header section (informational ones included)
+    /// was written. Unset if the driver never responded.
+    pub first_byte_at: Option<Instant>,
+
+    /// When the exchange was over: the response was written, and for
+    /// HTTP/2, the stream was closed
+    pub last_byte_at: Instant,
+}
+
+impl Timings {
+    /// All phases start out as having happened now
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            accepted_at: None,
+            tls_done_at: None,
+            headers_parsed_at: now,
+            driver_started_at: now,
+            first_byte_at: None,
+            last_byte_at: now,
+        }
+    }
+
+    /// From the connection being accepted to the end of the TLS handshake
+    pub fn handshake(&self) -> Option<Duration> {
+        Some(
+            self.tls_done_at?
+                .saturating_duration_since(self.accepted_at?),
+        )
+    }
+
+    /// From the driver being handed the request to the first response byte:
+    /// that's the driver's (or whatever it waits on) doing
+    pub fn time_to_first_byte(&self) -> Option<Duration> {
+        Some(
+            self.first_byte_at?
+                .saturating_duration_since(self.driver_started_at),
+        )
+    }
+
+    /// From the first response byte to the last
+    pub fn write(&self) -> Option<Duration> {
+        Some(
+            self.last_byte_at
+                .saturating_duration_since(self.first_byte_at?),
+        )
+    }
+
+    /// From the request's header section being parsed to the end
+    pub fn total(&self) -> Duration {
+        self.last_byte_at
+            .saturating_duration_since(self.headers_parsed_at)
+    }
+
+    /// Records durations on a request span that has `ttfb_us`, `write_us`
+    /// and `total_us` fields
+    pub(crate) fn record(&self, span: &Span) {
+        if let Some(ttfb) = self.time_to_first_byte() {
+            span.record("ttfb_us", ttfb.as_micros() as u64);
+        }
+        if let Some(write) = self.write() {
+            span.record("write_us", write.as_micros() as u64);
+        }
+        span.record("total_us", self.total().as_micros() as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Timings;
+
+    #[test]
+    fn test_timings_durations() {
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+        let timings = Timings {
+            accepted_at: Some(t0),
+            tls_done_at: Some(t0 + ms(5)),
+            headers_parsed_at: t0 + ms(6),
+            driver_started_at: t0 + ms(7),
+            first_byte_at: Some(t0 + ms(20)),
+            last_byte_at: t0 + ms(50),
+        };
+        assert_eq!(timings.handshake(), Some(ms(5)));
+        assert_eq!(timings.time_to_first_byte(), Some(ms(13)));
+        assert_eq!(timings.write(), Some(ms(30)));
+        assert_eq!(timings.total(), ms(44));
+
+        let plaintext = Timings {
+            accepted_at: Some(t0),
+            tls_done_at: None,
+            first_byte_at: None,
+            ..timings
+        };
+        assert_eq!(plaintext.handshake(), None);
+        assert_eq!(plaintext.time_to_first_byte(), None);
+        assert_eq!(plaintext.write(), None);
+    }
+}
@@ -0,0 +1,38 @@
+use crate::Timings;
+
+/// How many bytes a request/response exchange took on the wire, framing
+/// included: request line or HEADERS frames, chunk sizes, DATA frame headers
+/// and padding, HPACK-compressed header blocks, etc.
+///
+/// Drivers get it by registering a callback with
+/// [crate::Responder::on_complete].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WireSizes {
+    /// The request line (or pseudo-headers) and header section
+    pub request_headers: u64,
+
+    /// The request body, trailers included
+    pub request_body: u64,
+
+    /// The response header sections, informational ones included
+    pub response_headers: u64,
+
+    /// The response body, trailers included
+    pub response_body: u64,
+}
+
+impl WireSizes {
+    /// Everything received from the client
+    pub fn request_total(&self) -> u64 {
+        self.request_headers + self.request_body
+    }
+
+    /// Everything sent to the client
+    pub fn response_total(&self) -> u64 {
+        self.response_headers + self.response_body
+    }
+}
+
+/// Called with a request's [WireSizes] and [Timings] once the exchange is
+/// over
+pub type OnComplete = Box<dyn FnOnce(WireSizes, Timings)>;
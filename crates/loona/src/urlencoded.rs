@@ -0,0 +1,167 @@
+//! `key=value` pairs separated by `&`: query strings, and
+//! `application/x-www-form-urlencoded` bodies, cf.
+//! <https://url.spec.whatwg.org/#application/x-www-form-urlencoded>
+//!
+//! Iterating doesn't allocate or copy: pairs come out still encoded, as
+//! slices of the input, and [decode] only allocates for the ones that have
+//! something to decode.
+
+use std::borrow::Cow;
+
+use buffet::{Roll, RollSplit};
+
+use crate::Request;
+
+/// Iterates over the pairs of `input`, still encoded. A pair without `=`
+/// has an empty value, empty pairs (as in `a=1&&b=2`) are skipped.
+pub fn pairs(input: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    input
+        .split(|&b| b == b'&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match memchr::memchr(b'=', pair) {
+            Some(pos) => (&pair[..pos], &pair[pos + 1..]),
+            None => (pair, &[][..]),
+        })
+}
+
+/// Like [pairs], but yields slices of `input` that can be kept around
+/// after it's gone, e.g. when parsing a form body.
+pub fn roll_pairs(input: Roll) -> RollPairs {
+    RollPairs {
+        split: input.split_on(b'&'),
+    }
+}
+
+/// Returned by [roll_pairs]
+pub struct RollPairs {
+    split: RollSplit,
+}
+
+impl Iterator for RollPairs {
+    type Item = (Roll, Roll);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let pair = self.split.next()?;
+            if pair.is_empty() {
+                continue;
+            }
+            return Some(match pair.clone().split_once(b'=') {
+                Some(pair) => pair,
+                None => (pair, Roll::empty()),
+            });
+        }
+    }
+}
+
+/// Finds the first value for `key`, decoded
+pub fn get<'a>(input: &'a [u8], key: &str) -> Option<Cow<'a, [u8]>> {
+    pairs(input)
+        .find(|(k, _)| decode(k) == key.as_bytes())
+        .map(|(_, v)| decode(v))
+}
+
+/// Turns `+` into spaces and decodes `%XX` escapes. Malformed escapes are
+/// left as they are, as browsers do. Borrows `input` if there's nothing to
+/// decode.
+pub fn decode(input: &[u8]) -> Cow<'_, [u8]> {
+    if !input.iter().any(|&b| b == b'+' || b == b'%') {
+        return Cow::Borrowed(input);
+    }
+
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let hex = |b: Option<&u8>| b.and_then(|&b| (b as char).to_digit(16));
+                match (hex(input.get(i + 1)), hex(input.get(i + 2))) {
+                    (Some(hi), Some(lo)) => {
+                        out.push((hi * 16 + lo) as u8);
+                        i += 2;
+                    }
+                    _ => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    Cow::Owned(out)
+}
+
+impl Request {
+    /// The pairs of the query string, if any, still encoded
+    pub fn query_pairs(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        pairs(self.uri.query().unwrap_or_default().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use buffet::{Roll, RollMut};
+
+    use super::{decode, get, pairs, roll_pairs};
+    use crate::Request;
+
+    #[test]
+    fn test_pairs() {
+        let parsed: Vec<_> = pairs(b"a=1&&b=&c&d=x=y&=e").collect();
+        assert_eq!(
+            parsed,
+            [
+                (&b"a"[..], &b"1"[..]),
+                (b"b", b""),
+                (b"c", b""),
+                (b"d", b"x=y"),
+                (b"", b"e"),
+            ]
+        );
+        assert_eq!(pairs(b"").count(), 0);
+    }
+
+    #[test]
+    fn test_roll_pairs() {
+        buffet::bufpool::initialize_allocator().unwrap();
+
+        let mut buf = RollMut::alloc().unwrap();
+        buf.put(b"name=J%C3%B6rg+M&empty&&tags=a%2Cb").unwrap();
+        let parsed: Vec<(Roll, Roll)> = roll_pairs(buf.take_all()).collect();
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(&parsed[0].0[..], b"name");
+        assert_eq!(&decode(&parsed[0].1)[..], "Jörg M".as_bytes());
+        assert_eq!(&parsed[1].0[..], b"empty");
+        assert!(parsed[1].1.is_empty());
+        assert_eq!(&decode(&parsed[2].1)[..], b"a,b");
+    }
+
+    #[test]
+    fn test_decode() {
+        assert!(matches!(
+            decode(b"plain"),
+            std::borrow::Cow::Borrowed(b"plain")
+        ));
+        assert_eq!(&decode(b"a+b%20c")[..], b"a b c");
+        // malformed escapes stay
+        assert_eq!(&decode(b"100%")[..], b"100%");
+        assert_eq!(&decode(b"%zz%4")[..], b"%zz%4");
+        assert_eq!(&decode(b"%41%4a")[..], b"AJ");
+    }
+
+    #[test]
+    fn test_query() {
+        let req = Request {
+            uri: "/search?q=loona+http&page=2&q=again".parse().unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(req.query_pairs().count(), 3);
+        let query = req.uri.query().unwrap().as_bytes();
+        assert_eq!(get(query, "q").as_deref(), Some(&b"loona http"[..]));
+        assert_eq!(get(query, "page").as_deref(), Some(&b"2"[..]));
+        assert_eq!(get(query, "missing"), None);
+
+        let req = Request::default();
+        assert_eq!(req.query_pairs().count(), 0);
+    }
+}
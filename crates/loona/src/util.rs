@@ -1,8 +1,10 @@
+use std::fmt;
+
 use nom::IResult;
 use pretty_hex::PrettyHex;
 use tracing::{debug, trace};
 
-use buffet::{ReadOwned, Roll, RollMut};
+use buffet::{Piece, ReadOwned, Roll, RollMut};
 
 use thiserror::Error;
 
@@ -21,10 +23,88 @@ pub enum ReadAndParseError {
     #[error("Buffer limit reached while parsing (limit: {limit})")]
     BufferLimitReachedWhileParsing { limit: usize },
 
-    /// Parsing error
-    // TODO: should we pass any amount of detail here?
-    #[error("Parsing error in parser: {parser}")]
-    ParsingError { parser: &'static str },
+    /// The parser gave up because the input went over one of its limits,
+    /// like a maximum number of headers
+    #[error("Limit exceeded in parser: {parser}")]
+    LimitExceeded { parser: &'static str },
+
+    /// The parser rejected the input
+    #[error("Parsing error in parser {parser}: {kind:?} at offset {offset}: {snippet}")]
+    ParsingError {
+        parser: &'static str,
+        /// What the parser was matching when it gave up
+        kind: nom::error::ErrorKind,
+        /// Where it gave up, from the start of its input
+        offset: usize,
+        /// The input from `offset` on
+        snippet: Snippet,
+    },
+}
+
+/// Up to 32 bytes of input, shown as hex, then escaped
+#[derive(Clone, PartialEq, Eq)]
+pub struct Snippet(Vec<u8>);
+
+impl Snippet {
+    const MAX_LEN: usize = 32;
+
+    pub(crate) fn new(input: &[u8]) -> Self {
+        Self(input[..input.len().min(Self::MAX_LEN)].to_vec())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Snippet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("<end of input>");
+        }
+        write!(
+            f,
+            "{} \"{}\"",
+            pretty_hex::simple_hex(&self.0),
+            self.0.escape_ascii()
+        )
+    }
+}
+
+impl fmt::Debug for Snippet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl ReadAndParseError {
+    /// For a parser that was given `input`, and gave up at `err.input`
+    pub(crate) fn parsing(
+        parser: &'static str,
+        input: &[u8],
+        err: &nom::error::Error<Roll>,
+    ) -> Self {
+        let offset = (err.input.as_ptr() as usize)
+            .wrapping_sub(input.as_ptr() as usize)
+            .min(input.len());
+        ReadAndParseError::ParsingError {
+            parser,
+            kind: err.code,
+            offset,
+            snippet: Snippet::new(&input[offset..]),
+        }
+    }
+
+    /// Whether the peer sent something wrong (or too large), as opposed to
+    /// the read failing
+    pub(crate) fn is_protocol_error(&self) -> bool {
+        matches!(
+            self,
+            ReadAndParseError::BufferLimitReachedWhileParsing { .. }
+                | ReadAndParseError::LimitExceeded { .. }
+                | ReadAndParseError::ParsingError { .. }
+        )
+    }
 }
 
 /// Returns `None` on EOF, error if partially parsed message.
@@ -32,10 +112,26 @@ pub(crate) async fn read_and_parse<Parser, Output>(
     parser_name: &'static str,
     parser: Parser,
     stream: &mut impl ReadOwned,
-    mut buf: RollMut,
+    buf: RollMut,
     max_len: usize,
-    // TODO: proper error handling, no eyre::Result
 ) -> Result<Option<(RollMut, Output)>, ReadAndParseError>
+where
+    Parser: Fn(Roll) -> IResult<Roll, Output>,
+{
+    read_and_parse_keeping_input(parser_name, parser, stream, buf, max_len)
+        .await
+        .map_err(|(e, _input)| e)
+}
+
+/// Like [read_and_parse], but errors come with whatever was buffered, for
+/// [crate::protocol_errors]
+pub(crate) async fn read_and_parse_keeping_input<Parser, Output>(
+    parser_name: &'static str,
+    parser: Parser,
+    stream: &mut impl ReadOwned,
+    mut buf: RollMut,
+    max_len: usize,
+) -> Result<Option<(RollMut, Output)>, (ReadAndParseError, Roll)>
 where
     Parser: Fn(Roll) -> IResult<Roll, Output>,
 {
@@ -60,27 +156,36 @@ where
                     let res;
                     let read_limit = max_len - buf.len();
                     if buf.len() >= max_len {
-                        return Err(ReadAndParseError::BufferLimitReachedWhileParsing {
-                            limit: max_len,
-                        });
+                        return Err((
+                            ReadAndParseError::BufferLimitReachedWhileParsing { limit: max_len },
+                            buf.filled(),
+                        ));
                     }
 
                     if buf.cap() == 0 {
                         trace!("buf had zero cap, reserving");
-                        buf.reserve()?;
+                        if let Err(e) = buf.reserve() {
+                            return Err((e.into(), buf.filled()));
+                        }
                     }
                     trace!(
-                        "Calling read_into (len={}, cap={}, read_limit={read_limit})",
+                        "Calling read_into_vectored (len={}, cap={}, read_limit={read_limit})",
                         buf.len(),
                         buf.cap(),
                     );
-                    (res, buf) = buf.read_into(read_limit, stream).await;
+                    (res, buf) = buf.read_into_vectored(read_limit, stream).await;
 
-                    let n = res.map_err(ReadAndParseError::ReadError)?;
+                    let n = match res {
+                        Ok(n) => n,
+                        Err(e) => return Err((ReadAndParseError::ReadError(e), buf.filled())),
+                    };
                     if n == 0 {
                         if !buf.is_empty() {
-                            return Err(ReadAndParseError::ReadError(
-                                std::io::ErrorKind::UnexpectedEof.into(),
+                            return Err((
+                                ReadAndParseError::ReadError(
+                                    std::io::ErrorKind::UnexpectedEof.into(),
+                                ),
+                                buf.filled(),
                             ));
                         } else {
                             return Ok(None);
@@ -89,15 +194,286 @@ where
 
                     continue;
                 } else {
-                    if let nom::Err::Error(e) = &err {
-                        debug!(?err, "parsing error");
-                        debug!(input = %e.input.to_string_lossy(), "input was");
+                    let (nom::Err::Error(e) | nom::Err::Failure(e)) = &err else {
+                        unreachable!("incomplete input was handled above")
+                    };
+                    if matches!(err, nom::Err::Failure(_))
+                        && e.code == nom::error::ErrorKind::TooLarge
+                    {
+                        return Err((
+                            ReadAndParseError::LimitExceeded {
+                                parser: parser_name,
+                            },
+                            buf.filled(),
+                        ));
                     }
-                    return Err(ReadAndParseError::ParsingError {
-                        parser: parser_name,
-                    });
+                    let filled = buf.filled();
+                    let error = ReadAndParseError::parsing(parser_name, &filled[..], e);
+                    debug!(%error, "parsing error");
+                    return Err((error, filled));
                 }
             }
         };
     }
 }
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats a time as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`,
+/// cf. <https://httpwg.org/specs/rfc9110.html#http.date>
+pub(crate) fn fmt_http_date(time: std::time::SystemTime) -> Piece {
+    Piece::from_fmt(format_args!("{}", HttpDate(time)))
+}
+
+struct HttpDate(std::time::SystemTime);
+
+impl fmt::Display for HttpDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self
+            .0
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let days = secs / 86400;
+        let secs_of_day = secs % 86400;
+        let (year, month, day) = civil_from_days(days as i64);
+
+        write!(
+            f,
+            "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            WEEKDAYS[(days % 7) as usize],
+            day,
+            MONTHS[(month - 1) as usize],
+            year,
+            secs_of_day / 3600,
+            (secs_of_day % 3600) / 60,
+            secs_of_day % 60
+        )
+    }
+}
+
+thread_local! {
+    static DATE_CACHE: std::cell::RefCell<(u64, Piece)> =
+        std::cell::RefCell::new((u64::MAX, Piece::empty()));
+}
+
+/// The current time, formatted for a `date` header. Runtimes are
+/// single-threaded, so each thread keeps the last value it formatted, and
+/// formats a new one at most once per second.
+pub(crate) fn cached_http_date() -> Piece {
+    let now = std::time::SystemTime::now();
+    let secs = now
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    DATE_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if cache.0 != secs {
+            *cache = (secs, fmt_http_date(now));
+        }
+        cache.1.clone()
+    })
+}
+
+/// Formats a time the way Common Log Format does, in UTC, e.g.
+/// `06/Nov/1994:08:49:37 +0000`
+pub(crate) fn fmt_clf_date(time: std::time::SystemTime) -> String {
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let secs_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        (secs_of_day % 3600) / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Formats a time as RFC 3339, in UTC with milliseconds, e.g.
+/// `1994-11-06T08:49:37.000Z`
+pub(crate) fn fmt_rfc3339(time: std::time::SystemTime) -> String {
+    let since_epoch = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let secs_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        (secs_of_day % 3600) / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Parses an IMF-fixdate. The obsolete formats (RFC 850, asctime) aren't
+/// supported: callers treat them like invalid dates.
+pub(crate) fn parse_http_date(input: &[u8]) -> Option<std::time::SystemTime> {
+    let s = std::str::from_utf8(input).ok()?;
+    let (_weekday, rest) = s.split_once(", ")?;
+    let mut parts = rest.split(' ');
+    let day: u32 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|&m| m == month)? as u32 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut hms = parts.next()?.split(':');
+    let h: u64 = hms.next()?.parse().ok()?;
+    let m: u64 = hms.next()?.parse().ok()?;
+    let sec: u64 = hms.next()?.parse().ok()?;
+    if parts.next()? != "GMT" || parts.next().is_some() || hms.next().is_some() {
+        return None;
+    }
+    if !(1..=31).contains(&day) || h > 23 || m > 59 || sec > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+    let secs = days as u64 * 86400 + h * 3600 + m * 60 + sec;
+    Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+}
+
+/// Runs a request handler, catching panics so one misbehaving handler
+/// doesn't take the whole connection down: returns the panic message
+/// instead.
+pub(crate) async fn catch_handler_panic<F: std::future::Future>(
+    handler: F,
+) -> Result<F::Output, String> {
+    use futures_util::FutureExt;
+
+    std::panic::AssertUnwindSafe(handler)
+        .catch_unwind()
+        .await
+        .map_err(|payload| {
+            if let Some(s) = payload.downcast_ref::<&str>() {
+                s.to_string()
+            } else if let Some(s) = payload.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                "(non-string panic payload)".to_string()
+            }
+        })
+}
+
+// cf. http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let m = m as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use buffet::RollMut;
+
+    use super::{fmt_clf_date, fmt_http_date, fmt_rfc3339, parse_http_date, ReadAndParseError};
+
+    #[test]
+    fn test_parsing_error() {
+        buffet::bufpool::initialize_allocator().unwrap();
+        let mut buf = RollMut::alloc().unwrap();
+        buf.put(format!(
+            "GET / HTTP/1.1\r\nbad header: x\r\n{}\r\n\r\n",
+            "a".repeat(64)
+        ))
+        .unwrap();
+        let input = buf.filled();
+
+        let Err(nom::Err::Error(e)) = crate::h1::parse::request(8)(input.clone()) else {
+            panic!("the request should be rejected");
+        };
+        let err = ReadAndParseError::parsing("Http1Request", &input[..], &e);
+        let ReadAndParseError::ParsingError {
+            offset, snippet, ..
+        } = &err
+        else {
+            panic!("expected a parsing error, got {err:?}");
+        };
+        assert_eq!(*offset, 16);
+        assert!(snippet.as_bytes().starts_with(b"bad header"));
+        assert_eq!(snippet.as_bytes().len(), 32);
+
+        let message = err.to_string();
+        assert!(
+            message.starts_with("Parsing error in parser Http1Request: "),
+            "{message}"
+        );
+        assert!(message.contains("at offset 16: 62 61 64 20"), "{message}");
+        assert!(message.contains("\"bad header: x\\r\\naaa"), "{message}");
+    }
+
+    #[test]
+    fn test_http_date() {
+        let t = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(&fmt_http_date(t)[..], b"Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date(b"Sun, 06 Nov 1994 08:49:37 GMT"), Some(t));
+        assert_eq!(
+            &fmt_http_date(UNIX_EPOCH)[..],
+            b"Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+
+        let t = UNIX_EPOCH + Duration::from_secs(1709251199);
+        assert_eq!(&fmt_http_date(t)[..], b"Thu, 29 Feb 2024 23:59:59 GMT");
+        assert_eq!(parse_http_date(&fmt_http_date(t)), Some(t));
+
+        for invalid in [
+            &b"Sunday, 06-Nov-94 08:49:37 GMT"[..],
+            b"Sun Nov  6 08:49:37 1994",
+            b"Sun, 06 Nov 1994 08:49:37 UTC",
+            b"Sun, 06 Nov 1994 08:49 GMT",
+            b"Sun, 32 Nov 1994 08:49:37 GMT",
+        ] {
+            assert_eq!(parse_http_date(invalid), None);
+        }
+    }
+
+    #[test]
+    fn test_clf_date() {
+        let t = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(fmt_clf_date(t), "06/Nov/1994:08:49:37 +0000");
+        assert_eq!(fmt_clf_date(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        let t = UNIX_EPOCH + Duration::from_millis(784_111_777_042);
+        assert_eq!(fmt_rfc3339(t), "1994-11-06T08:49:37.042Z");
+        assert_eq!(fmt_rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    }
+}
@@ -0,0 +1,34 @@
+//! Replays the seeds of the fuzz targets in `crates/loona/fuzz`, and the
+//! inputs in `tests/fuzz-regressions/<target>/`: copy whatever
+//! `cargo fuzz` finds there once it's fixed.
+
+use std::path::Path;
+
+use loona::fuzz::TARGETS;
+
+#[test]
+fn fuzz_seeds() {
+    for target in TARGETS {
+        for (name, input) in (target.seeds)() {
+            eprintln!("{}: seed {name}", target.name);
+            (target.run)(&input);
+        }
+    }
+}
+
+#[test]
+fn fuzz_regressions() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fuzz-regressions");
+    for target in TARGETS {
+        let mut paths = std::fs::read_dir(dir.join(target.name))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        for path in paths {
+            eprintln!("{}: {}", target.name, path.display());
+            (target.run)(&std::fs::read(&path).unwrap());
+        }
+    }
+}
@@ -4,10 +4,15 @@ use std::rc::Rc;
 use b_x::{BxForResults, BX};
 use buffet::{IntoHalves, PipeRead, PipeWrite, ReadOwned, RollMut, WriteOwned};
 use http::StatusCode;
+use httpwg::{
+    faults::{Faults, FaultyIo},
+    shaping::{Shape, ShapedIo},
+};
 use loona::{
-    Body, BodyChunk, Encoder, ExpectResponseHeaders, Responder, Response, ResponseDone,
-    ServerDriver,
+    error::ServeError, h2::types::H2ConnectionError, Body, BodyChunk, Encoder,
+    ExpectResponseHeaders, Responder, Response, ResponseDone, ServerDriver,
 };
+use tokio::task::JoinHandle;
 use tracing::Level;
 use tracing_subscriber::{filter::Targets, layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -37,6 +42,9 @@ pub(crate) fn setup_tracing_and_error_reporting() {
         .init();
 }
 
+/// `/large` responses are this many 10 KB chunks
+const LARGE_BODY_CHUNKS: usize = 10;
+
 struct TestDriver;
 
 impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
@@ -52,6 +60,20 @@ where
         req_body: &mut impl Body,
         mut res: Responder<OurEncoder, ExpectResponseHeaders>,
     ) -> Result<Responder<OurEncoder, ResponseDone>, BX> {
+        // whatever the server is configured to do with connection-specific
+        // headers, they must never reach us
+        for name in [
+            "connection",
+            "keep-alive",
+            "proxy-connection",
+            "transfer-encoding",
+            "upgrade",
+        ] {
+            if _req.headers.contains_key(name) {
+                return Err(BX::from_string(format!("driver got a '{name}' header")));
+            }
+        }
+
         // if the client sent `expect: 100-continue`, we must send a 100 status code
         if let Some(h) = _req.headers.get(http::header::EXPECT) {
             if &h[..] == b"100-continue" {
@@ -78,19 +100,36 @@ where
                 BodyChunk::Chunk(chunk) => {
                     req_body_len += chunk.len();
                 }
+                BodyChunk::File { len, .. } => {
+                    req_body_len += len as usize;
+                }
             }
         }
         tracing::debug!(%req_body_len, "read request body");
 
+        // drivers may build header names from whatever bytes they like,
+        // they still go out lowercase
+        let mut headers = loona::Headers::default();
+        headers.insert(
+            http::HeaderName::from_bytes(b"X-Served-By").unwrap(),
+            "loona".into(),
+        );
         let mut res = res
             .write_final_response(Response {
                 status: StatusCode::OK,
+                headers,
                 ..Default::default()
             })
             .await?;
 
-        res.write_chunk("it's less dire to lose, than to lose oneself".into())
-            .await?;
+        if _req.uri.path() == "/large" {
+            for _ in 0..LARGE_BODY_CHUNKS {
+                res.write_chunk(vec![b'a'; 10_000].into()).await?;
+            }
+        } else {
+            res.write_chunk("it's less dire to lose, than to lose oneself".into())
+                .await?;
+        }
 
         let res = res.finish_body(None).await?;
 
@@ -109,28 +148,75 @@ impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W
 }
 
 pub fn start_server() -> httpwg::Conn<TwoHalves<PipeWrite, PipeRead>> {
+    start_server_with_conf(Default::default())
+}
+
+pub fn start_server_with_conf(
+    server_conf: loona::h2::ServerConf,
+) -> httpwg::Conn<TwoHalves<PipeWrite, PipeRead>> {
+    start_server_with(server_conf, Default::default())
+}
+
+/// Like [start_server_with_conf], with the client's config too
+pub fn start_server_with(
+    server_conf: loona::h2::ServerConf,
+    config: httpwg::Config,
+) -> httpwg::Conn<TwoHalves<PipeWrite, PipeRead>> {
+    let (io, server) = spawn_server(server_conf);
+    buffet::spawn(async move {
+        server.await.unwrap().unwrap();
+    });
+
+    httpwg::Conn::new(Rc::new(config), io)
+}
+
+/// Resolves once the server is done with a connection
+pub type ServerHandle = JoinHandle<Result<(), ServeError<BX>>>;
+
+/// Like [start_server], but the client's IO misbehaves on command.
+pub fn start_faulty_server() -> (
+    httpwg::Conn<FaultyIo<TwoHalves<PipeWrite, PipeRead>>>,
+    Faults,
+    ServerHandle,
+) {
+    let (io, server) = spawn_server(Default::default());
+    let (io, faults) = FaultyIo::new(io);
+
+    let config = Rc::new(httpwg::Config::default());
+    (httpwg::Conn::new(config, io), faults, server)
+}
+
+/// Like [start_server], but over a link with some latency, that splits
+/// whatever goes through it at random points.
+pub fn start_shaped_server(shape: Shape) -> httpwg::Conn<ShapedIo<TwoHalves<PipeWrite, PipeRead>>> {
+    let (io, server) = spawn_server(Default::default());
+    buffet::spawn(async move {
+        server.await.unwrap().unwrap();
+    });
+
+    let config = Rc::new(httpwg::Config::default());
+    httpwg::Conn::new(config, ShapedIo::new(io, shape))
+}
+
+/// Serves HTTP/2 over a pair of pipes, returns the client's end
+fn spawn_server(
+    server_conf: loona::h2::ServerConf,
+) -> (TwoHalves<PipeWrite, PipeRead>, ServerHandle) {
     let (server_write, client_read) = loona::buffet::pipe();
     let (client_write, server_read) = loona::buffet::pipe();
 
-    let serve_fut = async move {
-        let server_conf = Rc::new(loona::h2::ServerConf {
-            ..Default::default()
-        });
+    let server = buffet::spawn(async move {
+        let server_conf = Rc::new(server_conf);
 
         let client_buf = RollMut::alloc()?;
         let driver = Rc::new(TestDriver);
         let io = (server_read, server_write);
         loona::h2::serve(io, server_conf, client_buf, driver).await?;
         tracing::debug!("http/2 server done");
-        Ok::<_, BX>(())
-    };
-
-    buffet::spawn(async move {
-        serve_fut.await.unwrap();
+        Ok(())
     });
 
-    let config = Rc::new(httpwg::Config::default());
-    httpwg::Conn::new(config, TwoHalves(client_write, client_read))
+    (TwoHalves(client_write, client_read), server)
 }
 
 #[cfg(test)]
@@ -143,3 +229,487 @@ httpwg_macros::tests! {{
        result.unwrap()
    });
 }}
+
+/// The whole suite again, with frames straddling reads on both ends
+mod shaped {
+    httpwg_macros::tests! {{
+       crate::setup_tracing_and_error_reporting();
+
+       buffet::start(async move {
+           let conn = crate::start_shaped_server(httpwg::shaping::Shape {
+               latency: std::time::Duration::from_millis(1),
+               mtu: Some(1200),
+               split_seed: Some(0x1005e),
+               ..Default::default()
+           });
+           let result = test(conn).await;
+           result.unwrap()
+       });
+    }}
+}
+
+/// httpwg's `exceeds_concurrent_stream_limit` only sees the stream error, this
+/// also checks how many streams the server had open at once.
+#[test]
+fn max_concurrent_streams_observed() {
+    use httpwg::ErrorC;
+    use loona::h2::observe::{StreamEvent, StreamObserver};
+    use loona_h2::{Setting, StreamId};
+
+    buffet::start(async move {
+        let observer = StreamObserver::default();
+        let mut conn = start_server_with_conf(loona::h2::ServerConf {
+            max_streams: Some(4),
+            stream_observer: Some(observer.clone()),
+            ..Default::default()
+        });
+        conn.handshake().await.unwrap();
+
+        // with no window, the server can't finish any response
+        conn.write_settings(&[(Setting::InitialWindowSize, 0)])
+            .await
+            .unwrap();
+        for i in 0..4 {
+            conn.send_empty_post_to_root(StreamId(1 + i * 2))
+                .await
+                .unwrap();
+        }
+        observer.wait_for_concurrency(4).await;
+
+        conn.send_empty_post_to_root(StreamId(9)).await.unwrap();
+        conn.verify_stream_error(ErrorC::ProtocolError | ErrorC::RefusedStream)
+            .await
+            .unwrap();
+
+        assert_eq!(observer.concurrency(), 4);
+        assert_eq!(observer.max_concurrency(), 4);
+        assert!(!observer
+            .events()
+            .contains(&StreamEvent::Opened(StreamId(9))));
+    });
+}
+
+/// With [ConnectionSpecificHeaders::Strip], requests httpwg expects to be
+/// rejected go through, minus the offending headers.
+///
+/// [ConnectionSpecificHeaders::Strip]: loona::h2::ConnectionSpecificHeaders::Strip
+#[test]
+fn connection_specific_headers_stripped() {
+    use loona::h2::ConnectionSpecificHeaders;
+    use loona_h2::StreamId;
+
+    buffet::start(async move {
+        let mut conn = start_server_with_conf(loona::h2::ServerConf {
+            connection_specific_headers: ConnectionSpecificHeaders::Strip,
+            ..Default::default()
+        });
+        conn.handshake().await.unwrap();
+
+        let cases = [
+            ("connection", "keep-alive"),
+            ("keep-alive", "timeout=5"),
+            ("proxy-connection", "keep-alive"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "foo/2"),
+            ("te", "trailers, deflate"),
+        ];
+        for (i, (name, value)) in cases.into_iter().enumerate() {
+            let mut headers = conn.common_headers("POST");
+            headers.append(name, value);
+            conn.send_req_and_expect_status(StreamId(1 + i as u32 * 2), &headers, 200)
+                .await
+                .unwrap();
+        }
+    });
+}
+
+#[test]
+fn uppercase_header_names_lowercased() {
+    use loona::h2::UppercaseHeaderNames;
+    use loona_h2::StreamId;
+
+    buffet::start(async move {
+        let mut conn = start_server_with_conf(loona::h2::ServerConf {
+            uppercase_header_names: UppercaseHeaderNames::Lowercase,
+            ..Default::default()
+        });
+        conn.handshake().await.unwrap();
+
+        let mut headers = conn.common_headers("POST");
+        headers.append("X-Custom", "oh no");
+        conn.send_req_and_expect_status(StreamId(1), &headers, 200)
+            .await
+            .unwrap();
+    });
+}
+
+#[test]
+fn response_header_names_are_lowercase() {
+    use httpwg::FrameT;
+    use loona_h2::StreamId;
+
+    buffet::start(async move {
+        let mut conn = start_server();
+        conn.handshake().await.unwrap();
+
+        conn.send_empty_post_to_root(StreamId(1)).await.unwrap();
+        let (_frame, payload) = conn.wait_for_frame(FrameT::Headers).await.unwrap();
+        let headers = conn.decode_headers(payload.into()).unwrap();
+
+        let served_by = headers.get_first(&"x-served-by".into()).unwrap();
+        assert_eq!(&served_by[..], b"loona");
+        for (name, _) in headers.iter() {
+            assert!(
+                !name.iter().any(|b| b.is_ascii_uppercase()),
+                "uppercase in {:?}",
+                std::str::from_utf8(name)
+            );
+        }
+    });
+}
+
+#[test]
+fn max_data_frame_size_caps_data_frames() {
+    use httpwg::FrameT;
+    use loona_h2::StreamId;
+
+    buffet::start(async move {
+        let mut conn = start_server_with_conf(loona::h2::ServerConf {
+            max_data_frame_size: Some(16),
+            ..Default::default()
+        });
+        conn.handshake().await.unwrap();
+
+        conn.send_empty_post_to_root(StreamId(1)).await.unwrap();
+        let mut lens = vec![];
+        loop {
+            let (frame, _payload) = conn
+                .wait_for_frame(FrameT::Data)
+                .await
+                .into_result()
+                .unwrap();
+            lens.push(frame.len);
+            if frame.is_end_stream() {
+                break;
+            }
+        }
+        // the peer allows 16 KiB frames, the body is 44 bytes
+        assert!(lens.iter().all(|&len| len <= 16), "{lens:?}");
+        assert_eq!(lens.iter().sum::<u32>(), 44);
+    });
+}
+
+/// httpwg's frame size checks, with a response that needs many frames
+#[test]
+fn large_response_frames_within_max_frame_size() {
+    use httpwg::rfc9113::_4_http_frames as s;
+
+    buffet::start(async move {
+        let config = || httpwg::Config {
+            path: "/large".into(),
+            ..Default::default()
+        };
+        s::response_frames_within_max_frame_size(start_server_with(Default::default(), config()))
+            .await
+            .unwrap();
+        s::response_frames_within_raised_max_frame_size(start_server_with(
+            Default::default(),
+            config(),
+        ))
+        .await
+        .unwrap();
+    });
+}
+
+/// Once the client allows larger frames, DATA frames get larger
+#[test]
+fn data_frames_grow_with_peer_max_frame_size() {
+    use httpwg::FrameT;
+    use loona_h2::{Setting, StreamId};
+
+    buffet::start(async move {
+        let mut conn = start_server_with(
+            Default::default(),
+            httpwg::Config {
+                path: "/large".into(),
+                ..Default::default()
+            },
+        );
+        conn.handshake().await.unwrap();
+        conn.write_and_ack_settings(&[
+            (Setting::MaxFrameSize, 40_000),
+            (Setting::InitialWindowSize, 1 << 20),
+        ])
+        .await
+        .unwrap();
+        conn.write_window_update(StreamId::CONNECTION, 1 << 20)
+            .await
+            .unwrap();
+
+        conn.send_empty_post_to_root(StreamId(1)).await.unwrap();
+        let mut lens = vec![];
+        loop {
+            let (frame, _payload) = conn
+                .wait_for_frame(FrameT::Data)
+                .await
+                .into_result()
+                .unwrap();
+            lens.push(frame.len);
+            if frame.is_end_stream() {
+                break;
+            }
+        }
+        assert!(lens.iter().all(|&len| len <= 40_000), "{lens:?}");
+        assert!(lens.iter().any(|&len| len > 16_384), "{lens:?}");
+        assert_eq!(
+            lens.iter().sum::<u32>() as usize,
+            LARGE_BODY_CHUNKS * 10_000
+        );
+    });
+}
+
+/// What [loona::h2::ServerConf] says is what the server advertises, and
+/// goes by
+#[test]
+fn configured_settings() {
+    use httpwg::{rfc9113::_4_http_frames as s, ErrorC};
+    use loona_h2::{HeadersFlags, StreamId};
+
+    let conf = || loona::h2::ServerConf {
+        initial_window_size: 1 << 20,
+        max_frame_size: 1 << 15,
+        header_table_size: 8192,
+        ..Default::default()
+    };
+
+    buffet::start(async move {
+        let mut conn = start_server_with_conf(conf());
+        conn.handshake().await.unwrap();
+        assert_eq!(conn.settings.initial_window_size, 1 << 20);
+        assert_eq!(conn.settings.max_frame_size, 1 << 15);
+        assert_eq!(conn.settings.header_table_size, 8192);
+
+        // all of it can be sent without waiting for a WINDOW_UPDATE: the
+        // connection window was raised along with the streams'
+        let stream_id = StreamId(1);
+        let block_fragment = conn.encode_headers(&conn.common_headers("POST")).unwrap();
+        conn.write_headers(stream_id, HeadersFlags::EndHeaders, block_fragment)
+            .await
+            .unwrap();
+        for _ in 0..31 {
+            conn.write_data(stream_id, false, vec![0u8; 1 << 15])
+                .await
+                .unwrap();
+        }
+        conn.write_data(stream_id, true, vec![0u8; (1 << 15) - 1])
+            .await
+            .unwrap();
+        conn.verify_headers_frame(stream_id).await.unwrap();
+
+        // httpwg's frame size tests go by the advertised size
+        s::data_frame_with_max_length(start_server_with_conf(conf()))
+            .await
+            .unwrap();
+        s::frame_exceeding_max_size(start_server_with_conf(conf()))
+            .await
+            .unwrap();
+
+        // a smaller window applies once the client acknowledged it
+        let mut conn = start_server_with_conf(loona::h2::ServerConf {
+            initial_window_size: 1000,
+            ..Default::default()
+        });
+        conn.handshake().await.unwrap();
+        let block_fragment = conn.encode_headers(&conn.common_headers("POST")).unwrap();
+        conn.write_headers(stream_id, HeadersFlags::EndHeaders, block_fragment)
+            .await
+            .unwrap();
+        conn.write_data(stream_id, true, vec![0u8; 1001])
+            .await
+            .unwrap();
+        conn.verify_stream_error(ErrorC::FlowControlError)
+            .await
+            .unwrap();
+    });
+}
+
+/// Plays back the h2spec failures recorded with `httpwg --record-h2spec`
+#[test]
+fn h2spec_replays() {
+    use httpwg::replay::Replay;
+
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/h2spec-replays");
+    let mut paths = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "replay"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(!paths.is_empty(), "no replays in {}", dir.display());
+
+    for path in paths {
+        let replay = Replay::parse(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(Replay::parse(&replay.to_string()).unwrap(), replay);
+
+        buffet::start(async move {
+            let conn = start_server();
+            if let Err(e) = replay.run(conn).await {
+                panic!("{} ({}): {e:?}", replay.id, path.display());
+            }
+        });
+    }
+}
+
+/// Waits for the server to be done with a connection the client dropped
+async fn server_done(server: ServerHandle) -> Result<(), ServeError<BX>> {
+    tokio::time::timeout(std::time::Duration::from_secs(1), server)
+        .await
+        .expect("server should notice the connection is gone")
+        .expect("server task shouldn't panic")
+}
+
+#[test]
+fn faults_frames_split_across_reads() {
+    use loona_h2::StreamId;
+
+    buffet::start(async move {
+        let (mut conn, faults, _server) = start_faulty_server();
+        faults.split_writes(1);
+        faults.delay_reads(std::time::Duration::from_millis(1));
+        conn.handshake().await.unwrap();
+
+        let headers = conn.common_headers("POST");
+        conn.send_req_and_expect_status(StreamId(1), &headers, 200)
+            .await
+            .unwrap();
+        conn.verify_connection_still_alive().await.unwrap();
+    });
+}
+
+#[test]
+fn faults_hang_up_mid_frame_header() {
+    use loona_h2::StreamId;
+
+    buffet::start(async move {
+        let (mut conn, faults, server) = start_faulty_server();
+        conn.handshake().await.unwrap();
+
+        // 4 of the 9 bytes of the HEADERS frame header
+        faults.truncate_after(4);
+        assert!(conn.send_empty_post_to_root(StreamId(1)).await.is_err());
+        assert!(faults.is_hung_up());
+
+        let err = server_done(server).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ServeError::H2ConnectionError(H2ConnectionError::ReadAndParse(_))
+            ),
+            "{err:?}"
+        );
+    });
+}
+
+#[test]
+fn faults_hang_up_mid_header_block() {
+    use loona_h2::{HeadersFlags, StreamId};
+
+    buffet::start(async move {
+        let (mut conn, faults, server) = start_faulty_server();
+        conn.handshake().await.unwrap();
+
+        // no END_HEADERS: the server waits for a CONTINUATION that never comes
+        let headers = conn.common_headers("POST");
+        conn.encode_and_write_headers(StreamId(1), HeadersFlags::EndStream, &headers)
+            .await
+            .unwrap();
+        faults.hang_up();
+
+        let err = server_done(server).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ServeError::H2ConnectionError(H2ConnectionError::ExpectedContinuationFrame {
+                    frame_type: None,
+                    ..
+                })
+            ),
+            "{err:?}"
+        );
+        conn.verify_connection_close().await.unwrap();
+    });
+}
+
+#[test]
+fn faults_hang_up_mid_frame_payload() {
+    use loona_h2::{HeadersFlags, StreamId};
+
+    buffet::start(async move {
+        let (mut conn, faults, server) = start_faulty_server();
+        conn.handshake().await.unwrap();
+
+        let headers = conn.common_headers("POST");
+        let block_fragment = conn.encode_headers(&headers).unwrap();
+        // the frame header, and half the header block
+        faults.truncate_after(9 + block_fragment.len() as u64 / 2);
+        let res = conn
+            .write_headers(
+                StreamId(1),
+                HeadersFlags::EndStream | HeadersFlags::EndHeaders,
+                block_fragment,
+            )
+            .await;
+        assert!(res.is_err());
+
+        let err = server_done(server).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ServeError::H2ConnectionError(H2ConnectionError::ReadAndParse(_))
+            ),
+            "{err:?}"
+        );
+        conn.verify_connection_close().await.unwrap();
+    });
+}
+
+#[test]
+fn shaping_splits_and_delays_writes() {
+    use std::time::Duration;
+
+    buffet::start(async move {
+        let (write, mut far_read) = loona::buffet::pipe();
+        let (_far_write, read) = loona::buffet::pipe();
+        let io = ShapedIo::new(
+            TwoHalves(write, read),
+            Shape {
+                latency: Duration::from_millis(20),
+                mtu: Some(16),
+                split_seed: Some(7),
+                ..Default::default()
+            },
+        );
+        let (_r, mut w) = io.into_halves();
+
+        let start = tokio::time::Instant::now();
+        w.write_all_owned(vec![b'a'; 100]).await.unwrap();
+
+        let read_all = async {
+            let mut segments = vec![];
+            loop {
+                let (res, _) = far_read.read_owned(vec![0u8; 1024]).await;
+                match res.unwrap() {
+                    0 => break segments,
+                    n => segments.push(n),
+                }
+            }
+        };
+        let (shutdown, segments) = tokio::join!(w.shutdown(), read_all);
+        shutdown.unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(segments.iter().sum::<usize>(), 100);
+        assert!(segments.iter().all(|&n| n <= 16), "{segments:?}");
+        assert!(segments.len() > 100 / 16 + 1, "{segments:?}");
+    });
+}
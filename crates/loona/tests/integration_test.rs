@@ -7,8 +7,11 @@ use httparse::{Status, EMPTY_HEADER};
 use loona::buffet::{IntoHalves, ReadOwned, WriteOwned};
 use loona::{
     buffet::{PieceCore, RollMut},
-    h1, h2, Body, BodyChunk, Encoder, ExpectResponseHeaders, Headers, HeadersExt, Method, Request,
-    Responder, Response, ResponseDone, ServerDriver,
+    h1, h2, metrics,
+    pressure::PressureConf,
+    Body, BodyChunk, ConnInfo, Encoder, ExpectResponseHeaders, FileBody, Headers, HeadersExt,
+    Method, Request, Responder, Response, ResponseDone, ServeOutcome, ServerDriver, Timings,
+    WireSizes,
 };
 use pretty_assertions::assert_eq;
 use pretty_hex::PrettyHex;
@@ -154,11 +157,12 @@ fn request_api() {
         let (mut server_write, client_read) = loona::buffet::pipe();
         let (client_write, mut server_read) = loona::buffet::pipe();
 
-        let req = Request {
-            method: Method::Get,
-            uri: "/".parse().unwrap(),
-            ..Default::default()
-        };
+        let req = Request::builder()
+            .method(Method::Get)
+            .uri("/")
+            .header(header::HOST, "localhost")
+            .build()
+            .unwrap();
 
         struct TestDriver;
 
@@ -552,6 +556,97 @@ fn proxy_echo_body_chunked() {
     });
 }
 
+#[test]
+fn proxy_h2_echo_body_without_content_length() {
+    use loona_h2::{HeadersFlags, StreamId};
+
+    struct TwoHalves<W, R>(W, R);
+    impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+        type Read = R;
+        type Write = W;
+
+        fn into_halves(self) -> (Self::Read, Self::Write) {
+            (self.1, self.0)
+        }
+    }
+
+    helpers::run(async move {
+        let (upstream_addr, _upstream_guard) = testbed::start().await?;
+
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+
+        let serve_fut = loona::buffet::spawn(async move {
+            let driver = proxy::ProxyDriver {
+                upstream_addr,
+                pool: Default::default(),
+            };
+            h2::serve(
+                (server_read, server_write),
+                Rc::new(h2::ServerConf::default()),
+                RollMut::alloc()?,
+                Rc::new(driver),
+            )
+            .await?;
+            Ok::<_, BX>(())
+        });
+
+        // the upstream is a real process, give it time to answer
+        let config = Rc::new(httpwg::Config {
+            timeout: Duration::from_secs(5),
+            ..Default::default()
+        });
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+
+        let mut headers = httpwg::Headers::default();
+        headers.append(":method", "POST");
+        headers.append(":scheme", "http");
+        headers.append(":path", "/echo-body");
+        headers.append(":authority", "localhost");
+
+        // one request after the other, so that the second one goes over the
+        // pooled upstream connection: it'd be thrown off by whatever the
+        // upstream didn't consume of the first one
+        for (stream_id, chunks) in [
+            (StreamId(1), ["Please return ", "to sender"]),
+            (StreamId(3), ["Return ", "again"]),
+        ] {
+            conn.encode_and_write_headers(stream_id, HeadersFlags::EndHeaders, &headers)
+                .await
+                .unwrap();
+            for (i, chunk) in chunks.iter().enumerate() {
+                conn.write_data(stream_id, i == chunks.len() - 1, chunk.as_bytes().to_vec())
+                    .await
+                    .unwrap();
+            }
+
+            let mut echoed = Vec::new();
+            loop {
+                let (frame, payload) = conn
+                    .wait_for_frame(httpwg::FrameT::Headers | httpwg::FrameT::Data)
+                    .await
+                    .unwrap();
+                if frame.stream_id != stream_id {
+                    continue;
+                }
+                if matches!(frame.frame_type, loona_h2::FrameType::Data(_)) {
+                    echoed.extend_from_slice(&payload[..]);
+                }
+                if frame.is_end_stream() {
+                    break;
+                }
+            }
+            assert_eq!(echoed, chunks.concat().as_bytes());
+        }
+
+        drop(conn);
+        serve_fut.await.bx()??;
+
+        Ok(())
+    })
+}
+
 enum BodyType {
     ContentLen,
     Chunked,
@@ -765,24 +860,39 @@ fn curl_echo_body_noproxy(typ: BodyType) {
 fn h2_basic_post() {
     #[allow(drop_bounds)]
     async fn client(ln_addr: SocketAddr, _guard: impl Drop) -> b_x::Result<()> {
-        let req_body = "Please return to sender";
-        let mut cmd = tokio::process::Command::new("curl");
-
-        cmd.arg("--silent");
-        cmd.arg("--fail-with-body");
-        cmd.arg("--http2-prior-knowledge");
-        cmd.arg(format!("http://{ln_addr}/echo-body"));
-        cmd.arg("--data").arg(req_body);
-        cmd.arg("--header")
-            .arg("content-type: application/octet-stream");
+        // the second body is larger than the initial window size, so it
+        // only goes through if we send WINDOW_UPDATE frames
+        let large_body = (0..1024 * 1024).map(|i| b'a' + (i % 26) as u8).collect();
+        let large_body_path =
+            std::env::temp_dir().join(format!("loona-h2-basic-post-{}", std::process::id()));
+        std::fs::write(&large_body_path, &large_body)?;
+
+        for req_body in [b"Please return to sender".to_vec(), large_body] {
+            let mut cmd = tokio::process::Command::new("curl");
+
+            cmd.arg("--silent");
+            cmd.arg("--fail-with-body");
+            cmd.arg("--http2-prior-knowledge");
+            cmd.arg(format!("http://{ln_addr}/echo-body"));
+            if req_body.len() > 1024 {
+                cmd.arg("--data-binary")
+                    .arg(format!("@{}", large_body_path.display()));
+            } else {
+                cmd.arg("--data-binary")
+                    .arg(std::str::from_utf8(&req_body)?);
+            }
+            cmd.arg("--header")
+                .arg("content-type: application/octet-stream");
 
-        let output = cmd.output_assert_success().await;
-        let res_body = output.stdout;
+            let output = cmd.output_assert_success().await;
+            let res_body = output.stdout;
 
-        debug!("Got body: {:?}", res_body.hex_dump());
-        assert_eq!(res_body.len(), req_body.len());
-        assert_eq!(res_body, req_body.as_bytes());
+            debug!("Got {} bytes", res_body.len());
+            assert_eq!(res_body.len(), req_body.len());
+            assert_eq!(res_body, req_body);
+        }
 
+        std::fs::remove_file(&large_body_path)?;
         Ok(())
     }
 
@@ -1071,19 +1181,4065 @@ fn h2_basic_get() {
     });
 }
 
-trait CommandExt {
-    async fn output_assert_success(&mut self) -> std::process::Output;
+#[test]
+fn serve_file_body() {
+    helpers::run(async move {
+        let contents = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let path =
+            std::env::temp_dir().join(format!("loona-test-serve-file-body-{}", std::process::id()));
+        std::fs::write(&path, &contents)?;
+
+        struct TestDriver {
+            path: std::path::PathBuf,
+        }
+
+        impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+        where
+            OurEncoder: Encoder,
+        {
+            type Error = BX;
+
+            async fn handle(
+                &self,
+                _req: loona::Request,
+                _req_body: &mut impl Body,
+                res: Responder<OurEncoder, ExpectResponseHeaders>,
+            ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+                let mut body = FileBody::open(&self.path)?.with_range(100..90_100)?;
+                let res = res
+                    .write_final_response_with_body(Response::default(), &mut body)
+                    .await
+                    .bx()?;
+                Ok(res)
+            }
+        }
+
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, mut client_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h1::serve(
+            (server_read, server_write),
+            Rc::new(h1::ServerConf::default()),
+            RollMut::alloc()?,
+            TestDriver { path: path.clone() },
+        ));
+
+        client_write
+            .write_all_owned("GET / HTTP/1.1\r\n\r\n")
+            .await?;
+
+        let mut res_buf = BytesMut::new();
+        let mut buf = vec![0u8; 1024];
+        let body = loop {
+            let res;
+            (res, buf) = client_read.read_owned(buf).await;
+            let n = res?;
+            assert_ne!(n, 0, "unexpected EOF");
+            res_buf.extend_from_slice(&buf[..n]);
+
+            let mut headers = [EMPTY_HEADER; 16];
+            let mut res = httparse::Response::new(&mut headers[..]);
+            let body_offset = match res.parse(&res_buf[..]).bx()? {
+                Status::Complete(off) => off,
+                Status::Partial => continue,
+            };
+            assert_eq!(res.code, Some(200));
+            let content_length = res
+                .headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case("content-length"))
+                .map(|h| std::str::from_utf8(h.value).unwrap().to_owned());
+            assert_eq!(content_length.as_deref(), Some("90000"));
+
+            if res_buf.len() - body_offset >= 90_000 {
+                break res_buf.split_off(body_offset);
+            }
+        };
+        assert_eq!(&body[..], &contents[100..90_100]);
+
+        drop(client_write);
+        tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    })
 }
 
-impl CommandExt for Command {
-    async fn output_assert_success(&mut self) -> std::process::Output {
-        let output = self.output().await.unwrap();
-        if !output.status.success() {
-            // print stderr
-            eprintln!("command failed with status: {:?}", output.status);
-            eprintln!("stderr: {:?}", String::from_utf8_lossy(&output.stderr));
-            panic!("command failed with status: {:?} {:?}", output.status, self);
+#[test]
+fn h1_connect_tunnel() {
+    helpers::run(async move {
+        type Upstream = (loona::buffet::PipeRead, loona::buffet::PipeWrite);
+
+        struct TestDriver {
+            upstream: std::cell::RefCell<Option<Upstream>>,
         }
-        output
-    }
+
+        impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+        where
+            OurEncoder: Encoder,
+        {
+            type Error = BX;
+
+            async fn handle(
+                &self,
+                req: loona::Request,
+                req_body: &mut impl Body,
+                res: Responder<OurEncoder, ExpectResponseHeaders>,
+            ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+                assert_eq!(req.method, Method::Connect);
+                if req.uri.authority().map(|a| a.host()) != Some("upstream.test") {
+                    let mut headers = Headers::default();
+                    headers.insert(header::CONTENT_LENGTH, "0".into());
+                    let res = res
+                        .write_final_response(Response {
+                            status: StatusCode::FORBIDDEN,
+                            headers,
+                            ..Default::default()
+                        })
+                        .await?
+                        .finish_body(None)
+                        .await?;
+                    return Ok(res);
+                }
+
+                let upstream = self.upstream.borrow_mut().take().unwrap();
+                let res = res.write_final_response(Response::default()).await?;
+                Ok(loona::proxy::tunnel(req_body, res, upstream).await?)
+            }
+        }
+
+        // upstream: shouts back whatever it receives
+        let (upstream_w, mut echo_r) = loona::buffet::pipe();
+        let (mut echo_w, upstream_r) = loona::buffet::pipe();
+        let echo_fut = loona::buffet::spawn(async move {
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let res;
+                (res, buf) = echo_r.read_owned(buf).await;
+                let n = res?;
+                if n == 0 {
+                    break;
+                }
+                echo_w
+                    .write_all_owned(buf[..n].to_ascii_uppercase())
+                    .await?;
+            }
+            echo_w.shutdown().await?;
+            Ok::<_, BX>(())
+        });
+
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, mut client_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h1::serve(
+            (server_read, server_write),
+            Rc::new(h1::ServerConf::default()),
+            RollMut::alloc()?,
+            TestDriver {
+                upstream: Some((upstream_r, upstream_w)).into(),
+            },
+        ));
+
+        let mut res_buf = BytesMut::new();
+        let mut buf = vec![0u8; 1024];
+
+        // refused tunnels leave the connection usable, then we get one
+        for (target, status) in [("elsewhere.test:443", 403), ("upstream.test:443", 200)] {
+            client_write
+                .write_all_owned(
+                    format!("CONNECT {target} HTTP/1.1\r\nhost: {target}\r\n\r\n").into_bytes(),
+                )
+                .await?;
+
+            loop {
+                let res;
+                (res, buf) = client_read.read_owned(buf).await;
+                let n = res?;
+                assert_ne!(n, 0, "unexpected EOF");
+                res_buf.extend_from_slice(&buf[..n]);
+
+                let mut headers = [EMPTY_HEADER; 16];
+                let mut res = httparse::Response::new(&mut headers[..]);
+                let body_offset = match res.parse(&res_buf[..]).bx()? {
+                    Status::Complete(off) => off,
+                    Status::Partial => continue,
+                };
+                assert_eq!(res.code, Some(status));
+                if status == 200 {
+                    assert!(!res.headers.iter().any(|h| {
+                        h.name.eq_ignore_ascii_case("content-length")
+                            || h.name.eq_ignore_ascii_case("transfer-encoding")
+                    }));
+                }
+                _ = res_buf.split_to(body_offset);
+                break;
+            }
+        }
+
+        client_write.write_all_owned("hello tunnel").await?;
+        client_write.shutdown().await?;
+
+        loop {
+            let res;
+            (res, buf) = client_read.read_owned(buf).await;
+            let n = res?;
+            if n == 0 {
+                break;
+            }
+            res_buf.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(&res_buf[..], b"HELLO TUNNEL");
+
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+        assert_eq!(outcome, ServeOutcome::TunnelClosed);
+        echo_fut.await.bx()??;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_data_is_not_copied() {
+    use loona_h2::{HeadersFlags, StreamId, WindowUpdate};
+
+    helpers::run(async move {
+        const NUM_STREAMS: u32 = 8;
+        const FRAMES_PER_STREAM: usize = 3;
+        const FRAME_SIZE: usize = 16384;
+
+        struct TestDriver;
+
+        impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+        where
+            OurEncoder: Encoder,
+        {
+            type Error = BX;
+
+            async fn handle(
+                &self,
+                _req: loona::Request,
+                req_body: &mut impl Body,
+                res: Responder<OurEncoder, ExpectResponseHeaders>,
+            ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+                let mut len = 0;
+                while let BodyChunk::Chunk(chunk) = req_body.next_chunk().await.bx()? {
+                    len += chunk.len();
+                }
+                assert_eq!(len, FRAMES_PER_STREAM * FRAME_SIZE);
+
+                let res = res
+                    .write_final_response(Response::default())
+                    .await?
+                    .finish_body(None)
+                    .await?;
+                Ok(res)
+            }
+        }
+
+        struct TwoHalves<W, R>(W, R);
+        impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+            type Read = R;
+            type Write = W;
+
+            fn into_halves(self) -> (Self::Read, Self::Write) {
+                (self.1, self.0)
+            }
+        }
+
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+
+        let serve_fut = loona::buffet::spawn(async move {
+            let conf = Rc::new(h2::ServerConf::default());
+            let client_buf = RollMut::alloc()?;
+            h2::serve(
+                (server_read, server_write),
+                conf,
+                client_buf,
+                Rc::new(TestDriver),
+            )
+            .await?;
+            Ok::<_, BX>(())
+        });
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+
+        let mut headers = httpwg::Headers::default();
+        headers.append(":method", "POST");
+        headers.append(":scheme", "http");
+        headers.append(":path", "/");
+        headers.append(":authority", "localhost");
+        let data = vec![b'a'; FRAME_SIZE];
+
+        // we're not reading from the server in the background, so keep track
+        // of the connection window ourselves. stream windows are fresh for
+        // every stream and larger than a body.
+        let mut conn_window = 65535_i64;
+        let copied_before = loona::buffet::num_bytes_copied();
+        for i in 0..NUM_STREAMS {
+            let stream_id = StreamId(i * 2 + 1);
+            conn.encode_and_write_headers(stream_id, HeadersFlags::EndHeaders, &headers)
+                .await
+                .unwrap();
+            for j in 0..FRAMES_PER_STREAM {
+                while conn_window < FRAME_SIZE as i64 {
+                    let (frame, payload) = conn
+                        .wait_for_frame(httpwg::FrameT::WindowUpdate)
+                        .await
+                        .unwrap();
+                    if frame.stream_id == StreamId::CONNECTION {
+                        conn_window += WindowUpdate::parse(payload).unwrap().1.increment as i64;
+                    }
+                }
+                conn.write_data(stream_id, j == FRAMES_PER_STREAM - 1, data.clone())
+                    .await
+                    .unwrap();
+                conn_window -= FRAME_SIZE as i64;
+            }
+
+            loop {
+                let (frame, payload) = conn
+                    .wait_for_frame(
+                        httpwg::FrameT::Headers
+                            | httpwg::FrameT::Data
+                            | httpwg::FrameT::WindowUpdate,
+                    )
+                    .await
+                    .unwrap();
+                if frame.stream_id == StreamId::CONNECTION {
+                    conn_window += WindowUpdate::parse(payload).unwrap().1.increment as i64;
+                } else if frame.stream_id == stream_id && frame.is_end_stream() {
+                    break;
+                }
+            }
+        }
+        let copied = loona::buffet::num_bytes_copied() - copied_before;
+
+        // the server only copies what it read past a frame header before
+        // knowing it needed more room, a few dozen bytes per frame.
+        let sent = NUM_STREAMS as usize * FRAMES_PER_STREAM * FRAME_SIZE;
+        let copies_per_byte = copied as f64 / sent as f64;
+        debug!(%copied, %sent, %copies_per_byte, "h2 DATA copies");
+        assert!(
+            copies_per_byte < 0.05,
+            "copied {copied} bytes to receive {sent} bytes of DATA"
+        );
+
+        drop(conn);
+        serve_fut.await.bx()??;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_dropped_bodies_give_back_window() {
+    use loona_h2::{HeadersFlags, StreamId, WindowUpdate};
+    use std::cell::Cell;
+    use tokio::sync::Notify;
+
+    helpers::run(async move {
+        const NUM_ROUNDS: u32 = 64;
+        const NUM_STREAMS: u32 = 32;
+        const CHUNK_SIZE: usize = 800;
+
+        /// Handlers read one chunk, then wait for each other, so that all
+        /// the bodies of a round get dropped at once, with the event channel
+        /// full of responses.
+        #[derive(Default)]
+        struct TestDriver {
+            waiting: Cell<u32>,
+            all_read: Notify,
+        }
+
+        impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+        where
+            OurEncoder: Encoder,
+        {
+            type Error = BX;
+
+            async fn handle(
+                &self,
+                _req: loona::Request,
+                req_body: &mut impl Body,
+                res: Responder<OurEncoder, ExpectResponseHeaders>,
+            ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+                let chunk = req_body.next_chunk().await.bx()?;
+                assert!(matches!(chunk, BodyChunk::Chunk(_)));
+
+                let all_read = self.all_read.notified();
+                self.waiting.set(self.waiting.get() + 1);
+                if self.waiting.get() == NUM_STREAMS {
+                    self.waiting.set(0);
+                    self.all_read.notify_waiters();
+                } else {
+                    all_read.await;
+                }
+
+                let res = res
+                    .write_final_response(Response::default())
+                    .await?
+                    .finish_body(None)
+                    .await?;
+                Ok(res)
+            }
+        }
+
+        struct TwoHalves<W, R>(W, R);
+        impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+            type Read = R;
+            type Write = W;
+
+            fn into_halves(self) -> (Self::Read, Self::Write) {
+                (self.1, self.0)
+            }
+        }
+
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+
+        let serve_fut = loona::buffet::spawn(async move {
+            let conf = Rc::new(h2::ServerConf::default());
+            let client_buf = RollMut::alloc()?;
+            h2::serve(
+                (server_read, server_write),
+                conf,
+                client_buf,
+                Rc::new(TestDriver::default()),
+            )
+            .await?;
+            Ok::<_, BX>(())
+        });
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+
+        let mut headers = httpwg::Headers::default();
+        headers.append(":method", "POST");
+        headers.append(":scheme", "http");
+        headers.append(":path", "/");
+        headers.append(":authority", "localhost");
+        let data = vec![b'a'; CHUNK_SIZE];
+
+        // every round sends most of a connection window, half of which is
+        // never read: if dropping a body lost its credit, we'd run out of
+        // window after a few rounds and time out waiting for it.
+        let mut conn_window = 65535_i64;
+        for round in 0..NUM_ROUNDS {
+            let stream_ids: Vec<_> = (0..NUM_STREAMS)
+                .map(|i| StreamId((round * NUM_STREAMS + i) * 2 + 1))
+                .collect();
+            for &stream_id in &stream_ids {
+                conn.encode_and_write_headers(stream_id, HeadersFlags::EndHeaders, &headers)
+                    .await
+                    .unwrap();
+                for end_stream in [false, true] {
+                    while conn_window < CHUNK_SIZE as i64 {
+                        let (frame, payload) = conn
+                            .wait_for_frame(httpwg::FrameT::WindowUpdate)
+                            .await
+                            .unwrap();
+                        if frame.stream_id == StreamId::CONNECTION {
+                            conn_window += WindowUpdate::parse(payload).unwrap().1.increment as i64;
+                        }
+                    }
+                    conn.write_data(stream_id, end_stream, data.clone())
+                        .await
+                        .unwrap();
+                    conn_window -= CHUNK_SIZE as i64;
+                }
+            }
+
+            let mut num_done = 0;
+            while num_done < NUM_STREAMS {
+                let (frame, payload) = conn
+                    .wait_for_frame(
+                        httpwg::FrameT::Headers
+                            | httpwg::FrameT::Data
+                            | httpwg::FrameT::WindowUpdate,
+                    )
+                    .await
+                    .unwrap();
+                if frame.stream_id == StreamId::CONNECTION {
+                    conn_window += WindowUpdate::parse(payload).unwrap().1.increment as i64;
+                } else if stream_ids.contains(&frame.stream_id) && frame.is_end_stream() {
+                    num_done += 1;
+                }
+            }
+        }
+
+        drop(conn);
+        serve_fut.await.bx()??;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_header_limits() {
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+        where
+            OurEncoder: Encoder,
+        {
+            type Error = BX;
+
+            async fn handle(
+                &self,
+                _req: loona::Request,
+                _req_body: &mut impl Body,
+                _res: Responder<OurEncoder, ExpectResponseHeaders>,
+            ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+                panic!("requests over the limits should never make it to the driver")
+            }
+        }
+
+        let too_many = format!("GET / HTTP/1.1\r\n{}\r\n", "x-header: a\r\n".repeat(5));
+        let too_large = format!("GET / HTTP/1.1\r\nx-header: {}\r\n\r\n", "a".repeat(512));
+
+        for req in [too_many, too_large] {
+            let (mut client_write, server_read) = loona::buffet::pipe();
+            let (server_write, mut client_read) = loona::buffet::pipe();
+            let serve_fut = loona::buffet::spawn(h1::serve(
+                (server_read, server_write),
+                Rc::new(h1::ServerConf {
+                    max_header_section_size: 256,
+                    max_header_count: 4,
+                    ..Default::default()
+                }),
+                RollMut::alloc()?,
+                TestDriver,
+            ));
+
+            client_write.write_all_owned(req.into_bytes()).await?;
+
+            let mut res_buf = BytesMut::new();
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let res;
+                (res, buf) = client_read.read_owned(buf).await;
+                let n = res?;
+                if n == 0 {
+                    break;
+                }
+                res_buf.extend_from_slice(&buf[..n]);
+            }
+            assert!(
+                res_buf.starts_with(b"HTTP/1.1 431 "),
+                "unexpected response: {:?}",
+                res_buf.escape_ascii().to_string()
+            );
+
+            let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut)
+                .await
+                .bx()?
+                .bx()??;
+            assert_eq!(outcome, ServeOutcome::RequestHeadersTooLargeOnHttp1Conn);
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_header_limits() {
+    use loona_h2::{ContinuationFlags, HeadersFlags, StreamId};
+
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+        where
+            OurEncoder: Encoder,
+        {
+            type Error = BX;
+
+            async fn handle(
+                &self,
+                _req: loona::Request,
+                _req_body: &mut impl Body,
+                res: Responder<OurEncoder, ExpectResponseHeaders>,
+            ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+                let res = res
+                    .write_final_response(Response::default())
+                    .await?
+                    .finish_body(None)
+                    .await?;
+                Ok(res)
+            }
+        }
+
+        struct TwoHalves<W, R>(W, R);
+        impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+            type Read = R;
+            type Write = W;
+
+            fn into_halves(self) -> (Self::Read, Self::Write) {
+                (self.1, self.0)
+            }
+        }
+
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+
+        let serve_fut = loona::buffet::spawn(async move {
+            let conf = Rc::new(h2::ServerConf {
+                max_header_section_size: 1024,
+                max_header_count: 4,
+                ..Default::default()
+            });
+            let client_buf = RollMut::alloc()?;
+            _ = h2::serve(
+                (server_read, server_write),
+                conf,
+                client_buf,
+                Rc::new(TestDriver),
+            )
+            .await;
+            Ok::<_, BX>(())
+        });
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+        assert_eq!(conn.settings.max_header_list_size, 1024);
+
+        let mut ok = httpwg::Headers::default();
+        ok.append(":method", "GET");
+        ok.append(":scheme", "http");
+        ok.append(":path", "/");
+        ok.append(":authority", "localhost");
+
+        let mut too_many = ok.clone();
+        for i in 0..5 {
+            too_many.append(format!("x-header-{i}").into_bytes(), "a");
+        }
+        let mut too_large = ok.clone();
+        too_large.append("x-header", httpwg::dummy_bytes(2048));
+
+        // the server decodes the whole block even when it refuses it, so
+        // our HPACK tables stay in sync and the connection stays usable
+        for (stream_id, headers, status) in [
+            (1, &too_many, "431"),
+            (3, &too_large, "431"),
+            (5, &ok, "200"),
+        ] {
+            conn.encode_and_write_headers(
+                StreamId(stream_id),
+                HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+                headers,
+            )
+            .await
+            .unwrap();
+
+            let (frame, payload) = conn.wait_for_frame(httpwg::FrameT::Headers).await.unwrap();
+            assert_eq!(frame.stream_id, StreamId(stream_id));
+            let res = conn.decode_headers(payload.into()).unwrap();
+            assert_eq!(
+                &res.get_first(&":status".into()).unwrap()[..],
+                status.as_bytes()
+            );
+            conn.verify_stream_close(StreamId(stream_id)).await.unwrap();
+        }
+
+        // a header block that's too large before it's even decoded costs us
+        // the connection
+        conn.write_headers(
+            StreamId(7),
+            HeadersFlags::EndStream,
+            httpwg::dummy_bytes(800).into(),
+        )
+        .await
+        .unwrap();
+        conn.write_continuation(
+            StreamId(7),
+            ContinuationFlags::EndHeaders,
+            httpwg::dummy_bytes(800).into(),
+        )
+        .await
+        .unwrap();
+        conn.verify_stream_error(httpwg::ErrorC::CompressionError)
+            .await
+            .unwrap();
+
+        drop(conn);
+        serve_fut.await.bx()??;
+
+        Ok(())
+    })
+}
+
+/// Sums up what servers report, and remembers the highest each gauge went
+#[derive(Default)]
+struct RecordingSink {
+    counters: std::cell::RefCell<std::collections::HashMap<metrics::Counter, u64>>,
+    gauges: std::cell::RefCell<std::collections::HashMap<metrics::Gauge, (i64, i64)>>,
+    observed: std::cell::RefCell<Vec<(metrics::Histogram, f64)>>,
+}
+
+impl RecordingSink {
+    fn counter(&self, counter: metrics::Counter) -> u64 {
+        self.counters
+            .borrow()
+            .get(&counter)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the current value and the highest value seen
+    fn gauge(&self, gauge: metrics::Gauge) -> (i64, i64) {
+        self.gauges
+            .borrow()
+            .get(&gauge)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl metrics::MetricsSink for RecordingSink {
+    fn counter(&self, counter: metrics::Counter, n: u64) {
+        *self.counters.borrow_mut().entry(counter).or_default() += n;
+    }
+
+    fn gauge(&self, gauge: metrics::Gauge, delta: i64) {
+        let mut gauges = self.gauges.borrow_mut();
+        let (value, max) = gauges.entry(gauge).or_default();
+        *value += delta;
+        *max = (*max).max(*value);
+    }
+
+    fn observe(&self, histogram: metrics::Histogram, value: f64) {
+        self.observed.borrow_mut().push((histogram, value));
+    }
+}
+
+struct HelloDriver;
+
+impl<OurEncoder> ServerDriver<OurEncoder> for HelloDriver
+where
+    OurEncoder: Encoder,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        _req: loona::Request,
+        _req_body: &mut impl Body,
+        res: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+        let mut body = loona::SinglePieceBody::from("hello");
+        let res = res
+            .write_final_response_with_body(Response::default(), &mut body)
+            .await
+            .map_err(BX::from_err)?;
+        Ok(res)
+    }
+}
+
+#[test]
+fn h1_metrics() {
+    use metrics::{Counter, Gauge, Histogram};
+
+    helpers::run(async move {
+        let sink = Rc::new(RecordingSink::default());
+
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, mut client_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h1::serve(
+            (server_read, server_write),
+            Rc::new(h1::ServerConf {
+                metrics: Some(sink.clone()),
+                ..Default::default()
+            }),
+            RollMut::alloc()?,
+            HelloDriver,
+        ));
+
+        let req = b"GET / HTTP/1.1\r\nconnection: close\r\n\r\n";
+        client_write.write_all_owned(&req[..]).await?;
+
+        let mut res_buf = BytesMut::new();
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let res;
+            (res, buf) = client_read.read_owned(buf).await;
+            let n = res?;
+            if n == 0 {
+                break;
+            }
+            res_buf.extend_from_slice(&buf[..n]);
+        }
+        assert!(res_buf.ends_with(b"\r\n\r\nhello"));
+
+        tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+
+        assert_eq!(sink.counter(Counter::BytesIn), req.len() as u64);
+        assert_eq!(sink.counter(Counter::BytesOut), res_buf.len() as u64);
+        assert_eq!(sink.gauge(Gauge::ActiveConnections), (0, 1));
+        let observed = sink.observed.borrow();
+        assert_eq!(observed.len(), 1);
+        assert_eq!(observed[0].0, Histogram::RequestDuration);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_metrics() {
+    use loona_h2::{HeadersFlags, StreamId};
+    use metrics::{Counter, Gauge, Histogram};
+
+    helpers::run(async move {
+        struct TwoHalves<W, R>(W, R);
+        impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+            type Read = R;
+            type Write = W;
+
+            fn into_halves(self) -> (Self::Read, Self::Write) {
+                (self.1, self.0)
+            }
+        }
+
+        let sink = Rc::new(RecordingSink::default());
+
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+
+        let serve_fut = loona::buffet::spawn({
+            let sink = sink.clone();
+            async move {
+                let conf = Rc::new(h2::ServerConf {
+                    metrics: Some(sink),
+                    ..Default::default()
+                });
+                h2::serve(
+                    (server_read, server_write),
+                    conf,
+                    RollMut::alloc()?,
+                    Rc::new(HelloDriver),
+                )
+                .await?;
+                Ok::<_, BX>(())
+            }
+        });
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+
+        let mut headers = httpwg::Headers::default();
+        headers.append(":method", "GET");
+        headers.append(":scheme", "http");
+        headers.append(":path", "/");
+        headers.append(":authority", "localhost");
+        headers.append("x-custom", "indexed by the server's decoder");
+        conn.encode_and_write_headers(
+            StreamId(1),
+            HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+            &headers,
+        )
+        .await
+        .unwrap();
+        conn.verify_stream_close(StreamId(1)).await.unwrap();
+
+        drop(conn);
+        tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+
+        assert!(sink.counter(Counter::BytesIn) > 0);
+        assert!(sink.counter(Counter::BytesOut) > 0);
+        assert_eq!(sink.gauge(Gauge::ActiveConnections), (0, 1));
+        assert_eq!(sink.gauge(Gauge::OpenH2Streams), (0, 1));
+        let (table_size, max_table_size) = sink.gauge(Gauge::HpackDecoderTableSize);
+        assert_eq!(table_size, 0);
+        assert!(max_table_size > 0);
+        let observed = sink.observed.borrow();
+        assert_eq!(observed.len(), 1);
+        assert_eq!(observed[0].0, Histogram::RequestDuration);
+
+        Ok(())
+    })
+}
+
+/// Reads the request body, answers "hello", and records what each exchange
+/// took on the wire, and when its phases happened
+struct WireSizesDriver {
+    sizes: Rc<std::cell::RefCell<Vec<WireSizes>>>,
+    timings: Rc<std::cell::RefCell<Vec<Timings>>>,
+}
+
+/// Phases of an exchange happen in order
+fn assert_timings_in_order(t: &Timings) {
+    assert!(t.headers_parsed_at <= t.driver_started_at);
+    let first_byte_at = t.first_byte_at.expect("response was written");
+    assert!(t.driver_started_at <= first_byte_at);
+    assert!(first_byte_at <= t.last_byte_at);
+    assert_eq!(t.total(), t.last_byte_at - t.headers_parsed_at);
+}
+
+impl<OurEncoder> ServerDriver<OurEncoder> for WireSizesDriver
+where
+    OurEncoder: Encoder,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        _req: loona::Request,
+        req_body: &mut impl Body,
+        mut res: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+        let sizes = self.sizes.clone();
+        let timings = self.timings.clone();
+        res.on_complete(move |s, t| {
+            sizes.borrow_mut().push(s);
+            timings.borrow_mut().push(t);
+        });
+
+        while let BodyChunk::Chunk(_) = req_body.next_chunk().await.bx()? {}
+
+        let mut body = loona::SinglePieceBody::from("hello");
+        let res = res
+            .write_final_response_with_body(Response::default(), &mut body)
+            .await
+            .map_err(BX::from_err)?;
+        Ok(res)
+    }
+}
+
+#[test]
+fn h1_wire_sizes() {
+    helpers::run(async move {
+        let sizes: Rc<std::cell::RefCell<Vec<WireSizes>>> = Default::default();
+        let timings: Rc<std::cell::RefCell<Vec<Timings>>> = Default::default();
+
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, mut client_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h1::serve(
+            (server_read, server_write),
+            Default::default(),
+            RollMut::alloc()?,
+            WireSizesDriver {
+                sizes: sizes.clone(),
+                timings: timings.clone(),
+            },
+        ));
+
+        // two pipelined requests, the first one with a chunked body
+        let req1_headers = "POST / HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n";
+        let req1_body = "3;ext=1\r\nabc\r\n2\r\nde\r\n0\r\nx-trailer: 1\r\n\r\n";
+        let req2_headers = "GET / HTTP/1.1\r\nconnection: close\r\n\r\n";
+        client_write
+            .write_all_owned(format!("{req1_headers}{req1_body}{req2_headers}").into_bytes())
+            .await?;
+
+        let mut res_buf = BytesMut::new();
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let res;
+            (res, buf) = client_read.read_owned(buf).await;
+            let n = res?;
+            if n == 0 {
+                break;
+            }
+            res_buf.extend_from_slice(&buf[..n]);
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+
+        let sizes = sizes.borrow();
+        assert_eq!(sizes.len(), 2);
+        assert_eq!(sizes[0].request_headers, req1_headers.len() as u64);
+        assert_eq!(sizes[0].request_body, req1_body.len() as u64);
+        assert_eq!(sizes[1].request_headers, req2_headers.len() as u64);
+        assert_eq!(sizes[1].request_body, 0);
+        for s in sizes.iter() {
+            assert_eq!(s.response_body, "hello".len() as u64);
+        }
+        assert_eq!(
+            sizes[0].response_total() + sizes[1].response_total(),
+            res_buf.len() as u64
+        );
+
+        let timings = timings.borrow();
+        assert_eq!(timings.len(), 2);
+        timings.iter().for_each(assert_timings_in_order);
+        // pipelined: the second request is parsed after the first is done
+        assert!(timings[0].last_byte_at <= timings[1].headers_parsed_at);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_wire_sizes() {
+    use loona_h2::{FrameType, HeadersFlags, StreamId};
+
+    helpers::run(async move {
+        struct TwoHalves<W, R>(W, R);
+        impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+            type Read = R;
+            type Write = W;
+
+            fn into_halves(self) -> (Self::Read, Self::Write) {
+                (self.1, self.0)
+            }
+        }
+
+        let sizes: Rc<std::cell::RefCell<Vec<WireSizes>>> = Default::default();
+        let timings: Rc<std::cell::RefCell<Vec<Timings>>> = Default::default();
+
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+
+        let serve_fut = loona::buffet::spawn({
+            let driver = Rc::new(WireSizesDriver {
+                sizes: sizes.clone(),
+                timings: timings.clone(),
+            });
+            async move {
+                h2::serve(
+                    (server_read, server_write),
+                    Default::default(),
+                    RollMut::alloc()?,
+                    driver,
+                )
+                .await?;
+                Ok::<_, BX>(())
+            }
+        });
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+
+        let mut headers = httpwg::Headers::default();
+        headers.append(":method", "POST");
+        headers.append(":scheme", "http");
+        headers.append(":path", "/");
+        headers.append(":authority", "localhost");
+        let block = conn.encode_headers(&headers).unwrap();
+        let block_len = block.len() as u64;
+        conn.write_headers(StreamId(1), HeadersFlags::EndHeaders, block)
+            .await
+            .unwrap();
+        conn.write_data(StreamId(1), true, "hello").await.unwrap();
+
+        let mut response_headers = 0;
+        let mut response_body = 0;
+        loop {
+            let Some(httpwg::Ev::Frame { frame, .. }) = conn.ev_rx.recv().await else {
+                panic!("connection closed before the response was done");
+            };
+            if frame.stream_id != StreamId(1) {
+                continue;
+            }
+            match frame.frame_type {
+                FrameType::Headers(_) | FrameType::Continuation(_) => {
+                    response_headers += 9 + frame.len as u64
+                }
+                FrameType::Data(_) => response_body += 9 + frame.len as u64,
+                _ => {}
+            }
+            if frame.is_end_stream() {
+                break;
+            }
+        }
+
+        drop(conn);
+        tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+
+        assert_eq!(
+            &sizes.borrow()[..],
+            &[WireSizes {
+                request_headers: 9 + block_len,
+                request_body: 9 + 5,
+                response_headers,
+                response_body,
+            }]
+        );
+
+        let timings = timings.borrow();
+        assert_eq!(timings.len(), 1);
+        assert_timings_in_order(&timings[0]);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_memory_pressure() {
+    helpers::run(async move {
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, mut client_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h1::serve(
+            (server_read, server_write),
+            Rc::new(h1::ServerConf {
+                // under pressure as soon as a single buffer is in use
+                pressure: PressureConf {
+                    min_free_ratio: 1.0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            RollMut::alloc()?,
+            HelloDriver,
+        ));
+
+        client_write
+            .write_all_owned("GET / HTTP/1.1\r\n\r\n")
+            .await?;
+
+        let mut res_buf = BytesMut::new();
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let res;
+            (res, buf) = client_read.read_owned(buf).await;
+            let n = res?;
+            if n == 0 {
+                break;
+            }
+            res_buf.extend_from_slice(&buf[..n]);
+        }
+        assert!(res_buf.starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"));
+
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+        assert_eq!(outcome, ServeOutcome::RefusedUnderMemoryPressure);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_memory_pressure() {
+    use loona::buffet::bufpool::{self, BufMut};
+    use loona_h2::{GoAway, HeadersFlags, KnownErrorCode, StreamId};
+    use nom::Finish;
+
+    helpers::run(async move {
+        struct TwoHalves<W, R>(W, R);
+        impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+            type Read = R;
+            type Write = W;
+
+            fn into_halves(self) -> (Self::Read, Self::Write) {
+                (self.1, self.0)
+            }
+        }
+
+        let connect = |pressure: PressureConf| {
+            let (server_write, client_read) = loona::buffet::pipe();
+            let (client_write, server_read) = loona::buffet::pipe();
+
+            let serve_fut = loona::buffet::spawn(async move {
+                let conf = Rc::new(h2::ServerConf {
+                    pressure,
+                    ..Default::default()
+                });
+                h2::serve(
+                    (server_read, server_write),
+                    conf,
+                    RollMut::alloc()?,
+                    Rc::new(HelloDriver),
+                )
+                .await?;
+                Ok::<_, BX>(())
+            });
+
+            let config = Rc::new(httpwg::Config::default());
+            let conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+            (conn, serve_fut)
+        };
+
+        let mut headers = httpwg::Headers::default();
+        headers.append(":method", "GET");
+        headers.append(":scheme", "http");
+        headers.append(":path", "/");
+        headers.append(":authority", "localhost");
+
+        // new streams get a 503 while the pool is running low, and are
+        // served again once it isn't
+        let (mut conn, serve_fut) = connect(Default::default());
+        conn.handshake().await.unwrap();
+
+        let mut hog = Vec::new();
+        while bufpool::num_free() > bufpool::num_bufs() / 100 {
+            hog.push(BufMut::alloc()?);
+        }
+
+        for (stream_id, status) in [(1, "503"), (3, "200")] {
+            conn.encode_and_write_headers(
+                StreamId(stream_id),
+                HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+                &headers,
+            )
+            .await
+            .unwrap();
+
+            let (frame, payload) = conn.wait_for_frame(httpwg::FrameT::Headers).await.unwrap();
+            assert_eq!(frame.stream_id, StreamId(stream_id));
+            let res = conn.decode_headers(payload.into()).unwrap();
+            assert_eq!(
+                &res.get_first(&":status".into()).unwrap()[..],
+                status.as_bytes()
+            );
+            conn.verify_stream_close(StreamId(stream_id)).await.unwrap();
+
+            hog.clear();
+        }
+
+        drop(conn);
+        tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+
+        // new connections get a GOAWAY right after the server's SETTINGS
+        let (mut conn, serve_fut) = connect(PressureConf {
+            min_free_ratio: 1.0,
+            ..Default::default()
+        });
+        conn.send(loona_h2::PREFACE).await.unwrap();
+
+        let (_frame, payload) = conn.wait_for_frame(httpwg::FrameT::GoAway).await.unwrap();
+        let (_, goaway) = GoAway::parse(payload).finish().unwrap();
+        assert_eq!(goaway.last_stream_id, StreamId(0));
+        assert_eq!(
+            KnownErrorCode::try_from(goaway.error_code).unwrap(),
+            KnownErrorCode::NoError
+        );
+
+        drop(conn);
+        tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+
+        Ok(())
+    })
+}
+
+/// Records every span as it's created, as `parent > child field=value`
+#[derive(Default, Clone)]
+struct SpanRecorder {
+    spans: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl<S> tracing_subscriber::Layer<S> for SpanRecorder
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        struct Fields<'a>(&'a mut String);
+        impl tracing::field::Visit for Fields<'_> {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                self.0.push_str(&format!(" {}={:?}", field.name(), value));
+            }
+        }
+
+        let span = ctx.span(id).unwrap();
+        let mut line = span
+            .scope()
+            .from_root()
+            .map(|s| s.name())
+            .collect::<Vec<_>>()
+            .join(" > ");
+        attrs.record(&mut Fields(&mut line));
+        self.spans.lock().unwrap().push(line);
+    }
+}
+
+#[test]
+fn tracing_spans() {
+    use loona_h2::{HeadersFlags, StreamId};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    struct TwoHalves<W, R>(W, R);
+    impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+        type Read = R;
+        type Write = W;
+
+        fn into_halves(self) -> (Self::Read, Self::Write) {
+            (self.1, self.0)
+        }
+    }
+
+    // not `helpers::run`: we want our own subscriber, not the global one
+    let recorder = SpanRecorder::default();
+    let subscriber = tracing_subscriber::registry().with(recorder.clone());
+    tracing::subscriber::with_default(subscriber, || {
+        loona::buffet::start(async move {
+            let (mut client_write, server_read) = loona::buffet::pipe();
+            let (server_write, mut client_read) = loona::buffet::pipe();
+            let serve_fut = loona::buffet::spawn(h1::serve(
+                (server_read, server_write),
+                Default::default(),
+                RollMut::alloc().unwrap(),
+                HelloDriver,
+            ));
+            client_write
+                .write_all_owned("GET /h1 HTTP/1.1\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let res;
+                (res, buf) = client_read.read_owned(buf).await;
+                if res.unwrap() == 0 {
+                    break;
+                }
+            }
+            serve_fut.await.unwrap().unwrap();
+
+            let (server_write, client_read) = loona::buffet::pipe();
+            let (client_write, server_read) = loona::buffet::pipe();
+            let serve_fut = loona::buffet::spawn(h2::serve(
+                (server_read, server_write),
+                Default::default(),
+                RollMut::alloc().unwrap(),
+                Rc::new(HelloDriver),
+            ));
+
+            let config = Rc::new(httpwg::Config::default());
+            let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+            conn.handshake().await.unwrap();
+            let mut headers = httpwg::Headers::default();
+            headers.append(":method", "GET");
+            headers.append(":scheme", "http");
+            headers.append(":path", "/h2");
+            headers.append(":authority", "localhost");
+            conn.encode_and_write_headers(
+                StreamId(1),
+                HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+                &headers,
+            )
+            .await
+            .unwrap();
+            conn.verify_stream_close(StreamId(1)).await.unwrap();
+
+            drop(conn);
+            serve_fut.await.unwrap().unwrap();
+        });
+    });
+
+    let spans = recorder.spans.lock().unwrap();
+    for expected in [
+        r#"conn proto="h1" id="#,
+        "conn > request method=GET path=/h1",
+        r#"conn proto="h2" id="#,
+        "conn > stream id=1",
+        "conn > stream > request method=GET path=/h2",
+    ] {
+        // connection ids depend on what else ran in this process
+        let matches = |s: &String| match s.strip_prefix(expected) {
+            Some(id) if expected.ends_with("id=") => id.parse::<u64>().is_ok(),
+            _ => s == expected,
+        };
+        assert!(
+            spans.iter().any(matches),
+            "no {expected:?} span in {spans:#?}"
+        );
+    }
+}
+
+/// Answers with what it knows about the connection
+struct ConnInfoDriver;
+
+impl<OurEncoder> ServerDriver<OurEncoder> for ConnInfoDriver
+where
+    OurEncoder: Encoder,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        req: loona::Request,
+        _req_body: &mut impl Body,
+        res: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+        let conn = req.conn.unwrap();
+        let mut body = loona::SinglePieceBody::from(
+            format!(
+                "{} {:?} {:?} {:?} {:?}",
+                conn.id, conn.peer_addr, conn.alpn_protocol, conn.version, req.stream_id
+            )
+            .into_bytes(),
+        );
+        let res = res
+            .write_final_response_with_body(Response::default(), &mut body)
+            .await
+            .map_err(BX::from_err)?;
+        Ok(res)
+    }
+}
+
+#[test]
+fn conn_info() {
+    use loona_h2::{HeadersFlags, StreamId};
+
+    helpers::run(async move {
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, mut client_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h1::serve_with_conn_info(
+            (server_read, server_write),
+            Default::default(),
+            RollMut::alloc()?,
+            ConnInfoDriver,
+            ConnInfo {
+                peer_addr: Some("10.0.0.1:4567".parse().unwrap()),
+                alpn_protocol: Some(b"http/1.1".to_vec()),
+                ..Default::default()
+            },
+        ));
+
+        client_write
+            .write_all_owned("GET / HTTP/1.1\r\nconnection: close\r\n\r\n")
+            .await?;
+        let mut res_buf = BytesMut::new();
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let res;
+            (res, buf) = client_read.read_owned(buf).await;
+            let n = res?;
+            if n == 0 {
+                break;
+            }
+            res_buf.extend_from_slice(&buf[..n]);
+        }
+        let res = std::str::from_utf8(&res_buf[..]).unwrap();
+        let (_, body) = res.split_once("\r\n\r\n").unwrap();
+        let (conn_id, rest) = body.split_once(' ').unwrap();
+        assert_ne!(conn_id, "0");
+        assert_eq!(
+            rest,
+            "Some(10.0.0.1:4567) Some([104, 116, 116, 112, 47, 49, 46, 49]) HTTP/1.1 None"
+        );
+        serve_fut.await.bx()??;
+
+        struct TwoHalves<W, R>(W, R);
+        impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+            type Read = R;
+            type Write = W;
+
+            fn into_halves(self) -> (Self::Read, Self::Write) {
+                (self.1, self.0)
+            }
+        }
+
+        // no info from the caller, but the server still says which version
+        // the connection speaks
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h2::serve(
+            (server_read, server_write),
+            Default::default(),
+            RollMut::alloc()?,
+            Rc::new(ConnInfoDriver),
+        ));
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+        let mut headers = httpwg::Headers::default();
+        headers.append(":method", "GET");
+        headers.append(":scheme", "http");
+        headers.append(":path", "/");
+        headers.append(":authority", "localhost");
+        // every stream reports its own id, and the connection's
+        let mut conn_ids = Vec::new();
+        for stream_id in [1, 3] {
+            conn.encode_and_write_headers(
+                StreamId(stream_id),
+                HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+                &headers,
+            )
+            .await
+            .unwrap();
+
+            let (_, payload) = conn.wait_for_frame(httpwg::FrameT::Data).await.unwrap();
+            let payload = std::str::from_utf8(&payload[..]).unwrap();
+            let (conn_id, rest) = payload.split_once(' ').unwrap();
+            assert_eq!(rest, format!("None None HTTP/2.0 Some({stream_id})"));
+            conn_ids.push(conn_id.parse::<u64>().unwrap());
+        }
+        assert_ne!(conn_ids[0], 0);
+        assert_eq!(conn_ids[0], conn_ids[1]);
+
+        drop(conn);
+        serve_fut.await.bx()??;
+
+        Ok(())
+    })
+}
+
+/// Counts the requests it handled: one per connection, thanks to the factory
+struct CountingDriver {
+    handled: std::cell::Cell<u32>,
+}
+
+impl<OurEncoder> ServerDriver<OurEncoder> for CountingDriver
+where
+    OurEncoder: Encoder,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        _req: loona::Request,
+        _req_body: &mut impl Body,
+        res: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+        self.handled.set(self.handled.get() + 1);
+        let mut body =
+            loona::SinglePieceBody::from(format!("#{}", self.handled.get()).into_bytes());
+        let res = res
+            .write_final_response_with_body(Response::default(), &mut body)
+            .await
+            .map_err(BX::from_err)?;
+        Ok(res)
+    }
+}
+
+#[test]
+fn driver_factory() {
+    helpers::run(async move {
+        let created = Rc::new(std::cell::Cell::new(0));
+        let factory = {
+            let created = created.clone();
+            move |conn: &ConnInfo| {
+                assert_eq!(conn.version, loona::http::Version::HTTP_11);
+                created.set(created.get() + 1);
+                CountingDriver {
+                    handled: std::cell::Cell::new(0),
+                }
+            }
+        };
+
+        for _ in 0..2 {
+            let (mut client_write, server_read) = loona::buffet::pipe();
+            let (server_write, mut client_read) = loona::buffet::pipe();
+            let serve_fut = h1::serve_with_factory(
+                (server_read, server_write),
+                Default::default(),
+                RollMut::alloc()?,
+                &factory,
+                Default::default(),
+            );
+            let client_fut = async move {
+                client_write
+                    .write_all_owned(
+                        "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nconnection: close\r\n\r\n",
+                    )
+                    .await?;
+                let mut res_buf = BytesMut::new();
+                let mut buf = vec![0u8; 1024];
+                loop {
+                    let res;
+                    (res, buf) = client_read.read_owned(buf).await;
+                    let n = res?;
+                    if n == 0 {
+                        break;
+                    }
+                    res_buf.extend_from_slice(&buf[..n]);
+                }
+                Ok::<_, BX>(res_buf)
+            };
+            let (serve_res, res_buf) = tokio::join!(serve_fut, client_fut);
+            serve_res.bx()?;
+
+            // every connection starts counting from scratch
+            let res_buf = std::str::from_utf8(&res_buf?).unwrap().to_string();
+            assert!(res_buf.contains("\r\n\r\n#1HTTP/1.1"), "{res_buf:?}");
+            assert!(res_buf.ends_with("\r\n\r\n#2"), "{res_buf:?}");
+        }
+        assert_eq!(created.get(), 2);
+
+        Ok(())
+    })
+}
+
+/// Streams "hello world" in two chunks, without announcing its length
+struct UnknownLengthDriver;
+
+impl<OurEncoder> ServerDriver<OurEncoder> for UnknownLengthDriver
+where
+    OurEncoder: Encoder,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        _req: loona::Request,
+        _req_body: &mut impl Body,
+        res: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+        let mut res = res
+            .write_final_response(Response::default())
+            .await
+            .map_err(BX::from_err)?;
+        res.write_chunk("hello ".into())
+            .await
+            .map_err(BX::from_err)?;
+        res.write_chunk("world".into())
+            .await
+            .map_err(BX::from_err)?;
+        res.finish_body(None).await.map_err(BX::from_err)
+    }
+}
+
+#[test]
+fn h1_close_delimited_response_for_http10() {
+    helpers::run(async move {
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, mut client_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h1::serve(
+            (server_read, server_write),
+            Default::default(),
+            RollMut::alloc()?,
+            UnknownLengthDriver,
+        ));
+
+        // the second request never gets an answer: the first response ends
+        // when the connection does
+        client_write
+            .write_all_owned("GET / HTTP/1.0\r\n\r\nGET / HTTP/1.1\r\n\r\n")
+            .await?;
+        let mut res_buf = BytesMut::new();
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let res;
+            (res, buf) = client_read.read_owned(buf).await;
+            let n = res?;
+            if n == 0 {
+                break;
+            }
+            res_buf.extend_from_slice(&buf[..n]);
+        }
+
+        let mut headers = [EMPTY_HEADER; 16];
+        let mut res = httparse::Response::new(&mut headers[..]);
+        let Status::Complete(body_offset) = res.parse(&res_buf[..]).bx()? else {
+            panic!("incomplete response: {:?}", res_buf.hex_dump());
+        };
+        assert_eq!(res.code, Some(200));
+        assert!(res
+            .headers
+            .iter()
+            .any(|h| h.name.eq_ignore_ascii_case("connection") && h.value == b"close"));
+        assert!(!res.headers.iter().any(|h| {
+            h.name.eq_ignore_ascii_case("content-length")
+                || h.name.eq_ignore_ascii_case("transfer-encoding")
+        }));
+        assert_eq!(&res_buf[body_offset..], b"hello world");
+
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+        assert_eq!(outcome, ServeOutcome::ServerRequestedConnectionClose);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_client_reads_close_delimited_response() {
+    helpers::run(async move {
+        let (mut server_write, client_read) = loona::buffet::pipe();
+        let (client_write, mut server_read) = loona::buffet::pipe();
+
+        struct CollectDriver;
+
+        impl h1::ClientDriver for CollectDriver {
+            type Return = Vec<u8>;
+            type Error = BX;
+
+            async fn on_informational_response(&mut self, _res: Response) -> b_x::Result<()> {
+                unreachable!()
+            }
+
+            async fn on_final_response(
+                self,
+                _res: Response,
+                body: &mut impl Body,
+            ) -> b_x::Result<Self::Return> {
+                let mut data = Vec::new();
+                while let BodyChunk::Chunk(chunk) = body.next_chunk().await.bx()? {
+                    data.extend_from_slice(&chunk[..]);
+                }
+                Ok(data)
+            }
+        }
+
+        let request_fut = loona::buffet::spawn(async {
+            h1::request(
+                (client_read, client_write),
+                Request::default(),
+                &mut (),
+                CollectDriver,
+            )
+            .await
+        });
+
+        // the pipe only moves along as it's read from
+        let mut req_buf = BytesMut::new();
+        let mut buf = vec![0u8; 1024];
+        while !req_buf.ends_with(b"\r\n\r\n") {
+            let res;
+            (res, buf) = server_read.read_owned(buf).await;
+            req_buf.extend_from_slice(&buf[..res?]);
+        }
+        server_write
+            .write_all_owned("HTTP/1.1 200 OK\r\n\r\nstreamed until ")
+            .await?;
+        server_write.write_all_owned("the end").await?;
+        drop(server_write);
+
+        let (transport, body) = tokio::time::timeout(Duration::from_secs(5), request_fut)
+            .await
+            .bx()?
+            .bx()??;
+        assert_eq!(&body[..], b"streamed until the end");
+        // there's no telling where the next response would start
+        assert!(transport.is_none());
+
+        Ok(())
+    })
+}
+
+trait CommandExt {
+    async fn output_assert_success(&mut self) -> std::process::Output;
+}
+
+impl CommandExt for Command {
+    async fn output_assert_success(&mut self) -> std::process::Output {
+        let output = self.output().await.unwrap();
+        if !output.status.success() {
+            // print stderr
+            eprintln!("command failed with status: {:?}", output.status);
+            eprintln!("stderr: {:?}", String::from_utf8_lossy(&output.stderr));
+            panic!("command failed with status: {:?} {:?}", output.status, self);
+        }
+        output
+    }
+}
+
+#[test]
+fn h1_max_requests_per_connection() {
+    helpers::run(async move {
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, mut client_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h1::serve(
+            (server_read, server_write),
+            Rc::new(h1::ServerConf {
+                max_requests_per_connection: Some(2),
+                ..Default::default()
+            }),
+            RollMut::alloc()?,
+            HelloDriver,
+        ));
+
+        // the third request is never read
+        client_write
+            .write_all_owned("GET / HTTP/1.1\r\n\r\n".repeat(3).into_bytes())
+            .await?;
+        let mut res_buf = BytesMut::new();
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let res;
+            (res, buf) = client_read.read_owned(buf).await;
+            let n = res?;
+            if n == 0 {
+                break;
+            }
+            res_buf.extend_from_slice(&buf[..n]);
+        }
+
+        let mut closes = vec![];
+        let mut rest = &res_buf[..];
+        while !rest.is_empty() {
+            let mut headers = [EMPTY_HEADER; 16];
+            let mut res = httparse::Response::new(&mut headers[..]);
+            let Status::Complete(body_offset) = res.parse(rest).bx()? else {
+                panic!("incomplete response: {:?}", rest.hex_dump());
+            };
+            assert_eq!(res.code, Some(200));
+            closes.push(
+                res.headers
+                    .iter()
+                    .any(|h| h.name.eq_ignore_ascii_case("connection") && h.value == b"close"),
+            );
+            assert_eq!(&rest[body_offset..body_offset + 5], b"hello");
+            rest = &rest[body_offset + 5..];
+        }
+        assert_eq!(closes, [false, true]);
+
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+        assert_eq!(outcome, ServeOutcome::MaxRequestsPerConnectionReached);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_head_response_has_no_body() {
+    helpers::run(async move {
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, mut client_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h1::serve(
+            (server_read, server_write),
+            Default::default(),
+            RollMut::alloc()?,
+            HelloDriver,
+        ));
+
+        // the driver answers HEAD like GET
+        client_write
+            .write_all_owned("HEAD / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nconnection: close\r\n\r\n")
+            .await?;
+        let mut res_buf = BytesMut::new();
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let res;
+            (res, buf) = client_read.read_owned(buf).await;
+            let n = res?;
+            if n == 0 {
+                break;
+            }
+            res_buf.extend_from_slice(&buf[..n]);
+        }
+
+        let mut headers = [EMPTY_HEADER; 16];
+        let mut res = httparse::Response::new(&mut headers[..]);
+        let Status::Complete(body_offset) = res.parse(&res_buf[..]).bx()? else {
+            panic!("incomplete response: {:?}", res_buf.hex_dump());
+        };
+        assert_eq!(res.code, Some(200));
+        assert!(res
+            .headers
+            .iter()
+            .any(|h| h.name.eq_ignore_ascii_case("content-length") && h.value == b"5"));
+
+        // the GET response follows right away
+        let rest = &res_buf[body_offset..];
+        let mut headers = [EMPTY_HEADER; 16];
+        let mut res = httparse::Response::new(&mut headers[..]);
+        let Status::Complete(body_offset) = res.parse(rest).bx()? else {
+            panic!("incomplete response: {:?}", rest.hex_dump());
+        };
+        assert_eq!(res.code, Some(200));
+        assert_eq!(&rest[body_offset..], b"hello");
+
+        tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_fd_budget_prunes_idle_connection() {
+    use loona::fd_budget::{FdBudget, FdBudgetConf};
+
+    helpers::run(async move {
+        // stdin, stdout and stderr alone put us over that
+        let budget = Rc::new(FdBudget::new(FdBudgetConf {
+            limit: Some(2),
+            ..Default::default()
+        }));
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, mut client_read) = loona::buffet::pipe();
+        let conf = Rc::new(h1::ServerConf {
+            fd_budget: Some(budget.clone()),
+            ..Default::default()
+        });
+        let serve_fut = loona::buffet::spawn(h1::serve(
+            (server_read, server_write),
+            conf,
+            RollMut::alloc()?,
+            HelloDriver,
+        ));
+
+        // a connection that hasn't served anything yet isn't idle
+        tokio::task::yield_now().await;
+        assert_eq!(budget.check(), 0);
+
+        client_write
+            .write_all_owned("GET / HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await?;
+        let mut res_buf = BytesMut::new();
+        let mut buf = vec![0u8; 1024];
+        while !res_buf.ends_with(b"hello") {
+            let res;
+            (res, buf) = client_read.read_owned(buf).await;
+            let n = res?;
+            assert_ne!(n, 0, "server hung up early");
+            res_buf.extend_from_slice(&buf[..n]);
+        }
+
+        // now it's waiting for the next request
+        while budget.stats().idle_connections == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(budget.check(), 1);
+
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+        assert_eq!(outcome, ServeOutcome::PrunedWhileIdle);
+        let stats = budget.stats();
+        assert_eq!((stats.connections, stats.pruned_total), (0, 1));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_default_response_headers() {
+    helpers::run(async move {
+        let mut default_response_headers = Headers::default();
+        default_response_headers.insert(header::SERVER, "loona".into());
+        default_response_headers.insert(header::X_CONTENT_TYPE_OPTIONS, "nosniff".into());
+        let conf = Rc::new(h1::ServerConf {
+            default_response_headers,
+            ..Default::default()
+        });
+
+        // the server's own canned responses get them too
+        for (request, status) in [
+            ("GET / HTTP/1.1\r\nconnection: close\r\n\r\n", 200),
+            ("GET / HTTP/1.1\r\nbad header: x\r\n\r\n", 400),
+        ] {
+            let (mut client_write, server_read) = loona::buffet::pipe();
+            let (server_write, mut client_read) = loona::buffet::pipe();
+            let serve_fut = loona::buffet::spawn(h1::serve(
+                (server_read, server_write),
+                conf.clone(),
+                RollMut::alloc()?,
+                HelloDriver,
+            ));
+
+            client_write.write_all_owned(request).await?;
+            let mut res_buf = BytesMut::new();
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let res;
+                (res, buf) = client_read.read_owned(buf).await;
+                let n = res?;
+                if n == 0 {
+                    break;
+                }
+                res_buf.extend_from_slice(&buf[..n]);
+            }
+
+            let mut headers = [EMPTY_HEADER; 16];
+            let mut res = httparse::Response::new(&mut headers[..]);
+            let Status::Complete(_) = res.parse(&res_buf[..]).bx()? else {
+                panic!("incomplete response: {:?}", res_buf.hex_dump());
+            };
+            assert_eq!(res.code, Some(status));
+            let get = |name: &str| {
+                res.headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case(name))
+                    .map(|h| h.value)
+            };
+            assert_eq!(get("server"), Some(&b"loona"[..]));
+            assert_eq!(get("x-content-type-options"), Some(&b"nosniff"[..]));
+            assert!(get("date").is_some(), "{:?}", res_buf.hex_dump());
+
+            tokio::time::timeout(Duration::from_secs(5), serve_fut)
+                .await
+                .bx()?
+                .bx()??;
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_close_delimited_response() {
+    struct TestDriver;
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            req: loona::Request,
+            _req_body: &mut impl Body,
+            mut res: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            let mut response = Response::default();
+            if req.uri.path() == "/identity" {
+                response
+                    .headers
+                    .insert(header::TRANSFER_ENCODING, "identity".into());
+            } else {
+                assert!(res.close_delimited());
+            }
+            let mut body = loona::SinglePieceBody::from("hello");
+            let res = res
+                .write_final_response_with_body(response, &mut body)
+                .await
+                .map_err(BX::from_err)?;
+            Ok(res)
+        }
+    }
+
+    helpers::run(async move {
+        for path in ["/", "/identity"] {
+            let (mut client_write, server_read) = loona::buffet::pipe();
+            let (server_write, mut client_read) = loona::buffet::pipe();
+            let serve_fut = loona::buffet::spawn(h1::serve(
+                (server_read, server_write),
+                Rc::new(h1::ServerConf::default()),
+                RollMut::alloc()?,
+                TestDriver,
+            ));
+
+            // the client would keep the connection open, the server closes
+            // it anyway
+            client_write
+                .write_all_owned(format!("GET {path} HTTP/1.1\r\n\r\n").into_bytes())
+                .await?;
+            let mut res_buf = BytesMut::new();
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let res;
+                (res, buf) = client_read.read_owned(buf).await;
+                let n = res?;
+                if n == 0 {
+                    break;
+                }
+                res_buf.extend_from_slice(&buf[..n]);
+            }
+
+            let mut headers = [EMPTY_HEADER; 16];
+            let mut res = httparse::Response::new(&mut headers[..]);
+            let Status::Complete(body_offset) = res.parse(&res_buf[..]).bx()? else {
+                panic!("incomplete response: {:?}", res_buf.hex_dump());
+            };
+            let get = |name: &str| {
+                res.headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case(name))
+                    .map(|h| h.value)
+            };
+            assert_eq!(get("connection"), Some(&b"close"[..]), "{path}");
+            assert_eq!(get("content-length"), None, "{path}");
+            assert_eq!(get("transfer-encoding"), None, "{path}");
+            assert_eq!(&res_buf[body_offset..], b"hello", "{path}");
+
+            tokio::time::timeout(Duration::from_secs(5), serve_fut)
+                .await
+                .bx()?
+                .bx()??;
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_date_header() {
+    helpers::run(async move {
+        for date_header in [true, false] {
+            let (mut client_write, server_read) = loona::buffet::pipe();
+            let (server_write, mut client_read) = loona::buffet::pipe();
+            let conf = Rc::new(h1::ServerConf {
+                date_header,
+                ..Default::default()
+            });
+            let serve_fut = loona::buffet::spawn(h1::serve(
+                (server_read, server_write),
+                conf,
+                RollMut::alloc()?,
+                HelloDriver,
+            ));
+
+            client_write
+                .write_all_owned("GET / HTTP/1.1\r\nconnection: close\r\n\r\n")
+                .await?;
+            let mut res_buf = BytesMut::new();
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let res;
+                (res, buf) = client_read.read_owned(buf).await;
+                let n = res?;
+                if n == 0 {
+                    break;
+                }
+                res_buf.extend_from_slice(&buf[..n]);
+            }
+
+            let mut headers = [EMPTY_HEADER; 16];
+            let mut res = httparse::Response::new(&mut headers[..]);
+            let Status::Complete(_) = res.parse(&res_buf[..]).bx()? else {
+                panic!("incomplete response: {:?}", res_buf.hex_dump());
+            };
+            let date = res
+                .headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case("date"))
+                .map(|h| std::str::from_utf8(h.value).unwrap());
+            if date_header {
+                let date = date.expect("response should have a date header");
+                assert!(date.ends_with(" GMT"), "{date}");
+                assert_eq!(date.len(), "Sun, 06 Nov 1994 08:49:37 GMT".len());
+            } else {
+                assert_eq!(date, None);
+            }
+
+            tokio::time::timeout(Duration::from_secs(5), serve_fut)
+                .await
+                .bx()?
+                .bx()??;
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_protocol_error_log() {
+    helpers::run(async move {
+        let log = Rc::new(loona::protocol_errors::ProtocolErrorLog::new(8));
+        let too_large = format!("GET / HTTP/1.1\r\nx-header: {}\r\n\r\n", "a".repeat(512));
+
+        for req in [
+            b"GET /\x01 HTTP/1.1\r\n\r\n".to_vec(),
+            too_large.into_bytes(),
+        ] {
+            let (mut client_write, server_read) = loona::buffet::pipe();
+            let (server_write, mut client_read) = loona::buffet::pipe();
+            let serve_fut = loona::buffet::spawn(h1::serve(
+                (server_read, server_write),
+                Rc::new(h1::ServerConf {
+                    max_header_section_size: 256,
+                    protocol_errors: Some(log.clone()),
+                    ..Default::default()
+                }),
+                RollMut::alloc()?,
+                HelloDriver,
+            ));
+
+            client_write.write_all_owned(req).await?;
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let res;
+                (res, buf) = client_read.read_owned(buf).await;
+                if res? == 0 {
+                    break;
+                }
+            }
+            _ = tokio::time::timeout(Duration::from_secs(5), serve_fut)
+                .await
+                .bx()?;
+        }
+
+        let recent = log.recent();
+        assert_eq!(log.total(), 2);
+        assert!(recent.iter().all(|e| e.version == http::Version::HTTP_11));
+        assert!(recent[0].snippet.starts_with(b"GET /\x01"));
+        assert!(
+            recent[0]
+                .kind
+                .starts_with("Parsing error in parser Http1Request: "),
+            "{}",
+            recent[0].kind
+        );
+        assert!(recent[0].kind.contains(" at offset "), "{}", recent[0].kind);
+        assert_eq!(recent[1].snippet.len(), loona::protocol_errors::SNIPPET_LEN);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_error_responses() {
+    helpers::run(async move {
+        use loona::error::{ConnectionError, ConnectionErrorCategory};
+
+        let log = Rc::new(loona::protocol_errors::ProtocolErrorLog::new(8));
+        let sink = Rc::new(RecordingSink::default());
+        let error_body: h1::ErrorBodyHook = Rc::new(|error: &ConnectionError| {
+            (*error == ConnectionError::UnsupportedTransferCoding).then(|| h1::ErrorBody {
+                content_type: "text/plain".into(),
+                body: format!("{error}").into_bytes().into(),
+            })
+        });
+
+        for (req, status, outcome, error) in [
+            (
+                "GET / HTTP/1.1\r\nbad header: x\r\n\r\n",
+                "400 Bad Request",
+                ServeOutcome::ClientDidntSpeakHttp11,
+                ConnectionError::MalformedRequest,
+            ),
+            (
+                "GET / HTTP/2.0\r\n\r\n",
+                "505 HTTP Version Not Supported",
+                ServeOutcome::ClientDidntSpeakHttp11,
+                ConnectionError::UnsupportedVersion,
+            ),
+            (
+                "POST / HTTP/1.1\r\ncontent-length: 1\r\ncontent-length: 2\r\n\r\nab",
+                "400 Bad Request",
+                ServeOutcome::RejectedInvalidRequest,
+                ConnectionError::InvalidContentLength,
+            ),
+            (
+                "POST / HTTP/1.1\r\ntransfer-encoding: gzip, chunked\r\n\r\n",
+                "501 Not Implemented",
+                ServeOutcome::RejectedInvalidRequest,
+                ConnectionError::UnsupportedTransferCoding,
+            ),
+        ] {
+            let (mut client_write, server_read) = loona::buffet::pipe();
+            let (server_write, mut client_read) = loona::buffet::pipe();
+            let serve_fut = loona::buffet::spawn(h1::serve(
+                (server_read, server_write),
+                Rc::new(h1::ServerConf {
+                    protocol_errors: Some(log.clone()),
+                    metrics: Some(sink.clone()),
+                    error_body: Some(error_body.clone()),
+                    ..Default::default()
+                }),
+                RollMut::alloc()?,
+                HelloDriver,
+            ));
+
+            client_write.write_all_owned(req).await?;
+            let mut res_buf = BytesMut::new();
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let res;
+                (res, buf) = client_read.read_owned(buf).await;
+                let n = res?;
+                if n == 0 {
+                    break;
+                }
+                res_buf.extend_from_slice(&buf[..n]);
+            }
+            let res = std::str::from_utf8(&res_buf[..])?;
+            assert!(
+                res.starts_with(&format!("HTTP/1.1 {status}\r\n")),
+                "unexpected response to {req:?}: {res:?}"
+            );
+            assert!(res.contains("\r\nconnection: close\r\n"), "{res:?}");
+
+            if status.starts_with("501") {
+                assert!(res.contains("\r\ncontent-type: text/plain\r\n"), "{res:?}");
+                assert!(
+                    res.ends_with("\r\n\r\nunsupported transfer-coding"),
+                    "{res:?}"
+                );
+            } else {
+                assert!(res.contains("\r\ncontent-length: 0\r\n"), "{res:?}");
+                assert!(res.ends_with("\r\n\r\n"), "{res:?}");
+            }
+
+            let res_outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut)
+                .await
+                .bx()?
+                .bx()??;
+            assert_eq!(res_outcome, outcome);
+            assert_eq!(log.recent().last().unwrap().code, error);
+            assert_eq!(error.category(), ConnectionErrorCategory::Parse);
+            assert_eq!(
+                sink.counter(metrics::Counter::ConnectionErrors(error)),
+                1,
+                "{req:?}"
+            );
+        }
+
+        assert_eq!(log.total(), 4);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_tls_upgrade_requests() {
+    helpers::run(async move {
+        for tls_upgrade_requests in [
+            h1::TlsUpgradeRequests::Reject,
+            h1::TlsUpgradeRequests::Strip,
+        ] {
+            let (mut client_write, server_read) = loona::buffet::pipe();
+            let (server_write, mut client_read) = loona::buffet::pipe();
+            let serve_fut = loona::buffet::spawn(h1::serve(
+                (server_read, server_write),
+                Rc::new(h1::ServerConf {
+                    tls_upgrade_requests,
+                    ..Default::default()
+                }),
+                RollMut::alloc()?,
+                HelloDriver,
+            ));
+
+            client_write
+                .write_all_owned(
+                    "OPTIONS * HTTP/1.1\r\nhost: example.org\r\nupgrade: TLS/1.0\r\nconnection: close\r\n\r\n",
+                )
+                .await?;
+            let mut res_buf = BytesMut::new();
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let res;
+                (res, buf) = client_read.read_owned(buf).await;
+                let n = res?;
+                if n == 0 {
+                    break;
+                }
+                res_buf.extend_from_slice(&buf[..n]);
+            }
+
+            let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut)
+                .await
+                .bx()?
+                .bx()??;
+            match tls_upgrade_requests {
+                h1::TlsUpgradeRequests::Reject => {
+                    assert!(res_buf.starts_with(b"HTTP/1.1 400 "));
+                    assert!(!res_buf.windows(9).any(|w| w == b"\r\nupgrade"));
+                    assert_eq!(outcome, ServeOutcome::RefusedTlsUpgrade);
+                }
+                _ => {
+                    assert!(res_buf.starts_with(b"HTTP/1.1 200 "));
+                    assert_eq!(outcome, ServeOutcome::ClientRequestedConnectionClose);
+                }
+            }
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_serve_over_tokio_duplex() {
+    use loona::buffet::TokioIo;
+
+    helpers::run(async move {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let serve_fut = loona::buffet::spawn(h1::serve(
+            TokioIo(server).into_halves(),
+            Default::default(),
+            RollMut::alloc()?,
+            HelloDriver,
+        ));
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\nconnection: close\r\n\r\n")
+            .await?;
+        let mut res_buf = Vec::new();
+        client.read_to_end(&mut res_buf).await?;
+
+        let mut headers = [EMPTY_HEADER; 16];
+        let mut res = httparse::Response::new(&mut headers[..]);
+        let Status::Complete(body_offset) = res.parse(&res_buf[..]).bx()? else {
+            panic!("incomplete response: {:?}", res_buf.hex_dump());
+        };
+        assert_eq!(res.code, Some(200));
+        assert_eq!(&res_buf[body_offset..], b"hello");
+
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+        assert_eq!(outcome, ServeOutcome::ClientRequestedConnectionClose);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_connect_udp() {
+    use loona::connect_udp::{self, Capsule, CapsuleDecoder, DatagramRelay, UdpTarget};
+    use loona_h2::{HeadersFlags, StreamId};
+
+    /// Sends back every datagram, shouting
+    struct EchoRelay {
+        tx: tokio::sync::mpsc::UnboundedSender<loona::buffet::Piece>,
+        rx: tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<loona::buffet::Piece>>,
+    }
+
+    impl DatagramRelay for EchoRelay {
+        async fn send_datagram(&self, payload: loona::buffet::Piece) -> b_x::Result<()> {
+            self.tx
+                .send(payload.to_ascii_uppercase().into())
+                .map_err(|_| BX::from_string("echo relay closed".into()))
+        }
+
+        async fn recv_datagram(&self) -> b_x::Result<Option<loona::buffet::Piece>> {
+            Ok(self.rx.lock().await.recv().await)
+        }
+    }
+
+    struct TestDriver;
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            req: loona::Request,
+            req_body: &mut impl Body,
+            res: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            let target = UdpTarget::from_request(&req)?.unwrap();
+            assert_eq!(target.host, "192.0.2.6");
+            assert_eq!(target.port, 443);
+
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            let relay = EchoRelay { tx, rx: rx.into() };
+            let res = res.write_final_response(connect_udp::response()).await?;
+            Ok(connect_udp::relay(req_body, res, &relay).await?)
+        }
+    }
+
+    struct TwoHalves<W, R>(W, R);
+    impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+        type Read = R;
+        type Write = W;
+
+        fn into_halves(self) -> (Self::Read, Self::Write) {
+            (self.1, self.0)
+        }
+    }
+
+    fn connect_udp_headers() -> httpwg::Headers {
+        let mut headers = httpwg::Headers::default();
+        headers.append(":method", "CONNECT");
+        headers.append(":protocol", "connect-udp");
+        headers.append(":scheme", "https");
+        headers.append(":path", "/.well-known/masque/udp/192.0.2.6/443/");
+        headers.append(":authority", "proxy.test");
+        headers.append("capsule-protocol", "?1");
+        headers
+    }
+
+    helpers::run(async move {
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h2::serve(
+            (server_read, server_write),
+            Rc::new(h2::ServerConf {
+                enable_connect_protocol: true,
+                ..Default::default()
+            }),
+            RollMut::alloc()?,
+            Rc::new(TestDriver),
+        ));
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+        assert!(conn.settings.enable_connect_protocol);
+
+        let stream_id = StreamId(1);
+        conn.encode_and_write_headers(stream_id, HeadersFlags::EndHeaders, &connect_udp_headers())
+            .await
+            .unwrap();
+        let (_, payload) = conn.wait_for_frame(httpwg::FrameT::Headers).await.unwrap();
+        let headers = conn.decode_headers(payload.into()).unwrap();
+        assert_eq!(
+            headers.get_first(&":status".into()).map(|v| &v[..]),
+            Some(&b"200"[..])
+        );
+        assert_eq!(
+            headers
+                .get_first(&"capsule-protocol".into())
+                .map(|v| &v[..]),
+            Some(&b"?1"[..])
+        );
+
+        // capsules can be split across DATA frames, unknown ones are skipped
+        let mut capsules = Capsule::datagram(b"hello").encode();
+        capsules.extend(
+            Capsule {
+                capsule_type: 0x2a,
+                value: b"ignore me".to_vec(),
+            }
+            .encode(),
+        );
+        capsules.extend(Capsule::datagram(b"world").encode());
+        let (first, second) = capsules.split_at(10);
+        conn.write_data(stream_id, false, first.to_vec())
+            .await
+            .unwrap();
+        conn.write_data(stream_id, false, second.to_vec())
+            .await
+            .unwrap();
+
+        let mut decoder = CapsuleDecoder::default();
+        let mut echoed = Vec::new();
+        while echoed.len() < 2 {
+            let (_, payload) = conn.wait_for_frame(httpwg::FrameT::Data).await.unwrap();
+            decoder.push(&payload[..]);
+            while let Some(capsule) = decoder.next_capsule().unwrap() {
+                let (context_id, payload) = capsule.context().unwrap().unwrap();
+                assert_eq!(context_id, 0);
+                echoed.push(payload.to_vec());
+            }
+        }
+        assert_eq!(echoed, [b"HELLO".to_vec(), b"WORLD".to_vec()]);
+
+        // ending our side ends theirs
+        conn.write_data(stream_id, true, Vec::new()).await.unwrap();
+        loop {
+            let (frame, _) = conn.wait_for_frame(httpwg::FrameT::Data).await.unwrap();
+            if frame.is_end_stream() {
+                break;
+            }
+        }
+        drop(conn);
+        serve_fut.await.bx()??;
+
+        // without `enable_connect_protocol`, `:protocol` is malformed
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h2::serve(
+            (server_read, server_write),
+            Default::default(),
+            RollMut::alloc()?,
+            Rc::new(TestDriver),
+        ));
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+        assert!(!conn.settings.enable_connect_protocol);
+        conn.encode_and_write_headers(stream_id, HeadersFlags::EndHeaders, &connect_udp_headers())
+            .await
+            .unwrap();
+        conn.verify_stream_error(httpwg::ErrorC::ProtocolError)
+            .await
+            .unwrap();
+        drop(conn);
+        _ = serve_fut.await.bx()?;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn response_header_size_limit() {
+    use loona_h2::{HeadersFlags, StreamId};
+    use std::cell::RefCell;
+
+    /// `/big` has unique fields that add up to more than 4KiB, `/repeated`
+    /// has as many bytes of fields, but they're all the same
+    #[derive(Clone, Default)]
+    struct TestDriver {
+        errors: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+    where
+        OurEncoder: Encoder,
+    {
+        type Error = BX;
+
+        async fn handle(
+            &self,
+            req: loona::Request,
+            _req_body: &mut impl Body,
+            res: Responder<OurEncoder, ExpectResponseHeaders>,
+        ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+            let mut response = Response::default();
+            for i in 0..64 {
+                let (name, value) = match req.uri.path() {
+                    "/big" => (format!("x-debug-{i}"), format!("{i:0>100}")),
+                    "/repeated" => ("x-repeated".to_string(), "0".repeat(100)),
+                    _ => break,
+                };
+                response.headers.append(
+                    http::HeaderName::try_from(name).unwrap(),
+                    value.into_bytes().into(),
+                );
+            }
+
+            let mut body = loona::SinglePieceBody::from("ok");
+            match res
+                .write_final_response_with_body(response, &mut body)
+                .await
+            {
+                Ok(res) => Ok(res),
+                Err(e) => {
+                    self.errors.borrow_mut().push(e.to_string());
+                    Err(BX::from_err(e))
+                }
+            }
+        }
+    }
+
+    struct TwoHalves<W, R>(W, R);
+    impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+        type Read = R;
+        type Write = W;
+
+        fn into_halves(self) -> (Self::Read, Self::Write) {
+            (self.1, self.0)
+        }
+    }
+
+    helpers::run(async move {
+        const LIMIT: usize = 4096;
+
+        // HTTP/1.1 counts raw bytes: none of the response goes out, the
+        // handler fails, and the client gets a 500 instead
+        for (path, ok) in [("/", true), ("/big", false), ("/repeated", false)] {
+            let driver = TestDriver::default();
+            let (mut client_write, server_read) = loona::buffet::pipe();
+            let (server_write, mut client_read) = loona::buffet::pipe();
+            let serve_fut = loona::buffet::spawn(h1::serve(
+                (server_read, server_write),
+                Rc::new(h1::ServerConf {
+                    max_response_header_size: LIMIT,
+                    date_header: false,
+                    ..Default::default()
+                }),
+                RollMut::alloc()?,
+                driver.clone(),
+            ));
+
+            client_write
+                .write_all_owned(
+                    format!("GET {path} HTTP/1.1\r\nconnection: close\r\n\r\n").into_bytes(),
+                )
+                .await?;
+            let mut res_buf = BytesMut::new();
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let res;
+                (res, buf) = client_read.read_owned(buf).await;
+                let n = res?;
+                if n == 0 {
+                    break;
+                }
+                res_buf.extend_from_slice(&buf[..n]);
+            }
+            let res = serve_fut.await.bx()?;
+
+            if ok {
+                assert!(res_buf.starts_with(b"HTTP/1.1 200 OK\r\n"));
+                assert!(driver.errors.borrow().is_empty());
+            } else {
+                assert!(
+                    matches!(res, Ok(ServeOutcome::HandlerFailed)),
+                    "{path}: {res:?}"
+                );
+                assert_eq!(
+                    &res_buf[..],
+                    b"HTTP/1.1 500 Internal Server Error\r\nconnection: close\r\ncontent-length: 0\r\n\r\n",
+                    "{path}: {:?}",
+                    res_buf.hex_dump()
+                );
+                let errors = driver.errors.borrow();
+                assert_eq!(errors.len(), 1);
+                assert!(
+                    errors[0].contains(&format!("exceeds the limit of {LIMIT}")),
+                    "{path}: {}",
+                    errors[0]
+                );
+            }
+        }
+
+        // HTTP/2 counts bytes after HPACK: `/repeated` fits, `/big` gets a
+        // 500 instead, and its fields never make it to the dynamic table
+        let driver = TestDriver::default();
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h2::serve(
+            (server_read, server_write),
+            Rc::new(h2::ServerConf {
+                max_response_header_size: LIMIT,
+                ..Default::default()
+            }),
+            RollMut::alloc()?,
+            Rc::new(driver.clone()),
+        ));
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+
+        for (stream_id, path, status) in [
+            (1, "/repeated", "200"),
+            (3, "/big", "500"),
+            (5, "/repeated", "200"),
+        ] {
+            let mut headers = httpwg::Headers::default();
+            headers.append(":method", "GET");
+            headers.append(":scheme", "https");
+            headers.append(":path", path);
+            headers.append(":authority", "example.org");
+            conn.encode_and_write_headers(
+                StreamId(stream_id),
+                HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+                &headers,
+            )
+            .await
+            .unwrap();
+
+            let (frame, payload) = conn.wait_for_frame(httpwg::FrameT::Headers).await.unwrap();
+            assert_eq!(frame.stream_id, StreamId(stream_id));
+            assert!(payload.len() <= LIMIT);
+            let headers = conn.decode_headers(payload.into()).unwrap();
+            assert_eq!(
+                headers.get_first(&":status".into()).map(|v| &v[..]),
+                Some(status.as_bytes()),
+                "{path}"
+            );
+            if status == "200" {
+                let repeated = headers
+                    .iter()
+                    .filter(|(name, _)| &name[..] == b"x-repeated")
+                    .count();
+                assert_eq!(repeated, 64);
+            }
+        }
+
+        let errors = driver.errors.borrow().clone();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains(&format!("exceeds the limit of {LIMIT}")));
+
+        drop(conn);
+        _ = serve_fut.await.bx()?;
+
+        Ok(())
+    })
+}
+
+/// Fails the way the request path says: `/panic` and `/error` before the
+/// response, `/midway` after sending half of its body. Says hello otherwise.
+struct FailingDriver;
+
+impl<OurEncoder> ServerDriver<OurEncoder> for FailingDriver
+where
+    OurEncoder: Encoder,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        req: loona::Request,
+        _req_body: &mut impl Body,
+        res: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+        match req.uri.path() {
+            "/panic" => panic!("handler panicked on purpose"),
+            "/error" => return Err(BX::from_err(std::io::Error::other("failed on purpose"))),
+            "/midway" => {
+                let mut headers = Headers::default();
+                headers.insert(header::CONTENT_LENGTH, "10".into());
+                let mut res = res
+                    .write_final_response(Response {
+                        headers,
+                        ..Default::default()
+                    })
+                    .await
+                    .map_err(BX::from_err)?;
+                res.write_chunk("hello".into())
+                    .await
+                    .map_err(BX::from_err)?;
+                res.flush().await.map_err(BX::from_err)?;
+                return Err(BX::from_err(std::io::Error::other("failed on purpose")));
+            }
+            _ => {}
+        }
+
+        let mut body = loona::SinglePieceBody::from("hello");
+        let res = res
+            .write_final_response_with_body(Response::default(), &mut body)
+            .await
+            .map_err(BX::from_err)?;
+        Ok(res)
+    }
+}
+
+#[test]
+fn h1_handler_failures() {
+    helpers::run(async move {
+        use loona::error::ConnectionError;
+
+        async fn read_all(mut client_read: impl ReadOwned) -> b_x::Result<String> {
+            let mut res_buf = BytesMut::new();
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let res;
+                (res, buf) = client_read.read_owned(buf).await;
+                let n = res?;
+                if n == 0 {
+                    break;
+                }
+                res_buf.extend_from_slice(&buf[..n]);
+            }
+            Ok(String::from_utf8(res_buf.to_vec()).unwrap())
+        }
+
+        // before the response: 500s, and the connection goes on
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, client_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h1::serve(
+            (server_read, server_write),
+            Rc::new(h1::ServerConf {
+                date_header: false,
+                ..Default::default()
+            }),
+            RollMut::alloc()?,
+            FailingDriver,
+        ));
+        client_write
+            .write_all_owned(
+                "GET /panic HTTP/1.1\r\n\r\nGET /error HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+            )
+            .await?;
+        drop(client_write);
+        let res = read_all(client_read).await?;
+        let internal_error = "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\n\r\n";
+        let (first, rest) = res.split_at(internal_error.len());
+        assert_eq!(first, internal_error);
+        let (second, rest) = rest.split_at(internal_error.len());
+        assert_eq!(second, internal_error);
+        assert!(rest.starts_with("HTTP/1.1 200 OK\r\n"), "{res:?}");
+        assert!(rest.ends_with("\r\n\r\nhello"), "{res:?}");
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+        assert_eq!(outcome, ServeOutcome::ClientClosedConnectionBetweenRequests);
+
+        // the 500 goes through the error body hook too
+        let error_body: h1::ErrorBodyHook = Rc::new(|error: &ConnectionError| {
+            Some(h1::ErrorBody {
+                content_type: "text/plain".into(),
+                body: format!("{error}").into_bytes().into(),
+            })
+        });
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, client_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h1::serve(
+            (server_read, server_write),
+            Rc::new(h1::ServerConf {
+                error_body: Some(error_body),
+                ..Default::default()
+            }),
+            RollMut::alloc()?,
+            FailingDriver,
+        ));
+        client_write
+            .write_all_owned("GET /error HTTP/1.1\r\nconnection: close\r\n\r\n")
+            .await?;
+        let res = read_all(client_read).await?;
+        assert!(
+            res.starts_with("HTTP/1.1 500 Internal Server Error\r\nconnection: close\r\n"),
+            "{res:?}"
+        );
+        assert!(res.contains("\r\ncontent-type: text/plain\r\n"), "{res:?}");
+        assert!(res.ends_with("\r\n\r\nhandler failed"), "{res:?}");
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+        assert_eq!(outcome, ServeOutcome::HandlerFailed);
+
+        // mid-response: all we can do is hang up
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, client_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h1::serve(
+            (server_read, server_write),
+            Default::default(),
+            RollMut::alloc()?,
+            FailingDriver,
+        ));
+        client_write
+            .write_all_owned("GET /midway HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n")
+            .await?;
+        let res = read_all(client_read).await?;
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{res:?}");
+        assert!(res.contains("\r\ncontent-length: 10\r\n"), "{res:?}");
+        assert!(res.ends_with("\r\n\r\nhello"), "{res:?}");
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+        assert_eq!(outcome, ServeOutcome::HandlerFailed);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_handler_failures() {
+    use httpwg::ErrorC;
+    use loona_h2::{HeadersFlags, StreamId};
+
+    struct TwoHalves<W, R>(W, R);
+    impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+        type Read = R;
+        type Write = W;
+
+        fn into_halves(self) -> (Self::Read, Self::Write) {
+            (self.1, self.0)
+        }
+    }
+
+    helpers::run(async move {
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h2::serve(
+            (server_read, server_write),
+            Default::default(),
+            RollMut::alloc()?,
+            Rc::new(FailingDriver),
+        ));
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+
+        for (stream_id, path) in [(1, "/panic"), (3, "/error"), (5, "/midway"), (7, "/")] {
+            let mut headers = httpwg::Headers::default();
+            headers.append(":method", "GET");
+            headers.append(":scheme", "https");
+            headers.append(":path", path);
+            headers.append(":authority", "example.org");
+            conn.encode_and_write_headers(
+                StreamId(stream_id),
+                HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+                &headers,
+            )
+            .await
+            .unwrap();
+
+            let (frame, payload) = conn.wait_for_frame(httpwg::FrameT::Headers).await.unwrap();
+            assert_eq!(frame.stream_id, StreamId(stream_id));
+            let headers = conn.decode_headers(payload.into()).unwrap();
+            let status = headers.get_first(&":status".into()).unwrap();
+            match path {
+                "/panic" | "/error" => {
+                    assert_eq!(&status[..], b"500", "{path}");
+                    conn.verify_stream_close(StreamId(stream_id)).await.unwrap();
+                }
+                "/midway" => {
+                    assert_eq!(&status[..], b"200", "{path}");
+                    conn.verify_stream_error(ErrorC::InternalError)
+                        .await
+                        .unwrap();
+                }
+                _ => {
+                    assert_eq!(&status[..], b"200", "{path}");
+                    conn.verify_stream_close(StreamId(stream_id)).await.unwrap();
+                }
+            }
+        }
+        conn.verify_connection_still_alive().await.unwrap();
+
+        drop(conn);
+        _ = serve_fut.await.bx()?;
+
+        Ok(())
+    })
+}
+
+/// Streams "hello", then a trailer the body announced up front
+struct TrailersDriver;
+
+impl<OurEncoder> ServerDriver<OurEncoder> for TrailersDriver
+where
+    OurEncoder: Encoder,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        _req: loona::Request,
+        _req_body: &mut impl Body,
+        res: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+        let (tx, mut body) = loona::ChannelBody::new(2);
+        body.announce_trailers([header::HeaderName::from_static("x-checksum")]);
+        loona::buffet::spawn(async move {
+            tx.send("hello".into()).await?;
+            let mut trailers = Headers::default();
+            trailers.insert("x-checksum", "abc".into());
+            tx.finish(Some(Box::new(trailers))).await
+        });
+        let res = res
+            .write_final_response_with_body(Response::default(), &mut body)
+            .await
+            .map_err(BX::from_err)?;
+        Ok(res)
+    }
+}
+
+#[test]
+fn h1_response_trailers() {
+    helpers::run(async move {
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, mut client_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(h1::serve(
+            (server_read, server_write),
+            Default::default(),
+            RollMut::alloc()?,
+            TrailersDriver,
+        ));
+
+        client_write
+            .write_all_owned("GET / HTTP/1.1\r\nconnection: close\r\n\r\n")
+            .await?;
+        let mut res_buf = BytesMut::new();
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let res;
+            (res, buf) = client_read.read_owned(buf).await;
+            let n = res?;
+            if n == 0 {
+                break;
+            }
+            res_buf.extend_from_slice(&buf[..n]);
+        }
+
+        let mut headers = [EMPTY_HEADER; 16];
+        let mut res = httparse::Response::new(&mut headers[..]);
+        let Status::Complete(body_offset) = res.parse(&res_buf[..]).bx()? else {
+            panic!("incomplete response: {:?}", res_buf.hex_dump());
+        };
+        assert_eq!(res.code, Some(200));
+        let header = |name: &str| {
+            res.headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case(name))
+                .map(|h| h.value)
+        };
+        assert_eq!(header("trailer"), Some(&b"x-checksum"[..]));
+        assert_eq!(header("transfer-encoding"), Some(&b"chunked"[..]));
+        assert_eq!(header("content-length"), None);
+        assert_eq!(
+            &res_buf[body_offset..],
+            b"5\r\nhello\r\n0\r\nx-checksum: abc\r\n\r\n"
+        );
+
+        tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_response_trailers() {
+    use loona_h2::{FrameType, HeadersFlags, StreamId};
+
+    helpers::run(async move {
+        struct TwoHalves<W, R>(W, R);
+        impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+            type Read = R;
+            type Write = W;
+
+            fn into_halves(self) -> (Self::Read, Self::Write) {
+                (self.1, self.0)
+            }
+        }
+
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+
+        let serve_fut = loona::buffet::spawn(async move {
+            h2::serve(
+                (server_read, server_write),
+                Default::default(),
+                RollMut::alloc()?,
+                Rc::new(TrailersDriver),
+            )
+            .await?;
+            Ok::<_, BX>(())
+        });
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+
+        let mut headers = httpwg::Headers::default();
+        headers.append(":method", "GET");
+        headers.append(":scheme", "http");
+        headers.append(":path", "/");
+        headers.append(":authority", "localhost");
+        let block = conn.encode_headers(&headers).unwrap();
+        conn.write_headers(
+            StreamId(1),
+            HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+            block,
+        )
+        .await
+        .unwrap();
+
+        // response headers, DATA without END_STREAM, then the trailers
+        let mut blocks = vec![];
+        let mut body = vec![];
+        loop {
+            let Some(httpwg::Ev::Frame { frame, payload }) = conn.ev_rx.recv().await else {
+                panic!("connection closed before the response was done");
+            };
+            if frame.stream_id != StreamId(1) {
+                continue;
+            }
+            match frame.frame_type {
+                FrameType::Headers(_) => blocks.push(conn.decode_headers(payload.into()).unwrap()),
+                FrameType::Data(flags) => {
+                    assert!(!flags.contains(loona_h2::DataFlags::EndStream));
+                    body.extend_from_slice(&payload[..]);
+                }
+                _ => {}
+            }
+            if frame.is_end_stream() {
+                assert!(matches!(frame.frame_type, FrameType::Headers(_)));
+                break;
+            }
+        }
+        assert_eq!(body, b"hello");
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(
+            blocks[0].get_first(&"trailer".into()).map(|v| &v[..]),
+            Some(&b"x-checksum"[..])
+        );
+        assert_eq!(
+            blocks[1].get_first(&"x-checksum".into()).map(|v| &v[..]),
+            Some(&b"abc"[..])
+        );
+
+        drop(conn);
+        tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+
+        Ok(())
+    })
+}
+
+/// Responds with a cookie and a custom header, the same every time
+struct CookieDriver;
+
+impl<OurEncoder> ServerDriver<OurEncoder> for CookieDriver
+where
+    OurEncoder: Encoder,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        _req: loona::Request,
+        _req_body: &mut impl Body,
+        res: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+        let mut response = Response::default();
+        response
+            .headers
+            .insert(header::SET_COOKIE, "session=hunter2".into());
+        response.headers.insert("x-custom", "custom-value".into());
+        let res = res
+            .write_final_response_with_body(response, &mut ())
+            .await
+            .map_err(BX::from_err)?;
+        Ok(res)
+    }
+}
+
+#[test]
+fn h2_hpack_indexing() {
+    use loona_h2::{FrameType, HeadersFlags, StreamId};
+
+    helpers::run(async move {
+        struct TwoHalves<W, R>(W, R);
+        impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+            type Read = R;
+            type Write = W;
+
+            fn into_halves(self) -> (Self::Read, Self::Write) {
+                (self.1, self.0)
+            }
+        }
+
+        // raw header blocks of two identical responses
+        async fn response_blocks(
+            hpack_table_sizing: h2::HpackTableSizing,
+        ) -> b_x::Result<Vec<Vec<u8>>> {
+            let (server_write, client_read) = loona::buffet::pipe();
+            let (client_write, server_read) = loona::buffet::pipe();
+
+            let serve_fut = loona::buffet::spawn(async move {
+                h2::serve(
+                    (server_read, server_write),
+                    Rc::new(h2::ServerConf {
+                        hpack_table_sizing,
+                        // so literals can be found in the blocks
+                        hpack_huffman: false,
+                        ..Default::default()
+                    }),
+                    RollMut::alloc()?,
+                    Rc::new(CookieDriver),
+                )
+                .await?;
+                Ok::<_, BX>(())
+            });
+
+            let config = Rc::new(httpwg::Config::default());
+            let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+            conn.handshake().await.unwrap();
+
+            let mut blocks = vec![];
+            for id in [1, 3] {
+                let mut headers = httpwg::Headers::default();
+                headers.append(":method", "GET");
+                headers.append(":scheme", "http");
+                headers.append(":path", "/");
+                headers.append(":authority", "localhost");
+                let block = conn.encode_headers(&headers).unwrap();
+                conn.write_headers(
+                    StreamId(id),
+                    HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+                    block,
+                )
+                .await
+                .unwrap();
+
+                loop {
+                    let Some(httpwg::Ev::Frame { frame, payload }) = conn.ev_rx.recv().await else {
+                        panic!("connection closed before the response was done");
+                    };
+                    if frame.stream_id != StreamId(id) {
+                        continue;
+                    }
+                    if let FrameType::Headers(_) = frame.frame_type {
+                        blocks.push(payload[..].to_vec());
+                    }
+                    if frame.is_end_stream() {
+                        break;
+                    }
+                }
+            }
+
+            drop(conn);
+            tokio::time::timeout(Duration::from_secs(5), serve_fut)
+                .await
+                .bx()?
+                .bx()??;
+            Ok(blocks)
+        }
+
+        let contains = |block: &[u8], needle: &[u8]| {
+            block.windows(needle.len()).any(|window| window == needle)
+        };
+
+        // repeated fields are indexed, except for the cookie
+        let blocks = response_blocks(h2::HpackTableSizing::Fixed).await?;
+        assert!(contains(&blocks[0], b"custom-value"));
+        assert!(!contains(&blocks[1], b"custom-value"));
+        assert!(contains(&blocks[0], b"session=hunter2"));
+        assert!(contains(&blocks[1], b"session=hunter2"));
+
+        // nothing is
+        let blocks = response_blocks(h2::HpackTableSizing::Disabled).await?;
+        assert!(contains(&blocks[0], b"custom-value"));
+        assert!(contains(&blocks[1], b"custom-value"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_hpack_decoder_limits() {
+    use loona::buffet::{PipeRead, PipeWrite};
+    use loona_h2::{HeadersFlags, StreamId};
+
+    helpers::run(async move {
+        struct TwoHalves<W, R>(W, R);
+        impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+            type Read = R;
+            type Write = W;
+
+            fn into_halves(self) -> (Self::Read, Self::Write) {
+                (self.1, self.0)
+            }
+        }
+
+        async fn connect(
+            conf: h2::ServerConf,
+        ) -> b_x::Result<httpwg::Conn<TwoHalves<PipeWrite, PipeRead>>> {
+            let (server_write, client_read) = loona::buffet::pipe();
+            let (client_write, server_read) = loona::buffet::pipe();
+            loona::buffet::spawn(h2::serve(
+                (server_read, server_write),
+                Rc::new(conf),
+                RollMut::alloc()?,
+                Rc::new(CookieDriver),
+            ));
+
+            let config = Rc::new(httpwg::Config::default());
+            let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+            conn.handshake().await.unwrap();
+            Ok(conn)
+        }
+
+        // GET http://localhost/
+        let request: &[u8] = b"\x82\x86\x84\x01\x09localhost";
+
+        // a 4000-byte entry, then a hundred references to it: too large once
+        // decoded, but the connection goes on
+        let mut conn = connect(Default::default()).await?;
+        let mut bomb = request.to_vec();
+        bomb.extend([0x40, 6]);
+        bomb.extend(b"x-bomb");
+        bomb.extend([0x7f, 0xa1, 0x1e]);
+        bomb.extend([b'a'; 4000]);
+        bomb.extend([0xbe; 100]);
+        conn.write_headers(
+            StreamId(1),
+            HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+            bomb.into(),
+        )
+        .await
+        .unwrap();
+        let (_, payload) = conn.wait_for_frame(httpwg::FrameT::Headers).await.unwrap();
+        let headers = conn.decode_headers(payload.into()).unwrap();
+        assert_eq!(&headers.get_first(&":status".into()).unwrap()[..], b"431");
+        let headers = conn.common_headers("GET");
+        conn.send_req_and_expect_status(StreamId(3), &headers, 200)
+            .await
+            .unwrap();
+
+        // size updates only go at the start of a block
+        let mut conn = connect(Default::default()).await?;
+        let block = [&request[..1], &[0x20], &request[1..]].concat();
+        conn.write_headers(
+            StreamId(1),
+            HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+            block.into(),
+        )
+        .await
+        .unwrap();
+        conn.verify_stream_error(httpwg::ErrorC::CompressionError)
+            .await
+            .unwrap();
+
+        // once a smaller table size is acknowledged, the next block must
+        // start by shrinking the table
+        let small_table = || h2::ServerConf {
+            header_table_size: 256,
+            ..Default::default()
+        };
+        let mut conn = connect(small_table()).await?;
+        conn.write_headers(
+            StreamId(1),
+            HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+            request.into(),
+        )
+        .await
+        .unwrap();
+        conn.verify_stream_error(httpwg::ErrorC::CompressionError)
+            .await
+            .unwrap();
+
+        let mut conn = connect(small_table()).await?;
+        conn.write_headers(
+            StreamId(1),
+            HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+            [&[0x3f, 0xe1, 0x01][..], request].concat().into(),
+        )
+        .await
+        .unwrap();
+        conn.verify_headers_frame(StreamId(1)).await.unwrap();
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_priority_scheduling() {
+    use loona_h2::{HeadersFlags, StreamId};
+
+    helpers::run(async move {
+        const BODY_LEN: usize = 65535;
+
+        struct TestDriver;
+
+        impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+        where
+            OurEncoder: Encoder,
+        {
+            type Error = BX;
+
+            async fn handle(
+                &self,
+                _req: loona::Request,
+                _req_body: &mut impl Body,
+                res: Responder<OurEncoder, ExpectResponseHeaders>,
+            ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+                let mut body = loona::SinglePieceBody::from(vec![b'a'; BODY_LEN]);
+                res.write_final_response_with_body(Response::default(), &mut body)
+                    .await
+                    .bx()
+            }
+        }
+
+        struct TwoHalves<W, R>(W, R);
+        impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+            type Read = R;
+            type Write = W;
+
+            fn into_halves(self) -> (Self::Read, Self::Write) {
+                (self.1, self.0)
+            }
+        }
+
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+        loona::buffet::spawn(h2::serve(
+            (server_read, server_write),
+            Rc::new(h2::ServerConf::default()),
+            RollMut::alloc()?,
+            Rc::new(TestDriver),
+        ));
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+
+        let flags = HeadersFlags::EndHeaders | HeadersFlags::EndStream;
+        let with_priority = |conn: &httpwg::Conn<_>, priority: Option<&'static str>| {
+            let mut headers = conn.common_headers("GET");
+            if let Some(priority) = priority {
+                headers.append("priority", priority);
+            }
+            headers
+        };
+
+        // the first response uses up the connection window
+        let headers = with_priority(&conn, None);
+        conn.encode_and_write_headers(StreamId(1), flags, &headers)
+            .await
+            .unwrap();
+        loop {
+            let (frame, _) = conn.wait_for_frame(httpwg::FrameT::Data).await.unwrap();
+            if frame.is_end_stream() {
+                break;
+            }
+        }
+
+        // so these all wait for more of it
+        let headers = with_priority(&conn, Some("u=6"));
+        conn.encode_and_write_headers(StreamId(3), flags, &headers)
+            .await
+            .unwrap();
+        let headers = with_priority(&conn, Some("u=1"));
+        conn.encode_and_write_headers(StreamId(5), flags, &headers)
+            .await
+            .unwrap();
+        // updated before the request arrives, or after
+        conn.write_priority_update(StreamId(7), b"u=0")
+            .await
+            .unwrap();
+        let headers = with_priority(&conn, None);
+        conn.encode_and_write_headers(StreamId(7), flags, &headers)
+            .await
+            .unwrap();
+        let headers = with_priority(&conn, Some("u=0"));
+        conn.encode_and_write_headers(StreamId(9), flags, &headers)
+            .await
+            .unwrap();
+        conn.write_priority_update(StreamId(9), b"u=7")
+            .await
+            .unwrap();
+
+        // let all the handlers write their response
+        conn.verify_connection_still_alive().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        conn.write_window_update(StreamId::CONNECTION, 4 * BODY_LEN as u32)
+            .await
+            .unwrap();
+        let mut order = vec![];
+        while order.len() < 4 {
+            let (frame, _) = conn
+                .wait_for_frame(httpwg::FrameT::Headers | httpwg::FrameT::Data)
+                .await
+                .unwrap();
+            if frame.is_end_stream() {
+                order.push(frame.stream_id.0);
+            }
+        }
+        assert_eq!(order, [7, 5, 3, 9]);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_send_window_backpressure() {
+    use loona_h2::{HeadersFlags, StreamId};
+    use std::cell::Cell;
+
+    helpers::run(async move {
+        const CHUNK_LEN: usize = 16384;
+        const NUM_CHUNKS: usize = 64;
+
+        struct TestDriver {
+            written: Rc<Cell<usize>>,
+        }
+
+        impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+        where
+            OurEncoder: Encoder,
+        {
+            type Error = BX;
+
+            async fn handle(
+                &self,
+                _req: loona::Request,
+                _req_body: &mut impl Body,
+                res: Responder<OurEncoder, ExpectResponseHeaders>,
+            ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+                let mut res = res.write_final_response(Response::default()).await?;
+                for _ in 0..NUM_CHUNKS {
+                    res.write_chunk(vec![b'a'; CHUNK_LEN].into()).await?;
+                    self.written.set(self.written.get() + CHUNK_LEN);
+                }
+                Ok(res.finish_body(None).await?)
+            }
+        }
+
+        struct TwoHalves<W, R>(W, R);
+        impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+            type Read = R;
+            type Write = W;
+
+            fn into_halves(self) -> (Self::Read, Self::Write) {
+                (self.1, self.0)
+            }
+        }
+
+        let written: Rc<Cell<usize>> = Default::default();
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+        loona::buffet::spawn(h2::serve(
+            (server_read, server_write),
+            Rc::new(h2::ServerConf::default()),
+            RollMut::alloc()?,
+            Rc::new(TestDriver {
+                written: written.clone(),
+            }),
+        ));
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+
+        let headers = conn.common_headers("GET");
+        conn.encode_and_write_headers(
+            StreamId(1),
+            HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+            &headers,
+        )
+        .await
+        .unwrap();
+        conn.verify_headers_frame(StreamId(1)).await.unwrap();
+
+        // the handler can't get much further than the windows
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let before_updates = written.get();
+        assert!(
+            before_updates <= 65535 + 2 * CHUNK_LEN,
+            "wrote {before_updates} bytes past a 65535-byte window"
+        );
+
+        let mut received = 0;
+        loop {
+            let (frame, _) = conn.wait_for_frame(httpwg::FrameT::Data).await.unwrap();
+            received += frame.len as usize;
+            if frame.len > 0 {
+                conn.write_window_update(StreamId::CONNECTION, frame.len)
+                    .await
+                    .unwrap();
+                conn.write_window_update(StreamId(1), frame.len)
+                    .await
+                    .unwrap();
+            }
+            if frame.is_end_stream() {
+                break;
+            }
+        }
+        assert_eq!(received, CHUNK_LEN * NUM_CHUNKS);
+        assert_eq!(written.get(), CHUNK_LEN * NUM_CHUNKS);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2_origin_frame() {
+    use loona_h2::{Frame, FrameType, Origin, SettingsFlags, StreamId, PREFACE};
+
+    helpers::run(async move {
+        struct TestDriver;
+
+        impl<OurEncoder> ServerDriver<OurEncoder> for TestDriver
+        where
+            OurEncoder: Encoder,
+        {
+            type Error = BX;
+
+            async fn handle(
+                &self,
+                _req: loona::Request,
+                _req_body: &mut impl Body,
+                _res: Responder<OurEncoder, ExpectResponseHeaders>,
+            ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+                unreachable!("no requests are sent")
+            }
+
+            fn origins(&self) -> Option<Vec<String>> {
+                Some(vec![
+                    "https://example.org".into(),
+                    // doesn't fit in a 16384-byte frame
+                    format!("https://{}.org", "a".repeat(20000)),
+                    "https://cdn.example.org".into(),
+                ])
+            }
+        }
+
+        struct TwoHalves<W, R>(W, R);
+        impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+            type Read = R;
+            type Write = W;
+
+            fn into_halves(self) -> (Self::Read, Self::Write) {
+                (self.1, self.0)
+            }
+        }
+
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+        loona::buffet::spawn(h2::serve(
+            (server_read, server_write),
+            Rc::new(h2::ServerConf::default()),
+            RollMut::alloc()?,
+            Rc::new(TestDriver),
+        ));
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+
+        // not `handshake`, which would skip over the ORIGIN frame
+        conn.send(PREFACE).await.unwrap();
+        conn.write_frame(
+            Frame::new(
+                FrameType::Settings(Default::default()),
+                StreamId::CONNECTION,
+            ),
+            (),
+        )
+        .await
+        .unwrap();
+        let (frame, _) = conn.wait_for_frame(httpwg::FrameT::Settings).await.unwrap();
+        assert!(!frame.is_ack());
+
+        let (frame, payload) = conn.wait_for_frame(httpwg::FrameT::Origin).await.unwrap();
+        assert_eq!(frame.stream_id, StreamId::CONNECTION);
+        let (_, origin) = Origin::parse(payload).unwrap();
+        let origins: Vec<_> = origin.origins.iter().map(|o| &o[..]).collect();
+        assert_eq!(
+            origins,
+            [&b"https://example.org"[..], &b"https://cdn.example.org"[..]]
+        );
+
+        conn.write_frame(
+            Frame::new(
+                FrameType::Settings(SettingsFlags::Ack.into()),
+                StreamId::CONNECTION,
+            ),
+            (),
+        )
+        .await
+        .unwrap();
+
+        // clients have no business sending those, the server ignores them
+        conn.write_origin(&[b"https://example.net"]).await.unwrap();
+        conn.verify_connection_still_alive().await.unwrap();
+
+        Ok(())
+    })
+}
+
+/// Replies with the request's version, method and target
+struct RequestLineDriver;
+
+impl<OurEncoder> ServerDriver<OurEncoder> for RequestLineDriver
+where
+    OurEncoder: Encoder,
+{
+    type Error = BX;
+
+    async fn handle(
+        &self,
+        req: loona::Request,
+        req_body: &mut impl Body,
+        res: Responder<OurEncoder, ExpectResponseHeaders>,
+    ) -> b_x::Result<Responder<OurEncoder, ResponseDone>> {
+        while let BodyChunk::Chunk(_) = req_body.next_chunk().await.bx()? {}
+
+        let line = format!("{:?} {} {}", req.version, req.method, req.uri);
+        res.write_final_response_with_body(
+            Response::default(),
+            &mut loona::SinglePieceBody::from(line.into_bytes()),
+        )
+        .await
+        .bx()
+    }
+}
+
+/// Reads DATA frames on `stream_id` until the end of the stream
+async fn read_h2_body<IO: IntoHalves>(
+    conn: &mut httpwg::Conn<IO>,
+    stream_id: loona_h2::StreamId,
+) -> Vec<u8> {
+    let mut body = vec![];
+    loop {
+        let (frame, payload) = conn.wait_for_frame(httpwg::FrameT::Data).await.unwrap();
+        assert_eq!(frame.stream_id, stream_id);
+        body.extend_from_slice(&payload[..]);
+        if frame.is_end_stream() {
+            return body;
+        }
+    }
+}
+
+#[test]
+fn h2c_upgrade() {
+    use loona_h2::{Frame, FrameType, HeadersFlags, SettingsFlags, StreamId, PREFACE};
+
+    helpers::run(async move {
+        struct TwoHalves<W, R>(W, R);
+        impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+            type Read = R;
+            type Write = W;
+
+            fn into_halves(self) -> (Self::Read, Self::Write) {
+                (self.1, self.0)
+            }
+        }
+
+        let (server_write, mut client_read) = loona::buffet::pipe();
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let serve_fut = loona::buffet::spawn(loona::h2c::serve(
+            (server_read, server_write),
+            Default::default(),
+            Default::default(),
+            RollMut::alloc()?,
+            Rc::new(RequestLineDriver),
+        ));
+
+        // SETTINGS_MAX_CONCURRENT_STREAMS = 100
+        client_write
+            .write_all_owned(
+                "GET /hello HTTP/1.1\r\nhost: example.org\r\nconnection: Upgrade, HTTP2-Settings\r\nupgrade: h2c\r\nhttp2-settings: AAMAAABk\r\n\r\n",
+            )
+            .await?;
+
+        // a byte at a time, so the HTTP/2 frames that follow stay in the pipe
+        let mut res_buf = vec![];
+        while !res_buf.ends_with(b"\r\n\r\n") {
+            let (res, buf) = client_read.read_owned(vec![0u8; 1]).await;
+            assert_eq!(res?, 1, "server hung up after {:?}", res_buf.hex_dump());
+            res_buf.push(buf[0]);
+        }
+        assert!(
+            res_buf.starts_with(b"HTTP/1.1 101 "),
+            "{:?}",
+            res_buf.hex_dump()
+        );
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+
+        // not `handshake`, which would skip over the response on stream 1
+        conn.send(PREFACE).await.unwrap();
+        conn.write_frame(
+            Frame::new(
+                FrameType::Settings(Default::default()),
+                StreamId::CONNECTION,
+            ),
+            (),
+        )
+        .await
+        .unwrap();
+        let (frame, _) = conn.wait_for_frame(httpwg::FrameT::Settings).await.unwrap();
+        assert!(!frame.is_ack(), "the server's settings come first");
+        conn.write_frame(
+            Frame::new(
+                FrameType::Settings(SettingsFlags::Ack.into()),
+                StreamId::CONNECTION,
+            ),
+            (),
+        )
+        .await
+        .unwrap();
+
+        conn.verify_headers_frame(StreamId(1)).await.unwrap();
+        let body = read_h2_body(&mut conn, StreamId(1)).await;
+        assert_eq!(
+            std::str::from_utf8(&body)?,
+            "HTTP/2.0 GET http://example.org/hello"
+        );
+
+        // the connection goes on like any HTTP/2 connection
+        let headers = conn.common_headers("GET");
+        conn.encode_and_write_headers(
+            StreamId(3),
+            HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+            &headers,
+        )
+        .await
+        .unwrap();
+        conn.verify_headers_frame(StreamId(3)).await.unwrap();
+        let body = read_h2_body(&mut conn, StreamId(3)).await;
+        assert!(body.starts_with(b"HTTP/2.0 GET "));
+
+        drop(conn);
+        let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+        assert_eq!(outcome, ServeOutcome::SuccessfulHttp2GracefulShutdown);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h2c_prior_knowledge_and_http1() {
+    use loona_h2::{HeadersFlags, StreamId};
+
+    helpers::run(async move {
+        struct TwoHalves<W, R>(W, R);
+        impl<W: WriteOwned + 'static, R: ReadOwned + 'static> IntoHalves for TwoHalves<W, R> {
+            type Read = R;
+            type Write = W;
+
+            fn into_halves(self) -> (Self::Read, Self::Write) {
+                (self.1, self.0)
+            }
+        }
+
+        // clients that know we speak HTTP/2 start with the preface
+        let (server_write, client_read) = loona::buffet::pipe();
+        let (client_write, server_read) = loona::buffet::pipe();
+        loona::buffet::spawn(loona::h2c::serve(
+            (server_read, server_write),
+            Default::default(),
+            Default::default(),
+            RollMut::alloc()?,
+            Rc::new(RequestLineDriver),
+        ));
+
+        let config = Rc::new(httpwg::Config::default());
+        let mut conn = httpwg::Conn::new(config, TwoHalves(client_write, client_read));
+        conn.handshake().await.unwrap();
+        let headers = conn.common_headers("GET");
+        conn.encode_and_write_headers(
+            StreamId(1),
+            HeadersFlags::EndHeaders | HeadersFlags::EndStream,
+            &headers,
+        )
+        .await
+        .unwrap();
+        conn.verify_headers_frame(StreamId(1)).await.unwrap();
+        let body = read_h2_body(&mut conn, StreamId(1)).await;
+        assert!(body.starts_with(b"HTTP/2.0 GET "));
+
+        // the others get HTTP/1.1, even when asking to upgrade with a body
+        for (req, expected, expected_outcome) in [
+            (
+                "GET /plain HTTP/1.1\r\nhost: example.org\r\nconnection: close\r\n\r\n",
+                "HTTP/1.1 GET /plain",
+                ServeOutcome::ClientRequestedConnectionClose,
+            ),
+            (
+                "POST /upload HTTP/1.1\r\nhost: example.org\r\nconnection: Upgrade, HTTP2-Settings\r\nupgrade: h2c\r\nhttp2-settings: \r\ncontent-length: 5\r\n\r\nhello",
+                "HTTP/1.1 POST /upload",
+                ServeOutcome::ClientClosedConnectionBetweenRequests,
+            ),
+        ] {
+            let (server_write, mut client_read) = loona::buffet::pipe();
+            let (mut client_write, server_read) = loona::buffet::pipe();
+            let serve_fut = loona::buffet::spawn(loona::h2c::serve(
+                (server_read, server_write),
+                Default::default(),
+                Default::default(),
+                RollMut::alloc()?,
+                Rc::new(RequestLineDriver),
+            ));
+
+            client_write.write_all_owned(req).await?;
+            drop(client_write);
+            let mut res_buf = BytesMut::new();
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let res;
+                (res, buf) = client_read.read_owned(buf).await;
+                let n = res?;
+                if n == 0 {
+                    break;
+                }
+                res_buf.extend_from_slice(&buf[..n]);
+            }
+
+            let mut headers = [EMPTY_HEADER; 16];
+            let mut res = httparse::Response::new(&mut headers[..]);
+            let Status::Complete(body_offset) = res.parse(&res_buf[..]).bx()? else {
+                panic!("incomplete response: {:?}", res_buf.hex_dump());
+            };
+            assert_eq!(res.code, Some(200));
+            assert_eq!(std::str::from_utf8(&res_buf[body_offset..])?, expected);
+
+            let outcome = tokio::time::timeout(Duration::from_secs(5), serve_fut)
+                .await
+                .bx()?
+                .bx()??;
+            assert_eq!(outcome, expected_outcome);
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+fn h1_rate_limit() {
+    use loona::rate_limit::{self, RateLimitConf, RateLimitDriver, RateLimiter};
+
+    helpers::run(async move {
+        let (mut client_write, server_read) = loona::buffet::pipe();
+        let (server_write, mut client_read) = loona::buffet::pipe();
+        let limiter = Rc::new(RateLimiter::new(RateLimitConf {
+            requests_per_sec: 0.01,
+            burst: 2,
+            ..Default::default()
+        }));
+        let driver = RateLimitDriver::new(
+            HelloDriver,
+            limiter.clone(),
+            rate_limit::header_value(header::HeaderName::from_static("x-api-key")),
+        );
+        let serve_fut = loona::buffet::spawn(h1::serve(
+            (server_read, server_write),
+            Rc::new(h1::ServerConf::default()),
+            RollMut::alloc()?,
+            driver,
+        ));
+
+        // the limited request's body is drained, so the next one parses
+        client_write
+            .write_all_owned(
+                "GET / HTTP/1.1\r\nx-api-key: a\r\n\r\n\
+                 GET / HTTP/1.1\r\nx-api-key: a\r\n\r\n\
+                 POST / HTTP/1.1\r\nx-api-key: a\r\ncontent-length: 5\r\n\r\nhello\
+                 GET / HTTP/1.1\r\nx-api-key: b\r\n\r\n\
+                 GET / HTTP/1.1\r\nconnection: close\r\n\r\n",
+            )
+            .await?;
+        let mut res_buf = BytesMut::new();
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let res;
+            (res, buf) = client_read.read_owned(buf).await;
+            let n = res?;
+            if n == 0 {
+                break;
+            }
+            res_buf.extend_from_slice(&buf[..n]);
+        }
+
+        let mut statuses = vec![];
+        let mut rest = &res_buf[..];
+        while !rest.is_empty() {
+            let mut headers = [EMPTY_HEADER; 16];
+            let mut res = httparse::Response::new(&mut headers[..]);
+            let Status::Complete(len) = res.parse(rest).bx()? else {
+                panic!("incomplete response: {:?}", rest.hex_dump());
+            };
+            let header = |name: &str| {
+                res.headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case(name))
+                    .map(|h| std::str::from_utf8(h.value).unwrap().to_owned())
+            };
+            let content_len: usize = header("content-length").unwrap().parse().unwrap();
+            statuses.push((res.code.unwrap(), header("retry-after")));
+            rest = &rest[len + content_len..];
+        }
+        assert_eq!(
+            statuses,
+            vec![
+                (200, None),
+                (200, None),
+                (429, Some("100".to_owned())),
+                (200, None),
+                (200, None),
+            ]
+        );
+        assert_eq!(limiter.len(), 2);
+
+        tokio::time::timeout(Duration::from_secs(5), serve_fut)
+            .await
+            .bx()?
+            .bx()??;
+
+        Ok(())
+    })
 }
@@ -1,12 +1,10 @@
-use b_x::{BxForResults, BX};
-use http::StatusCode;
+use b_x::BX;
 use loona::{
     buffet::{
         net::{TcpReadHalf, TcpWriteHalf},
         IntoHalves, RollMut,
     },
-    h1, Body, BodyChunk, Encoder, ExpectResponseHeaders, HeadersExt, Responder, Response,
-    ResponseDone, ServerDriver,
+    h1, proxy, Body, Encoder, ExpectResponseHeaders, Responder, ResponseDone, ServerDriver,
 };
 use std::{cell::RefCell, future::Future, net::SocketAddr, rc::Rc};
 use tracing::debug;
@@ -28,17 +26,8 @@ where
         &self,
         req: loona::Request,
         req_body: &mut impl Body,
-        mut respond: Responder<OurEncoder, ExpectResponseHeaders>,
+        respond: Responder<OurEncoder, ExpectResponseHeaders>,
     ) -> Result<Responder<OurEncoder, ResponseDone>, BX> {
-        if req.headers.expects_100_continue() {
-            debug!("Sending 100-continue");
-            let res = Response {
-                status: StatusCode::CONTINUE,
-                ..Default::default()
-            };
-            respond.write_interim_response(res).await?;
-        }
-
         let transport = {
             let mut pool = self.pool.borrow_mut();
             pool.pop()
@@ -54,13 +43,11 @@ where
                 .into_halves()
         };
 
-        let driver = ProxyClientDriver { respond };
-
-        let (transport, res) = h1::request(transport, req, req_body, driver).await?;
+        let (transport, res) = proxy::proxy_request(transport, req, req_body, respond).await?;
 
         if let Some(transport) = transport {
             let mut pool = self.pool.borrow_mut();
-            // FIXME: leaky abstraction, `h1::request` returns both halves of the
+            // FIXME: leaky abstraction, `proxy_request` returns both halves of the
             // transport, which are both actually `Rc<TcpStream>`
             pool.push(transport);
         }
@@ -69,52 +56,6 @@ where
     }
 }
 
-struct ProxyClientDriver<OurEncoder>
-where
-    OurEncoder: Encoder,
-{
-    respond: Responder<OurEncoder, ExpectResponseHeaders>,
-}
-
-impl<OurEncoder> h1::ClientDriver for ProxyClientDriver<OurEncoder>
-where
-    OurEncoder: Encoder,
-{
-    type Return = Responder<OurEncoder, ResponseDone>;
-    type Error = BX;
-
-    async fn on_informational_response(&mut self, res: Response) -> Result<(), Self::Error> {
-        debug!("Got informational response {}", res.status);
-        Ok(())
-    }
-
-    async fn on_final_response(
-        self,
-        res: Response,
-        body: &mut impl Body,
-    ) -> Result<Self::Return, Self::Error> {
-        let respond = self.respond;
-        let mut respond = respond.write_final_response(res).await?;
-
-        let trailers = loop {
-            match body.next_chunk().await.bx()? {
-                BodyChunk::Chunk(chunk) => {
-                    respond.write_chunk(chunk).await?;
-                }
-                BodyChunk::Done { trailers } => {
-                    // should we do something here in case of
-                    // content-length mismatches or something?
-                    break trailers;
-                }
-            }
-        };
-
-        let respond = respond.finish_body(trailers).await?;
-
-        Ok(respond)
-    }
-}
-
 pub async fn start(
     upstream_addr: SocketAddr,
 ) -> b_x::Result<(SocketAddr, impl Drop, impl Future<Output = b_x::Result<()>>)> {
@@ -1,5 +1,6 @@
 use io_uring::{opcode::AsyncCancel, IoUring};
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::future::Future;
 use std::os::unix::prelude::{AsRawFd, RawFd};
 use std::rc::Rc;
@@ -35,6 +36,25 @@ enum Lifecycle<C: cqueue::Entry> {
     // The Op has received a submission queue entry. The Op will
     // be Ready the next time that it is polled.
     Completed(C),
+    // A multishot Op, which completes any number of times until a completion
+    // queue entry comes without the `IORING_CQE_F_MORE` flag. Entries queue
+    // up until they're polled.
+    Multishot {
+        cqes: VecDeque<C>,
+        waker: Option<std::task::Waker>,
+        done: bool,
+    },
+}
+
+impl<C: cqueue::Entry> Lifecycle<C> {
+    fn name(&self) -> &'static str {
+        match self {
+            Lifecycle::Submitted => "Submitted",
+            Lifecycle::Waiting(_) => "Waiting",
+            Lifecycle::Completed(_) => "Completed",
+            Lifecycle::Multishot { .. } => "Multishot",
+        }
+    }
 }
 
 // An Future implementation that represents the current state of an IoUring Op.
@@ -65,11 +85,7 @@ impl<C: cqueue::Entry> Drop for Op<C> {
         match &guard[inner.index] {
             Lifecycle::Completed(_) => {}
             _ => {
-                let state_name = match &guard[inner.index] {
-                    Lifecycle::Submitted => "Submitted",
-                    Lifecycle::Waiting(_) => "Waiting",
-                    Lifecycle::Completed(_) => "Completed",
-                };
+                let state_name = guard[inner.index].name();
                 tracing::debug!(%index, "dropping op in state {state_name}");
                 drop(guard);
 
@@ -117,6 +133,7 @@ impl<C: cqueue::Entry> Future for OpInner<C> {
                 tracing::trace!(index = %self.index, "poll: completed!");
                 std::task::Poll::Ready(cqe.clone())
             }
+            Lifecycle::Multishot { .. } => unreachable!("single-shot op in multishot state"),
         }
     }
 }
@@ -131,11 +148,7 @@ impl<C: cqueue::Entry> Drop for OpInner<C> {
                 if std::thread::panicking() {
                     // thread is panicking, eschewing drop cleanliness check
                 } else {
-                    let lifecycle_name = match lifecycle {
-                        Lifecycle::Submitted => "Submitted",
-                        Lifecycle::Waiting(_) => "Waiting",
-                        Lifecycle::Completed(_) => "Completed",
-                    };
+                    let lifecycle_name = lifecycle.name();
                     let index = self.index;
                     tracing::debug!("dropping op inner {index} ({})", lifecycle_name);
 
@@ -146,9 +159,101 @@ impl<C: cqueue::Entry> Drop for OpInner<C> {
     }
 }
 
+/// A multishot op (multishot accept, multishot recv, etc.), which completes
+/// any number of times, cf. [IoUringAsync::push_multishot]
+pub struct MultishotOp<C: cqueue::Entry> {
+    slab: Rc<RefCell<slab::Slab<Lifecycle<C>>>>,
+    index: usize,
+    leftover: Option<Box<dyn FnMut(C)>>,
+    // set once a cancellation was pushed for it: dropping it again must not
+    // push another one
+    cancelled: bool,
+}
+
+impl<C: cqueue::Entry> MultishotOp<C> {
+    /// Waits for the next completion. Returns `None` once the kernel is done
+    /// with the op, after an error for example: it must be pushed again to
+    /// get more completions.
+    pub async fn next(&mut self) -> Option<C> {
+        std::future::poll_fn(|cx| self.poll_next(cx)).await
+    }
+
+    pub fn poll_next(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<C>> {
+        let mut guard = self.slab.borrow_mut();
+        let Lifecycle::Multishot { cqes, waker, done } = &mut guard[self.index] else {
+            unreachable!("multishot op in single-shot state")
+        };
+        if let Some(cqe) = cqes.pop_front() {
+            return std::task::Poll::Ready(Some(cqe));
+        }
+        if *done {
+            return std::task::Poll::Ready(None);
+        }
+        *waker = Some(cx.waker().clone());
+        std::task::Poll::Pending
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(
+            &self.slab.borrow()[self.index],
+            Lifecycle::Multishot { done: true, .. }
+        )
+    }
+}
+
+impl<C: cqueue::Entry> Drop for MultishotOp<C> {
+    fn drop(&mut self) {
+        let mut leftover = self.leftover.take();
+        if self.is_done() {
+            let lifecycle = self.slab.borrow_mut().remove(self.index);
+            if let (Lifecycle::Multishot { cqes, .. }, Some(leftover)) = (lifecycle, &mut leftover)
+            {
+                cqes.into_iter().for_each(leftover);
+            }
+            return;
+        }
+
+        if self.cancelled {
+            // the task waiting for its last completion went away, the
+            // runtime is shutting down: hand over what came in so far, and
+            // keep the slot, since the kernel may still complete it
+            let mut guard = self.slab.borrow_mut();
+            if let (Lifecycle::Multishot { cqes, .. }, Some(leftover)) =
+                (&mut guard[self.index], &mut leftover)
+            {
+                cqes.drain(..).for_each(leftover);
+            }
+            return;
+        }
+
+        // the kernel may complete it again at any time: cancel it, and keep
+        // its slot until its last completion comes in
+        tracing::debug!(index = %self.index, "cancelling multishot op");
+        let cancel = AsyncCancel::new(self.index.try_into().unwrap()).build();
+        let cancel_op = get_ring().push(cancel);
+        let mut rest = MultishotOp {
+            slab: self.slab.clone(),
+            index: self.index,
+            leftover,
+            cancelled: true,
+        };
+        tokio::task::spawn_local(async move {
+            cancel_op.await;
+            while let Some(cqe) = rest.next().await {
+                if let Some(leftover) = &mut rest.leftover {
+                    leftover(cqe);
+                }
+            }
+        });
+    }
+}
+
 pub mod cqueue;
 pub mod squeue;
 
+mod buf_ring;
+pub use buf_ring::BufRing;
+
 pub struct IoUringAsync<
     S: squeue::Entry = io_uring::squeue::Entry,
     C: cqueue::Entry = io_uring::cqueue::Entry,
@@ -240,6 +345,45 @@ impl<S: squeue::Entry, C: cqueue::Entry> IoUringAsync<S, C> {
         }
     }
 
+    /// Pushes a multishot op. Whatever completions it gets after the
+    /// [MultishotOp] is dropped go to `leftover`: that's where accepted file
+    /// descriptors get closed, selected buffers get recycled, etc.
+    pub fn push_multishot(
+        &self,
+        entry: impl Into<S>,
+        leftover: impl FnMut(C) + 'static,
+    ) -> MultishotOp<C> {
+        let mut guard = self.slab.borrow_mut();
+        let index = guard.insert(Lifecycle::Multishot {
+            cqes: VecDeque::new(),
+            waker: None,
+            done: false,
+        });
+        tracing::trace!(%index, "pushing multishot op with index");
+        let entry = entry.into().user_data(index.try_into().unwrap());
+        while unsafe { self.uring.submission_shared().push(&entry).is_err() } {
+            self.uring.submit().unwrap();
+        }
+        MultishotOp {
+            slab: self.slab.clone(),
+            index,
+            leftover: Some(Box::new(leftover)),
+            cancelled: false,
+        }
+    }
+
+    /// Registers a ring of `entries` provided buffers of `buf_len` bytes
+    /// each as buffer group `bgid`, cf. [BufRing]. Fails on kernels older
+    /// than 5.19.
+    pub fn register_buf_ring(
+        &self,
+        entries: u16,
+        buf_len: usize,
+        bgid: u16,
+    ) -> std::io::Result<BufRing> {
+        BufRing::register(self.uring.clone(), entries, buf_len, bgid)
+    }
+
     pub fn handle_cqe(&self) {
         let mut guard = self.slab.borrow_mut();
         while let Some(cqe) = unsafe { self.uring.completion_shared() }.next() {
@@ -256,11 +400,20 @@ impl<S: squeue::Entry, C: cqueue::Entry> IoUringAsync<S, C> {
                 }
                 Lifecycle::Completed(cqe) => {
                     println!(
-                        "multishot operations not implemented: {}, {}",
+                        "single-shot op completed twice: {}, {}",
                         cqe.user_data(),
                         cqe.result()
                     );
                 }
+                Lifecycle::Multishot { cqes, waker, done } => {
+                    if !io_uring::cqueue::more(cqe.flags()) {
+                        *done = true;
+                    }
+                    cqes.push_back(cqe);
+                    if let Some(waker) = waker.take() {
+                        waker.wake();
+                    }
+                }
             }
         }
     }
@@ -273,10 +426,13 @@ impl<S: squeue::Entry, C: cqueue::Entry> IoUringAsync<S, C> {
 
 #[cfg(test)]
 mod tests {
-    use super::IoUringAsync;
-    use io_uring::opcode::Nop;
+    use super::{get_ring, IoUringAsync};
+    use io_uring::{
+        opcode::{Nop, RecvMulti},
+        types::Fd,
+    };
     use send_wrapper::SendWrapper;
-    use std::rc::Rc;
+    use std::{cell::Cell, os::fd::AsRawFd, rc::Rc};
 
     #[test]
     fn example1() {
@@ -306,6 +462,39 @@ mod tests {
         });
     }
 
+    #[test]
+    fn drop_armed_multishot_recv_on_shutdown() {
+        let (a, _b) = std::os::unix::net::UnixStream::pair().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async move {
+            let uring = get_ring();
+            let lset = tokio::task::LocalSet::new();
+            let leftovers = Rc::new(Cell::new(0));
+            lset.run_until(async {
+                tokio::task::spawn_local(IoUringAsync::listen(uring.clone()));
+
+                let buf_ring = uring.register_buf_ring(8, 4096, 0).unwrap();
+                let sqe = RecvMulti::new(Fd(a.as_raw_fd()), buf_ring.bgid()).build();
+                let leftovers = leftovers.clone();
+                let recv = uring.push_multishot(sqe, move |_| leftovers.set(leftovers.get() + 1));
+                uring.submit().unwrap();
+
+                // nothing to receive: this spawns a task that cancels it and
+                // waits for its last completion
+                drop(recv);
+            })
+            .await;
+
+            // ...which never gets to run: it's dropped with the local set
+            drop(lset);
+            assert_eq!(leftovers.get(), 0);
+        });
+    }
+
     #[test]
     fn example2() {
         let uring = IoUringAsync::new(8).unwrap();
@@ -0,0 +1,142 @@
+use std::{
+    alloc::{alloc_zeroed, dealloc, Layout},
+    cell::Cell,
+    rc::Rc,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use io_uring::{types::BufRingEntry, IoUring};
+
+use super::{cqueue, squeue};
+
+/// A ring of provided buffers (cf. `io_uring_register_buf_ring(3)`): ops
+/// submitted with its buffer group id let the kernel pick a buffer when data
+/// arrives, instead of pinning one per op while they wait. The buffer id the
+/// kernel picked is in the completion flags, cf.
+/// [io_uring::cqueue::buffer_select].
+///
+/// Buffers picked by the kernel belong to whoever got the completion until
+/// they're given back with [BufRing::recycle].
+pub struct BufRing {
+    unregister: Box<dyn Fn(u16) -> std::io::Result<()>>,
+    ring: *mut BufRingEntry,
+    ring_layout: Layout,
+    bufs: *mut u8,
+    bufs_layout: Layout,
+    buf_len: usize,
+    entries: u16,
+    bgid: u16,
+    tail: Cell<u16>,
+}
+
+impl BufRing {
+    pub(crate) fn register<S: squeue::Entry, C: cqueue::Entry>(
+        uring: Rc<IoUring<S, C>>,
+        entries: u16,
+        buf_len: usize,
+        bgid: u16,
+    ) -> std::io::Result<Self> {
+        assert!(
+            entries.is_power_of_two() && entries <= 32768,
+            "buf ring entries must be a power of two, at most 32768"
+        );
+        assert!(buf_len > 0 && buf_len <= u32::MAX as usize);
+
+        // the ring itself must be page-aligned
+        let ring_layout =
+            Layout::from_size_align(entries as usize * std::mem::size_of::<BufRingEntry>(), 4096)
+                .unwrap();
+        let bufs_layout = Layout::from_size_align(entries as usize * buf_len, 64).unwrap();
+        let ring = unsafe { alloc_zeroed(ring_layout) } as *mut BufRingEntry;
+        let bufs = unsafe { alloc_zeroed(bufs_layout) };
+        if ring.is_null() || bufs.is_null() {
+            std::alloc::handle_alloc_error(ring_layout);
+        }
+
+        let res = unsafe {
+            uring
+                .submitter()
+                .register_buf_ring(ring as u64, entries, bgid)
+        };
+        if let Err(e) = res {
+            unsafe {
+                dealloc(ring as *mut u8, ring_layout);
+                dealloc(bufs, bufs_layout);
+            }
+            return Err(e);
+        }
+
+        let this = Self {
+            unregister: Box::new(move |bgid| uring.submitter().unregister_buf_ring(bgid)),
+            ring,
+            ring_layout,
+            bufs,
+            bufs_layout,
+            buf_len,
+            entries,
+            bgid,
+            tail: Cell::new(0),
+        };
+        for bid in 0..entries {
+            this.push(bid);
+        }
+        this.publish();
+        Ok(this)
+    }
+
+    /// The buffer group id to submit ops with
+    pub fn bgid(&self) -> u16 {
+        self.bgid
+    }
+
+    pub fn buf_len(&self) -> usize {
+        self.buf_len
+    }
+
+    /// The first `len` bytes of buffer `bid`
+    ///
+    /// # Safety
+    ///
+    /// The kernel must have handed out `bid` (in a completion), and it must
+    /// not have been recycled since.
+    pub unsafe fn buf(&self, bid: u16, len: usize) -> &[u8] {
+        assert!(bid < self.entries && len <= self.buf_len);
+        std::slice::from_raw_parts(self.bufs.add(bid as usize * self.buf_len), len)
+    }
+
+    /// Gives buffer `bid` back to the kernel
+    pub fn recycle(&self, bid: u16) {
+        self.push(bid);
+        self.publish();
+    }
+
+    fn push(&self, bid: u16) {
+        let tail = self.tail.get();
+        let mask = self.entries - 1;
+        let entry = unsafe { &mut *self.ring.add((tail & mask) as usize) };
+        entry.set_addr(unsafe { self.bufs.add(bid as usize * self.buf_len) } as u64);
+        entry.set_len(self.buf_len as u32);
+        entry.set_bid(bid);
+        self.tail.set(tail.wrapping_add(1));
+    }
+
+    fn publish(&self) {
+        // the kernel reads the tail concurrently
+        let tail = unsafe { BufRingEntry::tail(self.ring) } as *const AtomicU16;
+        unsafe { (*tail).store(self.tail.get(), Ordering::Release) };
+    }
+}
+
+impl Drop for BufRing {
+    fn drop(&mut self) {
+        if let Err(e) = (self.unregister)(self.bgid) {
+            // the kernel may still write to it: leak it rather than free it
+            tracing::warn!("could not unregister buf ring {}: {e}", self.bgid);
+            return;
+        }
+        unsafe {
+            dealloc(self.ring as *mut u8, self.ring_layout);
+            dealloc(self.bufs, self.bufs_layout);
+        }
+    }
+}